@@ -0,0 +1,214 @@
+//! Shell completions, including names of clusters and drones fetched from
+//! NATS as they are typed.
+//!
+//! The static part of each script is generated by clap. The dynamic part
+//! calls back into `plane-cli __complete` with the words typed so far, which
+//! prints the names to offer if the next word is a cluster or drone.
+
+use anyhow::Result;
+use async_nats::jetstream::consumer::DeliverPolicy;
+use clap::Command;
+use clap_complete::Shell;
+use plane_core::{messages::agent::DroneStatusMessage, nats_connection::NatsConnectionSpec};
+use std::{collections::BTreeSet, time::Duration};
+
+/// How long to wait for NATS before offering no names, so that a missing or
+/// slow server does not hang the shell.
+const COMPLETION_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameKind {
+    Cluster,
+    Drone,
+}
+
+/// What each positional argument of a subcommand names, where it names
+/// something which can be looked up.
+fn positional_kinds(subcommand: &str) -> &'static [Option<NameKind>] {
+    match subcommand {
+        "spawn" | "terminate" | "tag" | "exec" | "attach" => &[Some(NameKind::Cluster)],
+        "drain" | "maintenance" | "approve-drone" => {
+            &[Some(NameKind::Drone), Some(NameKind::Cluster)]
+        }
+        "migrate" => &[Some(NameKind::Cluster), None, Some(NameKind::Drone)],
+        _ => &[],
+    }
+}
+
+/// Whether `word` is an option of `command` which is followed by a value.
+fn takes_value(command: &Command, word: &str) -> bool {
+    if word.contains('=') {
+        return false;
+    }
+
+    command.get_arguments().any(|arg| {
+        let matches = match word.strip_prefix("--") {
+            Some(long) => arg.get_long() == Some(long),
+            None => word.len() == 2 && arg.get_short() == word.chars().nth(1),
+        };
+        matches && arg.get_action().takes_values()
+    })
+}
+
+/// What the next word of a command line names, given the words before it
+/// (not including the program name).
+pub fn completion_kind(root: &Command, words: &[String]) -> Option<NameKind> {
+    let mut words = words.iter();
+
+    let subcommand = loop {
+        let word = words.next()?;
+        if word.starts_with('-') {
+            if takes_value(root, word) && words.next().is_none() {
+                return None;
+            }
+        } else {
+            break root.find_subcommand(word)?;
+        }
+    };
+
+    let mut position = 0;
+    while let Some(word) = words.next() {
+        if word == "--" {
+            return None;
+        }
+
+        if word.starts_with('-') {
+            if (takes_value(subcommand, word) || takes_value(root, word)) && words.next().is_none()
+            {
+                return None;
+            }
+        } else {
+            position += 1;
+        }
+    }
+
+    positional_kinds(subcommand.get_name())
+        .get(position)
+        .copied()
+        .flatten()
+}
+
+/// The NATS URL given on a command line, if any.
+pub fn nats_url(words: &[String]) -> Option<&str> {
+    words.iter().enumerate().find_map(|(i, word)| {
+        if word == "--nats" {
+            words.get(i + 1).map(String::as_str)
+        } else {
+            word.strip_prefix("--nats=")
+        }
+    })
+}
+
+/// Names of the given kind, from the status messages of drones.
+pub async fn fetch_names(nats_url: &str, kind: NameKind) -> Result<Vec<String>> {
+    let fetch = async {
+        let nats = NatsConnectionSpec::from_url(nats_url)?.connect().await?;
+        let drones = nats
+            .get_all(
+                &DroneStatusMessage::subscribe_subject(),
+                DeliverPolicy::LastPerSubject,
+            )
+            .await?;
+
+        let names: BTreeSet<String> = drones
+            .into_iter()
+            .map(|drone| match kind {
+                NameKind::Cluster => drone.cluster.to_string(),
+                NameKind::Drone => drone.drone_id.to_string(),
+            })
+            .collect();
+        Ok::<_, anyhow::Error>(names.into_iter().collect())
+    };
+
+    tokio::time::timeout(COMPLETION_TIMEOUT, fetch).await?
+}
+
+/// Script which adds dynamic completion of names to the script clap
+/// generates for a shell, if the shell is supported.
+fn dynamic_script(shell: Shell) -> Option<&'static str> {
+    match shell {
+        Shell::Bash => Some(
+            r#"
+_plane_cli_dynamic() {
+    local names
+    names=$(plane-cli __complete -- "${COMP_WORDS[@]:1:COMP_CWORD-1}" 2>/dev/null)
+    if [[ -n "$names" ]]; then
+        COMPREPLY=($(compgen -W "$names" -- "${COMP_WORDS[COMP_CWORD]}"))
+    else
+        _plane-cli "$@"
+    fi
+}
+complete -F _plane_cli_dynamic -o bashdefault -o default plane-cli
+"#,
+        ),
+        Shell::Zsh => Some(
+            r#"
+_plane_cli_dynamic() {
+    local -a names
+    names=(${(f)"$(plane-cli __complete -- ${words[2,CURRENT-1]} 2>/dev/null)"})
+    if (( ${#names} )); then
+        compadd -a names
+    else
+        _plane-cli "$@"
+    fi
+}
+compdef _plane_cli_dynamic plane-cli
+"#,
+        ),
+        Shell::Fish => Some(
+            r#"
+complete -c plane-cli -a "(plane-cli __complete -- (commandline -opc)[2..-1] 2>/dev/null)"
+"#,
+        ),
+        _ => None,
+    }
+}
+
+/// Print the completion script for a shell.
+pub fn print_completions(shell: Shell, mut command: Command) {
+    clap_complete::generate(shell, &mut command, "plane-cli", &mut std::io::stdout());
+    if let Some(script) = dynamic_script(shell) {
+        print!("{}", script);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opts;
+    use clap::CommandFactory;
+
+    fn kind(line: &str) -> Option<NameKind> {
+        let words: Vec<String> = line.split_whitespace().map(ToString::to_string).collect();
+        completion_kind(&Opts::command(), &words)
+    }
+
+    #[test]
+    fn test_completion_kind() {
+        assert_eq!(Some(NameKind::Cluster), kind("terminate"));
+        assert_eq!(None, kind("terminate plane.test"));
+        assert_eq!(Some(NameKind::Drone), kind("drain"));
+        assert_eq!(Some(NameKind::Cluster), kind("drain drone1"));
+        assert_eq!(Some(NameKind::Drone), kind("migrate plane.test backend1"));
+        assert_eq!(None, kind("list-drones"));
+
+        // Options and their values are not positional arguments.
+        assert_eq!(
+            Some(NameKind::Cluster),
+            kind("--nats nats://localhost spawn")
+        );
+        assert_eq!(
+            Some(NameKind::Cluster),
+            kind("maintenance --duration 60 drone1")
+        );
+        assert_eq!(None, kind("terminate --grace"));
+    }
+
+    #[test]
+    fn test_nats_url() {
+        let words: Vec<String> = vec!["--nats".into(), "nats://a".into(), "spawn".into()];
+        assert_eq!(Some("nats://a"), nats_url(&words));
+        assert_eq!(Some("nats://b"), nats_url(&["--nats=nats://b".to_string()]));
+        assert_eq!(None, nats_url(&["spawn".to_string()]));
+    }
+}
@@ -1,44 +1,266 @@
-use anyhow::Result;
-use async_nats::jetstream::consumer::DeliverPolicy;
-use clap::{Parser, Subcommand};
+use self::completion::{completion_kind, fetch_names, nats_url, print_completions};
+use anyhow::{anyhow, Context, Result};
+use async_nats::jetstream::{consumer::DeliverPolicy, stream::RetentionPolicy};
+use chrono::{DateTime, Utc};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
 use colored::Colorize;
+use crossterm::tty::IsTty;
 use plane_core::{
+    diagnostics::check_jetstream,
     messages::{
         agent::{
-            BackendStateMessage, DockerExecutableConfig, DroneStatusMessage, ResourceLimits,
-            TerminationRequest,
+            AttachInput, AttachInputMessage, AttachOutputChunk, AttachOutputMessage, AttachRequest,
+            BackendState, BackendStateMessage, DockerCredentials, DockerExecutableConfig,
+            DroneStatusMessage, EgressPolicy, ExecOutputChunk, ExecOutputMessage, ExecRequest,
+            ExecResponse, IdlePolicy, MigrateBackend, ObservabilityOptions, ResourceLimits,
+            SpawnProgress, SpawnProgressEvent, StatsRequest, TerminalSize, TerminateAllRequest,
+            TerminationRequest, UpdateBackendMetadata,
         },
         dns::SetDnsRecord,
-        scheduler::{DrainDrone, ScheduleRequest, ScheduleResponse},
+        scheduler::{
+            AffinityGroup, ApplyClusterConfig, ApproveDrone, BackendLookupRequest,
+            BatchScheduleRequest, ClusterConfig, ClusterListRequest, DrainDrone, ImageStatsRequest,
+            MaintenanceWindow, ScheduleMaintenance, ScheduleRequest, ScheduleResponse,
+            SchedulerLeader, TerminateBackendRequest,
+        },
     },
+    nats::{JetstreamSubscription, TypedNats, TypedSubscription},
     nats_connection::NatsConnectionSpec,
-    types::{BackendId, ClusterName, DroneId},
+    signing::SigningKey,
+    streams::{init_streams, StreamOptions, StreamsConfig},
+    types::{BackendId, ClusterName, DroneId, ProgressId},
+    version::{is_compatible, PLANE_VERSION},
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
 };
-use std::{collections::HashMap, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use uuid::Uuid;
+
+mod completion;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable, colored text.
+    Text,
+    /// Machine-parseable JSON. Streaming commands print one JSON object per line.
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Retention {
+    Limits,
+    Interest,
+    WorkQueue,
+}
+
+impl From<Retention> for RetentionPolicy {
+    fn from(retention: Retention) -> Self {
+        match retention {
+            Retention::Limits => RetentionPolicy::Limits,
+            Retention::Interest => RetentionPolicy::Interest,
+            Retention::WorkQueue => RetentionPolicy::WorkQueue,
+        }
+    }
+}
+
+fn parse_backend_state(state: &str) -> Result<BackendState, String> {
+    state
+        .parse()
+        .map_err(|error: anyhow::Error| error.to_string())
+}
+
+fn parse_metadata_entry(entry: &str) -> Result<(String, String), String> {
+    entry
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("Expected key=value, got {}.", entry))
+}
 
 #[derive(Parser)]
 struct Opts {
+    /// NATS URL to connect to, or a comma-separated list of URLs of servers
+    /// in the same NATS cluster.
     #[clap(long)]
     nats: Option<String>,
 
+    /// Token sent with control requests, for the controller to authenticate
+    /// them.
+    #[clap(long)]
+    auth_token: Option<String>,
+
+    /// Base64-encoded Ed25519 private key to sign requests with, for clusters
+    /// which require signed schedule requests, and for running commands in
+    /// backends.
+    #[clap(long)]
+    signing_key: Option<String>,
+
+    /// Format to print results in.
+    #[clap(long, global = true, value_enum, default_value = "text")]
+    output: OutputFormat,
+
+    /// Do not ask for confirmation before destructive commands.
+    #[clap(short, long, global = true)]
+    yes: bool,
+
     #[command(subcommand)]
     command: Command,
 }
 
+// Parsed once, so the size of Spawn's options does not matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Command {
-    ListDrones,
+    ListDrones {
+        /// Keep the list up to date as drones publish their status.
+        #[clap(long)]
+        watch: bool,
+    },
+    /// List backends and their most recent state.
+    ListBackends {
+        /// Keep the list up to date as backends change state.
+        #[clap(long)]
+        watch: bool,
+
+        /// Include backends which have terminated.
+        #[clap(long)]
+        all: bool,
+    },
     ListDns,
+    /// List the clusters the controller has observed, with their drone and
+    /// backend counts.
+    ListClusters,
+    /// Show which cluster and drone a backend runs on, its state and its URL,
+    /// given only its ID.
+    Where {
+        backend: String,
+    },
+    /// Show which controller is scheduling backends, when controllers run
+    /// with leader election.
+    Leader,
+    /// Check the JetStream streams and consumers Plane depends on for
+    /// misconfiguration and lag.
+    Doctor {
+        /// Number of undelivered messages beyond which a consumer is
+        /// reported as lagging.
+        #[clap(long, default_value = "1000")]
+        max_pending: u64,
+    },
+    /// Generate an Ed25519 key pair for signing schedule requests or drone
+    /// releases, or for a drone to register with.
+    GenerateSigningKey,
+    /// Sign a drone binary released as the given version, printing the
+    /// signature to set in a cluster's drone_update.
+    SignArtifact {
+        path: PathBuf,
+
+        /// Version the binary reports, as set in the cluster's drone_update.
+        #[clap(long)]
+        version: String,
+
+        /// Private key to sign releases with. This should be a key used
+        /// only for releases, not the key schedule requests are signed with.
+        #[clap(long)]
+        release_key: String,
+    },
+    /// Print a completion script for a shell. Names of clusters and drones
+    /// are completed from NATS in bash, zsh, and fish.
+    Completions {
+        #[clap(value_enum)]
+        shell: Shell,
+    },
+    /// Print the names which can follow the given words, for completion
+    /// scripts.
+    #[command(name = "__complete", hide = true)]
+    Complete {
+        #[clap(last = true)]
+        words: Vec<String>,
+    },
     Spawn {
         cluster: String,
         image: String,
         /// Grace period with no connections before shutting down the drone.
         #[clap(long, default_value = "300")]
         timeout: u64,
+        /// Maximum lifetime of the backend in seconds, after which it is shut
+        /// down even if it is in use.
+        #[clap(long)]
+        max_lifetime: Option<u64>,
+        /// How long to wait in seconds for the backend to accept requests
+        /// before it is considered to have failed to start.
+        #[clap(long)]
+        port_ready_timeout: Option<u64>,
+        /// Number of identical backends to schedule, spread across drones.
+        #[clap(long, default_value = "1")]
+        count: u32,
+        /// Hostname label of the backend, instead of a generated one. Must be
+        /// unique within the cluster.
+        #[clap(long, conflicts_with = "count")]
+        name: Option<String>,
+        /// Check the request and choose a drone for it, without spawning it.
+        #[clap(long)]
+        dry_run: bool,
+        /// Fail if no drone accepts the backend within this many
+        /// milliseconds, instead of waiting for the drone.
+        #[clap(long)]
+        deadline_ms: Option<u64>,
+        /// Only place the backend on drones with this CPU architecture, e.g.
+        /// amd64 or arm64.
+        #[clap(long)]
+        arch: Option<String>,
+        /// Place the backend on the same drone as the other live backends
+        /// of this affinity group, if it can take it.
+        #[clap(long)]
+        affinity_group: Option<String>,
+        /// Fail instead of placing the backend elsewhere when the drone of
+        /// its affinity group cannot take it.
+        #[clap(long, requires = "affinity_group")]
+        strict_affinity: bool,
+        /// Log level or filter passed to the backend as RUST_LOG and
+        /// LOG_LEVEL, e.g. debug.
+        #[clap(long)]
+        log_level: Option<String>,
+        /// OTLP endpoint passed to the backend as OTEL_EXPORTER_OTLP_ENDPOINT.
+        #[clap(long)]
+        otel_endpoint: Option<String>,
+        /// Fraction of traces the backend samples, between 0 and 1.
+        #[clap(long)]
+        trace_sample_rate: Option<f64>,
+        /// JSON file of credentials for pulling the image from a private
+        /// registry, e.g. {"UsernamePassword": {"username": "...", "password": "..."}}.
+        #[clap(long)]
+        credentials_file: Option<PathBuf>,
+        /// Wait until the backend is ready, showing its progress, and exit
+        /// with an error if it fails to start.
+        #[clap(long, conflicts_with_all = ["count", "dry_run"])]
+        wait: bool,
+        /// With --wait, give up waiting after this many seconds.
+        #[clap(long, requires = "wait", value_name = "SECONDS")]
+        wait_timeout: Option<u64>,
     },
     Status {
         backend: Option<String>,
     },
+    /// Show how long each step of spawning a backend took.
+    Timings {
+        backend: String,
+    },
+    /// Show the most recent resource usage of a running backend, as sampled
+    /// by its drone.
+    Stats {
+        backend: String,
+    },
+    /// Show spawn, failure, and timing statistics for each image scheduled
+    /// since the controller started.
+    ImageStats {
+        image: Option<String>,
+    },
     Drain {
         drone: String,
         cluster: String,
@@ -47,20 +269,596 @@ enum Command {
         #[clap(long)]
         cancel: bool,
     },
+    /// Approve the registration key of a drone waiting to join a cluster
+    /// which requires approval.
+    ApproveDrone {
+        drone: String,
+        cluster: String,
+
+        /// Fingerprint of the drone's registration key, as logged by the
+        /// drone while it waits for approval.
+        key_fingerprint: String,
+    },
+    /// Terminate a backend: `terminate <cluster> <backend>` sends the
+    /// request to the cluster's drones, and `terminate <backend>` has the
+    /// controller find the drone running it.
     Terminate {
+        /// The backend, or with a second argument, the cluster whose drones
+        /// the termination request is sent to directly.
+        #[clap(value_name = "CLUSTER|BACKEND")]
+        cluster_or_backend: String,
+
+        backend: Option<String>,
+
+        /// Stop routing new requests to the backend and wait up to this many
+        /// seconds for in-flight requests to finish before terminating it.
+        #[clap(long)]
+        grace: Option<u64>,
+    },
+    /// Terminate every backend running on a drone, e.g. before its host is
+    /// decommissioned. The request must be signed (see --signing-key).
+    TerminateAll {
+        #[clap(long)]
+        drone: String,
+
+        #[clap(long)]
+        cluster: String,
+
+        /// Only terminate backends in this state, e.g. Ready.
+        #[clap(long, value_parser = parse_backend_state)]
+        state: Option<BackendState>,
+    },
+    /// Move a running backend to another drone, keeping its in-memory state.
+    /// Experimental; both drones need checkpointing configured, and the
+    /// request must be signed (see --signing-key).
+    Migrate {
+        cluster: String,
+        backend: String,
+        target_drone: String,
+    },
+    /// Set metadata entries on a running backend, without restarting it.
+    Tag {
+        cluster: String,
+        backend: String,
+
+        /// Entries to set, as key=value. An empty value (key=) removes the key.
+        #[clap(value_parser = parse_metadata_entry, required = true)]
+        entries: Vec<(String, String)>,
+    },
+    /// Run a command inside a running backend, printing its output.
+    Exec {
+        cluster: String,
+        backend: String,
+
+        /// The command to run, after `--`.
+        #[clap(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Open an interactive terminal in a running backend. Press Ctrl-] to
+    /// detach.
+    Attach {
         cluster: String,
         backend: String,
+
+        /// The command to run, after `--`. Defaults to /bin/sh.
+        #[clap(last = true)]
+        command: Vec<String>,
     },
+    Maintenance {
+        drone: String,
+        cluster: String,
+
+        /// Start of the maintenance window, as an RFC 3339 timestamp. Defaults to now.
+        #[clap(long)]
+        start: Option<DateTime<Utc>>,
+
+        /// Length of the maintenance window in seconds.
+        #[clap(long, default_value = "3600")]
+        duration: u64,
+
+        /// Terminate backends running on the drone when the window starts.
+        #[clap(long)]
+        drain: bool,
+    },
+    /// Create the JetStream streams used by Plane, or update existing streams
+    /// to match the given options.
+    InitStreams {
+        #[clap(long, value_enum)]
+        retention: Option<Retention>,
+
+        /// Number of replicas to keep of each stream.
+        #[clap(long)]
+        replicas: Option<usize>,
+
+        /// Maximum age of messages in each stream, in seconds.
+        #[clap(long)]
+        max_age: Option<u64>,
+
+        /// TOML file of options for each stream, including the durable
+        /// consumers to provision on it, in the format of the controller's
+        /// `[streams]` section.
+        #[clap(long, conflicts_with_all = ["retention", "replicas", "max_age"])]
+        config: Option<PathBuf>,
+    },
+    /// Apply the cluster configs in a YAML file, replacing each cluster's
+    /// previous config. Documents are separated by `---`.
+    Apply {
+        #[clap(short, long)]
+        file: PathBuf,
+    },
+}
+
+/// How long a drone may go without publishing its status before watch mode
+/// stops listing it, matching the maximum age of drone status messages.
+const DRONE_STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often watch mode redraws its table, if anything changed.
+const WATCH_REDRAW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Byte sent by Ctrl-], which detaches from an attached terminal.
+const DETACH_KEY: u8 = 0x1d;
+
+/// How often an attached terminal checks whether it was resized.
+const RESIZE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long to wait for the leader to announce itself. The leader does so
+/// several times per lease, and leases default to ten seconds.
+const LEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ask the user to confirm a destructive action, unless they passed --yes.
+fn confirm(action: &str, yes: bool) -> Result<()> {
+    if yes {
+        return Ok(());
+    }
+
+    if !std::io::stdin().is_tty() {
+        return Err(anyhow!("Pass --yes to {} without confirmation.", action));
+    }
+
+    print!("{} {}? [y/N] ", "Really".bright_yellow(), action);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(anyhow!("Cancelled.")),
+    }
+}
+
+/// Parse every document of a YAML file as a [ClusterConfig].
+fn parse_cluster_configs(contents: &str) -> Result<Vec<ClusterConfig>> {
+    serde_yaml::Deserializer::from_str(contents)
+        .map(|document| Ok(ClusterConfig::deserialize(document)?))
+        .collect()
+}
+
+fn drone_row(drone: &DroneStatusMessage) -> String {
+    let mut details = Vec::new();
+    if let Some(running_backends) = drone.running_backends {
+        details.push(format!("{} backends", running_backends));
+    }
+    if let Some(remaining_capacity) = drone.remaining_capacity {
+        details.push(format!("{} remaining", remaining_capacity));
+    }
+    if let Some(host_metrics) = &drone.host_metrics {
+        if let Some(load_average) = host_metrics.load_average {
+            details.push(format!("load {:.2}", load_average));
+        }
+        if let Some(free_memory_bytes) = host_metrics.free_memory_bytes {
+            details.push(format!("{} MiB free", free_memory_bytes >> 20));
+        }
+        if let Some(disk_free_bytes) = host_metrics.docker_disk_free_bytes {
+            details.push(format!("{} GiB disk free", disk_free_bytes >> 30));
+        }
+        details.push(format!("{} images", host_metrics.cached_images.len()));
+        if let Some(reclaimed_image_bytes) = host_metrics.reclaimed_image_bytes {
+            details.push(format!("{} MiB reclaimed", reclaimed_image_bytes >> 20));
+        }
+    }
+
+    let version = if is_compatible(PLANE_VERSION, &drone.drone_version) {
+        drone.drone_version.normal()
+    } else {
+        format!(
+            "{} (incompatible with {})",
+            drone.drone_version, PLANE_VERSION
+        )
+        .red()
+    };
+
+    let state = if drone.draining {
+        "draining".yellow()
+    } else if drone.ready {
+        "ready".green()
+    } else {
+        "not ready".red()
+    };
+
+    format!(
+        "{}\t{}\t{}\t{}\t{}",
+        drone.drone_id.to_string().bright_green(),
+        drone.cluster.to_string().bright_cyan(),
+        state,
+        version,
+        details.join(", ").blue()
+    )
+}
+
+fn backend_row(message: &BackendStateMessage) -> String {
+    let reason: Vec<String> = message
+        .reason
+        .iter()
+        .cloned()
+        .chain(message.termination.iter().map(ToString::to_string))
+        .collect();
+
+    format!(
+        "{}\t{}\t{}\t{}",
+        message.backend.to_string().bright_cyan(),
+        message.state.to_string().bright_magenta(),
+        message.time.to_string().blue(),
+        reason.join("; ").red()
+    )
+}
+
+/// Replace the contents of the terminal with a table, for watch mode.
+fn redraw<'a>(title: &str, rows: impl Iterator<Item = &'a String>) -> Result<()> {
+    // Clear the screen and move the cursor to the top left.
+    print!("\x1B[2J\x1B[H");
+    println!("{}", title);
+    for row in rows {
+        println!("{}", row);
+    }
+    std::io::stdout().flush()?;
+    Ok(())
+}
+
+async fn watch_drones(nats: &TypedNats, output: OutputFormat) -> Result<()> {
+    let mut sub = nats
+        .subscribe_jetstream_from(
+            DroneStatusMessage::subscribe_subject(),
+            DeliverPolicy::LastPerSubject,
+        )
+        .await?;
+    let mut drones: BTreeMap<String, (Instant, String)> = BTreeMap::new();
+    let mut redraw_interval = tokio::time::interval(WATCH_REDRAW_INTERVAL);
+    let mut changed = true;
+
+    loop {
+        tokio::select! {
+            drone = sub.next() => {
+                let drone = match drone {
+                    Some(drone) => drone,
+                    None => return Ok(()),
+                };
+
+                if output == OutputFormat::Json {
+                    println!("{}", serde_json::to_string(&drone)?);
+                } else {
+                    let row = drone_row(&drone);
+                    drones.insert(drone.drone_id.to_string(), (Instant::now(), row));
+                    changed = true;
+                }
+            }
+            _ = redraw_interval.tick(), if output == OutputFormat::Text => {
+                let count = drones.len();
+                drones.retain(|_, (seen, _)| seen.elapsed() < DRONE_STATUS_TIMEOUT);
+                if changed || drones.len() != count {
+                    let title = format!("{} drones (watching):", drones.len());
+                    redraw(&title, drones.values().map(|(_, row)| row))?;
+                    changed = false;
+                }
+            }
+        }
+    }
+}
+
+async fn watch_backends(nats: &TypedNats, output: OutputFormat, all: bool) -> Result<()> {
+    let mut sub = nats
+        .subscribe_jetstream_from(
+            BackendStateMessage::wildcard_subject(),
+            DeliverPolicy::LastPerSubject,
+        )
+        .await?;
+    let mut backends: BTreeMap<String, String> = BTreeMap::new();
+    let mut redraw_interval = tokio::time::interval(WATCH_REDRAW_INTERVAL);
+    let mut changed = true;
+
+    loop {
+        tokio::select! {
+            message = sub.next() => {
+                let message = match message {
+                    Some(message) => message,
+                    None => return Ok(()),
+                };
+
+                let backend = message.backend.to_string();
+                let listed = backends.contains_key(&backend);
+                if all || !message.state.terminal() {
+                    backends.insert(backend, backend_row(&message));
+                } else {
+                    backends.remove(&backend);
+                }
+
+                if output == OutputFormat::Json {
+                    // Terminations of listed backends are still printed, so
+                    // that consumers can tell they are gone.
+                    if all || listed || !message.state.terminal() {
+                        println!("{}", serde_json::to_string(&message)?);
+                    }
+                } else {
+                    changed = true;
+                }
+            }
+            _ = redraw_interval.tick(), if output == OutputFormat::Text && changed => {
+                let title = format!("{} backends (watching):", backends.len());
+                redraw(&title, backends.values())?;
+                changed = false;
+            }
+        }
+    }
+}
+
+/// Subscriptions made before scheduling a backend with `spawn --wait`, so
+/// that none of its states or progress are missed.
+struct SpawnWatch {
+    backend_id: BackendId,
+
+    /// Only states published after subscribing, so that those of an earlier
+    /// backend with the same ID are not mistaken for the new one's.
+    states: JetstreamSubscription<BackendStateMessage>,
+    progress: TypedSubscription<SpawnProgress>,
+}
+
+impl SpawnWatch {
+    async fn new(nats: &TypedNats, request: &ScheduleRequest) -> Result<Self> {
+        let backend_id = request
+            .backend_id
+            .clone()
+            .ok_or_else(|| anyhow!("Expected a backend ID to watch."))?;
+        let progress_id = request
+            .progress_id
+            .as_ref()
+            .ok_or_else(|| anyhow!("Expected a progress ID to watch."))?;
+
+        Ok(SpawnWatch {
+            states: nats
+                .subscribe_jetstream_from(
+                    BackendStateMessage::subscribe_subject(&backend_id),
+                    DeliverPolicy::New,
+                )
+                .await?,
+            progress: nats
+                .subscribe(SpawnProgress::subscribe_subject(
+                    &request.cluster,
+                    progress_id,
+                ))
+                .await?,
+            backend_id,
+        })
+    }
+}
+
+/// Wait for a newly scheduled backend to become ready, printing the progress
+/// of spawning it. Fails if the backend stops before it is ready, or is not
+/// ready within `timeout`.
+async fn wait_ready(
+    nats: &TypedNats,
+    backend_id: &BackendId,
+    watch: SpawnWatch,
+    output: OutputFormat,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let wait = wait_ready_inner(nats, backend_id, watch, output);
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, wait).await.map_err(|_| {
+            anyhow!(
+                "Backend was not ready after {}s; it may still start.",
+                timeout.as_secs()
+            )
+        })?,
+        None => wait.await,
+    }
+}
+
+async fn wait_ready_inner(
+    nats: &TypedNats,
+    backend_id: &BackendId,
+    watch: SpawnWatch,
+    output: OutputFormat,
+) -> Result<()> {
+    let mut progress = watch.progress;
+    let mut states = if watch.backend_id == *backend_id {
+        watch.states
+    } else {
+        // An admission webhook renamed the backend, so its states could not
+        // be subscribed to before it was scheduled. Every state is delivered
+        // instead, so that none reached in the meantime are missed.
+        nats.subscribe_jetstream(BackendStateMessage::subscribe_subject(backend_id))
+            .await?
+    };
+
+    loop {
+        tokio::select! {
+            message = states.next() => {
+                let message =
+                    message.ok_or_else(|| anyhow!("Backend state subscription closed."))?;
+
+                if message.state == BackendState::Ready {
+                    if output == OutputFormat::Text {
+                        println!("{}", "Backend is ready.".bright_green());
+                    }
+                    return Ok(());
+                }
+
+                if message.state.terminal() {
+                    return Err(anyhow!(
+                        "Backend stopped before it was ready: {}",
+                        backend_row(&message)
+                    ));
+                }
+
+                if output == OutputFormat::Text {
+                    println!("State: {}", message.state.to_string().bright_magenta());
+                }
+            }
+            Some(message) = progress.next(), if output == OutputFormat::Text => {
+                match message.value.event {
+                    SpawnProgressEvent::ImagePullProgress { pulled_bytes, total_bytes } => println!(
+                        "Pulling image: {} of {} MiB",
+                        pulled_bytes >> 20,
+                        total_bytes >> 20
+                    ),
+                    SpawnProgressEvent::ContainerCreated => println!("Container created."),
+                    SpawnProgressEvent::PortReady => println!("Backend is accepting connections."),
+                }
+            }
+        }
+    }
+}
+
+fn terminal_size() -> TerminalSize {
+    let (cols, rows) = crossterm::terminal::size().unwrap_or((80, 24));
+    TerminalSize { cols, rows }
+}
+
+/// Relay the local terminal to an attached backend until the command exits
+/// or the user detaches. Returns the command's exit code, or zero if the user
+/// detached.
+async fn relay_terminal(
+    nats: &TypedNats,
+    request: &AttachRequest,
+    output: &mut TypedSubscription<AttachOutputMessage>,
+) -> Result<i64> {
+    let send = |input: AttachInput| {
+        let message = AttachInputMessage {
+            cluster_id: request.cluster_id.clone(),
+            backend_id: request.backend_id.clone(),
+            attach_id: request.attach_id.clone(),
+            input,
+        };
+        async move { nats.publish(&message).await }
+    };
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+    let mut buffer = [0; 1024];
+    let mut size = request.size;
+    let mut resize_interval = tokio::time::interval(RESIZE_POLL_INTERVAL);
+
+    loop {
+        tokio::select! {
+            message = output.next() => match message.map(|message| message.value.chunk) {
+                Some(AttachOutputChunk::Data(data)) => {
+                    stdout.write_all(&data).await?;
+                    stdout.flush().await?;
+                }
+                Some(AttachOutputChunk::Exit { code }) => return Ok(code.unwrap_or(1)),
+                None => return Err(anyhow!("Output subscription closed.")),
+            },
+            read = stdin.read(&mut buffer) => {
+                let data = &buffer[..read?];
+                match data.iter().position(|byte| *byte == DETACH_KEY) {
+                    Some(detach) => {
+                        if detach > 0 {
+                            send(AttachInput::Data(data[..detach].to_vec())).await?;
+                        }
+                        send(AttachInput::Detach).await?;
+                        return Ok(0);
+                    }
+                    None if data.is_empty() => {
+                        send(AttachInput::Detach).await?;
+                        return Ok(0);
+                    }
+                    None => send(AttachInput::Data(data.to_vec())).await?,
+                }
+            }
+            _ = resize_interval.tick() => {
+                let new_size = terminal_size();
+                if new_size != size {
+                    size = new_size;
+                    send(AttachInput::Resize(size)).await?;
+                }
+            }
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts = Opts::parse();
-    tracing_subscriber::fmt().init();
+    // Logs go to stderr so that they do not interfere with machine-readable output.
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+
+    if let Command::GenerateSigningKey = opts.command {
+        let private_key = SigningKey::generate()?;
+        let public_key = SigningKey::from_seed(&private_key)?.public_key();
+
+        if opts.output == OutputFormat::Json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&json!({
+                    "private_key": private_key,
+                    "public_key": public_key,
+                }))?
+            );
+        } else {
+            println!(
+                "Private key (pass to --signing-key): {}",
+                private_key.bright_red()
+            );
+            println!(
+                "Public key (set as signing_public_key for the cluster): {}",
+                public_key.bright_green()
+            );
+        }
+        return Ok(());
+    }
+
+    match &opts.command {
+        Command::SignArtifact {
+            path,
+            version,
+            release_key,
+        } => {
+            let artifact = std::fs::read(path)?;
+            println!(
+                "{}",
+                SigningKey::from_seed(release_key)?.sign_release(version, &artifact)
+            );
+            return Ok(());
+        }
+        Command::Completions { shell } => {
+            print_completions(*shell, Opts::command());
+            return Ok(());
+        }
+        Command::Complete { words } => {
+            if let Some(kind) = completion_kind(&Opts::command(), words) {
+                let url = nats_url(words)
+                    .or(opts.nats.as_deref())
+                    .unwrap_or("nats://localhost");
+                for name in fetch_names(url, kind).await? {
+                    println!("{}", name);
+                }
+            }
+            return Ok(());
+        }
+        _ => (),
+    }
+
+    let signing_key = opts
+        .signing_key
+        .as_deref()
+        .map(SigningKey::from_seed)
+        .transpose()?;
 
     let nats = NatsConnectionSpec::from_url(opts.nats.as_deref().unwrap_or("nats://localhost"))?
         .connect()
-        .await?;
+        .await?
+        .with_auth_token(opts.auth_token)
+        .with_signing_key(signing_key);
 
     match opts.command {
         Command::Status { backend } => {
@@ -75,15 +873,113 @@ async fn main() -> Result<()> {
             };
 
             while let Some(message) = sub.next().await {
+                match opts.output {
+                    OutputFormat::Json => println!("{}", serde_json::to_string(&message)?),
+                    OutputFormat::Text => println!("{}", backend_row(&message)),
+                }
+            }
+        }
+        Command::Timings { backend } => {
+            let timeline = nats
+                .get_all(
+                    &BackendStateMessage::subscribe_subject(&BackendId::new(backend.clone())),
+                    DeliverPolicy::All,
+                )
+                .await?
+                .into_iter()
+                .filter_map(|message| message.timeline)
+                .last()
+                .ok_or_else(|| anyhow!("No spawn timeline found for backend {}.", backend))?;
+
+            if opts.output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&timeline)?);
+                return Ok(());
+            }
+
+            let mut total = chrono::Duration::zero();
+            for (step, duration) in timeline.steps() {
+                total = total + duration;
+                println!(
+                    "{}\t{}ms",
+                    step.bright_cyan(),
+                    duration.num_milliseconds().to_string().bright_magenta()
+                );
+            }
+            println!(
+                "{}\t{}ms",
+                "total".bright_green(),
+                total.num_milliseconds().to_string().bright_magenta()
+            );
+            if timeline.ready_at.is_none() {
+                println!("{}", "Backend has not become ready.".yellow());
+            }
+        }
+        Command::Stats { backend } => {
+            let response = nats
+                .request(&StatsRequest {
+                    backend_id: BackendId::new(backend.clone()),
+                })
+                .await
+                .with_context(|| format!("No drone responded for backend {}.", backend))?
+                .map_err(|error| anyhow!("Could not get stats: {}", error))?;
+
+            if opts.output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&response.stats)?);
+                return Ok(());
+            }
+
+            let stats = match response.stats {
+                Some(stats) => stats,
+                None => {
+                    println!("{}", "Backend has not been sampled yet.".yellow());
+                    return Ok(());
+                }
+            };
+
+            println!(
+                "{}\t{}",
+                "cpu".bright_cyan(),
+                format!("{:.1}%", stats.cpu_use_percent).bright_magenta()
+            );
+            println!(
+                "{}\t{}",
+                "memory".bright_cyan(),
+                format!("{:.1}%", stats.mem_use_percent).bright_magenta()
+            );
+            if let (Some(rx), Some(tx)) = (stats.network_rx_bytes, stats.network_tx_bytes) {
+                println!(
+                    "{}\t{} bytes received, {} bytes sent",
+                    "network".bright_cyan(),
+                    rx.to_string().bright_magenta(),
+                    tx.to_string().bright_magenta()
+                );
+            }
+            if let (Some(rx), Some(tx)) =
+                (stats.network_rx_bytes_delta, stats.network_tx_bytes_delta)
+            {
+                println!(
+                    "{}\t{} bytes received, {} bytes sent since the previous sample",
+                    "network".bright_cyan(),
+                    rx.to_string().bright_magenta(),
+                    tx.to_string().bright_magenta()
+                );
+            }
+            if let (Some(read), Some(write)) =
+                (stats.block_read_bytes_delta, stats.block_write_bytes_delta)
+            {
                 println!(
-                    "{}\t{}\t{}",
-                    message.backend.to_string().bright_cyan(),
-                    message.state.to_string().bright_magenta(),
-                    message.time.to_string().blue()
+                    "{}\t{} bytes read, {} bytes written since the previous sample",
+                    "disk".bright_cyan(),
+                    read.to_string().bright_magenta(),
+                    write.to_string().bright_magenta()
                 );
             }
         }
-        Command::ListDrones => {
+        Command::ListDrones { watch } => {
+            if watch {
+                return watch_drones(&nats, opts.output).await;
+            }
+
             let drones = nats
                 .get_all(
                     &DroneStatusMessage::subscribe_subject(),
@@ -91,44 +987,203 @@ async fn main() -> Result<()> {
                 )
                 .await?;
 
+            if opts.output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&drones)?);
+                return Ok(());
+            }
+
             println!("Found {} drones:", drones.len());
 
             for drone in drones {
-                println!(
-                    "{}\t{}",
-                    drone.drone_id.to_string().bright_green(),
-                    drone.cluster.to_string().bright_cyan()
-                );
+                println!("{}", drone_row(&drone));
+            }
+        }
+        Command::ListBackends { watch, all } => {
+            if watch {
+                return watch_backends(&nats, opts.output, all).await;
+            }
+
+            let backends: Vec<BackendStateMessage> = nats
+                .get_all(
+                    &BackendStateMessage::wildcard_subject(),
+                    DeliverPolicy::LastPerSubject,
+                )
+                .await?
+                .into_iter()
+                .filter(|message| all || !message.state.terminal())
+                .collect();
+
+            if opts.output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&backends)?);
+                return Ok(());
+            }
+
+            println!("Found {} backends:", backends.len());
+
+            for message in backends {
+                println!("{}", backend_row(&message));
             }
         }
         Command::Spawn {
             image,
             cluster,
             timeout,
+            max_lifetime,
+            port_ready_timeout,
+            count,
+            name,
+            dry_run,
+            deadline_ms,
+            arch,
+            affinity_group,
+            strict_affinity,
+            log_level,
+            otel_endpoint,
+            trace_sample_rate,
+            credentials_file,
+            wait,
+            wait_timeout,
         } => {
-            let result = nats
-                .request(&ScheduleRequest {
-                    backend_id: None,
-                    cluster: ClusterName::new(&cluster),
-                    max_idle_secs: Duration::from_secs(timeout),
-                    metadata: HashMap::new(),
-                    executable: DockerExecutableConfig {
-                        image,
-                        env: HashMap::new(),
-                        credentials: None,
-                        resource_limits: ResourceLimits::default(),
-                    },
-                    require_bearer_token: false,
-                })
-                .await?;
+            let credentials = match credentials_file {
+                Some(path) => {
+                    let contents = std::fs::read_to_string(&path)
+                        .map_err(|error| anyhow!("Could not read {:?}: {}", path, error))?;
+                    let credentials: DockerCredentials = serde_json::from_str(&contents)
+                        .map_err(|error| anyhow!("Invalid credentials in {:?}: {}", path, error))?;
+                    Some(credentials)
+                }
+                None => None,
+            };
+
+            // When waiting, the backend's ID is chosen here, so that its
+            // states can be subscribed to before it is scheduled.
+            let backend_id = match name {
+                Some(name) => Some(BackendId::new(name)),
+                None if wait => Some(BackendId::new_random()),
+                None => None,
+            };
+
+            let request = ScheduleRequest {
+                backend_id,
+                cluster: ClusterName::new(&cluster),
+                max_idle_secs: Some(Duration::from_secs(timeout)),
+                max_lifetime_secs: max_lifetime.map(Duration::from_secs),
+                port_ready_timeout_secs: port_ready_timeout.map(Duration::from_secs),
+                metadata: HashMap::new(),
+                executable: DockerExecutableConfig {
+                    image,
+                    env: HashMap::new(),
+                    credentials,
+                    resource_limits: ResourceLimits::default(),
+                    egress_policy: EgressPolicy::default(),
+                },
+                require_bearer_token: false,
+                group: None,
+                dry_run,
+                schedule_deadline_ms: deadline_ms.map(Duration::from_millis),
+                idle_policy: IdlePolicy::default(),
+                arch,
+                progress_id: wait.then(ProgressId::new_random),
+                affinity_group: affinity_group.map(|name| AffinityGroup {
+                    name,
+                    strict: strict_affinity,
+                }),
+                observability: ObservabilityOptions {
+                    log_level,
+                    otel_endpoint,
+                    trace_sample_rate,
+                },
+            };
+
+            if count != 1 {
+                let results = nats
+                    .request(&BatchScheduleRequest { request, count })
+                    .await?;
+
+                if opts.output == OutputFormat::Json {
+                    println!("{}", serde_json::to_string_pretty(&results)?);
+                    return Ok(());
+                }
+
+                let scheduled = results
+                    .iter()
+                    .filter(|result| {
+                        matches!(
+                            result,
+                            ScheduleResponse::Scheduled { .. } | ScheduleResponse::DryRun { .. }
+                        )
+                    })
+                    .count();
+                println!("Scheduled {} of {} backends:", scheduled, results.len());
+
+                for result in results {
+                    match result {
+                        ScheduleResponse::Scheduled {
+                            drone,
+                            backend_id,
+                            url,
+                            ..
+                        } => println!(
+                            "{}\t{}",
+                            url.unwrap_or_else(|| format!("https://{}.{}", backend_id, cluster))
+                                .bright_green(),
+                            drone.to_string().bright_blue()
+                        ),
+                        ScheduleResponse::DryRun { drone, .. } => println!(
+                            "{}\t{}",
+                            "Dry run passed".bright_green(),
+                            drone.to_string().bright_blue()
+                        ),
+                        ScheduleResponse::Error(error) => {
+                            println!("{} {}", "Not scheduled:".bright_red(), error)
+                        }
+                        ScheduleResponse::Throttled { retry_after_ms } => println!(
+                            "{} throttled; retry after {}ms",
+                            "Not scheduled:".bright_red(),
+                            retry_after_ms
+                        ),
+                    }
+                }
+
+                return Ok(());
+            }
+
+            let watch = if wait {
+                Some(SpawnWatch::new(&nats, &request).await?)
+            } else {
+                None
+            };
+            let wait_timeout = wait_timeout.map(Duration::from_secs);
+
+            let result = nats.request(&request).await?;
 
             match result {
                 ScheduleResponse::Scheduled {
                     drone,
                     backend_id,
                     bearer_token,
+                    url,
                 } => {
-                    let url = format!("https://{}.{}", backend_id, cluster);
+                    // Older controllers do not send the URL.
+                    let url = url.unwrap_or_else(|| format!("https://{}.{}", backend_id, cluster));
+
+                    if opts.output == OutputFormat::Json {
+                        // When waiting, the result is only printed once the
+                        // backend is ready.
+                        if let Some(watch) = watch {
+                            wait_ready(&nats, &backend_id, watch, opts.output, wait_timeout)
+                                .await?;
+                        }
+
+                        let result = json!({
+                            "url": url,
+                            "drone": drone,
+                            "backend_id": backend_id,
+                            "bearer_token": bearer_token,
+                        });
+                        println!("{}", serde_json::to_string_pretty(&result)?);
+                        return Ok(());
+                    }
 
                     println!("Backend scheduled.");
                     println!("URL: {}", url.bright_green());
@@ -137,11 +1192,174 @@ async fn main() -> Result<()> {
                     if let Some(bearer_token) = bearer_token {
                         println!("Bearer token: {}", bearer_token.bright_blue());
                     }
+
+                    if let Some(watch) = watch {
+                        wait_ready(&nats, &backend_id, watch, opts.output, wait_timeout).await?;
+                    }
                 }
-                ScheduleResponse::NoDroneAvailable => tracing::error!(
-                    %cluster,
-                    "Could not schedule backend because no drone was available for cluster."
-                ),
+                ScheduleResponse::DryRun {
+                    ref drone,
+                    ref spawn_request,
+                } => {
+                    if opts.output == OutputFormat::Json {
+                        println!("{}", serde_json::to_string_pretty(&result)?);
+                        return Ok(());
+                    }
+
+                    println!("Dry run passed; nothing was spawned.");
+                    println!("Drone: {}", drone.to_string().bright_blue());
+                    println!(
+                        "Idle timeout: {}s",
+                        spawn_request
+                            .max_idle_secs
+                            .as_secs()
+                            .to_string()
+                            .bright_blue()
+                    );
+                    println!(
+                        "Resource limits: {}",
+                        serde_json::to_string(&spawn_request.executable.resource_limits)?
+                            .bright_blue()
+                    );
+                }
+                ScheduleResponse::Error(ref error) => {
+                    if opts.output == OutputFormat::Json {
+                        println!("{}", serde_json::to_string_pretty(&result)?);
+                    }
+
+                    tracing::error!(%cluster, ?error, "Could not schedule backend: {}", error)
+                }
+                ScheduleResponse::Throttled { retry_after_ms } => {
+                    if opts.output == OutputFormat::Json {
+                        println!("{}", serde_json::to_string_pretty(&result)?);
+                    }
+
+                    tracing::error!(
+                        %cluster,
+                        retry_after_ms,
+                        "Controller is throttling spawn requests; retry after {}ms.",
+                        retry_after_ms
+                    )
+                }
+            }
+        }
+        Command::ImageStats { image } => {
+            let stats = nats.request(&ImageStatsRequest { image }).await?;
+
+            if opts.output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+                return Ok(());
+            }
+
+            println!("Found {} images:", stats.len());
+
+            for stat in stats {
+                let failure_rate = stat
+                    .failure_rate()
+                    .map(|rate| format!("{:.1}%", rate * 100.))
+                    .unwrap_or_else(|| "-".into());
+                let cold_start = stat
+                    .mean_cold_start_ms
+                    .map(|ms| format!("{}ms", ms))
+                    .unwrap_or_else(|| "-".into());
+                let lifetime = stat
+                    .mean_lifetime_secs
+                    .map(|secs| format!("{}s", secs))
+                    .unwrap_or_else(|| "-".into());
+
+                println!(
+                    "{}\tspawned: {}\tfailure rate: {}\tcold start: {}\tlifetime: {}",
+                    stat.image.bright_magenta(),
+                    stat.spawned.to_string().bold(),
+                    failure_rate.bright_red(),
+                    cold_start.bright_cyan(),
+                    lifetime.bright_blue(),
+                );
+            }
+        }
+        Command::ListClusters => {
+            let clusters = nats.request(&ClusterListRequest::default()).await?;
+
+            if opts.output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&clusters)?);
+                return Ok(());
+            }
+
+            println!("Found {} clusters:", clusters.len());
+
+            for cluster in clusters {
+                let last_dns_record = cluster
+                    .last_dns_record
+                    .map(|time| time.to_rfc3339())
+                    .unwrap_or_else(|| "-".into());
+
+                println!(
+                    "{}\tdrones: {}\tbackends: {}\tlast DNS record: {}",
+                    cluster.cluster.to_string().bright_cyan(),
+                    cluster.ready_drones.to_string().bold(),
+                    cluster.running_backends.to_string().bold(),
+                    last_dns_record.blue(),
+                );
+            }
+        }
+        Command::Where { backend } => {
+            let location = nats
+                .request(&BackendLookupRequest {
+                    backend: BackendId::new(backend.clone()),
+                })
+                .await?
+                .ok_or_else(|| anyhow!("No state found for backend {}.", backend))?;
+
+            if opts.output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&location)?);
+                return Ok(());
+            }
+
+            let or_unknown = |value: Option<String>| value.unwrap_or_else(|| "unknown".into());
+            println!(
+                "Cluster: {}",
+                or_unknown(location.cluster.map(|cluster| cluster.to_string())).bright_cyan()
+            );
+            println!(
+                "Drone: {}",
+                or_unknown(location.drone.map(|drone| drone.to_string())).bright_blue()
+            );
+            println!(
+                "State: {} (since {})",
+                format!("{:?}", location.state).bright_magenta(),
+                location.time.to_rfc3339()
+            );
+            println!("URL: {}", or_unknown(location.url).bright_green());
+        }
+        Command::Leader => {
+            let mut sub = nats.subscribe(SchedulerLeader::subscribe_subject()).await?;
+            let leader = tokio::time::timeout(LEADER_TIMEOUT, sub.next())
+                .await
+                .ok()
+                .flatten()
+                .ok_or_else(|| anyhow!("No controller announced itself as leader."))?;
+
+            println!(
+                "{}\tleader since {}",
+                leader.value.controller.bright_green(),
+                leader.value.since.to_string().blue()
+            );
+        }
+        Command::Doctor { max_pending } => {
+            let alerts = check_jetstream(&nats, max_pending).await?;
+
+            if opts.output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&alerts)?);
+            } else if alerts.is_empty() {
+                println!("{}", "No problems found.".bright_green());
+            } else {
+                for alert in &alerts {
+                    println!("{}", alert.to_string().yellow());
+                }
+            }
+
+            if !alerts.is_empty() {
+                return Err(anyhow!("Found {} problems with JetStream.", alerts.len()));
             }
         }
         Command::ListDns => {
@@ -152,6 +1370,11 @@ async fn main() -> Result<()> {
                 )
                 .await?;
 
+            if opts.output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&results)?);
+                return Ok(());
+            }
+
             println!("Found {} DNS records:", results.len());
 
             for result in results {
@@ -165,15 +1388,206 @@ async fn main() -> Result<()> {
             }
         }
         Command::Terminate {
-            cluster, backend, ..
+            cluster_or_backend,
+            backend,
+            grace,
+        } => {
+            let (cluster, backend) = match backend {
+                Some(backend) => (Some(cluster_or_backend), backend),
+                None => (None, cluster_or_backend),
+            };
+            let description = match &cluster {
+                Some(cluster) => format!("terminate backend {} on {}", backend, cluster),
+                None => format!("terminate backend {}", backend),
+            };
+            confirm(&description, opts.yes)?;
+            let backend = BackendId::new(backend);
+            let drain = grace.is_some();
+            let grace_period_secs = Duration::from_secs(grace.unwrap_or_default());
+            let result = match cluster {
+                Some(cluster) => {
+                    nats.request(&TerminationRequest {
+                        backend_id: backend,
+                        cluster_id: ClusterName::new(&cluster),
+                        drain,
+                        grace_period_secs,
+                    })
+                    .await?
+                }
+                None => {
+                    nats.request(&TerminateBackendRequest {
+                        backend,
+                        drain,
+                        grace_period_secs,
+                    })
+                    .await?
+                }
+            };
+
+            match result {
+                Ok(()) => println!("{}", "Terminated successfully".bright_green()),
+                Err(error) => return Err(anyhow!("Could not terminate: {}", error)),
+            }
+        }
+        Command::TerminateAll {
+            drone,
+            cluster,
+            state,
+        } => {
+            let description = match state {
+                Some(state) => format!(
+                    "terminate all {} backends on drone {} on {}",
+                    state.to_string(),
+                    drone,
+                    cluster
+                ),
+                None => format!("terminate all backends on drone {} on {}", drone, cluster),
+            };
+            confirm(&description, opts.yes)?;
+            let result = nats
+                .request(&TerminateAllRequest {
+                    cluster_id: ClusterName::new(&cluster),
+                    drone_id: DroneId::new(drone),
+                    state,
+                })
+                .await?;
+
+            match result {
+                Ok(backends) => {
+                    for backend in &backends {
+                        println!("{}", backend.to_string().bright_cyan());
+                    }
+                    println!(
+                        "{}",
+                        format!("Terminated {} backends.", backends.len()).bright_green()
+                    );
+                }
+                Err(error) => return Err(anyhow!("Could not terminate: {}", error)),
+            }
+        }
+        Command::Migrate {
+            cluster,
+            backend,
+            target_drone,
         } => {
-            nats.request(&TerminationRequest {
+            let result = nats
+                .request(&MigrateBackend {
+                    cluster_id: ClusterName::new(&cluster),
+                    backend_id: BackendId::new(backend),
+                    target_drone: DroneId::new(target_drone),
+                })
+                .await?;
+
+            match result {
+                Ok(()) => println!("{}", "Migrated successfully".bright_green()),
+                Err(error) => return Err(anyhow!("Could not migrate: {}", error)),
+            }
+        }
+        Command::Exec {
+            cluster,
+            backend,
+            command,
+        } => {
+            let request = ExecRequest {
+                cluster_id: ClusterName::new(&cluster),
                 backend_id: BackendId::new(backend),
+                exec_id: Uuid::new_v4().to_string(),
+                command,
+            };
+
+            // Subscribe before sending the request, so that no output is missed.
+            let mut sub = nats
+                .subscribe(ExecOutputMessage::subscribe_subject(&request))
+                .await?;
+
+            if let ExecResponse::Failed { reason } = nats.request(&request).await? {
+                return Err(anyhow!("Could not run command: {}", reason));
+            }
+
+            while let Some(message) = sub.next().await {
+                if opts.output == OutputFormat::Json {
+                    println!("{}", serde_json::to_string(&message.value.chunk)?);
+                }
+
+                match message.value.chunk {
+                    ExecOutputChunk::Stdout(text) => {
+                        if opts.output == OutputFormat::Text {
+                            print!("{}", text);
+                            std::io::stdout().flush()?;
+                        }
+                    }
+                    ExecOutputChunk::Stderr(text) => {
+                        if opts.output == OutputFormat::Text {
+                            eprint!("{}", text);
+                        }
+                    }
+                    ExecOutputChunk::Exit { code } => {
+                        let code = code.unwrap_or(1);
+                        if code != 0 {
+                            std::process::exit(code as i32);
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+        Command::Attach {
+            cluster,
+            backend,
+            command,
+        } => {
+            let command = if command.is_empty() {
+                vec!["/bin/sh".to_string()]
+            } else {
+                command
+            };
+            let request = AttachRequest {
                 cluster_id: ClusterName::new(&cluster),
-            })
-            .await?;
+                backend_id: BackendId::new(backend),
+                attach_id: Uuid::new_v4().to_string(),
+                command,
+                size: terminal_size(),
+            };
+
+            // Subscribe before sending the request, so that no output is missed.
+            let mut sub = nats
+                .subscribe(AttachOutputMessage::subscribe_subject(&request))
+                .await?;
+
+            if let ExecResponse::Failed { reason } = nats.request(&request).await? {
+                return Err(anyhow!("Could not attach: {}", reason));
+            }
+
+            crossterm::terminal::enable_raw_mode()?;
+            let result = relay_terminal(&nats, &request, &mut sub).await;
+            crossterm::terminal::disable_raw_mode()?;
+
+            // Reading stdin blocks a thread which cannot be cancelled, so exit
+            // rather than waiting for the runtime to shut down.
+            std::process::exit(result? as i32);
+        }
+        Command::Tag {
+            cluster,
+            backend,
+            entries,
+        } => {
+            let metadata = nats
+                .request(&UpdateBackendMetadata {
+                    cluster_id: ClusterName::new(&cluster),
+                    backend_id: BackendId::new(backend),
+                    metadata: entries.into_iter().collect(),
+                })
+                .await?;
+
+            if opts.output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&metadata)?);
+                return Ok(());
+            }
 
-            println!("{}", "Terminated successfully".bright_green());
+            println!("{}", "Updated backend metadata.".bright_green());
+            for (key, value) in metadata {
+                println!("{}\t{}", key.bright_cyan(), value);
+            }
         }
         Command::Drain {
             drone,
@@ -181,6 +1595,9 @@ async fn main() -> Result<()> {
             cancel,
         } => {
             let drain = !cancel;
+            if drain {
+                confirm(&format!("drain drone {} on {}", drone, cluster), opts.yes)?;
+            }
             nats.request(&DrainDrone {
                 cluster: ClusterName::new(&cluster),
                 drone: DroneId::new(drone),
@@ -194,6 +1611,114 @@ async fn main() -> Result<()> {
                 println!("{}", "Draining cancelled on drone.".bright_green());
             }
         }
+        Command::ApproveDrone {
+            drone,
+            cluster,
+            key_fingerprint,
+        } => {
+            confirm(
+                &format!(
+                    "approve drone {} with key {} to join {}",
+                    drone, key_fingerprint, cluster
+                ),
+                opts.yes,
+            )?;
+            let result = nats
+                .request(&ApproveDrone {
+                    drone: DroneId::new(drone),
+                    cluster: ClusterName::new(&cluster),
+                    key_fingerprint,
+                })
+                .await?;
+
+            match result {
+                Ok(()) => println!("{}", "Drone approved.".bright_green()),
+                Err(error) => return Err(anyhow!("Could not approve drone: {}", error)),
+            }
+        }
+        Command::Maintenance {
+            drone,
+            cluster,
+            start,
+            duration,
+            drain,
+        } => {
+            if drain {
+                confirm(
+                    &format!(
+                        "terminate the backends on drone {} when maintenance starts",
+                        drone
+                    ),
+                    opts.yes,
+                )?;
+            }
+            let window = MaintenanceWindow {
+                start: start.unwrap_or_else(Utc::now),
+                duration: Duration::from_secs(duration),
+                drain,
+            };
+            nats.request(&ScheduleMaintenance {
+                cluster: ClusterName::new(&cluster),
+                drone: DroneId::new(drone),
+                window: window.clone(),
+            })
+            .await?;
+
+            println!(
+                "Maintenance scheduled from {} to {}.",
+                window.start.to_string().bright_green(),
+                window.end().to_string().bright_green()
+            );
+        }
+        Command::InitStreams {
+            retention,
+            replicas,
+            max_age,
+            config,
+        } => {
+            let config = match config {
+                Some(file) => StreamsConfig::from_file(&file)
+                    .map_err(|error| anyhow!("Invalid streams config in {:?}: {}", file, error))?,
+                None => StreamsConfig::uniform(StreamOptions {
+                    retention: retention.map(RetentionPolicy::from),
+                    replicas,
+                    max_age_secs: max_age,
+                    consumers: Vec::new(),
+                }),
+            };
+            init_streams(&nats, &config).await?;
+
+            println!("{}", "Streams initialized.".bright_green());
+        }
+        Command::Apply { file } => {
+            let contents = std::fs::read_to_string(&file)
+                .map_err(|error| anyhow!("Could not read {:?}: {}", file, error))?;
+            let configs = parse_cluster_configs(&contents)
+                .map_err(|error| anyhow!("Invalid cluster config in {:?}: {}", file, error))?;
+
+            for config in configs {
+                let cluster = config.cluster.clone();
+                match nats.request(&ApplyClusterConfig { config }).await? {
+                    Ok(()) => println!(
+                        "Applied config for cluster {}.",
+                        cluster.to_string().bright_green()
+                    ),
+                    Err(error) => {
+                        return Err(anyhow!(
+                            "Could not apply config for cluster {}: {}",
+                            cluster,
+                            error
+                        ))
+                    }
+                }
+            }
+        }
+        Command::GenerateSigningKey
+        | Command::SignArtifact { .. }
+        | Command::Completions { .. }
+        | Command::Complete { .. } => {
+            unreachable!("Handled before connecting to NATS.")
+        }
     }
 
     Ok(())
@@ -0,0 +1,13 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The gRPC bindings are generated from the protobuf definitions, which
+    // are the source of truth for the API's messages.
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/plane.proto");
+        tonic_build::configure()
+            .build_client(false)
+            .compile(&["proto/plane.proto"], &["proto"])?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,228 @@
+//! Admission control of schedule requests by an external webhook.
+//!
+//! Before scheduling, the controller POSTs each [ScheduleRequest] as JSON to
+//! the webhook, with the principal that sent it in the
+//! [PRINCIPAL_HEADER] header. The webhook responds with an
+//! [AdmissionDecision]: it may approve the request as it is, replace it with
+//! a mutated request (e.g. to inject environment variables or clamp resource
+//! limits), or reject it with a reason which is returned to the caller.
+//!
+//! Mutated requests are still subject to the cluster's policy, so a webhook
+//! cannot raise limits beyond what the cluster allows.
+//!
+//! Requests are reviewed concurrently, so a slow webhook only delays the
+//! requests it is reviewing, and each review is bounded by the configured
+//! timeout.
+
+use crate::auth::Principal;
+use anyhow::{anyhow, Result};
+use plane_core::{error::PlaneError, messages::scheduler::ScheduleRequest};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Header which carries the principal that sent the schedule request.
+pub const PRINCIPAL_HEADER: &str = "x-plane-principal";
+
+fn default_timeout_secs() -> u64 {
+    5
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AdmissionOptions {
+    /// URL the schedule request is POSTed to.
+    pub url: String,
+
+    /// How long to wait for the webhook to respond.
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+
+    /// If true, requests are admitted unchanged when the webhook cannot be
+    /// reached or responds with an error. Otherwise they are rejected.
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+/// Response of the webhook to a schedule request.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "decision", rename_all = "snake_case")]
+pub enum AdmissionDecision {
+    /// Schedule the request as it is.
+    Approve,
+
+    /// Schedule the given request instead. It must be for the same cluster.
+    Mutate { request: Box<ScheduleRequest> },
+
+    /// Do not schedule the request.
+    Reject { reason: String },
+}
+
+impl AdmissionDecision {
+    /// The request to schedule, or the reason it is rejected.
+    fn apply(self, request: &ScheduleRequest) -> Result<ScheduleRequest, PlaneError> {
+        match self {
+            AdmissionDecision::Approve => Ok(request.clone()),
+            AdmissionDecision::Mutate { request: mutated }
+                if mutated.cluster != request.cluster =>
+            {
+                Err(PlaneError::Internal {
+                    reason: "Admission webhook may not change the cluster of a request.".into(),
+                })
+            }
+            AdmissionDecision::Mutate { request: mutated } => Ok(*mutated),
+            AdmissionDecision::Reject { reason } => Err(PlaneError::AdmissionDenied { reason }),
+        }
+    }
+}
+
+pub struct AdmissionWebhook {
+    options: AdmissionOptions,
+    client: Client,
+}
+
+impl AdmissionWebhook {
+    pub fn new(options: AdmissionOptions) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(options.timeout_secs))
+            .build()?;
+
+        Ok(AdmissionWebhook { options, client })
+    }
+
+    async fn decide(
+        &self,
+        principal: &Principal,
+        request: &ScheduleRequest,
+    ) -> Result<AdmissionDecision> {
+        // The client's timeout does not bound a webhook which trickles its
+        // response body, so the whole exchange is bounded too.
+        let timeout = Duration::from_secs(self.options.timeout_secs);
+        tokio::time::timeout(timeout, self.send(principal, request))
+            .await
+            .map_err(|_| anyhow!("Admission webhook did not respond within {:?}.", timeout))?
+    }
+
+    async fn send(
+        &self,
+        principal: &Principal,
+        request: &ScheduleRequest,
+    ) -> Result<AdmissionDecision> {
+        let response = self
+            .client
+            .post(&self.options.url)
+            .header(PRINCIPAL_HEADER, principal.to_string())
+            .json(request)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "Admission webhook responded with status {}.",
+                response.status()
+            ));
+        }
+
+        Ok(response.json().await?)
+    }
+
+    /// Ask the webhook whether to admit a request. Returns the request to
+    /// schedule, or the reason it is rejected.
+    pub async fn review(
+        &self,
+        principal: &Principal,
+        request: &ScheduleRequest,
+    ) -> Result<ScheduleRequest, PlaneError> {
+        match self.decide(principal, request).await {
+            Ok(decision) => {
+                tracing::info!(?decision, %principal, "Admission webhook decided.");
+                decision.apply(request)
+            }
+            Err(error) if self.options.fail_open => {
+                tracing::warn!(?error, "Admission webhook failed; admitting request.");
+                Ok(request.clone())
+            }
+            Err(error) => {
+                tracing::warn!(?error, "Admission webhook failed; rejecting request.");
+                Err(PlaneError::Internal {
+                    reason: format!("Admission webhook failed: {}", error),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plane_core::{
+        messages::agent::{DockerExecutableConfig, IdlePolicy, ObservabilityOptions},
+        types::{BackendId, ClusterName},
+    };
+
+    fn request(cluster: &str) -> ScheduleRequest {
+        ScheduleRequest {
+            cluster: ClusterName::new(cluster),
+            backend_id: None,
+            max_idle_secs: None,
+            max_lifetime_secs: None,
+            port_ready_timeout_secs: None,
+            metadata: Default::default(),
+            executable: DockerExecutableConfig {
+                image: "ghcr.io/drifting-in-space/test-image:latest".into(),
+                env: Default::default(),
+                credentials: None,
+                resource_limits: Default::default(),
+                egress_policy: Default::default(),
+            },
+            require_bearer_token: false,
+            group: None,
+            dry_run: false,
+            schedule_deadline_ms: None,
+            idle_policy: IdlePolicy::default(),
+            arch: None,
+            progress_id: None,
+            affinity_group: None,
+            observability: ObservabilityOptions::default(),
+        }
+    }
+
+    #[test]
+    fn test_parse_decisions() {
+        let decision: AdmissionDecision =
+            serde_json::from_str(r#"{"decision": "approve"}"#).unwrap();
+        assert!(matches!(decision, AdmissionDecision::Approve));
+
+        let decision: AdmissionDecision =
+            serde_json::from_str(r#"{"decision": "reject", "reason": "Over quota."}"#).unwrap();
+        assert!(
+            matches!(decision, AdmissionDecision::Reject { reason } if reason == "Over quota.")
+        );
+
+        let body = serde_json::json!({
+            "decision": "mutate",
+            "request": request("plane.test"),
+        });
+        let decision: AdmissionDecision = serde_json::from_value(body).unwrap();
+        assert!(matches!(decision, AdmissionDecision::Mutate { .. }));
+    }
+
+    #[test]
+    fn test_apply_mutation() {
+        let original = request("plane.test");
+
+        let mut mutated = request("plane.test");
+        mutated.backend_id = Some(BackendId::new("mutated".into()));
+        let decision = AdmissionDecision::Mutate {
+            request: Box::new(mutated),
+        };
+        assert_eq!(
+            Some(BackendId::new("mutated".into())),
+            decision.apply(&original).unwrap().backend_id
+        );
+
+        let decision = AdmissionDecision::Mutate {
+            request: Box::new(request("other.test")),
+        };
+        assert!(decision.apply(&original).is_err());
+    }
+}
@@ -0,0 +1,435 @@
+//! Authentication of control requests sent to the controller.
+//!
+//! Clients attach a bearer token to requests (see
+//! [plane_core::nats::AUTHORIZATION_HEADER]), which the controller hands to an
+//! [AuthProvider] to find out who sent the request. Every operation is then
+//! logged with that principal. Crates which embed the controller can implement
+//! their own provider and pass it in through [crate::plan::SchedulerPlan].
+//!
+//! Client certificate (mTLS) identities are verified by the NATS server rather
+//! than the controller: use NATS's `verify_and_map` to map certificates to NATS
+//! users, and NATS permissions to limit which users may publish control
+//! requests.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use plane_core::nats::{MessageWithResponseHandle, TypedMessage};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+/// The identity a request was authenticated as.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Principal(String);
+
+impl Principal {
+    #[must_use]
+    pub fn new(name: &str) -> Self {
+        Principal(name.to_string())
+    }
+
+    /// Principal of requests which are not authenticated.
+    #[must_use]
+    pub fn anonymous() -> Self {
+        Principal::new("anonymous")
+    }
+}
+
+impl Display for Principal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Credentials presented with a request.
+#[derive(Clone, Debug, Default)]
+pub struct Credentials {
+    pub bearer_token: Option<String>,
+}
+
+impl Credentials {
+    #[must_use]
+    pub fn from_message<T: TypedMessage>(message: &MessageWithResponseHandle<T>) -> Self {
+        Credentials {
+            bearer_token: message.bearer_token().map(str::to_string),
+        }
+    }
+}
+
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    /// Determine who sent a request, or return the reason it is rejected.
+    async fn authenticate(&self, credentials: &Credentials) -> Result<Principal, String>;
+}
+
+/// Accept every request as anonymous.
+pub struct AllowAll;
+
+#[async_trait]
+impl AuthProvider for AllowAll {
+    async fn authenticate(&self, _credentials: &Credentials) -> Result<Principal, String> {
+        Ok(Principal::anonymous())
+    }
+}
+
+/// Compare two byte strings in time which depends only on their lengths, so
+/// that tokens cannot be guessed one byte at a time.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Accept requests bearing one of a fixed set of tokens.
+pub struct StaticTokens {
+    /// Map of principal name to the token it authenticates with.
+    pub tokens: HashMap<String, String>,
+}
+
+#[async_trait]
+impl AuthProvider for StaticTokens {
+    async fn authenticate(&self, credentials: &Credentials) -> Result<Principal, String> {
+        let bearer_token = credentials
+            .bearer_token
+            .as_deref()
+            .ok_or_else(|| "Request has no bearer token.".to_string())?;
+
+        // Every token is compared, so that the time taken does not reveal
+        // which principal matched.
+        let mut principal = None;
+        for (name, token) in &self.tokens {
+            if constant_time_eq(token.as_bytes(), bearer_token.as_bytes()) {
+                principal = Some(Principal::new(name));
+            }
+        }
+
+        principal.ok_or_else(|| "Bearer token is not valid.".to_string())
+    }
+}
+
+/// Minimum time between fetches of the key set, so that tokens with unknown
+/// key IDs cannot be used to flood the identity provider.
+const MIN_JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long fetching the key set may take.
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accept requests bearing a JWT signed by an OIDC identity provider, whose
+/// signing keys are published as a JSON Web Key Set.
+///
+/// The key set is fetched in the background, so that a slow identity provider
+/// never holds up the loops which authenticate requests. A token signed by a
+/// key which has not been fetched yet is rejected, and triggers a fetch.
+pub struct JwtAuth {
+    jwks_url: String,
+    issuer: Option<String>,
+    audience: Option<String>,
+    algorithms: Vec<Algorithm>,
+    principal_claim: String,
+    client: reqwest::Client,
+    keys: Arc<RwLock<JwkSet>>,
+    last_refresh: RwLock<Option<Instant>>,
+}
+
+impl JwtAuth {
+    #[must_use]
+    pub fn new(
+        jwks_url: String,
+        issuer: Option<String>,
+        audience: Option<String>,
+        algorithms: Vec<Algorithm>,
+        principal_claim: String,
+    ) -> Self {
+        JwtAuth {
+            jwks_url,
+            issuer,
+            audience,
+            algorithms,
+            principal_claim,
+            client: reqwest::Client::builder()
+                .timeout(JWKS_FETCH_TIMEOUT)
+                .build()
+                .expect("Default TLS backend is available."),
+            keys: Arc::new(RwLock::new(JwkSet { keys: Vec::new() })),
+            last_refresh: RwLock::new(None),
+        }
+    }
+
+    /// Fetch the key set in the background, unless it was fetched too
+    /// recently. Must be called within a Tokio runtime.
+    fn refresh_keys(&self) {
+        match self.last_refresh.write() {
+            Ok(mut last_refresh) => {
+                if let Some(last_refresh) = *last_refresh {
+                    if last_refresh.elapsed() < MIN_JWKS_REFRESH_INTERVAL {
+                        return;
+                    }
+                }
+                *last_refresh = Some(Instant::now());
+            }
+            Err(_) => {
+                tracing::error!("Key set lock poisoned.");
+                return;
+            }
+        }
+
+        let client = self.client.clone();
+        let jwks_url = self.jwks_url.clone();
+        let keys = self.keys.clone();
+        tokio::spawn(async move {
+            tracing::info!(%jwks_url, "Fetching JSON Web Key Set.");
+            match fetch_keys(&client, &jwks_url).await {
+                Ok(new_keys) => match keys.write() {
+                    Ok(mut keys) => *keys = new_keys,
+                    Err(_) => tracing::error!("Key set lock poisoned."),
+                },
+                Err(error) => tracing::warn!(?error, "Error fetching JSON Web Key Set."),
+            }
+        });
+    }
+
+    /// Validate a token against the current key set. Returns `Ok(None)` if
+    /// the token was signed by a key which is not in the set.
+    fn verify(&self, token: &str) -> Result<Option<Principal>, String> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|error| format!("Bearer token is not a valid JWT: {}", error))?;
+        if !self.algorithms.contains(&header.alg) {
+            return Err(format!("JWT algorithm {:?} is not allowed.", header.alg));
+        }
+        let kid = header
+            .kid
+            .ok_or_else(|| "JWT header has no key ID.".to_string())?;
+
+        let key = {
+            let keys = self
+                .keys
+                .read()
+                .map_err(|_| "Key set lock poisoned.".to_string())?;
+            match keys.find(&kid) {
+                Some(jwk) => DecodingKey::from_jwk(jwk)
+                    .map_err(|error| format!("Signing key {} is not usable: {}", kid, error))?,
+                None => return Ok(None),
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+
+        let claims =
+            jsonwebtoken::decode::<HashMap<String, serde_json::Value>>(token, &key, &validation)
+                .map_err(|error| format!("JWT is not valid: {}", error))?
+                .claims;
+
+        match claims.get(&self.principal_claim) {
+            Some(serde_json::Value::String(name)) => Ok(Some(Principal::new(name))),
+            _ => Err(format!("JWT has no string claim {}.", self.principal_claim)),
+        }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for JwtAuth {
+    async fn authenticate(&self, credentials: &Credentials) -> Result<Principal, String> {
+        let token = credentials
+            .bearer_token
+            .as_deref()
+            .ok_or_else(|| "Request has no bearer token.".to_string())?;
+
+        if let Some(principal) = self.verify(token)? {
+            return Ok(principal);
+        }
+
+        // The identity provider may have rotated its keys since they were
+        // last fetched.
+        self.refresh_keys();
+        Err("JWT was signed by an unknown key. The key set is being refreshed.".to_string())
+    }
+}
+
+async fn fetch_keys(client: &reqwest::Client, jwks_url: &str) -> Result<JwkSet> {
+    Ok(client
+        .get(jwks_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+fn default_algorithms() -> Vec<Algorithm> {
+    vec![Algorithm::RS256]
+}
+
+fn default_principal_claim() -> String {
+    "sub".to_string()
+}
+
+/// Built-in authentication providers, as selected in the controller config.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum AuthOptions {
+    #[default]
+    AllowAll,
+    StaticTokens {
+        /// Map of principal name to the token it authenticates with.
+        tokens: HashMap<String, String>,
+    },
+    Jwt {
+        /// URL of the identity provider's JSON Web Key Set.
+        jwks_url: String,
+
+        /// If provided, tokens must have this `iss` claim.
+        issuer: Option<String>,
+
+        /// If provided, tokens must have this `aud` claim.
+        audience: Option<String>,
+
+        /// Signing algorithms which are accepted.
+        #[serde(default = "default_algorithms")]
+        algorithms: Vec<Algorithm>,
+
+        /// Claim which names the principal.
+        #[serde(default = "default_principal_claim")]
+        principal_claim: String,
+    },
+}
+
+impl AuthOptions {
+    #[must_use]
+    pub fn provider(&self) -> Arc<dyn AuthProvider> {
+        match self {
+            AuthOptions::AllowAll => Arc::new(AllowAll),
+            AuthOptions::StaticTokens { tokens } => Arc::new(StaticTokens {
+                tokens: tokens.clone(),
+            }),
+            AuthOptions::Jwt {
+                jwks_url,
+                issuer,
+                audience,
+                algorithms,
+                principal_claim,
+            } => {
+                let auth = JwtAuth::new(
+                    jwks_url.clone(),
+                    issuer.clone(),
+                    audience.clone(),
+                    algorithms.clone(),
+                    principal_claim.clone(),
+                );
+                // Fetch the key set ahead of the first request.
+                if tokio::runtime::Handle::try_current().is_ok() {
+                    auth.refresh_keys();
+                }
+                Arc::new(auth)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+
+    fn credentials(token: &str) -> Credentials {
+        Credentials {
+            bearer_token: Some(token.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_static_tokens() {
+        let provider = StaticTokens {
+            tokens: vec![
+                ("alice".to_string(), "token-a".to_string()),
+                ("bob".to_string(), "token-b".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        assert_eq!(
+            Ok(Principal::new("bob")),
+            provider.authenticate(&credentials("token-b")).await
+        );
+        assert!(provider
+            .authenticate(&credentials("token-c"))
+            .await
+            .is_err());
+        assert!(provider
+            .authenticate(&Credentials::default())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+    }
+
+    fn jwt_auth() -> JwtAuth {
+        let auth = JwtAuth::new(
+            "https://example.com/.well-known/jwks.json".to_string(),
+            Some("https://example.com/".to_string()),
+            Some("plane".to_string()),
+            vec![Algorithm::HS256],
+            "sub".to_string(),
+        );
+        *auth.keys.write().unwrap() = serde_json::from_str(
+            r#"{"keys": [{"kty": "oct", "kid": "key1", "k": "c2VjcmV0LWtleS1mb3ItdGVzdHM="}]}"#,
+        )
+        .unwrap();
+        auth
+    }
+
+    fn jwt(kid: &str, claims: serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(kid.to_string());
+        jsonwebtoken::encode(
+            &header,
+            &claims,
+            &EncodingKey::from_secret(b"secret-key-for-tests"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_jwt_verify() {
+        let auth = jwt_auth();
+        let exp = jsonwebtoken::get_current_timestamp() + 3600;
+
+        let token = jwt(
+            "key1",
+            serde_json::json!({"sub": "alice", "iss": "https://example.com/", "aud": "plane", "exp": exp}),
+        );
+        assert_eq!(Ok(Some(Principal::new("alice"))), auth.verify(&token));
+
+        let token = jwt(
+            "key1",
+            serde_json::json!({"sub": "alice", "iss": "https://example.com/", "aud": "other", "exp": exp}),
+        );
+        assert!(auth.verify(&token).is_err());
+
+        let token = jwt(
+            "key1",
+            serde_json::json!({"sub": "alice", "iss": "https://example.com/", "aud": "plane", "exp": 1}),
+        );
+        assert!(auth.verify(&token).is_err());
+
+        let token = jwt(
+            "key2",
+            serde_json::json!({"sub": "alice", "iss": "https://example.com/", "aud": "plane", "exp": exp}),
+        );
+        assert_eq!(Ok(None), auth.verify(&token));
+    }
+}
@@ -0,0 +1,264 @@
+//! Pushing each cluster's TLS certificate to its drones.
+//!
+//! The certificate and its private key are read from files kept up to date by
+//! some external process. Whenever the certificate changes, or a drone with a
+//! new sealing key appears, the controller publishes a [CertificateUpdate]
+//! carrying the private key sealed to every live drone in the cluster. Updates
+//! are signed with the cluster's certificate signing key, so that drones only
+//! install certificates pushed by the controller.
+
+use crate::{
+    config::{CertificatePaths, CertificatePushOptions},
+    leader::Leadership,
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use plane_core::{
+    logging::LogError,
+    messages::{agent::DroneStatusMessage, cert::CertificateUpdate},
+    nats::TypedNats,
+    sealing::SealedData,
+    signing::SigningKey,
+    types::{ClusterName, DroneId},
+    NeverResult,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use tokio::select;
+
+/// How often certificate files are checked for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a drone's sealing key is used after its last status message.
+const DRONE_KEY_TTL: Duration = Duration::from_secs(30);
+
+/// How often the certificate is pushed again even if nothing has changed, in
+/// case a drone missed the last update.
+const REPUSH_INTERVAL: Duration = Duration::from_secs(600);
+
+struct Pushed {
+    cert_pem: String,
+    drones: HashSet<(DroneId, String)>,
+    time: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct CertificatePusher {
+    /// Sealing key of each live drone, with the time it was last seen.
+    drone_keys: HashMap<ClusterName, HashMap<DroneId, (String, DateTime<Utc>)>>,
+
+    /// The last update pushed to each cluster.
+    pushed: HashMap<ClusterName, Pushed>,
+}
+
+fn older_than(time: DateTime<Utc>, now: DateTime<Utc>, duration: Duration) -> bool {
+    now.signed_duration_since(time).to_std().unwrap_or_default() > duration
+}
+
+impl CertificatePusher {
+    fn update_status(&mut self, status: &DroneStatusMessage, now: DateTime<Utc>) {
+        if let Some(sealing_key) = &status.sealing_key {
+            self.drone_keys
+                .entry(status.cluster.clone())
+                .or_default()
+                .insert(status.drone_id.clone(), (sealing_key.clone(), now));
+        }
+    }
+
+    /// The update to publish for a cluster, if its certificate or drones have
+    /// changed since the last update was pushed.
+    fn certificate_update(
+        &mut self,
+        cluster: &ClusterName,
+        cert_pem: &str,
+        key_pem: &[u8],
+        now: DateTime<Utc>,
+    ) -> Result<Option<CertificateUpdate>> {
+        let drones: HashSet<(DroneId, String)> = match self.drone_keys.get_mut(cluster) {
+            Some(drone_keys) => {
+                drone_keys.retain(|_, (_, seen)| !older_than(*seen, now, DRONE_KEY_TTL));
+                drone_keys
+                    .iter()
+                    .map(|(drone_id, (sealing_key, _))| (drone_id.clone(), sealing_key.clone()))
+                    .collect()
+            }
+            None => HashSet::new(),
+        };
+
+        if drones.is_empty() {
+            return Ok(None);
+        }
+
+        if let Some(pushed) = self.pushed.get(cluster) {
+            if pushed.cert_pem == cert_pem
+                && drones.is_subset(&pushed.drones)
+                && !older_than(pushed.time, now, REPUSH_INTERVAL)
+            {
+                return Ok(None);
+            }
+        }
+
+        let mut keys = HashMap::new();
+        for (drone_id, sealing_key) in &drones {
+            keys.insert(drone_id.clone(), SealedData::seal(sealing_key, key_pem)?);
+        }
+
+        self.pushed.insert(
+            cluster.clone(),
+            Pushed {
+                cert_pem: cert_pem.to_string(),
+                drones,
+                time: now,
+            },
+        );
+
+        Ok(Some(CertificateUpdate {
+            cluster: cluster.clone(),
+            cert_pem: cert_pem.to_string(),
+            keys,
+        }))
+    }
+}
+
+fn read_certificate(paths: &CertificatePaths) -> Result<(String, Vec<u8>)> {
+    Ok((
+        std::fs::read_to_string(&paths.cert_path)?,
+        std::fs::read(&paths.key_path)?,
+    ))
+}
+
+async fn push_certificates(
+    pusher: &mut CertificatePusher,
+    certificates: &HashMap<ClusterName, (CertificatePaths, TypedNats)>,
+) {
+    for (cluster, (paths, nats)) in certificates {
+        let update = read_certificate(paths).and_then(|(cert_pem, key_pem)| {
+            pusher.certificate_update(cluster, &cert_pem, &key_pem, Utc::now())
+        });
+
+        match update {
+            Ok(Some(update)) => {
+                tracing::info!(%cluster, drones = update.keys.len(), "Pushing certificate.");
+                nats.publish(&update)
+                    .await
+                    .log_error("Error publishing certificate update.");
+            }
+            Ok(None) => (),
+            Err(error) => {
+                tracing::warn!(?error, %cluster, "Error preparing certificate update.");
+            }
+        }
+    }
+}
+
+/// Push the configured certificate of each cluster to its drones. Every
+/// controller keeps track of drones' sealing keys, but only the leader pushes.
+pub async fn certificate_push_loop(
+    nats: &TypedNats,
+    certificates: HashMap<ClusterName, CertificatePushOptions>,
+    leadership: &Leadership,
+) -> NeverResult {
+    let mut signed = HashMap::new();
+    for (cluster, options) in certificates {
+        let signing_key = SigningKey::from_seed(&options.signing_key)?;
+        let signed_nats = nats.clone().with_signing_key(Some(signing_key));
+        signed.insert(cluster, (options.paths, signed_nats));
+    }
+
+    let mut pusher = CertificatePusher::default();
+    let mut status_sub = nats
+        .subscribe(DroneStatusMessage::subscribe_subject())
+        .await?;
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+    loop {
+        select! {
+            _ = interval.tick() => {
+                if leadership.is_leader() {
+                    push_certificates(&mut pusher, &signed).await;
+                }
+            },
+
+            status_msg = status_sub.next() => {
+                match status_msg {
+                    Some(status_msg) => pusher.update_status(&status_msg.value, Utc::now()),
+                    None => return Err(anyhow!("status_sub.next() returned None.")),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plane_core::sealing::SealingKey;
+
+    fn status(drone_id: &DroneId, cluster: &ClusterName, key: &SealingKey) -> DroneStatusMessage {
+        DroneStatusMessage {
+            drone_id: drone_id.clone(),
+            cluster: cluster.clone(),
+            drone_version: "0.1.0".to_string(),
+            ready: true,
+            running_backends: None,
+            remaining_capacity: None,
+            host_metrics: None,
+            sealing_key: Some(key.public_key().unwrap()),
+            arch: None,
+            proxy_metrics: None,
+            draining: false,
+        }
+    }
+
+    #[test]
+    fn test_certificate_update() {
+        let mut pusher = CertificatePusher::default();
+        let cluster = ClusterName::new("mycluster.test");
+        let drone1 = DroneId::new_random();
+        let drone2 = DroneId::new_random();
+        let key1 = SealingKey::generate().unwrap();
+        let key2 = SealingKey::generate().unwrap();
+        let now = Utc::now();
+
+        // No drones to push to yet.
+        assert!(pusher
+            .certificate_update(&cluster, "cert1", b"key1", now)
+            .unwrap()
+            .is_none());
+
+        pusher.update_status(&status(&drone1, &cluster, &key1), now);
+        let update = pusher
+            .certificate_update(&cluster, "cert1", b"key1", now)
+            .unwrap()
+            .unwrap();
+        assert_eq!("cert1", update.cert_pem);
+        assert_eq!(b"key1".to_vec(), key1.open(&update.keys[&drone1]).unwrap());
+
+        // Nothing changed.
+        assert!(pusher
+            .certificate_update(&cluster, "cert1", b"key1", now)
+            .unwrap()
+            .is_none());
+
+        // A new drone receives the certificate, along with existing drones.
+        pusher.update_status(&status(&drone2, &cluster, &key2), now);
+        let update = pusher
+            .certificate_update(&cluster, "cert1", b"key1", now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(2, update.keys.len());
+        assert_eq!(b"key1".to_vec(), key2.open(&update.keys[&drone2]).unwrap());
+
+        // A renewed certificate is pushed, but not to drones which have
+        // stopped sending status messages.
+        let later = now + chrono::Duration::seconds(60);
+        pusher.update_status(&status(&drone2, &cluster, &key2), later);
+        let update = pusher
+            .certificate_update(&cluster, "cert2", b"key2", later)
+            .unwrap()
+            .unwrap();
+        assert_eq!(vec![&drone2], update.keys.keys().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::plan::ClusterPlan;
+use anyhow::anyhow;
+use async_nats::jetstream::consumer::DeliverPolicy;
+use dashmap::DashMap;
+use plane_core::{
+    messages::scheduler::ClusterConfig, nats::TypedNats, types::ClusterName, NeverResult,
+};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tokio::sync::watch;
+
+/// How long to wait before retrying to load the persisted configs.
+const LOAD_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Latest config applied to each cluster through `plane-cli apply`. One
+/// tracker is shared by the scheduler and the DNS server.
+pub struct ClusterConfigTracker {
+    configs: DashMap<ClusterName, ClusterConfig>,
+
+    /// Whether a loop is tracking configs, so that only one does.
+    tracking: AtomicBool,
+
+    /// Whether the configs persisted in JetStream have been loaded.
+    loaded: watch::Sender<bool>,
+}
+
+impl Default for ClusterConfigTracker {
+    fn default() -> Self {
+        ClusterConfigTracker {
+            configs: DashMap::default(),
+            tracking: AtomicBool::new(false),
+            loaded: watch::channel(false).0,
+        }
+    }
+}
+
+impl ClusterConfigTracker {
+    /// Replace the config of a cluster.
+    pub fn update(&self, config: &ClusterConfig) {
+        self.configs.insert(config.cluster.clone(), config.clone());
+    }
+
+    /// The plan to schedule a cluster's backends with: its plan from the
+    /// controller's configuration, overridden by its applied config.
+    pub fn plan(
+        &self,
+        clusters: &HashMap<ClusterName, ClusterPlan>,
+        cluster: &ClusterName,
+    ) -> ClusterPlan {
+        let plan = clusters.get(cluster).cloned().unwrap_or_default();
+
+        match self.configs.get(cluster) {
+            Some(config) => plan.with_config(&config),
+            None => plan,
+        }
+    }
+
+    /// Time-to-live of the cluster's DNS records, if its config sets one.
+    pub fn dns_ttl(&self, cluster: &ClusterName) -> Option<u32> {
+        self.configs.get(cluster)?.dns_ttl_secs
+    }
+
+    /// Wait until the configs persisted in JetStream have been loaded, so
+    /// that a cluster's limits and allowed images are enforced from the
+    /// first request.
+    pub async fn wait_loaded(&self) {
+        let mut loaded = self.loaded.subscribe();
+        while !*loaded.borrow() {
+            if loaded.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Track the configs persisted in JetStream. Only the first call tracks
+    /// them; any other never returns, so that every component sharing the
+    /// tracker can run it without loading the configs more than once.
+    pub async fn track(&self, nats: &TypedNats) -> NeverResult {
+        if self.tracking.swap(true, Ordering::SeqCst) {
+            return std::future::pending().await;
+        }
+
+        let mut config_sub = nats.subscribe(ClusterConfig::subscribe_subject()).await?;
+        tracing::info!("Subscribed to cluster config messages.");
+
+        // Load the current config of each cluster before applying updates,
+        // which are buffered in the meantime. Until then, schedule requests
+        // wait rather than being checked against the static plan alone.
+        let configs = loop {
+            match nats
+                .get_all(
+                    &ClusterConfig::subscribe_subject(),
+                    DeliverPolicy::LastPerSubject,
+                )
+                .await
+            {
+                Ok(configs) => break configs,
+                Err(error) => {
+                    tracing::warn!(?error, "Could not load cluster configs; retrying.");
+                    tokio::time::sleep(LOAD_RETRY_INTERVAL).await;
+                }
+            }
+        };
+        for config in &configs {
+            self.update(config);
+        }
+        self.loaded.send_replace(true);
+        tracing::info!(
+            num_configs = configs.len(),
+            "Loaded cluster configs from JetStream."
+        );
+
+        while let Some(config) = config_sub.next().await {
+            tracing::info!(config=?config.value, "Cluster config applied.");
+            self.update(&config.value);
+        }
+
+        Err(anyhow!("config_sub.next() returned None."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_applied_config_overrides_plan() {
+        let cluster = ClusterName::new("plane.test");
+        let mut clusters = HashMap::new();
+        clusters.insert(
+            cluster.clone(),
+            ClusterPlan {
+                max_memory_bytes: Some(1 << 30),
+                ..ClusterPlan::default()
+            },
+        );
+
+        let tracker = ClusterConfigTracker::default();
+        assert_eq!(None, tracker.plan(&clusters, &cluster).max_backends);
+
+        tracker.update(&ClusterConfig {
+            cluster: cluster.clone(),
+            max_backends: Some(4),
+            default_resource_limits: None,
+            max_memory_bytes: None,
+            max_cpu_period_percent: None,
+            dns_ttl_secs: None,
+            allowed_images: None,
+            backend_url: None,
+        });
+        let plan = tracker.plan(&clusters, &cluster);
+        assert_eq!(Some(4), plan.max_backends);
+        assert_eq!(Some(1 << 30), plan.max_memory_bytes);
+
+        let other = ClusterName::new("other.test");
+        assert_eq!(None, tracker.plan(&clusters, &other).max_backends);
+    }
+
+    #[tokio::test]
+    async fn test_wait_loaded() {
+        let tracker = ClusterConfigTracker::default();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), tracker.wait_loaded())
+                .await
+                .is_err()
+        );
+
+        tracker.loaded.send_replace(true);
+        tracker.wait_loaded().await;
+    }
+}
@@ -1,9 +1,237 @@
-use plane_core::nats_connection::NatsConnectionSpec;
+use crate::{
+    admission::AdmissionOptions, auth::AuthOptions, diagnostics::DiagnosticsOptions,
+    drone_update::DroneUpdateOptions, leader::LeaderElectionOptions, placement::PlacementOptions,
+    rate_limit::RateLimitOptions, retention::RetentionOptions,
+    scheduler::DEFAULT_FAILED_SCHEDULE_WINDOW_SECS,
+};
+use plane_core::{
+    messages::{agent::ResourceLimits, dns::DnsRecordType, scheduler::BackendUrlConfig},
+    nats_connection::NatsConnectionSpec,
+    streams::StreamsConfig,
+};
 use serde::{Deserialize, Serialize};
-use std::net::{IpAddr, Ipv4Addr};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+};
 
 #[derive(Serialize, Deserialize)]
-pub struct SchedulerOptions {}
+pub struct SchedulerOptions {
+    /// If provided, the scheduler periodically publishes a capacity report
+    /// for each cluster it knows about, for use by external autoscalers.
+    pub autoscaler: Option<AutoscalerOptions>,
+
+    /// If provided, the scheduler periodically asks a drone in each cluster
+    /// to pull the listed images, seeding any pull-through cache the drones
+    /// share.
+    pub image_cache: Option<ImageCacheOptions>,
+
+    /// How backends are placed on the live drones of a cluster.
+    #[serde(default)]
+    pub placement: PlacementOptions,
+
+    /// How control requests (such as schedule requests) are authenticated.
+    #[serde(default)]
+    pub auth: AuthOptions,
+
+    /// If provided, schedule requests are sent to this webhook, which may
+    /// approve, mutate, or reject them before they are scheduled.
+    pub admission: Option<AdmissionOptions>,
+
+    /// Per-cluster scheduling policy, keyed by cluster name.
+    #[serde(default)]
+    pub clusters: HashMap<String, ClusterOptions>,
+
+    /// How drones whose version is incompatible with the controller are
+    /// treated.
+    #[serde(default)]
+    pub version_policy: VersionPolicy,
+
+    /// If true, backends are preferably placed on drones which already have
+    /// their image cached, before the placement strategy chooses among them.
+    #[serde(default)]
+    pub image_affinity: bool,
+
+    /// How many running backends having a backend's image cached is worth
+    /// to a drone under image affinity: drones without the image are only
+    /// considered while their load is lower than that of every drone with it
+    /// by at least this much. Defaults to
+    /// [crate::scheduler::DEFAULT_IMAGE_AFFINITY_WEIGHT].
+    pub image_affinity_weight: Option<f64>,
+
+    /// If provided, several controllers can run at once, and elect a leader
+    /// which is the only one to schedule backends.
+    pub leader_election: Option<LeaderElectionOptions>,
+
+    /// If provided, the JetStream streams and consumers Plane depends on are
+    /// checked periodically, and an alert is published for each problem.
+    pub diagnostics: Option<DiagnosticsOptions>,
+
+    /// If provided, schedule requests beyond this rate, across all clusters,
+    /// are throttled.
+    pub rate_limit: Option<RateLimitOptions>,
+
+    /// Largest `count` a batch schedule request may have. Larger batches are
+    /// rejected. Defaults to [crate::DEFAULT_MAX_BATCH_SIZE].
+    pub max_batch_size: Option<u32>,
+
+    /// If provided, the state history of backends which terminated long ago
+    /// is periodically purged from JetStream.
+    pub retention: Option<RetentionOptions>,
+}
+
+/// How the scheduler treats drones whose version is not semver-compatible
+/// with the controller's.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionPolicy {
+    /// Never schedule backends to incompatible drones.
+    #[default]
+    Refuse,
+
+    /// Only schedule backends to incompatible drones when no compatible
+    /// drone is available.
+    Deprioritize,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct ClusterOptions {
+    /// Idle timeout used when a schedule request does not provide one.
+    pub default_idle_secs: Option<u64>,
+
+    /// Lower bound on the idle timeout of backends in this cluster.
+    pub min_idle_secs: Option<u64>,
+
+    /// Upper bound on the idle timeout of backends in this cluster.
+    pub max_idle_secs: Option<u64>,
+
+    /// If true, backends may request an idle timeout of zero, which means
+    /// they are never swept for being idle.
+    #[serde(default)]
+    pub allow_unlimited_idle: bool,
+
+    /// Resource limits given to backends whose request does not set any.
+    pub default_resource_limits: Option<ResourceLimits>,
+
+    /// Upper bound on the memory limit of each backend, in bytes.
+    pub max_memory_bytes: Option<i64>,
+
+    /// Upper bound on the percentage of a CPU period each backend may use.
+    pub max_cpu_period_percent: Option<u8>,
+
+    /// If true, requests which exceed the bounds above are rejected instead
+    /// of having their limits lowered to the bounds.
+    #[serde(default)]
+    pub reject_over_limits: bool,
+
+    /// Base64-encoded Ed25519 public key. If provided, schedule requests for
+    /// this cluster are only accepted if signed with the matching private key.
+    pub signing_public_key: Option<String>,
+
+    /// If provided, the certificate at these paths is pushed to the drones of
+    /// this cluster whenever it changes.
+    pub certificate: Option<CertificatePushOptions>,
+
+    /// If true, backends are only scheduled to drones which have registered
+    /// with an approved key.
+    #[serde(default)]
+    pub require_drone_approval: bool,
+
+    /// Base64-encoded Ed25519 public keys of drones which are approved as
+    /// soon as they register. Others must be approved with
+    /// `plane-cli approve-drone`.
+    #[serde(default)]
+    pub approved_drone_keys: Vec<String>,
+
+    /// How the URLs of backends in this cluster, returned to clients which
+    /// schedule them, are formed. By default, `https://{backend}.{cluster}`.
+    pub backend_url: Option<BackendUrlConfig>,
+
+    /// If provided, schedule requests for this cluster beyond this rate are
+    /// throttled.
+    pub rate_limit: Option<RateLimitOptions>,
+
+    /// If provided, drones of this cluster which update themselves are told
+    /// to run this version.
+    pub drone_update: Option<DroneUpdateOptions>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CertificatePushOptions {
+    #[serde(flatten)]
+    pub paths: CertificatePaths,
+
+    /// Base64-encoded private key (as generated by
+    /// `plane-cli generate-signing-key`) which certificate updates are signed
+    /// with. Drones only install updates signed with the key whose public key
+    /// is their `certificate_public_key`.
+    pub signing_key: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CertificatePaths {
+    /// PEM-encoded certificate chain.
+    pub cert_path: PathBuf,
+
+    /// PEM-encoded private key of the certificate.
+    pub key_path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ImageCacheOptions {
+    /// Images to seed.
+    pub images: Vec<String>,
+
+    /// How often to seed each image, so that updated tags are picked up.
+    #[serde(default = "default_seed_interval_secs")]
+    pub seed_interval_secs: u64,
+}
+
+impl ImageCacheOptions {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.seed_interval_secs == 0 {
+            return Err(anyhow::anyhow!(
+                "seed_interval_secs must be greater than 0."
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_seed_interval_secs() -> u64 {
+    3600
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AutoscalerOptions {
+    /// How often to publish a capacity report for each cluster.
+    #[serde(default = "default_report_interval_secs")]
+    pub report_interval_secs: u64,
+
+    /// The window over which failed schedule requests are counted.
+    #[serde(default = "default_failed_schedule_window_secs")]
+    pub failed_schedule_window_secs: u64,
+}
+
+impl AutoscalerOptions {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.report_interval_secs == 0 {
+            return Err(anyhow::anyhow!(
+                "report_interval_secs must be greater than 0."
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_report_interval_secs() -> u64 {
+    10
+}
+
+fn default_failed_schedule_window_secs() -> u64 {
+    DEFAULT_FAILED_SCHEDULE_WINDOW_SECS
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct DnsOptions {
@@ -19,6 +247,96 @@ pub struct DnsOptions {
     /// however, the email provided here should be a normal "username@domain.tld"-format
     /// email.
     pub soa_email: Option<String>,
+
+    /// Zones the server is authoritative for, keyed by the cluster whose
+    /// records each serves. If any are configured, queries for names outside
+    /// of them are refused; otherwise the records of every cluster are served.
+    #[serde(default)]
+    pub zones: HashMap<String, ZoneOptions>,
+
+    /// If provided, the server also answers queries over TLS and HTTPS, for
+    /// resolvers which require an encrypted upstream transport.
+    pub encrypted: Option<EncryptedDnsOptions>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EncryptedDnsOptions {
+    /// Certificate presented to clients of the encrypted listeners.
+    pub certificate: CertificatePaths,
+
+    /// If provided, DNS-over-TLS is served on this port (conventionally 853).
+    pub dot_port: Option<u16>,
+
+    /// If provided, DNS-over-HTTPS is served at `/dns-query` on this port
+    /// (conventionally 443).
+    pub doh_port: Option<u16>,
+
+    /// Hostname DNS-over-HTTPS is served at, which is required with
+    /// `doh_port`. Requests for other hostnames are rejected.
+    pub doh_hostname: Option<String>,
+
+    /// How often the certificate's files are checked for changes, e.g. when
+    /// it is renewed. A changed certificate is presented to new connections.
+    #[serde(default = "default_certificate_check_secs")]
+    pub certificate_check_secs: u64,
+}
+
+fn default_certificate_check_secs() -> u64 {
+    60
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ZoneOptions {
+    /// Hostnames of the zone's name servers, served as its NS records. The
+    /// first is also given as the primary name server in its SOA record.
+    #[serde(default)]
+    pub name_servers: Vec<String>,
+
+    /// Email address to use as RNAME in the zone's SOA record, instead of
+    /// the server-wide `soa_email`.
+    pub soa_email: Option<String>,
+
+    #[serde(default)]
+    pub soa: SoaOptions,
+
+    /// Records served in addition to those published by drones.
+    #[serde(default)]
+    pub records: Vec<StaticRecord>,
+}
+
+/// Timing parameters of a zone's SOA record.
+/// See [RFC 1035](https://www.rfc-editor.org/rfc/rfc1035#section-3.3.13).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct SoaOptions {
+    pub serial: u32,
+    pub refresh: i32,
+    pub retry: i32,
+    pub expire: i32,
+    pub minimum: u32,
+}
+
+impl Default for SoaOptions {
+    fn default() -> Self {
+        SoaOptions {
+            serial: 1,
+            refresh: 7200,
+            retry: 7200,
+            expire: 7200,
+            minimum: 7200,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StaticRecord {
+    /// Name of the record within the zone, e.g. "www". Empty for the zone's
+    /// own name.
+    #[serde(default)]
+    pub name: String,
+
+    pub kind: DnsRecordType,
+    pub value: String,
 }
 
 fn default_port() -> u16 {
@@ -29,6 +347,27 @@ fn default_bind_ip() -> IpAddr {
     IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct GrpcOptions {
+    #[serde(default = "default_grpc_port")]
+    pub port: u16,
+
+    #[serde(default = "default_bind_ip")]
+    pub bind_ip: IpAddr,
+
+    /// Certificate the gRPC API is served over TLS with. The API is not
+    /// served in plaintext.
+    pub tls: CertificatePaths,
+
+    /// Provider which authenticates the bearer token of each call, before
+    /// the call is translated into a NATS request. Must not be `allow_all`.
+    pub auth: AuthOptions,
+}
+
+fn default_grpc_port() -> u16 {
+    9090
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ControllerConfig {
     /// How to connect to NATS.
@@ -37,4 +376,47 @@ pub struct ControllerConfig {
     pub scheduler: Option<SchedulerOptions>,
 
     pub dns: Option<DnsOptions>,
+
+    /// If provided, the controller serves a gRPC API which mirrors the NATS
+    /// API. Requires the `grpc` feature.
+    pub grpc: Option<GrpcOptions>,
+
+    /// If provided, the JetStream streams used by Plane are created (or
+    /// updated to match this configuration) when the controller starts.
+    pub streams: Option<StreamsConfig>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_image_cache() {
+        let options = ImageCacheOptions {
+            images: vec!["ghcr.io/drifting-in-space/demo-image-drop-four".to_string()],
+            seed_interval_secs: 3600,
+        };
+        assert!(options.validate().is_ok());
+        assert!(ImageCacheOptions {
+            seed_interval_secs: 0,
+            ..options
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_validate_autoscaler() {
+        let options = AutoscalerOptions {
+            report_interval_secs: 10,
+            failed_schedule_window_secs: 60,
+        };
+        assert!(options.validate().is_ok());
+        assert!(AutoscalerOptions {
+            report_interval_secs: 0,
+            ..options
+        }
+        .validate()
+        .is_err());
+    }
 }
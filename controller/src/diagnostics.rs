@@ -0,0 +1,141 @@
+//! Periodic checks of the JetStream streams and consumers Plane depends on,
+//! published as [JetStreamAlert]s so that operators learn that state
+//! replication is falling behind before scheduling decisions go stale.
+
+use crate::leader::Leadership;
+use plane_core::{
+    diagnostics::check_jetstream,
+    logging::LogError,
+    messages::scheduler::{JetStreamAlert, JetStreamProblem},
+    nats::TypedNats,
+    NeverResult,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, time::Duration};
+
+fn default_interval_secs() -> u64 {
+    30
+}
+
+fn default_max_pending() -> u64 {
+    1000
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DiagnosticsOptions {
+    /// How often to check the streams.
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+
+    /// Number of undelivered messages beyond which a consumer is considered
+    /// to be lagging.
+    #[serde(default = "default_max_pending")]
+    pub max_pending: u64,
+}
+
+impl DiagnosticsOptions {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.interval_secs == 0 {
+            return Err(anyhow::anyhow!("interval_secs must be greater than 0."));
+        }
+        Ok(())
+    }
+}
+
+/// Identifies a lagging consumer across checks.
+fn lagging_consumer(alert: &JetStreamAlert) -> Option<(String, String)> {
+    match &alert.problem {
+        JetStreamProblem::ConsumerLagging { consumer, .. } => {
+            Some((alert.stream.clone(), consumer.clone()))
+        }
+        _ => None,
+    }
+}
+
+/// Alerts worth publishing from a check, given the consumers which were
+/// lagging at the previous check. Consumers catch up on a burst of messages
+/// quickly, and new consumers start with the whole stream pending, so a
+/// consumer is only reported once it lags at two checks in a row.
+fn alerts_to_publish(
+    alerts: Vec<JetStreamAlert>,
+    previously_lagging: &HashSet<(String, String)>,
+) -> Vec<JetStreamAlert> {
+    alerts
+        .into_iter()
+        .filter(|alert| match lagging_consumer(alert) {
+            Some(consumer) => previously_lagging.contains(&consumer),
+            None => true,
+        })
+        .collect()
+}
+
+/// Periodically check JetStream, publishing an alert for each problem found.
+/// Only the leader publishes alerts, so that they are not duplicated.
+pub async fn diagnostics_loop(
+    nats: &TypedNats,
+    options: DiagnosticsOptions,
+    leadership: &Leadership,
+) -> NeverResult {
+    let mut interval = tokio::time::interval(Duration::from_secs(options.interval_secs));
+    let mut lagging: HashSet<(String, String)> = HashSet::new();
+
+    loop {
+        interval.tick().await;
+        if !leadership.is_leader() {
+            continue;
+        }
+
+        let alerts = match check_jetstream(nats, options.max_pending).await {
+            Ok(alerts) => alerts,
+            Err(error) => {
+                tracing::warn!(?error, "Error checking JetStream.");
+                continue;
+            }
+        };
+
+        let now_lagging: HashSet<(String, String)> =
+            alerts.iter().filter_map(lagging_consumer).collect();
+        for alert in alerts_to_publish(alerts, &lagging) {
+            tracing::warn!(%alert, "JetStream problem found.");
+            nats.publish(&alert)
+                .await
+                .log_error("Error publishing JetStream alert.");
+        }
+        lagging = now_lagging;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lagging(consumer: &str) -> JetStreamAlert {
+        JetStreamAlert {
+            stream: "backend_status".into(),
+            problem: JetStreamProblem::ConsumerLagging {
+                consumer: consumer.into(),
+                num_pending: 5000,
+                num_ack_pending: 1,
+            },
+        }
+    }
+
+    #[test]
+    fn test_alerts_to_publish() {
+        let missing = JetStreamAlert {
+            stream: "dns_record".into(),
+            problem: JetStreamProblem::StreamMissing,
+        };
+        let alerts = vec![missing.clone(), lagging("a"), lagging("b")];
+
+        let previously_lagging: HashSet<(String, String)> =
+            vec![lagging_consumer(&lagging("a")).unwrap()]
+                .into_iter()
+                .collect();
+
+        assert_eq!(
+            vec![missing, lagging("a")],
+            alerts_to_publish(alerts, &previously_lagging)
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Listeners for DNS over TLS and HTTPS. The certificate they present is
+//! reloaded when its files change, e.g. when it is renewed, by registering
+//! the listeners again with the new certificate before the old registration
+//! is dropped. Connections made before then keep the old certificate.
+
+use super::TCP_TIMEOUT_SECONDS;
+use crate::config::{CertificatePaths, EncryptedDnsOptions};
+use anyhow::{anyhow, Context};
+use plane_core::Never;
+use rustls::{Certificate, PrivateKey};
+use rustls_pemfile::Item;
+use std::{
+    fs::File,
+    io::BufReader,
+    net::{IpAddr, TcpListener as StdTcpListener},
+    time::{Duration, SystemTime},
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+use trust_dns_server::{proto::error::ProtoError, server::RequestHandler, ServerFuture};
+
+/// Load the certificate chain and private key presented by the encrypted
+/// listeners. The key may be in PKCS #8 or PKCS #1 (RSA) form.
+fn load_certificate(paths: &CertificatePaths) -> anyhow::Result<(Vec<Certificate>, PrivateKey)> {
+    let mut reader =
+        BufReader::new(File::open(&paths.cert_path).context("Opening DNS certificate.")?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)
+        .context("Reading DNS certificate.")?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {:?}.", paths.cert_path));
+    }
+
+    let mut reader =
+        BufReader::new(File::open(&paths.key_path).context("Opening DNS private key.")?);
+    let key = rustls_pemfile::read_all(&mut reader)
+        .context("Reading DNS private key.")?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key found in {:?}.", paths.key_path))?;
+
+    Ok((certs, key))
+}
+
+/// Modification times of the certificate's files, which change when it is
+/// renewed.
+fn certificate_modified(paths: &CertificatePaths) -> anyhow::Result<(SystemTime, SystemTime)> {
+    Ok((
+        std::fs::metadata(&paths.cert_path)?.modified()?,
+        std::fs::metadata(&paths.key_path)?.modified()?,
+    ))
+}
+
+/// The encrypted listeners' sockets, which stay bound while they are
+/// registered again with a renewed certificate.
+pub struct EncryptedListeners {
+    dot: Option<StdTcpListener>,
+    doh: Option<(StdTcpListener, String)>,
+}
+
+impl EncryptedListeners {
+    pub async fn bind(bind_ip: IpAddr, options: &EncryptedDnsOptions) -> anyhow::Result<Self> {
+        let dot = match options.dot_port {
+            Some(port) => {
+                let listener = TcpListener::bind((bind_ip, port))
+                    .await
+                    .context("Binding DNS-over-TLS port.")?;
+                tracing::info!(ip=%bind_ip, %port, "Listening for DNS-over-TLS queries.");
+                Some(listener.into_std()?)
+            }
+            None => None,
+        };
+
+        let doh = match (options.doh_port, &options.doh_hostname) {
+            (Some(port), Some(hostname)) => {
+                let listener = TcpListener::bind((bind_ip, port))
+                    .await
+                    .context("Binding DNS-over-HTTPS port.")?;
+                tracing::info!(ip=%bind_ip, %port, "Listening for DNS-over-HTTPS queries.");
+                Some((listener.into_std()?, hostname.clone()))
+            }
+            _ => None,
+        };
+
+        Ok(EncryptedListeners { dot, doh })
+    }
+
+    /// Serve the listeners with a certificate until the returned task is
+    /// aborted.
+    fn serve<T: RequestHandler>(
+        &self,
+        handler: T,
+        certificate_and_key: (Vec<Certificate>, PrivateKey),
+    ) -> anyhow::Result<JoinHandle<Result<(), ProtoError>>> {
+        let timeout = Duration::from_secs(TCP_TIMEOUT_SECONDS);
+        let mut fut = ServerFuture::new(handler);
+
+        if let Some(listener) = &self.dot {
+            fut.register_tls_listener(
+                TcpListener::from_std(listener.try_clone()?)?,
+                timeout,
+                certificate_and_key.clone(),
+            )
+            .context("Registering DNS-over-TLS listener.")?;
+        }
+
+        if let Some((listener, hostname)) = &self.doh {
+            fut.register_https_listener(
+                TcpListener::from_std(listener.try_clone()?)?,
+                timeout,
+                certificate_and_key,
+                hostname.clone(),
+            )
+            .context("Registering DNS-over-HTTPS listener.")?;
+        }
+
+        Ok(tokio::spawn(fut.block_until_done()))
+    }
+}
+
+/// Serve the encrypted listeners, checking the certificate's files for
+/// changes every `check_interval`.
+pub async fn serve_encrypted<T: RequestHandler + Clone>(
+    handler: T,
+    listeners: EncryptedListeners,
+    paths: &CertificatePaths,
+    check_interval: Duration,
+) -> anyhow::Result<Never> {
+    let mut modified = certificate_modified(paths).context("Reading DNS certificate.")?;
+    let mut server = listeners.serve(handler.clone(), load_certificate(paths)?)?;
+    let mut interval = tokio::time::interval(check_interval);
+
+    loop {
+        tokio::select! {
+            result = &mut server => {
+                result?.context("Internal DNS error.")?;
+                return Err(anyhow!("Encrypted DNS listeners terminated unexpectedly."));
+            }
+            _ = interval.tick() => {
+                let current = match certificate_modified(paths) {
+                    Ok(current) => current,
+                    Err(error) => {
+                        tracing::warn!(?error, "Error checking DNS certificate for renewal.");
+                        continue;
+                    }
+                };
+                if current == modified {
+                    continue;
+                }
+
+                // If the files are only partly written, loading fails and is
+                // retried on the next check.
+                match load_certificate(paths).and_then(|certificate_and_key| {
+                    listeners.serve(handler.clone(), certificate_and_key)
+                }) {
+                    Ok(renewed) => {
+                        server.abort();
+                        server = renewed;
+                        modified = current;
+                        tracing::info!("Reloaded DNS certificate.");
+                    }
+                    Err(error) => tracing::warn!(?error, "Error reloading DNS certificate."),
+                }
+            }
+        }
+    }
+}
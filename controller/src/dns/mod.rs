@@ -1,8 +1,11 @@
+mod encrypted;
 mod error;
 pub mod rname_format;
 
+use self::encrypted::{serve_encrypted, EncryptedListeners};
 use self::error::OrDnsError;
-use crate::plan::DnsPlan;
+use crate::cluster_config::ClusterConfigTracker;
+use crate::plan::{DnsPlan, ZonePlan};
 use crate::ttl_store::ttl_map::TtlMap;
 use crate::ttl_store::ttl_multistore::TtlMultistore;
 use anyhow::{anyhow, Context};
@@ -12,7 +15,8 @@ use plane_core::messages::dns::DnsRecordType;
 use plane_core::messages::dns::SetDnsRecord;
 use plane_core::types::ClusterName;
 use plane_core::Never;
-use std::net::Ipv4Addr;
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 use tokio::task::JoinHandle;
@@ -35,10 +39,71 @@ use trust_dns_server::{
 
 const TCP_TIMEOUT_SECONDS: u64 = 10;
 
-/// Time-to-live value set on records returned from the DNS server.
-/// Not related to TTL of records used internally.
+/// Time-to-live value set on records returned from the DNS server, unless
+/// the cluster's config sets one. Not related to TTL of records used internally.
 const DNS_RECORD_TTL: u32 = 60;
 
+/// The cluster a queried name belongs to, and the name within it (empty for
+/// the cluster's own name). Without configured zones, the first label of a
+/// name is taken to be the name within the cluster named by the rest.
+fn locate(zones: &HashMap<ClusterName, ZonePlan>, name: &str) -> Result<(ClusterName, String)> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+
+    if zones.is_empty() {
+        let (hostname, cluster_name) = name
+            .split_once('.')
+            .or_dns_error(ResponseCode::NXDomain, || {
+                format!("Invalid name for this server {}", name)
+            })?;
+        return Ok((ClusterName::new(cluster_name), hostname.to_string()));
+    }
+
+    // Zones may be nested, in which case the most specific one applies.
+    let lowercase_name = name.to_ascii_lowercase();
+    zones
+        .keys()
+        .filter_map(|zone| {
+            let zone_name = zone.hostname().to_ascii_lowercase();
+            if lowercase_name == zone_name {
+                return Some((zone, String::new()));
+            }
+            let hostname = lowercase_name.strip_suffix(&zone_name)?.strip_suffix('.')?;
+            Some((zone, name[..hostname.len()].to_string()))
+        })
+        .max_by_key(|(zone, _)| zone.hostname().len())
+        .map(|(zone, hostname)| (zone.clone(), hostname))
+        .or_dns_error(ResponseCode::Refused, || {
+            format!("{} is not in a zone served by this server.", name)
+        })
+}
+
+/// SOA record of a zone, owned by the name at its apex.
+fn zone_soa(zone: &ZonePlan, apex: Name) -> Result<Record> {
+    let soa_email = zone
+        .soa_email
+        .as_ref()
+        .or_dns_error(ResponseCode::ServFail, || {
+            "SOA record email not set in config.".to_string()
+        })?;
+    let primary_name_server = zone
+        .name_servers
+        .first()
+        .cloned()
+        .unwrap_or_else(|| apex.clone());
+
+    let rdata = RData::SOA(SOA::new(
+        primary_name_server,
+        soa_email.clone(),
+        zone.soa.serial,
+        zone.soa.refresh,
+        zone.soa.retry,
+        zone.soa.expire,
+        zone.soa.minimum,
+    ));
+
+    Ok(Record::from_rdata(apex, 60, rdata))
+}
+
 #[derive(PartialEq, Eq, Hash, Clone)]
 struct RecordKey {
     cluster: ClusterName,
@@ -47,8 +112,13 @@ struct RecordKey {
 
 struct ClusterDnsServer {
     a_record_map: Arc<Mutex<TtlMap<RecordKey, RData>>>,
+    aaaa_record_map: Arc<Mutex<TtlMap<RecordKey, RData>>>,
     txt_record_map: Arc<Mutex<TtlMultistore<RecordKey, RData>>>,
     soa_email: Option<Name>,
+    zones: HashMap<ClusterName, ZonePlan>,
+
+    /// Time-to-live of records for each cluster whose config sets one.
+    cluster_configs: Arc<ClusterConfigTracker>,
     _handle: JoinHandle<anyhow::Result<()>>,
 }
 
@@ -57,11 +127,14 @@ impl ClusterDnsServer {
         let nc = plan.nc.clone();
         let a_record_map: Arc<Mutex<TtlMap<RecordKey, RData>>> =
             Arc::new(Mutex::new(TtlMap::new(SetDnsRecord::ttl())));
+        let aaaa_record_map: Arc<Mutex<TtlMap<RecordKey, RData>>> =
+            Arc::new(Mutex::new(TtlMap::new(SetDnsRecord::ttl())));
         let txt_record_map: Arc<Mutex<TtlMultistore<RecordKey, RData>>> =
             Arc::new(Mutex::new(TtlMultistore::new(SetDnsRecord::ttl())));
 
         let handle = {
             let a_record_map = a_record_map.clone();
+            let aaaa_record_map = aaaa_record_map.clone();
             let txt_record_map = txt_record_map.clone();
 
             tokio::spawn(async move {
@@ -75,22 +148,27 @@ impl ClusterDnsServer {
                         tracing::info!(?v, "Got SetDnsRecord request.");
 
                         match v.kind {
-                            DnsRecordType::A => {
-                                let ip: Ipv4Addr = match v.value.parse() {
-                                    Ok(v) => v,
-                                    Err(error) => {
+                            DnsRecordType::A | DnsRecordType::AAAA => {
+                                let (value, record_map) = match (v.kind, v.value.parse()) {
+                                    (DnsRecordType::A, Ok(IpAddr::V4(ip))) => {
+                                        (RData::A(ip), &a_record_map)
+                                    }
+                                    (DnsRecordType::AAAA, Ok(IpAddr::V6(ip))) => {
+                                        (RData::AAAA(ip), &aaaa_record_map)
+                                    }
+                                    (kind, result) => {
                                         tracing::warn!(
-                                            ?error,
+                                            error = ?result.err(),
+                                            ?kind,
                                             ip = v.value,
-                                            "Error parsing IP in SetDnsRecord request."
+                                            "Invalid IP for record type in SetDnsRecord request."
                                         );
                                         continue;
                                     }
                                 };
-                                let value = RData::A(ip);
-                                a_record_map
+                                record_map
                                     .lock()
-                                    .expect("a_record_map was poisoned")
+                                    .expect("address record map was poisoned")
                                     .insert(
                                         RecordKey {
                                             cluster: v.cluster.clone(),
@@ -124,31 +202,47 @@ impl ClusterDnsServer {
 
         ClusterDnsServer {
             a_record_map,
+            aaaa_record_map,
             txt_record_map,
             soa_email: plan.soa_email.clone(),
+            zones: plan.zones.clone(),
+            cluster_configs: plan.cluster_configs.clone(),
             _handle: handle,
         }
     }
 
+    fn record_ttl(&self, cluster: &ClusterName) -> u32 {
+        self.cluster_configs
+            .dns_ttl(cluster)
+            .unwrap_or(DNS_RECORD_TTL)
+    }
+
     async fn do_lookup(&self, request: &Request) -> Result<Vec<Record>> {
         let name = request.query().name().to_string();
-        let (hostname, cluster_name) = name
-            .split_once('.')
-            .or_dns_error(ResponseCode::NXDomain, || {
-                format!("Invalid name for this server {}", name)
-            })?;
-        let cluster_name = if let Some(cluster_name) = cluster_name.strip_suffix('.') {
-            ClusterName::new(cluster_name)
-        } else {
-            ClusterName::new(cluster_name)
-        };
+        let (cluster_name, hostname) = locate(&self.zones, &name)?;
+        let zone = self.zones.get(&cluster_name);
 
         tracing::info!(?cluster_name, %hostname, "Received DNS record request.");
+        let ttl = self.record_ttl(&cluster_name);
+        let query_type = request.query().query_type();
+
+        // Static records of the zone are served alongside those of drones.
+        let mut responses: Vec<Record> = zone
+            .map(|zone| {
+                let name: Name = request.query().name().clone().into();
+                zone.records
+                    .iter()
+                    .filter(|(record_name, rdata)| {
+                        record_name.eq_ignore_ascii_case(&hostname)
+                            && rdata.to_record_type() == query_type
+                    })
+                    .map(|(_, rdata)| Record::from_rdata(name.clone(), ttl, rdata.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
 
-        match request.query().query_type() {
+        match query_type {
             RecordType::TXT => {
-                let mut responses = Vec::new();
-
                 if let Some(v) = self
                     .txt_record_map
                     .lock()
@@ -156,45 +250,68 @@ impl ClusterDnsServer {
                     .iter(
                         &RecordKey {
                             cluster: cluster_name,
-                            name: hostname.into(),
+                            name: hostname,
                         },
                         SystemTime::now(),
                     )
                 {
                     let name: Name = request.query().name().clone().into();
                     for rdata in v {
-                        let record =
-                            Record::from_rdata(name.clone(), DNS_RECORD_TTL, rdata.clone());
+                        let record = Record::from_rdata(name.clone(), ttl, rdata.clone());
                         responses.push(record);
                     }
                 }
 
                 Ok(responses)
             }
-            RecordType::A => {
-                let mut responses = Vec::new();
-
-                if let Some(v) = self
-                    .a_record_map
+            record_type @ (RecordType::A | RecordType::AAAA) => {
+                let record_map = if record_type == RecordType::A {
+                    &self.a_record_map
+                } else {
+                    &self.aaaa_record_map
+                };
+
+                if let Some(v) = record_map
                     .lock()
-                    .expect("a_record_map was poisoned")
+                    .expect("address record map was poisoned")
                     .get(
                         &RecordKey {
                             cluster: cluster_name,
-                            name: hostname.into(),
+                            name: hostname,
                         },
                         SystemTime::now(),
                     )
                 {
                     let name = request.query().name().clone();
                     let rdata = v.clone();
-                    let record = Record::from_rdata(name.into(), DNS_RECORD_TTL, rdata);
+                    let record = Record::from_rdata(name.into(), ttl, rdata);
                     responses.push(record);
                 }
 
                 Ok(responses)
             }
+            RecordType::NS => {
+                if let (Some(zone), true) = (zone, hostname.is_empty()) {
+                    let name: Name = request.query().name().clone().into();
+                    for name_server in &zone.name_servers {
+                        let rdata = RData::NS(name_server.clone());
+                        responses.push(Record::from_rdata(name.clone(), ttl, rdata));
+                    }
+                }
+
+                Ok(responses)
+            }
             RecordType::SOA => {
+                if let Some(zone) = zone {
+                    // Only the apex of a zone has an SOA record.
+                    if !hostname.is_empty() {
+                        return Ok(vec![]);
+                    }
+
+                    let name: Name = request.query().name().clone().into();
+                    return Ok(vec![zone_soa(zone, name)?]);
+                }
+
                 let name = request.query().name();
                 let soa_record = self
                     .soa_email
@@ -217,7 +334,7 @@ impl ClusterDnsServer {
 
                 Ok(vec![record])
             }
-            RecordType::CAA | RecordType::AAAA => Ok(vec![]), // Not supported but don't report.
+            RecordType::CAA => Ok(vec![]), // Not supported but don't report.
             request => {
                 tracing::info!(?request, "Unhandled lookup type {}.", request);
                 Ok(vec![])
@@ -226,6 +343,21 @@ impl ClusterDnsServer {
     }
 }
 
+/// A [ClusterDnsServer] shared by the plain and encrypted listeners, so that
+/// they answer from the same records.
+#[derive(Clone)]
+struct SharedDnsServer(Arc<ClusterDnsServer>);
+
+#[async_trait]
+impl RequestHandler for SharedDnsServer {
+    async fn handle_request<R>(&self, request: &Request, response_handle: R) -> ResponseInfo
+    where
+        R: ResponseHandler,
+    {
+        self.0.handle_request(request, response_handle).await
+    }
+}
+
 #[async_trait]
 impl RequestHandler for ClusterDnsServer {
     async fn handle_request<R>(&self, request: &Request, mut response_handle: R) -> ResponseInfo
@@ -237,6 +369,7 @@ impl RequestHandler for ClusterDnsServer {
 
         let result = match self.do_lookup(request).await {
             Ok(answers) => {
+                header.set_authoritative(true);
                 let response = builder.build(header, answers.iter(), vec![], vec![], vec![]);
                 response_handle.send_response(response).await
             }
@@ -259,7 +392,8 @@ impl RequestHandler for ClusterDnsServer {
 }
 
 pub async fn serve_dns(plan: DnsPlan) -> anyhow::Result<Never> {
-    let mut fut = ServerFuture::new(ClusterDnsServer::new(&plan).await);
+    let handler = SharedDnsServer(Arc::new(ClusterDnsServer::new(&plan).await));
+    let mut fut = ServerFuture::new(handler.clone());
 
     let ip_port_pair = (plan.bind_ip, plan.port);
 
@@ -278,9 +412,72 @@ pub async fn serve_dns(plan: DnsPlan) -> anyhow::Result<Never> {
 
     tracing::info!(ip=%plan.bind_ip, port=%plan.port, "Listening for DNS queries.");
 
-    fut.block_until_done()
-        .await
-        .context("Internal DNS error.")?;
+    let encrypted = match &plan.encrypted {
+        Some(encrypted) => Some((
+            encrypted,
+            EncryptedListeners::bind(plan.bind_ip, encrypted).await?,
+        )),
+        None => None,
+    };
+
+    // Record TTLs come from the cluster configs, which are tracked here
+    // unless the scheduler sharing the tracker already does.
+    tokio::select! {
+        result = fut.block_until_done() => {
+            result.context("Internal DNS error.")?;
+        }
+        result = plan.cluster_configs.track(&plan.nc) => return result,
+        result = async {
+            match encrypted {
+                Some((encrypted, listeners)) => serve_encrypted(
+                    handler,
+                    listeners,
+                    &encrypted.certificate,
+                    std::time::Duration::from_secs(encrypted.certificate_check_secs),
+                ).await,
+                None => std::future::pending().await,
+            }
+        } => return result,
+    }
 
     Err(anyhow!("DNS server terminated unexpectedly."))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate() {
+        let (cluster, hostname) = locate(&HashMap::new(), "abc.plane.test.").unwrap();
+        assert_eq!(ClusterName::new("plane.test"), cluster);
+        assert_eq!("abc", hostname);
+
+        let zone = ZonePlan {
+            name_servers: vec![],
+            soa_email: None,
+            soa: Default::default(),
+            records: vec![],
+        };
+        let zones: HashMap<ClusterName, ZonePlan> = [
+            (ClusterName::new("plane.test"), zone.clone()),
+            (ClusterName::new("eu.plane.test"), zone),
+        ]
+        .into_iter()
+        .collect();
+
+        let (cluster, hostname) = locate(&zones, "abc.def.plane.test.").unwrap();
+        assert_eq!(ClusterName::new("plane.test"), cluster);
+        assert_eq!("abc.def", hostname);
+
+        let (cluster, hostname) = locate(&zones, "ABC.EU.plane.test.").unwrap();
+        assert_eq!(ClusterName::new("eu.plane.test"), cluster);
+        assert_eq!("ABC", hostname);
+
+        let (cluster, hostname) = locate(&zones, "plane.test.").unwrap();
+        assert_eq!(ClusterName::new("plane.test"), cluster);
+        assert_eq!("", hostname);
+
+        assert!(locate(&zones, "abc.otherplane.test.").is_err());
+    }
+}
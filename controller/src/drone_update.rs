@@ -0,0 +1,62 @@
+//! Rolling out new drone binaries.
+//!
+//! Clusters can be configured with the drone version their drones should
+//! run. The leader publishes it to the cluster periodically, and drones which
+//! are configured to update themselves download, verify, and re-exec into the
+//! new binary if they are running another version.
+
+use crate::{leader::Leadership, plan::ClusterPlan};
+use plane_core::{
+    logging::LogError, messages::scheduler::DroneUpdate, nats::TypedNats, types::ClusterName,
+    NeverResult,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+/// How often the desired version of each cluster is published, so that
+/// drones which start in the meantime pick it up.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DroneUpdateOptions {
+    /// Version of the drone binary, as reported in drone status messages.
+    pub version: String,
+
+    /// URL to download the drone binary from.
+    pub artifact_url: String,
+
+    /// Signature of the binary, as printed by `plane-cli sign-artifact`.
+    pub signature: String,
+}
+
+pub async fn drone_update_loop(
+    nats: &TypedNats,
+    clusters: &HashMap<ClusterName, ClusterPlan>,
+    leadership: &Leadership,
+) -> NeverResult {
+    let mut interval = tokio::time::interval(PUBLISH_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        if !leadership.is_leader() {
+            continue;
+        }
+
+        for (cluster, plan) in clusters {
+            let options = match &plan.drone_update {
+                Some(options) => options,
+                None => continue,
+            };
+
+            tracing::debug!(%cluster, version=%options.version, "Publishing drone update.");
+            nats.publish(&DroneUpdate {
+                cluster: cluster.clone(),
+                version: options.version.clone(),
+                artifact_url: options.artifact_url.clone(),
+                signature: options.signature.clone(),
+            })
+            .await
+            .log_error("Error publishing drone update.");
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use plane_core::{
+    messages::{agent::BackendState, scheduler::BackendGroupStatusMessage},
+    types::{BackendGroupId, BackendId, ClusterName},
+};
+use std::collections::HashMap;
+
+/// Tracks the state of backends which were scheduled as members of a replica
+/// group, so that an aggregated status can be published for each group.
+#[derive(Default)]
+pub struct GroupTracker {
+    /// Cluster and group of each live backend which belongs to a group.
+    membership: DashMap<BackendId, (ClusterName, BackendGroupId)>,
+
+    /// Most recent state and URL of each member of each group.
+    members: DashMap<(ClusterName, BackendGroupId), HashMap<BackendId, (BackendState, String)>>,
+}
+
+fn group_status(
+    cluster: &ClusterName,
+    group: &BackendGroupId,
+    members: &HashMap<BackendId, (BackendState, String)>,
+    time: DateTime<Utc>,
+) -> BackendGroupStatusMessage {
+    let mut status = BackendGroupStatusMessage {
+        cluster: cluster.clone(),
+        group: group.clone(),
+        ready: 0,
+        pending: 0,
+        failed: 0,
+        endpoints: Vec::new(),
+        time,
+    };
+
+    for (state, url) in members.values() {
+        match state {
+            BackendState::Ready => {
+                status.ready += 1;
+                status.endpoints.push(url.clone());
+            }
+            // A checkpointed backend is on its way to another drone.
+            BackendState::Loading | BackendState::Starting | BackendState::Checkpointed => {
+                status.pending += 1
+            }
+            BackendState::ErrorLoading
+            | BackendState::ErrorStarting
+            | BackendState::TimedOutBeforeReady
+            | BackendState::Failed
+            | BackendState::OutOfMemory => status.failed += 1,
+            BackendState::Exited | BackendState::Swept | BackendState::Terminated => (),
+        }
+    }
+
+    status.endpoints.sort();
+    status
+}
+
+impl GroupTracker {
+    /// Add a backend to a group, with the URL it was scheduled with.
+    pub fn add_member(
+        &self,
+        cluster: &ClusterName,
+        group: &BackendGroupId,
+        backend: &BackendId,
+        url: &str,
+    ) {
+        self.membership
+            .insert(backend.clone(), (cluster.clone(), group.clone()));
+        self.members
+            .entry((cluster.clone(), group.clone()))
+            .or_default()
+            .insert(backend.clone(), (BackendState::Loading, url.to_string()));
+    }
+
+    /// Record a state change of a backend. If the backend belongs to a group,
+    /// returns the updated status of that group.
+    pub fn update_state(
+        &self,
+        backend: &BackendId,
+        state: BackendState,
+        time: DateTime<Utc>,
+    ) -> Option<BackendGroupStatusMessage> {
+        let (cluster, group) = self.membership.get(backend)?.value().clone();
+        if state.terminal() {
+            self.membership.remove(backend);
+        }
+
+        let key = (cluster, group);
+        let (status, finished) = {
+            let mut members = self.members.get_mut(&key)?;
+            if let Some(member) = members.get_mut(backend) {
+                member.0 = state;
+            }
+
+            (
+                group_status(&key.0, &key.1, &members, time),
+                members.values().all(|(state, _)| state.terminal()),
+            )
+        };
+
+        // Once every member has reached a terminal state, the group can no longer change.
+        if finished {
+            self.members.remove(&key);
+        }
+
+        Some(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plane_core::messages::scheduler::BackendUrlConfig;
+
+    #[test]
+    fn test_group_status() {
+        let tracker = GroupTracker::default();
+        let cluster = ClusterName::new("mycluster.test");
+        let group = "mygroup".parse::<BackendGroupId>().unwrap();
+        let backend1 = BackendId::new("backend1".into());
+        let backend2 = BackendId::new("backend2".into());
+        let now = Utc::now();
+        let backend_url = BackendUrlConfig {
+            hostname_suffix: Some("backends.test".into()),
+            port: Some(8443),
+            path_routing: true,
+            ..BackendUrlConfig::default()
+        };
+
+        for backend in [&backend1, &backend2] {
+            let url = backend_url.backend_url(backend, &cluster);
+            tracker.add_member(&cluster, &group, backend, &url);
+        }
+
+        assert!(tracker
+            .update_state(&BackendId::new("other".into()), BackendState::Ready, now)
+            .is_none());
+
+        let status = tracker
+            .update_state(&backend1, BackendState::Ready, now)
+            .unwrap();
+        assert_eq!(1, status.ready);
+        assert_eq!(1, status.pending);
+        assert_eq!(0, status.failed);
+        assert_eq!(
+            vec!["https://backends.test:8443/backends/backend1".to_string()],
+            status.endpoints
+        );
+
+        let status = tracker
+            .update_state(&backend2, BackendState::ErrorStarting, now)
+            .unwrap();
+        assert_eq!(1, status.ready);
+        assert_eq!(0, status.pending);
+        assert_eq!(1, status.failed);
+
+        let status = tracker
+            .update_state(&backend1, BackendState::Swept, now)
+            .unwrap();
+        assert_eq!(0, status.ready);
+        assert_eq!(1, status.failed);
+        assert!(status.endpoints.is_empty());
+
+        // All members are terminal, so the group is no longer tracked.
+        assert!(tracker
+            .update_state(&backend1, BackendState::Swept, now)
+            .is_none());
+    }
+}
@@ -0,0 +1,381 @@
+//! gRPC API of the controller, for clients in languages where NATS and JSON
+//! are awkward to use. Each call is translated into the equivalent NATS
+//! request, so that it goes through the same authentication, admission, and
+//! scheduling as requests made over NATS.
+//!
+//! The API is only served over TLS. Each call must carry a bearer token in its
+//! `authorization` metadata, which is authenticated with the API's
+//! [AuthProvider] before the call is translated, and is then passed on as the
+//! auth token of its NATS request.
+
+use crate::{
+    auth::{AuthProvider, Credentials},
+    plan::GrpcPlan,
+};
+use anyhow::{anyhow, Context};
+use futures::Stream;
+use plane_core::{
+    error::PlaneError,
+    messages::{
+        agent::{
+            BackendStateMessage, DockerExecutableConfig, EgressPolicy, IdlePolicy,
+            ObservabilityOptions, ResourceLimits,
+        },
+        scheduler::{AffinityGroup, ScheduleRequest, ScheduleResponse, TerminateBackendRequest},
+    },
+    nats::TypedNats,
+    types::{BackendGroupId, BackendId, ClusterName},
+    NeverResult,
+};
+use proto::controller_server::{Controller, ControllerServer};
+use std::{net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+use tonic::{
+    metadata::MetadataMap,
+    transport::{Identity, Server, ServerTlsConfig},
+    Request, Response, Status,
+};
+
+pub mod proto {
+    tonic::include_proto!("plane.v1");
+}
+
+/// Status a gRPC call fails with, given the error its NATS request failed with.
+fn status_from_plane_error(error: &PlaneError) -> Status {
+    let message = error.to_string();
+    match error {
+        PlaneError::NoDroneAvailable
+        | PlaneError::DroneTimeout { .. }
+        | PlaneError::InsufficientDisk { .. } => Status::unavailable(message),
+        PlaneError::Unauthenticated { .. } => Status::unauthenticated(message),
+        PlaneError::InvalidRequest { .. } => Status::invalid_argument(message),
+        PlaneError::NotFound { .. } => Status::not_found(message),
+        PlaneError::QuotaExceeded { .. } => Status::resource_exhausted(message),
+        PlaneError::AdmissionDenied { .. } => Status::permission_denied(message),
+        PlaneError::LoadTimeout { .. }
+        | PlaneError::SpawnQueueTimeout { .. }
+        | PlaneError::ScheduleDeadlineExceeded { .. } => Status::deadline_exceeded(message),
+        PlaneError::ImagePullFailed { .. } | PlaneError::Internal { .. } => {
+            Status::internal(message)
+        }
+    }
+}
+
+fn status_from_anyhow(error: &anyhow::Error) -> Status {
+    tracing::warn!(?error, "Error making NATS request for gRPC call.");
+    Status::unavailable(error.to_string())
+}
+
+fn resource_limits(limits: proto::ResourceLimits) -> Result<ResourceLimits, Status> {
+    let cpu_period_percent = limits
+        .cpu_period_percent
+        .map(u8::try_from)
+        .transpose()
+        .map_err(|_| Status::invalid_argument("cpu_period_percent must be at most 255."))?;
+    let io_weight = limits
+        .io_weight
+        .map(u16::try_from)
+        .transpose()
+        .map_err(|_| Status::invalid_argument("io_weight must be at most 1000."))?;
+
+    Ok(ResourceLimits {
+        cpu_period: limits.cpu_period_secs.map(Duration::from_secs),
+        cpu_period_percent,
+        cpu_time_limit: limits.cpu_time_limit_secs.map(Duration::from_secs),
+        memory_limit_bytes: limits.memory_limit_bytes,
+        cpu_quota: limits.cpu_quota_micros.map(Duration::from_micros),
+        cpu_shares: limits.cpu_shares,
+        io_weight,
+        pids_limit: limits.pids_limit,
+    })
+}
+
+fn schedule_request(request: proto::SpawnRequest) -> Result<ScheduleRequest, Status> {
+    if request.cluster.is_empty() {
+        return Err(Status::invalid_argument("cluster is required."));
+    }
+    if request.image.is_empty() {
+        return Err(Status::invalid_argument("image is required."));
+    }
+
+    Ok(ScheduleRequest {
+        cluster: ClusterName::new(&request.cluster),
+        backend_id: request.backend_id.map(BackendId::new),
+        max_idle_secs: request.max_idle_secs.map(Duration::from_secs),
+        max_lifetime_secs: request.max_lifetime_secs.map(Duration::from_secs),
+        port_ready_timeout_secs: request.port_ready_timeout_secs.map(Duration::from_secs),
+        metadata: request.metadata,
+        executable: DockerExecutableConfig {
+            image: request.image,
+            env: request.env,
+            credentials: None,
+            resource_limits: request
+                .resource_limits
+                .map(resource_limits)
+                .transpose()?
+                .unwrap_or_default(),
+            egress_policy: EgressPolicy::default(),
+        },
+        require_bearer_token: request.require_bearer_token,
+        group: request
+            .group
+            .map(BackendGroupId::try_from)
+            .transpose()
+            .map_err(|error| Status::invalid_argument(error.to_string()))?,
+        dry_run: false,
+        schedule_deadline_ms: request.schedule_deadline_ms.map(Duration::from_millis),
+        idle_policy: IdlePolicy::default(),
+        arch: request.arch,
+        progress_id: None,
+        affinity_group: request.affinity_group.map(|name| AffinityGroup {
+            name,
+            strict: request.strict_affinity,
+        }),
+        observability: ObservabilityOptions {
+            log_level: request.log_level,
+            otel_endpoint: request.otel_endpoint,
+            trace_sample_rate: request.trace_sample_rate,
+        },
+    })
+}
+
+fn backend_state_event(message: &BackendStateMessage) -> proto::BackendStateEvent {
+    proto::BackendStateEvent {
+        backend_id: message.backend.id().to_string(),
+        state: format!("{:?}", message.state),
+        time: message.time.to_rfc3339(),
+        drone_id: message.drone.as_ref().map(|drone| drone.id().to_string()),
+        reason: message.reason.clone(),
+    }
+}
+
+/// The bearer token in a call's `authorization` metadata, if any.
+fn bearer_token(metadata: &MetadataMap) -> Option<String> {
+    metadata
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(ToString::to_string)
+}
+
+struct ControllerService {
+    nats: TypedNats,
+    auth: Arc<dyn AuthProvider>,
+}
+
+impl ControllerService {
+    /// Authenticate a call, and return the NATS client to make its requests
+    /// with, which sends the call's bearer token.
+    async fn authenticate(
+        &self,
+        method: &str,
+        metadata: &MetadataMap,
+    ) -> Result<TypedNats, Status> {
+        let credentials = Credentials {
+            bearer_token: bearer_token(metadata),
+        };
+        let principal = self
+            .auth
+            .authenticate(&credentials)
+            .await
+            .map_err(|reason| {
+                tracing::warn!(%reason, %method, "Rejected gRPC call.");
+                Status::unauthenticated(reason)
+            })?;
+        tracing::info!(%principal, %method, "Authenticated gRPC call.");
+
+        Ok(self.nats.clone().with_auth_token(credentials.bearer_token))
+    }
+}
+
+type BackendStateStream =
+    Pin<Box<dyn Stream<Item = Result<proto::BackendStateEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Controller for ControllerService {
+    async fn spawn(
+        &self,
+        request: Request<proto::SpawnRequest>,
+    ) -> Result<Response<proto::SpawnResponse>, Status> {
+        let nats = self.authenticate("Spawn", request.metadata()).await?;
+        let schedule_request = schedule_request(request.into_inner())?;
+
+        match nats
+            .request(&schedule_request)
+            .await
+            .map_err(|error| status_from_anyhow(&error))?
+        {
+            ScheduleResponse::Scheduled {
+                drone,
+                backend_id,
+                bearer_token,
+                url,
+            } => Ok(Response::new(proto::SpawnResponse {
+                drone_id: drone.id().to_string(),
+                backend_id: backend_id.id().to_string(),
+                bearer_token,
+                url,
+            })),
+            ScheduleResponse::DryRun { .. } => Err(Status::internal(
+                "Controller responded to a spawn with a dry run.",
+            )),
+            ScheduleResponse::Error(error) => Err(status_from_plane_error(&error)),
+            ScheduleResponse::Throttled { retry_after_ms } => {
+                let mut status = Status::resource_exhausted(format!(
+                    "Too many spawn requests; retry after {}ms.",
+                    retry_after_ms
+                ));
+                status
+                    .metadata_mut()
+                    .insert("retry-after-ms", retry_after_ms.into());
+                Err(status)
+            }
+        }
+    }
+
+    async fn terminate(
+        &self,
+        request: Request<proto::TerminateRequest>,
+    ) -> Result<Response<proto::TerminateResponse>, Status> {
+        let nats = self.authenticate("Terminate", request.metadata()).await?;
+        let request = request.into_inner();
+
+        let terminate_request = TerminateBackendRequest {
+            backend: BackendId::new(request.backend_id),
+            drain: request.grace_period_secs.is_some(),
+            grace_period_secs: Duration::from_secs(request.grace_period_secs.unwrap_or_default()),
+        };
+        let result = match request.cluster {
+            Some(cluster) => {
+                nats.request(&terminate_request.termination_request(ClusterName::new(&cluster)))
+                    .await
+            }
+            None => nats.request(&terminate_request).await,
+        };
+        result
+            .map_err(|error| status_from_anyhow(&error))?
+            .map_err(|error| status_from_plane_error(&error))?;
+
+        Ok(Response::new(proto::TerminateResponse {}))
+    }
+
+    type WatchBackendStateStream = BackendStateStream;
+
+    async fn watch_backend_state(
+        &self,
+        request: Request<proto::WatchBackendStateRequest>,
+    ) -> Result<Response<Self::WatchBackendStateStream>, Status> {
+        let nats = self
+            .authenticate("WatchBackendState", request.metadata())
+            .await?;
+        let backend = BackendId::new(request.into_inner().backend_id);
+        let sub = nats
+            .subscribe_jetstream(BackendStateMessage::subscribe_subject(&backend))
+            .await
+            .map_err(|error| status_from_anyhow(&error))?;
+
+        let stream = futures::stream::unfold(Some(sub), |sub| async move {
+            let mut sub = sub?;
+            let message = sub.next().await?;
+            // No state follows a terminal one, so the stream ends there.
+            let sub = (!message.state.terminal()).then_some(sub);
+            Some((Ok(backend_state_event(&message)), sub))
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+pub async fn serve_grpc(plan: GrpcPlan) -> NeverResult {
+    let addr = SocketAddr::new(plan.bind_ip, plan.port);
+    let cert = std::fs::read(&plan.tls.cert_path)
+        .with_context(|| format!("Error reading {}.", plan.tls.cert_path.display()))?;
+    let key = std::fs::read(&plan.tls.key_path)
+        .with_context(|| format!("Error reading {}.", plan.tls.key_path.display()))?;
+    let tls = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+    tracing::info!(%addr, "Serving gRPC API over TLS.");
+
+    Server::builder()
+        .tls_config(tls)
+        .context("Invalid gRPC TLS configuration.")?
+        .add_service(ControllerServer::new(ControllerService {
+            nats: plan.nc,
+            auth: plan.auth,
+        }))
+        .serve(addr)
+        .await?;
+
+    Err(anyhow!("gRPC server exited."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn_request() -> proto::SpawnRequest {
+        proto::SpawnRequest {
+            cluster: "plane.test".into(),
+            image: "ghcr.io/drifting-in-space/test-image:latest".into(),
+            max_idle_secs: Some(60),
+            ..proto::SpawnRequest::default()
+        }
+    }
+
+    #[test]
+    fn test_schedule_request() {
+        let request = schedule_request(spawn_request()).unwrap();
+        assert_eq!(ClusterName::new("plane.test"), request.cluster);
+        assert_eq!(Some(Duration::from_secs(60)), request.max_idle_secs);
+        assert_eq!(
+            ResourceLimits::default(),
+            request.executable.resource_limits
+        );
+
+        let request = proto::SpawnRequest {
+            resource_limits: Some(proto::ResourceLimits {
+                cpu_period_percent: Some(300),
+                ..proto::ResourceLimits::default()
+            }),
+            ..spawn_request()
+        };
+        assert_eq!(
+            tonic::Code::InvalidArgument,
+            schedule_request(request).unwrap_err().code()
+        );
+
+        let request = proto::SpawnRequest {
+            cluster: String::new(),
+            ..spawn_request()
+        };
+        assert_eq!(
+            tonic::Code::InvalidArgument,
+            schedule_request(request).unwrap_err().code()
+        );
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let mut metadata = MetadataMap::new();
+        assert_eq!(None, bearer_token(&metadata));
+
+        metadata.insert("authorization", "Basic abc".parse().unwrap());
+        assert_eq!(None, bearer_token(&metadata));
+
+        metadata.insert("authorization", "Bearer abc".parse().unwrap());
+        assert_eq!(Some("abc".to_string()), bearer_token(&metadata));
+    }
+
+    #[test]
+    fn test_status_from_plane_error() {
+        assert_eq!(
+            tonic::Code::Unavailable,
+            status_from_plane_error(&PlaneError::NoDroneAvailable).code()
+        );
+        assert_eq!(
+            tonic::Code::ResourceExhausted,
+            status_from_plane_error(&PlaneError::QuotaExceeded {
+                reason: "Too many backends.".into()
+            })
+            .code()
+        );
+    }
+}
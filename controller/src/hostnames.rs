@@ -0,0 +1,182 @@
+use crate::scheduler::Scheduler;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use plane_core::{
+    messages::dns::{DnsRecordType, SetDnsRecord},
+    types::{BackendId, ClusterName},
+};
+use std::sync::Mutex;
+
+/// Hostnames in use in each cluster, as evidenced by live DNS records, or by
+/// backends this controller has scheduled whose records may not have been
+/// published yet. Backends which have not terminated hold their hostname
+/// even without DNS records, e.g. while loading; the [Scheduler] tracks
+/// those.
+pub struct HostnameTracker {
+    /// Last time each hostname was seen in each cluster.
+    names: DashMap<(ClusterName, String), DateTime<Utc>>,
+
+    /// Last time any DNS record was seen for each cluster.
+    last_record: DashMap<ClusterName, DateTime<Utc>>,
+
+    /// Last time expired hostnames were removed.
+    last_prune: Mutex<DateTime<Utc>>,
+}
+
+impl Default for HostnameTracker {
+    fn default() -> Self {
+        HostnameTracker {
+            names: DashMap::default(),
+            last_record: DashMap::default(),
+            last_prune: Mutex::new(Utc::now()),
+        }
+    }
+}
+
+fn expired(seen: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+    now.signed_duration_since(seen).to_std().unwrap_or_default() > SetDnsRecord::ttl()
+}
+
+impl HostnameTracker {
+    pub fn record_dns(&self, record: &SetDnsRecord, now: DateTime<Utc>) {
+        self.last_record.insert(record.cluster.clone(), now);
+        if matches!(record.kind, DnsRecordType::A | DnsRecordType::AAAA) {
+            self.names
+                .insert((record.cluster.clone(), record.name.clone()), now);
+        }
+        self.prune(now);
+    }
+
+    /// Hold a hostname for a backend which was just scheduled, until its
+    /// state or its own DNS records take over.
+    pub fn reserve(&self, cluster: &ClusterName, backend: &BackendId, now: DateTime<Utc>) {
+        self.names
+            .insert((cluster.clone(), backend.id().to_string()), now);
+    }
+
+    /// Whether a hostname is in use in a cluster.
+    pub fn in_use(
+        &self,
+        scheduler: &Scheduler,
+        cluster: &ClusterName,
+        backend: &BackendId,
+        now: DateTime<Utc>,
+    ) -> bool {
+        scheduler.is_live(cluster, backend)
+            || self
+                .names
+                .get(&(cluster.clone(), backend.id().to_string()))
+                .map_or(false, |seen| !expired(*seen, now))
+    }
+
+    pub fn last_dns_record(&self, cluster: &ClusterName) -> Option<DateTime<Utc>> {
+        self.last_record.get(cluster).map(|last| *last)
+    }
+
+    /// Clusters which DNS records have been seen for.
+    pub fn clusters(&self) -> Vec<ClusterName> {
+        self.last_record
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// Remove expired hostnames, at most once per TTL.
+    fn prune(&self, now: DateTime<Utc>) {
+        {
+            let mut last_prune = self.last_prune.lock().expect("last_prune lock poisoned.");
+            if !expired(*last_prune, now) {
+                return;
+            }
+            *last_prune = now;
+        }
+        self.names.retain(|_, seen| !expired(*seen, now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plane_core::{
+        messages::agent::{BackendState, BackendStateMessage},
+        types::DroneId,
+    };
+
+    fn a_record(cluster: &ClusterName, name: &str) -> SetDnsRecord {
+        SetDnsRecord {
+            cluster: cluster.clone(),
+            kind: DnsRecordType::A,
+            name: name.to_string(),
+            value: "127.0.0.1".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_hostname_in_use() {
+        let tracker = HostnameTracker::default();
+        let scheduler = Scheduler::default();
+        let cluster = ClusterName::new("mycluster.test");
+        let other_cluster = ClusterName::new("othercluster.test");
+        let backend = BackendId::new("game-lobby-42".into());
+        let now = Utc::now();
+
+        assert!(!tracker.in_use(&scheduler, &cluster, &backend, now));
+
+        tracker.record_dns(&a_record(&cluster, "game-lobby-42"), now);
+        assert!(tracker.in_use(&scheduler, &cluster, &backend, now));
+        assert!(!tracker.in_use(&scheduler, &other_cluster, &backend, now));
+        assert_eq!(Some(now), tracker.last_dns_record(&cluster));
+
+        let later = now + chrono::Duration::seconds(120);
+        assert!(!tracker.in_use(&scheduler, &cluster, &backend, later));
+
+        tracker.reserve(&other_cluster, &backend, later);
+        assert!(tracker.in_use(&scheduler, &other_cluster, &backend, later));
+    }
+
+    #[test]
+    fn test_loading_backend_holds_hostname() {
+        let tracker = HostnameTracker::default();
+        let scheduler = Scheduler::default();
+        let cluster = ClusterName::new("mycluster.test");
+        let backend = BackendId::new("game-lobby-42".into());
+        let now = Utc::now();
+
+        tracker.reserve(&cluster, &backend, now);
+        scheduler.update_backend_state(
+            &BackendStateMessage::new(BackendState::Loading, backend.clone())
+                .with_drone(DroneId::new_random()),
+            now,
+        );
+
+        // The backend is still loading, so has published no DNS records,
+        // after its reservation has expired.
+        let later = now + chrono::Duration::seconds(120);
+        assert!(tracker.in_use(&scheduler, &cluster, &backend, later));
+
+        scheduler.update_backend_state(
+            &BackendStateMessage::new(BackendState::ErrorLoading, backend.clone())
+                .with_drone(DroneId::new_random()),
+            later,
+        );
+        assert!(!tracker.in_use(&scheduler, &cluster, &backend, later));
+    }
+
+    #[test]
+    fn test_hostname_label_validation() {
+        for label in ["game-lobby-42", "a", "a".repeat(63).as_str()] {
+            assert!(BackendId::new(label.to_string()).is_valid_hostname_label());
+        }
+        for label in [
+            "",
+            "-a",
+            "a-",
+            "Game",
+            "a.b",
+            "a_b",
+            "a".repeat(64).as_str(),
+        ] {
+            assert!(!BackendId::new(label.to_string()).is_valid_hostname_label());
+        }
+    }
+}
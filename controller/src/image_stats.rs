@@ -0,0 +1,165 @@
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use plane_core::{
+    messages::{agent::BackendState, scheduler::ImageStats},
+    types::BackendId,
+};
+
+/// A backend which has been scheduled but has not yet reached a terminal state.
+struct LiveBackend {
+    image: String,
+    scheduled: DateTime<Utc>,
+}
+
+/// Accumulated statistics for one image.
+#[derive(Default)]
+struct ImageTotals {
+    stats: ImageStats,
+    total_cold_start_ms: u64,
+    total_lifetime_secs: u64,
+}
+
+/// Tracks the outcome of each backend scheduled by this controller, aggregated
+/// by image.
+#[derive(Default)]
+pub struct ImageStatsTracker {
+    backends: DashMap<BackendId, LiveBackend>,
+    images: DashMap<String, ImageTotals>,
+}
+
+impl ImageStatsTracker {
+    pub fn record_spawn(&self, backend: &BackendId, image: &str, timestamp: DateTime<Utc>) {
+        self.backends.insert(
+            backend.clone(),
+            LiveBackend {
+                image: image.to_string(),
+                scheduled: timestamp,
+            },
+        );
+        self.images
+            .entry(image.to_string())
+            .or_default()
+            .stats
+            .spawned += 1;
+    }
+
+    pub fn update_state(&self, backend: &BackendId, state: BackendState, timestamp: DateTime<Utc>) {
+        let (image, elapsed) = match self.backends.get(backend) {
+            Some(live) => (
+                live.image.clone(),
+                timestamp
+                    .signed_duration_since(live.scheduled)
+                    .max(chrono::Duration::zero()),
+            ),
+            None => return,
+        };
+
+        if state.terminal() {
+            self.backends.remove(backend);
+        }
+
+        let mut totals = self.images.entry(image).or_default();
+        match state {
+            BackendState::Ready => {
+                totals.stats.ready += 1;
+                totals.total_cold_start_ms += elapsed.num_milliseconds() as u64;
+            }
+            BackendState::Swept => totals.stats.swept += 1,
+            BackendState::Terminated => totals.stats.terminated += 1,
+            BackendState::Exited => totals.stats.exited += 1,
+            BackendState::ErrorLoading
+            | BackendState::ErrorStarting
+            | BackendState::TimedOutBeforeReady
+            | BackendState::Failed
+            | BackendState::OutOfMemory => totals.stats.failed += 1,
+            BackendState::Loading | BackendState::Starting | BackendState::Checkpointed => (),
+        }
+
+        if state.terminal() {
+            totals.total_lifetime_secs += elapsed.num_seconds() as u64;
+        }
+    }
+
+    /// Statistics for every image seen (or only the given image), sorted by image.
+    pub fn stats(&self, image: Option<&str>) -> Vec<ImageStats> {
+        let mut result: Vec<ImageStats> = self
+            .images
+            .iter()
+            .filter(|entry| image.map(|image| image == entry.key()).unwrap_or(true))
+            .map(|entry| {
+                let totals = entry.value();
+                let finished = totals.stats.swept
+                    + totals.stats.terminated
+                    + totals.stats.exited
+                    + totals.stats.failed;
+
+                ImageStats {
+                    image: entry.key().clone(),
+                    mean_cold_start_ms: (totals.stats.ready > 0)
+                        .then(|| totals.total_cold_start_ms / totals.stats.ready),
+                    mean_lifetime_secs: (finished > 0)
+                        .then(|| totals.total_lifetime_secs / finished),
+                    ..totals.stats.clone()
+                }
+            })
+            .collect();
+
+        result.sort_by(|a, b| a.image.cmp(&b.image));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(date: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(date).unwrap().into()
+    }
+
+    #[test]
+    fn test_image_stats() {
+        let tracker = ImageStatsTracker::default();
+        let backend1 = BackendId::new("backend1".into());
+        let backend2 = BackendId::new("backend2".into());
+
+        tracker.record_spawn(&backend1, "image-a", date("2020-01-01T05:00:00+00:00"));
+        tracker.record_spawn(&backend2, "image-a", date("2020-01-01T05:00:00+00:00"));
+
+        tracker.update_state(
+            &backend1,
+            BackendState::Ready,
+            date("2020-01-01T05:00:02+00:00"),
+        );
+        tracker.update_state(
+            &backend1,
+            BackendState::Swept,
+            date("2020-01-01T05:01:00+00:00"),
+        );
+        tracker.update_state(
+            &backend2,
+            BackendState::ErrorStarting,
+            date("2020-01-01T05:00:20+00:00"),
+        );
+
+        // Backends which were not scheduled by this controller are ignored.
+        tracker.update_state(
+            &BackendId::new("other".into()),
+            BackendState::Failed,
+            date("2020-01-01T05:00:20+00:00"),
+        );
+
+        let stats = tracker.stats(None);
+        assert_eq!(1, stats.len());
+        let stats = &stats[0];
+        assert_eq!("image-a", stats.image);
+        assert_eq!(2, stats.spawned);
+        assert_eq!(1, stats.ready);
+        assert_eq!(1, stats.swept);
+        assert_eq!(1, stats.failed);
+        assert_eq!(Some(2000), stats.mean_cold_start_ms);
+        assert_eq!(Some(40), stats.mean_lifetime_secs);
+
+        assert!(tracker.stats(Some("image-b")).is_empty());
+    }
+}
@@ -0,0 +1,317 @@
+//! Leader election between controllers, so that several can run hot/standby
+//! without scheduling a request twice.
+//!
+//! Controllers compete for a lease held in a JetStream key-value bucket,
+//! whose entries expire after the lease duration. The leader renews the lease
+//! several times per lease duration; if it stops (e.g. because it died), the
+//! lease lapses and a standby acquires it. Only the leader schedules backends,
+//! while standbys keep consuming cluster state so that they can take over
+//! straight away.
+
+use anyhow::{anyhow, Result};
+use async_nats::jetstream::kv::{Config, Operation, Store};
+use chrono::{DateTime, Utc};
+use plane_core::{
+    logging::LogError, messages::scheduler::SchedulerLeader, nats::TypedNats, NeverResult,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+/// Key-value bucket which holds the leader lease.
+const LEADER_BUCKET: &str = "scheduler_leader";
+
+/// Key of the leader lease in [LEADER_BUCKET].
+const LEADER_KEY: &str = "leader";
+
+/// Number of times the leader renews its lease per lease duration.
+const RENEWALS_PER_LEASE: u32 = 3;
+
+fn default_lease_secs() -> u64 {
+    10
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LeaderElectionOptions {
+    /// Identifies this controller in the lease and in status messages.
+    /// Defaults to a random identifier.
+    pub controller_id: Option<String>,
+
+    /// How long the leader's lease lasts without being renewed. This bounds
+    /// how long scheduling stops for when the leader fails.
+    #[serde(default = "default_lease_secs")]
+    pub lease_secs: u64,
+}
+
+impl LeaderElectionOptions {
+    pub fn validate(&self) -> Result<()> {
+        if self.lease_secs == 0 {
+            return Err(anyhow!("lease_secs must be greater than 0."));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct StreamInfoResponse {
+    config: Option<StreamConfigInfo>,
+}
+
+/// The part of the lease bucket's configuration which sets how long an
+/// unrenewed lease lasts.
+#[derive(Deserialize, Debug)]
+struct StreamConfigInfo {
+    /// In nanoseconds, where zero means entries never expire.
+    #[serde(default)]
+    max_age: u64,
+}
+
+/// How long the lease lasts, given the configured duration and the
+/// `max_age` of the bucket holding it. The bucket may have been created by
+/// a controller configured differently, in which case its `max_age` is what
+/// governs when the lease lapses.
+fn lease_duration(configured: Duration, bucket_max_age: Duration) -> Result<Duration> {
+    if bucket_max_age.is_zero() {
+        return Err(anyhow!(
+            "The {} bucket has no max_age, so the leader lease would never lapse.",
+            LEADER_BUCKET
+        ));
+    }
+    if bucket_max_age != configured {
+        tracing::warn!(
+            ?configured,
+            ?bucket_max_age,
+            "The leader lease bucket was created with another lease duration; using the bucket's."
+        );
+    }
+
+    Ok(bucket_max_age)
+}
+
+/// Whether this controller is the leader. Controllers without leader
+/// election are always the leader.
+pub struct Leadership {
+    leader: AtomicBool,
+}
+
+impl Leadership {
+    #[must_use]
+    pub fn new(leader: bool) -> Self {
+        Leadership {
+            leader: AtomicBool::new(leader),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::Relaxed)
+    }
+
+    /// Set whether this controller is the leader, returning whether it was.
+    fn set(&self, leader: bool) -> bool {
+        self.leader.swap(leader, Ordering::Relaxed)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct Lease {
+    controller: String,
+    since: DateTime<Utc>,
+}
+
+/// What a controller should do, given the current state of the lease.
+#[derive(Debug, PartialEq, Eq)]
+enum LeaseAction {
+    /// Nobody holds the lease; take it. Carries the revision of the deleted
+    /// entry, if there is one.
+    Acquire(Option<u64>),
+
+    /// This controller holds the lease, which it should renew at the given
+    /// revision, keeping the time it became leader.
+    Renew(u64, DateTime<Utc>),
+
+    /// Another controller holds the lease.
+    Standby(String),
+}
+
+/// Decide what to do given the current entry of the lease, as its value and
+/// revision, and whether it has been deleted.
+fn lease_action(entry: Option<(Option<Lease>, u64)>, controller: &str) -> LeaseAction {
+    match entry {
+        None => LeaseAction::Acquire(None),
+        Some((None, revision)) => LeaseAction::Acquire(Some(revision)),
+        Some((Some(lease), revision)) if lease.controller == controller => {
+            LeaseAction::Renew(revision, lease.since)
+        }
+        Some((Some(lease), _)) => LeaseAction::Standby(lease.controller),
+    }
+}
+
+/// Acquire or renew the lease if possible. Returns the time this controller
+/// became leader if it holds the lease.
+async fn try_lead(store: &Store, controller: &str) -> Result<Option<DateTime<Utc>>> {
+    let entry = store
+        .entry(LEADER_KEY)
+        .await
+        .map_err(|error| anyhow!("Error reading leader lease: {}", error))?
+        .map(|entry| {
+            let lease = match entry.operation {
+                Operation::Put => serde_json::from_slice(&entry.value).ok(),
+                Operation::Delete | Operation::Purge => None,
+            };
+            (lease, entry.revision)
+        });
+
+    let (since, result) = match lease_action(entry, controller) {
+        LeaseAction::Standby(leader) => {
+            tracing::debug!(%leader, "Another controller holds the leader lease.");
+            return Ok(None);
+        }
+        LeaseAction::Acquire(revision) => {
+            let since = Utc::now();
+            let lease = serde_json::to_vec(&Lease {
+                controller: controller.to_string(),
+                since,
+            })?;
+            // Expecting revision 0 only succeeds if the key was never written.
+            let result = store
+                .update(LEADER_KEY, lease.into(), revision.unwrap_or(0))
+                .await;
+            (since, result)
+        }
+        LeaseAction::Renew(revision, since) => {
+            let lease = serde_json::to_vec(&Lease {
+                controller: controller.to_string(),
+                since,
+            })?;
+            (
+                since,
+                store.update(LEADER_KEY, lease.into(), revision).await,
+            )
+        }
+    };
+
+    match result {
+        Ok(_) => Ok(Some(since)),
+        Err(error) => {
+            // Another controller wrote the lease since it was read.
+            tracing::debug!(%error, "Lost race for the leader lease.");
+            Ok(None)
+        }
+    }
+}
+
+/// Compete for the leader lease, keeping `leadership` up to date, and
+/// publish the leader's identity while this controller holds it.
+pub async fn leader_election_loop(
+    nats: &TypedNats,
+    options: LeaderElectionOptions,
+    leadership: &Leadership,
+) -> NeverResult {
+    let controller = options
+        .controller_id
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    let store = nats
+        .key_value(Config {
+            bucket: LEADER_BUCKET.into(),
+            history: 1,
+            max_age: Duration::from_secs(options.lease_secs),
+            ..Config::default()
+        })
+        .await?;
+    let info: StreamInfoResponse = nats
+        .jetstream_api(&format!("STREAM.INFO.KV_{}", LEADER_BUCKET))
+        .await?;
+    let bucket_max_age = info
+        .config
+        .ok_or_else(|| anyhow!("Error getting info of the {} bucket.", LEADER_BUCKET))?
+        .max_age;
+    let lease_duration = lease_duration(
+        Duration::from_secs(options.lease_secs),
+        Duration::from_nanos(bucket_max_age),
+    )?;
+    tracing::info!(%controller, ?lease_duration, "Competing for the leader lease.");
+
+    let mut interval = tokio::time::interval(lease_duration / RENEWALS_PER_LEASE);
+
+    loop {
+        interval.tick().await;
+
+        let since = match try_lead(&store, &controller).await {
+            Ok(since) => since,
+            Err(error) => {
+                // The lease cannot be renewed, so another controller may
+                // take over at any time.
+                tracing::warn!(?error, "Error renewing leader lease; standing by.");
+                None
+            }
+        };
+
+        let was_leader = leadership.set(since.is_some());
+        match (was_leader, since) {
+            (false, Some(_)) => tracing::info!(%controller, "Became the leader."),
+            (true, None) => tracing::warn!(%controller, "No longer the leader."),
+            _ => (),
+        }
+
+        if let Some(since) = since {
+            nats.publish(&SchedulerLeader {
+                controller: controller.clone(),
+                since,
+            })
+            .await
+            .log_error("Error publishing scheduler leader.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lease(controller: &str) -> Lease {
+        Lease {
+            controller: controller.into(),
+            since: DateTime::parse_from_rfc3339("2022-11-01T12:00:00+00:00")
+                .unwrap()
+                .into(),
+        }
+    }
+
+    #[test]
+    fn test_lease_action() {
+        assert_eq!(LeaseAction::Acquire(None), lease_action(None, "a"));
+        assert_eq!(
+            LeaseAction::Acquire(Some(4)),
+            lease_action(Some((None, 4)), "a")
+        );
+        assert_eq!(
+            LeaseAction::Renew(5, lease("a").since),
+            lease_action(Some((Some(lease("a")), 5)), "a")
+        );
+        assert_eq!(
+            LeaseAction::Standby("b".into()),
+            lease_action(Some((Some(lease("b")), 5)), "a")
+        );
+    }
+
+    #[test]
+    fn test_lease_duration() {
+        let configured = Duration::from_secs(10);
+        assert_eq!(configured, lease_duration(configured, configured).unwrap());
+        assert_eq!(
+            Duration::from_secs(30),
+            lease_duration(configured, Duration::from_secs(30)).unwrap()
+        );
+        assert!(lease_duration(configured, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn test_leadership() {
+        let leadership = Leadership::new(false);
+        assert!(!leadership.is_leader());
+        assert!(!leadership.set(true));
+        assert!(leadership.is_leader());
+    }
+}
@@ -1,84 +1,1645 @@
+use admission::AdmissionWebhook;
 use anyhow::anyhow;
-use chrono::Utc;
+use async_nats::jetstream::consumer::DeliverPolicy;
+use auth::{AllowAll, AuthProvider, Credentials, Principal};
+use certs::certificate_push_loop;
+use chrono::{DateTime, Utc};
+use cluster_config::ClusterConfigTracker;
+use config::CertificatePushOptions;
+use diagnostics::diagnostics_loop;
+use drone_update::drone_update_loop;
+use futures::{future::join_all, stream::FuturesUnordered, StreamExt};
+use groups::GroupTracker;
+use hostnames::HostnameTracker;
+use image_stats::ImageStatsTracker;
+use leader::{leader_election_loop, Leadership};
+use lifecycle::DroneLifecycleTracker;
+use metadata::MetadataRegistry;
+use plan::{AutoscalerPlan, ClusterPlan, ImageCachePlan, SchedulerPlan};
 use plane_core::{
-    messages::agent::DroneStatusMessage,
-    messages::scheduler::{ScheduleRequest, ScheduleResponse},
-    nats::TypedNats,
+    error::PlaneError,
+    logging::LogError,
+    messages::agent::{
+        BackendMetadataMessage, BackendStateMessage, DockerCredentials, DroneRegistrationResponse,
+        DroneStatusMessage, RegisterDrone, ResourceLimits, SpawnRequest, TerminationRequest,
+    },
+    messages::dns::SetDnsRecord,
+    messages::scheduler::{
+        AffinityGroup, AffinityMember, ApplyClusterConfig, ApproveDrone, BackendLocation,
+        BackendLookupRequest, BatchScheduleRequest, ClusterListRequest, ClusterSummary,
+        DesiredDroneCount, DrainDrone, DroneApproval, DroneLifecycleMessage, ImageStatsRequest,
+        ScheduleRequest, ScheduleResponse, SeedImage, TerminateBackendRequest,
+    },
+    nats::{MessageWithResponseHandle, RequestTimeout, TypedMessage, TypedNats},
+    signing::{SeenNonces, VerifyingKey},
     timing::Timer,
+    types::{BackendId, ClusterName, DroneId},
     NeverResult,
 };
-use scheduler::Scheduler;
-use tokio::select;
+use rate_limit::{RateLimiter, Throttle};
+use registration::DroneRegistry;
+use retention::retention_loop;
+use scheduler::{Requirements, Scheduler};
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+use tokio::{
+    select,
+    sync::mpsc::{self, UnboundedSender},
+    time::{timeout_at, Instant},
+};
 
+pub mod admission;
+pub mod auth;
+mod certs;
+pub mod cluster_config;
 pub mod config;
+pub mod diagnostics;
 pub mod dns;
+pub mod drone_update;
+mod groups;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod hostnames;
+mod image_stats;
+pub mod leader;
+mod lifecycle;
+mod metadata;
+pub mod placement;
 pub mod plan;
+pub mod rate_limit;
+mod registration;
+pub mod retention;
 pub mod run;
 mod scheduler;
+mod simulate;
 pub mod ttl_store;
 
-pub async fn run_scheduler(nats: TypedNats) -> NeverResult {
-    let scheduler = Scheduler::default();
+/// Largest batch schedule request accepted, unless the scheduler is
+/// configured with another `max_batch_size`.
+pub const DEFAULT_MAX_BATCH_SIZE: u32 = 100;
+
+pub async fn run_scheduler(nats: TypedNats, plan: SchedulerPlan) -> NeverResult {
+    let scheduler = match plan.placement {
+        Some(placement) => Scheduler::new(placement),
+        None => Scheduler::default(),
+    }
+    .with_version_policy(plan.version_policy)
+    .with_image_affinity(plan.image_affinity)
+    .with_image_affinity_weight(plan.image_affinity_weight);
+    let scheduler = match &plan.autoscaler {
+        Some(autoscaler) => {
+            scheduler.with_failed_schedule_window(autoscaler.failed_schedule_window)
+        }
+        None => scheduler,
+    };
+    let groups = GroupTracker::default();
+    let image_stats = ImageStatsTracker::default();
+    let metadata = MetadataRegistry::default();
+    let hostnames = HostnameTracker::default();
+    let cluster_configs = plan.cluster_configs.clone();
+    let registry = DroneRegistry::default();
+    let rate_limiter = RateLimiter::new(plan.rate_limit);
+    let leadership = Leadership::new(plan.leader_election.is_none());
+    let auth = plan.auth.unwrap_or_else(|| Arc::new(AllowAll));
+    let certificates: HashMap<ClusterName, CertificatePushOptions> = plan
+        .clusters
+        .iter()
+        .filter_map(|(cluster, cluster_plan)| {
+            Some((cluster.clone(), cluster_plan.certificate.clone()?))
+        })
+        .collect();
+
+    select! {
+        result = scheduler_loop(
+            &nats,
+            auth.as_ref(),
+            plan.admission.as_ref(),
+            &scheduler,
+            &groups,
+            &image_stats,
+            &metadata,
+            &hostnames,
+            &cluster_configs,
+            &registry,
+            &rate_limiter,
+            &leadership,
+            &plan.clusters,
+            plan.max_batch_size.unwrap_or(DEFAULT_MAX_BATCH_SIZE),
+        ) => result,
+        result = drone_registration_loop(
+            &nats,
+            auth.as_ref(),
+            &registry,
+            &cluster_configs,
+            &leadership,
+            &plan.clusters,
+        ) => result,
+        result = backend_state_loop(&nats, &scheduler, &groups, &image_stats, &metadata) => result,
+        result = image_stats_loop(&nats, auth.as_ref(), &image_stats, &leadership) => result,
+        result = cluster_list_loop(&nats, auth.as_ref(), &scheduler, &hostnames) => result,
+        result = backend_lookup_loop(
+            &nats,
+            auth.as_ref(),
+            &scheduler,
+            &cluster_configs,
+            &plan.clusters,
+        ) => result,
+        result = terminate_backend_loop(
+            &nats,
+            auth.as_ref(),
+            &scheduler,
+            &cluster_configs,
+            &plan.clusters,
+            &leadership,
+        ) => result,
+        result = dns_record_loop(&nats, &hostnames) => result,
+        result = cluster_configs.track(&nats) => result,
+        result = apply_cluster_config_loop(&nats, auth.as_ref(), &leadership) => result,
+        result = drone_lifecycle_loop(&nats, &leadership) => result,
+        result = run_if_configured(
+            plan.autoscaler.map(|plan| {
+                capacity_report_loop(&nats, auth.as_ref(), &scheduler, &leadership, plan)
+            })
+        ) => result,
+        result = run_if_configured(
+            plan.image_cache.map(|plan| image_cache_loop(&nats, &scheduler, &leadership, plan))
+        ) => result,
+        result = run_if_configured(
+            plan.leader_election.map(|options| leader_election_loop(&nats, options, &leadership))
+        ) => result,
+        result = run_if_configured(
+            plan.diagnostics.map(|options| diagnostics_loop(&nats, options, &leadership))
+        ) => result,
+        result = run_if_configured(
+            plan.retention.map(|options| retention_loop(&nats, options, &leadership))
+        ) => result,
+        result = run_if_configured(
+            plan.clusters
+                .values()
+                .any(|cluster_plan| cluster_plan.drone_update.is_some())
+                .then(|| drone_update_loop(&nats, &plan.clusters, &leadership))
+        ) => result,
+        result = run_if_configured(
+            (!certificates.is_empty()).then(|| certificate_push_loop(&nats, certificates, &leadership))
+        ) => result,
+    }
+}
+
+/// Run an optional loop, or wait forever if it is not configured.
+async fn run_if_configured(fut: Option<impl Future<Output = NeverResult>>) -> NeverResult {
+    match fut {
+        Some(fut) => fut.await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Periodically ask one drone in each known cluster to pull each configured
+/// image, seeding the pull-through cache shared by the cluster's drones.
+async fn image_cache_loop(
+    nats: &TypedNats,
+    scheduler: &Scheduler,
+    leadership: &Leadership,
+    plan: ImageCachePlan,
+) -> NeverResult {
+    let mut interval = tokio::time::interval(plan.seed_interval);
+
+    loop {
+        interval.tick().await;
+        if !leadership.is_leader() {
+            continue;
+        }
+
+        for cluster in scheduler.clusters() {
+            let drone = match scheduler.schedule(&cluster, Requirements::default(), Utc::now()) {
+                Ok(drone) => drone,
+                Err(error) => {
+                    tracing::warn!(?error, %cluster, "No drone available to seed images.");
+                    continue;
+                }
+            };
+
+            for image in &plan.images {
+                tracing::info!(%cluster, %drone, %image, "Seeding image.");
+                nats.request(&SeedImage {
+                    drone: drone.clone(),
+                    cluster: cluster.clone(),
+                    image: image.clone(),
+                })
+                .await
+                .log_error("Error sending seed image request.");
+            }
+        }
+    }
+}
+
+async fn publish_lifecycle_events(nats: &TypedNats, events: Vec<DroneLifecycleMessage>) {
+    for event in events {
+        tracing::info!(?event, "Drone lifecycle event.");
+        nats.publish(&event)
+            .await
+            .log_error("Error publishing drone lifecycle event.");
+    }
+}
+
+/// Publish drone lifecycle events derived from drone status messages and
+/// drain requests. Every controller keeps track of drones, but only the
+/// leader publishes, so that each event is published once.
+async fn drone_lifecycle_loop(nats: &TypedNats, leadership: &Leadership) -> NeverResult {
+    let tracker = DroneLifecycleTracker::default();
+
+    let mut status_sub = nats
+        .subscribe(DroneStatusMessage::subscribe_subject())
+        .await?;
+    let mut drain_sub = nats.subscribe(DrainDrone::wildcard_subject()).await?;
+    tracing::info!("Subscribed to drone status messages and drain requests.");
+
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        select! {
+            _ = interval.tick() => {
+                let events = tracker.sweep(Utc::now());
+                if leadership.is_leader() {
+                    publish_lifecycle_events(nats, events).await;
+                }
+            },
+
+            status_msg = status_sub.next() => {
+                match status_msg {
+                    Some(status_msg) => {
+                        let events = tracker.update_status(&status_msg.value, Utc::now());
+                        if leadership.is_leader() {
+                            publish_lifecycle_events(nats, events).await;
+                        }
+                    }
+                    None => return Err(anyhow!("status_sub.next() returned None.")),
+                }
+            },
+
+            drain_msg = drain_sub.next() => {
+                match drain_msg {
+                    Some(drain_msg) => {
+                        // The drone itself responds to the drain request.
+                        let events = tracker.update_drain(&drain_msg.value, Utc::now());
+                        if leadership.is_leader() {
+                            publish_lifecycle_events(nats, events.into_iter().collect()).await;
+                        }
+                    }
+                    None => return Err(anyhow!("drain_sub.next() returned None.")),
+                }
+            },
+        }
+    }
+}
+
+/// Record the registrations of drones, and approve their keys when an
+/// operator asks to. Every controller keeps track of registrations and
+/// approvals, but only the leader responds.
+async fn drone_registration_loop(
+    nats: &TypedNats,
+    auth: &dyn AuthProvider,
+    registry: &DroneRegistry,
+    cluster_configs: &ClusterConfigTracker,
+    leadership: &Leadership,
+    clusters: &HashMap<ClusterName, ClusterPlan>,
+) -> NeverResult {
+    let mut register_sub = nats.subscribe(RegisterDrone::subscribe_subject()).await?;
+    tracing::info!("Subscribed to drone registrations.");
+
+    let mut approve_sub = nats.subscribe(ApproveDrone::subscribe_subject()).await?;
+    tracing::info!("Subscribed to drone approval requests.");
+
+    // Approvals are persisted in JetStream, so this replays every approval
+    // made before the controller started.
+    let mut approval_sub = nats
+        .subscribe_jetstream(DroneApproval::subscribe_subject())
+        .await?;
+    tracing::info!("Subscribed to drone approvals.");
+
+    let seen_nonces = SeenNonces::default();
+    loop {
+        select! {
+            registration = register_sub.next() => {
+                let registration = match registration {
+                    Some(registration) => registration,
+                    None => return Err(anyhow!("register_sub.next() returned None.")),
+                };
+                let value = &registration.value;
+                let cluster_plan = cluster_configs.plan(clusters, &value.cluster);
+
+                // The drone proves that it holds the key by signing the request.
+                let verified = VerifyingKey::from_base64(&value.public_key)
+                    .and_then(|key| registration.verify_signature(&key, &seen_nonces));
+                let registered = match verified {
+                    Ok(()) => registry.register(&cluster_plan, value),
+                    Err(error) => Err(format!("Registration signature rejected: {}", error)),
+                };
+
+                let response = match registered {
+                    Err(reason) => {
+                        tracing::warn!(
+                            %reason,
+                            drone_id=%value.drone_id,
+                            "Rejected drone registration."
+                        );
+                        DroneRegistrationResponse::Rejected { reason }
+                    }
+                    Ok(_) if registry.is_approved(
+                        &cluster_plan,
+                        &value.cluster,
+                        &value.public_key,
+                    ) => DroneRegistrationResponse::Approved,
+                    Ok(new) => {
+                        if new {
+                            tracing::info!(
+                                drone_id=%value.drone_id,
+                                cluster=%value.cluster,
+                                public_key=%value.public_key,
+                                key_fingerprint=%VerifyingKey::from_base64(&value.public_key)
+                                    .map(|key| key.fingerprint())
+                                    .unwrap_or_default(),
+                                host=?value.host,
+                                "Drone registered and is waiting for approval."
+                            );
+                        }
+                        DroneRegistrationResponse::Pending
+                    }
+                };
+
+                if leadership.is_leader() {
+                    registration.respond(&response).await?;
+                }
+            },
+
+            approve_request = approve_sub.next() => {
+                let approve_request = match approve_request {
+                    // Only the leader responds, so that each approval is published once.
+                    Some(_) if !leadership.is_leader() => continue,
+                    Some(approve_request) => approve_request,
+                    None => return Err(anyhow!("approve_sub.next() returned None.")),
+                };
+
+                let credentials = Credentials::from_message(&approve_request);
+                let result = match auth.authenticate(&credentials).await {
+                    Err(reason) => {
+                        tracing::warn!(%reason, "Rejected unauthenticated drone approval.");
+                        Err(PlaneError::Unauthenticated { reason })
+                    }
+                    Ok(principal) => match registry.registration_to_approve(&approve_request.value) {
+                        Ok(registration) => {
+                            let approval = DroneApproval {
+                                cluster: registration.cluster,
+                                public_key: registration.public_key,
+                                drone: registration.drone_id,
+                                time: Utc::now(),
+                            };
+                            match nats.publish_jetstream(&approval).await {
+                                Ok(()) => {
+                                    tracing::info!(%principal, ?approval, "Approved drone.");
+                                    registry.approve(&approval);
+                                    Ok(())
+                                }
+                                Err(error) => {
+                                    tracing::error!(?error, "Error publishing drone approval.");
+                                    Err(PlaneError::Internal {
+                                        reason: "Could not persist the approval.".into(),
+                                    })
+                                }
+                            }
+                        }
+                        Err(reason) => Err(PlaneError::InvalidRequest { reason }),
+                    },
+                };
+
+                approve_request.respond(&result).await?;
+            },
+
+            approval = approval_sub.next() => {
+                match approval {
+                    Some(approval) => registry.approve(&approval),
+                    None => return Err(anyhow!("approval_sub.next() returned None.")),
+                }
+            },
+        }
+    }
+}
+
+/// Respond to requests for per-image statistics. Spawns are only recorded by
+/// the leader, which schedules them, so only the leader responds.
+async fn image_stats_loop(
+    nats: &TypedNats,
+    auth: &dyn AuthProvider,
+    image_stats: &ImageStatsTracker,
+    leadership: &Leadership,
+) -> NeverResult {
+    let mut image_stats_sub = nats
+        .subscribe(ImageStatsRequest::subscribe_subject())
+        .await?;
+    tracing::info!("Subscribed to image stats requests.");
+
+    while let Some(req) = image_stats_sub.next().await {
+        if !leadership.is_leader() {
+            continue;
+        }
+        let principal = match auth.authenticate(&Credentials::from_message(&req)).await {
+            Ok(principal) => principal,
+            Err(reason) => {
+                tracing::warn!(%reason, "Ignored unauthenticated image stats request.");
+                continue;
+            }
+        };
+        tracing::debug!(%principal, image=?req.value.image, "Got image stats request.");
+        req.respond(&image_stats.stats(req.value.image.as_deref()))
+            .await?;
+    }
+
+    Err(anyhow!("image_stats_sub.next() returned None."))
+}
+
+/// Track the DNS records published in each cluster, so that hostnames in use
+/// are not handed out again.
+async fn dns_record_loop(nats: &TypedNats, hostnames: &HostnameTracker) -> NeverResult {
+    let mut dns_record_sub = nats.subscribe(SetDnsRecord::subscribe_subject()).await?;
+    tracing::info!("Subscribed to DNS record messages.");
+
+    while let Some(dns_record) = dns_record_sub.next().await {
+        hostnames.record_dns(&dns_record.value, Utc::now());
+    }
+
+    Err(anyhow!("dns_record_sub.next() returned None."))
+}
+
+/// Persist the cluster configs applied through `plane-cli apply`, once the
+/// request to apply each is authenticated.
+async fn apply_cluster_config_loop(
+    nats: &TypedNats,
+    auth: &dyn AuthProvider,
+    leadership: &Leadership,
+) -> NeverResult {
+    let mut apply_sub = nats
+        .subscribe(ApplyClusterConfig::subscribe_subject())
+        .await?;
+    tracing::info!("Subscribed to cluster config requests.");
+
+    while let Some(req) = apply_sub.next().await {
+        // Only the leader responds, so that each config is published once.
+        if !leadership.is_leader() {
+            continue;
+        }
+
+        let result = match auth.authenticate(&Credentials::from_message(&req)).await {
+            Err(reason) => {
+                tracing::warn!(%reason, "Rejected unauthenticated cluster config.");
+                Err(PlaneError::Unauthenticated { reason })
+            }
+            Ok(principal) => match nats.publish_jetstream(&req.value.config).await {
+                Ok(()) => {
+                    tracing::info!(%principal, config=?req.value.config, "Persisted cluster config.");
+                    Ok(())
+                }
+                Err(error) => {
+                    tracing::error!(?error, "Error publishing cluster config.");
+                    Err(PlaneError::Internal {
+                        reason: "Could not persist the cluster config.".into(),
+                    })
+                }
+            },
+        };
+
+        req.respond(&result).await?;
+    }
+
+    Err(anyhow!("apply_sub.next() returned None."))
+}
+
+/// Respond to requests for the clusters this controller has observed.
+async fn cluster_list_loop(
+    nats: &TypedNats,
+    auth: &dyn AuthProvider,
+    scheduler: &Scheduler,
+    hostnames: &HostnameTracker,
+) -> NeverResult {
+    let mut cluster_list_sub = nats
+        .subscribe(ClusterListRequest::subscribe_subject())
+        .await?;
+    tracing::info!("Subscribed to cluster list requests.");
+
+    while let Some(req) = cluster_list_sub.next().await {
+        let principal = match auth.authenticate(&Credentials::from_message(&req)).await {
+            Ok(principal) => principal,
+            Err(reason) => {
+                tracing::warn!(%reason, "Ignored unauthenticated cluster list request.");
+                continue;
+            }
+        };
+        tracing::debug!(%principal, "Got cluster list request.");
+
+        let now = Utc::now();
+        let mut clusters = scheduler.clusters();
+        for cluster in hostnames.clusters() {
+            if !clusters.contains(&cluster) {
+                clusters.push(cluster);
+            }
+        }
+        clusters.sort_by_key(|cluster| cluster.to_string());
+
+        let summaries: Vec<ClusterSummary> = clusters
+            .into_iter()
+            .map(|cluster| {
+                let (ready_drones, running_backends) = scheduler.cluster_load(&cluster, now);
+                ClusterSummary {
+                    last_dns_record: hostnames.last_dns_record(&cluster),
+                    cluster,
+                    ready_drones,
+                    running_backends,
+                }
+            })
+            .collect();
+
+        req.respond(&summaries).await?;
+    }
+
+    Err(anyhow!("cluster_list_sub.next() returned None."))
+}
+
+/// Find where a backend runs from its latest state in JetStream.
+async fn lookup_backend(
+    nats: &TypedNats,
+    scheduler: &Scheduler,
+    cluster_configs: &ClusterConfigTracker,
+    clusters: &HashMap<ClusterName, ClusterPlan>,
+    backend: &BackendId,
+) -> anyhow::Result<Option<BackendLocation>> {
+    let state = nats
+        .get_all(
+            &BackendStateMessage::subscribe_subject(backend),
+            DeliverPolicy::Last,
+        )
+        .await?
+        .pop();
+    let state = match state {
+        Some(state) => state,
+        None => return Ok(None),
+    };
+
+    let cluster = state
+        .drone
+        .as_ref()
+        .and_then(|drone| scheduler.drone_cluster(drone));
+    let url = cluster.as_ref().map(|cluster| {
+        cluster_configs
+            .plan(clusters, cluster)
+            .backend_url
+            .backend_url(backend, cluster)
+    });
+
+    Ok(Some(BackendLocation {
+        backend: backend.clone(),
+        cluster,
+        drone: state.drone,
+        state: state.state,
+        time: state.time,
+        url,
+    }))
+}
+
+/// Respond to requests for where a backend runs, given its ID.
+async fn backend_lookup_loop(
+    nats: &TypedNats,
+    auth: &dyn AuthProvider,
+    scheduler: &Scheduler,
+    cluster_configs: &ClusterConfigTracker,
+    clusters: &HashMap<ClusterName, ClusterPlan>,
+) -> NeverResult {
+    let mut lookup_sub = nats
+        .subscribe(BackendLookupRequest::subscribe_subject())
+        .await?;
+    tracing::info!("Subscribed to backend lookup requests.");
+
+    while let Some(req) = lookup_sub.next().await {
+        let principal = match auth.authenticate(&Credentials::from_message(&req)).await {
+            Ok(principal) => principal,
+            Err(reason) => {
+                tracing::warn!(%reason, "Ignored unauthenticated backend lookup request.");
+                continue;
+            }
+        };
+        tracing::debug!(%principal, backend=%req.value.backend, "Got backend lookup request.");
+
+        match lookup_backend(
+            nats,
+            scheduler,
+            cluster_configs,
+            clusters,
+            &req.value.backend,
+        )
+        .await
+        {
+            Ok(location) => req.respond(&location).await?,
+            Err(error) => tracing::warn!(?error, "Error looking up backend."),
+        }
+    }
+
+    Err(anyhow!("lookup_sub.next() returned None."))
+}
+
+/// Terminate a backend on whichever drone runs it, failing if it does not
+/// exist or has already terminated.
+async fn terminate_backend(
+    nats: &TypedNats,
+    scheduler: &Scheduler,
+    cluster_configs: &ClusterConfigTracker,
+    clusters: &HashMap<ClusterName, ClusterPlan>,
+    request: &TerminateBackendRequest,
+) -> Result<(), PlaneError> {
+    let location = lookup_backend(nats, scheduler, cluster_configs, clusters, &request.backend)
+        .await
+        .map_err(|error| PlaneError::from_anyhow(&error))?
+        .ok_or_else(|| PlaneError::NotFound {
+            reason: format!("Backend {} does not exist.", request.backend),
+        })?;
+
+    if location.state.terminal() {
+        return Err(PlaneError::NotFound {
+            reason: format!(
+                "Backend {} has already terminated ({:?}).",
+                request.backend, location.state
+            ),
+        });
+    }
+
+    let cluster = location.cluster.ok_or_else(|| PlaneError::Internal {
+        reason: format!(
+            "The drone running backend {} has not reported its cluster.",
+            request.backend
+        ),
+    })?;
+
+    match nats.request(&request.termination_request(cluster)).await {
+        Ok(result) => result,
+        Err(error) => Err(match location.drone {
+            Some(drone) if error.is::<RequestTimeout>() => PlaneError::DroneTimeout { drone },
+            _ => PlaneError::Internal {
+                reason: format!(
+                    "Error forwarding termination of backend {}: {}",
+                    request.backend, error
+                ),
+            },
+        }),
+    }
+}
+
+/// Forward requests to terminate a backend, given its ID, to the drone
+/// running it. Requests are forwarded concurrently, so that a drone which is
+/// slow to respond does not hold up requests for backends on other drones.
+async fn terminate_backend_loop(
+    nats: &TypedNats,
+    auth: &dyn AuthProvider,
+    scheduler: &Scheduler,
+    cluster_configs: &ClusterConfigTracker,
+    clusters: &HashMap<ClusterName, ClusterPlan>,
+    leadership: &Leadership,
+) -> NeverResult {
+    let mut terminate_sub = nats
+        .subscribe(TerminateBackendRequest::subscribe_subject())
+        .await?;
+    tracing::info!("Subscribed to backend termination requests.");
+
+    let mut pending = FuturesUnordered::new();
+    loop {
+        select! {
+            req = terminate_sub.next() => {
+                let req = match req {
+                    Some(req) => req,
+                    None => return Err(anyhow!("terminate_sub.next() returned None.")),
+                };
+
+                // Only the leader responds, so that each request is forwarded once.
+                if !leadership.is_leader() {
+                    continue;
+                }
+
+                let principal = match auth.authenticate(&Credentials::from_message(&req)).await {
+                    Ok(principal) => principal,
+                    Err(reason) => {
+                        tracing::warn!(%reason, "Ignored unauthenticated backend termination request.");
+                        continue;
+                    }
+                };
+                tracing::info!(
+                    %principal,
+                    backend=%req.value.backend,
+                    "Got backend termination request."
+                );
+
+                pending.push(async move {
+                    let result =
+                        terminate_backend(nats, scheduler, cluster_configs, clusters, &req.value)
+                            .await;
+                    if let Err(error) = &result {
+                        tracing::warn!(%error, backend=%req.value.backend, "Could not terminate backend.");
+                    }
+                    req.respond(&result).await
+                });
+            },
+
+            Some(responded) = pending.next(), if !pending.is_empty() => responded?,
+        }
+    }
+}
+
+/// Record that a backend was placed in an affinity group, and persist it to
+/// JetStream so that a controller started later places the group's other
+/// backends with it.
+async fn record_affinity_member(
+    nats: &TypedNats,
+    scheduler: &Scheduler,
+    cluster: &ClusterName,
+    group: &AffinityGroup,
+    backend: &BackendId,
+    drone: &DroneId,
+) {
+    let member = AffinityMember {
+        cluster: cluster.clone(),
+        group: group.name.clone(),
+        backend: backend.clone(),
+        drone: drone.clone(),
+        time: Utc::now(),
+    };
+    nats.publish_jetstream(&member)
+        .await
+        .log_error("Error publishing affinity group member.");
+    scheduler.record_affinity_member(member, Utc::now());
+}
+
+/// Track the state of backends scheduled by this controller, publishing an
+/// aggregated status for a replica group whenever one of its members changes
+/// state, and logging each change with the backend's current metadata.
+async fn backend_state_loop(
+    nats: &TypedNats,
+    scheduler: &Scheduler,
+    groups: &GroupTracker,
+    image_stats: &ImageStatsTracker,
+    metadata: &MetadataRegistry,
+) -> NeverResult {
+    let mut backend_state_sub = nats
+        .subscribe(BackendStateMessage::wildcard_subject())
+        .await?;
+    tracing::info!("Subscribed to backend state messages.");
+
+    // Affinity group members are replayed before backend states, so that
+    // members which have since terminated are forgotten again.
+    match nats
+        .get_all(
+            &AffinityMember::subscribe_subject(),
+            DeliverPolicy::LastPerSubject,
+        )
+        .await
+    {
+        Ok(members) => {
+            let num_members = members.len();
+            for member in members {
+                scheduler.record_affinity_member(member, Utc::now());
+            }
+            tracing::info!(num_members, "Reconciled affinity groups from JetStream.");
+        }
+        Err(error) => tracing::warn!(?error, "Could not reconcile affinity groups."),
+    }
+
+    // Backends which started before this controller did only count towards
+    // their drone's load once they change state again, so seed the scheduler
+    // with each backend's latest state. Messages which arrive in the meantime
+    // are buffered by the subscription and applied afterwards.
+    match nats
+        .get_all(
+            &BackendStateMessage::wildcard_subject(),
+            DeliverPolicy::LastPerSubject,
+        )
+        .await
+    {
+        Ok(states) => {
+            let now = Utc::now();
+            for state in &states {
+                scheduler.update_backend_state(state, now);
+            }
+            tracing::info!(
+                num_states = states.len(),
+                "Reconciled backend states from JetStream."
+            );
+        }
+        Err(error) => tracing::warn!(?error, "Could not reconcile backend states."),
+    }
+
+    let mut metadata_sub = nats
+        .subscribe(BackendMetadataMessage::wildcard_subject())
+        .await?;
+    tracing::info!("Subscribed to backend metadata messages.");
+
+    loop {
+        select! {
+            state_msg = backend_state_sub.next() => {
+                let state_msg = match state_msg {
+                    Some(state_msg) => state_msg,
+                    None => return Err(anyhow!("backend_state_sub.next() returned None.")),
+                };
+
+                if let Some(backend_metadata) =
+                    metadata.update_state(&state_msg.value.backend, state_msg.value.state)
+                {
+                    tracing::info!(
+                        backend=%state_msg.value.backend,
+                        state=?state_msg.value.state,
+                        metadata=?backend_metadata,
+                        "Backend changed state."
+                    );
+                }
+
+                scheduler.update_backend_state(&state_msg.value, Utc::now());
+
+                image_stats.update_state(
+                    &state_msg.value.backend,
+                    state_msg.value.state,
+                    state_msg.value.time,
+                );
+
+                if let Some(status) =
+                    groups.update_state(&state_msg.value.backend, state_msg.value.state, Utc::now())
+                {
+                    tracing::debug!(?status, "Publishing group status.");
+                    nats.publish(&status)
+                        .await
+                        .log_error("Error publishing group status.");
+                }
+            },
+
+            metadata_msg = metadata_sub.next() => {
+                match metadata_msg {
+                    Some(metadata_msg) if !metadata_msg.subject_matches() => {
+                        tracing::warn!(
+                            subject=%metadata_msg.message().subject,
+                            backend=%metadata_msg.value.backend,
+                            "Ignored metadata message for a backend other than its subject's."
+                        );
+                    }
+                    Some(metadata_msg) => {
+                        tracing::info!(value=?metadata_msg.value, "Backend metadata changed.");
+                        metadata.update_metadata(&metadata_msg.value.backend, &metadata_msg.value.metadata);
+                    }
+                    None => return Err(anyhow!("metadata_sub.next() returned None.")),
+                }
+            },
+        }
+    }
+}
+
+/// Periodically publish a capacity report for each known cluster, and record
+/// desired drone counts sent by operators or external autoscalers.
+async fn capacity_report_loop(
+    nats: &TypedNats,
+    auth: &dyn AuthProvider,
+    scheduler: &Scheduler,
+    leadership: &Leadership,
+    plan: AutoscalerPlan,
+) -> NeverResult {
+    let mut desired_drones_sub = nats
+        .subscribe(DesiredDroneCount::subscribe_subject())
+        .await?;
+    tracing::info!("Subscribed to desired drone count messages.");
+
+    let mut interval = tokio::time::interval(plan.report_interval);
+
+    loop {
+        select! {
+            // Standbys do not see failed schedules, so only the leader reports.
+            _ = interval.tick() => {
+                let clusters = if leadership.is_leader() {
+                    scheduler.clusters()
+                } else {
+                    Vec::new()
+                };
+                for cluster in clusters {
+                    let report = scheduler.capacity_report(&cluster, Utc::now());
+                    nats.publish(&report)
+                        .await
+                        .log_error("Error publishing capacity report.");
+                }
+            },
+
+            desired_drones = desired_drones_sub.next() => {
+                match desired_drones {
+                    Some(desired_drones) => {
+                        let principal = match auth.authenticate(&Credentials::from_message(&desired_drones)).await {
+                            Ok(principal) => principal,
+                            Err(reason) => {
+                                tracing::warn!(%reason, "Ignored unauthenticated desired drone count.");
+                                continue;
+                            }
+                        };
+                        tracing::info!(%principal, value=?desired_drones.value, "Got desired drone count.");
+                        scheduler.set_desired_drones(
+                            &desired_drones.value.cluster,
+                            desired_drones.value.count,
+                        );
+                        // Every controller records the count, but only the leader responds.
+                        if leadership.is_leader() {
+                            desired_drones.respond(&()).await?;
+                        }
+                    }
+                    None => return Err(anyhow!("desired_drones_sub.next() returned None.")),
+                }
+            }
+        }
+    }
+}
+
+/// If the cluster requires signed schedule requests, check that the request
+/// was signed with the cluster's key, and has not been accepted before.
+fn check_signature<T: TypedMessage>(
+    cluster_plan: &ClusterPlan,
+    request: &MessageWithResponseHandle<T>,
+    seen_nonces: &SeenNonces,
+) -> Result<(), PlaneError> {
+    match &cluster_plan.signing_key {
+        Some(key) => request.verify_signature(key, seen_nonces).map_err(|error| {
+            PlaneError::Unauthenticated {
+                reason: format!("Request signature rejected: {}", error),
+            }
+        }),
+        None => Ok(()),
+    }
+}
+
+/// If the request names its backend, check that the name can be used as a
+/// hostname label and is not already in use in the cluster.
+fn check_hostname(
+    hostnames: &HostnameTracker,
+    scheduler: &Scheduler,
+    request: &ScheduleRequest,
+    now: DateTime<Utc>,
+) -> Result<(), PlaneError> {
+    match &request.backend_id {
+        Some(backend_id) if !backend_id.is_valid_hostname_label() => Err(PlaneError::InvalidRequest {
+            reason: format!(
+                "Backend ID {} is not a valid hostname label. It must be 1 to 63 lowercase letters, digits, and hyphens, and may not start or end with a hyphen.",
+                backend_id
+            ),
+        }),
+        Some(backend_id) if hostnames.in_use(scheduler, &request.cluster, backend_id, now) => {
+            Err(PlaneError::InvalidRequest {
+                reason: format!(
+                    "Hostname {} is already in use in cluster {}.",
+                    backend_id, request.cluster
+                ),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check the request's observability options, which the drone would
+/// otherwise refuse the backend for.
+fn check_observability(request: &ScheduleRequest) -> Result<(), PlaneError> {
+    request
+        .observability
+        .validate()
+        .map_err(|error| PlaneError::InvalidRequest {
+            reason: error.to_string(),
+        })
+}
+
+/// Check a request against the images the cluster allows, and that
+/// `count` more backends fit within its backend quota.
+fn check_cluster_policy(
+    scheduler: &Scheduler,
+    cluster_plan: &ClusterPlan,
+    request: &ScheduleRequest,
+    count: u32,
+    now: DateTime<Utc>,
+) -> Result<(), PlaneError> {
+    cluster_plan.check_image(&request.executable.image)?;
+    let (_, running_backends) = scheduler.cluster_load(&request.cluster, now);
+    cluster_plan.check_backend_quota(running_backends, count)
+}
+
+/// A schedule request on its way through admission, along with what is
+/// needed to schedule it afterwards.
+struct Reviewed<T: TypedMessage> {
+    message: MessageWithResponseHandle<T>,
+    principal: Principal,
+    cluster_plan: ClusterPlan,
+    deadline: Option<Instant>,
+
+    /// The request to schedule, or the reason it is rejected.
+    admitted: Result<ScheduleRequest, PlaneError>,
+}
+
+/// If an admission webhook is configured, ask it whether to admit a request
+/// which has not already been rejected, then send the request on to be
+/// scheduled. The webhook is asked in a task of its own, so that a slow
+/// webhook does not hold up other requests.
+fn review<T: TypedMessage + Send + 'static>(
+    admission: Option<&Arc<AdmissionWebhook>>,
+    mut reviewed: Reviewed<T>,
+    send: &UnboundedSender<Reviewed<T>>,
+) {
+    match (admission, &reviewed.admitted) {
+        (Some(admission), Ok(request)) => {
+            let admission = admission.clone();
+            let request = request.clone();
+            let send = send.clone();
+            tokio::spawn(async move {
+                reviewed.admitted = admission.review(&reviewed.principal, &request).await;
+                // Only fails if the scheduler loop has stopped.
+                let _ = send.send(reviewed);
+            });
+        }
+        _ => {
+            let _ = send.send(reviewed);
+        }
+    }
+}
+
+/// When to stop waiting for a drone to accept a request received now, if the
+/// request has a deadline.
+fn schedule_deadline(request: &ScheduleRequest) -> Option<Instant> {
+    request
+        .schedule_deadline_ms
+        .map(|deadline| Instant::now() + deadline)
+}
+
+/// Placeholder for secrets left out of dry run responses.
+const REDACTED: &str = "<redacted>";
+
+/// Remove secrets from the spawn request returned by a dry run. Registry
+/// passwords are always removed, and so are environment variables which the
+/// admission webhook added or changed, since the client did not send them.
+fn redact_dry_run(spawn_request: &mut SpawnRequest, sent: &ScheduleRequest) {
+    if let Some(DockerCredentials::UsernamePassword { password, .. }) =
+        &mut spawn_request.executable.credentials
+    {
+        *password = REDACTED.to_string();
+    }
+    for (key, value) in spawn_request.executable.env.iter_mut() {
+        if sent.executable.env.get(key) != Some(value) {
+            *value = REDACTED.to_string();
+        }
+    }
+}
+
+/// Send a scheduled backend to the drone chosen for it, and record it if the
+/// drone accepts. `sent` is the request as the client sent it, before
+/// admission; whether it is a dry run is taken from it, so that the webhook
+/// can not turn a dry run into a spawn. Dry runs return the spawn request,
+/// with secrets redacted, without sending it.
+#[allow(clippy::too_many_arguments)]
+async fn spawn_on_drone(
+    nats: &TypedNats,
+    principal: &Principal,
+    groups: &GroupTracker,
+    image_stats: &ImageStatsTracker,
+    metadata: &MetadataRegistry,
+    cluster_plan: &ClusterPlan,
+    sent: &ScheduleRequest,
+    schedule_request: &ScheduleRequest,
+    resource_limits: ResourceLimits,
+    drone_id: DroneId,
+    deadline: Option<Instant>,
+) -> ScheduleResponse {
+    let timer = Timer::new();
+    let idle_timeout = cluster_plan.idle_timeout(schedule_request.max_idle_secs);
+    let mut spawn_request = schedule_request.schedule(&drone_id, idle_timeout);
+    spawn_request.executable.resource_limits = resource_limits;
+    spawn_request.path_routing = cluster_plan.backend_url.path_routing;
+    let url = cluster_plan
+        .backend_url
+        .backend_url(&spawn_request.backend_id, &schedule_request.cluster);
+    spawn_request.backend_url = Some(url.clone());
+    if sent.dry_run {
+        redact_dry_run(&mut spawn_request, sent);
+        tracing::info!(%drone_id, %principal, "Dry run passed; not spawning backend.");
+        return ScheduleResponse::DryRun {
+            drone: drone_id,
+            spawn_request: Box::new(spawn_request),
+        };
+    }
+
+    let response = match deadline {
+        Some(deadline) => {
+            let mut request = {
+                let nats = nats.clone();
+                let spawn_request = spawn_request.clone();
+                Box::pin(async move { nats.request(&spawn_request).await })
+            };
+            match timeout_at(deadline, &mut request).await {
+                Ok(response) => response,
+                Err(_) => {
+                    tracing::warn!(%drone_id, "Drone did not accept backend before the deadline.");
+                    tokio::spawn(terminate_after_deadline(
+                        nats.clone(),
+                        schedule_request.cluster.clone(),
+                        spawn_request.backend_id.clone(),
+                        request,
+                    ));
+                    let deadline_ms = schedule_request
+                        .schedule_deadline_ms
+                        .unwrap_or_default()
+                        .as_millis() as u64;
+                    return ScheduleResponse::Error(PlaneError::ScheduleDeadlineExceeded {
+                        deadline_ms,
+                    });
+                }
+            }
+        }
+        None => nats.request(&spawn_request).await,
+    };
+
+    match response {
+        Ok(Ok(())) => {
+            tracing::info!(
+                duration=?timer.duration(),
+                backend_id=%spawn_request.backend_id,
+                %drone_id,
+                %principal,
+                "Drone accepted backend."
+            );
+            image_stats.record_spawn(
+                &spawn_request.backend_id,
+                &spawn_request.executable.image,
+                Utc::now(),
+            );
+            metadata.record_spawn(&spawn_request.backend_id, &spawn_request.metadata);
+            if let Some(group) = &schedule_request.group {
+                groups.add_member(
+                    &schedule_request.cluster,
+                    group,
+                    &spawn_request.backend_id,
+                    &url,
+                );
+            }
+            ScheduleResponse::Scheduled {
+                drone: drone_id,
+                backend_id: spawn_request.backend_id,
+                bearer_token: spawn_request.bearer_token,
+                url: Some(url),
+            }
+        }
+        Ok(Err(error)) => {
+            tracing::warn!(%error, %drone_id, "Drone refused backend.");
+            ScheduleResponse::Error(error)
+        }
+        Err(error) if error.is::<RequestTimeout>() => {
+            tracing::warn!(%drone_id, "Drone did not respond to spawn request.");
+            ScheduleResponse::Error(PlaneError::DroneTimeout { drone: drone_id })
+        }
+        Err(error) => {
+            tracing::warn!(?error, %drone_id, "Error sending spawn request to drone.");
+            ScheduleResponse::Error(PlaneError::Internal {
+                reason: format!("Error sending backend to drone {}: {}", drone_id, error),
+            })
+        }
+    }
+}
+
+/// Wait for the drone's response to a spawn request which missed its
+/// schedule deadline, and terminate the backend unless the drone refused it,
+/// since the client was told it was not scheduled.
+async fn terminate_after_deadline(
+    nats: TypedNats,
+    cluster: ClusterName,
+    backend: BackendId,
+    response: impl Future<Output = anyhow::Result<Result<(), PlaneError>>>,
+) {
+    if let Ok(Err(_)) = response.await {
+        return;
+    }
+
+    let request = TerminationRequest {
+        cluster_id: cluster,
+        backend_id: backend.clone(),
+        drain: false,
+        grace_period_secs: Duration::ZERO,
+    };
+    match nats.request(&request).await {
+        Ok(Ok(())) => tracing::info!(%backend, "Terminated backend which missed its deadline."),
+        Ok(Err(error)) => {
+            tracing::warn!(%error, %backend, "Could not terminate backend which missed its deadline.")
+        }
+        Err(error) => {
+            tracing::warn!(?error, %backend, "Could not terminate backend which missed its deadline.")
+        }
+    }
+}
+
+/// Response to a schedule request refused by the rate limiter.
+fn throttled(throttle: Throttle) -> ScheduleResponse {
+    match throttle {
+        Throttle::RetryAfter(retry_after) => ScheduleResponse::Throttled {
+            retry_after_ms: retry_after.as_millis().try_into().unwrap_or(u64::MAX),
+        },
+        Throttle::ExceedsBurst { burst } => ScheduleResponse::Error(PlaneError::InvalidRequest {
+            reason: format!(
+                "Request is larger than the rate limit's burst of {} backends.",
+                burst
+            ),
+        }),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn scheduler_loop(
+    nats: &TypedNats,
+    auth: &dyn AuthProvider,
+    admission: Option<&Arc<AdmissionWebhook>>,
+    scheduler: &Scheduler,
+    groups: &GroupTracker,
+    image_stats: &ImageStatsTracker,
+    metadata: &MetadataRegistry,
+    hostnames: &HostnameTracker,
+    cluster_configs: &ClusterConfigTracker,
+    registry: &DroneRegistry,
+    rate_limiter: &RateLimiter,
+    leadership: &Leadership,
+    clusters: &HashMap<ClusterName, ClusterPlan>,
+    max_batch_size: u32,
+) -> NeverResult {
     let mut spawn_request_sub = nats.subscribe(ScheduleRequest::subscribe_subject()).await?;
     tracing::info!("Subscribed to spawn requests.");
 
+    // Signed requests are only accepted once.
+    let seen_nonces = SeenNonces::default();
+
+    let mut batch_request_sub = nats
+        .subscribe(BatchScheduleRequest::subscribe_subject())
+        .await?;
+    tracing::info!("Subscribed to batch spawn requests.");
+
     let mut status_sub = nats
         .subscribe(DroneStatusMessage::subscribe_subject())
         .await?;
     tracing::info!("Subscribed to drone status messages.");
 
+    let mut drain_sub = nats.subscribe(DrainDrone::wildcard_subject()).await?;
+    tracing::info!("Subscribed to drain requests.");
+
+    // Requests come back from admission here, in the order it finishes.
+    let (reviewed_send, mut reviewed_recv) = mpsc::unbounded_channel();
+    let (reviewed_batch_send, mut reviewed_batch_recv) = mpsc::unbounded_channel();
+
+    // Drones only publish their status every few seconds, so seed the
+    // scheduler with the latest status of each drone rather than refusing
+    // to schedule until they do. Each is stamped with when it was stored,
+    // so that drones which went away before the controller started are not
+    // taken to be alive.
+    match nats
+        .get_all_with_info(
+            &DroneStatusMessage::subscribe_subject(),
+            DeliverPolicy::LastPerSubject,
+        )
+        .await
+    {
+        Ok(statuses) => {
+            for (status, info) in &statuses {
+                let cluster_plan = cluster_configs.plan(clusters, &status.cluster);
+                // Statuses read back from JetStream carry no signature to
+                // verify, so drones in clusters which require approval are
+                // only scheduled to once they publish a fresh status.
+                if !cluster_plan.require_drone_approval {
+                    scheduler.update_status(info.published, status);
+                }
+            }
+            tracing::info!(
+                num_drones = statuses.len(),
+                "Reconciled drone statuses from JetStream."
+            );
+        }
+        Err(error) => tracing::warn!(?error, "Could not reconcile drone statuses."),
+    }
+
+    // Schedule requests are buffered by their subscriptions until the
+    // clusters' applied configs are loaded, so that none is admitted without
+    // them.
+    cluster_configs.wait_loaded().await;
+
+    let mut prune_interval = tokio::time::interval(Duration::from_secs(1));
     loop {
         select! {
+            _ = prune_interval.tick() => scheduler.prune_lost_drones(Utc::now()),
+
             status_msg = status_sub.next() => {
                 tracing::debug!(?status_msg, "Got drone status");
                 if let Some(status_msg) = status_msg {
-                    scheduler.update_status(Utc::now(), &status_msg.value);
+                    let cluster_plan = cluster_configs.plan(clusters, &status_msg.value.cluster);
+                    let admitted = registry.admits(&cluster_plan, &status_msg.value, |key| {
+                        status_msg.verify_signature(key, &seen_nonces)
+                    });
+                    if admitted {
+                        scheduler.update_status(Utc::now(), &status_msg.value);
+                    } else {
+                        tracing::debug!(
+                            drone_id=%status_msg.value.drone_id,
+                            "Ignored status of unapproved drone."
+                        );
+                    }
                 } else {
                     return Err(anyhow!("status_sub.next() returned None."));
                 }
             },
 
+            drain_msg = drain_sub.next() => {
+                match drain_msg {
+                    // The drone itself responds to the drain request.
+                    Some(drain_msg) => scheduler.update_drain(Utc::now(), &drain_msg.value),
+                    None => return Err(anyhow!("drain_sub.next() returned None.")),
+                }
+            },
+
             spawn_request = spawn_request_sub.next() => {
                 match spawn_request {
+                    // Only the leader responds, so that no request is scheduled twice.
+                    Some(_) if !leadership.is_leader() => continue,
                     Some(schedule_request) => {
-                        tracing::info!(spawn_request=?schedule_request.value, "Got spawn request");
-                        let result = match scheduler.schedule(&schedule_request.value.cluster, Utc::now()) {
-                            Ok(drone_id) => {
-                                let timer = Timer::new();
-                                let spawn_request = schedule_request.value.schedule(&drone_id);
-                                match nats.request(&spawn_request).await {
-                                    Ok(true) => {
-                                        tracing::info!(
-                                            duration=?timer.duration(),
-                                            backend_id=%spawn_request.backend_id,
-                                            %drone_id,
-                                            "Drone accepted backend."
-                                        );
-                                        ScheduleResponse::Scheduled {
-                                            drone: drone_id,
-                                            backend_id: spawn_request.backend_id,
-                                            bearer_token: None,
-                                        }
-                                    }
-                                    Ok(false) => {
-                                        tracing::warn!("No drone available.");
-                                        ScheduleResponse::NoDroneAvailable
-                                    },
-                                    Err(error) => {
-                                        tracing::warn!(?error, "Scheduler returned error.");
-                                        ScheduleResponse::NoDroneAvailable
-                                    },
-                                }
-                            },
+                        let principal = match auth.authenticate(&Credentials::from_message(&schedule_request)).await {
+                            Ok(principal) => principal,
+                            Err(reason) => {
+                                tracing::warn!(%reason, "Rejected unauthenticated spawn request.");
+                                let error = PlaneError::Unauthenticated { reason };
+                                schedule_request.respond(&ScheduleResponse::Error(error)).await?;
+                                continue;
+                            }
+                        };
+                        tracing::info!(%principal, spawn_request=?schedule_request.value, "Got spawn request");
+                        let deadline = schedule_deadline(&schedule_request.value);
+                        let cluster_plan =
+                            cluster_configs.plan(clusters, &schedule_request.value.cluster);
+                        // Checked before the admission webhook, so that a flood
+                        // of requests does not reach it either.
+                        if let Err(throttle) = rate_limiter.check(
+                            &schedule_request.value.cluster,
+                            cluster_plan.rate_limit,
+                            1,
+                            Instant::now(),
+                        ) {
+                            tracing::warn!(%principal, ?throttle, "Throttled spawn request.");
+                            schedule_request.respond(&throttled(throttle)).await?;
+                            continue;
+                        }
+
+                        // The signature covers the request as sent, so it is
+                        // checked before the admission webhook can mutate it.
+                        let admitted = check_signature(&cluster_plan, &schedule_request, &seen_nonces)
+                            .map(|()| schedule_request.value.clone());
+                        review(admission, Reviewed {
+                            message: schedule_request,
+                            principal,
+                            cluster_plan,
+                            deadline,
+                            admitted,
+                        }, &reviewed_send);
+                    },
+                    None => return Err(anyhow!("spawn_request_sub.next() returned None.")),
+                }
+            }
+
+            reviewed = reviewed_recv.recv() => {
+                match reviewed {
+                    Some(Reviewed { message: schedule_request, principal, cluster_plan, deadline, admitted }) => {
+                        let admitted = admitted.and_then(|request| {
+                            check_hostname(hostnames, scheduler, &request, Utc::now())?;
+                            check_observability(&request)?;
+                            check_cluster_policy(
+                                scheduler,
+                                &cluster_plan,
+                                &request,
+                                1,
+                                Utc::now(),
+                            )?;
+                            let limits = cluster_plan
+                                .resource_limits(&request.executable.resource_limits)?;
+                            Ok((request, limits))
+                        });
+                        let dry_run = schedule_request.value.dry_run;
+                        let named_backend = matches!(
+                            &admitted,
+                            Ok((request, _)) if request.backend_id.is_some()
+                        );
+                        let result = match admitted {
                             Err(error) => {
-                                tracing::warn!(?error, "Communication error during scheduling.");
-                                ScheduleResponse::NoDroneAvailable
+                                tracing::warn!(%error, %principal, "Rejected spawn request.");
+                                ScheduleResponse::Error(error)
+                            },
+                            Ok((request, resource_limits)) => match scheduler.schedule(
+                                &request.cluster,
+                                Requirements::of(&request),
+                                Utc::now(),
+                            ) {
+                                Ok(drone_id) => {
+                                    spawn_on_drone(
+                                        nats,
+                                        &principal,
+                                        groups,
+                                        image_stats,
+                                        metadata,
+                                        &cluster_plan,
+                                        &schedule_request.value,
+                                        &request,
+                                        resource_limits,
+                                        drone_id,
+                                        deadline,
+                                    ).await
+                                },
+                                Err(error) => {
+                                    tracing::warn!(?error, "Communication error during scheduling.");
+                                    ScheduleResponse::Error(PlaneError::NoDroneAvailable)
+                                },
                             },
                         };
 
+                        match &result {
+                            // Dry runs are not demand, so do not count towards autoscaling.
+                            ScheduleResponse::Error(PlaneError::NoDroneAvailable) if !dry_run => {
+                                scheduler.record_failed_schedule(&schedule_request.value.cluster, Utc::now());
+                            }
+                            ScheduleResponse::Scheduled { backend_id, .. } if named_backend => {
+                                hostnames.reserve(&schedule_request.value.cluster, backend_id, Utc::now());
+                            }
+                            _ => (),
+                        }
+
+                        if let Some(group) = &schedule_request.value.affinity_group {
+                            if let ScheduleResponse::Scheduled { backend_id, drone, .. } = &result {
+                                record_affinity_member(
+                                    nats,
+                                    scheduler,
+                                    &schedule_request.value.cluster,
+                                    group,
+                                    backend_id,
+                                    drone,
+                                )
+                                .await;
+                            }
+                        }
+
                         schedule_request.respond(&result).await?;
                     },
-                    None => return Err(anyhow!("spawn_request_sub.next() returned None.")),
+                    None => return Err(anyhow!("reviewed_recv.recv() returned None.")),
+                }
+            }
+
+            batch_request = batch_request_sub.next() => {
+                match batch_request {
+                    Some(_) if !leadership.is_leader() => continue,
+                    // Responses are allocated for each backend in the batch,
+                    // so oversized batches get a single error response.
+                    Some(batch_request) if batch_request.value.count > max_batch_size => {
+                        let count = batch_request.value.count;
+                        tracing::warn!(%count, %max_batch_size, "Rejected oversized batch spawn request.");
+                        let error = PlaneError::InvalidRequest {
+                            reason: format!(
+                                "Batch of {} backends exceeds the maximum of {}.",
+                                count, max_batch_size
+                            ),
+                        };
+                        batch_request.respond(&vec![ScheduleResponse::Error(error)]).await?;
+                    }
+                    Some(batch_request) => {
+                        let principal = match auth.authenticate(&Credentials::from_message(&batch_request)).await {
+                            Ok(principal) => principal,
+                            Err(reason) => {
+                                tracing::warn!(%reason, "Rejected unauthenticated batch spawn request.");
+                                let count = batch_request.value.count as usize;
+                                let error = PlaneError::Unauthenticated { reason };
+                                let results = vec![ScheduleResponse::Error(error); count];
+                                batch_request.respond(&results).await?;
+                                continue;
+                            }
+                        };
+                        tracing::info!(%principal, batch_request=?batch_request.value, "Got batch spawn request");
+                        let deadline = schedule_deadline(&batch_request.value.request);
+                        let count = batch_request.value.count;
+                        let cluster = batch_request.value.request.cluster.clone();
+                        let cluster_plan = cluster_configs.plan(clusters, &cluster);
+                        if let Err(throttle) = rate_limiter.check(
+                            &cluster,
+                            cluster_plan.rate_limit,
+                            count,
+                            Instant::now(),
+                        ) {
+                            tracing::warn!(
+                                %principal,
+                                ?throttle,
+                                "Throttled batch spawn request."
+                            );
+                            let results = vec![throttled(throttle); count as usize];
+                            batch_request.respond(&results).await?;
+                            continue;
+                        }
+
+                        // The webhook reviews the request once for the whole batch.
+                        let admitted = check_signature(&cluster_plan, &batch_request, &seen_nonces)
+                            .map(|()| batch_request.value.request.clone());
+                        review(admission, Reviewed {
+                            message: batch_request,
+                            principal,
+                            cluster_plan,
+                            deadline,
+                            admitted,
+                        }, &reviewed_batch_send);
+                    },
+                    None => return Err(anyhow!("batch_request_sub.next() returned None.")),
+                }
+            }
+
+            reviewed = reviewed_batch_recv.recv() => {
+                match reviewed {
+                    Some(Reviewed { message: batch_request, principal, cluster_plan, deadline, admitted }) => {
+                        let count = batch_request.value.count;
+                        let cluster = batch_request.value.request.cluster.clone();
+                        let admitted = admitted.and_then(|request| {
+                            if request.backend_id.is_some() {
+                                let reason = "Batch spawn requests cannot specify a backend ID.";
+                                return Err(PlaneError::InvalidRequest {
+                                    reason: reason.into(),
+                                });
+                            }
+                            check_observability(&request)?;
+                            check_cluster_policy(
+                                scheduler,
+                                &cluster_plan,
+                                &request,
+                                count,
+                                Utc::now(),
+                            )?;
+                            let limits = cluster_plan
+                                .resource_limits(&request.executable.resource_limits)?;
+                            Ok((request, limits))
+                        });
+
+                        let results = match &admitted {
+                            Err(error) => {
+                                tracing::warn!(%error, %principal, "Rejected batch spawn request.");
+                                vec![ScheduleResponse::Error(error.clone()); count as usize]
+                            },
+                            Ok((request, resource_limits)) => {
+                                let placements = scheduler.schedule_batch(
+                                    &cluster,
+                                    Requirements::of(request),
+                                    Utc::now(),
+                                    count,
+                                );
+                                let original_request = &batch_request.value.request;
+                                join_all(placements.into_iter().map(|placement| {
+                                    let resource_limits = resource_limits.clone();
+                                    let cluster_plan = &cluster_plan;
+                                    let principal = &principal;
+                                    async move {
+                                        match placement {
+                                            Ok(drone_id) => spawn_on_drone(
+                                                nats,
+                                                principal,
+                                                groups,
+                                                image_stats,
+                                                metadata,
+                                                cluster_plan,
+                                                original_request,
+                                                request,
+                                                resource_limits,
+                                                drone_id,
+                                                deadline,
+                                            ).await,
+                                            Err(error) => {
+                                                tracing::warn!(?error, "Communication error during scheduling.");
+                                                ScheduleResponse::Error(PlaneError::NoDroneAvailable)
+                                            },
+                                        }
+                                    }
+                                })).await
+                            },
+                        };
+
+                        let dry_run = batch_request.value.request.dry_run;
+                        for result in &results {
+                            let failed = *result == ScheduleResponse::Error(PlaneError::NoDroneAvailable);
+                            if failed && !dry_run {
+                                scheduler.record_failed_schedule(&cluster, Utc::now());
+                            }
+                        }
+
+                        if let Some(group) = &batch_request.value.request.affinity_group {
+                            for result in &results {
+                                if let ScheduleResponse::Scheduled {
+                                    backend_id, drone, ..
+                                } = result
+                                {
+                                    record_affinity_member(
+                                        nats, scheduler, &cluster, group, backend_id, drone,
+                                    )
+                                    .await;
+                                }
+                            }
+                        }
+
+                        batch_request.respond(&results).await?;
+                    },
+                    None => return Err(anyhow!("reviewed_batch_recv.recv() returned None.")),
                 }
             }
         }
@@ -0,0 +1,211 @@
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use plane_core::{
+    messages::{
+        agent::DroneStatusMessage,
+        scheduler::{DrainDrone, DroneLifecycleEvent, DroneLifecycleMessage},
+    },
+    types::{ClusterName, DroneId},
+};
+
+/// Number of seconds after its last status message that a drone is reported
+/// as lost. This is longer than the scheduler's timeout so that a single late
+/// status message does not produce a lost/connected pair.
+pub(crate) const DRONE_LOST_TIMEOUT_SECONDS: i64 = 15;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Active,
+    Draining,
+    Drained,
+}
+
+struct DroneState {
+    last_seen: DateTime<Utc>,
+    phase: Phase,
+}
+
+/// Derives drone lifecycle events from drone status messages and drain requests.
+#[derive(Default)]
+pub struct DroneLifecycleTracker {
+    drones: DashMap<(ClusterName, DroneId), DroneState>,
+}
+
+fn event(
+    cluster: &ClusterName,
+    drone: &DroneId,
+    event: DroneLifecycleEvent,
+    time: DateTime<Utc>,
+) -> DroneLifecycleMessage {
+    DroneLifecycleMessage {
+        cluster: cluster.clone(),
+        drone: drone.clone(),
+        event,
+        time,
+    }
+}
+
+impl DroneLifecycleTracker {
+    /// Record a drone status message, returning the events it implies.
+    pub fn update_status(
+        &self,
+        status: &DroneStatusMessage,
+        time: DateTime<Utc>,
+    ) -> Vec<DroneLifecycleMessage> {
+        let cluster = &status.cluster;
+        let drone = &status.drone_id;
+        let mut events = Vec::new();
+
+        let mut state = self
+            .drones
+            .entry((cluster.clone(), drone.clone()))
+            .or_insert_with(|| {
+                events.push(event(
+                    cluster,
+                    drone,
+                    DroneLifecycleEvent::DroneConnected,
+                    time,
+                ));
+                DroneState {
+                    last_seen: time,
+                    phase: Phase::Active,
+                }
+            });
+        state.last_seen = time;
+
+        if status.ready {
+            // Draining was cancelled; the drone accepts backends again.
+            state.phase = Phase::Active;
+            return events;
+        }
+
+        if state.phase == Phase::Active {
+            state.phase = Phase::Draining;
+            events.push(event(
+                cluster,
+                drone,
+                DroneLifecycleEvent::DroneDraining,
+                time,
+            ));
+        }
+
+        if state.phase == Phase::Draining && status.running_backends == Some(0) {
+            state.phase = Phase::Drained;
+            events.push(event(
+                cluster,
+                drone,
+                DroneLifecycleEvent::DroneDrained,
+                time,
+            ));
+        }
+
+        events
+    }
+
+    /// Record a drain request, so that draining is reported without waiting
+    /// for the drone's next status message.
+    pub fn update_drain(
+        &self,
+        drain: &DrainDrone,
+        time: DateTime<Utc>,
+    ) -> Option<DroneLifecycleMessage> {
+        let mut state = self
+            .drones
+            .get_mut(&(drain.cluster.clone(), drain.drone.clone()))?;
+
+        if drain.drain && state.phase == Phase::Active {
+            state.phase = Phase::Draining;
+            Some(event(
+                &drain.cluster,
+                &drain.drone,
+                DroneLifecycleEvent::DroneDraining,
+                time,
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Forget drones which have not sent a status message recently, returning
+    /// an event for each.
+    pub fn sweep(&self, time: DateTime<Utc>) -> Vec<DroneLifecycleMessage> {
+        let threshold = time - Duration::seconds(DRONE_LOST_TIMEOUT_SECONDS);
+        let lost: Vec<(ClusterName, DroneId)> = self
+            .drones
+            .iter()
+            .filter(|entry| entry.value().last_seen < threshold)
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        lost.into_iter()
+            .filter_map(|key| self.drones.remove(&key))
+            .map(|((cluster, drone), _)| {
+                event(&cluster, &drone, DroneLifecycleEvent::DroneLost, time)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(date: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(date).unwrap().into()
+    }
+
+    fn status(ready: bool, running_backends: u32) -> DroneStatusMessage {
+        DroneStatusMessage {
+            drone_id: DroneId::new("drone1".into()),
+            cluster: ClusterName::new("mycluster.test"),
+            drone_version: "0.1.0".into(),
+            ready,
+            running_backends: Some(running_backends),
+            remaining_capacity: None,
+            host_metrics: None,
+            sealing_key: None,
+            arch: None,
+            proxy_metrics: None,
+            draining: false,
+        }
+    }
+
+    fn events(messages: Vec<DroneLifecycleMessage>) -> Vec<DroneLifecycleEvent> {
+        messages.into_iter().map(|m| m.event).collect()
+    }
+
+    #[test]
+    fn test_drone_lifecycle() {
+        let tracker = DroneLifecycleTracker::default();
+
+        assert_eq!(
+            vec![DroneLifecycleEvent::DroneConnected],
+            events(tracker.update_status(&status(true, 2), date("2020-01-01T05:00:00+00:00")))
+        );
+        assert!(tracker
+            .update_status(&status(true, 2), date("2020-01-01T05:00:04+00:00"))
+            .is_empty());
+
+        assert_eq!(
+            vec![DroneLifecycleEvent::DroneDraining],
+            events(tracker.update_status(&status(false, 1), date("2020-01-01T05:00:08+00:00")))
+        );
+        assert_eq!(
+            vec![DroneLifecycleEvent::DroneDrained],
+            events(tracker.update_status(&status(false, 0), date("2020-01-01T05:00:12+00:00")))
+        );
+        assert!(tracker
+            .update_status(&status(false, 0), date("2020-01-01T05:00:16+00:00"))
+            .is_empty());
+
+        assert!(tracker.sweep(date("2020-01-01T05:00:20+00:00")).is_empty());
+        assert_eq!(
+            vec![DroneLifecycleEvent::DroneLost],
+            events(tracker.sweep(date("2020-01-01T05:00:40+00:00")))
+        );
+        assert_eq!(
+            vec![DroneLifecycleEvent::DroneConnected],
+            events(tracker.update_status(&status(true, 0), date("2020-01-01T05:01:00+00:00")))
+        );
+    }
+}
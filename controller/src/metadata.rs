@@ -0,0 +1,67 @@
+use dashmap::DashMap;
+use plane_core::{messages::agent::BackendState, types::BackendId};
+use std::collections::HashMap;
+
+/// Current metadata of each live backend scheduled by this controller, kept
+/// up to date as operators change it after spawn.
+#[derive(Default)]
+pub struct MetadataRegistry {
+    backends: DashMap<BackendId, HashMap<String, String>>,
+}
+
+impl MetadataRegistry {
+    pub fn record_spawn(&self, backend: &BackendId, metadata: &HashMap<String, String>) {
+        self.backends.insert(backend.clone(), metadata.clone());
+    }
+
+    /// Replace a backend's metadata. Updates for backends this controller did
+    /// not schedule are ignored.
+    pub fn update_metadata(&self, backend: &BackendId, metadata: &HashMap<String, String>) {
+        if let Some(mut entry) = self.backends.get_mut(backend) {
+            *entry = metadata.clone();
+        }
+    }
+
+    /// Record a backend state change, returning the backend's metadata.
+    pub fn update_state(
+        &self,
+        backend: &BackendId,
+        state: BackendState,
+    ) -> Option<HashMap<String, String>> {
+        if state.terminal() {
+            self.backends.remove(backend).map(|(_, metadata)| metadata)
+        } else {
+            self.backends.get(backend).map(|entry| entry.clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_registry() {
+        let registry = MetadataRegistry::default();
+        let backend = BackendId::new("backend1".into());
+        let other = BackendId::new("backend2".into());
+        let tagged: HashMap<String, String> = [("incident".to_string(), "INC-1".to_string())]
+            .into_iter()
+            .collect();
+
+        registry.record_spawn(&backend, &HashMap::new());
+        registry.update_metadata(&backend, &tagged);
+        registry.update_metadata(&other, &tagged);
+
+        assert_eq!(
+            Some(tagged.clone()),
+            registry.update_state(&backend, BackendState::Ready)
+        );
+        assert_eq!(None, registry.update_state(&other, BackendState::Ready));
+        assert_eq!(
+            Some(tagged),
+            registry.update_state(&backend, BackendState::Swept)
+        );
+        assert_eq!(None, registry.update_state(&backend, BackendState::Swept));
+    }
+}
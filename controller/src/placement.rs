@@ -0,0 +1,199 @@
+//! Strategies for choosing which drone a backend is placed on.
+//!
+//! The scheduler narrows each cluster down to its live drones and hands them to
+//! a [PlacementStrategy]. Crates which embed the controller can implement their
+//! own strategy and pass it in through [crate::plan::SchedulerPlan].
+
+use plane_core::{
+    messages::agent::ProxyMetrics,
+    types::{ClusterName, DroneId},
+};
+use rand::{seq::SliceRandom, thread_rng};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// A live drone which a backend can be placed on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DroneCandidate {
+    pub drone_id: DroneId,
+
+    /// Number of backends the drone is running, if known: the larger of its
+    /// most recent report and the number of its backends not yet terminated.
+    pub running_backends: Option<u32>,
+
+    /// Traffic through the drone's proxy, if it reports it.
+    pub proxy_metrics: Option<ProxyMetrics>,
+}
+
+/// Units of live traffic (open connections, WebSocket connections, and
+/// requests per second) which weigh as much as one running backend in
+/// [LeastLoadedPlacement].
+const TRAFFIC_PER_BACKEND: f64 = 100.0;
+
+impl DroneCandidate {
+    /// Load of the drone: its running backends, plus its live traffic
+    /// weighed against [TRAFFIC_PER_BACKEND].
+    #[must_use]
+    pub fn load(&self) -> f64 {
+        let traffic = self.proxy_metrics.as_ref().map_or(0.0, |metrics| {
+            f64::from(metrics.open_connections)
+                + f64::from(metrics.websocket_connections)
+                + metrics.requests_per_second
+        });
+
+        f64::from(self.running_backends.unwrap_or_default()) + traffic / TRAFFIC_PER_BACKEND
+    }
+}
+
+pub trait PlacementStrategy: Send + Sync {
+    /// Choose a drone from `candidates` (which is never empty) to run a backend
+    /// in the given cluster, or return None if none of them should be used.
+    fn place(&self, cluster: &ClusterName, candidates: &[DroneCandidate]) -> Option<DroneId>;
+}
+
+/// Place each backend on a drone chosen uniformly at random.
+pub struct RandomPlacement;
+
+impl PlacementStrategy for RandomPlacement {
+    fn place(&self, _cluster: &ClusterName, candidates: &[DroneCandidate]) -> Option<DroneId> {
+        candidates
+            .choose(&mut thread_rng())
+            .map(|d| d.drone_id.clone())
+    }
+}
+
+/// Place each backend on the drone with the least load (see
+/// [DroneCandidate::load]), which is mostly the number of backends it runs,
+/// weighed up by the traffic through its proxy.
+///
+/// Drones report their backend count periodically, so several backends
+/// scheduled in quick succession may land on the same drone.
+pub struct LeastLoadedPlacement;
+
+impl PlacementStrategy for LeastLoadedPlacement {
+    fn place(&self, _cluster: &ClusterName, candidates: &[DroneCandidate]) -> Option<DroneId> {
+        candidates
+            .iter()
+            .min_by(|a, b| a.load().total_cmp(&b.load()))
+            .map(|d| d.drone_id.clone())
+    }
+}
+
+/// Place each backend on the busiest drone that still has room for it, so that
+/// lightly-used drones can be scaled down.
+pub struct BinPackingPlacement {
+    pub max_backends_per_drone: u32,
+}
+
+impl PlacementStrategy for BinPackingPlacement {
+    fn place(&self, _cluster: &ClusterName, candidates: &[DroneCandidate]) -> Option<DroneId> {
+        candidates
+            .iter()
+            .filter(|d| d.running_backends.unwrap_or_default() < self.max_backends_per_drone)
+            .max_by_key(|d| d.running_backends.unwrap_or_default())
+            .map(|d| d.drone_id.clone())
+    }
+}
+
+/// Built-in placement strategies, as selected in the controller config.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum PlacementOptions {
+    #[default]
+    Random,
+    LeastLoaded,
+    BinPacking {
+        max_backends_per_drone: u32,
+    },
+}
+
+impl PlacementOptions {
+    #[must_use]
+    pub fn strategy(&self) -> Arc<dyn PlacementStrategy> {
+        match self {
+            PlacementOptions::Random => Arc::new(RandomPlacement),
+            PlacementOptions::LeastLoaded => Arc::new(LeastLoadedPlacement),
+            PlacementOptions::BinPacking {
+                max_backends_per_drone,
+            } => Arc::new(BinPackingPlacement {
+                max_backends_per_drone: *max_backends_per_drone,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates() -> Vec<DroneCandidate> {
+        vec![
+            DroneCandidate {
+                drone_id: DroneId::new("drone1".into()),
+                running_backends: Some(3),
+                proxy_metrics: None,
+            },
+            DroneCandidate {
+                drone_id: DroneId::new("drone2".into()),
+                running_backends: Some(1),
+                proxy_metrics: None,
+            },
+            DroneCandidate {
+                drone_id: DroneId::new("drone3".into()),
+                running_backends: Some(5),
+                proxy_metrics: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_least_loaded() {
+        let cluster = ClusterName::new("mycluster.test");
+        assert_eq!(
+            Some(DroneId::new("drone2".into())),
+            LeastLoadedPlacement.place(&cluster, &candidates())
+        );
+    }
+
+    #[test]
+    fn test_least_loaded_weighs_traffic() {
+        let cluster = ClusterName::new("mycluster.test");
+        let mut candidates = candidates();
+        candidates[1].proxy_metrics = Some(ProxyMetrics {
+            open_connections: 150,
+            requests_per_second: 200.0,
+            websocket_connections: 100,
+        });
+        assert_eq!(
+            Some(DroneId::new("drone1".into())),
+            LeastLoadedPlacement.place(&cluster, &candidates)
+        );
+
+        candidates[1].proxy_metrics = Some(ProxyMetrics {
+            open_connections: 10,
+            requests_per_second: 5.0,
+            websocket_connections: 10,
+        });
+        assert_eq!(
+            Some(DroneId::new("drone2".into())),
+            LeastLoadedPlacement.place(&cluster, &candidates)
+        );
+    }
+
+    #[test]
+    fn test_bin_packing() {
+        let cluster = ClusterName::new("mycluster.test");
+        let strategy = BinPackingPlacement {
+            max_backends_per_drone: 5,
+        };
+        assert_eq!(
+            Some(DroneId::new("drone1".into())),
+            strategy.place(&cluster, &candidates())
+        );
+
+        let strategy = BinPackingPlacement {
+            max_backends_per_drone: 1,
+        };
+        assert_eq!(None, strategy.place(&cluster, &candidates()));
+    }
+}
@@ -1,15 +1,374 @@
-use crate::{config::ControllerConfig, dns::rname_format::format_rname};
-use anyhow::{Context, Result};
-use plane_core::nats::TypedNats;
-use std::net::IpAddr;
-use trust_dns_server::client::rr::Name;
+use crate::{
+    admission::AdmissionWebhook,
+    auth::{AuthOptions, AuthProvider},
+    cluster_config::ClusterConfigTracker,
+    config::{
+        CertificatePaths, CertificatePushOptions, ControllerConfig, EncryptedDnsOptions,
+        SoaOptions, VersionPolicy, ZoneOptions,
+    },
+    diagnostics::DiagnosticsOptions,
+    dns::rname_format::format_rname,
+    drone_update::DroneUpdateOptions,
+    leader::LeaderElectionOptions,
+    placement::PlacementStrategy,
+    rate_limit::RateLimitOptions,
+    retention::RetentionOptions,
+    scheduler::DEFAULT_IMAGE_AFFINITY_WEIGHT,
+};
+use anyhow::{anyhow, Context, Result};
+use plane_core::{
+    error::PlaneError,
+    messages::{
+        agent::ResourceLimits,
+        dns::DnsRecordType,
+        scheduler::{BackendUrlConfig, ClusterConfig},
+    },
+    nats::TypedNats,
+    signing::{SigningKey, VerifyingKey},
+    streams::StreamsConfig,
+    types::ClusterName,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
+};
+use trust_dns_server::client::rr::{rdata::TXT, Name, RData};
 
-pub struct SchedulerPlan;
+#[derive(Default)]
+pub struct SchedulerPlan {
+    pub autoscaler: Option<AutoscalerPlan>,
+    pub image_cache: Option<ImageCachePlan>,
+
+    /// Strategy used to place backends on drones. Crates which embed the
+    /// controller can supply their own; defaults to random placement.
+    pub placement: Option<Arc<dyn PlacementStrategy>>,
+
+    /// Provider used to authenticate control requests. Crates which embed
+    /// the controller can supply their own; defaults to allowing every request.
+    pub auth: Option<Arc<dyn AuthProvider>>,
+
+    /// Webhook which reviews schedule requests before they are scheduled.
+    pub admission: Option<Arc<AdmissionWebhook>>,
+
+    pub clusters: HashMap<ClusterName, ClusterPlan>,
+
+    /// How drones with an incompatible version are treated.
+    pub version_policy: VersionPolicy,
+
+    /// Whether drones which have a backend's image cached are preferred.
+    pub image_affinity: bool,
+
+    /// How many backends of load having an image cached is worth to a drone.
+    pub image_affinity_weight: f64,
+
+    /// If provided, this controller only schedules backends while it is the
+    /// elected leader. Otherwise, it always does.
+    pub leader_election: Option<LeaderElectionOptions>,
+
+    /// If provided, JetStream streams and consumers are checked periodically,
+    /// and an alert is published for each problem found.
+    pub diagnostics: Option<DiagnosticsOptions>,
+
+    /// If provided, schedule requests beyond this rate are throttled.
+    pub rate_limit: Option<RateLimitOptions>,
+
+    /// Largest batch schedule request which is accepted. Defaults to
+    /// [crate::DEFAULT_MAX_BATCH_SIZE].
+    pub max_batch_size: Option<u32>,
+    /// If provided, the states of backends which terminated long ago are
+    /// periodically purged.
+    pub retention: Option<RetentionOptions>,
+
+    /// Configs applied to clusters through `plane-cli apply`, shared with
+    /// the DNS server.
+    pub cluster_configs: Arc<ClusterConfigTracker>,
+}
+
+/// Idle timeout used when neither the request nor the cluster provide one.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Default, Clone)]
+pub struct ClusterPlan {
+    pub default_idle: Option<Duration>,
+    pub min_idle: Option<Duration>,
+    pub max_idle: Option<Duration>,
+    pub allow_unlimited_idle: bool,
+    pub default_resource_limits: Option<ResourceLimits>,
+    pub max_memory_bytes: Option<i64>,
+    pub max_cpu_period_percent: Option<u8>,
+    pub reject_over_limits: bool,
+
+    /// Upper bound on the number of backends running in the cluster.
+    pub max_backends: Option<u32>,
+
+    /// If provided, backends may only use images under one of these
+    /// prefixes, matched on whole path segments.
+    pub allowed_images: Option<Vec<String>>,
+
+    /// If provided, schedule requests must be signed with the matching
+    /// private key.
+    pub signing_key: Option<VerifyingKey>,
+
+    /// If provided, the certificate pushed to the cluster's drones.
+    pub certificate: Option<CertificatePushOptions>,
+
+    /// Whether drones must register with an approved key to be scheduled to.
+    pub require_drone_approval: bool,
+
+    /// Registration keys which are approved without an operator.
+    pub approved_drone_keys: Vec<String>,
+
+    /// How the URLs of the cluster's backends are formed.
+    pub backend_url: BackendUrlConfig,
+
+    /// If provided, schedule requests for the cluster beyond this rate are
+    /// throttled.
+    pub rate_limit: Option<RateLimitOptions>,
+
+    /// If provided, the drone version published to the cluster.
+    pub drone_update: Option<DroneUpdateOptions>,
+}
+
+/// Whether an image is under `prefix` on whole path segments, so that
+/// `ghcr.io/my-org` covers `ghcr.io/my-org/app:1.0` but not
+/// `ghcr.io/my-org-fork/app`. A prefix naming a whole repository covers its
+/// tags and digests.
+fn image_has_prefix(image: &str, prefix: &str) -> bool {
+    match image.strip_prefix(prefix.trim_end_matches('/')) {
+        Some(rest) => rest.is_empty() || rest.starts_with(|c| matches!(c, '/' | ':' | '@')),
+        None => false,
+    }
+}
+
+impl ClusterPlan {
+    /// This plan, with the settings of a config applied through
+    /// `plane-cli apply` taking precedence.
+    #[must_use]
+    pub fn with_config(mut self, config: &ClusterConfig) -> Self {
+        self.max_backends = config.max_backends.or(self.max_backends);
+        self.default_resource_limits = config
+            .default_resource_limits
+            .clone()
+            .or(self.default_resource_limits);
+        self.max_memory_bytes = config.max_memory_bytes.or(self.max_memory_bytes);
+        self.max_cpu_period_percent = config
+            .max_cpu_period_percent
+            .or(self.max_cpu_period_percent);
+        self.allowed_images = config.allowed_images.clone().or(self.allowed_images);
+        if let Some(backend_url) = &config.backend_url {
+            self.backend_url = backend_url.clone();
+        }
+        self
+    }
+
+    /// Check that the cluster allows backends to use an image.
+    pub fn check_image(&self, image: &str) -> Result<(), PlaneError> {
+        match &self.allowed_images {
+            Some(allowed) if !allowed.iter().any(|prefix| image_has_prefix(image, prefix)) => {
+                Err(PlaneError::InvalidRequest {
+                    reason: format!("Image {} is not allowed in this cluster.", image),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that `requested` more backends fit within the cluster's backend
+    /// quota, given the number already running.
+    pub fn check_backend_quota(&self, running: u32, requested: u32) -> Result<(), PlaneError> {
+        match self.max_backends {
+            Some(max_backends) if running.saturating_add(requested) > max_backends => {
+                Err(PlaneError::QuotaExceeded {
+                    reason: format!(
+                        "Cluster is limited to {} backends, and {} are running.",
+                        max_backends, running
+                    ),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The idle timeout to give a backend, given the timeout its request asked
+    /// for. A timeout of zero disables idle sweeping, and is only passed through
+    /// if the cluster allows it.
+    #[must_use]
+    pub fn idle_timeout(&self, requested: Option<Duration>) -> Duration {
+        let mut idle_timeout = requested
+            .or(self.default_idle)
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT);
+
+        if idle_timeout.is_zero() {
+            if self.allow_unlimited_idle {
+                return Duration::ZERO;
+            }
+            idle_timeout = self.default_idle.unwrap_or(DEFAULT_IDLE_TIMEOUT);
+        }
+
+        if let Some(min_idle) = self.min_idle {
+            idle_timeout = idle_timeout.max(min_idle);
+        }
+        if let Some(max_idle) = self.max_idle {
+            idle_timeout = idle_timeout.min(max_idle);
+        }
+
+        idle_timeout
+    }
+
+    /// The resource limits to give a backend, given the limits its request
+    /// asked for, or the reason the request should be rejected.
+    pub fn resource_limits(
+        &self,
+        requested: &ResourceLimits,
+    ) -> Result<ResourceLimits, PlaneError> {
+        let mut limits = match &self.default_resource_limits {
+            Some(default_limits) if *requested == ResourceLimits::default() => {
+                default_limits.clone()
+            }
+            _ => requested.clone(),
+        };
+
+        if let Some(max_memory_bytes) = self.max_memory_bytes {
+            match limits.memory_limit_bytes {
+                Some(memory) if memory <= max_memory_bytes => (),
+                Some(memory) if self.reject_over_limits => {
+                    return Err(PlaneError::QuotaExceeded {
+                        reason: format!(
+                            "Memory limit of {} bytes exceeds the cluster maximum of {} bytes.",
+                            memory, max_memory_bytes
+                        ),
+                    })
+                }
+                _ => limits.memory_limit_bytes = Some(max_memory_bytes),
+            }
+        }
+
+        // The maximum applies to the CPU time per period however it is
+        // given, since a `cpu_quota` takes precedence over a percentage.
+        if let Some(max_cpu_period_percent) = self.max_cpu_period_percent {
+            let cpu_period = limits
+                .cpu_period
+                .unwrap_or(ResourceLimits::DEFAULT_CPU_PERIOD);
+            let max_cpu_quota = cpu_period.saturating_mul(max_cpu_period_percent as u32) / 100;
+            match limits.effective_cpu_quota() {
+                Some(cpu_quota) if cpu_quota <= max_cpu_quota => (),
+                Some(cpu_quota) if self.reject_over_limits => {
+                    return Err(PlaneError::QuotaExceeded {
+                        reason: format!(
+                            "CPU limit of {:.0}% exceeds the cluster maximum of {}%.",
+                            cpu_quota.as_secs_f64() / cpu_period.as_secs_f64() * 100.0,
+                            max_cpu_period_percent
+                        ),
+                    })
+                }
+                _ => {
+                    limits.cpu_quota = None;
+                    limits.cpu_period_percent = Some(max_cpu_period_percent);
+                }
+            }
+        }
+
+        limits.validate()?;
+        Ok(limits)
+    }
+}
+
+pub struct AutoscalerPlan {
+    pub report_interval: Duration,
+    pub failed_schedule_window: Duration,
+}
+
+pub struct ImageCachePlan {
+    pub images: Vec<String>,
+    pub seed_interval: Duration,
+}
 
 pub struct DnsPlan {
     pub port: u16,
     pub bind_ip: IpAddr,
     pub soa_email: Option<Name>,
+
+    /// Zones the server is authoritative for. If empty, the records of every
+    /// cluster are served.
+    pub zones: HashMap<ClusterName, ZonePlan>,
+
+    /// If provided, listeners for DNS over TLS and HTTPS.
+    pub encrypted: Option<EncryptedDnsOptions>,
+
+    /// Configs applied to clusters through `plane-cli apply`, shared with
+    /// the scheduler.
+    pub cluster_configs: Arc<ClusterConfigTracker>,
+    pub nc: TypedNats,
+}
+
+#[derive(Clone)]
+pub struct ZonePlan {
+    pub name_servers: Vec<Name>,
+    pub soa_email: Option<Name>,
+    pub soa: SoaOptions,
+
+    /// Static records, each with its name within the zone.
+    pub records: Vec<(String, RData)>,
+}
+
+fn parse_soa_email(soa_email: &str) -> Result<Name> {
+    let soa_email = format_rname(soa_email)
+        .context("soa_email provided in configuration was not a valid email address.")?;
+    Name::from_ascii(&soa_email).context("soa_email contained non-ascii characters.")
+}
+
+impl ZonePlan {
+    fn from_options(zone: &str, options: ZoneOptions, soa_email: &Option<Name>) -> Result<Self> {
+        let name_servers = options
+            .name_servers
+            .iter()
+            .map(|name_server| {
+                Name::from_ascii(format!("{}.", name_server.trim_end_matches('.')))
+                    .with_context(|| format!("Invalid name server {} for {}.", name_server, zone))
+            })
+            .collect::<Result<_>>()?;
+
+        let records = options
+            .records
+            .into_iter()
+            .map(|record| {
+                let rdata = match record.kind {
+                    DnsRecordType::A => record.value.parse::<Ipv4Addr>().map(RData::A).ok(),
+                    DnsRecordType::AAAA => record.value.parse::<Ipv6Addr>().map(RData::AAAA).ok(),
+                    DnsRecordType::TXT => Some(RData::TXT(TXT::new(vec![record.value.clone()]))),
+                }
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Invalid value {} for {} record {:?} in {}.",
+                        record.value,
+                        record.kind,
+                        record.name,
+                        zone
+                    )
+                })?;
+                Ok((record.name, rdata))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(ZonePlan {
+            name_servers,
+            soa_email: match &options.soa_email {
+                Some(zone_soa_email) => Some(parse_soa_email(zone_soa_email)?),
+                None => soa_email.clone(),
+            },
+            soa: options.soa,
+            records,
+        })
+    }
+}
+
+pub struct GrpcPlan {
+    pub port: u16,
+    pub bind_ip: IpAddr,
+    pub tls: CertificatePaths,
+    pub auth: Arc<dyn AuthProvider>,
     pub nc: TypedNats,
 }
 
@@ -17,40 +376,440 @@ pub struct ControllerPlan {
     pub nats: TypedNats,
     pub scheduler_plan: Option<SchedulerPlan>,
     pub dns_plan: Option<DnsPlan>,
+    pub grpc_plan: Option<GrpcPlan>,
+    pub streams: Option<StreamsConfig>,
 }
 
 impl ControllerPlan {
     pub async fn from_controller_config(config: ControllerConfig) -> Result<Self> {
         let nats = config.nats.connect_with_retry().await?;
+        let cluster_configs: Arc<ClusterConfigTracker> = Arc::default();
 
-        let scheduler_plan = config.scheduler.map(|_| SchedulerPlan);
+        let scheduler_plan = config
+            .scheduler
+            .map(|options| -> Result<SchedulerPlan> {
+                let mut clusters = HashMap::new();
+                for (cluster, cluster_options) in options.clusters {
+                    let plan = ClusterPlan {
+                        default_idle: cluster_options.default_idle_secs.map(Duration::from_secs),
+                        min_idle: cluster_options.min_idle_secs.map(Duration::from_secs),
+                        max_idle: cluster_options.max_idle_secs.map(Duration::from_secs),
+                        allow_unlimited_idle: cluster_options.allow_unlimited_idle,
+                        default_resource_limits: cluster_options.default_resource_limits,
+                        max_memory_bytes: cluster_options.max_memory_bytes,
+                        max_cpu_period_percent: cluster_options.max_cpu_period_percent,
+                        reject_over_limits: cluster_options.reject_over_limits,
+                        max_backends: None,
+                        allowed_images: None,
+                        signing_key: cluster_options
+                            .signing_public_key
+                            .as_deref()
+                            .map(VerifyingKey::from_base64)
+                            .transpose()
+                            .with_context(|| {
+                                format!("Invalid signing_public_key for cluster {}.", cluster)
+                            })?,
+                        certificate: cluster_options.certificate,
+                        require_drone_approval: cluster_options.require_drone_approval,
+                        approved_drone_keys: cluster_options.approved_drone_keys,
+                        backend_url: cluster_options.backend_url.unwrap_or_default(),
+                        rate_limit: cluster_options.rate_limit,
+                        drone_update: cluster_options.drone_update,
+                    };
+                    if let Some(certificate) = &plan.certificate {
+                        SigningKey::from_seed(&certificate.signing_key).with_context(|| {
+                            format!("Invalid certificate.signing_key for cluster {}.", cluster)
+                        })?;
+                    }
+                    for key in &plan.approved_drone_keys {
+                        VerifyingKey::from_base64(key).with_context(|| {
+                            format!("Invalid approved_drone_keys for cluster {}.", cluster)
+                        })?;
+                    }
+                    if let (Some(min_idle), Some(max_idle)) = (plan.min_idle, plan.max_idle) {
+                        if min_idle > max_idle {
+                            return Err(anyhow!(
+                                "min_idle_secs is greater than max_idle_secs for cluster {}.",
+                                cluster
+                            ));
+                        }
+                    }
+                    if let Some(rate_limit) = &plan.rate_limit {
+                        rate_limit.validate().with_context(|| {
+                            format!("Invalid rate_limit for cluster {}.", cluster)
+                        })?;
+                    }
+                    clusters.insert(ClusterName::new(&cluster), plan);
+                }
+
+                if let Some(rate_limit) = &options.rate_limit {
+                    rate_limit
+                        .validate()
+                        .context("Invalid scheduler rate_limit.")?;
+                }
+                if let Some(autoscaler) = &options.autoscaler {
+                    autoscaler
+                        .validate()
+                        .context("Invalid scheduler autoscaler.")?;
+                }
+                if let Some(image_cache) = &options.image_cache {
+                    image_cache
+                        .validate()
+                        .context("Invalid scheduler image_cache.")?;
+                }
+                if let Some(retention) = &options.retention {
+                    retention
+                        .validate()
+                        .context("Invalid scheduler retention.")?;
+                }
+                if let Some(diagnostics) = &options.diagnostics {
+                    diagnostics
+                        .validate()
+                        .context("Invalid scheduler diagnostics.")?;
+                }
+                if let Some(leader_election) = &options.leader_election {
+                    leader_election
+                        .validate()
+                        .context("Invalid scheduler leader_election.")?;
+                }
+                if options.max_batch_size == Some(0) {
+                    return Err(anyhow!("scheduler.max_batch_size must be greater than 0."));
+                }
+                let image_affinity_weight = options
+                    .image_affinity_weight
+                    .unwrap_or(DEFAULT_IMAGE_AFFINITY_WEIGHT);
+                if !(image_affinity_weight >= 0.0 && image_affinity_weight.is_finite()) {
+                    return Err(anyhow!(
+                        "scheduler.image_affinity_weight must be a non-negative number."
+                    ));
+                }
+
+                Ok(SchedulerPlan {
+                    autoscaler: options.autoscaler.map(|autoscaler| AutoscalerPlan {
+                        report_interval: Duration::from_secs(autoscaler.report_interval_secs),
+                        failed_schedule_window: Duration::from_secs(
+                            autoscaler.failed_schedule_window_secs,
+                        ),
+                    }),
+                    image_cache: options.image_cache.map(|image_cache| ImageCachePlan {
+                        images: image_cache.images,
+                        seed_interval: Duration::from_secs(image_cache.seed_interval_secs),
+                    }),
+                    placement: Some(options.placement.strategy()),
+                    auth: Some(options.auth.provider()),
+                    admission: options
+                        .admission
+                        .map(AdmissionWebhook::new)
+                        .transpose()
+                        .context("Error building admission webhook client.")?
+                        .map(Arc::new),
+                    clusters,
+                    version_policy: options.version_policy,
+                    image_affinity: options.image_affinity,
+                    image_affinity_weight,
+                    leader_election: options.leader_election,
+                    diagnostics: options.diagnostics,
+                    rate_limit: options.rate_limit,
+                    retention: options.retention,
+                    max_batch_size: options.max_batch_size,
+                    cluster_configs: cluster_configs.clone(),
+                })
+            })
+            .transpose()?;
         let dns_plan = if let Some(options) = config.dns {
-            let soa_email = if let Some(soa_email) = options.soa_email {
-                let soa_email = format_rname(&soa_email).context(
-                    "soa_email provided in configuration was not a valid email address.",
-                )?;
-                Some(
-                    Name::from_ascii(&soa_email)
-                        .context("soa_email contained non-ascii characters.")?,
-                )
-            } else {
-                None
-            };
+            let soa_email = options
+                .soa_email
+                .as_deref()
+                .map(parse_soa_email)
+                .transpose()?;
+
+            let mut zones = HashMap::new();
+            for (zone, zone_options) in options.zones {
+                let plan = ZonePlan::from_options(&zone, zone_options, &soa_email)?;
+                zones.insert(ClusterName::new(&zone), plan);
+            }
+
+            if let Some(encrypted) = &options.encrypted {
+                if encrypted.dot_port.is_none() && encrypted.doh_port.is_none() {
+                    return Err(anyhow!(
+                        "dns.encrypted requires at least one of dot_port and doh_port."
+                    ));
+                }
+                if encrypted.doh_port.is_some() && encrypted.doh_hostname.is_none() {
+                    return Err(anyhow!("dns.encrypted.doh_port requires doh_hostname."));
+                }
+                if encrypted.certificate_check_secs == 0 {
+                    return Err(anyhow!(
+                        "dns.encrypted.certificate_check_secs must be greater than 0."
+                    ));
+                }
+            }
 
             Some(DnsPlan {
                 port: options.port,
                 bind_ip: options.bind_ip,
                 soa_email,
+                zones,
+                encrypted: options.encrypted,
+                cluster_configs: cluster_configs.clone(),
                 nc: nats.clone(),
             })
         } else {
             None
         };
 
+        let grpc_plan = config
+            .grpc
+            .map(|options| {
+                if options.auth == AuthOptions::AllowAll {
+                    return Err(anyhow!(
+                        "grpc.auth must authenticate calls; allow_all is not accepted."
+                    ));
+                }
+
+                Ok(GrpcPlan {
+                    port: options.port,
+                    bind_ip: options.bind_ip,
+                    tls: options.tls,
+                    auth: options.auth.provider(),
+                    nc: nats.clone(),
+                })
+            })
+            .transpose()?;
+
         Ok(ControllerPlan {
             nats,
             scheduler_plan,
             dns_plan,
+            grpc_plan,
+            streams: config.streams,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StaticRecord;
+
+    #[test]
+    fn test_idle_timeout_bounds() {
+        let plan = ClusterPlan {
+            default_idle: Some(Duration::from_secs(60)),
+            min_idle: Some(Duration::from_secs(10)),
+            max_idle: Some(Duration::from_secs(3600)),
+            ..ClusterPlan::default()
+        };
+
+        assert_eq!(Duration::from_secs(60), plan.idle_timeout(None));
+        assert_eq!(
+            Duration::from_secs(120),
+            plan.idle_timeout(Some(Duration::from_secs(120)))
+        );
+        assert_eq!(
+            Duration::from_secs(10),
+            plan.idle_timeout(Some(Duration::from_secs(1)))
+        );
+        assert_eq!(
+            Duration::from_secs(3600),
+            plan.idle_timeout(Some(Duration::from_secs(30 * 24 * 3600)))
+        );
+        assert_eq!(
+            DEFAULT_IDLE_TIMEOUT,
+            ClusterPlan::default().idle_timeout(None)
+        );
+    }
+
+    #[test]
+    fn test_unlimited_idle_timeout() {
+        let mut plan = ClusterPlan {
+            default_idle: Some(Duration::from_secs(60)),
+            max_idle: Some(Duration::from_secs(3600)),
+            ..ClusterPlan::default()
+        };
+
+        assert_eq!(
+            Duration::from_secs(60),
+            plan.idle_timeout(Some(Duration::ZERO))
+        );
+
+        plan.allow_unlimited_idle = true;
+        assert_eq!(Duration::ZERO, plan.idle_timeout(Some(Duration::ZERO)));
+        assert_eq!(Duration::from_secs(60), plan.idle_timeout(None));
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let mut plan = ClusterPlan {
+            default_resource_limits: Some(ResourceLimits {
+                memory_limit_bytes: Some(1 << 28),
+                ..ResourceLimits::default()
+            }),
+            max_memory_bytes: Some(1 << 30),
+            max_cpu_period_percent: Some(50),
+            ..ClusterPlan::default()
+        };
+
+        let limits = plan.resource_limits(&ResourceLimits::default()).unwrap();
+        assert_eq!(Some(1 << 28), limits.memory_limit_bytes);
+        assert_eq!(Some(50), limits.cpu_period_percent);
+
+        let requested = ResourceLimits {
+            memory_limit_bytes: Some(1 << 32),
+            cpu_period_percent: Some(25),
+            ..ResourceLimits::default()
+        };
+        let limits = plan.resource_limits(&requested).unwrap();
+        assert_eq!(Some(1 << 30), limits.memory_limit_bytes);
+        assert_eq!(Some(25), limits.cpu_period_percent);
+
+        // A quota above the maximum is held to it too.
+        let requested = ResourceLimits {
+            cpu_quota: Some(Duration::from_millis(80)),
+            ..ResourceLimits::default()
+        };
+        let limits = plan.resource_limits(&requested).unwrap();
+        assert_eq!(None, limits.cpu_quota);
+        assert_eq!(
+            Some(Duration::from_millis(50)),
+            limits.effective_cpu_quota()
+        );
+
+        let within = ResourceLimits {
+            cpu_period: Some(Duration::from_millis(200)),
+            cpu_quota: Some(Duration::from_millis(80)),
+            ..ResourceLimits::default()
+        };
+        assert_eq!(
+            Some(Duration::from_millis(80)),
+            plan.resource_limits(&within).unwrap().cpu_quota
+        );
+
+        plan.reject_over_limits = true;
+        assert!(matches!(
+            plan.resource_limits(&requested),
+            Err(PlaneError::QuotaExceeded { .. })
+        ));
+        let requested = ResourceLimits {
+            memory_limit_bytes: Some(1 << 32),
+            ..ResourceLimits::default()
+        };
+        assert!(plan.resource_limits(&requested).is_err());
+
+        let requested = ResourceLimits {
+            io_weight: Some(5000),
+            ..ResourceLimits::default()
+        };
+        assert!(matches!(
+            plan.resource_limits(&requested),
+            Err(PlaneError::InvalidRequest { .. })
+        ));
+    }
+
+    #[test]
+    fn test_with_config() {
+        let plan = ClusterPlan {
+            max_memory_bytes: Some(1 << 30),
+            max_cpu_period_percent: Some(50),
+            ..ClusterPlan::default()
+        };
+        let config = ClusterConfig {
+            cluster: ClusterName::new("plane.test"),
+            max_backends: Some(10),
+            default_resource_limits: None,
+            max_memory_bytes: Some(1 << 28),
+            max_cpu_period_percent: None,
+            dns_ttl_secs: None,
+            allowed_images: Some(vec!["ghcr.io/drifting-in-space/".into()]),
+            backend_url: Some(BackendUrlConfig {
+                port: Some(8443),
+                ..BackendUrlConfig::default()
+            }),
+        };
+
+        let plan = plan.with_config(&config);
+        assert_eq!(Some(10), plan.max_backends);
+        assert_eq!(Some(1 << 28), plan.max_memory_bytes);
+        assert_eq!(Some(50), plan.max_cpu_period_percent);
+        assert_eq!(Some(8443), plan.backend_url.port);
+
+        assert!(plan
+            .check_image("ghcr.io/drifting-in-space/demo-image-drop-four")
+            .is_ok());
+        assert!(matches!(
+            plan.check_image("docker.io/library/nginx"),
+            Err(PlaneError::InvalidRequest { .. })
+        ));
+        assert!(matches!(
+            plan.check_image("ghcr.io/drifting-in-space-fork/demo-image-drop-four"),
+            Err(PlaneError::InvalidRequest { .. })
+        ));
+
+        assert!(plan.check_backend_quota(9, 1).is_ok());
+        assert!(matches!(
+            plan.check_backend_quota(9, 2),
+            Err(PlaneError::QuotaExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn test_image_has_prefix() {
+        assert!(image_has_prefix("ghcr.io/my-org/app:1.0", "ghcr.io/my-org"));
+        assert!(image_has_prefix(
+            "ghcr.io/my-org/app:1.0",
+            "ghcr.io/my-org/"
+        ));
+        assert!(image_has_prefix(
+            "ghcr.io/my-org/app:1.0",
+            "ghcr.io/my-org/app"
+        ));
+        assert!(image_has_prefix(
+            "ghcr.io/my-org/app@sha256:abcd",
+            "ghcr.io/my-org/app"
+        ));
+        assert!(!image_has_prefix(
+            "ghcr.io/my-org-fork/app",
+            "ghcr.io/my-org"
+        ));
+        assert!(!image_has_prefix(
+            "ghcr.io/my-org/app-evil",
+            "ghcr.io/my-org/app"
+        ));
+        assert!(!image_has_prefix(
+            "docker.io/library/nginx",
+            "ghcr.io/my-org"
+        ));
+    }
+
+    #[test]
+    fn test_zone_plan() {
+        let options = ZoneOptions {
+            name_servers: vec!["ns1.plane.test".into()],
+            records: vec![StaticRecord {
+                name: "www".into(),
+                kind: DnsRecordType::A,
+                value: "12.12.12.12".into(),
+            }],
+            ..ZoneOptions::default()
+        };
+        let soa_email = Some(Name::from_ascii("admin.plane.test.").unwrap());
+        let plan = ZonePlan::from_options("plane.test", options.clone(), &soa_email).unwrap();
+
+        assert_eq!("ns1.plane.test.", plan.name_servers[0].to_ascii());
+        assert_eq!(soa_email, plan.soa_email);
+        assert_eq!(
+            vec![("www".to_string(), RData::A(Ipv4Addr::new(12, 12, 12, 12)))],
+            plan.records
+        );
+
+        let options = ZoneOptions {
+            records: vec![StaticRecord {
+                name: "www".into(),
+                kind: DnsRecordType::AAAA,
+                value: "12.12.12.12".into(),
+            }],
+            ..options
+        };
+        assert!(ZonePlan::from_options("plane.test", options, &soa_email).is_err());
+    }
+}
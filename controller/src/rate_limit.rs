@@ -0,0 +1,268 @@
+//! Limits on how quickly the controller accepts schedule requests, so that
+//! an upstream system which floods it with requests is throttled before it
+//! can overwhelm the drones.
+//!
+//! Each controller keeps its own buckets. With leader election, only the
+//! leader accepts requests, so the limits apply to the cluster as a whole;
+//! without it, each controller accepts requests at the configured rate.
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use plane_core::types::ClusterName;
+use serde::{Deserialize, Serialize};
+use std::{sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+/// A limit on the rate of schedule requests. Each backend requested counts
+/// as one request, so a batch of ten uses ten. A batch larger than the burst
+/// is never accepted.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct RateLimitOptions {
+    /// Requests accepted per second on average.
+    pub requests_per_second: f64,
+
+    /// Requests which may be accepted at once after a quiet period. Defaults
+    /// to one second's worth of requests.
+    pub burst: Option<u32>,
+}
+
+impl RateLimitOptions {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.requests_per_second > 0.0 && self.requests_per_second.is_finite()) {
+            return Err(anyhow!("requests_per_second must be a positive number."));
+        }
+        Ok(())
+    }
+}
+
+/// Why a request was not accepted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Throttle {
+    /// The request can be retried after this long.
+    RetryAfter(Duration),
+
+    /// The request needs more tokens than a limit's burst, so it would never
+    /// be accepted.
+    ExceedsBurst { burst: u32 },
+}
+
+/// Token bucket which refills at a constant rate up to its capacity.
+#[derive(Debug)]
+struct TokenBucket {
+    options: RateLimitOptions,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(options: RateLimitOptions, now: Instant) -> Self {
+        let mut bucket = TokenBucket {
+            options,
+            tokens: 0.0,
+            updated: now,
+        };
+        bucket.tokens = bucket.capacity();
+        bucket
+    }
+
+    fn capacity(&self) -> f64 {
+        match self.options.burst {
+            Some(burst) => burst.max(1) as f64,
+            None => self.options.requests_per_second.ceil().max(1.0),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.options.requests_per_second).min(self.capacity());
+        self.updated = now;
+    }
+
+    /// Why the given number of tokens can not be taken now, if they can not.
+    fn throttle(&self, cost: u32) -> Option<Throttle> {
+        let needed = cost as f64;
+        if needed > self.capacity() {
+            Some(Throttle::ExceedsBurst {
+                burst: self.capacity() as u32,
+            })
+        } else if self.tokens >= needed {
+            None
+        } else {
+            Some(Throttle::RetryAfter(Duration::from_secs_f64(
+                (needed - self.tokens) / self.options.requests_per_second,
+            )))
+        }
+    }
+
+    fn take(&mut self, cost: u32) {
+        self.tokens = (self.tokens - cost as f64).max(0.0);
+    }
+}
+
+/// Applies a global rate limit, and each cluster's own, to schedule
+/// requests.
+pub struct RateLimiter {
+    global: Option<Mutex<TokenBucket>>,
+    clusters: DashMap<ClusterName, TokenBucket>,
+}
+
+impl RateLimiter {
+    #[must_use]
+    pub fn new(global: Option<RateLimitOptions>) -> Self {
+        RateLimiter {
+            global: global.map(|options| Mutex::new(TokenBucket::new(options, Instant::now()))),
+            clusters: DashMap::default(),
+        }
+    }
+
+    /// Accept a request for `cost` backends in the given cluster, or return
+    /// why it was refused. Requests which are refused do not count against
+    /// either limit.
+    pub fn check(
+        &self,
+        cluster: &ClusterName,
+        cluster_limit: Option<RateLimitOptions>,
+        cost: u32,
+        now: Instant,
+    ) -> Result<(), Throttle> {
+        let mut global = self
+            .global
+            .as_ref()
+            .map(|global| global.lock().expect("Rate limiter lock was poisoned."));
+
+        let mut cluster_bucket = match cluster_limit {
+            Some(options) => {
+                let mut bucket = self
+                    .clusters
+                    .entry(cluster.clone())
+                    .or_insert_with(|| TokenBucket::new(options, now));
+                // The cluster's limit may have been reconfigured.
+                if bucket.options != options {
+                    *bucket = TokenBucket::new(options, now);
+                }
+                Some(bucket)
+            }
+            None => {
+                self.clusters.remove(cluster);
+                None
+            }
+        };
+
+        if let Some(global) = global.as_deref_mut() {
+            global.refill(now);
+        }
+        if let Some(bucket) = cluster_bucket.as_deref_mut() {
+            bucket.refill(now);
+        }
+
+        // A request which can never be accepted is refused as such, rather
+        // than told to retry.
+        let throttle = [
+            global.as_deref().and_then(|bucket| bucket.throttle(cost)),
+            cluster_bucket
+                .as_deref()
+                .and_then(|bucket| bucket.throttle(cost)),
+        ]
+        .into_iter()
+        .flatten()
+        .max_by_key(|throttle| match throttle {
+            Throttle::RetryAfter(wait) => *wait,
+            Throttle::ExceedsBurst { .. } => Duration::MAX,
+        });
+        if let Some(throttle) = throttle {
+            return Err(throttle);
+        }
+
+        if let Some(global) = global.as_deref_mut() {
+            global.take(cost);
+        }
+        if let Some(bucket) = cluster_bucket.as_deref_mut() {
+            bucket.take(cost);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(requests_per_second: f64, burst: Option<u32>) -> RateLimitOptions {
+        RateLimitOptions {
+            requests_per_second,
+            burst,
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let cluster = ClusterName::new("plane.test");
+        let other_cluster = ClusterName::new("other.test");
+        let start = Instant::now();
+        let limiter = RateLimiter::new(Some(limit(10.0, Some(5))));
+
+        // The global burst is used up, regardless of cluster.
+        for _ in 0..4 {
+            assert!(limiter.check(&cluster, None, 1, start).is_ok());
+        }
+        assert!(limiter.check(&other_cluster, None, 1, start).is_ok());
+        let wait = limiter.check(&cluster, None, 1, start).unwrap_err();
+        assert_eq!(Throttle::RetryAfter(Duration::from_millis(100)), wait);
+
+        // Tokens refill at the configured rate.
+        let later = start + Duration::from_millis(200);
+        assert!(limiter.check(&cluster, None, 2, later).is_ok());
+        assert!(limiter.check(&cluster, None, 1, later).is_err());
+    }
+
+    #[test]
+    fn test_cluster_rate_limit() {
+        let cluster = ClusterName::new("plane.test");
+        let other_cluster = ClusterName::new("other.test");
+        let start = Instant::now();
+        let limiter = RateLimiter::new(None);
+        let cluster_limit = Some(limit(1.0, None));
+
+        assert!(limiter.check(&cluster, cluster_limit, 1, start).is_ok());
+        assert_eq!(
+            Err(Throttle::RetryAfter(Duration::from_secs(1))),
+            limiter.check(&cluster, cluster_limit, 1, start)
+        );
+        assert!(limiter.check(&other_cluster, None, 100, start).is_ok());
+
+        // Batches larger than the burst are never accepted, even once the
+        // bucket is full.
+        let later = start + Duration::from_secs(1);
+        assert_eq!(
+            Err(Throttle::ExceedsBurst { burst: 1 }),
+            limiter.check(&cluster, cluster_limit, 10, later)
+        );
+        assert!(limiter.check(&cluster, cluster_limit, 1, later).is_ok());
+
+        // A changed limit takes effect immediately.
+        let new_limit = Some(limit(100.0, None));
+        let later = later + Duration::from_secs(1);
+        assert!(limiter.check(&cluster, new_limit, 10, later).is_ok());
+    }
+
+    #[test]
+    fn test_refused_requests_are_free() {
+        let cluster = ClusterName::new("plane.test");
+        let start = Instant::now();
+        let limiter = RateLimiter::new(Some(limit(10.0, Some(10))));
+        let cluster_limit = Some(limit(1.0, Some(1)));
+
+        assert!(limiter.check(&cluster, cluster_limit, 1, start).is_ok());
+        for _ in 0..20 {
+            assert!(limiter.check(&cluster, cluster_limit, 1, start).is_err());
+        }
+
+        // Requests refused by the cluster's limit did not use up the global one.
+        let other_cluster = ClusterName::new("other.test");
+        for _ in 0..9 {
+            assert!(limiter.check(&other_cluster, None, 1, start).is_ok());
+        }
+    }
+}
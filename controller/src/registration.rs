@@ -0,0 +1,289 @@
+use crate::plan::ClusterPlan;
+use anyhow::Result;
+use dashmap::{DashMap, DashSet};
+use plane_core::{
+    messages::{
+        agent::{DroneStatusMessage, RegisterDrone},
+        scheduler::{ApproveDrone, DroneApproval},
+    },
+    signing::VerifyingKey,
+    types::{ClusterName, DroneId},
+};
+
+/// Registrations of drones, and the registration keys approved for each
+/// cluster, which together decide whose status messages the scheduler
+/// accepts in clusters which require drones to be approved.
+#[derive(Default)]
+pub struct DroneRegistry {
+    /// Latest registration of each drone, whose signature has been verified.
+    registrations: DashMap<DroneId, RegisterDrone>,
+
+    /// Keys approved through `plane-cli approve-drone`.
+    approved: DashSet<(ClusterName, String)>,
+}
+
+impl DroneRegistry {
+    /// Whether a key is approved for a cluster, either in the controller's
+    /// configuration or by an operator.
+    pub fn is_approved(&self, plan: &ClusterPlan, cluster: &ClusterName, public_key: &str) -> bool {
+        plan.approved_drone_keys
+            .iter()
+            .any(|approved| approved == public_key)
+            || self
+                .approved
+                .contains(&(cluster.clone(), public_key.to_string()))
+    }
+
+    /// Record a verified registration. Returns true if the drone had not
+    /// registered with the same key before.
+    ///
+    /// A drone which registered with an approved key cannot re-register with
+    /// a different one, so that its ID cannot be taken over by a drone
+    /// without the key.
+    pub fn register(
+        &self,
+        plan: &ClusterPlan,
+        registration: &RegisterDrone,
+    ) -> Result<bool, String> {
+        if let Some(previous) = self.registrations.get(&registration.drone_id) {
+            if previous.public_key == registration.public_key {
+                drop(previous);
+                self.registrations
+                    .insert(registration.drone_id.clone(), registration.clone());
+                return Ok(false);
+            }
+
+            if self.is_approved(plan, &previous.cluster, &previous.public_key) {
+                return Err(format!(
+                    "Drone {} is already registered with another key.",
+                    registration.drone_id
+                ));
+            }
+        }
+
+        self.registrations
+            .insert(registration.drone_id.clone(), registration.clone());
+        Ok(true)
+    }
+
+    pub fn registration(&self, drone: &DroneId) -> Option<RegisterDrone> {
+        self.registrations
+            .get(drone)
+            .map(|registration| registration.clone())
+    }
+
+    /// The registration an approval request refers to. The request names the
+    /// fingerprint of the key the operator saw, so that a drone which
+    /// registers under the same ID with another key before the approval is
+    /// not approved in its place.
+    pub fn registration_to_approve(&self, request: &ApproveDrone) -> Result<RegisterDrone, String> {
+        let registration = match self.registration(&request.drone) {
+            Some(registration) if registration.cluster == request.cluster => registration,
+            _ => {
+                return Err(format!(
+                    "Drone {} has not registered with cluster {}.",
+                    request.drone, request.cluster
+                ))
+            }
+        };
+
+        let fingerprint = VerifyingKey::from_base64(&registration.public_key)
+            .map_err(|error| error.to_string())?
+            .fingerprint();
+        if fingerprint != request.key_fingerprint {
+            return Err(format!(
+                "Drone {} is registered with key {}, not {}.",
+                request.drone, fingerprint, request.key_fingerprint
+            ));
+        }
+
+        Ok(registration)
+    }
+
+    pub fn approve(&self, approval: &DroneApproval) {
+        self.approved
+            .insert((approval.cluster.clone(), approval.public_key.clone()));
+    }
+
+    /// Whether the scheduler should accept a drone's status message. In
+    /// clusters which require approval, the message must come from a drone
+    /// whose key is approved, which `verify` checks by verifying the
+    /// message's signature with the key.
+    pub fn admits(
+        &self,
+        plan: &ClusterPlan,
+        status: &DroneStatusMessage,
+        verify: impl FnOnce(&VerifyingKey) -> Result<()>,
+    ) -> bool {
+        if !plan.require_drone_approval {
+            return true;
+        }
+
+        let public_key = match self.registrations.get(&status.drone_id) {
+            Some(registration)
+                if registration.cluster == status.cluster
+                    && self.is_approved(plan, &registration.cluster, &registration.public_key) =>
+            {
+                registration.public_key.clone()
+            }
+            _ => return false,
+        };
+
+        match VerifyingKey::from_base64(&public_key).and_then(|key| verify(&key)) {
+            Ok(()) => true,
+            Err(error) => {
+                tracing::warn!(
+                    ?error,
+                    drone_id=%status.drone_id,
+                    "Rejected drone status signature."
+                );
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use plane_core::{messages::agent::HostFacts, signing::SigningKey};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn registration(drone: &str, public_key: &str) -> RegisterDrone {
+        RegisterDrone {
+            drone_id: DroneId::new(drone.into()),
+            cluster: ClusterName::new("plane.test"),
+            public_key: public_key.into(),
+            host: HostFacts {
+                hostname: None,
+                ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                drone_version: "0.3.4".into(),
+                os: "linux".into(),
+                arch: "x86_64".into(),
+            },
+        }
+    }
+
+    fn status(drone: &str) -> DroneStatusMessage {
+        DroneStatusMessage {
+            drone_id: DroneId::new(drone.into()),
+            cluster: ClusterName::new("plane.test"),
+            drone_version: "0.3.4".into(),
+            ready: true,
+            running_backends: None,
+            remaining_capacity: None,
+            host_metrics: None,
+            sealing_key: None,
+            arch: None,
+            proxy_metrics: None,
+            draining: false,
+        }
+    }
+
+    fn public_key() -> String {
+        SigningKey::from_seed(&SigningKey::generate().unwrap())
+            .unwrap()
+            .public_key()
+    }
+
+    fn fingerprint(public_key: &str) -> String {
+        VerifyingKey::from_base64(public_key).unwrap().fingerprint()
+    }
+
+    #[test]
+    fn test_approval_required() {
+        let key1 = public_key();
+        let key2 = public_key();
+        let configured = public_key();
+        let registry = DroneRegistry::default();
+        let plan = ClusterPlan {
+            require_drone_approval: true,
+            approved_drone_keys: vec![configured.clone()],
+            ..ClusterPlan::default()
+        };
+
+        // Drones are admitted as before unless the cluster requires approval.
+        assert!(registry.admits(&ClusterPlan::default(), &status("drone1"), |_| Ok(())));
+        assert!(!registry.admits(&plan, &status("drone1"), |_| Ok(())));
+
+        assert_eq!(
+            Ok(true),
+            registry.register(&plan, &registration("drone1", &key1))
+        );
+        assert_eq!(
+            Ok(false),
+            registry.register(&plan, &registration("drone1", &key1))
+        );
+        assert!(!registry.admits(&plan, &status("drone1"), |_| Ok(())));
+
+        registry.approve(&DroneApproval {
+            cluster: ClusterName::new("plane.test"),
+            public_key: key1.clone(),
+            drone: DroneId::new("drone1".into()),
+            time: Utc::now(),
+        });
+        assert!(registry.admits(&plan, &status("drone1"), |_| Ok(())));
+
+        // Status messages must be signed with the approved key.
+        assert!(!registry.admits(&plan, &status("drone1"), |key| {
+            if key == &VerifyingKey::from_base64(&key2).unwrap() {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("Signature is invalid."))
+            }
+        }));
+
+        // An approved drone's ID cannot be registered with another key.
+        assert!(registry
+            .register(&plan, &registration("drone1", &key2))
+            .is_err());
+
+        // Keys in the configuration are approved without an operator.
+        assert_eq!(
+            Ok(true),
+            registry.register(&plan, &registration("drone2", &configured))
+        );
+        assert!(registry.admits(&plan, &status("drone2"), |_| Ok(())));
+    }
+
+    #[test]
+    fn test_approve_by_fingerprint() {
+        let key1 = public_key();
+        let key2 = public_key();
+        let registry = DroneRegistry::default();
+        let plan = ClusterPlan {
+            require_drone_approval: true,
+            ..ClusterPlan::default()
+        };
+        let request = |key_fingerprint: String| ApproveDrone {
+            drone: DroneId::new("drone1".into()),
+            cluster: ClusterName::new("plane.test"),
+            key_fingerprint,
+        };
+
+        assert!(registry
+            .registration_to_approve(&request(fingerprint(&key1)))
+            .is_err());
+
+        registry
+            .register(&plan, &registration("drone1", &key1))
+            .unwrap();
+        assert_eq!(
+            key1,
+            registry
+                .registration_to_approve(&request(fingerprint(&key1)))
+                .unwrap()
+                .public_key
+        );
+
+        // Another drone takes over the pending registration before the
+        // operator approves the key they saw.
+        registry
+            .register(&plan, &registration("drone1", &key2))
+            .unwrap();
+        assert!(registry
+            .registration_to_approve(&request(fingerprint(&key1)))
+            .is_err());
+    }
+}
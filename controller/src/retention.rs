@@ -0,0 +1,173 @@
+//! Garbage collection of the state history of backends which terminated
+//! long ago.
+//!
+//! Every backend leaves its state messages in the `backend_status` stream
+//! after it terminates, so the stream (and every wildcard consumer or
+//! last-per-subject query over it) grows with the number of backends ever
+//! spawned. The leader periodically purges the subjects of backends which
+//! have been in a terminal state for longer than the configured age.
+
+use crate::leader::Leadership;
+use async_nats::jetstream::consumer::DeliverPolicy;
+use chrono::{DateTime, Utc};
+use plane_core::{
+    messages::agent::BackendStateMessage,
+    nats::{TypedMessage, TypedNats},
+    NeverResult,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+fn default_interval_secs() -> u64 {
+    3600
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RetentionOptions {
+    /// How long the states of a backend are kept after it terminates.
+    pub terminal_backend_max_age_secs: u64,
+
+    /// How often to look for backends to purge.
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+impl RetentionOptions {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.interval_secs == 0 {
+            return Err(anyhow::anyhow!("interval_secs must be greater than 0."));
+        }
+        Ok(())
+    }
+}
+
+/// Whether the last state of a backend shows that it terminated longer ago
+/// than `max_age`. The state's own `time` comes from the drone's clock, so
+/// its age is measured from when the NATS server stored it instead.
+fn expired(
+    last_state: &BackendStateMessage,
+    stored: DateTime<Utc>,
+    now: DateTime<Utc>,
+    max_age: Duration,
+) -> bool {
+    let max_age = match chrono::Duration::from_std(max_age) {
+        Ok(max_age) => max_age,
+        Err(_) => return false,
+    };
+
+    last_state.state.terminal() && now.signed_duration_since(stored) > max_age
+}
+
+/// Purge the states of each backend which terminated more than
+/// `max_age` ago. Returns the number of backends purged.
+async fn purge_terminal_backends(nats: &TypedNats, max_age: Duration) -> anyhow::Result<usize> {
+    let last_states = nats
+        .get_all_with_info(
+            &BackendStateMessage::wildcard_subject(),
+            DeliverPolicy::LastPerSubject,
+        )
+        .await?;
+
+    let now = Utc::now();
+    let mut purged = 0;
+    for (last_state, info) in last_states {
+        if !expired(&last_state, info.published, now, max_age) {
+            continue;
+        }
+
+        let result = nats
+            .purge_jetstream_subject::<BackendStateMessage>(&last_state.subject())
+            .await;
+        match result {
+            Ok(messages) => {
+                tracing::debug!(backend_id=%last_state.backend, messages, "Purged backend states.");
+                purged += 1;
+            }
+            Err(error) => tracing::warn!(
+                ?error,
+                backend_id=%last_state.backend,
+                "Error purging backend states."
+            ),
+        }
+    }
+
+    Ok(purged)
+}
+
+pub async fn retention_loop(
+    nats: &TypedNats,
+    options: RetentionOptions,
+    leadership: &Leadership,
+) -> NeverResult {
+    let max_age = Duration::from_secs(options.terminal_backend_max_age_secs);
+    let mut interval = tokio::time::interval(Duration::from_secs(options.interval_secs));
+
+    loop {
+        interval.tick().await;
+        if !leadership.is_leader() {
+            continue;
+        }
+
+        match purge_terminal_backends(nats, max_age).await {
+            Ok(0) => (),
+            Ok(purged) => tracing::info!(purged, "Purged states of terminated backends."),
+            Err(error) => tracing::warn!(?error, "Error purging states of terminated backends."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plane_core::{messages::agent::BackendState, types::BackendId};
+
+    #[test]
+    fn test_validate() {
+        let options = RetentionOptions {
+            terminal_backend_max_age_secs: 86400,
+            interval_secs: 3600,
+        };
+        assert!(options.validate().is_ok());
+        assert!(RetentionOptions {
+            interval_secs: 0,
+            ..options
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_expired() {
+        let now = Utc::now();
+        let max_age = Duration::from_secs(86400);
+        let backend = BackendId::new("backend1".into());
+        let message = |state| BackendStateMessage::new(state, backend.clone());
+
+        assert!(expired(
+            &message(BackendState::Swept),
+            now - chrono::Duration::days(2),
+            now,
+            max_age
+        ));
+        assert!(!expired(
+            &message(BackendState::Swept),
+            now - chrono::Duration::hours(1),
+            now,
+            max_age
+        ));
+        assert!(!expired(
+            &message(BackendState::Ready),
+            now - chrono::Duration::days(2),
+            now,
+            max_age
+        ));
+
+        // A drone clock far behind the controller's does not expire a
+        // backend which only just terminated.
+        let skewed = BackendStateMessage {
+            time: now - chrono::Duration::days(30),
+            ..message(BackendState::Swept)
+        };
+        assert!(!expired(&skewed, now, now, max_age));
+    }
+}
@@ -2,10 +2,13 @@ use crate::config::ControllerConfig;
 use crate::dns::serve_dns;
 use crate::plan::ControllerPlan;
 use crate::run_scheduler;
+use crate::simulate::{run_record, run_simulate, RecordArgs, SimulateArgs};
 use anyhow::{anyhow, Result};
+use clap::Subcommand;
 use futures::future::try_join_all;
+use plane_core::cli::{init_cli_with_subcommands, CliCommand};
 use plane_core::messages::logging::Component;
-use plane_core::{cli::init_cli, logging::TracingHandle, NeverResult};
+use plane_core::{logging::TracingHandle, streams::init_streams, NeverResult};
 use signal_hook::{
     consts::{SIGINT, SIGTERM},
     iterator::Signals,
@@ -14,29 +17,58 @@ use std::future::Future;
 use std::pin::Pin;
 use std::thread;
 
-async fn controller_main() -> NeverResult {
+/// Tools run by the controller binary in place of the controller.
+#[derive(Subcommand)]
+enum ControllerCommand {
+    /// Replay recorded drone status messages and schedule requests, and
+    /// report where each request would be placed under each placement
+    /// strategy.
+    Simulate(SimulateArgs),
+
+    /// Record the messages `simulate` replays from a live cluster.
+    Record(RecordArgs),
+}
+
+async fn controller_main(config: ControllerConfig) -> NeverResult {
     let mut tracing_handle = TracingHandle::init(Component::Controller)?;
-    let config: ControllerConfig = init_cli()?;
 
     let plan = ControllerPlan::from_controller_config(config).await?;
     let ControllerPlan {
         nats,
         dns_plan,
+        grpc_plan,
         scheduler_plan,
+        streams,
     } = plan;
 
     tracing_handle.attach_nats(nats.clone())?;
 
+    if let Some(streams) = streams {
+        init_streams(&nats, &streams).await?;
+    }
+
     let mut futs: Vec<Pin<Box<dyn Future<Output = NeverResult>>>> = vec![];
 
-    if scheduler_plan.is_some() {
-        futs.push(Box::pin(run_scheduler(nats.clone())))
+    if let Some(scheduler_plan) = scheduler_plan {
+        futs.push(Box::pin(run_scheduler(nats.clone(), scheduler_plan)))
     }
 
     if let Some(dns_plan) = dns_plan {
         futs.push(Box::pin(serve_dns(dns_plan)))
     }
 
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_plan) = grpc_plan {
+        futs.push(Box::pin(crate::grpc::serve_grpc(grpc_plan)))
+    }
+
+    #[cfg(not(feature = "grpc"))]
+    if grpc_plan.is_some() {
+        return Err(anyhow!(
+            "grpc was configured, but the controller was built without the grpc feature."
+        ));
+    }
+
     try_join_all(futs.into_iter()).await?;
     // try_join_all either returns an Err, or Ok() with a list of Never values.
     // Since Never values are not constructable, if we get here, we can assume that
@@ -45,7 +77,21 @@ async fn controller_main() -> NeverResult {
     Err(anyhow!("No event loops selected."))
 }
 
+fn runtime() -> Result<tokio::runtime::Runtime> {
+    Ok(tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?)
+}
+
 pub fn run() -> Result<()> {
+    let config: ControllerConfig = match init_cli_with_subcommands()? {
+        CliCommand::Run(config) => config,
+        CliCommand::Subcommand(ControllerCommand::Simulate(args)) => return run_simulate(args),
+        CliCommand::Subcommand(ControllerCommand::Record(args)) => {
+            return runtime()?.block_on(run_record(args))
+        }
+    };
+
     let mut signals = Signals::new([SIGINT, SIGTERM])?;
 
     thread::spawn(move || {
@@ -55,10 +101,7 @@ pub fn run() -> Result<()> {
         }
     });
 
-    tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()?
-        .block_on(controller_main())?;
+    runtime()?.block_on(controller_main(config))?;
 
     Ok(())
 }
@@ -1,18 +1,201 @@
+use crate::{
+    config::VersionPolicy,
+    lifecycle::DRONE_LOST_TIMEOUT_SECONDS,
+    placement::{DroneCandidate, PlacementStrategy, RandomPlacement},
+};
 use chrono::{DateTime, Duration, Utc};
 use dashmap::DashMap;
 use plane_core::{
-    messages::agent::DroneStatusMessage,
-    types::{ClusterName, DroneId},
+    messages::{
+        agent::{normalize_arch, BackendStateMessage, DroneStatusMessage, ProxyMetrics},
+        scheduler::{
+            AffinityGroup, AffinityMember, ClusterCapacityReport, DrainDrone, ScheduleRequest,
+        },
+    },
+    types::{BackendId, ClusterName, DroneId},
+    version::{is_compatible, PLANE_VERSION},
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt::Display,
+    sync::Arc,
 };
-use rand::{seq::SliceRandom, thread_rng};
-use std::{error::Error, fmt::Display};
 
-#[derive(Default)]
+/// Number of seconds after its last status message that a drone is
+/// considered to have gone away.
+const DRONE_STATUS_TIMEOUT_SECONDS: i64 = 5;
+
+/// Default for how many backends of load having a backend's image cached is
+/// worth to a drone, under image affinity.
+pub const DEFAULT_IMAGE_AFFINITY_WEIGHT: f64 = 4.0;
+
+/// Default for the window over which failed schedule requests are counted.
+pub const DEFAULT_FAILED_SCHEDULE_WINDOW_SECS: u64 = 300;
+
 pub struct Scheduler {
     last_status: DashMap<ClusterName, DashMap<DroneId, DateTime<Utc>>>,
+
+    /// Most recently reported number of running backends for each drone, regardless
+    /// of whether the drone is ready, along with the time it was reported.
+    running_backends: DashMap<ClusterName, DashMap<DroneId, (DateTime<Utc>, u32)>>,
+
+    /// Most backends each drone accepts at once, derived from its most recent
+    /// report of its remaining capacity. Drones without a maximum are absent.
+    max_backends: DashMap<DroneId, u32>,
+
+    /// Drone running each backend which has not terminated, according to the
+    /// backend's most recent state message. Unlike reported counts, this
+    /// reflects backends started since their drone's last status message.
+    /// Each is kept with the time it was recorded, so that the backends of a
+    /// drone which is never seen again can be forgotten.
+    live_backends: DashMap<BackendId, (DroneId, DateTime<Utc>)>,
+
+    /// Timestamps of schedule requests which could not be fulfilled, per
+    /// cluster, within `failed_schedule_window`.
+    failed_schedules: DashMap<ClusterName, VecDeque<DateTime<Utc>>>,
+
+    /// The window over which failed schedule requests are counted.
+    failed_schedule_window: std::time::Duration,
+
+    /// Drone count most recently requested for each cluster.
+    desired_drones: DashMap<ClusterName, u32>,
+
+    /// Chooses among the live drones of a cluster.
+    placement: Arc<dyn PlacementStrategy>,
+
+    /// Whether the most recently reported version of each drone is
+    /// compatible with this controller.
+    compatible: DashMap<DroneId, bool>,
+
+    /// How drones with an incompatible version are treated.
+    version_policy: VersionPolicy,
+
+    /// Times drones were sought for each cluster and every live drone was
+    /// refused for having an incompatible version, within
+    /// `failed_schedule_window`.
+    incompatible_refusals: DashMap<ClusterName, VecDeque<DateTime<Utc>>>,
+
+    /// Images (by tag and by digest) most recently reported cached by each
+    /// drone.
+    cached_images: DashMap<DroneId, HashSet<String>>,
+
+    /// Whether backends are placed on drones which have their image cached,
+    /// when any live drone does.
+    image_affinity: bool,
+
+    /// How many backends of load having an image cached is worth to a drone.
+    image_affinity_weight: f64,
+
+    /// CPU architecture most recently reported by each drone, normalized.
+    arch: DashMap<DroneId, String>,
+
+    /// Cluster each drone most recently reported belonging to.
+    drone_clusters: DashMap<DroneId, ClusterName>,
+
+    /// Traffic through each drone's proxy, as most recently reported.
+    proxy_metrics: DashMap<DroneId, ProxyMetrics>,
+
+    /// Each backend which was placed in an affinity group and has not
+    /// terminated, along with the time it was recorded.
+    affinity_members: DashMap<BackendId, (AffinityMember, DateTime<Utc>)>,
+
+    /// Time of each drone's most recent status message, whether or not it
+    /// was ready, until the drone is lost.
+    last_seen: DashMap<DroneId, DateTime<Utc>>,
+
+    /// Time each drone was last told to drain, until it is told to stop
+    /// draining. Status messages published before the drone received the
+    /// request do not make it schedulable again.
+    drain_requests: DashMap<DroneId, DateTime<Utc>>,
+}
+
+/// What a backend asks of the drone it is placed on, beyond capacity.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Requirements<'a> {
+    /// Image of the backend, for preferring drones which have it cached.
+    pub image: Option<&'a str>,
+
+    /// CPU architecture the backend's image is built for.
+    pub arch: Option<&'a str>,
+
+    /// Group of backends the backend should be placed with.
+    pub affinity_group: Option<&'a AffinityGroup>,
+}
+
+impl<'a> Requirements<'a> {
+    pub fn of(request: &'a ScheduleRequest) -> Self {
+        Requirements {
+            image: Some(&request.executable.image),
+            arch: request.arch.as_deref(),
+            affinity_group: request.affinity_group.as_ref(),
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new(Arc::new(RandomPlacement))
+    }
+}
+
+/// A canonical reference for an image, so that the images drones report and
+/// those backends request can be compared. Images without a tag or digest are
+/// the `latest` tag, and images on Docker Hub are named as Docker reports
+/// them, without the `docker.io/` registry or the `library/` namespace of
+/// official images.
+fn image_reference(image: &str) -> String {
+    let mut image = image;
+    for registry in ["docker.io/", "index.docker.io/"] {
+        if let Some(name) = image.strip_prefix(registry) {
+            image = name;
+            break;
+        }
+    }
+    let image = image.strip_prefix("library/").unwrap_or(image);
+
+    let name = image.rsplit('/').next().unwrap_or(image);
+    if name.contains(':') || name.contains('@') {
+        image.to_string()
+    } else {
+        format!("{}:latest", image)
+    }
+}
+
+/// Narrow candidates down to the drones running backends of an affinity
+/// group, if the group has any. If none of those drones is a candidate, a
+/// strict group cannot be placed, and any candidate will do otherwise.
+fn prefer_affinity(
+    candidates: Vec<DroneCandidate>,
+    drones: &HashSet<DroneId>,
+    group: &AffinityGroup,
+) -> Result<Vec<DroneCandidate>, SchedulerError> {
+    if drones.is_empty() {
+        return Ok(candidates);
+    }
+
+    let (colocated, others): (Vec<DroneCandidate>, Vec<DroneCandidate>) = candidates
+        .into_iter()
+        .partition(|d| drones.contains(&d.drone_id));
+
+    if !colocated.is_empty() {
+        Ok(colocated)
+    } else if group.strict {
+        tracing::warn!(group=%group.name, "No drone of strict affinity group is available.");
+        Err(SchedulerError::NoDroneAvailable)
+    } else {
+        tracing::info!(group=%group.name, "No drone of affinity group is available.");
+        Ok(others)
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+fn threshold_time(current_timestamp: DateTime<Utc>) -> DateTime<Utc> {
+    current_timestamp
+        .checked_sub_signed(Duration::seconds(DRONE_STATUS_TIMEOUT_SECONDS))
+        .unwrap()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum SchedulerError {
     NoDroneAvailable,
 }
@@ -26,12 +209,188 @@ impl Display for SchedulerError {
 impl Error for SchedulerError {}
 
 impl Scheduler {
+    pub fn new(placement: Arc<dyn PlacementStrategy>) -> Self {
+        Scheduler {
+            last_status: DashMap::default(),
+            running_backends: DashMap::default(),
+            max_backends: DashMap::default(),
+            live_backends: DashMap::default(),
+            failed_schedules: DashMap::default(),
+            failed_schedule_window: std::time::Duration::from_secs(
+                DEFAULT_FAILED_SCHEDULE_WINDOW_SECS,
+            ),
+            desired_drones: DashMap::default(),
+            placement,
+            compatible: DashMap::default(),
+            version_policy: VersionPolicy::default(),
+            incompatible_refusals: DashMap::default(),
+            cached_images: DashMap::default(),
+            image_affinity: false,
+            image_affinity_weight: DEFAULT_IMAGE_AFFINITY_WEIGHT,
+            arch: DashMap::default(),
+            drone_clusters: DashMap::default(),
+            proxy_metrics: DashMap::default(),
+            affinity_members: DashMap::default(),
+            last_seen: DashMap::default(),
+            drain_requests: DashMap::default(),
+        }
+    }
+
+    #[must_use]
+    pub fn with_version_policy(mut self, version_policy: VersionPolicy) -> Self {
+        self.version_policy = version_policy;
+        self
+    }
+
+    #[must_use]
+    pub fn with_image_affinity(mut self, image_affinity: bool) -> Self {
+        self.image_affinity = image_affinity;
+        self
+    }
+
+    #[must_use]
+    pub fn with_failed_schedule_window(mut self, window: std::time::Duration) -> Self {
+        self.failed_schedule_window = window;
+        self
+    }
+
+    #[must_use]
+    pub fn with_image_affinity_weight(mut self, image_affinity_weight: f64) -> Self {
+        self.image_affinity_weight = image_affinity_weight;
+        self
+    }
+
+    fn is_compatible(&self, drone_id: &DroneId) -> bool {
+        self.compatible
+            .get(drone_id)
+            .map_or(true, |compatible| *compatible)
+    }
+
+    /// Whether a drone can run images built for `arch`. Drones which have not
+    /// reported their architecture are assumed to.
+    fn runs_arch(&self, drone_id: &DroneId, arch: &str) -> bool {
+        self.arch
+            .get(drone_id)
+            .map_or(true, |drone_arch| *drone_arch == normalize_arch(arch))
+    }
+
+    /// Whether a drone runs as many backends as it accepts, given its load.
+    fn is_full(&self, drone_id: &DroneId, running_backends: Option<u32>) -> bool {
+        self.max_backends
+            .get(drone_id)
+            .map_or(false, |max_backends| {
+                running_backends.unwrap_or_default() >= *max_backends
+            })
+    }
+
+    /// Record a request for a drone to drain, excluding it from scheduling
+    /// right away rather than once its next status message says so.
+    pub fn update_drain(&self, timestamp: DateTime<Utc>, drain: &DrainDrone) {
+        if drain.drain {
+            self.drain_requests.insert(drain.drone.clone(), timestamp);
+            if let Some(cluster_map) = self.last_status.get(&drain.cluster) {
+                cluster_map.remove(&drain.drone);
+            }
+        } else {
+            // The drone becomes schedulable with its next status message.
+            self.drain_requests.remove(&drain.drone);
+        }
+    }
+
+    /// Whether a drone was told to drain too recently for a status message
+    /// to reflect it.
+    fn drain_pending(&self, drone_id: &DroneId, timestamp: DateTime<Utc>) -> bool {
+        match self.drain_requests.get(drone_id) {
+            Some(requested) => {
+                timestamp < *requested + Duration::seconds(DRONE_STATUS_TIMEOUT_SECONDS)
+            }
+            None => false,
+        }
+    }
+
     pub fn update_status(&self, timestamp: DateTime<Utc>, status: &DroneStatusMessage) {
         // Drone status is stored in a hashmap for each cluster. There's no external
         // source-of-truth for cluster existence; we simply create a hashmap for a cluster
         // the first time we see a status message for it.
+        let compatible = is_compatible(PLANE_VERSION, &status.drone_version);
+        if self.compatible.insert(status.drone_id.clone(), compatible) != Some(compatible)
+            && !compatible
+        {
+            tracing::warn!(
+                drone_id=%status.drone_id,
+                drone_version=%status.drone_version,
+                controller_version=%PLANE_VERSION,
+                policy=?self.version_policy,
+                "Drone version is incompatible with controller."
+            );
+        }
+
+        match status.remaining_capacity {
+            Some(remaining_capacity) => {
+                let max_backends =
+                    remaining_capacity.saturating_add(status.running_backends.unwrap_or_default());
+                self.max_backends
+                    .insert(status.drone_id.clone(), max_backends);
+            }
+            None => {
+                self.max_backends.remove(&status.drone_id);
+            }
+        }
+
+        match &status.host_metrics {
+            Some(host_metrics) => {
+                let images = host_metrics
+                    .cached_images
+                    .iter()
+                    .chain(&host_metrics.image_digests)
+                    .map(|image| image_reference(image))
+                    .collect();
+                self.cached_images.insert(status.drone_id.clone(), images);
+            }
+            None => {
+                self.cached_images.remove(&status.drone_id);
+            }
+        }
+
+        match &status.arch {
+            Some(arch) => {
+                self.arch
+                    .insert(status.drone_id.clone(), normalize_arch(arch));
+            }
+            None => {
+                self.arch.remove(&status.drone_id);
+            }
+        }
+
+        match &status.proxy_metrics {
+            Some(proxy_metrics) => {
+                self.proxy_metrics
+                    .insert(status.drone_id.clone(), proxy_metrics.clone());
+            }
+            None => {
+                self.proxy_metrics.remove(&status.drone_id);
+            }
+        }
+
+        self.drone_clusters
+            .insert(status.drone_id.clone(), status.cluster.clone());
+        self.last_seen.insert(status.drone_id.clone(), timestamp);
+
+        // A drone which has no capacity left is treated as not ready.
+        let full = status.remaining_capacity == Some(0);
+        if full {
+            tracing::debug!(drone_id=%status.drone_id, "Drone is at capacity.");
+        }
+
+        // A drone which was told to drain is treated as not ready, even if its
+        // status was published before it received the request.
+        let draining = status.draining || self.drain_pending(&status.drone_id, timestamp);
+        if !draining {
+            self.drain_requests.remove(&status.drone_id);
+        }
+
         let cluster_map = self.last_status.entry(status.cluster.clone()).or_default();
-        if status.ready {
+        if status.ready && !full && !draining {
             // If drone is ready, it gets an entry in cluster hashmap.
             cluster_map.insert(status.drone_id.clone(), timestamp);
         } else {
@@ -39,18 +398,242 @@ impl Scheduler {
             // is not already in this cluster hashmap, this is a no-op.
             cluster_map.remove(&status.drone_id);
         }
+
+        if let Some(running_backends) = status.running_backends {
+            self.running_backends
+                .entry(status.cluster.clone())
+                .or_default()
+                .insert(status.drone_id.clone(), (timestamp, running_backends));
+        }
     }
 
-    pub fn schedule(
+    /// Track which drone a backend runs on, from one of its state messages.
+    /// Messages from drones which do not identify themselves are ignored.
+    pub fn update_backend_state(&self, message: &BackendStateMessage, timestamp: DateTime<Utc>) {
+        let drone = match &message.drone {
+            Some(drone) => drone,
+            None => return,
+        };
+
+        if message.state.terminal() {
+            self.live_backends.remove(&message.backend);
+            self.affinity_members.remove(&message.backend);
+        } else {
+            self.live_backends
+                .insert(message.backend.clone(), (drone.clone(), timestamp));
+        }
+    }
+
+    /// Record that a backend of an affinity group was placed on a drone, so
+    /// that later backends of the group are placed with it.
+    pub fn record_affinity_member(&self, member: AffinityMember, timestamp: DateTime<Utc>) {
+        self.affinity_members
+            .insert(member.backend.clone(), (member, timestamp));
+    }
+
+    /// Drones running live backends of an affinity group.
+    fn affinity_drones(&self, cluster: &ClusterName, group: &AffinityGroup) -> HashSet<DroneId> {
+        self.affinity_members
+            .iter()
+            .filter(|entry| {
+                let (member, _) = entry.value();
+                member.cluster == *cluster && member.group == group.name
+            })
+            .map(|entry| entry.value().0.drone.clone())
+            .collect()
+    }
+
+    /// Forget drones which have not sent a status message for long enough to
+    /// be considered lost, along with their versions, the images they
+    /// reported, and the live
+    /// backends and affinity group members placed on them, since those
+    /// backends are gone too. Backends
+    /// are kept for as long after being recorded if their drone has not been
+    /// seen at all, e.g. when they are replayed before the drone's first
+    /// status message.
+    pub fn prune_lost_drones(&self, timestamp: DateTime<Utc>) {
+        let threshold = timestamp - Duration::seconds(DRONE_LOST_TIMEOUT_SECONDS);
+        // Drones are only unseen once the lost ones are forgotten, so which
+        // ones were lost is decided before forgetting them.
+        let lost: HashSet<DroneId> = self
+            .last_seen
+            .iter()
+            .filter(|entry| *entry.value() < threshold)
+            .map(|entry| entry.key().clone())
+            .collect();
+        let on_live_drone = |drone: &DroneId, recorded: &DateTime<Utc>| {
+            !lost.contains(drone) && (self.last_seen.contains_key(drone) || *recorded >= threshold)
+        };
+
+        self.last_seen.retain(|drone, _| !lost.contains(drone));
+        self.compatible
+            .retain(|drone, _| self.last_seen.contains_key(drone));
+        self.cached_images
+            .retain(|drone, _| self.last_seen.contains_key(drone));
+        self.affinity_members
+            .retain(|_, (member, recorded)| on_live_drone(&member.drone, recorded));
+        self.live_backends
+            .retain(|_, (drone, recorded)| on_live_drone(drone, recorded));
+    }
+
+    /// The cluster a drone belongs to, if it has sent a status message since
+    /// the controller started.
+    pub fn drone_cluster(&self, drone_id: &DroneId) -> Option<ClusterName> {
+        self.drone_clusters
+            .get(drone_id)
+            .map(|cluster| cluster.clone())
+    }
+
+    /// Whether a backend which has not terminated runs in a cluster. A
+    /// backend on a drone which has not yet sent a status message is
+    /// assumed to, since its cluster is not known.
+    pub fn is_live(&self, cluster: &ClusterName, backend: &BackendId) -> bool {
+        self.live_backends.get(backend).map_or(false, |entry| {
+            self.drone_cluster(&entry.value().0)
+                .map_or(true, |drone_cluster| drone_cluster == *cluster)
+        })
+    }
+
+    /// Number of backends which have not terminated on each drone.
+    fn live_backend_counts(&self) -> HashMap<DroneId, u32> {
+        let mut counts: HashMap<DroneId, u32> = HashMap::new();
+        for entry in self.live_backends.iter() {
+            *counts.entry(entry.value().0.clone()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Record that a schedule request for the given cluster could not be fulfilled.
+    pub fn record_failed_schedule(&self, cluster: &ClusterName, timestamp: DateTime<Utc>) {
+        let mut failures = self.failed_schedules.entry(cluster.clone()).or_default();
+        failures.push_back(timestamp);
+        self.prune_failed_schedules(&mut failures, timestamp);
+    }
+
+    /// Forget failed schedule requests which are older than the window, as
+    /// of `current_timestamp`.
+    fn prune_failed_schedules(
+        &self,
+        failures: &mut VecDeque<DateTime<Utc>>,
+        current_timestamp: DateTime<Utc>,
+    ) {
+        let window_start = current_timestamp
+            - Duration::from_std(self.failed_schedule_window).unwrap_or_else(|_| Duration::zero());
+        while let Some(time) = failures.front() {
+            if *time >= window_start {
+                break;
+            }
+            failures.pop_front();
+        }
+    }
+
+    pub fn set_desired_drones(&self, cluster: &ClusterName, count: u32) {
+        self.desired_drones.insert(cluster.clone(), count);
+    }
+
+    /// Returns every cluster this scheduler has seen a drone status message for.
+    pub fn clusters(&self) -> Vec<ClusterName> {
+        self.last_status.iter().map(|d| d.key().clone()).collect()
+    }
+
+    /// Number of live, ready drones in a cluster which backends may be
+    /// scheduled to, and the number of backends its live drones most recently
+    /// reported running.
+    pub fn cluster_load(
         &self,
         cluster: &ClusterName,
         current_timestamp: DateTime<Utc>,
-    ) -> Result<DroneId, SchedulerError> {
-        // TODO: this is a dumb placeholder scheduler.
+    ) -> (u32, u32) {
+        let threshold_time = threshold_time(current_timestamp);
+
+        let ready_drones = self
+            .last_status
+            .get(cluster)
+            .map(|drones| {
+                drones
+                    .iter()
+                    .filter(|d| d.value() > &threshold_time)
+                    .filter(|d| {
+                        self.version_policy == VersionPolicy::Deprioritize
+                            || self.is_compatible(d.key())
+                    })
+                    .count()
+            })
+            .unwrap_or_default();
+
+        let live_backends = self.live_backend_counts();
+        let running_backends: u32 = self
+            .running_backends
+            .get(cluster)
+            .map(|drones| {
+                drones
+                    .iter()
+                    .filter(|d| d.value().0 > threshold_time)
+                    .map(|d| {
+                        let live = live_backends.get(d.key()).copied().unwrap_or_default();
+                        d.value().1.max(live)
+                    })
+                    .sum()
+            })
+            .unwrap_or_default();
 
-        let threshold_time = current_timestamp
-            .checked_sub_signed(Duration::seconds(5))
-            .unwrap();
+        (ready_drones as u32, running_backends)
+    }
+
+    pub fn capacity_report(
+        &self,
+        cluster: &ClusterName,
+        current_timestamp: DateTime<Utc>,
+    ) -> ClusterCapacityReport {
+        let (ready_drones, running_backends) = self.cluster_load(cluster, current_timestamp);
+
+        let failed_schedules = if let Some(mut failures) = self.failed_schedules.get_mut(cluster) {
+            self.prune_failed_schedules(&mut failures, current_timestamp);
+            failures.len()
+        } else {
+            0
+        };
+        let incompatible_refusals =
+            if let Some(mut refusals) = self.incompatible_refusals.get_mut(cluster) {
+                self.prune_failed_schedules(&mut refusals, current_timestamp);
+                refusals.len()
+            } else {
+                0
+            };
+
+        let threshold_time = threshold_time(current_timestamp);
+        let incompatible_drones = self
+            .last_status
+            .get(cluster)
+            .map(|drones| {
+                drones
+                    .iter()
+                    .filter(|d| d.value() > &threshold_time && !self.is_compatible(d.key()))
+                    .count()
+            })
+            .unwrap_or_default();
+
+        ClusterCapacityReport {
+            cluster: cluster.clone(),
+            ready_drones,
+            incompatible_drones: incompatible_drones as u32,
+            running_backends,
+            failed_schedules: failed_schedules as u32,
+            incompatible_refusals: incompatible_refusals as u32,
+            failed_schedules_window: self.failed_schedule_window,
+            desired_drones: self.desired_drones.get(cluster).map(|d| *d),
+            time: current_timestamp,
+        }
+    }
+
+    /// Live drones of a cluster, along with their most recently reported load.
+    fn candidates(
+        &self,
+        cluster: &ClusterName,
+        requirements: Requirements,
+        current_timestamp: DateTime<Utc>,
+    ) -> Result<Vec<DroneCandidate>, SchedulerError> {
+        let threshold_time = threshold_time(current_timestamp);
 
         let cluster_drones = if let Some(cluster_drones) = self.last_status.get(cluster) {
             cluster_drones
@@ -62,29 +645,192 @@ impl Scheduler {
             return Err(SchedulerError::NoDroneAvailable);
         };
 
-        let drone_ids: Vec<DroneId> = cluster_drones
+        let running_backends = self.running_backends.get(cluster);
+        let live_backends = self.live_backend_counts();
+        let (compatible, incompatible): (Vec<DroneCandidate>, Vec<DroneCandidate>) = cluster_drones
             .iter()
             .filter(|d| d.value() > &threshold_time)
-            .map(|d| d.key().clone())
-            .collect();
+            .map(|d| DroneCandidate {
+                drone_id: d.key().clone(),
+                // A drone's report may predate backends it has since started.
+                running_backends: running_backends
+                    .as_ref()
+                    .and_then(|r| r.get(d.key()).map(|r| r.value().1))
+                    .max(live_backends.get(d.key()).copied()),
+                proxy_metrics: self.proxy_metrics.get(d.key()).map(|m| m.value().clone()),
+            })
+            // A drone may have filled up since its last status message.
+            .filter(|d| !self.is_full(&d.drone_id, d.running_backends))
+            .filter(|d| {
+                requirements
+                    .arch
+                    .map_or(true, |arch| self.runs_arch(&d.drone_id, arch))
+            })
+            .partition(|d| self.is_compatible(&d.drone_id));
 
         tracing::info!(
             total_num_candidates=%cluster_drones.len(),
-            num_live_candidates=%drone_ids.len(),
+            num_live_candidates=%compatible.len(),
+            num_incompatible_candidates=%incompatible.len(),
             %cluster,
             "Found cluster state to schedule."
         );
 
-        drone_ids
-            .choose(&mut thread_rng())
-            .cloned()
+        // Drones with an incompatible version are only used, if at all, when
+        // no compatible drone is live.
+        let candidates = match self.version_policy {
+            VersionPolicy::Deprioritize if compatible.is_empty() => incompatible,
+            VersionPolicy::Refuse if compatible.is_empty() && !incompatible.is_empty() => {
+                tracing::warn!(
+                    %cluster,
+                    num_incompatible_candidates=%incompatible.len(),
+                    "Refusing to schedule to drones with an incompatible version."
+                );
+                let mut refusals = self
+                    .incompatible_refusals
+                    .entry(cluster.clone())
+                    .or_default();
+                refusals.push_back(current_timestamp);
+                self.prune_failed_schedules(&mut refusals, current_timestamp);
+                compatible
+            }
+            _ => compatible,
+        };
+
+        if candidates.is_empty() {
+            return Err(SchedulerError::NoDroneAvailable);
+        }
+
+        let candidates = match requirements.affinity_group {
+            Some(group) => {
+                let drones = self.affinity_drones(cluster, group);
+                prefer_affinity(candidates, &drones, group)?
+            }
+            None => candidates,
+        };
+
+        Ok(match requirements.image {
+            Some(image) if self.image_affinity => self.prefer_cached(candidates, image),
+            _ => candidates,
+        })
+    }
+
+    /// Prefer the drones which have an image cached, if any of them do. A
+    /// drone without the image remains a candidate only if its load is less
+    /// than that of every drone with the image by at least the affinity
+    /// weight, so that popular images do not pile onto the drones which have
+    /// them.
+    fn prefer_cached(&self, candidates: Vec<DroneCandidate>, image: &str) -> Vec<DroneCandidate> {
+        let reference = image_reference(image);
+        let (mut cached, uncached): (Vec<DroneCandidate>, Vec<DroneCandidate>) =
+            candidates.into_iter().partition(|d| {
+                self.cached_images
+                    .get(&d.drone_id)
+                    .map_or(false, |images| images.contains(&reference))
+            });
+
+        let least_cached_load = match cached.iter().map(DroneCandidate::load).reduce(f64::min) {
+            Some(load) => load,
+            None => return uncached,
+        };
+        let num_cached_candidates = cached.len();
+        cached.extend(
+            uncached
+                .into_iter()
+                .filter(|d| d.load() + self.image_affinity_weight <= least_cached_load),
+        );
+
+        tracing::debug!(
+            num_cached_candidates,
+            num_candidates=%cached.len(),
+            %image,
+            "Preferring drones with image cached."
+        );
+
+        cached
+    }
+
+    /// Choose a drone for a backend which meets its requirements. If an image
+    /// is given and image affinity is enabled, drones which have the image
+    /// cached are preferred.
+    pub fn schedule(
+        &self,
+        cluster: &ClusterName,
+        requirements: Requirements,
+        current_timestamp: DateTime<Utc>,
+    ) -> Result<DroneId, SchedulerError> {
+        let candidates = self.candidates(cluster, requirements, current_timestamp)?;
+
+        self.placement
+            .place(cluster, &candidates)
             .ok_or(SchedulerError::NoDroneAvailable)
     }
+
+    /// Choose drones for `count` backends at once, spreading them across
+    /// drones: no drone receives a second backend from the batch until every
+    /// live drone has received one. Each backend placed counts towards its
+    /// drone's load for the rest of the batch. Backends of an affinity group
+    /// are placed together instead.
+    pub fn schedule_batch(
+        &self,
+        cluster: &ClusterName,
+        requirements: Requirements,
+        current_timestamp: DateTime<Utc>,
+        count: u32,
+    ) -> Vec<Result<DroneId, SchedulerError>> {
+        let mut candidates = match self.candidates(cluster, requirements, current_timestamp) {
+            Ok(candidates) => candidates,
+            Err(error) => return (0..count).map(|_| Err(error.clone())).collect(),
+        };
+        let mut used: Vec<DroneId> = Vec::new();
+        let mut placed: HashSet<DroneId> = HashSet::new();
+
+        (0..count)
+            .map(|_| {
+                candidates.retain(|d| !self.is_full(&d.drone_id, d.running_backends));
+                if candidates.is_empty() {
+                    return Err(SchedulerError::NoDroneAvailable);
+                }
+
+                let mut unused: Vec<DroneCandidate> = candidates
+                    .iter()
+                    .filter(|d| !used.contains(&d.drone_id))
+                    .cloned()
+                    .collect();
+                if unused.is_empty() {
+                    used.clear();
+                    unused = candidates.clone();
+                }
+
+                // Backends of an affinity group are placed together instead
+                // of being spread.
+                if let Some(group) = requirements.affinity_group {
+                    unused = prefer_affinity(candidates.clone(), &placed, group)?;
+                }
+
+                let drone_id = self
+                    .placement
+                    .place(cluster, &unused)
+                    .ok_or(SchedulerError::NoDroneAvailable)?;
+
+                if let Some(candidate) = candidates.iter_mut().find(|d| d.drone_id == drone_id) {
+                    candidate.running_backends =
+                        Some(candidate.running_backends.unwrap_or_default() + 1);
+                }
+                used.push(drone_id.clone());
+                placed.insert(drone_id.clone());
+
+                Ok(drone_id)
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::placement::LeastLoadedPlacement;
+    use plane_core::messages::agent::{BackendState, HostMetrics};
     const PLANE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
     fn date(date: &str) -> DateTime<Utc> {
@@ -97,7 +843,11 @@ mod tests {
         let timestamp = date("2020-01-01T05:00:00+00:00");
         assert_eq!(
             Err(SchedulerError::NoDroneAvailable),
-            scheduler.schedule(&ClusterName::new("mycluster.test"), timestamp)
+            scheduler.schedule(
+                &ClusterName::new("mycluster.test"),
+                Requirements::default(),
+                timestamp
+            )
         );
     }
 
@@ -114,13 +864,24 @@ mod tests {
                 drone_version: PLANE_VERSION.to_string(),
                 ready: true,
                 running_backends: None,
+                remaining_capacity: None,
+                host_metrics: None,
+                sealing_key: None,
+                arch: None,
+                proxy_metrics: None,
+                draining: false,
             },
         );
 
+        assert_eq!(
+            Some(ClusterName::new("mycluster.test")),
+            scheduler.drone_cluster(&drone_id)
+        );
         assert_eq!(
             Ok(drone_id),
             scheduler.schedule(
                 &ClusterName::new("mycluster.test"),
+                Requirements::default(),
                 date("2020-01-01T05:00:03+00:00")
             )
         );
@@ -138,6 +899,12 @@ mod tests {
                 drone_version: PLANE_VERSION.to_string(),
                 ready: true,
                 running_backends: None,
+                remaining_capacity: None,
+                host_metrics: None,
+                sealing_key: None,
+                arch: None,
+                proxy_metrics: None,
+                draining: false,
             },
         );
 
@@ -145,6 +912,7 @@ mod tests {
             Err(SchedulerError::NoDroneAvailable),
             scheduler.schedule(
                 &ClusterName::new("mycluster2.test"),
+                Requirements::default(),
                 date("2020-01-01T05:00:03+00:00")
             )
         );
@@ -162,6 +930,12 @@ mod tests {
                 drone_version: PLANE_VERSION.to_string(),
                 ready: true,
                 running_backends: None,
+                remaining_capacity: None,
+                host_metrics: None,
+                sealing_key: None,
+                arch: None,
+                proxy_metrics: None,
+                draining: false,
             },
         );
 
@@ -169,8 +943,772 @@ mod tests {
             Err(SchedulerError::NoDroneAvailable),
             scheduler.schedule(
                 &ClusterName::new("mycluster.test"),
+                Requirements::default(),
                 date("2020-01-01T05:00:09+00:00")
             )
         );
     }
+
+    #[test]
+    fn test_capacity_report() {
+        let scheduler = Scheduler::default();
+        let cluster = ClusterName::new("mycluster.test");
+
+        scheduler.update_status(
+            date("2020-01-01T05:00:00+00:00"),
+            &DroneStatusMessage {
+                drone_id: DroneId::new_random(),
+                cluster: cluster.clone(),
+                drone_version: PLANE_VERSION.to_string(),
+                ready: true,
+                running_backends: Some(3),
+                remaining_capacity: None,
+                host_metrics: None,
+                sealing_key: None,
+                arch: None,
+                proxy_metrics: None,
+                draining: false,
+            },
+        );
+        scheduler.update_status(
+            date("2020-01-01T05:00:01+00:00"),
+            &DroneStatusMessage {
+                drone_id: DroneId::new_random(),
+                cluster: cluster.clone(),
+                drone_version: PLANE_VERSION.to_string(),
+                ready: false,
+                running_backends: Some(2),
+                remaining_capacity: None,
+                host_metrics: None,
+                sealing_key: None,
+                arch: None,
+                proxy_metrics: None,
+                draining: false,
+            },
+        );
+        scheduler.record_failed_schedule(&cluster, date("2020-01-01T04:50:00+00:00"));
+        scheduler.record_failed_schedule(&cluster, date("2020-01-01T04:59:00+00:00"));
+        scheduler.set_desired_drones(&cluster, 4);
+
+        let report = scheduler.capacity_report(&cluster, date("2020-01-01T05:00:03+00:00"));
+
+        assert_eq!(1, report.ready_drones);
+        assert_eq!(5, report.running_backends);
+        assert_eq!(1, report.failed_schedules);
+        assert_eq!(Some(4), report.desired_drones);
+    }
+
+    #[test]
+    fn test_failed_schedules_pruned_on_insert() {
+        let scheduler =
+            Scheduler::default().with_failed_schedule_window(std::time::Duration::from_secs(60));
+        let cluster = ClusterName::new("mycluster.test");
+
+        // Without capacity reports, e.g. when no autoscaler is configured,
+        // failures outside the window are still forgotten.
+        for minute in 0..10 {
+            scheduler.record_failed_schedule(
+                &cluster,
+                date(&format!("2020-01-01T05:{:02}:00+00:00", minute)),
+            );
+        }
+        assert_eq!(2, scheduler.failed_schedules.get(&cluster).unwrap().len());
+    }
+
+    #[test]
+    fn test_schedule_batch_spreads_drones() {
+        let scheduler = Scheduler::default();
+        let cluster = ClusterName::new("mycluster.test");
+        let drones = vec![DroneId::new_random(), DroneId::new_random()];
+
+        for drone_id in &drones {
+            scheduler.update_status(
+                date("2020-01-01T05:00:00+00:00"),
+                &DroneStatusMessage {
+                    drone_id: drone_id.clone(),
+                    cluster: cluster.clone(),
+                    drone_version: PLANE_VERSION.to_string(),
+                    ready: true,
+                    running_backends: None,
+                    remaining_capacity: None,
+                    host_metrics: None,
+                    sealing_key: None,
+                    arch: None,
+                    proxy_metrics: None,
+                    draining: false,
+                },
+            );
+        }
+
+        let placed: Vec<DroneId> = scheduler
+            .schedule_batch(
+                &cluster,
+                Requirements::default(),
+                date("2020-01-01T05:00:03+00:00"),
+                4,
+            )
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(4, placed.len());
+        assert_ne!(placed[0], placed[1]);
+        assert_ne!(placed[2], placed[3]);
+        for drone_id in &drones {
+            assert_eq!(2, placed.iter().filter(|d| *d == drone_id).count());
+        }
+
+        assert_eq!(
+            vec![Err(SchedulerError::NoDroneAvailable); 2],
+            scheduler.schedule_batch(
+                &ClusterName::new("othercluster.test"),
+                Requirements::default(),
+                date("2020-01-01T05:00:03+00:00"),
+                2
+            )
+        );
+    }
+
+    fn status_with_version(drone_id: &DroneId, drone_version: &str) -> DroneStatusMessage {
+        DroneStatusMessage {
+            drone_id: drone_id.clone(),
+            cluster: ClusterName::new("mycluster.test"),
+            drone_version: drone_version.to_string(),
+            ready: true,
+            running_backends: None,
+            remaining_capacity: None,
+            host_metrics: None,
+            sealing_key: None,
+            arch: None,
+            proxy_metrics: None,
+            draining: false,
+        }
+    }
+
+    #[test]
+    fn test_incompatible_drone_refused() {
+        let scheduler = Scheduler::default();
+        let cluster = ClusterName::new("mycluster.test");
+        let drone_id = DroneId::new_random();
+
+        scheduler.update_status(
+            date("2020-01-01T05:00:00+00:00"),
+            &status_with_version(&drone_id, "9999.0.0"),
+        );
+
+        assert_eq!(
+            Err(SchedulerError::NoDroneAvailable),
+            scheduler.schedule(
+                &cluster,
+                Requirements::default(),
+                date("2020-01-01T05:00:03+00:00")
+            )
+        );
+
+        let report = scheduler.capacity_report(&cluster, date("2020-01-01T05:00:03+00:00"));
+        assert_eq!(0, report.ready_drones);
+        assert_eq!(1, report.incompatible_drones);
+        assert_eq!(1, report.incompatible_refusals);
+
+        // Once the drone is upgraded, it can be scheduled to.
+        scheduler.update_status(
+            date("2020-01-01T05:00:01+00:00"),
+            &status_with_version(&drone_id, PLANE_VERSION),
+        );
+        assert_eq!(
+            Ok(drone_id),
+            scheduler.schedule(
+                &cluster,
+                Requirements::default(),
+                date("2020-01-01T05:00:03+00:00")
+            )
+        );
+    }
+
+    #[test]
+    fn test_versions_forgotten_with_lost_drone() {
+        let scheduler = Scheduler::default();
+        let drone_id = DroneId::new_random();
+
+        scheduler.update_status(
+            date("2020-01-01T05:00:00+00:00"),
+            &status_with_version(&drone_id, "9999.0.0"),
+        );
+        assert!(!scheduler.is_compatible(&drone_id));
+
+        scheduler.prune_lost_drones(date("2020-01-01T06:00:00+00:00"));
+        assert!(scheduler.compatible.is_empty());
+    }
+
+    #[test]
+    fn test_incompatible_drone_deprioritized() {
+        let scheduler = Scheduler::default().with_version_policy(VersionPolicy::Deprioritize);
+        let cluster = ClusterName::new("mycluster.test");
+        let old_drone = DroneId::new_random();
+        let new_drone = DroneId::new_random();
+
+        scheduler.update_status(
+            date("2020-01-01T05:00:00+00:00"),
+            &status_with_version(&old_drone, "9999.0.0"),
+        );
+
+        // With no compatible drone live, the incompatible drone is used.
+        assert_eq!(
+            Ok(old_drone.clone()),
+            scheduler.schedule(
+                &cluster,
+                Requirements::default(),
+                date("2020-01-01T05:00:03+00:00")
+            )
+        );
+
+        scheduler.update_status(
+            date("2020-01-01T05:00:00+00:00"),
+            &status_with_version(&new_drone, PLANE_VERSION),
+        );
+
+        for _ in 0..10 {
+            assert_eq!(
+                Ok(new_drone.clone()),
+                scheduler.schedule(
+                    &cluster,
+                    Requirements::default(),
+                    date("2020-01-01T05:00:03+00:00")
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_live_backends_counted() {
+        let scheduler = Scheduler::new(Arc::new(LeastLoadedPlacement));
+        let cluster = ClusterName::new("mycluster.test");
+        let busy_drone = DroneId::new_random();
+        let idle_drone = DroneId::new_random();
+
+        for drone_id in [&busy_drone, &idle_drone] {
+            let mut status = status_with_version(drone_id, PLANE_VERSION);
+            status.running_backends = Some(0);
+            scheduler.update_status(date("2020-01-01T05:00:00+00:00"), &status);
+        }
+
+        // Backends started since the drones last reported their load.
+        let backends: Vec<BackendId> = (0..2).map(|_| BackendId::new_random()).collect();
+        for backend in &backends {
+            scheduler.update_backend_state(
+                &BackendStateMessage::new(BackendState::Loading, backend.clone())
+                    .with_drone(busy_drone.clone()),
+                date("2020-01-01T05:00:01+00:00"),
+            );
+        }
+
+        assert_eq!(
+            Ok(idle_drone.clone()),
+            scheduler.schedule(
+                &cluster,
+                Requirements::default(),
+                date("2020-01-01T05:00:03+00:00")
+            )
+        );
+        assert_eq!(
+            (2, 2),
+            scheduler.cluster_load(&cluster, date("2020-01-01T05:00:03+00:00"))
+        );
+
+        // Terminated backends no longer count towards their drone's load.
+        for backend in &backends {
+            scheduler.update_backend_state(
+                &BackendStateMessage::new(BackendState::Terminated, backend.clone())
+                    .with_drone(busy_drone.clone()),
+                date("2020-01-01T05:00:02+00:00"),
+            );
+        }
+        assert_eq!(
+            (2, 0),
+            scheduler.cluster_load(&cluster, date("2020-01-01T05:00:03+00:00"))
+        );
+    }
+
+    #[test]
+    fn test_is_live() {
+        let scheduler = Scheduler::new(Arc::new(LeastLoadedPlacement));
+        let cluster = ClusterName::new("mycluster.test");
+        let other_cluster = ClusterName::new("othercluster.test");
+        let drone_id = DroneId::new_random();
+        let backend = BackendId::new("game-lobby-42".into());
+
+        scheduler.update_status(
+            date("2020-01-01T05:00:00+00:00"),
+            &status_with_version(&drone_id, PLANE_VERSION),
+        );
+        assert!(!scheduler.is_live(&cluster, &backend));
+
+        scheduler.update_backend_state(
+            &BackendStateMessage::new(BackendState::Loading, backend.clone())
+                .with_drone(drone_id.clone()),
+            date("2020-01-01T05:00:01+00:00"),
+        );
+        assert!(scheduler.is_live(&cluster, &backend));
+        assert!(!scheduler.is_live(&other_cluster, &backend));
+
+        scheduler.update_backend_state(
+            &BackendStateMessage::new(BackendState::ErrorLoading, backend.clone())
+                .with_drone(drone_id.clone()),
+            date("2020-01-01T05:00:02+00:00"),
+        );
+        assert!(!scheduler.is_live(&cluster, &backend));
+    }
+
+    #[test]
+    fn test_live_backends_forgotten_with_lost_drone() {
+        let scheduler = Scheduler::new(Arc::new(LeastLoadedPlacement));
+        let cluster = ClusterName::new("mycluster.test");
+        let lost_drone = DroneId::new_random();
+        let unseen_drone = DroneId::new_random();
+
+        let mut status = status_with_version(&lost_drone, PLANE_VERSION);
+        status.running_backends = Some(0);
+        scheduler.update_status(date("2020-01-01T05:00:00+00:00"), &status);
+
+        // Backends of a drone which has not reported since the controller
+        // started, e.g. replayed from JetStream.
+        for drone_id in [&lost_drone, &unseen_drone] {
+            scheduler.update_backend_state(
+                &BackendStateMessage::new(BackendState::Ready, BackendId::new_random())
+                    .with_drone(drone_id.clone()),
+                date("2020-01-01T05:00:10+00:00"),
+            );
+        }
+        assert_eq!(2, scheduler.live_backends.len());
+
+        // The lost drone's backends are forgotten with it, while the unseen
+        // drone has as long after the backend was recorded to report.
+        scheduler.prune_lost_drones(date("2020-01-01T05:00:20+00:00"));
+        assert_eq!(1, scheduler.live_backends.len());
+        scheduler.prune_lost_drones(date("2020-01-01T05:00:30+00:00"));
+        assert!(scheduler.live_backends.is_empty());
+        assert_eq!(
+            (0, 0),
+            scheduler.cluster_load(&cluster, date("2020-01-01T05:00:30+00:00"))
+        );
+    }
+
+    #[test]
+    fn test_affinity_group() {
+        let scheduler = Scheduler::new(Arc::new(LeastLoadedPlacement));
+        let cluster = ClusterName::new("mycluster.test");
+        let group_drone = DroneId::new_random();
+        let idle_drone = DroneId::new_random();
+        let time = date("2020-01-01T05:00:00+00:00");
+
+        let mut status = status_with_version(&group_drone, PLANE_VERSION);
+        status.running_backends = Some(3);
+        scheduler.update_status(time, &status);
+        let mut status = status_with_version(&idle_drone, PLANE_VERSION);
+        status.running_backends = Some(0);
+        scheduler.update_status(time, &status);
+
+        let preferred = AffinityGroup {
+            name: "game1".into(),
+            strict: false,
+        };
+        let requirements = Requirements {
+            affinity_group: Some(&preferred),
+            ..Requirements::default()
+        };
+
+        // The first backend of a group is placed as usual.
+        assert_eq!(
+            Ok(idle_drone.clone()),
+            scheduler.schedule(&cluster, requirements, time)
+        );
+
+        // Later ones follow it, even to a busier drone.
+        let backend = BackendId::new_random();
+        scheduler.record_affinity_member(
+            affinity_member(&cluster, &preferred, &backend, &group_drone),
+            time,
+        );
+        assert_eq!(
+            Ok(group_drone.clone()),
+            scheduler.schedule(&cluster, requirements, time)
+        );
+        assert_eq!(
+            vec![Ok(group_drone.clone()), Ok(group_drone.clone())],
+            scheduler.schedule_batch(&cluster, requirements, time, 2)
+        );
+
+        // If the group's drone is unavailable, a preferred group is placed
+        // elsewhere, and a strict one is not placed.
+        let mut status = status_with_version(&group_drone, PLANE_VERSION);
+        status.ready = false;
+        scheduler.update_status(time, &status);
+        assert_eq!(
+            Ok(idle_drone.clone()),
+            scheduler.schedule(&cluster, requirements, time)
+        );
+        let strict = AffinityGroup {
+            strict: true,
+            ..preferred.clone()
+        };
+        let strict_requirements = Requirements {
+            affinity_group: Some(&strict),
+            ..Requirements::default()
+        };
+        assert_eq!(
+            Err(SchedulerError::NoDroneAvailable),
+            scheduler.schedule(&cluster, strict_requirements, time)
+        );
+
+        // Once the group's backends terminate, the group can be placed anew.
+        scheduler.update_backend_state(
+            &BackendStateMessage::new(BackendState::Terminated, backend)
+                .with_drone(group_drone.clone()),
+            time,
+        );
+        assert_eq!(
+            Ok(idle_drone),
+            scheduler.schedule(&cluster, strict_requirements, time)
+        );
+    }
+
+    fn affinity_member(
+        cluster: &ClusterName,
+        group: &AffinityGroup,
+        backend: &BackendId,
+        drone: &DroneId,
+    ) -> AffinityMember {
+        AffinityMember {
+            cluster: cluster.clone(),
+            group: group.name.clone(),
+            backend: backend.clone(),
+            drone: drone.clone(),
+            time: date("2020-01-01T05:00:00+00:00"),
+        }
+    }
+
+    #[test]
+    fn test_affinity_group_forgotten_with_lost_drone() {
+        let scheduler = Scheduler::new(Arc::new(LeastLoadedPlacement));
+        let cluster = ClusterName::new("mycluster.test");
+        let lost_drone = DroneId::new_random();
+        let live_drone = DroneId::new_random();
+        let unseen_drone = DroneId::new_random();
+        let group = AffinityGroup {
+            name: "game1".into(),
+            strict: true,
+        };
+        let requirements = Requirements {
+            affinity_group: Some(&group),
+            ..Requirements::default()
+        };
+
+        let time = date("2020-01-01T05:00:00+00:00");
+        scheduler.update_status(time, &status_with_version(&lost_drone, PLANE_VERSION));
+        scheduler.record_affinity_member(
+            affinity_member(&cluster, &group, &BackendId::new_random(), &lost_drone),
+            time,
+        );
+
+        // The group's drone stops reporting, and another drone joins.
+        let time = date("2020-01-01T05:00:20+00:00");
+        scheduler.update_status(time, &status_with_version(&live_drone, PLANE_VERSION));
+        assert_eq!(
+            Err(SchedulerError::NoDroneAvailable),
+            scheduler.schedule(&cluster, requirements, time)
+        );
+
+        // Members placed on a drone which has not been seen yet, e.g. replayed
+        // at startup, are given time for it to report.
+        scheduler.record_affinity_member(
+            affinity_member(&cluster, &group, &BackendId::new_random(), &unseen_drone),
+            time,
+        );
+        scheduler.prune_lost_drones(time);
+        assert_eq!(
+            HashSet::from([unseen_drone.clone()]),
+            scheduler.affinity_drones(&cluster, &group)
+        );
+
+        let time = date("2020-01-01T05:00:40+00:00");
+        scheduler.update_status(time, &status_with_version(&live_drone, PLANE_VERSION));
+        scheduler.prune_lost_drones(time);
+        assert_eq!(
+            Ok(live_drone),
+            scheduler.schedule(&cluster, requirements, time)
+        );
+    }
+
+    #[test]
+    fn test_full_drone_not_ready() {
+        let scheduler = Scheduler::default();
+        let cluster = ClusterName::new("mycluster.test");
+        let full_drone = DroneId::new_random();
+        let drone_id = DroneId::new_random();
+
+        let mut status = status_with_version(&full_drone, PLANE_VERSION);
+        status.running_backends = Some(2);
+        status.remaining_capacity = Some(0);
+        scheduler.update_status(date("2020-01-01T05:00:00+00:00"), &status);
+
+        assert_eq!(
+            Err(SchedulerError::NoDroneAvailable),
+            scheduler.schedule(
+                &cluster,
+                Requirements::default(),
+                date("2020-01-01T05:00:03+00:00")
+            )
+        );
+        assert_eq!(
+            (0, 2),
+            scheduler.cluster_load(&cluster, date("2020-01-01T05:00:03+00:00"))
+        );
+
+        let mut status = status_with_version(&drone_id, PLANE_VERSION);
+        status.running_backends = Some(0);
+        status.remaining_capacity = Some(2);
+        scheduler.update_status(date("2020-01-01T05:00:00+00:00"), &status);
+
+        // Backends placed in a batch count towards the drone's capacity.
+        assert_eq!(
+            vec![
+                Ok(drone_id.clone()),
+                Ok(drone_id.clone()),
+                Err(SchedulerError::NoDroneAvailable)
+            ],
+            scheduler.schedule_batch(
+                &cluster,
+                Requirements::default(),
+                date("2020-01-01T05:00:03+00:00"),
+                3
+            )
+        );
+
+        // So do backends started since the drone last reported its load.
+        for _ in 0..2 {
+            scheduler.update_backend_state(
+                &BackendStateMessage::new(BackendState::Loading, BackendId::new_random())
+                    .with_drone(drone_id.clone()),
+                date("2020-01-01T05:00:03+00:00"),
+            );
+        }
+        assert_eq!(
+            Err(SchedulerError::NoDroneAvailable),
+            scheduler.schedule(
+                &cluster,
+                Requirements::default(),
+                date("2020-01-01T05:00:03+00:00")
+            )
+        );
+    }
+
+    fn for_image(image: &str) -> Requirements<'_> {
+        Requirements {
+            image: Some(image),
+            ..Requirements::default()
+        }
+    }
+
+    #[test]
+    fn test_image_affinity() {
+        let scheduler = Scheduler::default().with_image_affinity(true);
+        let cluster = ClusterName::new("mycluster.test");
+        let cold_drone = DroneId::new_random();
+        let warm_drone = DroneId::new_random();
+
+        scheduler.update_status(
+            date("2020-01-01T05:00:00+00:00"),
+            &status_with_version(&cold_drone, PLANE_VERSION),
+        );
+        let mut status = status_with_version(&warm_drone, PLANE_VERSION);
+        status.host_metrics = Some(HostMetrics {
+            cached_images: vec!["ghcr.io/org/app:latest".into()],
+            image_digests: vec!["ghcr.io/org/app@sha256:abc".into()],
+            ..HostMetrics::default()
+        });
+        scheduler.update_status(date("2020-01-01T05:00:00+00:00"), &status);
+
+        let now = date("2020-01-01T05:00:03+00:00");
+        for image in ["ghcr.io/org/app", "ghcr.io/org/app@sha256:abc"] {
+            for _ in 0..10 {
+                assert_eq!(
+                    Ok(warm_drone.clone()),
+                    scheduler.schedule(&cluster, for_image(image), now)
+                );
+            }
+        }
+        assert!(scheduler
+            .schedule_batch(&cluster, for_image("ghcr.io/org/app"), now, 3)
+            .into_iter()
+            .all(|drone| drone == Ok(warm_drone.clone())));
+
+        // Without a drone which has the image, any drone may be chosen.
+        assert!(scheduler
+            .schedule(&cluster, for_image("ghcr.io/org/other"), now)
+            .is_ok());
+
+        // Drones which have forgotten are not remembered for their images.
+        scheduler.prune_lost_drones(date("2020-01-01T06:00:00+00:00"));
+        assert!(scheduler.cached_images.is_empty());
+    }
+
+    #[test]
+    fn test_image_affinity_is_weighted() {
+        let scheduler = Scheduler::new(Arc::new(LeastLoadedPlacement))
+            .with_image_affinity(true)
+            .with_image_affinity_weight(4.0);
+        let cluster = ClusterName::new("mycluster.test");
+        let cold_drone = DroneId::new_random();
+        let warm_drone = DroneId::new_random();
+
+        let mut status = status_with_version(&cold_drone, PLANE_VERSION);
+        status.running_backends = Some(0);
+        scheduler.update_status(date("2020-01-01T05:00:00+00:00"), &status);
+        let mut status = status_with_version(&warm_drone, PLANE_VERSION);
+        status.running_backends = Some(3);
+        status.host_metrics = Some(HostMetrics {
+            cached_images: vec!["nginx:latest".into()],
+            ..HostMetrics::default()
+        });
+        scheduler.update_status(date("2020-01-01T05:00:00+00:00"), &status);
+
+        // Images on Docker Hub match however they are named.
+        let now = date("2020-01-01T05:00:03+00:00");
+        for image in ["nginx", "library/nginx", "docker.io/library/nginx:latest"] {
+            assert_eq!(
+                Ok(warm_drone.clone()),
+                scheduler.schedule(&cluster, for_image(image), now)
+            );
+        }
+
+        // Once the drone with the image is loaded by more than the weight,
+        // the drone without it is preferred.
+        status.running_backends = Some(4);
+        scheduler.update_status(date("2020-01-01T05:00:01+00:00"), &status);
+        assert_eq!(
+            Ok(cold_drone.clone()),
+            scheduler.schedule(&cluster, for_image("nginx"), now)
+        );
+    }
+
+    #[test]
+    fn test_image_reference() {
+        assert_eq!("nginx:latest", image_reference("nginx"));
+        assert_eq!("ubuntu:22.04", image_reference("ubuntu:22.04"));
+        assert_eq!(
+            "nginx:1.25",
+            image_reference("docker.io/library/nginx:1.25")
+        );
+        assert_eq!("org/app:latest", image_reference("docker.io/org/app"));
+        assert_eq!(
+            "ghcr.io/org/app@sha256:abc",
+            image_reference("ghcr.io/org/app@sha256:abc")
+        );
+        assert_eq!(
+            "localhost:5000/app:latest",
+            image_reference("localhost:5000/app")
+        );
+    }
+
+    #[test]
+    fn test_arch() {
+        let scheduler = Scheduler::default();
+        let cluster = ClusterName::new("mycluster.test");
+        let amd64_drone = DroneId::new_random();
+        let arm64_drone = DroneId::new_random();
+
+        let mut status = status_with_version(&amd64_drone, PLANE_VERSION);
+        status.arch = Some("x86_64".into());
+        scheduler.update_status(date("2020-01-01T05:00:00+00:00"), &status);
+        let mut status = status_with_version(&arm64_drone, PLANE_VERSION);
+        status.arch = Some("arm64".into());
+        scheduler.update_status(date("2020-01-01T05:00:00+00:00"), &status);
+
+        let now = date("2020-01-01T05:00:03+00:00");
+        let requirements = |arch| Requirements {
+            arch: Some(arch),
+            ..Requirements::default()
+        };
+        for _ in 0..10 {
+            assert_eq!(
+                Ok(arm64_drone.clone()),
+                scheduler.schedule(&cluster, requirements("arm64"), now)
+            );
+            assert_eq!(
+                Ok(amd64_drone.clone()),
+                scheduler.schedule(&cluster, requirements("amd64"), now)
+            );
+        }
+        assert_eq!(
+            Err(SchedulerError::NoDroneAvailable),
+            scheduler.schedule(&cluster, requirements("riscv64"), now)
+        );
+
+        // Drones which do not report their architecture may run any image.
+        let unknown_drone = DroneId::new_random();
+        scheduler.update_status(
+            date("2020-01-01T05:00:00+00:00"),
+            &status_with_version(&unknown_drone, PLANE_VERSION),
+        );
+        assert_eq!(
+            Ok(unknown_drone),
+            scheduler.schedule(&cluster, requirements("riscv64"), now)
+        );
+    }
+
+    #[test]
+    fn test_drained_drone_not_scheduled() {
+        let scheduler = Scheduler::default();
+        let cluster = ClusterName::new("mycluster.test");
+        let drone_id = DroneId::new_random();
+        let drain = |drain| DrainDrone {
+            drone: drone_id.clone(),
+            cluster: cluster.clone(),
+            drain,
+        };
+
+        scheduler.update_status(
+            date("2020-01-01T05:00:00+00:00"),
+            &status_with_version(&drone_id, PLANE_VERSION),
+        );
+        let now = date("2020-01-01T05:00:01+00:00");
+        assert_eq!(
+            Ok(drone_id.clone()),
+            scheduler.schedule(&cluster, Requirements::default(), now)
+        );
+
+        // The drone is excluded as soon as it is told to drain, even if a
+        // status published before it received the request arrives later.
+        scheduler.update_drain(now, &drain(true));
+        assert_eq!(
+            Err(SchedulerError::NoDroneAvailable),
+            scheduler.schedule(&cluster, Requirements::default(), now)
+        );
+        let now = date("2020-01-01T05:00:02+00:00");
+        scheduler.update_status(now, &status_with_version(&drone_id, PLANE_VERSION));
+        assert_eq!(
+            Err(SchedulerError::NoDroneAvailable),
+            scheduler.schedule(&cluster, Requirements::default(), now)
+        );
+
+        // Later, the drone's own report of draining keeps it excluded.
+        let now = date("2020-01-01T05:00:10+00:00");
+        let mut status = status_with_version(&drone_id, PLANE_VERSION);
+        status.ready = false;
+        status.draining = true;
+        scheduler.update_status(now, &status);
+        assert_eq!(
+            Err(SchedulerError::NoDroneAvailable),
+            scheduler.schedule(&cluster, Requirements::default(), now)
+        );
+
+        // Once draining is cancelled, the drone's next status makes it
+        // schedulable again.
+        scheduler.update_drain(now, &drain(false));
+        scheduler.update_status(now, &status_with_version(&drone_id, PLANE_VERSION));
+        assert_eq!(
+            Ok(drone_id.clone()),
+            scheduler.schedule(&cluster, Requirements::default(), now)
+        );
+    }
 }
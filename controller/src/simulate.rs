@@ -0,0 +1,549 @@
+//! `plane-controller simulate`: replay drone status messages and schedule
+//! requests recorded from NATS, and report which drone each request would be
+//! placed on under each of several placement strategies, so that a change of
+//! strategy can be evaluated without touching a live cluster.
+//!
+//! The export is a file of JSON lines, one message per line, with the
+//! message's `subject`, its `time` (RFC 3339), and its `data` (base64 of the
+//! message's JSON), e.g.
+//!
+//! ```text
+//! {"subject":"drone.drone1.status","time":"2023-01-01T00:00:00Z","data":"eyJjbHVzdGVyIjoi..."}
+//! ```
+//!
+//! This is also the shape JetStream's message get API returns stored
+//! messages in. `plane-controller record` writes an export from the messages
+//! it sees on NATS. Messages are replayed in order of time. Besides drone
+//! status messages (`drone.*.status`) and schedule requests
+//! (`cluster.*.schedule`), backend state messages (`backend.*.status`) in
+//! which a backend terminated are replayed, so that backends placed in the
+//! simulation stop counting towards their drone's load. Other messages are
+//! skipped.
+
+use crate::{
+    placement::PlacementOptions,
+    scheduler::{Requirements, Scheduler, SchedulerError},
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::Args;
+use plane_core::{
+    messages::{
+        agent::{BackendState, BackendStateMessage, DroneStatusMessage},
+        scheduler::{AffinityMember, ScheduleRequest},
+    },
+    nats::{TypedMessage, TypedNats},
+    nats_connection::NatsConnectionSpec,
+    subjects::{self, Any},
+    types::{BackendId, ClusterName, DroneId},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    time::Duration,
+};
+use tokio::{select, time::Instant};
+
+#[derive(Args)]
+pub struct SimulateArgs {
+    /// File of JetStream messages to replay.
+    export: PathBuf,
+
+    /// Placement strategy to simulate: `random`, `least_loaded`, or
+    /// `bin_packing:<max_backends_per_drone>`. May be given more than once.
+    #[clap(long = "strategy", value_parser = parse_strategy)]
+    strategies: Vec<PlacementOptions>,
+
+    /// Prefer drones which have a backend's image cached.
+    #[clap(long)]
+    image_affinity: bool,
+}
+
+fn parse_strategy(strategy: &str) -> Result<PlacementOptions> {
+    match strategy.split_once(':') {
+        None if strategy == "random" => Ok(PlacementOptions::Random),
+        None if strategy == "least_loaded" => Ok(PlacementOptions::LeastLoaded),
+        Some(("bin_packing", max_backends_per_drone)) => Ok(PlacementOptions::BinPacking {
+            max_backends_per_drone: max_backends_per_drone
+                .parse()
+                .context("Expected bin_packing:<max_backends_per_drone>.")?,
+        }),
+        _ => Err(anyhow!("Unknown placement strategy {}.", strategy)),
+    }
+}
+
+fn strategy_name(strategy: &PlacementOptions) -> String {
+    match strategy {
+        PlacementOptions::Random => "random".into(),
+        PlacementOptions::LeastLoaded => "least_loaded".into(),
+        PlacementOptions::BinPacking {
+            max_backends_per_drone,
+        } => format!("bin_packing:{}", max_backends_per_drone),
+    }
+}
+
+/// A message stored in JetStream, as exported.
+#[derive(Serialize, Deserialize)]
+struct StoredMessage {
+    subject: String,
+    time: String,
+    data: String,
+}
+
+enum Event {
+    Status(DroneStatusMessage),
+    BackendTerminated(BackendStateMessage),
+    Schedule(Box<ScheduleRequest>),
+}
+
+fn parse_event(message: &StoredMessage) -> Result<Option<Event>> {
+    let data = base64::decode(&message.data)?;
+
+    let subject = &message.subject;
+    let event = if subjects::matches(&subjects::drone_status(Any), subject) {
+        Some(Event::Status(serde_json::from_slice(&data)?))
+    } else if subjects::matches(&subjects::cluster_schedule(Any), subject) {
+        Some(Event::Schedule(Box::new(serde_json::from_slice(&data)?)))
+    } else if subjects::matches(&subjects::backend_status(Any), subject) {
+        let state: BackendStateMessage = serde_json::from_slice(&data)?;
+        if state.state.terminal() {
+            Some(Event::BackendTerminated(state))
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+
+    Ok(event)
+}
+
+/// Read an export, returning its events in order of time.
+fn read_export(export: impl BufRead) -> Result<Vec<(DateTime<Utc>, Event)>> {
+    let mut events = Vec::new();
+    let mut skipped = 0;
+
+    for (line_number, line) in export.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let message: StoredMessage = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid message on line {}.", line_number + 1))?;
+        let time: DateTime<Utc> = DateTime::parse_from_rfc3339(&message.time)
+            .with_context(|| format!("Invalid time on line {}.", line_number + 1))?
+            .into();
+        let event = parse_event(&message).with_context(|| {
+            format!(
+                "Invalid {} message on line {}.",
+                message.subject,
+                line_number + 1
+            )
+        })?;
+
+        match event.map(|event| (time, event)) {
+            Some(event) => events.push(event),
+            None => skipped += 1,
+        }
+    }
+
+    eprintln!("Replaying {} messages, skipping {}.", events.len(), skipped);
+    // Sorting is stable, so messages recorded at the same time keep their order.
+    events.sort_by_key(|(time, _)| *time);
+    Ok(events)
+}
+
+/// Where a recorded schedule request would have been placed.
+struct SimulatedPlacement {
+    time: DateTime<Utc>,
+    cluster: ClusterName,
+    backend: Option<BackendId>,
+
+    /// The drone chosen under each strategy, in the order of the strategies.
+    drones: Vec<Result<DroneId, SchedulerError>>,
+}
+
+fn simulate(
+    events: &[(DateTime<Utc>, Event)],
+    strategies: &[PlacementOptions],
+    image_affinity: bool,
+) -> Vec<SimulatedPlacement> {
+    let schedulers: Vec<Scheduler> = strategies
+        .iter()
+        .map(|strategy| Scheduler::new(strategy.strategy()).with_image_affinity(image_affinity))
+        .collect();
+    let mut placements = Vec::new();
+
+    for (time, event) in events {
+        // The controller does this every second.
+        for scheduler in &schedulers {
+            scheduler.prune_lost_drones(*time);
+        }
+
+        match event {
+            Event::Status(status) => {
+                for scheduler in &schedulers {
+                    scheduler.update_status(*time, status);
+                }
+            }
+            Event::BackendTerminated(state) => {
+                for scheduler in &schedulers {
+                    scheduler.update_backend_state(state, *time);
+                }
+            }
+            Event::Schedule(request) => {
+                let drones = schedulers
+                    .iter()
+                    .map(|scheduler| {
+                        let drone =
+                            scheduler.schedule(&request.cluster, Requirements::of(request), *time);
+
+                        // Count the backend towards its drone's load until it
+                        // terminates, as if it had been spawned there.
+                        if let (Ok(drone), Some(backend)) = (&drone, &request.backend_id) {
+                            scheduler.update_backend_state(
+                                &BackendStateMessage {
+                                    state: BackendState::Starting,
+                                    backend: backend.clone(),
+                                    time: *time,
+                                    reason: None,
+                                    error: None,
+                                    termination: None,
+                                    timeline: None,
+                                    drone: Some(drone.clone()),
+                                },
+                                *time,
+                            );
+                            if let Some(group) = &request.affinity_group {
+                                scheduler.record_affinity_member(
+                                    AffinityMember {
+                                        cluster: request.cluster.clone(),
+                                        group: group.name.clone(),
+                                        backend: backend.clone(),
+                                        drone: drone.clone(),
+                                        time: *time,
+                                    },
+                                    *time,
+                                );
+                            }
+                        }
+
+                        drone
+                    })
+                    .collect();
+
+                placements.push(SimulatedPlacement {
+                    time: *time,
+                    cluster: request.cluster.clone(),
+                    backend: request.backend_id.clone(),
+                    drones,
+                });
+            }
+        }
+    }
+
+    placements
+}
+
+fn print_report(strategies: &[PlacementOptions], placements: &[SimulatedPlacement]) {
+    let names: Vec<String> = strategies.iter().map(strategy_name).collect();
+
+    println!("time\tcluster\tbackend\t{}", names.join("\t"));
+    for placement in placements {
+        let drones: Vec<String> = placement
+            .drones
+            .iter()
+            .map(|drone| match drone {
+                Ok(drone) => drone.to_string(),
+                Err(_) => "-".into(),
+            })
+            .collect();
+        println!(
+            "{}\t{}\t{}\t{}",
+            placement.time.to_rfc3339(),
+            placement.cluster,
+            placement
+                .backend
+                .as_ref()
+                .map_or_else(|| "-".to_string(), |backend| backend.to_string()),
+            drones.join("\t")
+        );
+    }
+
+    for (i, name) in names.iter().enumerate() {
+        let mut per_drone: BTreeMap<String, u32> = BTreeMap::new();
+        let mut failed = 0;
+        for placement in placements {
+            match &placement.drones[i] {
+                Ok(drone) => *per_drone.entry(drone.to_string()).or_default() += 1,
+                Err(_) => failed += 1,
+            }
+        }
+
+        println!();
+        println!(
+            "{}: {} placed, {} failed",
+            name,
+            placements.len() - failed,
+            failed
+        );
+        for (drone, count) in per_drone {
+            println!("  {}\t{}", drone, count);
+        }
+    }
+}
+
+pub fn run_simulate(args: SimulateArgs) -> Result<()> {
+    let strategies = if args.strategies.is_empty() {
+        vec![PlacementOptions::Random, PlacementOptions::LeastLoaded]
+    } else {
+        args.strategies
+    };
+
+    let export = File::open(&args.export)
+        .with_context(|| format!("Could not open {}.", args.export.display()))?;
+    let events = read_export(BufReader::new(export))?;
+    let placements = simulate(&events, &strategies, args.image_affinity);
+    print_report(&strategies, &placements);
+
+    Ok(())
+}
+
+#[derive(Args)]
+pub struct RecordArgs {
+    /// File to write the export to. It is overwritten.
+    export: PathBuf,
+
+    /// NATS server to record from.
+    #[clap(long, default_value = "nats://localhost")]
+    nats: String,
+
+    /// Stop recording after this many seconds, instead of when interrupted.
+    #[clap(long)]
+    duration_secs: Option<u64>,
+}
+
+/// The line of an export recording a message, received at `time`.
+fn export_line<T: TypedMessage>(value: &T, time: DateTime<Utc>) -> Result<String> {
+    Ok(serde_json::to_string(&StoredMessage {
+        subject: value.subject(),
+        time: time.to_rfc3339(),
+        data: base64::encode(serde_json::to_vec(value)?),
+    })?)
+}
+
+/// Write every message `simulate` replays to an export, as it is published.
+async fn record(
+    nats: &TypedNats,
+    export: &mut impl Write,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    let mut status_sub = nats
+        .subscribe(DroneStatusMessage::subscribe_subject())
+        .await?;
+    let mut schedule_sub = nats.subscribe(ScheduleRequest::subscribe_subject()).await?;
+    let mut state_sub = nats
+        .subscribe(BackendStateMessage::wildcard_subject())
+        .await?;
+    let deadline = async {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => futures::future::pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+    eprintln!("Recording; interrupt to stop.");
+
+    let mut recorded = 0;
+    loop {
+        let line = select! {
+            status = status_sub.next() => match status {
+                Some(status) => export_line(&status.value, Utc::now())?,
+                None => return Err(anyhow!("status_sub.next() returned None.")),
+            },
+            request = schedule_sub.next() => match request {
+                Some(request) => export_line(&request.value, Utc::now())?,
+                None => return Err(anyhow!("schedule_sub.next() returned None.")),
+            },
+            state = state_sub.next() => match state {
+                Some(state) => export_line(&state.value, Utc::now())?,
+                None => return Err(anyhow!("state_sub.next() returned None.")),
+            },
+            _ = &mut deadline => break,
+        };
+
+        // Flushed as it goes, so that an interrupted recording is complete.
+        writeln!(export, "{}", line)?;
+        export.flush()?;
+        recorded += 1;
+    }
+
+    eprintln!("Recorded {} messages.", recorded);
+    Ok(())
+}
+
+pub async fn run_record(args: RecordArgs) -> Result<()> {
+    let nats = NatsConnectionSpec::from_url(&args.nats)?.connect().await?;
+    let mut export = File::create(&args.export)
+        .with_context(|| format!("Could not create {}.", args.export.display()))?;
+    let deadline = args
+        .duration_secs
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+
+    record(&nats, &mut export, deadline).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plane_core::{
+        messages::agent::{DockerExecutableConfig, IdlePolicy, ObservabilityOptions},
+        version::PLANE_VERSION,
+    };
+    use serde::Serialize;
+    use serde_json::json;
+
+    fn stored_message(subject: &str, time: &str, data: &impl Serialize) -> String {
+        json!({
+            "subject": subject,
+            "time": time,
+            "data": base64::encode(serde_json::to_vec(data).unwrap()),
+        })
+        .to_string()
+    }
+
+    fn status(drone: &str, running_backends: u32) -> DroneStatusMessage {
+        DroneStatusMessage {
+            drone_id: DroneId::new(drone.into()),
+            cluster: ClusterName::new("plane.test"),
+            drone_version: PLANE_VERSION.to_string(),
+            ready: true,
+            running_backends: Some(running_backends),
+            remaining_capacity: None,
+            host_metrics: None,
+            sealing_key: None,
+            arch: None,
+            proxy_metrics: None,
+            draining: false,
+        }
+    }
+
+    fn request(backend: &str) -> ScheduleRequest {
+        ScheduleRequest {
+            cluster: ClusterName::new("plane.test"),
+            backend_id: Some(BackendId::new(backend.into())),
+            max_idle_secs: None,
+            max_lifetime_secs: None,
+            port_ready_timeout_secs: None,
+            metadata: Default::default(),
+            executable: DockerExecutableConfig {
+                image: "ghcr.io/drifting-in-space/test-image:latest".into(),
+                env: Default::default(),
+                credentials: None,
+                resource_limits: Default::default(),
+                egress_policy: Default::default(),
+            },
+            require_bearer_token: false,
+            group: None,
+            dry_run: false,
+            schedule_deadline_ms: None,
+            idle_policy: IdlePolicy::default(),
+            arch: None,
+            progress_id: None,
+            affinity_group: None,
+            observability: ObservabilityOptions::default(),
+        }
+    }
+
+    #[test]
+    fn test_simulate() {
+        let export = [
+            stored_message(
+                "cluster.plane_test.schedule",
+                "2020-01-01T05:00:02+00:00",
+                &request("backend1"),
+            ),
+            stored_message(
+                "drone.drone1.status",
+                "2020-01-01T05:00:00+00:00",
+                &status("drone1", 3),
+            ),
+            stored_message(
+                "drone.drone2.status",
+                "2020-01-01T05:00:00+00:00",
+                &status("drone2", 0),
+            ),
+            stored_message(
+                "cluster.plane_test.schedule",
+                "2020-01-01T05:00:03+00:00",
+                &request("backend2"),
+            ),
+            stored_message(
+                "cluster.plane_test.schedule",
+                "2020-01-01T05:00:04+00:00",
+                &request("backend3"),
+            ),
+            stored_message("scheduler.leader", "2020-01-01T05:00:04+00:00", &"c1"),
+        ]
+        .join("\n");
+
+        let events = read_export(export.as_bytes()).unwrap();
+        assert_eq!(5, events.len());
+
+        let strategies = vec![
+            PlacementOptions::LeastLoaded,
+            PlacementOptions::BinPacking {
+                max_backends_per_drone: 4,
+            },
+        ];
+        let placements = simulate(&events, &strategies, false);
+        let drones: Vec<Vec<Result<DroneId, SchedulerError>>> = placements
+            .into_iter()
+            .map(|placement| placement.drones)
+            .collect();
+
+        let drone1 = || Ok(DroneId::new("drone1".into()));
+        let drone2 = || Ok(DroneId::new("drone2".into()));
+        assert_eq!(
+            vec![
+                vec![drone2(), drone1()],
+                vec![drone2(), drone1()],
+                vec![drone2(), drone1()],
+            ],
+            drones
+        );
+    }
+
+    #[test]
+    fn test_recorded_messages_replay() {
+        let time: DateTime<Utc> = DateTime::parse_from_rfc3339("2020-01-01T05:00:00+00:00")
+            .unwrap()
+            .into();
+        let export = [
+            export_line(&status("drone1", 0), time).unwrap(),
+            export_line(&request("backend1"), time).unwrap(),
+        ]
+        .join("\n");
+
+        let events = read_export(export.as_bytes()).unwrap();
+        assert_eq!(2, events.len());
+        assert!(matches!(events[0], (t, Event::Status(_)) if t == time));
+        assert!(matches!(events[1], (t, Event::Schedule(_)) if t == time));
+    }
+
+    #[test]
+    fn test_parse_strategy() {
+        assert_eq!(
+            PlacementOptions::BinPacking {
+                max_backends_per_drone: 20
+            },
+            parse_strategy("bin_packing:20").unwrap()
+        );
+        assert!(parse_strategy("bin_packing").is_err());
+        assert!(parse_strategy("fastest").is_err());
+    }
+}
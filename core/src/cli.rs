@@ -1,18 +1,41 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use serde::{de::DeserializeOwned, Serialize};
 
-#[derive(Parser)]
-struct CliArgs {
+#[derive(Args)]
+struct ConfigArgs {
     #[clap(short, long)]
     dump_config: bool,
 
     config_file: Option<String>,
 }
 
-pub fn init_cli<C: Serialize + DeserializeOwned>() -> Result<C> {
-    let cli_args = CliArgs::parse();
+#[derive(Parser)]
+struct CliArgs {
+    #[clap(flatten)]
+    config: ConfigArgs,
+}
+
+#[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
+struct CliArgsWithSubcommands<S: Subcommand> {
+    #[clap(flatten)]
+    config: ConfigArgs,
+
+    #[clap(subcommand)]
+    command: Option<S>,
+}
 
+/// What a binary with subcommands was asked to do.
+pub enum CliCommand<C, S> {
+    /// Run as usual, with the given configuration.
+    Run(C),
+
+    /// Run a subcommand instead.
+    Subcommand(S),
+}
+
+fn load_config<C: Serialize + DeserializeOwned>(cli_args: ConfigArgs) -> Result<C> {
     let mut config_builder = config::Config::builder();
     if let Some(config_file) = cli_args.config_file {
         config_builder =
@@ -36,3 +59,18 @@ pub fn init_cli<C: Serialize + DeserializeOwned>() -> Result<C> {
 
     Ok(config)
 }
+
+pub fn init_cli<C: Serialize + DeserializeOwned>() -> Result<C> {
+    load_config(CliArgs::parse().config)
+}
+
+/// Like [init_cli], but the binary may instead be given one of the
+/// subcommands `S`, in which case its configuration is not loaded.
+pub fn init_cli_with_subcommands<C: Serialize + DeserializeOwned, S: Subcommand>(
+) -> Result<CliCommand<C, S>> {
+    let cli_args = CliArgsWithSubcommands::<S>::parse();
+    match cli_args.command {
+        Some(command) => Ok(CliCommand::Subcommand(command)),
+        None => Ok(CliCommand::Run(load_config(cli_args.config)?)),
+    }
+}
@@ -0,0 +1,281 @@
+//! Checks of the JetStream streams Plane relies on and of the consumers
+//! reading them, to catch misconfigured streams and state replication which
+//! has fallen behind before they lead to stale scheduling.
+
+use crate::{
+    messages::{
+        agent::{BackendStateMessage, DroneStatusMessage},
+        dns::SetDnsRecord,
+        scheduler::{
+            AffinityMember, ClusterConfig, DroneApproval, JetStreamAlert, JetStreamProblem,
+        },
+    },
+    nats::{JetStreamable, TypedNats},
+};
+use anyhow::{anyhow, Result};
+use async_nats::jetstream::stream::Config;
+use serde::{Deserialize, Serialize};
+
+/// Error returned by the JetStream API in place of a response.
+#[derive(Deserialize, Debug)]
+struct ApiError {
+    code: u16,
+    description: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct StreamInfoResponse {
+    error: Option<ApiError>,
+    config: Option<StreamConfigInfo>,
+}
+
+/// The parts of a stream's configuration which Plane depends on.
+#[derive(Deserialize, Debug, Default)]
+struct StreamConfigInfo {
+    #[serde(default)]
+    subjects: Vec<String>,
+
+    #[serde(default)]
+    max_msgs_per_subject: i64,
+}
+
+/// Request for a page of a stream's consumers.
+#[derive(Serialize, Debug)]
+struct ConsumerListRequest {
+    offset: usize,
+}
+
+/// A page of a stream's consumers, starting at the requested offset.
+#[derive(Deserialize, Debug)]
+struct ConsumerListResponse {
+    error: Option<ApiError>,
+
+    /// Number of consumers of the stream, across pages.
+    #[serde(default)]
+    total: usize,
+
+    #[serde(default)]
+    consumers: Vec<ConsumerInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ConsumerInfo {
+    name: String,
+
+    #[serde(default)]
+    num_pending: u64,
+
+    #[serde(default)]
+    num_ack_pending: u64,
+}
+
+/// The streams Plane relies on, with the configuration Plane creates them
+/// with.
+fn plane_streams() -> Vec<Config> {
+    vec![
+        BackendStateMessage::config(),
+        DroneStatusMessage::config(),
+        SetDnsRecord::config(),
+        ClusterConfig::config(),
+        DroneApproval::config(),
+        AffinityMember::config(),
+    ]
+}
+
+/// Limit on the number of messages kept per subject, where zero or a
+/// negative value means there is none.
+fn per_subject_limit(max_messages_per_subject: i64) -> Option<i64> {
+    (max_messages_per_subject > 0).then_some(max_messages_per_subject)
+}
+
+/// Ways in which a stream's configuration breaks Plane's assumptions.
+/// Retention, replicas, and message age may be tuned per deployment (see
+/// [crate::streams]), so they are not checked.
+fn stream_problems(expected: &Config, actual: &StreamConfigInfo) -> Vec<JetStreamProblem> {
+    let mut problems = Vec::new();
+
+    let mut expected_subjects = expected.subjects.clone();
+    expected_subjects.sort();
+    let mut actual_subjects = actual.subjects.clone();
+    actual_subjects.sort();
+    if expected_subjects != actual_subjects {
+        problems.push(JetStreamProblem::StreamMisconfigured {
+            reason: format!(
+                "subjects are {:?}, expected {:?}.",
+                actual_subjects, expected_subjects
+            ),
+        });
+    }
+
+    let expected_limit = per_subject_limit(expected.max_messages_per_subject);
+    let actual_limit = per_subject_limit(actual.max_msgs_per_subject);
+    if expected_limit != actual_limit {
+        problems.push(JetStreamProblem::StreamMisconfigured {
+            reason: format!(
+                "per-subject message limit is {:?}, expected {:?}.",
+                actual_limit, expected_limit
+            ),
+        });
+    }
+
+    problems
+}
+
+/// Consumers which have more than `max_pending` messages left to deliver.
+fn consumer_problems(consumers: &[ConsumerInfo], max_pending: u64) -> Vec<JetStreamProblem> {
+    consumers
+        .iter()
+        .filter(|consumer| consumer.num_pending > max_pending)
+        .map(|consumer| JetStreamProblem::ConsumerLagging {
+            consumer: consumer.name.clone(),
+            num_pending: consumer.num_pending,
+            num_ack_pending: consumer.num_ack_pending,
+        })
+        .collect()
+}
+
+/// Offset of the page of consumers after one of `page_len` consumers at
+/// `offset`, if there are more to list.
+fn next_page(offset: usize, page_len: usize, total: usize) -> Option<usize> {
+    let next = offset + page_len;
+    (page_len > 0 && next < total).then_some(next)
+}
+
+/// List every consumer of a stream, following the pages JetStream returns
+/// them in.
+async fn list_consumers(nats: &TypedNats, stream: &str) -> Result<Vec<ConsumerInfo>> {
+    let mut consumers = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let list: ConsumerListResponse = nats
+            .jetstream_api_with(
+                &format!("CONSUMER.LIST.{}", stream),
+                &ConsumerListRequest { offset },
+            )
+            .await?;
+        if let Some(error) = list.error {
+            return Err(anyhow!(
+                "Error listing consumers of stream {}: {}",
+                stream,
+                error.description
+            ));
+        }
+
+        let page_len = list.consumers.len();
+        consumers.extend(list.consumers);
+        match next_page(offset, page_len, list.total) {
+            Some(next) => offset = next,
+            None => return Ok(consumers),
+        }
+    }
+}
+
+/// Check each stream Plane relies on, and the consumers reading it, for
+/// problems. Consumers with more than `max_pending` messages left to deliver
+/// are reported as lagging.
+pub async fn check_jetstream(nats: &TypedNats, max_pending: u64) -> Result<Vec<JetStreamAlert>> {
+    let mut alerts = Vec::new();
+
+    for expected in plane_streams() {
+        let stream = expected.name.clone();
+        let alert = |problem| JetStreamAlert {
+            stream: stream.clone(),
+            problem,
+        };
+
+        let info: StreamInfoResponse = nats
+            .jetstream_api(&format!("STREAM.INFO.{}", stream))
+            .await?;
+        let config = match (info.error, info.config) {
+            (Some(error), _) if error.code == 404 => {
+                alerts.push(alert(JetStreamProblem::StreamMissing));
+                continue;
+            }
+            (Some(error), _) => {
+                return Err(anyhow!(
+                    "Error getting info of stream {}: {}",
+                    stream,
+                    error.description
+                ))
+            }
+            (None, config) => config.unwrap_or_default(),
+        };
+        alerts.extend(stream_problems(&expected, &config).into_iter().map(alert));
+
+        let consumers = list_consumers(nats, &stream).await?;
+        alerts.extend(
+            consumer_problems(&consumers, max_pending)
+                .into_iter()
+                .map(alert),
+        );
+    }
+
+    Ok(alerts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_page() {
+        assert_eq!(Some(256), next_page(0, 256, 300));
+        assert_eq!(None, next_page(256, 44, 300));
+        assert_eq!(None, next_page(0, 3, 3));
+        // An empty page ends the listing, even if consumers were removed
+        // since the total was counted.
+        assert_eq!(None, next_page(256, 0, 300));
+    }
+
+    #[test]
+    fn test_stream_problems() {
+        let expected = ClusterConfig::config();
+        let mut actual = StreamConfigInfo {
+            subjects: expected.subjects.clone(),
+            max_msgs_per_subject: expected.max_messages_per_subject,
+        };
+        assert!(stream_problems(&expected, &actual).is_empty());
+
+        // Streams without a per-subject limit may report it as -1.
+        let expected = BackendStateMessage::config();
+        actual.subjects = expected.subjects.clone();
+        actual.max_msgs_per_subject = -1;
+        assert!(stream_problems(&expected, &actual).is_empty());
+
+        actual.subjects = vec!["backend.*.other".into()];
+        actual.max_msgs_per_subject = 10;
+        assert_eq!(2, stream_problems(&expected, &actual).len());
+    }
+
+    #[test]
+    fn test_parse_consumer_list() {
+        let list: ConsumerListResponse = serde_json::from_str(
+            r#"{
+                "type": "io.nats.jetstream.api.v1.consumer_list_response",
+                "total": 2,
+                "consumers": [
+                    {"name": "a", "num_pending": 5, "num_ack_pending": 1},
+                    {"name": "b", "num_pending": 5000, "num_ack_pending": 1}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert!(list.error.is_none());
+
+        assert_eq!(
+            vec![JetStreamProblem::ConsumerLagging {
+                consumer: "b".into(),
+                num_pending: 5000,
+                num_ack_pending: 1,
+            }],
+            consumer_problems(&list.consumers, 1000)
+        );
+
+        let error: ConsumerListResponse = serde_json::from_str(
+            r#"{"error": {"code": 404, "err_code": 10059, "description": "stream not found"}}"#,
+        )
+        .unwrap();
+        assert_eq!(404, error.error.unwrap().code);
+    }
+}
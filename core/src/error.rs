@@ -0,0 +1,162 @@
+//! Structured errors returned to API consumers in responses and status
+//! messages, so that they can tell failures apart without parsing messages.
+
+use crate::types::DroneId;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PlaneError {
+    /// No drone in the cluster could accept the backend.
+    NoDroneAvailable,
+
+    /// The request was not authenticated, or its signature was not valid.
+    Unauthenticated { reason: String },
+
+    /// The request is malformed, or conflicts with the state of the cluster
+    /// (e.g. it asks for a hostname which is in use).
+    InvalidRequest { reason: String },
+
+    /// The backend (or other resource) the request refers to does not
+    /// exist, or is no longer running.
+    NotFound { reason: String },
+
+    /// The request exceeds a resource limit of its cluster.
+    QuotaExceeded { reason: String },
+
+    /// The request was rejected by the admission webhook.
+    AdmissionDenied { reason: String },
+
+    /// The drone chosen for the backend did not respond.
+    DroneTimeout { drone: DroneId },
+
+    /// The backend's image could not be pulled.
+    ImagePullFailed { image: String, reason: String },
+
+    /// The backend took longer than the drone allows to load.
+    LoadTimeout { timeout_secs: u64 },
+
+    /// The drone was busy loading other backends, and the backend waited
+    /// longer than the drone allows to start loading.
+    SpawnQueueTimeout { timeout_secs: u64 },
+
+    /// The drone chosen for the backend did not accept it before the
+    /// deadline given in the schedule request. The drone may still start the
+    /// backend, in which case it is swept once it is idle.
+    ScheduleDeadlineExceeded { deadline_ms: u64 },
+
+    /// The drone is low on disk space, and refuses new backends until space
+    /// is freed.
+    InsufficientDisk {
+        free_bytes: u64,
+        min_free_bytes: u64,
+    },
+
+    /// Any other failure.
+    Internal { reason: String },
+}
+
+impl PlaneError {
+    /// Wrap any other error as [PlaneError::Internal], unless it already is
+    /// a [PlaneError].
+    #[must_use]
+    pub fn from_anyhow(error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<PlaneError>() {
+            Some(error) => error.clone(),
+            None => PlaneError::Internal {
+                reason: error.to_string(),
+            },
+        }
+    }
+}
+
+impl Display for PlaneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlaneError::NoDroneAvailable => write!(f, "No drone available."),
+            PlaneError::Unauthenticated { reason }
+            | PlaneError::InvalidRequest { reason }
+            | PlaneError::NotFound { reason }
+            | PlaneError::QuotaExceeded { reason }
+            | PlaneError::AdmissionDenied { reason }
+            | PlaneError::Internal { reason } => write!(f, "{}", reason),
+            PlaneError::DroneTimeout { drone } => write!(f, "Drone {} did not respond.", drone),
+            PlaneError::ImagePullFailed { image, reason } => {
+                write!(f, "Could not pull image {}: {}", image, reason)
+            }
+            PlaneError::LoadTimeout { timeout_secs } => {
+                write!(
+                    f,
+                    "Timed out after {} seconds loading backend.",
+                    timeout_secs
+                )
+            }
+            PlaneError::SpawnQueueTimeout { timeout_secs } => {
+                write!(
+                    f,
+                    "Timed out after {} seconds waiting for the drone to load backend.",
+                    timeout_secs
+                )
+            }
+            PlaneError::ScheduleDeadlineExceeded { deadline_ms } => {
+                write!(
+                    f,
+                    "No drone accepted backend within the deadline of {}ms.",
+                    deadline_ms
+                )
+            }
+            PlaneError::InsufficientDisk {
+                free_bytes,
+                min_free_bytes,
+            } => {
+                write!(
+                    f,
+                    "Drone has {} bytes of disk free, below its minimum of {} bytes.",
+                    free_bytes, min_free_bytes
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for PlaneError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_serialization() {
+        let error = PlaneError::QuotaExceeded {
+            reason: "Too much memory.".into(),
+        };
+        let json = serde_json::to_string(&error).unwrap();
+
+        assert_eq!(
+            r#"{"kind":"quota_exceeded","reason":"Too much memory."}"#,
+            json
+        );
+        assert_eq!(error, serde_json::from_str(&json).unwrap());
+    }
+
+    #[test]
+    fn test_from_anyhow() {
+        let error = anyhow::Error::new(PlaneError::LoadTimeout { timeout_secs: 10 });
+        assert_eq!(
+            PlaneError::LoadTimeout { timeout_secs: 10 },
+            PlaneError::from_anyhow(&error)
+        );
+
+        let error: anyhow::Error = Err::<(), _>(std::fmt::Error)
+            .context("Something broke.")
+            .unwrap_err();
+        assert_eq!(
+            PlaneError::Internal {
+                reason: "Something broke.".into()
+            },
+            PlaneError::from_anyhow(&error)
+        );
+    }
+}
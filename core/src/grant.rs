@@ -0,0 +1,298 @@
+//! Short-lived grants which a browser can exchange with a drone's proxy for a
+//! session cookie scoped to a single backend.
+//!
+//! A grant is signed with the backend's bearer token, so it can be generated by
+//! whoever requested the backend (typically an application server) and handed to
+//! a browser without revealing the bearer token itself.
+//!
+//! Also defines reconnect tokens, which the proxy issues to route requests made
+//! to a cluster's root hostname to a particular backend.
+
+use crate::types::BackendId;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use ring::hmac;
+use std::time::Duration;
+
+/// Path on a backend's hostname at which the proxy exchanges grants for session cookies.
+pub const GRANT_PATH: &str = "/_plane_auth";
+
+/// Name of the query parameter on [GRANT_PATH] which carries the grant.
+pub const GRANT_QUERY_PARAM: &str = "grant";
+
+/// Name of the cookie the proxy sets after a successful grant exchange.
+pub const SESSION_COOKIE: &str = "plane_session";
+
+/// Path on a backend's hostname at which the proxy issues reconnect tokens.
+pub const RECONNECT_PATH: &str = "/_plane_reconnect";
+
+/// Name of the query parameter which carries a reconnect token on a request to
+/// the cluster's root hostname.
+pub const RECONNECT_QUERY_PARAM: &str = "plane_reconnect";
+
+/// Name of the header which may carry a reconnect token instead of
+/// [RECONNECT_QUERY_PARAM].
+pub const RECONNECT_HEADER: &str = "x-plane-reconnect";
+
+fn sign(bearer_token: &str, message: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, bearer_token.as_bytes());
+    let tag = hmac::sign(&key, message.as_bytes());
+    base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD)
+}
+
+fn verify(bearer_token: &str, message: &str, signature: &str) -> bool {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, bearer_token.as_bytes());
+    match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+        Ok(signature) => hmac::verify(&key, message.as_bytes(), &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthGrant {
+    pub backend_id: BackendId,
+    pub expires: DateTime<Utc>,
+}
+
+impl AuthGrant {
+    /// Construct a grant for the given backend which expires after `ttl`.
+    #[must_use]
+    pub fn new(backend_id: BackendId, ttl: Duration) -> Self {
+        let expires = Utc::now()
+            + chrono::Duration::from_std(ttl).unwrap_or_else(|_| chrono::Duration::zero());
+        AuthGrant {
+            backend_id,
+            expires,
+        }
+    }
+
+    fn message(backend_id: &BackendId, expires: i64) -> String {
+        format!("grant:{}:{}", backend_id.id(), expires)
+    }
+
+    /// Serialize and sign this grant with the backend's bearer token.
+    #[must_use]
+    pub fn sign(&self, bearer_token: &str) -> String {
+        let expires = self.expires.timestamp();
+        let signature = sign(bearer_token, &Self::message(&self.backend_id, expires));
+        format!("{}.{}", expires, signature)
+    }
+
+    /// Parse a signed grant and verify that it was signed with the given bearer
+    /// token for the given backend, and has not expired.
+    pub fn verify(
+        grant: &str,
+        backend_id: &BackendId,
+        bearer_token: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Self> {
+        let (expires, signature) = grant
+            .split_once('.')
+            .ok_or_else(|| anyhow!("Malformed grant."))?;
+        let expires: i64 = expires.parse()?;
+
+        if !verify(bearer_token, &Self::message(backend_id, expires), signature) {
+            return Err(anyhow!("Grant signature is invalid."));
+        }
+
+        let expires = Utc.timestamp(expires, 0);
+        if expires < now {
+            return Err(anyhow!("Grant has expired."));
+        }
+
+        Ok(AuthGrant {
+            backend_id: backend_id.clone(),
+            expires,
+        })
+    }
+}
+
+/// A token which routes requests made to the root hostname of a cluster to the
+/// backend it was issued for, so that a client can reconnect without resolving
+/// the backend's own hostname. Signed with a secret shared by the cluster's
+/// proxies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectToken {
+    pub backend_id: BackendId,
+    pub expires: DateTime<Utc>,
+}
+
+impl ReconnectToken {
+    /// Construct a token for the given backend which expires after `ttl`.
+    #[must_use]
+    pub fn new(backend_id: BackendId, ttl: Duration) -> Self {
+        let expires = Utc::now()
+            + chrono::Duration::from_std(ttl).unwrap_or_else(|_| chrono::Duration::zero());
+        ReconnectToken {
+            backend_id,
+            expires,
+        }
+    }
+
+    fn message(backend_id: &str, expires: i64) -> String {
+        format!("reconnect:{}:{}", backend_id, expires)
+    }
+
+    /// Serialize and sign this token with the given secret.
+    #[must_use]
+    pub fn sign(&self, secret: &str) -> String {
+        let expires = self.expires.timestamp();
+        let signature = sign(secret, &Self::message(self.backend_id.id(), expires));
+        format!("{}.{}.{}", self.backend_id.id(), expires, signature)
+    }
+
+    /// Parse a signed token and verify that it was signed with the given
+    /// secret and has not expired.
+    pub fn verify(token: &str, secret: &str, now: DateTime<Utc>) -> Result<Self> {
+        let mut parts = token.rsplitn(3, '.');
+        let (signature, expires, backend_id) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(signature), Some(expires), Some(backend_id)) => (signature, expires, backend_id),
+            _ => return Err(anyhow!("Malformed reconnect token.")),
+        };
+        let expires: i64 = expires.parse()?;
+
+        if !verify(secret, &Self::message(backend_id, expires), signature) {
+            return Err(anyhow!("Reconnect token signature is invalid."));
+        }
+
+        let expires = Utc.timestamp(expires, 0);
+        if expires < now {
+            return Err(anyhow!("Reconnect token has expired."));
+        }
+
+        Ok(ReconnectToken {
+            backend_id: BackendId::new(backend_id.to_string()),
+            expires,
+        })
+    }
+}
+
+fn session_message(backend_id: &BackendId, expires: i64) -> String {
+    format!("session:{}:{}", backend_id.id(), expires)
+}
+
+/// Value of the [SESSION_COOKIE] cookie for the given backend, which is
+/// valid until `expires`.
+#[must_use]
+pub fn session_token(backend_id: &BackendId, bearer_token: &str, expires: DateTime<Utc>) -> String {
+    let expires = expires.timestamp();
+    let signature = sign(bearer_token, &session_message(backend_id, expires));
+    format!("{}.{}", expires, signature)
+}
+
+/// Returns true if `token` is a session token for the given backend which
+/// has not expired.
+#[must_use]
+pub fn verify_session_token(
+    backend_id: &BackendId,
+    bearer_token: &str,
+    token: &str,
+    now: DateTime<Utc>,
+) -> bool {
+    let (expires, signature) = match token.split_once('.') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let expires: i64 = match expires.parse() {
+        Ok(expires) => expires,
+        Err(_) => return false,
+    };
+
+    verify(
+        bearer_token,
+        &session_message(backend_id, expires),
+        signature,
+    ) && now.timestamp() <= expires
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grant_round_trip() {
+        let backend_id = BackendId::new("foo".into());
+        let grant = AuthGrant::new(backend_id.clone(), Duration::from_secs(30));
+        let signed = grant.sign("my-token");
+
+        let verified = AuthGrant::verify(&signed, &backend_id, "my-token", Utc::now()).unwrap();
+        assert_eq!(grant.expires.timestamp(), verified.expires.timestamp());
+
+        assert!(AuthGrant::verify(&signed, &backend_id, "other-token", Utc::now()).is_err());
+        assert!(AuthGrant::verify(
+            &signed,
+            &BackendId::new("bar".into()),
+            "my-token",
+            Utc::now()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_grant_expires() {
+        let backend_id = BackendId::new("foo".into());
+        let signed = AuthGrant::new(backend_id.clone(), Duration::from_secs(30)).sign("my-token");
+
+        let later = Utc::now() + chrono::Duration::seconds(60);
+        assert!(AuthGrant::verify(&signed, &backend_id, "my-token", later).is_err());
+    }
+
+    #[test]
+    fn test_session_token() {
+        let backend_id = BackendId::new("foo".into());
+        let now = Utc::now();
+        let expires = now + chrono::Duration::seconds(30);
+        let token = session_token(&backend_id, "my-token", expires);
+
+        assert!(verify_session_token(&backend_id, "my-token", &token, now));
+        assert!(!verify_session_token(
+            &backend_id,
+            "other-token",
+            &token,
+            now
+        ));
+        assert!(!verify_session_token(
+            &BackendId::new("bar".into()),
+            "my-token",
+            &token,
+            now
+        ));
+
+        // The expiry can not be extended without the bearer token.
+        let later = now + chrono::Duration::seconds(60);
+        assert!(!verify_session_token(
+            &backend_id,
+            "my-token",
+            &token,
+            later
+        ));
+        let (_, signature) = token.split_once('.').unwrap();
+        let extended = format!("{}.{}", later.timestamp() + 60, signature);
+        assert!(!verify_session_token(
+            &backend_id,
+            "my-token",
+            &extended,
+            later
+        ));
+    }
+
+    #[test]
+    fn test_reconnect_token() {
+        let backend_id = BackendId::new("foo".into());
+        let signed =
+            ReconnectToken::new(backend_id.clone(), Duration::from_secs(30)).sign("secret");
+
+        let verified = ReconnectToken::verify(&signed, "secret", Utc::now()).unwrap();
+        assert_eq!(backend_id, verified.backend_id);
+
+        assert!(ReconnectToken::verify(&signed, "other-secret", Utc::now()).is_err());
+        assert!(
+            ReconnectToken::verify(&signed.replacen("foo", "bar", 1), "secret", Utc::now())
+                .is_err()
+        );
+        assert!(ReconnectToken::verify("foo", "secret", Utc::now()).is_err());
+
+        let later = Utc::now() + chrono::Duration::seconds(60);
+        assert!(ReconnectToken::verify(&signed, "secret", later).is_err());
+    }
+}
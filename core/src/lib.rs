@@ -1,11 +1,20 @@
 pub mod cli;
+pub mod diagnostics;
+pub mod error;
+pub mod grant;
 pub mod logging;
 pub mod messages;
 pub mod nats;
 pub mod nats_connection;
 pub mod retry;
+pub mod sealing;
+pub mod signing;
+pub mod streams;
+pub mod subjects;
 pub mod timing;
 pub mod types;
+pub mod version;
+pub mod watch;
 
 /// This is a stand-in for the “never” type until RFC 1216 is stabilized.
 /// Because it is not constructable, the compiler enforces that a function
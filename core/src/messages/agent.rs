@@ -1,21 +1,37 @@
 use crate::{
+    error::PlaneError,
     nats::{JetStreamable, NoReply, SubscribeSubject, TypedMessage},
-    types::{BackendId, ClusterName, DroneId},
+    subjects::{self, Any},
+    types::{BackendId, ClusterName, DroneId, ProgressId},
 };
 use anyhow::{anyhow, Error};
 #[cfg(feature = "bollard")]
-use bollard::{container::LogOutput, container::Stats};
+use bollard::{container::LogOutput, container::NetworkStats, container::Stats};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
-use serde_with::DurationSeconds;
+use serde_with::{DurationMicroSeconds, DurationSeconds};
 use std::{collections::HashMap, net::IpAddr, str::FromStr, time::Duration};
 
-#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Debug)]
+/// Credentials used to pull an image from a private registry. They are
+/// redacted from debug output, so that requests can be logged safely.
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum DockerCredentials {
     UsernamePassword { username: String, password: String },
 }
 
+impl std::fmt::Debug for DockerCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DockerCredentials::UsernamePassword { username, .. } => f
+                .debug_struct("UsernamePassword")
+                .field("username", username)
+                .field("password", &"<redacted>")
+                .finish(),
+        }
+    }
+}
+
 #[cfg(feature = "bollard")]
 impl From<&DockerCredentials> for bollard::auth::DockerCredentials {
     fn from(creds: &DockerCredentials) -> Self {
@@ -31,7 +47,7 @@ impl From<&DockerCredentials> for bollard::auth::DockerCredentials {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub enum DroneLogMessageKind {
     Stdout,
     Stderr,
@@ -48,7 +64,11 @@ impl TypedMessage for DroneLogMessage {
     type Response = NoReply;
 
     fn subject(&self) -> String {
-        format!("backend.{}.log", self.backend_id.id())
+        subjects::backend_log(&self.backend_id)
+    }
+
+    fn allow_compact_encoding() -> bool {
+        true
     }
 }
 
@@ -81,11 +101,11 @@ impl DroneLogMessage {
     }
 
     pub fn subscribe_subject(backend: &BackendId) -> SubscribeSubject<Self> {
-        SubscribeSubject::new(format!("backend.{}.log", backend))
+        SubscribeSubject::new(subjects::backend_log(backend))
     }
 
     pub fn wildcard_subject() -> SubscribeSubject<Self> {
-        SubscribeSubject::new("backend.*.log".into())
+        SubscribeSubject::new(subjects::backend_log(Any))
     }
 }
 
@@ -97,32 +117,121 @@ impl JetStreamable for DroneLogMessage {
     fn config() -> async_nats::jetstream::stream::Config {
         async_nats::jetstream::stream::Config {
             name: Self::stream_name().into(),
-            subjects: vec!["backend.*.log".into()],
+            subjects: vec![subjects::backend_log(Any)],
             ..async_nats::jetstream::stream::Config::default()
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BackendStatsMessage {
     backend_id: BackendId,
     /// Fraction of maximum CPU.
     pub cpu_use_percent: f64,
     /// Fraction of maximum memory.
     pub mem_use_percent: f64,
+
+    /// Bytes received over the network since the backend started, if the
+    /// engine reports it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_rx_bytes: Option<u64>,
+
+    /// Bytes sent over the network since the backend started, if the engine
+    /// reports it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_tx_bytes: Option<u64>,
+
+    /// Bytes received over the network since the previous sample.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_rx_bytes_delta: Option<u64>,
+
+    /// Bytes sent over the network since the previous sample.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_tx_bytes_delta: Option<u64>,
+
+    /// Bytes read from block devices since the previous sample.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_read_bytes_delta: Option<u64>,
+
+    /// Bytes written to block devices since the previous sample.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_write_bytes_delta: Option<u64>,
 }
 
 impl TypedMessage for BackendStatsMessage {
     type Response = NoReply;
 
     fn subject(&self) -> String {
-        format!("backend.{}.stats", self.backend_id.id())
+        subjects::backend_stats(&self.backend_id)
+    }
+
+    fn allow_compact_encoding() -> bool {
+        true
     }
 }
 
 impl BackendStatsMessage {
+    pub fn new(backend_id: &BackendId, cpu_use_percent: f64, mem_use_percent: f64) -> Self {
+        BackendStatsMessage {
+            backend_id: backend_id.clone(),
+            cpu_use_percent,
+            mem_use_percent,
+            network_rx_bytes: None,
+            network_tx_bytes: None,
+            network_rx_bytes_delta: None,
+            network_tx_bytes_delta: None,
+            block_read_bytes_delta: None,
+            block_write_bytes_delta: None,
+        }
+    }
+
+    /// This message, with the bytes read from and written to block devices
+    /// since the previous sample.
+    #[must_use]
+    pub fn with_block_io_deltas(
+        mut self,
+        read_bytes: Option<u64>,
+        write_bytes: Option<u64>,
+    ) -> Self {
+        self.block_read_bytes_delta = read_bytes;
+        self.block_write_bytes_delta = write_bytes;
+        self
+    }
+
     pub fn subscribe_subject(backend_id: &BackendId) -> SubscribeSubject<Self> {
-        SubscribeSubject::new(format!("backend.{}.stats", backend_id.id()))
+        SubscribeSubject::new(subjects::backend_stats(backend_id))
+    }
+}
+
+/// A request for the most recent stats of a backend, answered by the drone
+/// running it. Unlike [BackendStatsMessage], this does not require a
+/// subscription to be open when the stats are sampled.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatsRequest {
+    pub backend_id: BackendId,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatsResponse {
+    /// None if the drone has not yet sampled the backend.
+    pub stats: Option<BackendStatsMessage>,
+}
+
+impl TypedMessage for StatsRequest {
+    /// Fails with [PlaneError::NotFound] from the drone which ran the
+    /// backend, if it is no longer running. Drones which never ran the
+    /// backend do not respond.
+    type Response = Result<StatsResponse, PlaneError>;
+
+    fn subject(&self) -> String {
+        subjects::backend_stats_request(&self.backend_id)
+    }
+}
+
+impl StatsRequest {
+    #[must_use]
+    pub fn subscribe_subject() -> SubscribeSubject<StatsRequest> {
+        SubscribeSubject::new(subjects::backend_stats_request(Any))
     }
 }
 
@@ -172,17 +281,71 @@ impl BackendStatsMessage {
         //       the top bound makes that impossible
         let cpu_use_percent = (cpu_delta as f64 / sys_cpu_delta) * 100.0;
 
-        // TODO: implement disk stats from stream at
-        //       https://docs.docker.com/engine/api/v1.41/#tag/Container/operation/ContainerInspect
+        // network, summed over the container's interfaces
+        let network_rx_bytes = network_bytes(cur_stats_message, |network| network.rx_bytes);
+        let network_tx_bytes = network_bytes(cur_stats_message, |network| network.tx_bytes);
+        let prev_network_rx_bytes = network_bytes(prev_stats_message, |network| network.rx_bytes);
+        let prev_network_tx_bytes = network_bytes(prev_stats_message, |network| network.tx_bytes);
+
+        // block IO, summed over devices
+        let block_read_bytes = block_io_bytes(cur_stats_message, "read");
+        let block_write_bytes = block_io_bytes(cur_stats_message, "write");
 
         Ok(BackendStatsMessage {
             backend_id: backend_id.clone(),
             cpu_use_percent,
             mem_use_percent,
+            network_rx_bytes,
+            network_tx_bytes,
+            network_rx_bytes_delta: counter_delta(prev_network_rx_bytes, network_rx_bytes),
+            network_tx_bytes_delta: counter_delta(prev_network_tx_bytes, network_tx_bytes),
+            block_read_bytes_delta: counter_delta(
+                block_io_bytes(prev_stats_message, "read"),
+                block_read_bytes,
+            ),
+            block_write_bytes_delta: counter_delta(
+                block_io_bytes(prev_stats_message, "write"),
+                block_write_bytes,
+            ),
         })
     }
 }
 
+/// A network counter of a container, summed over its interfaces.
+#[cfg(feature = "bollard")]
+fn network_bytes(stats: &Stats, counter: impl Fn(&NetworkStats) -> u64) -> Option<u64> {
+    let networks = stats.networks.as_ref()?;
+    Some(networks.values().map(counter).sum())
+}
+
+/// Bytes read or written by a container, summed over block devices. cgroup
+/// v1 names the operations `Read` and `Write`, and cgroup v2 `read` and
+/// `write`.
+#[cfg(feature = "bollard")]
+fn block_io_bytes(stats: &Stats, op: &str) -> Option<u64> {
+    let entries = stats.blkio_stats.io_service_bytes_recursive.as_ref()?;
+    Some(
+        entries
+            .iter()
+            .filter(|entry| entry.op.eq_ignore_ascii_case(op))
+            .map(|entry| entry.value)
+            .sum(),
+    )
+}
+
+/// The increase of a counter between two samples, or `None` without both. A
+/// counter which went down, e.g. because the container was restarted, counts
+/// from zero.
+#[cfg(feature = "bollard")]
+fn counter_delta(previous: Option<u64>, current: Option<u64>) -> Option<u64> {
+    let (previous, current) = (previous?, current?);
+    if previous <= current {
+        Some(current - previous)
+    } else {
+        Some(current)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DroneStatusMessage {
     pub drone_id: DroneId,
@@ -197,6 +360,108 @@ pub struct DroneStatusMessage {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub running_backends: Option<u32>,
+
+    /// Number of further backends the drone accepts, if it is configured
+    /// with a maximum. The scheduler treats a drone with none left as not
+    /// ready.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining_capacity: Option<u32>,
+
+    /// Resource information about the drone's host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_metrics: Option<HostMetrics>,
+    /// PEM-encoded public key of the drone, to which secrets such as the
+    /// cluster's TLS private key are sealed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealing_key: Option<String>,
+
+    /// CPU architecture of the drone's host, as named by container image
+    /// platforms (see [normalize_arch]), e.g. `amd64` or `arm64`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+
+    /// Traffic through the drone's proxy, if the proxy runs alongside the
+    /// agent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_metrics: Option<ProxyMetrics>,
+
+    /// Whether the drone has been told to drain. A draining drone is also
+    /// not ready, but a drone may be not ready for other reasons, e.g. low
+    /// disk space.
+    #[serde(default)]
+    pub draining: bool,
+}
+
+/// The name container image platforms use for a CPU architecture, given
+/// either that name or the one Rust and `uname -m` use, e.g. `amd64` for
+/// `x86_64`.
+#[must_use]
+pub fn normalize_arch(arch: &str) -> String {
+    let arch = arch.to_lowercase();
+    match arch.as_str() {
+        "x86_64" | "x86-64" => "amd64".to_string(),
+        "aarch64" => "arm64".to_string(),
+        "x86" | "i386" | "i686" => "386".to_string(),
+        _ => arch,
+    }
+}
+
+/// Resource information about the host a drone runs on. Each value is
+/// omitted if the drone was unable to determine it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct HostMetrics {
+    /// Memory available for starting new processes, in bytes.
+    pub free_memory_bytes: Option<u64>,
+
+    /// One-minute load average.
+    pub load_average: Option<f64>,
+
+    /// Free space on the filesystem holding Docker's data directory, in bytes.
+    pub docker_disk_free_bytes: Option<u64>,
+
+    /// Images available locally on the drone.
+    #[serde(default)]
+    pub cached_images: Vec<String>,
+
+    /// Digests of the images available locally on the drone, as references
+    /// of the form `repository@sha256:...`, so that requests which pin an
+    /// image by digest can be matched to drones which have it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub image_digests: Vec<String>,
+
+    /// Disk space reclaimed by removing unused images since the drone
+    /// started, in bytes. Omitted if image garbage collection is not enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reclaimed_image_bytes: Option<u64>,
+
+    /// Memory used by the drone's backends in total, not counting the page
+    /// cache, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend_memory_bytes: Option<u64>,
+
+    /// Disk space used by the writable filesystems of the drone's backends
+    /// in total, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend_disk_bytes: Option<u64>,
+
+    /// Number of backends whose usage could not be measured, and so is
+    /// missing from the totals above.
+    #[serde(default)]
+    pub backend_usage_failures: u32,
+}
+
+/// Aggregate traffic through a drone's proxy, across all of its backends.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ProxyMetrics {
+    /// Client connections currently open to the proxy, including upgraded
+    /// connections.
+    pub open_connections: u32,
+
+    /// Requests served per second since the previous status message.
+    pub requests_per_second: f64,
+
+    /// Upgraded (e.g. WebSocket) connections currently open to backends.
+    pub websocket_connections: u32,
 }
 
 fn default_ready() -> bool {
@@ -207,7 +472,7 @@ impl TypedMessage for DroneStatusMessage {
     type Response = NoReply;
 
     fn subject(&self) -> String {
-        format!("drone.{}.status", self.drone_id.id())
+        subjects::drone_status(&self.drone_id)
     }
 }
 
@@ -215,7 +480,7 @@ impl JetStreamable for DroneStatusMessage {
     fn config() -> async_nats::jetstream::stream::Config {
         async_nats::jetstream::stream::Config {
             name: Self::stream_name().into(),
-            subjects: vec!["drone.*.status".into()],
+            subjects: vec![subjects::drone_status(Any)],
             max_messages_per_subject: 1,
             max_age: Duration::from_secs(5),
             ..async_nats::jetstream::stream::Config::default()
@@ -229,7 +494,7 @@ impl JetStreamable for DroneStatusMessage {
 
 impl DroneStatusMessage {
     pub fn subscribe_subject() -> SubscribeSubject<DroneStatusMessage> {
-        SubscribeSubject::new("drone.*.status".to_string())
+        SubscribeSubject::new(subjects::drone_status(Any))
     }
 }
 
@@ -250,13 +515,74 @@ impl TypedMessage for DroneConnectRequest {
     type Response = NoReply;
 
     fn subject(&self) -> String {
-        "drone.register".to_string()
+        subjects::drone_connect()
     }
 }
 
 impl DroneConnectRequest {
     pub fn subscribe_subject() -> SubscribeSubject<Self> {
-        SubscribeSubject::new("drone.register".to_string())
+        SubscribeSubject::new(subjects::drone_connect())
+    }
+}
+
+/// Facts about the host a drone runs on, which an operator can check before
+/// approving it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HostFacts {
+    /// Hostname of the machine, if it could be determined.
+    pub hostname: Option<String>,
+
+    /// The public-facing IP address of the drone.
+    pub ip: IpAddr,
+
+    pub drone_version: String,
+
+    /// Operating system and CPU architecture, e.g. `linux` and `x86_64`.
+    pub os: String,
+    pub arch: String,
+}
+
+/// Sent periodically by a drone configured with a registration key, asking
+/// to be admitted to its cluster. The request is signed with the key, so
+/// that the controller can check that the drone holds it.
+///
+/// In clusters which require approval, the scheduler ignores the status
+/// messages of a drone until its key has been approved.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RegisterDrone {
+    pub drone_id: DroneId,
+    pub cluster: ClusterName,
+
+    /// Base64-encoded Ed25519 public key of the drone's registration key.
+    pub public_key: String,
+
+    pub host: HostFacts,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum DroneRegistrationResponse {
+    /// The drone's key is approved, so the drone may be scheduled to.
+    Approved,
+
+    /// The drone is waiting for an operator to approve its key.
+    Pending,
+
+    /// The registration was not accepted, e.g. because it was not signed
+    /// with the key it names.
+    Rejected { reason: String },
+}
+
+impl TypedMessage for RegisterDrone {
+    type Response = DroneRegistrationResponse;
+
+    fn subject(&self) -> String {
+        subjects::drone_register(&self.cluster, &self.drone_id)
+    }
+}
+
+impl RegisterDrone {
+    pub fn subscribe_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::drone_register(Any, Any))
     }
 }
 
@@ -266,6 +592,12 @@ pub struct DockerExecutableConfig {
     pub image: String,
 
     /// Environment variables to pass in to the container.
+    ///
+    /// Values may contain the template variables `{{backend_id}}`,
+    /// `{{drone_id}}`, `{{cluster}}`, `{{backend_hostname}}` and
+    /// `{{backend_url}}`, which the drone expands before starting the
+    /// container. Any other `{{...}}` is passed through unchanged, so values
+    /// which use the same syntax for their own templates are not broken.
     pub env: HashMap<String, String>,
 
     /// Credentials used to fetch the image.
@@ -274,6 +606,29 @@ pub struct DockerExecutableConfig {
     /// Resource limits
     #[serde(default = "ResourceLimits::default")]
     pub resource_limits: ResourceLimits,
+
+    /// Outbound network access allowed to the container.
+    #[serde(default)]
+    pub egress_policy: EgressPolicy,
+}
+
+/// Outbound network access allowed to a backend. Inbound connections, and
+/// replies to them, are always allowed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum EgressPolicy {
+    #[default]
+    AllowAll,
+    DenyAll,
+
+    /// Only allow connections to the given CIDR blocks and hostnames.
+    /// Hostnames are resolved when the backend starts.
+    Allowlist {
+        #[serde(default)]
+        cidrs: Vec<String>,
+        #[serde(default)]
+        hostnames: Vec<String>,
+    },
 }
 
 #[serde_as]
@@ -282,9 +637,23 @@ pub struct SpawnRequest {
     pub drone_id: DroneId,
 
     /// The timeout after which the drone is shut down if no connections are made.
+    /// Zero means the backend is never shut down for being idle.
     #[serde_as(as = "DurationSeconds")]
     pub max_idle_secs: Duration,
 
+    /// If set, the backend is swept once it has existed for this long, even
+    /// if it is not idle.
+    #[serde_as(as = "Option<DurationSeconds>")]
+    #[serde(default)]
+    pub max_lifetime_secs: Option<Duration>,
+
+    /// How long the drone waits for the backend to accept requests on its
+    /// port before considering it to have failed to start. If not set, the
+    /// drone's default is used.
+    #[serde_as(as = "Option<DurationSeconds>")]
+    #[serde(default)]
+    pub port_ready_timeout_secs: Option<Duration>,
+
     /// The name of the backend. This forms part of the hostname used to
     /// connect to the drone.
     pub backend_id: BackendId,
@@ -296,12 +665,173 @@ pub struct SpawnRequest {
     pub executable: DockerExecutableConfig,
 
     /// If set, the proxy will check for the given bearer token in requests (as
-    /// a Bearer Authorization header, HTTP cookie, or query parameter) before
-    /// allowing requests through.
-    ///
-    /// NOT YET IMPLEMENTED.
+    /// a Bearer Authorization header, or a session cookie obtained by exchanging
+    /// a grant as described in [crate::grant]) before allowing requests through.
     #[serde(default)]
     pub bearer_token: Option<String>,
+
+    /// When the controller received the request to schedule this backend, if
+    /// it was scheduled by a controller. Used for the backend's [SpawnTimeline].
+    #[serde(default)]
+    pub requested_at: Option<DateTime<Utc>>,
+
+    /// If set, the backend is restored from the checkpoint of this name
+    /// instead of being started afresh. Set by drones migrating a backend
+    /// (see [MigrateBackend]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restore_checkpoint: Option<String>,
+
+    /// Which traffic through the proxy keeps the backend from being idle.
+    #[serde(default, skip_serializing_if = "IdlePolicy::is_default")]
+    pub idle_policy: IdlePolicy,
+
+    /// If set, the drone publishes [SpawnProgress] messages with this ID as
+    /// it spawns the backend.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress_id: Option<ProgressId>,
+
+    /// Logging and tracing settings passed to the backend as environment
+    /// variables.
+    #[serde(default, skip_serializing_if = "ObservabilityOptions::is_default")]
+    pub observability: ObservabilityOptions,
+
+    /// If true, the proxy also routes requests for `/backends/{backend}/` on
+    /// the cluster's root hostname to the backend. Set by the controller when
+    /// the backend's cluster is configured to address backends by path.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub path_routing: bool,
+
+    /// The backend's public URL, as configured for its cluster. Set by the
+    /// controller; if not provided, the drone assumes the default
+    /// `https://{backend}.{cluster}/`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend_url: Option<String>,
+}
+
+/// Logging and tracing settings for a backend, which the drone passes to it
+/// as the environment variables conventionally read by logging libraries and
+/// OpenTelemetry SDKs. Variables set explicitly in the backend's environment
+/// take precedence.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ObservabilityOptions {
+    /// Log level or filter, e.g. `debug` or `info,my_app=trace`, passed as
+    /// `RUST_LOG` and `LOG_LEVEL`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>,
+
+    /// OTLP endpoint traces are exported to, passed as
+    /// `OTEL_EXPORTER_OTLP_ENDPOINT`. The backend's ID and cluster are passed
+    /// as resource attributes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub otel_endpoint: Option<String>,
+
+    /// Fraction of traces to sample, between 0 and 1, passed as a
+    /// parent-based trace ID ratio sampler.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_sample_rate: Option<f64>,
+}
+
+// Sample rates are validated to be between 0 and 1 by the controller before
+// backends are scheduled, and by the drone before they are spawned, so are
+// never NaN where options are compared.
+impl Eq for ObservabilityOptions {}
+
+impl ObservabilityOptions {
+    #[must_use]
+    pub fn is_default(&self) -> bool {
+        *self == ObservabilityOptions::default()
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(rate) = self.trace_sample_rate {
+            // NaN is not contained in any range, so is rejected too.
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(anyhow!(
+                    "trace_sample_rate must be between 0 and 1, got {}.",
+                    rate
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The environment variables these options set for a backend.
+    pub fn env(
+        &self,
+        backend: &BackendId,
+        cluster: &ClusterName,
+    ) -> anyhow::Result<Vec<(&'static str, String)>> {
+        self.validate()?;
+        let mut env = Vec::new();
+
+        if let Some(log_level) = &self.log_level {
+            env.push(("RUST_LOG", log_level.clone()));
+            env.push(("LOG_LEVEL", log_level.clone()));
+        }
+
+        if let Some(otel_endpoint) = &self.otel_endpoint {
+            env.push(("OTEL_EXPORTER_OTLP_ENDPOINT", otel_endpoint.clone()));
+            env.push((
+                "OTEL_RESOURCE_ATTRIBUTES",
+                format!("plane.backend={},plane.cluster={}", backend, cluster),
+            ));
+        }
+
+        if let Some(rate) = self.trace_sample_rate {
+            env.push(("OTEL_TRACES_SAMPLER", "parentbased_traceidratio".into()));
+            env.push(("OTEL_TRACES_SAMPLER_ARG", rate.to_string()));
+        }
+
+        Ok(env)
+    }
+}
+
+/// Which traffic through the proxy counts as activity when deciding whether
+/// a backend is idle. By default, every request and open connection does.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct IdlePolicy {
+    /// If true, only WebSocket (and other upgraded) connections count as
+    /// activity, not plain HTTP requests.
+    #[serde(default)]
+    pub websockets_only: bool,
+
+    /// Requests to these paths, e.g. health checks, never count as activity.
+    #[serde(default)]
+    pub ignored_paths: Vec<String>,
+
+    /// If set, upgraded connections only count as activity once they have
+    /// been open this long, so that clients which connect briefly (e.g. to
+    /// check that the backend is up) do not keep it alive.
+    #[serde_as(as = "Option<DurationSeconds>")]
+    #[serde(default)]
+    pub min_connection_secs: Option<Duration>,
+}
+
+impl IdlePolicy {
+    #[must_use]
+    pub fn is_default(&self) -> bool {
+        *self == IdlePolicy::default()
+    }
+
+    /// Whether a request to `path` counts as activity when it is made.
+    /// Upgraded connections which are not ignored are also counted for as
+    /// long as they stay open, once `min_connection_secs` has passed.
+    #[must_use]
+    pub fn counts_request(&self, path: &str, is_upgrade: bool) -> bool {
+        if self.ignores_path(path) {
+            false
+        } else if is_upgrade {
+            self.min_connection_secs.is_none()
+        } else {
+            !self.websockets_only
+        }
+    }
+
+    #[must_use]
+    pub fn ignores_path(&self, path: &str) -> bool {
+        self.ignored_paths.iter().any(|ignored| ignored == path)
+    }
 }
 
 // eventually, this will be generic over executors
@@ -319,121 +849,697 @@ pub struct ResourceLimits {
     /// Total cpu time allocated to container    
     #[serde_as(as = "Option<DurationSeconds>")]
     pub cpu_time_limit: Option<Duration>,
+
+    /// Maximum memory available to the container, in bytes
+    #[serde(default)]
+    pub memory_limit_bytes: Option<i64>,
+
+    /// CPU time the container may use in each period, serializes as
+    /// microseconds. Takes precedence over `cpu_period_percent`.
+    #[serde_as(as = "Option<DurationMicroSeconds>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_quota: Option<Duration>,
+
+    /// Relative share of CPU time under contention, as in `docker run
+    /// --cpu-shares` (default 1024). On cgroup v2 hosts, this becomes the
+    /// container's `cpu.weight`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_shares: Option<u32>,
+
+    /// Relative share of block IO under contention, between 10 and 1000.
+    /// On cgroup v2 hosts, this becomes the container's `io.weight`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub io_weight: Option<u16>,
+
+    /// Maximum number of processes (and threads) in the container.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids_limit: Option<i64>,
+}
+
+impl ResourceLimits {
+    /// Period used when a quota is given without one, matching Docker's default.
+    pub const DEFAULT_CPU_PERIOD: Duration = Duration::from_millis(100);
+
+    /// The CPU time the container may use in each period, from either
+    /// `cpu_quota` or `cpu_period_percent`.
+    #[must_use]
+    pub fn effective_cpu_quota(&self) -> Option<Duration> {
+        if let Some(cpu_quota) = self.cpu_quota {
+            return Some(cpu_quota);
+        }
+
+        let cpu_period = self.cpu_period.unwrap_or(Self::DEFAULT_CPU_PERIOD);
+        self.cpu_period_percent
+            .and_then(|percent| cpu_period.saturating_mul(percent as u32).checked_div(100))
+    }
+
+    /// Check that each limit is within the range the kernel accepts, so that
+    /// a bad request fails with a useful reason rather than a runtime error.
+    pub fn validate(&self) -> Result<(), PlaneError> {
+        let invalid = |reason: String| Err(PlaneError::InvalidRequest { reason });
+
+        if let Some(cpu_period) = self.cpu_period {
+            if cpu_period < Duration::from_millis(1) || cpu_period > Duration::from_secs(1) {
+                return invalid(format!(
+                    "CPU period of {:?} must be between 1ms and 1s.",
+                    cpu_period
+                ));
+            }
+        }
+        if let Some(cpu_quota) = self.effective_cpu_quota() {
+            if cpu_quota < Duration::from_millis(1) {
+                return invalid(format!(
+                    "CPU quota of {:?} must be at least 1ms.",
+                    cpu_quota
+                ));
+            }
+        }
+        if let Some(cpu_shares) = self.cpu_shares {
+            if !(2..=262_144).contains(&cpu_shares) {
+                return invalid(format!(
+                    "CPU shares of {} must be between 2 and 262144.",
+                    cpu_shares
+                ));
+            }
+        }
+        if let Some(io_weight) = self.io_weight {
+            if !(10..=1000).contains(&io_weight) {
+                return invalid(format!(
+                    "IO weight of {} must be between 10 and 1000.",
+                    io_weight
+                ));
+            }
+        }
+        if let Some(pids_limit) = self.pids_limit {
+            if pids_limit < 1 {
+                return invalid(format!("PIDs limit of {} must be positive.", pids_limit));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl TypedMessage for SpawnRequest {
-    type Response = bool;
+    /// The drone responds once it has accepted the backend, or with the
+    /// reason it refuses it, e.g. [PlaneError::InsufficientDisk].
+    type Response = Result<(), PlaneError>;
 
     fn subject(&self) -> String {
-        format!("drone.{}.spawn", self.drone_id.id())
+        subjects::drone_spawn(&self.drone_id)
     }
 }
 
 impl SpawnRequest {
     pub fn subscribe_subject(drone_id: &DroneId) -> SubscribeSubject<Self> {
-        SubscribeSubject::new(format!("drone.{}.spawn", drone_id.id()))
+        SubscribeSubject::new(subjects::drone_spawn(drone_id))
     }
 }
 
 /// A message telling a drone to terminate a backend.
+#[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TerminationRequest {
     pub cluster_id: ClusterName,
     pub backend_id: BackendId,
+
+    /// If true, the proxy stops routing new requests to the backend, and the
+    /// backend is only stopped once in-flight requests and connections have
+    /// finished (or the grace period has elapsed).
+    #[serde(default)]
+    pub drain: bool,
+
+    /// Maximum time to wait for the backend to drain. Only used if `drain` is set.
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(default)]
+    pub grace_period_secs: Duration,
 }
 
 impl TypedMessage for TerminationRequest {
-    type Response = ();
+    /// Sent by the drone running the backend, once it has been signalled to
+    /// stop (or, if draining, once draining has begun).
+    type Response = Result<(), PlaneError>;
 
     fn subject(&self) -> String {
-        format!(
-            "cluster.{}.backend.{}.terminate",
-            self.cluster_id.subject_name(),
-            self.backend_id.id()
-        )
+        subjects::backend_terminate(&self.cluster_id, &self.backend_id)
     }
 }
 
 impl TerminationRequest {
     #[must_use]
     pub fn subscribe_subject(cluster: &ClusterName) -> SubscribeSubject<TerminationRequest> {
-        SubscribeSubject::new(format!(
-            "cluster.{}.backend.*.terminate",
-            cluster.subject_name()
-        ))
+        SubscribeSubject::new(subjects::backend_terminate(cluster, Any))
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
-pub enum BackendState {
-    /// The backend has been created, and the image is being fetched.
-    Loading,
-
-    /// A failure occured while loading the image.
-    ErrorLoading,
+/// A step in spawning a backend.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SpawnProgressEvent {
+    /// Bytes of the backend's image pulled so far, out of the total size of
+    /// the layers the drone has started pulling. The total grows as the
+    /// drone starts pulling more layers.
+    ImagePullProgress { pulled_bytes: u64, total_bytes: u64 },
+
+    /// The image is available and the backend's container has been created.
+    ContainerCreated,
+
+    /// The backend is accepting connections on its port. It is marked ready
+    /// once it is routable.
+    PortReady,
+}
 
-    /// The image has been fetched and is running, but is not yet listening
-    /// on a port.
-    Starting,
+/// Progress of a spawn, published by the drone spawning a backend whose
+/// request carried a `progress_id`, so that the caller can show it (e.g. as
+/// a progress bar). Progress messages are not persisted; the backend's
+/// [BackendStateMessage]s remain the record of whether it started.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SpawnProgress {
+    pub cluster_id: ClusterName,
+    pub backend_id: BackendId,
 
-    /// A failure occured while starting the container.
-    ErrorStarting,
+    /// Identifies the request being spawned, chosen by its caller.
+    pub progress_id: ProgressId,
 
-    /// The container is listening on the expected port.
-    Ready,
+    pub event: SpawnProgressEvent,
+}
 
-    /// A timeout occurred becfore the container was ready.
-    TimedOutBeforeReady,
+impl TypedMessage for SpawnProgress {
+    type Response = NoReply;
 
-    /// The container exited on its own initiative with a non-zero status.
-    Failed,
+    fn subject(&self) -> String {
+        subjects::backend_progress(&self.cluster_id, &self.backend_id, &self.progress_id)
+    }
+}
 
-    /// The container exited on its own initiative with a zero status.
-    Exited,
+impl SpawnProgress {
+    /// Subscribe to the progress of a request, whichever backend it spawns.
+    #[must_use]
+    pub fn subscribe_subject(
+        cluster: &ClusterName,
+        progress_id: &ProgressId,
+    ) -> SubscribeSubject<SpawnProgress> {
+        SubscribeSubject::new(subjects::backend_progress(cluster, Any, progress_id))
+    }
+}
 
-    /// The container was terminated because all connections were closed.
-    Swept,
+/// A message telling a drone to terminate every backend it runs, e.g. before
+/// its host is decommissioned. It must be signed with the key configured in
+/// the drone's `[agent.terminate_all]` section.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TerminateAllRequest {
+    pub cluster_id: ClusterName,
+    pub drone_id: DroneId,
 
-    /// The container was terminated through the API.
-    Terminated,
+    /// If provided, only backends in this state are terminated.
+    #[serde(default)]
+    pub state: Option<BackendState>,
 }
 
-impl FromStr for BackendState {
-    type Err = Error;
+impl TypedMessage for TerminateAllRequest {
+    /// The backends which were signalled to stop.
+    type Response = Result<Vec<BackendId>, PlaneError>;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "Loading" => Ok(BackendState::Loading),
-            "ErrorLoading" => Ok(BackendState::ErrorLoading),
-            "Starting" => Ok(BackendState::Starting),
-            "ErrorStarting" => Ok(BackendState::ErrorStarting),
-            "Ready" => Ok(BackendState::Ready),
-            "TimedOutBeforeReady" => Ok(BackendState::TimedOutBeforeReady),
-            "Failed" => Ok(BackendState::Failed),
-            "Exited" => Ok(BackendState::Exited),
-            "Swept" => Ok(BackendState::Swept),
-            "Terminated" => Ok(BackendState::Terminated),
-            _ => Err(anyhow!(
-                "The string {:?} does not describe a valid state.",
-                s
-            )),
-        }
+    fn subject(&self) -> String {
+        subjects::drone_terminate_all(&self.cluster_id, &self.drone_id)
     }
 }
 
-impl ToString for BackendState {
-    fn to_string(&self) -> String {
-        match self {
-            BackendState::Loading => "Loading".to_string(),
-            BackendState::ErrorLoading => "ErrorLoading".to_string(),
-            BackendState::Starting => "Starting".to_string(),
-            BackendState::ErrorStarting => "ErrorStarting".to_string(),
-            BackendState::Ready => "Ready".to_string(),
-            BackendState::TimedOutBeforeReady => "TimedOutBeforeReady".to_string(),
-            BackendState::Failed => "Failed".to_string(),
+impl TerminateAllRequest {
+    #[must_use]
+    pub fn subscribe_subject(
+        cluster: &ClusterName,
+        drone: &DroneId,
+    ) -> SubscribeSubject<TerminateAllRequest> {
+        SubscribeSubject::new(subjects::drone_terminate_all(cluster, drone))
+    }
+}
+
+/// An experimental request to move a running backend to another drone with
+/// its in-memory state intact. The drone running the backend checkpoints it
+/// with CRIU, stops it, and sends the target drone a [SpawnRequest] which
+/// restores it from the checkpoint, sealed to the target drone's key. Both
+/// drones need Docker's experimental checkpoint support and a shared
+/// checkpoint directory, and the request must be signed with the key the
+/// drone running the backend accepts for migration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MigrateBackend {
+    pub cluster_id: ClusterName,
+    pub backend_id: BackendId,
+
+    /// The drone to move the backend to.
+    pub target_drone: DroneId,
+}
+
+impl TypedMessage for MigrateBackend {
+    /// Sent by the drone running the backend, once the target drone has
+    /// accepted it.
+    type Response = Result<(), PlaneError>;
+
+    fn subject(&self) -> String {
+        subjects::backend_migrate(&self.cluster_id, &self.backend_id)
+    }
+}
+
+impl MigrateBackend {
+    #[must_use]
+    pub fn subscribe_subject(cluster: &ClusterName) -> SubscribeSubject<MigrateBackend> {
+        SubscribeSubject::new(subjects::backend_migrate(cluster, Any))
+    }
+}
+
+/// A request served by the drone proxy, published when access logging is enabled.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogMessage {
+    pub backend: BackendId,
+
+    /// HTTP method of the request.
+    pub method: String,
+
+    /// Path of the request, without the query string.
+    pub path: String,
+
+    /// Status code returned to the client.
+    pub status: u16,
+
+    /// Time from receiving the request to receiving the response headers
+    /// from the backend, in milliseconds.
+    pub latency_ms: u64,
+
+    /// Size of the response body, if the backend provided a Content-Length.
+    pub bytes: Option<u64>,
+
+    pub time: DateTime<Utc>,
+}
+
+impl TypedMessage for AccessLogMessage {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::backend_access(&self.backend)
+    }
+
+    fn allow_compact_encoding() -> bool {
+        true
+    }
+}
+
+impl AccessLogMessage {
+    pub fn subscribe_subject(backend_id: &BackendId) -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::backend_access(backend_id))
+    }
+
+    pub fn wildcard_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::backend_access(Any))
+    }
+}
+
+/// A message telling the drone running a backend to change its metadata,
+/// without restarting it. Only the drone running the backend responds.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateBackendMetadata {
+    pub cluster_id: ClusterName,
+    pub backend_id: BackendId,
+
+    /// Entries to set. An entry with an empty value removes that key.
+    pub metadata: HashMap<String, String>,
+}
+
+impl UpdateBackendMetadata {
+    /// Apply this update to a backend's existing metadata.
+    pub fn apply(&self, metadata: &mut HashMap<String, String>) {
+        for (key, value) in &self.metadata {
+            if value.is_empty() {
+                metadata.remove(key);
+            } else {
+                metadata.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+impl TypedMessage for UpdateBackendMetadata {
+    /// The backend's metadata after the update.
+    type Response = HashMap<String, String>;
+
+    fn subject(&self) -> String {
+        subjects::backend_update_metadata(&self.cluster_id, &self.backend_id)
+    }
+}
+
+impl UpdateBackendMetadata {
+    #[must_use]
+    pub fn subscribe_subject(cluster: &ClusterName) -> SubscribeSubject<UpdateBackendMetadata> {
+        SubscribeSubject::new(subjects::backend_update_metadata(cluster, Any))
+    }
+}
+
+/// Published by a drone when the metadata of one of its backends changes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackendMetadataMessage {
+    pub backend: BackendId,
+
+    /// The backend's full metadata after the change.
+    pub metadata: HashMap<String, String>,
+
+    pub time: DateTime<Utc>,
+}
+
+impl TypedMessage for BackendMetadataMessage {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::backend_metadata(&self.backend)
+    }
+}
+
+impl BackendMetadataMessage {
+    pub fn wildcard_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::backend_metadata(Any))
+    }
+}
+
+/// A message telling the drone running a backend to run a command inside it.
+/// Only the drone running the backend responds. Output is published as
+/// [ExecOutputMessage]s, so the requester should subscribe to
+/// [ExecOutputMessage::subscribe_subject] before sending the request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExecRequest {
+    pub cluster_id: ClusterName,
+    pub backend_id: BackendId,
+
+    /// Identifies the output of this command, chosen by the requester.
+    /// Must be a single subject token (see [crate::subjects::is_single_token]).
+    pub exec_id: String,
+
+    /// The command and its arguments.
+    pub command: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ExecResponse {
+    /// The command was started, and its output will be published.
+    Started,
+
+    /// The command could not be started.
+    Failed { reason: String },
+}
+
+impl TypedMessage for ExecRequest {
+    type Response = ExecResponse;
+
+    fn subject(&self) -> String {
+        subjects::backend_exec(&self.cluster_id, &self.backend_id)
+    }
+}
+
+impl ExecRequest {
+    #[must_use]
+    pub fn subscribe_subject(cluster: &ClusterName) -> SubscribeSubject<ExecRequest> {
+        SubscribeSubject::new(subjects::backend_exec(cluster, Any))
+    }
+}
+
+/// A piece of the output of a command run by an [ExecRequest].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ExecOutputChunk {
+    Stdout(String),
+    Stderr(String),
+
+    /// The command finished. This is always the last chunk.
+    Exit {
+        code: Option<i64>,
+    },
+}
+
+impl ExecOutputChunk {
+    #[cfg(feature = "bollard")]
+    #[must_use]
+    pub fn from_log_message(log_message: &LogOutput) -> Option<ExecOutputChunk> {
+        match log_message {
+            LogOutput::StdOut { message } | LogOutput::Console { message } => Some(
+                ExecOutputChunk::Stdout(String::from_utf8_lossy(message).to_string()),
+            ),
+            LogOutput::StdErr { message } => Some(ExecOutputChunk::Stderr(
+                String::from_utf8_lossy(message).to_string(),
+            )),
+            LogOutput::StdIn { message } => {
+                tracing::warn!(?message, "Unexpected stdin message.");
+                None
+            }
+        }
+    }
+}
+
+/// Published by a drone for each piece of output of a command run by an
+/// [ExecRequest].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExecOutputMessage {
+    pub cluster_id: ClusterName,
+    pub backend_id: BackendId,
+    pub exec_id: String,
+    pub chunk: ExecOutputChunk,
+}
+
+impl TypedMessage for ExecOutputMessage {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::backend_exec_output(&self.cluster_id, &self.backend_id, self.exec_id.as_str())
+    }
+}
+
+impl ExecOutputMessage {
+    #[must_use]
+    pub fn subscribe_subject(request: &ExecRequest) -> SubscribeSubject<ExecOutputMessage> {
+        SubscribeSubject::new(subjects::backend_exec_output(
+            &request.cluster_id,
+            &request.backend_id,
+            request.exec_id.as_str(),
+        ))
+    }
+}
+
+/// Serializes bytes as a base64 string, which is much more compact in JSON
+/// than an array of numbers.
+mod base64_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        base64::decode(encoded).map_err(D::Error::custom)
+    }
+}
+
+/// Size of a terminal, in characters.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TerminalSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+/// A message telling the drone running a backend to run a command inside it
+/// with a TTY, for interactive use. Only the drone running the backend
+/// responds, with an [ExecResponse].
+///
+/// The drone publishes the terminal's output as [AttachOutputMessage]s, so
+/// the requester should subscribe to [AttachOutputMessage::subscribe_subject]
+/// before sending the request. Once the command has started, the requester
+/// sends input as [AttachInputMessage]s.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttachRequest {
+    pub cluster_id: ClusterName,
+    pub backend_id: BackendId,
+
+    /// Identifies this session's input and output, chosen by the requester.
+    /// Must be a single subject token (see [crate::subjects::is_single_token]).
+    pub attach_id: String,
+
+    /// The command and its arguments, typically a shell.
+    pub command: Vec<String>,
+
+    /// Initial size of the requester's terminal.
+    pub size: TerminalSize,
+}
+
+impl TypedMessage for AttachRequest {
+    type Response = ExecResponse;
+
+    fn subject(&self) -> String {
+        subjects::backend_attach(&self.cluster_id, &self.backend_id)
+    }
+}
+
+impl AttachRequest {
+    #[must_use]
+    pub fn subscribe_subject(cluster: &ClusterName) -> SubscribeSubject<AttachRequest> {
+        SubscribeSubject::new(subjects::backend_attach(cluster, Any))
+    }
+}
+
+/// Input from the requester of an [AttachRequest].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum AttachInput {
+    /// Bytes typed into the terminal.
+    Data(#[serde(with = "base64_bytes")] Vec<u8>),
+
+    /// The requester's terminal was resized.
+    Resize(TerminalSize),
+
+    /// The requester detached. The command's stdin is closed.
+    Detach,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AttachInputMessage {
+    pub cluster_id: ClusterName,
+    pub backend_id: BackendId,
+    pub attach_id: String,
+    pub input: AttachInput,
+}
+
+impl TypedMessage for AttachInputMessage {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::backend_attach_input(&self.cluster_id, &self.backend_id, self.attach_id.as_str())
+    }
+}
+
+impl AttachInputMessage {
+    #[must_use]
+    pub fn subscribe_subject(request: &AttachRequest) -> SubscribeSubject<AttachInputMessage> {
+        SubscribeSubject::new(subjects::backend_attach_input(
+            &request.cluster_id,
+            &request.backend_id,
+            request.attach_id.as_str(),
+        ))
+    }
+}
+
+/// A piece of the output of a terminal opened by an [AttachRequest].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum AttachOutputChunk {
+    /// Bytes written to the terminal. These may split multi-byte characters
+    /// and escape sequences, so should be written to a terminal unchanged.
+    Data(#[serde(with = "base64_bytes")] Vec<u8>),
+
+    /// The command finished. This is always the last chunk.
+    Exit { code: Option<i64> },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AttachOutputMessage {
+    pub cluster_id: ClusterName,
+    pub backend_id: BackendId,
+    pub attach_id: String,
+    pub chunk: AttachOutputChunk,
+}
+
+impl TypedMessage for AttachOutputMessage {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::backend_attach_output(&self.cluster_id, &self.backend_id, self.attach_id.as_str())
+    }
+}
+
+impl AttachOutputMessage {
+    #[must_use]
+    pub fn subscribe_subject(request: &AttachRequest) -> SubscribeSubject<AttachOutputMessage> {
+        SubscribeSubject::new(subjects::backend_attach_output(
+            &request.cluster_id,
+            &request.backend_id,
+            request.attach_id.as_str(),
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendState {
+    /// The backend has been created, and the image is being fetched.
+    Loading,
+
+    /// A failure occured while loading the image.
+    ErrorLoading,
+
+    /// The image has been fetched and is running, but is not yet listening
+    /// on a port.
+    Starting,
+
+    /// A failure occured while starting the container.
+    ErrorStarting,
+
+    /// The container is listening on the expected port.
+    Ready,
+
+    /// A timeout occurred becfore the container was ready.
+    TimedOutBeforeReady,
+
+    /// The container exited on its own initiative with a non-zero status.
+    Failed,
+
+    /// The container was killed for exceeding its memory limit.
+    OutOfMemory,
+
+    /// The container exited on its own initiative with a zero status.
+    Exited,
+
+    /// The container was terminated because all connections were closed.
+    Swept,
+
+    /// The container was terminated through the API.
+    Terminated,
+
+    /// The backend's memory and process state were checkpointed and the
+    /// container stopped, so that it can be restored on another drone (see
+    /// [MigrateBackend]).
+    Checkpointed,
+}
+
+impl FromStr for BackendState {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Loading" => Ok(BackendState::Loading),
+            "ErrorLoading" => Ok(BackendState::ErrorLoading),
+            "Starting" => Ok(BackendState::Starting),
+            "ErrorStarting" => Ok(BackendState::ErrorStarting),
+            "Ready" => Ok(BackendState::Ready),
+            "TimedOutBeforeReady" => Ok(BackendState::TimedOutBeforeReady),
+            "Failed" => Ok(BackendState::Failed),
+            "OutOfMemory" => Ok(BackendState::OutOfMemory),
+            "Exited" => Ok(BackendState::Exited),
+            "Swept" => Ok(BackendState::Swept),
+            "Terminated" => Ok(BackendState::Terminated),
+            "Checkpointed" => Ok(BackendState::Checkpointed),
+            _ => Err(anyhow!(
+                "The string {:?} does not describe a valid state.",
+                s
+            )),
+        }
+    }
+}
+
+impl ToString for BackendState {
+    fn to_string(&self) -> String {
+        match self {
+            BackendState::Loading => "Loading".to_string(),
+            BackendState::ErrorLoading => "ErrorLoading".to_string(),
+            BackendState::Starting => "Starting".to_string(),
+            BackendState::ErrorStarting => "ErrorStarting".to_string(),
+            BackendState::Ready => "Ready".to_string(),
+            BackendState::TimedOutBeforeReady => "TimedOutBeforeReady".to_string(),
+            BackendState::Failed => "Failed".to_string(),
+            BackendState::OutOfMemory => "OutOfMemory".to_string(),
             BackendState::Exited => "Exited".to_string(),
             BackendState::Swept => "Swept".to_string(),
             BackendState::Terminated => "Terminated".to_string(),
+            BackendState::Checkpointed => "Checkpointed".to_string(),
         }
     }
 }
@@ -448,6 +1554,7 @@ impl BackendState {
                 | BackendState::ErrorStarting
                 | BackendState::TimedOutBeforeReady
                 | BackendState::Failed
+                | BackendState::OutOfMemory
                 | BackendState::Exited
                 | BackendState::Swept
                 | BackendState::Terminated
@@ -472,13 +1579,197 @@ pub struct BackendStateMessage {
 
     /// The time the state change was observed.
     pub time: DateTime<Utc>,
+
+    /// Why the backend entered this state, if it is an error state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    /// The kind of failure which put the backend in this state, if it is an
+    /// error state. Absent in messages from older drones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<PlaneError>,
+
+    /// Why the backend stopped, if this state is the result of it stopping.
+    /// Absent in messages from older drones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub termination: Option<TerminationReason>,
+
+    /// When the backend reached each step of being spawned, as of this state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeline: Option<SpawnTimeline>,
+
+    /// The drone running the backend. Absent in messages from older drones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub drone: Option<DroneId>,
+}
+
+/// What stopped a backend.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TerminatedBy {
+    /// The backend's process exited, was killed for exceeding its memory
+    /// limit, or asked the drone to stop it.
+    Backend,
+
+    /// The drone stopped the backend because it was idle.
+    IdleTimeout,
+
+    /// The drone stopped the backend because it reached its maximum lifetime.
+    MaxLifetime,
+
+    /// A [TerminationRequest] or [TerminateAllRequest], or the drone's admin
+    /// API.
+    Request,
+
+    /// The drone, e.g. because it was shutting down or the backend failed to
+    /// start.
+    Drone,
+
+    /// Something other than Plane, e.g. the container was removed by hand.
+    External,
+}
+
+impl std::fmt::Display for TerminatedBy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            TerminatedBy::Backend => "backend",
+            TerminatedBy::IdleTimeout => "idle timeout",
+            TerminatedBy::MaxLifetime => "maximum lifetime",
+            TerminatedBy::Request => "request",
+            TerminatedBy::Drone => "drone",
+            TerminatedBy::External => "external",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Why a backend stopped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TerminationReason {
+    pub terminated_by: TerminatedBy,
+
+    /// Exit code of the backend's process, if it exited on its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i64>,
+
+    /// Whether the backend was killed for exceeding its memory limit.
+    #[serde(default)]
+    pub oom_killed: bool,
+
+    /// What went wrong, if the backend was stopped because of an error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl TerminationReason {
+    #[must_use]
+    pub fn new(terminated_by: TerminatedBy) -> Self {
+        TerminationReason {
+            terminated_by,
+            exit_code: None,
+            oom_killed: false,
+            error: None,
+        }
+    }
+
+    #[must_use]
+    pub fn with_exit_code(mut self, exit_code: i64) -> Self {
+        self.exit_code = Some(exit_code);
+        self
+    }
+
+    #[must_use]
+    pub fn with_error(mut self, error: String) -> Self {
+        self.error = Some(error);
+        self
+    }
+}
+
+impl std::fmt::Display for TerminationReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "terminated by {}", self.terminated_by)?;
+        if let Some(exit_code) = self.exit_code {
+            write!(f, ", exit code {}", exit_code)?;
+        }
+        if self.oom_killed {
+            write!(f, ", out of memory")?;
+        }
+        if let Some(error) = &self.error {
+            write!(f, ": {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// When a backend reached each step of being spawned, for diagnosing slow
+/// cold starts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SpawnTimeline {
+    /// When the controller received the schedule request.
+    pub requested_at: Option<DateTime<Utc>>,
+
+    /// When the drone accepted the backend.
+    pub accepted_at: DateTime<Utc>,
+
+    /// When the backend finished loading (e.g. pulling its image) and began
+    /// starting.
+    pub loaded_at: Option<DateTime<Utc>>,
+
+    /// When the backend became ready to receive connections.
+    pub ready_at: Option<DateTime<Utc>>,
+}
+
+impl SpawnTimeline {
+    #[must_use]
+    pub fn new(requested_at: Option<DateTime<Utc>>, accepted_at: DateTime<Utc>) -> Self {
+        SpawnTimeline {
+            requested_at,
+            accepted_at,
+            loaded_at: None,
+            ready_at: None,
+        }
+    }
+
+    /// Record the first time the backend reached a state.
+    pub fn record(&mut self, state: BackendState, time: DateTime<Utc>) {
+        match state {
+            BackendState::Starting => {
+                self.loaded_at.get_or_insert(time);
+            }
+            BackendState::Ready => {
+                self.ready_at.get_or_insert(time);
+            }
+            _ => (),
+        }
+    }
+
+    /// The steps of the timeline, each with the time taken since the
+    /// previous step. Steps which have not been reached are omitted.
+    #[must_use]
+    pub fn steps(&self) -> Vec<(&'static str, chrono::Duration)> {
+        let times = [
+            ("accepted", Some(self.accepted_at)),
+            ("loaded", self.loaded_at),
+            ("ready", self.ready_at),
+        ];
+
+        let mut previous = self.requested_at.unwrap_or(self.accepted_at);
+        let mut steps = Vec::new();
+        for (name, time) in times {
+            if let Some(time) = time {
+                steps.push((name, time - previous));
+                previous = time;
+            }
+        }
+        steps
+    }
 }
 
 impl JetStreamable for BackendStateMessage {
     fn config() -> async_nats::jetstream::stream::Config {
         async_nats::jetstream::stream::Config {
             name: Self::stream_name().into(),
-            subjects: vec!["backend.*.status".into()],
+            subjects: vec![subjects::backend_status(Any)],
             ..async_nats::jetstream::stream::Config::default()
         }
     }
@@ -492,17 +1783,17 @@ impl TypedMessage for BackendStateMessage {
     type Response = NoReply;
 
     fn subject(&self) -> String {
-        format!("backend.{}.status", self.backend.id())
+        subjects::backend_status(&self.backend)
     }
 }
 
 impl BackendStateMessage {
     pub fn subscribe_subject(backend_id: &BackendId) -> SubscribeSubject<Self> {
-        SubscribeSubject::new(format!("backend.{}.status", backend_id.id()))
+        SubscribeSubject::new(subjects::backend_status(backend_id))
     }
 
     pub fn wildcard_subject() -> SubscribeSubject<Self> {
-        SubscribeSubject::new("backend.*.status".into())
+        SubscribeSubject::new(subjects::backend_status(Any))
     }
 }
 
@@ -514,6 +1805,285 @@ impl BackendStateMessage {
             state,
             backend,
             time: Utc::now(),
+            reason: None,
+            error: None,
+            termination: None,
+            timeline: None,
+            drone: None,
         }
     }
+
+    #[must_use]
+    pub fn with_drone(mut self, drone: DroneId) -> Self {
+        self.drone = Some(drone);
+        self
+    }
+
+    #[must_use]
+    pub fn with_timeline(mut self, timeline: Option<SpawnTimeline>) -> Self {
+        self.timeline = timeline;
+        self
+    }
+
+    #[must_use]
+    pub fn with_reason(mut self, reason: Option<String>) -> Self {
+        self.reason = reason;
+        self
+    }
+
+    #[must_use]
+    pub fn with_termination(mut self, termination: Option<TerminationReason>) -> Self {
+        self.termination = termination;
+        self
+    }
+
+    /// Set the error which put the backend in this state, and its message as
+    /// the reason.
+    #[must_use]
+    pub fn with_error(mut self, error: Option<PlaneError>) -> Self {
+        self.reason = error.as_ref().map(ToString::to_string);
+        self.error = error;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "bollard")]
+    #[test]
+    fn test_counter_delta() {
+        assert_eq!(Some(150), counter_delta(Some(100), Some(250)));
+        // Without a previous sample, the cumulative counter is not a delta.
+        assert_eq!(None, counter_delta(None, Some(250)));
+        // The counter was reset, e.g. by a container restart.
+        assert_eq!(Some(20), counter_delta(Some(100), Some(20)));
+        assert_eq!(None, counter_delta(Some(100), None));
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let limits = ResourceLimits {
+            cpu_period: Some(Duration::from_millis(200)),
+            cpu_period_percent: Some(50),
+            ..ResourceLimits::default()
+        };
+        assert_eq!(
+            Some(Duration::from_millis(100)),
+            limits.effective_cpu_quota()
+        );
+        assert!(limits.validate().is_ok());
+
+        let limits = ResourceLimits {
+            cpu_quota: Some(Duration::from_millis(20)),
+            ..limits
+        };
+        assert_eq!(
+            Some(Duration::from_millis(20)),
+            limits.effective_cpu_quota()
+        );
+
+        let invalid = [
+            ResourceLimits {
+                cpu_quota: Some(Duration::from_micros(500)),
+                ..ResourceLimits::default()
+            },
+            ResourceLimits {
+                cpu_shares: Some(1),
+                ..ResourceLimits::default()
+            },
+            ResourceLimits {
+                io_weight: Some(5000),
+                ..ResourceLimits::default()
+            },
+            ResourceLimits {
+                pids_limit: Some(0),
+                ..ResourceLimits::default()
+            },
+        ];
+        for limits in invalid {
+            assert!(matches!(
+                limits.validate(),
+                Err(PlaneError::InvalidRequest { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_apply_metadata_update() {
+        let mut metadata: HashMap<String, String> = [
+            ("owner".to_string(), "alice".to_string()),
+            ("incident".to_string(), "INC-1".to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        UpdateBackendMetadata {
+            cluster_id: ClusterName::new("mycluster.test"),
+            backend_id: BackendId::new("backend1".into()),
+            metadata: [
+                ("owner".to_string(), "bob".to_string()),
+                ("incident".to_string(), String::new()),
+            ]
+            .into_iter()
+            .collect(),
+        }
+        .apply(&mut metadata);
+
+        assert_eq!(1, metadata.len());
+        assert_eq!(Some("bob"), metadata.get("owner").map(String::as_str));
+    }
+
+    #[test]
+    fn test_exec_output_subject() {
+        let message = ExecOutputMessage {
+            cluster_id: ClusterName::new("mycluster.test"),
+            backend_id: BackendId::new("backend1".into()),
+            exec_id: "exec1".to_string(),
+            chunk: ExecOutputChunk::Exit { code: Some(0) },
+        };
+
+        assert_eq!(
+            "cluster.mycluster_test.backend.backend1.exec.exec1.output",
+            message.subject()
+        );
+    }
+
+    #[test]
+    fn test_checkpointed_state() {
+        let state = BackendState::Checkpointed;
+
+        assert_eq!(state, BackendState::from_str(&state.to_string()).unwrap());
+        // The backend lives on, on another drone.
+        assert!(!state.terminal());
+        assert!(!state.running());
+    }
+
+    #[test]
+    fn test_normalize_arch() {
+        assert_eq!("amd64", normalize_arch("x86_64"));
+        assert_eq!("amd64", normalize_arch("AMD64"));
+        assert_eq!("arm64", normalize_arch("aarch64"));
+        assert_eq!("arm64", normalize_arch("arm64"));
+        assert_eq!("riscv64", normalize_arch("riscv64"));
+    }
+
+    #[test]
+    fn test_idle_policy() {
+        let policy = IdlePolicy::default();
+        assert!(policy.counts_request("/", false));
+        assert!(policy.counts_request("/ws", true));
+
+        let policy = IdlePolicy {
+            websockets_only: true,
+            ignored_paths: vec!["/healthz".into()],
+            min_connection_secs: Some(Duration::from_secs(5)),
+        };
+        assert!(!policy.counts_request("/", false));
+        assert!(!policy.counts_request("/healthz", false));
+        // Counted only once it has been open long enough.
+        assert!(!policy.counts_request("/ws", true));
+        assert!(policy.ignores_path("/healthz"));
+        assert!(!policy.ignores_path("/ws"));
+    }
+
+    #[test]
+    fn test_attach_input_serialization() {
+        let input = AttachInput::Data(vec![0x1b, b'[', b'A', 0xff]);
+        let json = serde_json::to_string(&input).unwrap();
+
+        assert_eq!(r#"{"Data":"G1tB/w=="}"#, json);
+        assert_eq!(input, serde_json::from_str(&json).unwrap());
+    }
+
+    #[test]
+    fn test_spawn_timeline_steps() {
+        let requested_at = Utc::now();
+        let accepted_at = requested_at + chrono::Duration::milliseconds(100);
+        let mut timeline = SpawnTimeline::new(Some(requested_at), accepted_at);
+
+        assert_eq!(
+            vec![("accepted", chrono::Duration::milliseconds(100))],
+            timeline.steps()
+        );
+
+        timeline.record(BackendState::Loading, accepted_at);
+        timeline.record(
+            BackendState::Starting,
+            accepted_at + chrono::Duration::seconds(2),
+        );
+        timeline.record(
+            BackendState::Ready,
+            accepted_at + chrono::Duration::seconds(3),
+        );
+        // Only the first time a state is reached is recorded.
+        timeline.record(
+            BackendState::Ready,
+            accepted_at + chrono::Duration::seconds(9),
+        );
+
+        assert_eq!(
+            vec![
+                ("accepted", chrono::Duration::milliseconds(100)),
+                ("loaded", chrono::Duration::seconds(2)),
+                ("ready", chrono::Duration::seconds(1)),
+            ],
+            timeline.steps()
+        );
+    }
+
+    #[test]
+    fn test_credentials_redacted_from_debug() {
+        let credentials = DockerCredentials::UsernamePassword {
+            username: "jane".into(),
+            password: "hunter2".into(),
+        };
+        let debug = format!("{:?}", credentials);
+
+        assert!(debug.contains("jane"));
+        assert!(!debug.contains("hunter2"));
+    }
+
+    #[test]
+    fn test_observability_env() {
+        let backend = BackendId::new("backend1".into());
+        let cluster = ClusterName::new("plane.test");
+        assert!(ObservabilityOptions::default()
+            .env(&backend, &cluster)
+            .unwrap()
+            .is_empty());
+
+        let options = ObservabilityOptions {
+            log_level: Some("debug".into()),
+            otel_endpoint: Some("http://collector:4317".into()),
+            trace_sample_rate: Some(0.25),
+        };
+        let env: HashMap<_, _> = options
+            .env(&backend, &cluster)
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!("debug", env["RUST_LOG"]);
+        assert_eq!("debug", env["LOG_LEVEL"]);
+        assert_eq!("http://collector:4317", env["OTEL_EXPORTER_OTLP_ENDPOINT"]);
+        assert_eq!(
+            "plane.backend=backend1,plane.cluster=plane.test",
+            env["OTEL_RESOURCE_ATTRIBUTES"]
+        );
+        assert_eq!("parentbased_traceidratio", env["OTEL_TRACES_SAMPLER"]);
+        assert_eq!("0.25", env["OTEL_TRACES_SAMPLER_ARG"]);
+
+        let options = ObservabilityOptions {
+            trace_sample_rate: Some(1.5),
+            ..ObservabilityOptions::default()
+        };
+        assert!(options.env(&backend, &cluster).is_err());
+
+        let options = ObservabilityOptions {
+            trace_sample_rate: Some(f64::NAN),
+            ..ObservabilityOptions::default()
+        };
+        assert!(options.validate().is_err());
+    }
 }
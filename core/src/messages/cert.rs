@@ -1,8 +1,11 @@
 use crate::{
-    nats::{SubscribeSubject, TypedMessage},
-    types::ClusterName,
+    nats::{NoReply, SubscribeSubject, TypedMessage},
+    sealing::SealedData,
+    subjects,
+    types::{ClusterName, DroneId},
 };
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 /// A request from the drone to the DNS server telling it to set
 /// a TXT record on the given domain with the given value.
@@ -16,12 +19,40 @@ impl TypedMessage for SetAcmeDnsRecord {
     type Response = bool;
 
     fn subject(&self) -> String {
-        "acme.set_dns_record".to_string()
+        subjects::acme_set_dns_record()
     }
 }
 
 impl SetAcmeDnsRecord {
     pub fn subscribe_subject() -> SubscribeSubject<Self> {
-        SubscribeSubject::new("acme.set_dns_record".to_string())
+        SubscribeSubject::new(subjects::acme_set_dns_record())
+    }
+}
+
+/// A TLS certificate for a cluster, pushed by the controller to every drone
+/// in the cluster. The certificate chain is public, but the private key is
+/// sealed separately to the key advertised by each drone.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CertificateUpdate {
+    pub cluster: ClusterName,
+
+    /// PEM-encoded certificate chain.
+    pub cert_pem: String,
+
+    /// PEM-encoded private key of the certificate, sealed to each drone.
+    pub keys: HashMap<DroneId, SealedData>,
+}
+
+impl TypedMessage for CertificateUpdate {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::cluster_certificate(&self.cluster)
+    }
+}
+
+impl CertificateUpdate {
+    pub fn subscribe_subject(cluster: &ClusterName) -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::cluster_certificate(cluster))
     }
 }
@@ -1,5 +1,6 @@
 use crate::{
     nats::{JetStreamable, NoReply, SubscribeSubject, TypedMessage},
+    subjects::{self, Any},
     types::ClusterName,
 };
 use serde::{Deserialize, Serialize};
@@ -12,6 +13,7 @@ const TTL_BUFFER_SECONDS: u64 = 10;
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum DnsRecordType {
     A,
+    AAAA,
     TXT,
 }
 
@@ -33,7 +35,7 @@ impl TypedMessage for SetDnsRecord {
     type Response = NoReply;
 
     fn subject(&self) -> String {
-        format!("cluster.{}.dns.{}", self.cluster.subject_name(), self.kind)
+        subjects::cluster_dns(&self.cluster, &self.kind)
     }
 }
 
@@ -41,7 +43,7 @@ impl JetStreamable for SetDnsRecord {
     fn config() -> async_nats::jetstream::stream::Config {
         async_nats::jetstream::stream::Config {
             name: Self::stream_name().into(),
-            subjects: vec!["cluster.*.dns.*".into()],
+            subjects: vec![subjects::cluster_dns(Any, Any)],
             max_age: Duration::from_secs(Self::ttl_seconds()),
             ..async_nats::jetstream::stream::Config::default()
         }
@@ -54,7 +56,7 @@ impl JetStreamable for SetDnsRecord {
 
 impl SetDnsRecord {
     pub fn subscribe_subject() -> SubscribeSubject<Self> {
-        SubscribeSubject::new("cluster.*.dns.*".into())
+        SubscribeSubject::new(subjects::cluster_dns(Any, Any))
     }
 
     fn ttl_seconds() -> u64 {
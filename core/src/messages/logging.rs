@@ -1,4 +1,5 @@
 use crate::nats::{NoReply, TypedMessage};
+use crate::subjects;
 use crate::types::DroneId;
 use anyhow::Result;
 use chrono::{DateTime, Utc};
@@ -55,8 +56,8 @@ impl TypedMessage for LogMessage {
 
     fn subject(&self) -> String {
         match &self.component {
-            Component::Controller => "logs.controller".into(),
-            Component::Drone { drone_id } => format!("logs.drone.{}", drone_id.id()),
+            Component::Controller => subjects::controller_logs(),
+            Component::Drone { drone_id } => subjects::drone_logs(drone_id),
         }
     }
 }
@@ -1,12 +1,18 @@
-use super::agent::{DockerExecutableConfig, SpawnRequest};
+use super::agent::{
+    BackendState, DockerExecutableConfig, IdlePolicy, ObservabilityOptions, ResourceLimits,
+    SpawnRequest, TerminationRequest,
+};
 use crate::{
-    nats::{SubscribeSubject, TypedMessage},
-    types::{BackendId, ClusterName, DroneId},
+    error::PlaneError,
+    nats::{JetStreamable, NoReply, SubscribeSubject, TypedMessage},
+    subjects::{self, Any},
+    types::{BackendGroupId, BackendId, ClusterName, DroneId, ProgressId},
 };
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
-use serde_with::DurationSeconds;
-use std::{collections::HashMap, time::Duration};
+use serde_with::{DurationMilliSeconds, DurationSeconds};
+use std::{collections::HashMap, fmt::Display, time::Duration};
 
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -14,12 +20,30 @@ pub struct ScheduleRequest {
     pub cluster: ClusterName,
 
     /// The name of the backend. This forms part of the hostname used to
-    /// connect to the drone.
+    /// connect to the drone. If provided, it must be a valid hostname label
+    /// which is not in use in the cluster; otherwise one is generated.
     pub backend_id: Option<BackendId>,
 
     /// The timeout after which the drone is shut down if no connections are made.
-    #[serde_as(as = "DurationSeconds")]
-    pub max_idle_secs: Duration,
+    /// If not provided, the cluster's default is used. The controller clamps it
+    /// to the bounds configured for the cluster. Zero means the backend is never
+    /// swept for being idle, if the cluster allows it.
+    #[serde_as(as = "Option<DurationSeconds>")]
+    #[serde(default)]
+    pub max_idle_secs: Option<Duration>,
+
+    /// If provided, the backend is swept once it has existed for this long,
+    /// regardless of activity.
+    #[serde_as(as = "Option<DurationSeconds>")]
+    #[serde(default)]
+    pub max_lifetime_secs: Option<Duration>,
+
+    /// How long the drone waits for the backend to accept requests on its
+    /// port before considering it to have failed to start. If not provided,
+    /// the drone's default is used.
+    #[serde_as(as = "Option<DurationSeconds>")]
+    #[serde(default)]
+    pub port_ready_timeout_secs: Option<Duration>,
 
     /// Metadata for the spawn. Typically added to log messages for debugging and observability.
     pub metadata: HashMap<String, String>,
@@ -27,28 +51,104 @@ pub struct ScheduleRequest {
     /// Configuration for docker run (image, creds, env vars etc.)
     pub executable: DockerExecutableConfig,
 
+    /// If true, the scheduler generates a bearer token which must accompany
+    /// requests to the backend. See [crate::grant] for exchanging it for a
+    /// browser session.
     #[serde(default)]
     pub require_bearer_token: bool,
+
+    /// Replica group this backend belongs to, if any. The controller publishes
+    /// aggregated status for each group as a [BackendGroupStatusMessage].
+    #[serde(default)]
+    pub group: Option<BackendGroupId>,
+
+    /// If true, the controller runs every check it would run before
+    /// scheduling the backend and chooses a drone for it, but does not spawn
+    /// it. It responds with [ScheduleResponse::DryRun] instead.
+    #[serde(default)]
+    pub dry_run: bool,
+
+    /// If provided, the controller stops waiting for the chosen drone to
+    /// accept the backend once this long has passed since it received the
+    /// request, and responds with [PlaneError::ScheduleDeadlineExceeded].
+    /// This bounds the latency of spawning for interactive callers. If the
+    /// drone accepts the backend afterwards, the controller terminates it.
+    #[serde_as(as = "Option<DurationMilliSeconds>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule_deadline_ms: Option<Duration>,
+
+    /// Which traffic through the proxy keeps the backend from being idle.
+    #[serde(default, skip_serializing_if = "IdlePolicy::is_default")]
+    pub idle_policy: IdlePolicy,
+
+    /// If provided, the CPU architecture the image is built for, e.g.
+    /// `amd64` or `arm64`. The backend is only placed on drones which report
+    /// this architecture (or none, if they predate reporting it), so that it
+    /// does not fail to start with an exec format error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+
+    /// If provided, the drone which spawns the backend publishes
+    /// [crate::messages::agent::SpawnProgress] messages with this ID, e.g. as
+    /// its image is pulled. Should be unique, e.g. a UUID, and must be a
+    /// single subject token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress_id: Option<ProgressId>,
+
+    /// If provided, the backend is placed on the same drone as the other
+    /// live backends of this group in the cluster, e.g. so that a game
+    /// server and its voice relay share fast local networking.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub affinity_group: Option<AffinityGroup>,
+
+    /// Logging and tracing settings passed to the backend as environment
+    /// variables.
+    #[serde(default, skip_serializing_if = "ObservabilityOptions::is_default")]
+    pub observability: ObservabilityOptions,
+}
+
+/// Backends which should be placed on the same drone.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AffinityGroup {
+    /// Name of the group, unique within the cluster.
+    pub name: String,
+
+    /// If true, scheduling fails with [PlaneError::NoDroneAvailable] when the
+    /// drone running the group's other backends cannot take this one. If
+    /// false, the backend is placed elsewhere instead.
+    #[serde(default)]
+    pub strict: bool,
 }
 
 impl ScheduleRequest {
-    pub fn schedule(&self, drone_id: &DroneId) -> SpawnRequest {
+    pub fn schedule(&self, drone_id: &DroneId, max_idle_secs: Duration) -> SpawnRequest {
         let backend_id = self
             .backend_id
             .clone()
             .unwrap_or_else(BackendId::new_random);
 
-        if self.require_bearer_token {
-            tracing::warn!("Scheduler received request with auth_token, which is not yet implemented. Ignoring.");
-        }
+        let bearer_token = if self.require_bearer_token {
+            Some(uuid::Uuid::new_v4().to_string())
+        } else {
+            None
+        };
 
         SpawnRequest {
             drone_id: drone_id.clone(),
             backend_id,
-            max_idle_secs: self.max_idle_secs,
+            max_idle_secs,
+            max_lifetime_secs: self.max_lifetime_secs,
+            port_ready_timeout_secs: self.port_ready_timeout_secs,
             metadata: self.metadata.clone(),
             executable: self.executable.clone(),
-            bearer_token: None,
+            bearer_token,
+            requested_at: Some(Utc::now()),
+            restore_checkpoint: None,
+            idle_policy: self.idle_policy.clone(),
+            progress_id: self.progress_id.clone(),
+            observability: self.observability.clone(),
+            path_routing: false,
+            backend_url: None,
         }
     }
 }
@@ -60,21 +160,241 @@ pub enum ScheduleResponse {
         backend_id: BackendId,
         #[serde(skip_serializing_if = "Option::is_none")]
         bearer_token: Option<String>,
+
+        /// URL of the backend, as configured for its cluster. Absent in
+        /// responses from older controllers.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        url: Option<String>,
     },
-    NoDroneAvailable,
+
+    /// The request was a dry run which passed every check. Nothing was
+    /// spawned.
+    DryRun {
+        /// The drone the backend would have been scheduled to.
+        drone: DroneId,
+
+        /// The request which would have been sent to the drone, with the
+        /// cluster's idle timeout and resource limits applied. If the schedule
+        /// request did not name the backend, the ID in it is a placeholder.
+        spawn_request: Box<SpawnRequest>,
+    },
+
+    /// The backend was not scheduled. Schedule requests which could not be
+    /// placed fail with [PlaneError::NoDroneAvailable].
+    Error(PlaneError),
+
+    /// The controller is receiving more schedule requests than it is
+    /// configured to accept, so the request was not considered. It may be
+    /// retried after the given time.
+    Throttled { retry_after_ms: u64 },
 }
 
 impl TypedMessage for ScheduleRequest {
     type Response = ScheduleResponse;
 
     fn subject(&self) -> String {
-        format!("cluster.{}.schedule", self.cluster.subject_name())
+        subjects::cluster_schedule(&self.cluster)
+    }
+
+    fn authenticated_by_controller() -> bool {
+        true
     }
 }
 
 impl ScheduleRequest {
     pub fn subscribe_subject() -> SubscribeSubject<Self> {
-        SubscribeSubject::new("cluster.*.schedule".into())
+        SubscribeSubject::new(subjects::cluster_schedule(Any))
+    }
+}
+
+/// Request to schedule several identical backends in one round-trip. The
+/// controller spreads them across the drones of the cluster.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchScheduleRequest {
+    /// The backend to schedule. Each backend in the batch gets a random ID,
+    /// so `backend_id` must not be set.
+    pub request: ScheduleRequest,
+
+    /// Number of backends to schedule.
+    pub count: u32,
+}
+
+impl TypedMessage for BatchScheduleRequest {
+    /// One response for each backend in the batch, in order.
+    type Response = Vec<ScheduleResponse>;
+
+    fn subject(&self) -> String {
+        subjects::cluster_schedule_batch(&self.request.cluster)
+    }
+
+    fn authenticated_by_controller() -> bool {
+        true
+    }
+}
+
+impl BatchScheduleRequest {
+    pub fn subscribe_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::cluster_schedule_batch(Any))
+    }
+}
+
+/// Aggregated status of the backends in a replica group, published by the
+/// controller whenever the state of one of its members changes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackendGroupStatusMessage {
+    pub cluster: ClusterName,
+    pub group: BackendGroupId,
+
+    /// Number of members which are ready to accept connections.
+    pub ready: u32,
+
+    /// Number of members which are still loading or starting.
+    pub pending: u32,
+
+    /// Number of members which failed to start or exited with an error.
+    pub failed: u32,
+
+    /// URLs of the members which are ready, as returned when each was
+    /// scheduled.
+    pub endpoints: Vec<String>,
+
+    pub time: DateTime<Utc>,
+}
+
+impl TypedMessage for BackendGroupStatusMessage {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::cluster_group_status(&self.cluster, &self.group)
+    }
+}
+
+impl BackendGroupStatusMessage {
+    pub fn subscribe_subject(
+        cluster: &ClusterName,
+        group: &BackendGroupId,
+    ) -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::cluster_group_status(cluster, group))
+    }
+}
+
+/// Request for per-image statistics collected by the controller since it started.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImageStatsRequest {
+    /// If provided, only return statistics for this image.
+    pub image: Option<String>,
+}
+
+/// Outcomes of the backends scheduled with an image.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageStats {
+    pub image: String,
+
+    /// Number of backends scheduled with this image.
+    pub spawned: u64,
+
+    /// Number of backends which became ready.
+    pub ready: u64,
+
+    /// Number of backends which failed to start or exited with an error.
+    pub failed: u64,
+
+    /// Number of backends which were shut down for being idle.
+    pub swept: u64,
+
+    /// Number of backends which were terminated through the API.
+    pub terminated: u64,
+
+    /// Number of backends which exited on their own without an error.
+    pub exited: u64,
+
+    /// Mean time from scheduling to ready.
+    pub mean_cold_start_ms: Option<u64>,
+
+    /// Mean time from scheduling to a terminal state.
+    pub mean_lifetime_secs: Option<u64>,
+}
+
+impl ImageStats {
+    /// Fraction of finished backends which failed.
+    #[must_use]
+    pub fn failure_rate(&self) -> Option<f64> {
+        let finished = self.failed + self.swept + self.terminated + self.exited;
+        (finished > 0).then(|| self.failed as f64 / finished as f64)
+    }
+}
+
+impl TypedMessage for ImageStatsRequest {
+    type Response = Vec<ImageStats>;
+
+    fn subject(&self) -> String {
+        subjects::scheduler_image_stats()
+    }
+
+    fn authenticated_by_controller() -> bool {
+        true
+    }
+}
+
+impl ImageStatsRequest {
+    pub fn subscribe_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::scheduler_image_stats())
+    }
+}
+
+/// Message sent to a drone to tell it to pull an image ahead of time. If the
+/// drone is configured with a pull-through cache, this also seeds the cache
+/// for every other drone that shares it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SeedImage {
+    pub drone: DroneId,
+    pub cluster: ClusterName,
+    pub image: String,
+}
+
+impl TypedMessage for SeedImage {
+    type Response = ();
+
+    fn subject(&self) -> String {
+        subjects::drone_seed_image(&self.cluster, &self.drone)
+    }
+}
+
+impl SeedImage {
+    pub fn subscribe_subject(drone: &DroneId, cluster: &ClusterName) -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::drone_seed_image(cluster, drone))
+    }
+}
+
+/// The drone version the controller wants a cluster's drones to run, and
+/// where to download it. Published periodically, so that drones which start
+/// later also see it. Drones configured to update themselves download the
+/// binary, check its signature, drain briefly, and re-exec into it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DroneUpdate {
+    pub cluster: ClusterName,
+
+    /// Version of the drone binary, as reported in drone status messages.
+    pub version: String,
+
+    /// URL to download the drone binary from.
+    pub artifact_url: String,
+
+    /// Signature of the binary, as made by `plane-cli sign-artifact`.
+    pub signature: String,
+}
+
+impl TypedMessage for DroneUpdate {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::cluster_drone_update(&self.cluster)
+    }
+}
+
+impl DroneUpdate {
+    pub fn subscribe_subject(cluster: &ClusterName) -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::cluster_drone_update(cluster))
     }
 }
 
@@ -90,20 +410,797 @@ impl TypedMessage for DrainDrone {
     type Response = ();
 
     fn subject(&self) -> String {
-        format!(
-            "cluster.{}.drone.{}.drain",
-            self.cluster.subject_name(),
-            self.drone.id()
-        )
+        subjects::drone_drain(&self.cluster, &self.drone)
     }
 }
 
 impl DrainDrone {
     pub fn subscribe_subject(drone: DroneId, cluster: ClusterName) -> SubscribeSubject<Self> {
-        SubscribeSubject::new(format!(
-            "cluster.{}.drone.{}.drain",
-            cluster.subject_name(),
-            drone.id()
-        ))
+        SubscribeSubject::new(subjects::drone_drain(&cluster, &drone))
+    }
+}
+
+impl DrainDrone {
+    pub fn wildcard_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::drone_drain(Any, Any))
+    }
+}
+
+/// Approve the registration key of a drone which has registered with a
+/// cluster, so that it (and any later drone with the same key) may be
+/// scheduled to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApproveDrone {
+    pub drone: DroneId,
+    pub cluster: ClusterName,
+
+    /// Fingerprint of the registration key being approved, as logged by the
+    /// drone and the controller. The approval is refused if the drone has
+    /// since registered with another key.
+    pub key_fingerprint: String,
+}
+
+impl TypedMessage for ApproveDrone {
+    type Response = Result<(), PlaneError>;
+
+    fn subject(&self) -> String {
+        subjects::drone_approve(&self.cluster, &self.drone)
+    }
+
+    fn authenticated_by_controller() -> bool {
+        true
+    }
+}
+
+impl ApproveDrone {
+    pub fn subscribe_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::drone_approve(Any, Any))
+    }
+}
+
+/// Published to JetStream by the controller when a drone's registration key
+/// is approved, so that every controller, including ones started later,
+/// admits drones with the key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DroneApproval {
+    pub cluster: ClusterName,
+
+    /// Base64-encoded Ed25519 public key which is approved.
+    pub public_key: String,
+
+    /// The drone whose registration was approved.
+    pub drone: DroneId,
+
+    pub time: DateTime<Utc>,
+}
+
+impl TypedMessage for DroneApproval {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::cluster_drone_approval(&self.cluster, self.public_key.as_str())
+    }
+}
+
+impl JetStreamable for DroneApproval {
+    fn config() -> async_nats::jetstream::stream::Config {
+        async_nats::jetstream::stream::Config {
+            name: Self::stream_name().into(),
+            subjects: vec![subjects::cluster_drone_approval(Any, Any)],
+            max_messages_per_subject: 1,
+            ..async_nats::jetstream::stream::Config::default()
+        }
+    }
+
+    fn stream_name() -> &'static str {
+        "drone_approval"
+    }
+}
+
+impl DroneApproval {
+    pub fn subscribe_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::cluster_drone_approval(Any, Any))
+    }
+}
+
+/// Published to JetStream by the controller when it places a backend in an
+/// affinity group, so that a controller started later places the group's
+/// other backends with it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AffinityMember {
+    pub cluster: ClusterName,
+
+    /// Name of the affinity group.
+    pub group: String,
+
+    pub backend: BackendId,
+
+    /// The drone the backend was placed on.
+    pub drone: DroneId,
+
+    pub time: DateTime<Utc>,
+}
+
+impl TypedMessage for AffinityMember {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::cluster_affinity_member(&self.cluster, &self.backend)
+    }
+}
+
+impl JetStreamable for AffinityMember {
+    fn config() -> async_nats::jetstream::stream::Config {
+        async_nats::jetstream::stream::Config {
+            name: Self::stream_name().into(),
+            subjects: vec![subjects::cluster_affinity_member(Any, Any)],
+            max_messages_per_subject: 1,
+            ..async_nats::jetstream::stream::Config::default()
+        }
+    }
+
+    fn stream_name() -> &'static str {
+        "affinity_member"
+    }
+}
+
+impl AffinityMember {
+    pub fn subscribe_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::cluster_affinity_member(Any, Any))
+    }
+}
+
+/// A change in a drone's lifecycle, as observed by the controller.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DroneLifecycleEvent {
+    /// The drone sent its first status message, or its first since it was lost.
+    DroneConnected,
+
+    /// The drone stopped sending status messages.
+    DroneLost,
+
+    /// The drone stopped accepting new backends.
+    DroneDraining,
+
+    /// A draining drone has no backends left running.
+    DroneDrained,
+}
+
+/// Message published by the controller when a drone's lifecycle changes, so
+/// that consumers don't need to infer it from drone status messages.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DroneLifecycleMessage {
+    pub cluster: ClusterName,
+    pub drone: DroneId,
+    pub event: DroneLifecycleEvent,
+    pub time: DateTime<Utc>,
+}
+
+impl TypedMessage for DroneLifecycleMessage {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::drone_lifecycle(&self.cluster, &self.drone)
+    }
+}
+
+impl DroneLifecycleMessage {
+    pub fn subscribe_subject(cluster: &ClusterName) -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::drone_lifecycle(cluster, Any))
+    }
+
+    pub fn wildcard_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::drone_lifecycle(Any, Any))
+    }
+}
+
+/// A period during which a drone takes itself out of service, e.g. for
+/// host maintenance.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+    /// The time at which the drone stops accepting new backends.
+    pub start: DateTime<Utc>,
+
+    /// How long the window lasts, after which the drone returns to service.
+    #[serde_as(as = "DurationSeconds")]
+    pub duration: Duration,
+
+    /// If true, backends running on the drone are terminated at the start
+    /// of the window. Otherwise, they are left to run to completion.
+    #[serde(default)]
+    pub drain: bool,
+}
+
+impl MaintenanceWindow {
+    #[must_use]
+    pub fn end(&self) -> DateTime<Utc> {
+        self.start
+            + chrono::Duration::from_std(self.duration).unwrap_or_else(|_| chrono::Duration::zero())
+    }
+}
+
+/// Message sent to a drone to schedule a maintenance window.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleMaintenance {
+    pub drone: DroneId,
+    pub cluster: ClusterName,
+    pub window: MaintenanceWindow,
+}
+
+impl TypedMessage for ScheduleMaintenance {
+    type Response = ();
+
+    fn subject(&self) -> String {
+        subjects::drone_schedule_maintenance(&self.cluster, &self.drone)
+    }
+}
+
+impl ScheduleMaintenance {
+    pub fn subscribe_subject(drone: DroneId, cluster: ClusterName) -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::drone_schedule_maintenance(&cluster, &drone))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaintenancePhase {
+    /// The window has been accepted by the drone and will start in the future.
+    Scheduled,
+
+    /// The window has started; the drone no longer accepts new backends.
+    Cordoned,
+
+    /// Backends running on the drone have been terminated.
+    Drained,
+
+    /// The window has ended and the drone has returned to service.
+    Completed,
+}
+
+/// Message published by a drone as it moves through a maintenance window.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DroneMaintenanceEvent {
+    pub drone: DroneId,
+    pub cluster: ClusterName,
+    pub phase: MaintenancePhase,
+    pub window: MaintenanceWindow,
+    pub time: DateTime<Utc>,
+}
+
+impl TypedMessage for DroneMaintenanceEvent {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::drone_maintenance(&self.cluster, &self.drone)
+    }
+}
+
+impl DroneMaintenanceEvent {
+    pub fn wildcard_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::drone_maintenance(Any, Any))
+    }
+}
+
+/// Periodic summary of the capacity of a cluster, published by the controller
+/// so that external autoscalers can decide when to add or remove drones.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClusterCapacityReport {
+    pub cluster: ClusterName,
+
+    /// Number of drones which are currently accepting backends.
+    pub ready_drones: u32,
+
+    /// Number of live drones whose version is incompatible with the
+    /// controller. Unless the controller is configured to deprioritize rather
+    /// than refuse these drones, they are not counted in `ready_drones`.
+    #[serde(default)]
+    pub incompatible_drones: u32,
+
+    /// Total number of backends running across all live drones in the cluster.
+    pub running_backends: u32,
+
+    /// Number of schedule requests for this cluster which could not be
+    /// fulfilled within `failed_schedules_window`.
+    pub failed_schedules: u32,
+
+    /// Number of times within `failed_schedules_window` that drones were
+    /// sought for this cluster and every live drone was refused for having
+    /// an incompatible version. Dry runs are counted too.
+    #[serde(default)]
+    pub incompatible_refusals: u32,
+
+    /// The window over which `failed_schedules` is counted.
+    #[serde_as(as = "DurationSeconds")]
+    pub failed_schedules_window: Duration,
+
+    /// The most recent drone count requested through [DesiredDroneCount], if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desired_drones: Option<u32>,
+
+    /// The time the report was generated.
+    pub time: DateTime<Utc>,
+}
+
+impl TypedMessage for ClusterCapacityReport {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::cluster_capacity(&self.cluster)
+    }
+}
+
+impl ClusterCapacityReport {
+    pub fn subscribe_subject(cluster: &ClusterName) -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::cluster_capacity(cluster))
+    }
+
+    pub fn wildcard_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::cluster_capacity(Any))
+    }
+}
+
+/// Message sent to the controller to record the number of drones a cluster
+/// should have. The controller does not provision drones itself; the value is
+/// echoed in subsequent [ClusterCapacityReport]s for external autoscalers to act on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DesiredDroneCount {
+    pub cluster: ClusterName,
+    pub count: u32,
+}
+
+impl TypedMessage for DesiredDroneCount {
+    type Response = ();
+
+    fn subject(&self) -> String {
+        subjects::cluster_desired_drones(&self.cluster)
+    }
+
+    fn authenticated_by_controller() -> bool {
+        true
+    }
+}
+
+impl DesiredDroneCount {
+    pub fn subscribe_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::cluster_desired_drones(Any))
+    }
+}
+
+/// Request for every cluster the controller has observed, either through
+/// drone status messages or DNS records.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClusterListRequest {}
+
+/// Summary of a cluster known to the controller.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClusterSummary {
+    pub cluster: ClusterName,
+
+    /// Number of drones which are live and ready to accept backends.
+    pub ready_drones: u32,
+
+    /// Number of backends running on the cluster's live drones, as most
+    /// recently reported by each drone.
+    pub running_backends: u32,
+
+    /// When a DNS record was last set for the cluster, if one has been set
+    /// since the controller started.
+    pub last_dns_record: Option<DateTime<Utc>>,
+}
+
+impl TypedMessage for ClusterListRequest {
+    type Response = Vec<ClusterSummary>;
+
+    fn subject(&self) -> String {
+        subjects::scheduler_list_clusters()
+    }
+
+    fn authenticated_by_controller() -> bool {
+        true
+    }
+}
+
+impl ClusterListRequest {
+    pub fn subscribe_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::scheduler_list_clusters())
+    }
+}
+
+/// Request for where a backend runs, given only its ID (e.g. one found in
+/// logs). Answered by the controller from the backend's latest state in
+/// JetStream, whichever cluster it belongs to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackendLookupRequest {
+    pub backend: BackendId,
+}
+
+/// Where a backend runs, and its latest state.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackendLocation {
+    pub backend: BackendId,
+
+    /// The cluster of the drone running the backend, if the controller has
+    /// received a status message from that drone since it started.
+    pub cluster: Option<ClusterName>,
+
+    /// The drone running the backend. Absent if the backend's state was
+    /// reported by an older drone.
+    pub drone: Option<DroneId>,
+
+    /// The backend's latest state, and when it entered it.
+    pub state: BackendState,
+    pub time: DateTime<Utc>,
+
+    /// The URL the backend is served at, if its cluster is known.
+    pub url: Option<String>,
+}
+
+impl TypedMessage for BackendLookupRequest {
+    /// None if no state has been published for the backend, e.g. because it
+    /// does not exist or its state has expired from JetStream.
+    type Response = Option<BackendLocation>;
+
+    fn subject(&self) -> String {
+        subjects::scheduler_lookup_backend()
+    }
+
+    fn authenticated_by_controller() -> bool {
+        true
+    }
+}
+
+impl BackendLookupRequest {
+    pub fn subscribe_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::scheduler_lookup_backend())
+    }
+}
+
+/// Request to terminate a backend, given only its ID. The controller finds
+/// the cluster of the drone running the backend from its latest state, and
+/// forwards a [TerminationRequest] to it.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TerminateBackendRequest {
+    pub backend: BackendId,
+
+    /// Drain the backend before terminating it, as in [TerminationRequest].
+    #[serde(default)]
+    pub drain: bool,
+
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(default)]
+    pub grace_period_secs: Duration,
+}
+
+impl TypedMessage for TerminateBackendRequest {
+    /// The drone's response to the forwarded termination request. Fails with
+    /// [PlaneError::NotFound] if the backend does not exist or has already
+    /// terminated.
+    type Response = Result<(), PlaneError>;
+
+    fn subject(&self) -> String {
+        subjects::scheduler_terminate_backend()
+    }
+
+    fn authenticated_by_controller() -> bool {
+        true
+    }
+}
+
+impl TerminateBackendRequest {
+    pub fn subscribe_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::scheduler_terminate_backend())
+    }
+
+    /// The termination request to forward to the backend's drone.
+    #[must_use]
+    pub fn termination_request(&self, cluster: ClusterName) -> TerminationRequest {
+        TerminationRequest {
+            cluster_id: cluster,
+            backend_id: self.backend.clone(),
+            drain: self.drain,
+            grace_period_secs: self.grace_period_secs,
+        }
+    }
+}
+
+/// How the URL of a backend is formed from its ID and cluster. By default,
+/// it is `https://{backend}.{cluster}`; clusters behind a shared load
+/// balancer, or served on another port, can configure the parts which
+/// differ.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackendUrlConfig {
+    /// URL scheme, `https` if not provided.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheme: Option<String>,
+
+    /// Port, if not the default port of the scheme.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    /// Domain which backend hostnames are under, instead of the cluster
+    /// name. Drones route requests by the `{backend}.{cluster}` hostname, so
+    /// whatever serves this domain must pass that hostname on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname_suffix: Option<String>,
+
+    /// Path appended to the URL, e.g. `/app/`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+
+    /// If true, backends are addressed by path on the cluster's root
+    /// hostname, as `/backends/{backend}/`, instead of by their own hostname.
+    /// Backends addressed this way share an origin, so they should trust
+    /// each other.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub path_routing: bool,
+}
+
+impl BackendUrlConfig {
+    #[must_use]
+    pub fn backend_url(&self, backend: &BackendId, cluster: &ClusterName) -> String {
+        let scheme = self.scheme.as_deref().unwrap_or("https");
+        let domain = match &self.hostname_suffix {
+            Some(suffix) => suffix.clone(),
+            None => cluster.to_string(),
+        };
+        let mut url = if self.path_routing {
+            format!("{}://{}", scheme, domain)
+        } else {
+            format!("{}://{}.{}", scheme, backend, domain)
+        };
+
+        let default_port = match scheme {
+            "http" => Some(80),
+            "https" => Some(443),
+            _ => None,
+        };
+        if let Some(port) = self.port.filter(|port| Some(*port) != default_port) {
+            url.push_str(&format!(":{}", port));
+        }
+
+        if self.path_routing {
+            url.push_str(&format!("/backends/{}", backend));
+        }
+
+        if let Some(path) = &self.path {
+            if !path.starts_with('/') {
+                url.push('/');
+            }
+            url.push_str(path);
+        }
+
+        url
+    }
+}
+
+/// Cluster-level policy, managed declaratively with `plane-cli apply`.
+///
+/// Configs are persisted in JetStream by the controller, keeping only the
+/// latest for each cluster, so each one replaces the previous config of its
+/// cluster as a whole. Settings which are not provided fall back to the
+/// controller's static configuration for the cluster.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClusterConfig {
+    pub cluster: ClusterName,
+
+    /// Maximum number of backends which may run in the cluster at once.
+    #[serde(default)]
+    pub max_backends: Option<u32>,
+
+    /// Resource limits given to backends whose request does not set any.
+    #[serde(default)]
+    pub default_resource_limits: Option<ResourceLimits>,
+
+    /// Upper bound on the memory limit of each backend, in bytes.
+    #[serde(default)]
+    pub max_memory_bytes: Option<i64>,
+
+    /// Upper bound on the percentage of a CPU period each backend may use.
+    #[serde(default)]
+    pub max_cpu_period_percent: Option<u8>,
+
+    /// Time-to-live of the cluster's records served by the controller's
+    /// DNS server.
+    #[serde(default)]
+    pub dns_ttl_secs: Option<u32>,
+
+    /// If provided, backends may only use images under one of these
+    /// prefixes, e.g. `ghcr.io/my-org`. Prefixes match whole path segments,
+    /// so `ghcr.io/my-org` does not allow `ghcr.io/my-org-fork/app`.
+    #[serde(default)]
+    pub allowed_images: Option<Vec<String>>,
+
+    /// How the URLs of the cluster's backends are formed.
+    #[serde(default)]
+    pub backend_url: Option<BackendUrlConfig>,
+}
+
+impl TypedMessage for ClusterConfig {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::cluster_config(&self.cluster)
+    }
+}
+
+impl JetStreamable for ClusterConfig {
+    fn config() -> async_nats::jetstream::stream::Config {
+        async_nats::jetstream::stream::Config {
+            name: Self::stream_name().into(),
+            subjects: vec![subjects::cluster_config(Any)],
+            max_messages_per_subject: 1,
+            ..async_nats::jetstream::stream::Config::default()
+        }
+    }
+
+    fn stream_name() -> &'static str {
+        "cluster_config"
+    }
+}
+
+impl ClusterConfig {
+    pub fn subscribe_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::cluster_config(Any))
+    }
+}
+
+/// Request to the controller to apply a cluster config, which it persists
+/// once the request is authenticated.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApplyClusterConfig {
+    pub config: ClusterConfig,
+}
+
+impl TypedMessage for ApplyClusterConfig {
+    type Response = Result<(), PlaneError>;
+
+    fn subject(&self) -> String {
+        subjects::scheduler_apply_cluster_config()
+    }
+
+    fn authenticated_by_controller() -> bool {
+        true
+    }
+}
+
+impl ApplyClusterConfig {
+    pub fn subscribe_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::scheduler_apply_cluster_config())
+    }
+}
+
+/// Published periodically by the controller which schedules backends, when
+/// controllers run with leader election.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SchedulerLeader {
+    /// Identifier of the controller which holds the leader lease.
+    pub controller: String,
+
+    /// When the controller became the leader.
+    pub since: DateTime<Utc>,
+}
+
+impl TypedMessage for SchedulerLeader {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::scheduler_leader()
+    }
+}
+
+impl SchedulerLeader {
+    pub fn subscribe_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::scheduler_leader())
+    }
+}
+
+/// A problem with one of the JetStream streams Plane relies on, or with a
+/// consumer reading it. Published by the controller when diagnostics are
+/// enabled.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JetStreamAlert {
+    pub stream: String,
+    pub problem: JetStreamProblem,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JetStreamProblem {
+    /// The stream does not exist.
+    StreamMissing,
+
+    /// The stream's configuration differs from the one Plane creates it
+    /// with in a way which breaks Plane's assumptions.
+    StreamMisconfigured { reason: String },
+
+    /// A consumer of the stream has fallen behind.
+    ConsumerLagging {
+        consumer: String,
+
+        /// Messages in the stream not yet delivered to the consumer.
+        num_pending: u64,
+
+        /// Messages delivered to the consumer but not yet acknowledged.
+        num_ack_pending: u64,
+    },
+}
+
+impl Display for JetStreamAlert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.problem {
+            JetStreamProblem::StreamMissing => write!(f, "Stream {} does not exist.", self.stream),
+            JetStreamProblem::StreamMisconfigured { reason } => {
+                write!(f, "Stream {} is misconfigured: {}", self.stream, reason)
+            }
+            JetStreamProblem::ConsumerLagging {
+                consumer,
+                num_pending,
+                num_ack_pending,
+            } => write!(
+                f,
+                "Consumer {} of stream {} is behind by {} messages ({} unacknowledged).",
+                consumer, self.stream, num_pending, num_ack_pending
+            ),
+        }
+    }
+}
+
+impl TypedMessage for JetStreamAlert {
+    type Response = NoReply;
+
+    fn subject(&self) -> String {
+        subjects::jetstream_alert(self.stream.as_str())
+    }
+}
+
+impl JetStreamAlert {
+    pub fn subscribe_subject() -> SubscribeSubject<Self> {
+        SubscribeSubject::new(subjects::jetstream_alert(Any))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend_url() {
+        let backend = BackendId::new("abc123".into());
+        let cluster = ClusterName::new("plane.test");
+
+        assert_eq!(
+            "https://abc123.plane.test",
+            BackendUrlConfig::default().backend_url(&backend, &cluster)
+        );
+        assert_eq!(
+            "https://abc123.plane.test",
+            BackendUrlConfig {
+                port: Some(443),
+                ..BackendUrlConfig::default()
+            }
+            .backend_url(&backend, &cluster)
+        );
+        assert_eq!(
+            "http://abc123.apps.example.com:8080/app/",
+            BackendUrlConfig {
+                scheme: Some("http".into()),
+                port: Some(8080),
+                hostname_suffix: Some("apps.example.com".into()),
+                path: Some("app/".into()),
+                path_routing: false,
+            }
+            .backend_url(&backend, &cluster)
+        );
+        assert_eq!(
+            "https://plane.test/backends/abc123/",
+            BackendUrlConfig {
+                path: Some("/".into()),
+                path_routing: true,
+                ..BackendUrlConfig::default()
+            }
+            .backend_url(&backend, &cluster)
+        );
     }
 }
@@ -1,6 +1,8 @@
 //! Typed wrappers around NATS.
 //!
 //! These use serde to serialize data to/from JSON over nats into Rust types.
+//! High-volume messages may instead be sent as MessagePack, which is marked
+//! with a header so that subscribers can decode either.
 
 use anyhow::{anyhow, Result};
 use async_nats::jetstream;
@@ -8,8 +10,9 @@ use async_nats::jetstream::consumer::push::Messages;
 use async_nats::jetstream::consumer::DeliverPolicy;
 use async_nats::jetstream::stream::Config;
 use async_nats::jetstream::Context;
-use async_nats::{Client, Message, Subscriber};
+use async_nats::{Client, HeaderMap, Message, Subscriber};
 use bytes::Bytes;
+use chrono::{DateTime, TimeZone, Utc};
 use dashmap::DashSet;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::error::Error;
@@ -19,6 +22,7 @@ use std::sync::Arc;
 use tokio_stream::StreamExt;
 
 use crate::logging::LogError;
+use crate::signing::{SeenNonces, SigningKey, VerifyingKey, SIGNATURE_HEADER};
 
 /// Unconstructable type, used as a [TypedMessage::Response] to indicate that
 /// no response is allowed.
@@ -32,6 +36,59 @@ pub trait TypedMessage: Serialize + DeserializeOwned {
     /// Subjects must be deterministically generated from the message
     /// body.
     fn subject(&self) -> String;
+
+    /// Whether this message is sent as MessagePack when the client has
+    /// compact encoding enabled. Only high-volume messages opt in; control
+    /// messages are always sent as JSON.
+    fn allow_compact_encoding() -> bool {
+        false
+    }
+
+    /// Whether the client's bearer token is sent with this message, for the
+    /// controller to authenticate it. Only requests the controller handles
+    /// opt in, so that the token is never sent to drones, or anyone else
+    /// who can subscribe to their subjects.
+    fn authenticated_by_controller() -> bool {
+        false
+    }
+}
+
+/// Header which marks the encoding of a message. Messages without it are JSON.
+const CONTENT_TYPE_HEADER: &str = "content-type";
+const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+/// Header carrying the credentials of a request, as `Bearer <token>`.
+pub const AUTHORIZATION_HEADER: &str = "authorization";
+
+/// Extract the token from an `authorization` header value of the form
+/// `Bearer <token>`.
+fn parse_bearer_token(value: &str) -> Option<&str> {
+    let (scheme, token) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    let token = token.trim();
+    (!token.is_empty()).then_some(token)
+}
+
+/// The value of a header, if it is set. NATS headers may have several values;
+/// Plane only ever sets one.
+fn header_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|value| value.iter().next())
+        .map(String::as_str)
+}
+
+/// Decode a message payload according to its content-type header.
+fn decode<T: DeserializeOwned>(headers: Option<&HeaderMap>, payload: &[u8]) -> Result<T> {
+    let content_type = headers.and_then(|headers| header_value(headers, CONTENT_TYPE_HEADER));
+
+    if content_type == Some(MSGPACK_CONTENT_TYPE) {
+        Ok(rmp_serde::from_slice(payload)?)
+    } else {
+        Ok(serde_json::from_slice(payload)?)
+    }
 }
 
 pub trait JetStreamable: TypedMessage {
@@ -72,6 +129,12 @@ where
             _ph_m: PhantomData::default(),
         }
     }
+
+    /// The subject, which may contain wildcards.
+    #[must_use]
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
 }
 
 #[derive(Debug)]
@@ -93,7 +156,7 @@ where
 {
     fn new(message: Message, nc: Client) -> Result<Self> {
         Ok(MessageWithResponseHandle {
-            value: serde_json::from_slice(&message.payload)?,
+            value: decode(message.headers.as_ref(), &message.payload)?,
             message,
             nc,
         })
@@ -103,6 +166,39 @@ where
         &self.message
     }
 
+    /// The bearer token the message was sent with, if any.
+    pub fn bearer_token(&self) -> Option<&str> {
+        parse_bearer_token(header_value(
+            self.message.headers.as_ref()?,
+            AUTHORIZATION_HEADER,
+        )?)
+    }
+
+    /// Whether the message was received on the subject its value is sent
+    /// on, i.e. its body names the same backend (or other entity) as its
+    /// subject, which is what permissions on subjects are granted for.
+    pub fn subject_matches(&self) -> bool {
+        self.message.subject == self.value.subject()
+    }
+
+    /// Verify that the message was signed with the private key matching `key`,
+    /// for the subject it was received on, and has not been seen before.
+    pub fn verify_signature(&self, key: &VerifyingKey, seen: &SeenNonces) -> Result<()> {
+        let signature = self
+            .message
+            .headers
+            .as_ref()
+            .and_then(|headers| header_value(headers, SIGNATURE_HEADER))
+            .ok_or_else(|| anyhow!("Request is not signed."))?;
+        key.verify(
+            &self.message.subject,
+            &self.message.payload,
+            signature,
+            Utc::now(),
+            seen,
+        )
+    }
+
     pub async fn respond(&self, response: &T::Response) -> Result<()> {
         self.nc
             .publish(
@@ -158,6 +254,31 @@ where
     }
 }
 
+/// The error (within an [anyhow::Error]) of a [TypedNats::request] which was
+/// not responded to in time, as opposed to one which could not be sent or
+/// whose response could not be parsed.
+#[derive(Debug)]
+pub struct RequestTimeout;
+
+impl std::fmt::Display for RequestTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NATS request timed out.")
+    }
+}
+
+impl Error for RequestTimeout {}
+
+/// Convert the error of a NATS request, keeping timeouts distinguishable as
+/// [RequestTimeout].
+fn request_error(error: async_nats::Error) -> anyhow::Error {
+    match error.downcast_ref::<std::io::Error>() {
+        Some(io_error) if io_error.kind() == std::io::ErrorKind::TimedOut => {
+            anyhow::Error::new(RequestTimeout)
+        }
+        _ => anyhow!("NATS Error: {:?}", error),
+    }
+}
+
 /// NATS errors are not castable to anyhow::Error, because they don't
 /// implement [Sized] for some reason.
 ///
@@ -198,6 +319,12 @@ pub struct TypedNats {
     /// creating the same stream repeatedly because it costs a round-trip
     /// to NATS.
     jetstream_created_streams: Arc<DashSet<String>>,
+    /// Whether messages which allow it are sent as MessagePack.
+    compact_encoding: bool,
+    /// Token sent with requests, for the controller to authenticate them.
+    auth_token: Option<String>,
+    /// Key which requests are signed with.
+    signing_key: Option<Arc<SigningKey>>,
 }
 
 pub struct DelayedReply<T: DeserializeOwned> {
@@ -224,6 +351,13 @@ pub struct JetstreamSubscription<T: TypedMessage> {
 
 impl<T: TypedMessage> JetstreamSubscription<T> {
     pub async fn next(&mut self) -> Option<T> {
+        self.next_with_sequence().await.map(|(value, _)| value)
+    }
+
+    /// Like [JetstreamSubscription::next], but also returns the sequence
+    /// number of the message in its stream, which can be passed to
+    /// [DeliverPolicy::ByStartSequence] to resume after it.
+    pub async fn next_with_sequence(&mut self) -> Option<(T, u64)> {
         loop {
             if let Some(message) = self.stream.next().await {
                 let message = match message {
@@ -237,9 +371,19 @@ impl<T: TypedMessage> JetstreamSubscription<T> {
                     .ack()
                     .await
                     .log_error("Error acking jetstream message.");
-                let value: Result<T, _> = serde_json::from_slice(&message.payload);
+                let sequence = match message.info() {
+                    Ok(info) => info.stream_sequence,
+                    Err(error) => {
+                        tracing::error!(
+                            ?error,
+                            "Error reading jetstream message info; message ignored."
+                        );
+                        continue;
+                    }
+                };
+                let value: Result<T> = decode(message.headers.as_ref(), &message.payload);
                 match value {
-                    Ok(value) => return Some(value),
+                    Ok(value) => return Some((value, sequence)),
                     Err(error) => {
                         tracing::error!(?error, "Error parsing jetstream message; message ignored.")
                     }
@@ -251,6 +395,16 @@ impl<T: TypedMessage> JetstreamSubscription<T> {
     }
 }
 
+/// Where and when JetStream stored a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StoredMessageInfo {
+    /// Sequence number of the message in its stream.
+    pub sequence: u64,
+
+    /// When the NATS server stored the message, by its own clock.
+    pub published: DateTime<Utc>,
+}
+
 /// async_nats returns an Ok(Err(_)) when a stream is empty instead of None, this replaces that specific error
 /// with None.
 ///
@@ -281,9 +435,69 @@ impl TypedNats {
             nc,
             jetstream,
             jetstream_created_streams: Arc::default(),
+            compact_encoding: false,
+            auth_token: None,
+            signing_key: None,
         }
     }
 
+    /// Send high-volume messages as MessagePack instead of JSON.
+    #[must_use]
+    pub fn with_compact_encoding(mut self, compact_encoding: bool) -> Self {
+        self.compact_encoding = compact_encoding;
+        self
+    }
+
+    /// Send the given token as a bearer token with every request.
+    #[must_use]
+    pub fn with_auth_token(mut self, auth_token: Option<String>) -> Self {
+        self.auth_token = auth_token;
+        self
+    }
+
+    /// Sign every request and published message with the given key.
+    #[must_use]
+    pub fn with_signing_key(mut self, signing_key: Option<SigningKey>) -> Self {
+        self.signing_key = signing_key.map(Arc::new);
+        self
+    }
+
+    /// Sign a message to be published, if a signing key is set.
+    fn sign_headers(&self, headers: &mut HeaderMap, subject: &str, payload: &[u8]) -> Result<()> {
+        if let Some(signing_key) = &self.signing_key {
+            headers.insert(
+                SIGNATURE_HEADER,
+                signing_key.sign(subject, payload, Utc::now())?.as_str(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Headers sent with a request, if any are needed.
+    fn request_headers<T: TypedMessage>(
+        &self,
+        subject: &str,
+        payload: &[u8],
+    ) -> Result<Option<HeaderMap>> {
+        let auth_token = self
+            .auth_token
+            .as_ref()
+            .filter(|_| T::authenticated_by_controller());
+        if auth_token.is_none() && self.signing_key.is_none() {
+            return Ok(None);
+        }
+
+        let mut headers = HeaderMap::new();
+        if let Some(auth_token) = auth_token {
+            headers.insert(
+                AUTHORIZATION_HEADER,
+                format!("Bearer {}", auth_token).as_str(),
+            );
+        }
+        self.sign_headers(&mut headers, subject, payload)?;
+        Ok(Some(headers))
+    }
+
     pub async fn ensure_jetstream_exists<T: JetStreamable>(&self) -> Result<()> {
         if !self.jetstream_created_streams.contains(T::stream_name()) {
             self.add_jetstream_stream::<T>().await?;
@@ -303,13 +517,19 @@ impl TypedNats {
     {
         let inbox = self.nc.new_inbox();
         let subscription = self.nc.subscribe(inbox.clone()).await.to_anyhow()?;
-        self.nc
-            .publish_with_reply(
-                message.subject(),
-                inbox,
-                Bytes::from(serde_json::to_vec(&message)?),
-            )
-            .await?;
+        let payload = Bytes::from(serde_json::to_vec(&message)?);
+        match self.request_headers::<T>(&message.subject(), &payload)? {
+            Some(headers) => {
+                self.nc
+                    .publish_with_reply_and_headers(message.subject(), inbox, headers, payload)
+                    .await?
+            }
+            None => {
+                self.nc
+                    .publish_with_reply(message.subject(), inbox, payload)
+                    .await?
+            }
+        }
 
         Ok(DelayedReply {
             subscription,
@@ -328,11 +548,121 @@ impl TypedNats {
         Ok(())
     }
 
+    /// Create a JetStream stream with the given configuration, or update the
+    /// stream to match it if one already exists with the same name.
+    pub async fn provision_jetstream(&self, config: Config) -> Result<()> {
+        let name = config.name.clone();
+        if self.jetstream.get_stream(&name).await.is_ok() {
+            tracing::info!(%name, "Updating jetstream stream.");
+            self.jetstream.update_stream(&config).await.to_anyhow()?;
+        } else {
+            tracing::info!(%name, "Creating jetstream stream.");
+            self.jetstream.create_stream(config).await.to_anyhow()?;
+        }
+
+        self.jetstream_created_streams.insert(name);
+        Ok(())
+    }
+
+    /// Create a durable consumer of a JetStream stream, or update the
+    /// consumer to match the given configuration if it already exists.
+    pub async fn provision_jetstream_consumer(
+        &self,
+        stream_name: &str,
+        config: jetstream::consumer::pull::Config,
+    ) -> Result<()> {
+        let stream = self.jetstream.get_stream(stream_name).await.to_anyhow()?;
+        tracing::info!(
+            stream=%stream_name,
+            consumer=?config.durable_name,
+            "Provisioning jetstream consumer."
+        );
+        stream.create_consumer(config).await.to_anyhow()?;
+
+        Ok(())
+    }
+
+    /// Get a JetStream key-value bucket, creating it with the given
+    /// configuration if it does not exist.
+    pub async fn key_value(&self, config: jetstream::kv::Config) -> Result<jetstream::kv::Store> {
+        if let Ok(store) = self.jetstream.get_key_value(&config.bucket).await {
+            return Ok(store);
+        }
+
+        tracing::info!(bucket=%config.bucket, "Creating jetstream key-value bucket.");
+        self.jetstream.create_key_value(config).await.to_anyhow()
+    }
+
+    /// Make a request to an endpoint of the JetStream API, such as
+    /// `STREAM.INFO.<stream>`, for information the client does not expose.
+    /// JetStream reports errors in the body of its response, so the response
+    /// type should account for them.
+    pub async fn jetstream_api<R: DeserializeOwned>(&self, endpoint: &str) -> Result<R> {
+        self.jetstream_api_request(endpoint, Bytes::new()).await
+    }
+
+    /// Like [TypedNats::jetstream_api], for endpoints which take a request,
+    /// such as the offset of the page to list.
+    pub async fn jetstream_api_with<T: Serialize, R: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        request: &T,
+    ) -> Result<R> {
+        self.jetstream_api_request(endpoint, serde_json::to_vec(request)?.into())
+            .await
+    }
+
+    async fn jetstream_api_request<R: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        payload: Bytes,
+    ) -> Result<R> {
+        let response = self
+            .nc
+            .request(format!("$JS.API.{}", endpoint), payload)
+            .await
+            .to_anyhow()?;
+
+        Ok(serde_json::from_slice(&response.payload)?)
+    }
+
+    /// Delete every message published to a subject of a stream, e.g. the
+    /// state history of a backend which is long gone. Returns the number of
+    /// messages deleted.
+    pub async fn purge_jetstream_subject<T: JetStreamable>(&self, subject: &str) -> Result<u64> {
+        let stream = self
+            .jetstream
+            .get_stream(T::stream_name())
+            .await
+            .to_anyhow()?;
+        let response = stream.purge_subject(subject).await.to_anyhow()?;
+
+        Ok(response.purged)
+    }
+
     pub async fn get_all<T>(
         &self,
         subject: &SubscribeSubject<T>,
         deliver_policy: DeliverPolicy,
     ) -> Result<Vec<T>>
+    where
+        T: TypedMessage<Response = NoReply> + JetStreamable,
+    {
+        Ok(self
+            .get_all_with_info(subject, deliver_policy)
+            .await?
+            .into_iter()
+            .map(|(value, _)| value)
+            .collect())
+    }
+
+    /// Like [TypedNats::get_all], but also returns where and when each
+    /// message was stored in its stream.
+    pub async fn get_all_with_info<T>(
+        &self,
+        subject: &SubscribeSubject<T>,
+        deliver_policy: DeliverPolicy,
+    ) -> Result<Vec<(T, StoredMessageInfo)>>
     where
         T: TypedMessage<Response = NoReply> + JetStreamable,
     {
@@ -352,7 +682,7 @@ impl TypedNats {
             .await
             .to_anyhow()?;
 
-        let mut result: Vec<T> = Vec::new();
+        let mut result: Vec<(T, StoredMessageInfo)> = Vec::new();
 
         loop {
             let mut messages = consumer.fetch().messages().await.to_anyhow()?;
@@ -361,7 +691,14 @@ impl TypedNats {
             while let Some(v) = nats_error_hack(messages.next().await)? {
                 done = false;
 
-                result.push(serde_json::from_slice(&v.payload)?);
+                let info = v
+                    .info()
+                    .map_err(|error| anyhow!("Error reading jetstream message info: {}", error))?;
+                let info = StoredMessageInfo {
+                    sequence: info.stream_sequence,
+                    published: Utc.timestamp_nanos(info.published.unix_timestamp_nanos() as i64),
+                };
+                result.push((decode(v.headers.as_ref(), &v.payload)?, info));
             }
 
             if done {
@@ -376,6 +713,18 @@ impl TypedNats {
     pub async fn subscribe_jetstream<T: JetStreamable>(
         &self,
         subject: SubscribeSubject<T>,
+    ) -> Result<JetstreamSubscription<T>> {
+        self.subscribe_jetstream_from(subject, DeliverPolicy::All)
+            .await
+    }
+
+    /// Like [TypedNats::subscribe_jetstream], but starting from the messages
+    /// selected by `deliver_policy`. For example, [DeliverPolicy::LastPerSubject]
+    /// delivers the current value of each subject followed by live updates.
+    pub async fn subscribe_jetstream_from<T: JetStreamable>(
+        &self,
+        subject: SubscribeSubject<T>,
+        deliver_policy: DeliverPolicy,
     ) -> Result<JetstreamSubscription<T>> {
         let subject = subject.subject.to_string();
         let _ = self.ensure_jetstream_exists::<T>().await;
@@ -386,7 +735,7 @@ impl TypedNats {
 
         let consumer = stream
             .create_consumer(async_nats::jetstream::consumer::push::Config {
-                deliver_policy: DeliverPolicy::All,
+                deliver_policy,
                 filter_subject: subject,
                 deliver_subject,
                 max_ack_pending: 1, // NOTE: If you remove this or change the value,
@@ -409,11 +758,25 @@ impl TypedNats {
     where
         T: TypedMessage<Response = NoReply>,
     {
+        let subject = value.subject();
+        let compact = self.compact_encoding && T::allow_compact_encoding();
+        if !compact && self.signing_key.is_none() {
+            self.nc
+                .publish(subject, Bytes::from(serde_json::to_vec(value)?))
+                .await?;
+            return Ok(());
+        }
+
+        let mut headers = HeaderMap::new();
+        let payload = if compact {
+            headers.insert(CONTENT_TYPE_HEADER, MSGPACK_CONTENT_TYPE);
+            Bytes::from(rmp_serde::to_vec_named(value)?)
+        } else {
+            Bytes::from(serde_json::to_vec(value)?)
+        };
+        self.sign_headers(&mut headers, &subject, &payload)?;
         self.nc
-            .publish(
-                value.subject().clone(),
-                Bytes::from(serde_json::to_vec(value)?),
-            )
+            .publish_with_headers(subject, headers, payload)
             .await?;
         Ok(())
     }
@@ -424,13 +787,18 @@ impl TypedNats {
     {
         self.ensure_jetstream_exists::<T>().await?;
 
-        self.jetstream
-            .publish(
-                value.subject().clone(),
-                Bytes::from(serde_json::to_vec(value)?),
-            )
-            .await
-            .to_anyhow()?;
+        let subject = value.subject();
+        let payload = Bytes::from(serde_json::to_vec(value)?);
+        if self.signing_key.is_some() {
+            let mut headers = HeaderMap::new();
+            self.sign_headers(&mut headers, &subject, &payload)?;
+            self.jetstream
+                .publish_with_headers(subject, headers, payload)
+                .await
+                .to_anyhow()?;
+        } else {
+            self.jetstream.publish(subject, payload).await.to_anyhow()?;
+        }
         Ok(())
     }
 
@@ -438,11 +806,19 @@ impl TypedNats {
     where
         T: TypedMessage,
     {
-        let result = self
-            .nc
-            .request(value.subject(), Bytes::from(serde_json::to_vec(value)?))
-            .await
-            .to_anyhow()?;
+        let payload = Bytes::from(serde_json::to_vec(value)?);
+        let result = match self.request_headers::<T>(&value.subject(), &payload)? {
+            Some(headers) => self
+                .nc
+                .request_with_headers(value.subject(), headers, payload)
+                .await
+                .map_err(request_error)?,
+            None => self
+                .nc
+                .request(value.subject(), payload)
+                .await
+                .map_err(request_error)?,
+        };
 
         let value: T::Response = serde_json::from_slice(&result.payload)?;
         Ok(value)
@@ -456,3 +832,48 @@ impl TypedNats {
         Ok(TypedSubscription::new(subscription, self.nc.clone()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    struct Sample {
+        name: String,
+        count: u32,
+    }
+
+    #[test]
+    fn test_decode() {
+        let sample = Sample {
+            name: "sample".into(),
+            count: 3,
+        };
+
+        let json = serde_json::to_vec(&sample).unwrap();
+        assert_eq!(sample, decode::<Sample>(None, &json).unwrap());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE_HEADER, MSGPACK_CONTENT_TYPE);
+        let msgpack = rmp_serde::to_vec_named(&sample).unwrap();
+        assert_eq!(sample, decode::<Sample>(Some(&headers), &msgpack).unwrap());
+    }
+
+    #[test]
+    fn test_request_error() {
+        let timed_out = std::io::Error::new(std::io::ErrorKind::TimedOut, "request timed out");
+        assert!(request_error(Box::new(timed_out)).is::<RequestTimeout>());
+
+        let no_responders = std::io::Error::new(std::io::ErrorKind::NotFound, "no responders");
+        assert!(!request_error(Box::new(no_responders)).is::<RequestTimeout>());
+    }
+
+    #[test]
+    fn test_parse_bearer_token() {
+        assert_eq!(Some("abc123"), parse_bearer_token("Bearer abc123"));
+        assert_eq!(Some("abc123"), parse_bearer_token("bearer  abc123 "));
+        assert_eq!(None, parse_bearer_token("Basic abc123"));
+        assert_eq!(None, parse_bearer_token("Bearer "));
+        assert_eq!(None, parse_bearer_token("abc123"));
+    }
+}
@@ -1,7 +1,8 @@
 use crate::{nats::TypedNats, retry::do_with_retry};
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use async_nats::{ConnectOptions, ServerAddr};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::time::Duration;
 use url::Url;
@@ -16,55 +17,162 @@ pub enum NatsAuthorization {
 
     /// Authenticate using a username and password.
     UserAndPassword { username: String, password: String },
-    // TODO: JWT
+
+    /// Authenticate using a NATS credentials file (containing a user JWT and nkey seed).
+    Credentials { credentials_file: PathBuf },
+
+    /// Authenticate using an nkey seed.
+    NKey { nkey_seed: String },
+}
+
+/// TLS settings for the NATS connection.
+#[derive(Serialize, Deserialize, Default)]
+pub struct NatsTlsSpec {
+    /// Root certificate (PEM) to trust when verifying the server, e.g. for
+    /// a private CA.
+    pub ca_cert: Option<PathBuf>,
+
+    /// Client certificate (PEM) to present to the server for mutual TLS.
+    /// Requires `client_key`.
+    pub client_cert: Option<PathBuf>,
+
+    /// Private key (PEM) of `client_cert`.
+    pub client_key: Option<PathBuf>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct NatsConnectionSpec {
     pub auth: Option<NatsAuthorization>,
+
+    /// Servers to connect to. An entry may also be a comma-separated list of
+    /// servers. The client connects to one of them, fails over to another if
+    /// it is lost, and also learns about other servers in the NATS cluster
+    /// from the servers themselves.
     pub hosts: Vec<String>,
+
+    /// If provided, TLS is required for the connection.
+    #[serde(default)]
+    pub tls: Option<NatsTlsSpec>,
+
+    /// If true, high-volume messages such as backend logs and stats are
+    /// published as MessagePack instead of JSON. Subscribers decode either.
+    #[serde(default)]
+    pub compact_encoding: bool,
 }
 
 impl NatsConnectionSpec {
-    pub fn from_url(url: &str) -> Result<Self> {
-        let url = Url::parse(url)?;
+    /// Parse a NATS URL, or a comma-separated list of them. Credentials are
+    /// taken from the first URL which has any.
+    pub fn from_url(urls: &str) -> Result<Self> {
+        let mut auth = None;
+        let mut hosts = Vec::new();
 
-        let auth = if let Some(password) = url.password().as_ref() {
-            Some(NatsAuthorization::UserAndPassword {
-                username: url.username().to_string(),
-                password: (*password).to_string(),
-            })
-        } else if !url.username().is_empty() {
-            Some(NatsAuthorization::Token {
-                token: url.username().to_string(),
-            })
-        } else {
-            None
-        };
+        for url in urls.split(',').map(str::trim).filter(|url| !url.is_empty()) {
+            let url = Url::parse(url).with_context(|| format!("Invalid NATS URL {}.", url))?;
+
+            if auth.is_none() {
+                if let Some(password) = url.password() {
+                    auth = Some(NatsAuthorization::UserAndPassword {
+                        username: url.username().to_string(),
+                        password: password.to_string(),
+                    });
+                } else if !url.username().is_empty() {
+                    auth = Some(NatsAuthorization::Token {
+                        token: url.username().to_string(),
+                    });
+                }
+            }
 
-        let hosts = vec![url.host_str().unwrap_or("localhost").into()];
+            let host = url.host_str().unwrap_or("localhost");
+            hosts.push(match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            });
+        }
 
-        Ok(NatsConnectionSpec { auth, hosts })
+        Ok(NatsConnectionSpec {
+            auth,
+            hosts,
+            tls: None,
+            compact_encoding: false,
+        })
     }
 
-    pub fn connect_options(&self) -> ConnectOptions {
-        match &self.auth {
+    pub async fn connect_options(&self) -> Result<ConnectOptions> {
+        let mut options = match &self.auth {
             None => ConnectOptions::default(),
             Some(NatsAuthorization::Token { token }) => ConnectOptions::with_token(token.into()),
-            _ => todo!("Unsupported authentication."),
+            Some(NatsAuthorization::UserAndPassword { username, password }) => {
+                ConnectOptions::with_user_and_password(username.into(), password.into())
+            }
+            Some(NatsAuthorization::Credentials { credentials_file }) => {
+                ConnectOptions::with_credentials_file(credentials_file.clone())
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Error reading NATS credentials file {:?}.",
+                            credentials_file
+                        )
+                    })?
+            }
+            Some(NatsAuthorization::NKey { nkey_seed }) => {
+                ConnectOptions::with_nkey(nkey_seed.into())
+            }
+        };
+
+        if let Some(tls) = &self.tls {
+            options = options.require_tls(true);
+
+            if let Some(ca_cert) = &tls.ca_cert {
+                options = options.add_root_certificates(ca_cert.clone());
+            }
+
+            match (&tls.client_cert, &tls.client_key) {
+                (Some(cert), Some(key)) => {
+                    options = options.add_client_certificate(cert.clone(), key.clone());
+                }
+                (None, None) => (),
+                _ => {
+                    return Err(anyhow!(
+                        "NATS TLS client_cert and client_key must be provided together."
+                    ))
+                }
+            }
         }
+
+        Ok(options)
+    }
+
+    /// Every server listed in `hosts`.
+    fn server_addrs(&self) -> Result<Vec<ServerAddr>> {
+        let server_addrs = self
+            .hosts
+            .iter()
+            .flat_map(|hosts| hosts.split(','))
+            .map(str::trim)
+            .filter(|host| !host.is_empty())
+            .map(|host| {
+                ServerAddr::from_str(host)
+                    .with_context(|| format!("Invalid NATS server address {}.", host))
+            })
+            .collect::<Result<Vec<ServerAddr>>>()?;
+
+        if server_addrs.is_empty() {
+            return Err(anyhow!("No NATS servers provided."));
+        }
+
+        Ok(server_addrs)
     }
 
     pub async fn connect_with_retry(&self) -> Result<TypedNats> {
-        let server_addrs: Result<Vec<ServerAddr>, _> =
-            self.hosts.iter().map(|d| ServerAddr::from_str(d)).collect();
-        let server_addrs = server_addrs?;
+        let server_addrs = self.server_addrs()?;
 
         let nats = do_with_retry(
-            || {
-                async_nats::connect_with_options(
-                    &server_addrs as &[ServerAddr],
-                    self.connect_options(),
+            || async {
+                let options = self.connect_options().await?;
+                Ok::<_, anyhow::Error>(
+                    async_nats::connect_with_options(&server_addrs as &[ServerAddr], options)
+                        .await?,
                 )
             },
             30,
@@ -72,20 +180,48 @@ impl NatsConnectionSpec {
         )
         .await?;
 
-        Ok(TypedNats::new(nats))
+        Ok(TypedNats::new(nats).with_compact_encoding(self.compact_encoding))
     }
 
     pub async fn connect(&self) -> Result<TypedNats> {
-        let server_addrs: Result<Vec<ServerAddr>, _> =
-            self.hosts.iter().map(|d| ServerAddr::from_str(d)).collect();
-        let server_addrs = server_addrs?;
+        let server_addrs = self.server_addrs()?;
 
         let nats = async_nats::connect_with_options(
             &server_addrs as &[ServerAddr],
-            self.connect_options(),
+            self.connect_options().await?,
         )
         .await?;
 
-        Ok(TypedNats::new(nats))
+        Ok(TypedNats::new(nats).with_compact_encoding(self.compact_encoding))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_url_list() {
+        let spec =
+            NatsConnectionSpec::from_url("nats://token@nats1:4223, nats://nats2,nats://nats3:4224")
+                .unwrap();
+
+        assert_eq!(vec!["nats1:4223", "nats2", "nats3:4224"], spec.hosts);
+        assert!(matches!(
+            spec.auth,
+            Some(NatsAuthorization::Token { token }) if token == "token"
+        ));
+    }
+
+    #[test]
+    fn test_comma_separated_hosts() {
+        let spec = NatsConnectionSpec {
+            auth: None,
+            hosts: vec!["nats1,nats2:4223".into(), "nats3".into()],
+            tls: None,
+            compact_encoding: false,
+        };
+
+        assert_eq!(3, spec.server_addrs().unwrap().len());
     }
 }
@@ -0,0 +1,130 @@
+//! Encryption of secrets sent over NATS to a single drone.
+//!
+//! Each drone generates an RSA key pair when it starts, and advertises the
+//! public key in its status messages. Data is sealed to that key by
+//! encrypting it with a random AES-256-GCM key, which is in turn encrypted
+//! with RSA-OAEP, so that only the drone holding the private key can open it.
+
+use anyhow::{anyhow, Result};
+use openssl::{
+    encrypt::{Decrypter, Encrypter},
+    pkey::{PKey, Private},
+    rand::rand_bytes,
+    rsa::{Padding, Rsa},
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use serde::{Deserialize, Serialize};
+
+const RSA_BITS: u32 = 2048;
+const KEY_LEN: usize = 32;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(value: &str) -> Result<Vec<u8>> {
+    Ok(base64::decode_config(value, base64::URL_SAFE_NO_PAD)?)
+}
+
+/// Private key used to open data sealed to a drone.
+pub struct SealingKey {
+    key: PKey<Private>,
+}
+
+impl SealingKey {
+    pub fn generate() -> Result<Self> {
+        Ok(SealingKey {
+            key: PKey::from_rsa(Rsa::generate(RSA_BITS)?)?,
+        })
+    }
+
+    /// The PEM-encoded public key, to which data for this key is sealed.
+    pub fn public_key(&self) -> Result<String> {
+        Ok(String::from_utf8(self.key.public_key_to_pem()?)?)
+    }
+
+    pub fn open(&self, sealed: &SealedData) -> Result<Vec<u8>> {
+        let encrypted_key = decode(&sealed.encrypted_key)?;
+        let mut decrypter = Decrypter::new(&self.key)?;
+        decrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+        let mut key = vec![0; decrypter.decrypt_len(&encrypted_key)?];
+        let key_len = decrypter.decrypt(&encrypted_key, &mut key)?;
+        key.truncate(key_len);
+
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(decode(&sealed.iv)?.as_slice()),
+            &[],
+            &decode(&sealed.ciphertext)?,
+            &decode(&sealed.tag)?,
+        )
+        .map_err(|_| anyhow!("Sealed data could not be opened with this key."))
+    }
+}
+
+/// Data sealed to a single [SealingKey]. Each field is base64-encoded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SealedData {
+    /// The AES key, encrypted with the recipient's RSA key.
+    pub encrypted_key: String,
+    pub iv: String,
+    pub ciphertext: String,
+    pub tag: String,
+}
+
+impl SealedData {
+    /// Seal data to the PEM-encoded public key of a [SealingKey].
+    pub fn seal(public_key: &str, data: &[u8]) -> Result<Self> {
+        let public_key = PKey::public_key_from_pem(public_key.as_bytes())?;
+
+        let mut key = [0u8; KEY_LEN];
+        rand_bytes(&mut key)?;
+        let mut iv = [0u8; IV_LEN];
+        rand_bytes(&mut iv)?;
+
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(&iv[..]),
+            &[],
+            data,
+            &mut tag,
+        )?;
+
+        let mut encrypter = Encrypter::new(&public_key)?;
+        encrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+        let mut encrypted_key = vec![0; encrypter.encrypt_len(&key)?];
+        let encrypted_key_len = encrypter.encrypt(&key, &mut encrypted_key)?;
+        encrypted_key.truncate(encrypted_key_len);
+
+        Ok(SealedData {
+            encrypted_key: encode(&encrypted_key),
+            iv: encode(&iv),
+            ciphertext: encode(&ciphertext),
+            tag: encode(&tag),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let key = SealingKey::generate().unwrap();
+        let sealed = SealedData::seal(&key.public_key().unwrap(), b"secret").unwrap();
+        assert_eq!(b"secret".to_vec(), key.open(&sealed).unwrap());
+
+        let other_key = SealingKey::generate().unwrap();
+        assert!(other_key.open(&sealed).is_err());
+
+        let mut tampered = sealed;
+        tampered.ciphertext = encode(b"secreT");
+        assert!(key.open(&tampered).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+//! Ed25519 signatures over NATS request payloads.
+//!
+//! A cluster can be configured with a public key, in which case the controller
+//! only schedules backends for requests signed with the matching private key.
+//! This prevents anyone who merely has access to NATS from spawning arbitrary
+//! images.
+//!
+//! The signature is sent in the [SIGNATURE_HEADER] header as
+//! `{timestamp}.{nonce}.{signature}`, where the signature covers the
+//! timestamp, a random nonce, the subject the request was sent to, and the
+//! payload. A signed request is therefore only valid for a short time, only
+//! on its subject, and is only accepted once by a verifier which remembers the
+//! nonces it has seen (see [SeenNonces]).
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+    signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519},
+};
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+/// Header carrying the signature of a request.
+pub const SIGNATURE_HEADER: &str = "plane-signature";
+
+/// Context tag prefixed to signed release messages.
+const RELEASE_CONTEXT: &str = "plane-drone-release";
+
+/// How far the timestamp of a signature may be from the verifier's clock.
+pub const MAX_SIGNATURE_AGE: Duration = Duration::from_secs(300);
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(value: &str) -> Result<Vec<u8>> {
+    Ok(base64::decode_config(
+        value.trim(),
+        base64::URL_SAFE_NO_PAD,
+    )?)
+}
+
+fn message(timestamp: i64, nonce: &str, subject: &str, payload: &[u8]) -> Vec<u8> {
+    // NATS subjects can not contain whitespace, so the newline unambiguously
+    // ends the subject.
+    let mut message = format!("{}.{}.{}\n", timestamp, nonce, subject).into_bytes();
+    message.extend_from_slice(payload);
+    message
+}
+
+/// The message signed for a release. The context tag keeps release
+/// signatures and request signatures from being mistaken for one another,
+/// and the version is signed so that a binary can not be published as
+/// another version than the one it was released as.
+fn release_message(version: &str, artifact: &[u8]) -> Vec<u8> {
+    let hash = digest(&SHA256, artifact);
+    let mut message = format!("{}\n{}\n", RELEASE_CONTEXT, version).into_bytes();
+    message.extend_from_slice(hash.as_ref());
+    message
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| anyhow!("Could not generate random bytes."))?;
+    Ok(bytes)
+}
+
+/// Private key used to sign requests.
+pub struct SigningKey {
+    key_pair: Ed25519KeyPair,
+}
+
+impl SigningKey {
+    /// Generate a new key, returned as its base64-encoded seed.
+    pub fn generate() -> Result<String> {
+        Ok(encode(&random_bytes::<32>()?))
+    }
+
+    /// Construct a key from its base64-encoded 32-byte seed.
+    pub fn from_seed(seed: &str) -> Result<Self> {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&decode(seed)?)
+            .map_err(|error| anyhow!("Invalid Ed25519 seed: {}", error))?;
+        Ok(SigningKey { key_pair })
+    }
+
+    /// The base64-encoded public key which verifies this key's signatures.
+    #[must_use]
+    pub fn public_key(&self) -> String {
+        encode(self.key_pair.public_key().as_ref())
+    }
+
+    /// Sign a payload sent to `subject` at the given time, returning the
+    /// value of the [SIGNATURE_HEADER] header.
+    pub fn sign(&self, subject: &str, payload: &[u8], now: DateTime<Utc>) -> Result<String> {
+        let timestamp = now.timestamp();
+        let nonce = encode(&random_bytes::<16>()?);
+        let signature = self
+            .key_pair
+            .sign(&message(timestamp, &nonce, subject, payload));
+        Ok(format!(
+            "{}.{}.{}",
+            timestamp,
+            nonce,
+            encode(signature.as_ref())
+        ))
+    }
+
+    /// Sign a drone binary released as `version`. Release signatures do not
+    /// expire, and should be made with a key used for nothing else.
+    #[must_use]
+    pub fn sign_release(&self, version: &str, artifact: &[u8]) -> String {
+        encode(
+            self.key_pair
+                .sign(&release_message(version, artifact))
+                .as_ref(),
+        )
+    }
+}
+
+/// Public key used to verify signed requests.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifyingKey {
+    public_key: Vec<u8>,
+}
+
+impl VerifyingKey {
+    /// Construct a key from its base64 encoding.
+    pub fn from_base64(public_key: &str) -> Result<Self> {
+        let public_key = decode(public_key)?;
+        if public_key.len() != 32 {
+            return Err(anyhow!(
+                "Ed25519 public key should be 32 bytes, got {}.",
+                public_key.len()
+            ));
+        }
+        Ok(VerifyingKey { public_key })
+    }
+
+    /// Short fingerprint of the key, as `SHA256:{hash}`, by which operators
+    /// can tell keys apart.
+    #[must_use]
+    pub fn fingerprint(&self) -> String {
+        format!(
+            "SHA256:{}",
+            encode(digest(&SHA256, &self.public_key).as_ref())
+        )
+    }
+
+    /// Verify the value of a [SIGNATURE_HEADER] header against a payload
+    /// received on `subject`, and record its nonce in `seen` so that it is
+    /// not accepted again.
+    pub fn verify(
+        &self,
+        subject: &str,
+        payload: &[u8],
+        signature: &str,
+        now: DateTime<Utc>,
+        seen: &SeenNonces,
+    ) -> Result<()> {
+        let mut parts = signature.splitn(3, '.');
+        let (timestamp, nonce, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(timestamp), Some(nonce), Some(signature)) => (timestamp, nonce, signature),
+            _ => return Err(anyhow!("Malformed signature.")),
+        };
+        let timestamp: i64 = timestamp.parse()?;
+
+        UnparsedPublicKey::new(&ED25519, &self.public_key)
+            .verify(
+                &message(timestamp, nonce, subject, payload),
+                &decode(signature)?,
+            )
+            .map_err(|_| anyhow!("Signature is invalid."))?;
+
+        if now.timestamp().abs_diff(timestamp) > MAX_SIGNATURE_AGE.as_secs() {
+            return Err(anyhow!("Signature has expired."));
+        }
+
+        seen.insert(nonce, timestamp, now)
+    }
+
+    /// Verify the signature of a drone binary released as `version`, as made
+    /// by [SigningKey::sign_release].
+    pub fn verify_release(&self, version: &str, artifact: &[u8], signature: &str) -> Result<()> {
+        UnparsedPublicKey::new(&ED25519, &self.public_key)
+            .verify(&release_message(version, artifact), &decode(signature)?)
+            .map_err(|_| anyhow!("Release signature is invalid."))
+    }
+}
+
+/// Nonces of the signatures a verifier has accepted, kept for as long as the
+/// signatures would otherwise be valid.
+#[derive(Default, Debug)]
+pub struct SeenNonces {
+    nonces: Mutex<HashMap<String, i64>>,
+}
+
+impl SeenNonces {
+    fn insert(&self, nonce: &str, timestamp: i64, now: DateTime<Utc>) -> Result<()> {
+        let mut nonces = self.nonces.lock().expect("Nonce lock was poisoned.");
+        let now = now.timestamp();
+        nonces.retain(|_, timestamp| {
+            now.saturating_sub(*timestamp) <= MAX_SIGNATURE_AGE.as_secs() as i64
+        });
+
+        if nonces.insert(nonce.to_string(), timestamp).is_some() {
+            return Err(anyhow!("Signature has already been used."));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = SigningKey::from_seed(&SigningKey::generate().unwrap()).unwrap();
+        let verifying_key = VerifyingKey::from_base64(&key.public_key()).unwrap();
+        let now = Utc::now();
+        let signature = key.sign("subject", b"payload", now).unwrap();
+
+        assert!(verifying_key
+            .verify(
+                "subject",
+                b"payload2",
+                &signature,
+                now,
+                &SeenNonces::default()
+            )
+            .is_err());
+        assert!(verifying_key
+            .verify(
+                "subject2",
+                b"payload",
+                &signature,
+                now,
+                &SeenNonces::default()
+            )
+            .is_err());
+
+        let later = now + chrono::Duration::seconds(600);
+        assert!(verifying_key
+            .verify(
+                "subject",
+                b"payload",
+                &signature,
+                later,
+                &SeenNonces::default()
+            )
+            .is_err());
+
+        let other_key = SigningKey::from_seed(&SigningKey::generate().unwrap()).unwrap();
+        let other_verifying_key = VerifyingKey::from_base64(&other_key.public_key()).unwrap();
+        assert!(other_verifying_key
+            .verify(
+                "subject",
+                b"payload",
+                &signature,
+                now,
+                &SeenNonces::default()
+            )
+            .is_err());
+
+        let seen = SeenNonces::default();
+        assert!(verifying_key
+            .verify("subject", b"payload", &signature, now, &seen)
+            .is_ok());
+        // A signed request is only accepted once.
+        assert!(verifying_key
+            .verify("subject", b"payload", &signature, now, &seen)
+            .is_err());
+
+        // Signing the same request again uses a new nonce.
+        let signature = key.sign("subject", b"payload", now).unwrap();
+        assert!(verifying_key
+            .verify("subject", b"payload", &signature, now, &seen)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_sign_and_verify_release() {
+        let key = SigningKey::from_seed(&SigningKey::generate().unwrap()).unwrap();
+        let verifying_key = VerifyingKey::from_base64(&key.public_key()).unwrap();
+        let signature = key.sign_release("0.4.0", b"binary");
+
+        assert!(verifying_key
+            .verify_release("0.4.0", b"binary", &signature)
+            .is_ok());
+        assert!(verifying_key
+            .verify_release("0.4.0", b"binary2", &signature)
+            .is_err());
+        assert!(verifying_key
+            .verify_release("0.3.0", b"binary", &signature)
+            .is_err());
+        assert!(verifying_key
+            .verify_release("0.4.0", b"binary", "garbage")
+            .is_err());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let key = SigningKey::from_seed(&SigningKey::generate().unwrap()).unwrap();
+        let verifying_key = VerifyingKey::from_base64(&key.public_key()).unwrap();
+        let other_key = SigningKey::from_seed(&SigningKey::generate().unwrap()).unwrap();
+        let other_verifying_key = VerifyingKey::from_base64(&other_key.public_key()).unwrap();
+
+        assert!(verifying_key.fingerprint().starts_with("SHA256:"));
+        assert_eq!(
+            verifying_key.fingerprint(),
+            VerifyingKey::from_base64(&key.public_key())
+                .unwrap()
+                .fingerprint()
+        );
+        assert_ne!(
+            verifying_key.fingerprint(),
+            other_verifying_key.fingerprint()
+        );
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        assert!(SigningKey::from_seed("not a key").is_err());
+        assert!(VerifyingKey::from_base64("c2hvcnQ").is_err());
+    }
+}
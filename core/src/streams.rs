@@ -0,0 +1,217 @@
+//! Declarative provisioning of the JetStream streams Plane relies on.
+//!
+//! Streams are otherwise created lazily, with their default configuration,
+//! by whichever component first uses them. Provisioning them explicitly
+//! allows retention, replication, and message age to be tuned per deployment,
+//! and updates streams which already exist to match. Durable consumers, for
+//! readers outside Plane which should not miss messages while disconnected,
+//! are provisioned along with their stream.
+
+use crate::{
+    messages::{
+        agent::{BackendStateMessage, DroneStatusMessage},
+        dns::SetDnsRecord,
+        scheduler::{AffinityMember, ClusterConfig, DroneApproval},
+    },
+    nats::{JetStreamable, TypedNats},
+};
+use anyhow::Result;
+use async_nats::jetstream::{
+    consumer::pull,
+    stream::{Config, RetentionPolicy},
+};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, time::Duration};
+
+/// Overrides applied on top of a stream's default configuration.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StreamOptions {
+    /// One of "limits", "interest", or "workqueue".
+    pub retention: Option<RetentionPolicy>,
+
+    /// Number of replicas of the stream kept in a clustered JetStream.
+    pub replicas: Option<usize>,
+
+    /// Maximum age of messages in the stream, in seconds.
+    pub max_age_secs: Option<u64>,
+
+    /// Durable consumers to create on the stream, or update to match.
+    #[serde(default)]
+    pub consumers: Vec<ConsumerOptions>,
+}
+
+impl StreamOptions {
+    #[must_use]
+    pub fn apply(&self, mut config: Config) -> Config {
+        if let Some(retention) = self.retention {
+            config.retention = retention;
+        }
+        if let Some(replicas) = self.replicas {
+            config.num_replicas = replicas;
+        }
+        if let Some(max_age_secs) = self.max_age_secs {
+            config.max_age = Duration::from_secs(max_age_secs);
+        }
+
+        config
+    }
+}
+
+/// A durable pull consumer of a stream.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConsumerOptions {
+    /// Durable name of the consumer, unique within its stream.
+    pub name: String,
+
+    /// Only messages on subjects matching this pattern, which may contain
+    /// wildcards, are delivered. By default, every message in the stream is.
+    pub filter_subject: Option<String>,
+
+    /// How long a delivered message may go unacknowledged before it is
+    /// delivered again, in seconds.
+    pub ack_wait_secs: Option<u64>,
+
+    /// Maximum number of messages delivered but not yet acknowledged.
+    pub max_ack_pending: Option<i64>,
+}
+
+impl ConsumerOptions {
+    pub fn config(&self) -> pull::Config {
+        let mut config = pull::Config {
+            durable_name: Some(self.name.clone()),
+            filter_subject: self.filter_subject.clone().unwrap_or_default(),
+            ..pull::Config::default()
+        };
+        if let Some(ack_wait_secs) = self.ack_wait_secs {
+            config.ack_wait = Duration::from_secs(ack_wait_secs);
+        }
+        if let Some(max_ack_pending) = self.max_ack_pending {
+            config.max_ack_pending = max_ack_pending;
+        }
+
+        config
+    }
+}
+
+/// Options for each stream which can be provisioned.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StreamsConfig {
+    #[serde(default)]
+    pub backend_status: StreamOptions,
+
+    #[serde(default)]
+    pub drone_status: StreamOptions,
+
+    #[serde(default)]
+    pub dns_record: StreamOptions,
+
+    #[serde(default)]
+    pub cluster_config: StreamOptions,
+
+    #[serde(default)]
+    pub drone_approval: StreamOptions,
+
+    #[serde(default)]
+    pub affinity_member: StreamOptions,
+}
+
+impl StreamsConfig {
+    /// Load options from a TOML file, in the format of the controller's
+    /// `[streams]` section without the `streams.` prefix.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Ok(config::Config::builder()
+            .add_source(config::File::from(path).format(config::FileFormat::Toml))
+            .build()?
+            .try_deserialize()?)
+    }
+
+    /// Use the same options for every stream.
+    #[must_use]
+    pub fn uniform(options: StreamOptions) -> Self {
+        StreamsConfig {
+            backend_status: options.clone(),
+            drone_status: options.clone(),
+            dns_record: options.clone(),
+            cluster_config: options.clone(),
+            drone_approval: options.clone(),
+            affinity_member: options,
+        }
+    }
+}
+
+/// Create or update a stream, then its consumers.
+async fn provision(nats: &TypedNats, options: &StreamOptions, config: Config) -> Result<()> {
+    let name = config.name.clone();
+    nats.provision_jetstream(options.apply(config)).await?;
+    for consumer in &options.consumers {
+        nats.provision_jetstream_consumer(&name, consumer.config())
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Create or update every stream in `config`, along with its consumers.
+pub async fn init_streams(nats: &TypedNats, config: &StreamsConfig) -> Result<()> {
+    provision(nats, &config.backend_status, BackendStateMessage::config()).await?;
+    provision(nats, &config.drone_status, DroneStatusMessage::config()).await?;
+    provision(nats, &config.dns_record, SetDnsRecord::config()).await?;
+    provision(nats, &config.cluster_config, ClusterConfig::config()).await?;
+    provision(nats, &config.drone_approval, DroneApproval::config()).await?;
+    provision(nats, &config.affinity_member, AffinityMember::config()).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_stream_options() {
+        let options = StreamOptions {
+            retention: Some(RetentionPolicy::Interest),
+            replicas: Some(3),
+            max_age_secs: None,
+            consumers: Vec::new(),
+        };
+
+        let config = options.apply(SetDnsRecord::config());
+        assert_eq!(RetentionPolicy::Interest, config.retention);
+        assert_eq!(3, config.num_replicas);
+        assert_eq!(SetDnsRecord::config().max_age, config.max_age);
+        assert_eq!(SetDnsRecord::config().subjects, config.subjects);
+    }
+
+    #[test]
+    fn test_consumers_from_file() {
+        let path = std::env::temp_dir().join(format!("plane-streams-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            [backend_status]
+            replicas = 3
+
+            [[backend_status.consumers]]
+            name = "audit"
+            filter_subject = "backend.*.status"
+            ack_wait_secs = 30
+            "#,
+        )
+        .unwrap();
+        let config = StreamsConfig::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(Some(3), config.backend_status.replicas);
+        assert!(config.drone_status.consumers.is_empty());
+
+        let consumer = config.backend_status.consumers[0].config();
+        assert_eq!(Some("audit".to_string()), consumer.durable_name);
+        assert_eq!("backend.*.status", consumer.filter_subject);
+        assert_eq!(Duration::from_secs(30), consumer.ack_wait);
+        assert_eq!(
+            pull::Config::default().max_ack_pending,
+            consumer.max_ack_pending
+        );
+    }
+}
@@ -0,0 +1,551 @@
+//! The hierarchy of NATS subjects Plane uses, in one place.
+//!
+//! Each subject is built by one function here, which both the publishing
+//! side of a message (its [TypedMessage::subject](crate::nats::TypedMessage::subject))
+//! and the subscribing side (its `subscribe_subject`) call, so that the two
+//! cannot drift apart. Parameters are typed, so passing a drone ID where a
+//! backend ID belongs, or leaving one out, fails to compile. Any parameter
+//! accepts [Any] in place of a value, to build a wildcard subject.
+
+use crate::{
+    messages::dns::DnsRecordType,
+    types::{BackendGroupId, BackendId, ClusterName, DroneId, ProgressId},
+};
+
+/// A value which appears as one token of a subject.
+pub trait SubjectToken {
+    fn token(&self) -> String;
+}
+
+impl SubjectToken for str {
+    fn token(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl SubjectToken for BackendId {
+    fn token(&self) -> String {
+        self.id().to_string()
+    }
+}
+
+impl SubjectToken for DroneId {
+    fn token(&self) -> String {
+        self.id().to_string()
+    }
+}
+
+impl SubjectToken for BackendGroupId {
+    fn token(&self) -> String {
+        self.id().to_string()
+    }
+}
+
+impl SubjectToken for ProgressId {
+    fn token(&self) -> String {
+        self.id().to_string()
+    }
+}
+
+impl SubjectToken for ClusterName {
+    /// Cluster names contain dots, which would split them across tokens.
+    fn token(&self) -> String {
+        self.subject_name()
+    }
+}
+
+impl SubjectToken for DnsRecordType {
+    fn token(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// Matches any value of a parameter, as the `*` wildcard.
+#[derive(Clone, Copy, Debug)]
+pub struct Any;
+
+/// A parameter of a subject: either a value, or any value.
+pub enum Token<'a, T: ?Sized> {
+    Value(&'a T),
+    Any,
+}
+
+impl<'a, T: ?Sized> From<&'a T> for Token<'a, T> {
+    fn from(value: &'a T) -> Self {
+        Token::Value(value)
+    }
+}
+
+impl<'a, T: ?Sized> From<Any> for Token<'a, T> {
+    fn from(_: Any) -> Self {
+        Token::Any
+    }
+}
+
+/// Builds a subject one token at a time.
+pub struct SubjectBuilder {
+    tokens: Vec<String>,
+}
+
+impl SubjectBuilder {
+    #[must_use]
+    pub fn new(root: &str) -> Self {
+        SubjectBuilder {
+            tokens: vec![root.to_string()],
+        }
+    }
+
+    #[must_use]
+    pub fn literal(mut self, literal: &str) -> Self {
+        self.tokens.push(literal.to_string());
+        self
+    }
+
+    #[must_use]
+    pub fn token<T: SubjectToken + ?Sized>(mut self, token: Token<'_, T>) -> Self {
+        self.tokens.push(match token {
+            Token::Value(value) => value.token(),
+            Token::Any => "*".to_string(),
+        });
+        self
+    }
+
+    #[must_use]
+    pub fn build(self) -> String {
+        self.tokens.join(".")
+    }
+}
+
+fn backend<'a>(backend: impl Into<Token<'a, BackendId>>) -> SubjectBuilder {
+    SubjectBuilder::new("backend").token(backend.into())
+}
+
+fn drone<'a>(drone: impl Into<Token<'a, DroneId>>) -> SubjectBuilder {
+    SubjectBuilder::new("drone").token(drone.into())
+}
+
+fn cluster<'a>(cluster: impl Into<Token<'a, ClusterName>>) -> SubjectBuilder {
+    SubjectBuilder::new("cluster").token(cluster.into())
+}
+
+fn cluster_backend<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    backend: impl Into<Token<'a, BackendId>>,
+) -> SubjectBuilder {
+    cluster(cluster_name)
+        .literal("backend")
+        .token(backend.into())
+}
+
+fn cluster_drone<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    drone: impl Into<Token<'a, DroneId>>,
+) -> SubjectBuilder {
+    cluster(cluster_name).literal("drone").token(drone.into())
+}
+
+// backend.{backend}.*
+
+pub fn backend_log<'a>(backend_id: impl Into<Token<'a, BackendId>>) -> String {
+    backend(backend_id).literal("log").build()
+}
+
+pub fn backend_stats<'a>(backend_id: impl Into<Token<'a, BackendId>>) -> String {
+    backend(backend_id).literal("stats").build()
+}
+
+pub fn backend_stats_request<'a>(backend_id: impl Into<Token<'a, BackendId>>) -> String {
+    backend(backend_id)
+        .literal("stats")
+        .literal("request")
+        .build()
+}
+
+pub fn backend_access<'a>(backend_id: impl Into<Token<'a, BackendId>>) -> String {
+    backend(backend_id).literal("access").build()
+}
+
+pub fn backend_metadata<'a>(backend_id: impl Into<Token<'a, BackendId>>) -> String {
+    backend(backend_id).literal("metadata").build()
+}
+
+pub fn backend_status<'a>(backend_id: impl Into<Token<'a, BackendId>>) -> String {
+    backend(backend_id).literal("status").build()
+}
+
+// drone.*
+
+pub fn drone_status<'a>(drone_id: impl Into<Token<'a, DroneId>>) -> String {
+    drone(drone_id).literal("status").build()
+}
+
+pub fn drone_spawn<'a>(drone_id: impl Into<Token<'a, DroneId>>) -> String {
+    drone(drone_id).literal("spawn").build()
+}
+
+pub fn drone_connect() -> String {
+    SubjectBuilder::new("drone").literal("register").build()
+}
+
+// cluster.{cluster}.*
+
+pub fn cluster_certificate<'a>(cluster_name: impl Into<Token<'a, ClusterName>>) -> String {
+    cluster(cluster_name).literal("certificate").build()
+}
+
+pub fn cluster_dns<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    kind: impl Into<Token<'a, DnsRecordType>>,
+) -> String {
+    cluster(cluster_name)
+        .literal("dns")
+        .token(kind.into())
+        .build()
+}
+
+pub fn cluster_schedule<'a>(cluster_name: impl Into<Token<'a, ClusterName>>) -> String {
+    cluster(cluster_name).literal("schedule").build()
+}
+
+pub fn cluster_schedule_batch<'a>(cluster_name: impl Into<Token<'a, ClusterName>>) -> String {
+    cluster(cluster_name).literal("schedule_batch").build()
+}
+
+pub fn cluster_group_status<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    group: impl Into<Token<'a, BackendGroupId>>,
+) -> String {
+    cluster(cluster_name)
+        .literal("group")
+        .token(group.into())
+        .literal("status")
+        .build()
+}
+
+pub fn cluster_drone_approval<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    public_key: impl Into<Token<'a, str>>,
+) -> String {
+    cluster(cluster_name)
+        .literal("drone_approval")
+        .token(public_key.into())
+        .build()
+}
+
+pub fn cluster_affinity_member<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    backend: impl Into<Token<'a, BackendId>>,
+) -> String {
+    cluster(cluster_name)
+        .literal("affinity_member")
+        .token(backend.into())
+        .build()
+}
+
+pub fn cluster_capacity<'a>(cluster_name: impl Into<Token<'a, ClusterName>>) -> String {
+    cluster(cluster_name).literal("capacity").build()
+}
+
+pub fn cluster_desired_drones<'a>(cluster_name: impl Into<Token<'a, ClusterName>>) -> String {
+    cluster(cluster_name).literal("desired_drones").build()
+}
+
+pub fn cluster_config<'a>(cluster_name: impl Into<Token<'a, ClusterName>>) -> String {
+    cluster(cluster_name).literal("config").build()
+}
+
+pub fn cluster_drone_update<'a>(cluster_name: impl Into<Token<'a, ClusterName>>) -> String {
+    cluster(cluster_name).literal("drone_update").build()
+}
+
+// cluster.{cluster}.backend.{backend}.*
+
+pub fn backend_terminate<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    backend_id: impl Into<Token<'a, BackendId>>,
+) -> String {
+    cluster_backend(cluster_name, backend_id)
+        .literal("terminate")
+        .build()
+}
+
+pub fn backend_progress<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    backend_id: impl Into<Token<'a, BackendId>>,
+    progress_id: impl Into<Token<'a, ProgressId>>,
+) -> String {
+    cluster_backend(cluster_name, backend_id)
+        .literal("progress")
+        .token(progress_id.into())
+        .build()
+}
+
+pub fn backend_migrate<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    backend_id: impl Into<Token<'a, BackendId>>,
+) -> String {
+    cluster_backend(cluster_name, backend_id)
+        .literal("migrate")
+        .build()
+}
+
+pub fn backend_update_metadata<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    backend_id: impl Into<Token<'a, BackendId>>,
+) -> String {
+    cluster_backend(cluster_name, backend_id)
+        .literal("metadata")
+        .build()
+}
+
+pub fn backend_exec<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    backend_id: impl Into<Token<'a, BackendId>>,
+) -> String {
+    cluster_backend(cluster_name, backend_id)
+        .literal("exec")
+        .build()
+}
+
+pub fn backend_exec_output<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    backend_id: impl Into<Token<'a, BackendId>>,
+    exec_id: impl Into<Token<'a, str>>,
+) -> String {
+    cluster_backend(cluster_name, backend_id)
+        .literal("exec")
+        .token(exec_id.into())
+        .literal("output")
+        .build()
+}
+
+pub fn backend_attach<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    backend_id: impl Into<Token<'a, BackendId>>,
+) -> String {
+    cluster_backend(cluster_name, backend_id)
+        .literal("attach")
+        .build()
+}
+
+pub fn backend_attach_input<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    backend_id: impl Into<Token<'a, BackendId>>,
+    attach_id: impl Into<Token<'a, str>>,
+) -> String {
+    cluster_backend(cluster_name, backend_id)
+        .literal("attach")
+        .token(attach_id.into())
+        .literal("input")
+        .build()
+}
+
+pub fn backend_attach_output<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    backend_id: impl Into<Token<'a, BackendId>>,
+    attach_id: impl Into<Token<'a, str>>,
+) -> String {
+    cluster_backend(cluster_name, backend_id)
+        .literal("attach")
+        .token(attach_id.into())
+        .literal("output")
+        .build()
+}
+
+// cluster.{cluster}.drone.{drone}.*
+
+/// `action` is one of the requests a drone listens for, e.g. `drain`.
+fn drone_action<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    drone_id: impl Into<Token<'a, DroneId>>,
+    action: &str,
+) -> String {
+    cluster_drone(cluster_name, drone_id)
+        .literal(action)
+        .build()
+}
+
+pub fn drone_register<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    drone_id: impl Into<Token<'a, DroneId>>,
+) -> String {
+    drone_action(cluster_name, drone_id, "register")
+}
+
+pub fn drone_terminate_all<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    drone_id: impl Into<Token<'a, DroneId>>,
+) -> String {
+    drone_action(cluster_name, drone_id, "terminate_all")
+}
+
+pub fn drone_seed_image<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    drone_id: impl Into<Token<'a, DroneId>>,
+) -> String {
+    drone_action(cluster_name, drone_id, "seed_image")
+}
+
+pub fn drone_drain<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    drone_id: impl Into<Token<'a, DroneId>>,
+) -> String {
+    drone_action(cluster_name, drone_id, "drain")
+}
+
+pub fn drone_approve<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    drone_id: impl Into<Token<'a, DroneId>>,
+) -> String {
+    drone_action(cluster_name, drone_id, "approve")
+}
+
+pub fn drone_lifecycle<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    drone_id: impl Into<Token<'a, DroneId>>,
+) -> String {
+    drone_action(cluster_name, drone_id, "lifecycle")
+}
+
+pub fn drone_schedule_maintenance<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    drone_id: impl Into<Token<'a, DroneId>>,
+) -> String {
+    drone_action(cluster_name, drone_id, "schedule_maintenance")
+}
+
+pub fn drone_maintenance<'a>(
+    cluster_name: impl Into<Token<'a, ClusterName>>,
+    drone_id: impl Into<Token<'a, DroneId>>,
+) -> String {
+    drone_action(cluster_name, drone_id, "maintenance")
+}
+
+// scheduler.*
+
+fn scheduler(request: &str) -> String {
+    SubjectBuilder::new("scheduler").literal(request).build()
+}
+
+pub fn scheduler_image_stats() -> String {
+    scheduler("image_stats")
+}
+
+pub fn scheduler_list_clusters() -> String {
+    scheduler("list_clusters")
+}
+
+pub fn scheduler_lookup_backend() -> String {
+    scheduler("lookup_backend")
+}
+
+pub fn scheduler_terminate_backend() -> String {
+    scheduler("terminate_backend")
+}
+
+pub fn scheduler_leader() -> String {
+    scheduler("leader")
+}
+
+pub fn scheduler_apply_cluster_config() -> String {
+    scheduler("apply_cluster_config")
+}
+
+// Everything else.
+
+pub fn acme_set_dns_record() -> String {
+    SubjectBuilder::new("acme")
+        .literal("set_dns_record")
+        .build()
+}
+
+pub fn controller_logs() -> String {
+    SubjectBuilder::new("logs").literal("controller").build()
+}
+
+pub fn drone_logs<'a>(drone_id: impl Into<Token<'a, DroneId>>) -> String {
+    SubjectBuilder::new("logs")
+        .literal("drone")
+        .token(drone_id.into())
+        .build()
+}
+
+pub fn jetstream_alert<'a>(stream: impl Into<Token<'a, str>>) -> String {
+    SubjectBuilder::new("diagnostics")
+        .literal("jetstream")
+        .token(stream.into())
+        .build()
+}
+
+/// Whether `value` can be used as one token of a subject, as IDs chosen by
+/// requesters are. A token may not be empty, or contain a separator, a
+/// wildcard, or whitespace, any of which would make the subject built from
+/// it reach other subjects.
+#[must_use]
+pub fn is_single_token(value: &str) -> bool {
+    !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| matches!(c, '.' | '*' | '>') || c.is_whitespace())
+}
+
+/// Whether `subject` matches `pattern`, which may contain the `*` and `>`
+/// wildcards.
+#[must_use]
+pub fn matches(pattern: &str, subject: &str) -> bool {
+    let mut subject_tokens = subject.split('.');
+    for pattern_token in pattern.split('.') {
+        match (pattern_token, subject_tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (pattern_token, Some(subject_token)) if pattern_token == subject_token => {}
+            _ => return false,
+        }
+    }
+    subject_tokens.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subjects() {
+        let cluster = ClusterName::new("plane.test");
+        let backend = BackendId::new("backend1".into());
+
+        assert_eq!("backend.backend1.status", backend_status(&backend));
+        assert_eq!("backend.*.status", backend_status(Any));
+        assert_eq!(
+            "cluster.plane_test.backend.backend1.terminate",
+            backend_terminate(&cluster, &backend)
+        );
+        assert_eq!(
+            "cluster.plane_test.backend.*.progress.progress1",
+            backend_progress(&cluster, Any, &"progress1".parse().unwrap())
+        );
+        assert_eq!("cluster.*.drone.*.drain", drone_drain(Any, Any));
+        assert_eq!("scheduler.leader", scheduler_leader());
+    }
+
+    #[test]
+    fn test_matches() {
+        let backend = BackendId::new("backend1".into());
+
+        assert!(matches(&backend_status(Any), &backend_status(&backend)));
+        assert!(!matches(&backend_status(Any), &backend_log(&backend)));
+        assert!(matches("backend.>", &backend_status(&backend)));
+        assert!(!matches("backend.>", "backend"));
+        assert!(!matches("backend.*", &backend_status(&backend)));
+    }
+
+    #[test]
+    fn test_is_single_token() {
+        assert!(is_single_token("3f6c9a1e-attach"));
+        assert!(!is_single_token(""));
+        assert!(!is_single_token("a.b"));
+        assert!(!is_single_token("*"));
+        assert!(!is_single_token(">"));
+        assert!(!is_single_token("a b"));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::subjects;
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 use std::{convert::Infallible, fmt::Display, str::FromStr};
 use uuid::Uuid;
@@ -68,6 +70,105 @@ impl BackendId {
         let id = Uuid::new_v4();
         BackendId(id.to_string())
     }
+
+    /// Whether this ID can be used as the backend's hostname label: 1 to 63
+    /// lowercase ASCII letters, digits, and hyphens, not starting or ending
+    /// with a hyphen.
+    #[must_use]
+    pub fn is_valid_hostname_label(&self) -> bool {
+        (1..=63).contains(&self.0.len())
+            && !self.0.starts_with('-')
+            && !self.0.ends_with('-')
+            && self
+                .0
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+    }
+}
+
+/// Identifies a group of replica backends whose status is aggregated by the
+/// controller. Since it becomes one token of the subject the group's status
+/// is published to, it must be a single subject token (see
+/// [crate::subjects::is_single_token]).
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "String")]
+pub struct BackendGroupId(String);
+
+impl Display for BackendGroupId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl TryFrom<String> for BackendGroupId {
+    type Error = anyhow::Error;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        if !subjects::is_single_token(&id) {
+            return Err(anyhow!("Invalid backend group ID {:?}.", id));
+        }
+        Ok(BackendGroupId(id))
+    }
+}
+
+impl FromStr for BackendGroupId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        BackendGroupId::try_from(s.to_string())
+    }
+}
+
+impl BackendGroupId {
+    #[must_use]
+    pub fn id(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Identifies the progress messages of a spawn, chosen by its caller. Since
+/// it becomes one token of the subject progress is published to, it must be
+/// a single subject token (see [crate::subjects::is_single_token]).
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "String")]
+pub struct ProgressId(String);
+
+impl Display for ProgressId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl TryFrom<String> for ProgressId {
+    type Error = anyhow::Error;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        if !subjects::is_single_token(&id) {
+            return Err(anyhow!("Invalid progress ID {:?}.", id));
+        }
+        Ok(ProgressId(id))
+    }
+}
+
+impl FromStr for ProgressId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ProgressId::try_from(s.to_string())
+    }
+}
+
+impl ProgressId {
+    #[must_use]
+    pub fn id(&self) -> &str {
+        &self.0
+    }
+
+    #[must_use]
+    pub fn new_random() -> Self {
+        let id = Uuid::new_v4();
+        ProgressId(id.to_string())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
@@ -101,3 +202,39 @@ impl Display for ClusterName {
         self.0.fmt(f)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_id() {
+        assert!("3f6c9a1e".parse::<ProgressId>().is_ok());
+        assert!("a.b".parse::<ProgressId>().is_err());
+        assert!(">".parse::<ProgressId>().is_err());
+
+        assert_eq!(
+            "3f6c9a1e",
+            serde_json::from_str::<ProgressId>("\"3f6c9a1e\"")
+                .unwrap()
+                .id()
+        );
+        assert!(serde_json::from_str::<ProgressId>("\"*\"").is_err());
+    }
+
+    #[test]
+    fn test_backend_group_id() {
+        assert!("lobby-replicas".parse::<BackendGroupId>().is_ok());
+        for id in ["", "a.b", "*", ">", "a b"] {
+            assert!(id.parse::<BackendGroupId>().is_err());
+        }
+
+        assert_eq!(
+            "lobby-replicas",
+            serde_json::from_str::<BackendGroupId>("\"lobby-replicas\"")
+                .unwrap()
+                .id()
+        );
+        assert!(serde_json::from_str::<BackendGroupId>("\"lobby.>\"").is_err());
+    }
+}
@@ -0,0 +1,52 @@
+//! Compatibility between the versions of Plane components.
+
+/// The version of Plane, which every crate in the workspace shares.
+pub const PLANE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Parse the `major.minor.patch` core of a version, ignoring any pre-release
+/// or build suffix.
+fn parse(version: &str) -> Option<(u64, u64, u64)> {
+    let version = version.split(['-', '+']).next()?;
+    let mut parts = version.split('.').map(str::parse);
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => Some((major, minor, patch)),
+        _ => None,
+    }
+}
+
+/// Whether two versions of Plane components can work together, following
+/// Cargo's interpretation of semver: versions are compatible if they agree on
+/// their leftmost non-zero component. Unparseable versions are never
+/// compatible.
+#[must_use]
+pub fn is_compatible(version: &str, other: &str) -> bool {
+    match (parse(version), parse(other)) {
+        (Some((0, 0, patch)), Some((0, 0, other_patch))) => patch == other_patch,
+        (Some((0, minor, _)), Some((0, other_minor, _))) => minor == other_minor,
+        (Some((major, _, _)), Some((other_major, _, _))) => major == other_major,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_compatible() {
+        assert!(is_compatible("1.2.3", "1.0.0"));
+        assert!(is_compatible("1.2.3", "1.9.0-rc.1"));
+        assert!(!is_compatible("1.2.3", "2.0.0"));
+
+        assert!(is_compatible("0.3.4", "0.3.0"));
+        assert!(!is_compatible("0.3.4", "0.4.0"));
+        assert!(!is_compatible("0.3.4", "1.3.4"));
+
+        assert!(is_compatible("0.0.1", "0.0.1+build"));
+        assert!(!is_compatible("0.0.1", "0.0.2"));
+
+        assert!(!is_compatible("0.3.4", "unknown"));
+        assert!(!is_compatible("0.3", "0.3.0"));
+        assert!(is_compatible(PLANE_VERSION, PLANE_VERSION));
+    }
+}
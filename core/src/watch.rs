@@ -0,0 +1,155 @@
+//! Watching the states of backends from outside of Plane.
+//!
+//! Every [BackendStateMessage] is persisted in the `backend_status`
+//! JetStream stream. [WatchBackends] reads it in order, and pairs each state
+//! change with a [ResumeToken]. A consumer which stores the token of each
+//! change along with the effect of processing it can restart from the
+//! stored token and see every later change exactly once, without managing
+//! JetStream consumers itself. Changes which the stream's retention policy
+//! has discarded by the time a watch resumes are skipped.
+
+use crate::{
+    messages::agent::BackendStateMessage,
+    nats::{JetstreamSubscription, TypedNats},
+    types::BackendId,
+};
+use anyhow::{Context, Result};
+use async_nats::jetstream::consumer::DeliverPolicy;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, str::FromStr};
+
+/// Position of a state change in the backend state stream. Watches resumed
+/// from a token start with the change after it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct ResumeToken(u64);
+
+impl Display for ResumeToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for ResumeToken {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(ResumeToken(s.parse().context("Invalid resume token.")?))
+    }
+}
+
+/// A change in the state of a backend, seen by a watch.
+#[derive(Debug)]
+pub struct BackendStateChange {
+    pub message: BackendStateMessage,
+
+    /// Token to resume a watch after this change.
+    pub resume_token: ResumeToken,
+}
+
+/// Options of a watch on the states of backends. By default, every state
+/// change still in the stream is delivered, followed by live changes.
+#[derive(Clone, Debug, Default)]
+pub struct WatchBackends {
+    backend: Option<BackendId>,
+    resume_token: Option<ResumeToken>,
+    only_new: bool,
+}
+
+impl WatchBackends {
+    #[must_use]
+    pub fn new() -> Self {
+        WatchBackends::default()
+    }
+
+    /// Only watch the given backend.
+    #[must_use]
+    pub fn with_backend(mut self, backend: BackendId) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Start with the change after the one the token was returned with.
+    #[must_use]
+    pub fn with_resume_token(mut self, resume_token: Option<ResumeToken>) -> Self {
+        self.resume_token = resume_token;
+        self
+    }
+
+    /// Without a resume token, only deliver changes made after the watch
+    /// starts.
+    #[must_use]
+    pub fn with_only_new(mut self, only_new: bool) -> Self {
+        self.only_new = only_new;
+        self
+    }
+
+    fn deliver_policy(&self) -> DeliverPolicy {
+        match self.resume_token {
+            Some(ResumeToken(sequence)) => DeliverPolicy::ByStartSequence {
+                start_sequence: sequence + 1,
+            },
+            None if self.only_new => DeliverPolicy::New,
+            None => DeliverPolicy::All,
+        }
+    }
+
+    pub async fn watch(self, nats: &TypedNats) -> Result<BackendWatch> {
+        let subject = match &self.backend {
+            Some(backend) => BackendStateMessage::subscribe_subject(backend),
+            None => BackendStateMessage::wildcard_subject(),
+        };
+        let subscription = nats
+            .subscribe_jetstream_from(subject, self.deliver_policy())
+            .await?;
+
+        Ok(BackendWatch { subscription })
+    }
+}
+
+/// A running watch on the states of backends.
+pub struct BackendWatch {
+    subscription: JetstreamSubscription<BackendStateMessage>,
+}
+
+impl BackendWatch {
+    /// The next state change, in the order the changes were published.
+    /// Returns None if the connection to NATS is closed.
+    pub async fn next(&mut self) -> Option<BackendStateChange> {
+        let (message, sequence) = self.subscription.next_with_sequence().await?;
+
+        Some(BackendStateChange {
+            message,
+            resume_token: ResumeToken(sequence),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deliver_policy() {
+        assert!(matches!(
+            WatchBackends::new().deliver_policy(),
+            DeliverPolicy::All
+        ));
+        assert!(matches!(
+            WatchBackends::new().with_only_new(true).deliver_policy(),
+            DeliverPolicy::New
+        ));
+
+        let resume_token: ResumeToken = "41".parse().unwrap();
+        assert_eq!("41", resume_token.to_string());
+        assert!(matches!(
+            WatchBackends::new()
+                .with_only_new(true)
+                .with_resume_token(Some(resume_token))
+                .deliver_policy(),
+            DeliverPolicy::ByStartSequence { start_sequence: 42 }
+        ));
+
+        assert!("abc".parse::<ResumeToken>().is_err());
+    }
+}
@@ -11,11 +11,13 @@ fn integration_test_impl(item: proc_macro2::TokenStream) -> proc_macro2::TokenSt
     );
     let mut sig = func.sig.clone();
     sig.asyncness = None;
+    let attrs = func.attrs;
     let block = func.block;
     let name = func.sig.ident.to_string();
 
     quote! {
         #[test]
+        #(#attrs)*
         #sig {
             plane_dev::run_test(#name, async move {
                 #block
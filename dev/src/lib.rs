@@ -10,8 +10,11 @@ use std::{
 };
 
 pub mod container;
+pub mod mock_engine;
 pub mod resources;
+pub mod test_image;
 pub mod timeout;
+pub mod traffic;
 pub mod util;
 
 thread_local! {
@@ -0,0 +1,429 @@
+//! An in-memory [Engine] for exercising the drone's executor without Docker.
+//!
+//! Each loaded backend is served by a local HTTP [Server], so the executor's
+//! readiness checks succeed. Status changes can be scripted with
+//! [MockEngine::set_status], and failures injected at each backend state with
+//! [MockEngineBuilder]. Checkpoints are passed between engines through
+//! [MockCheckpoints], sealed like real ones.
+
+use crate::resources::server::Server;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::{future::ready, Stream, StreamExt};
+use plane_core::{
+    messages::agent::{
+        AttachInput, AttachOutputChunk, BackendStatsMessage, DroneLogMessage, ExecOutputChunk,
+        SpawnRequest, TerminalSize,
+    },
+    sealing::{SealedData, SealingKey},
+    types::BackendId,
+};
+use plane_drone::agent::engine::{Engine, EngineBackendStatus, ImagePullProgress};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+/// A failure injected into every backend run by a [MockEngine].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MockFailure {
+    /// `load` returns an error, so the backend goes from Loading to
+    /// ErrorLoading.
+    Load,
+
+    /// `load` never completes, as if an image pull hung.
+    HangLoad,
+
+    /// The backend fails instead of running, so it goes from Starting to
+    /// ErrorStarting.
+    Start,
+
+    /// The backend reaches the given status this long after it is loaded,
+    /// interrupting the executor (typically while the backend is Ready).
+    AfterLoad {
+        delay: Duration,
+        status: EngineBackendStatus,
+    },
+
+    /// `backend_status` returns an error.
+    Status,
+
+    /// `stop` returns an error.
+    Stop,
+}
+
+/// Checkpoints sealed by one [MockEngine] for another, by name, standing in
+/// for the directory drones share.
+pub type MockCheckpoints = Arc<Mutex<HashMap<String, SealedData>>>;
+
+struct MockBackend {
+    status: EngineBackendStatus,
+
+    /// Serves the backend while it is running.
+    _server: Option<Server>,
+}
+
+struct MockEngineState {
+    backends: Mutex<HashMap<BackendId, MockBackend>>,
+    failures: Vec<MockFailure>,
+    interrupt_sender: UnboundedSender<BackendId>,
+    interrupt_receiver: Mutex<Option<UnboundedReceiver<BackendId>>>,
+    loaded: Mutex<Vec<BackendId>>,
+    stopped: Mutex<Vec<BackendId>>,
+
+    /// Checkpoints this engine made, which it can restore without opening.
+    local_checkpoints: Mutex<HashMap<String, BackendId>>,
+    shared_checkpoints: MockCheckpoints,
+
+    /// Progress reported for the image pull of every loaded backend.
+    pull_progress: Option<ImagePullProgress>,
+}
+
+#[derive(Default)]
+pub struct MockEngineBuilder {
+    failures: Vec<MockFailure>,
+    checkpoints: MockCheckpoints,
+    pull_progress: Option<ImagePullProgress>,
+}
+
+impl MockEngineBuilder {
+    /// Inject a failure into every backend the engine runs.
+    #[must_use]
+    pub fn with_failure(mut self, failure: MockFailure) -> Self {
+        self.failures.push(failure);
+        self
+    }
+
+    /// Share checkpoints with other engines built with the same store.
+    #[must_use]
+    pub fn with_checkpoints(mut self, checkpoints: MockCheckpoints) -> Self {
+        self.checkpoints = checkpoints;
+        self
+    }
+
+    /// Report the given image pull progress for every backend loaded.
+    #[must_use]
+    pub fn with_pull_progress(mut self, progress: ImagePullProgress) -> Self {
+        self.pull_progress = Some(progress);
+        self
+    }
+
+    #[must_use]
+    pub fn build(self) -> MockEngine {
+        let (interrupt_sender, interrupt_receiver) = unbounded_channel();
+
+        MockEngine {
+            state: Arc::new(MockEngineState {
+                backends: Mutex::default(),
+                failures: self.failures,
+                interrupt_sender,
+                interrupt_receiver: Mutex::new(Some(interrupt_receiver)),
+                loaded: Mutex::default(),
+                stopped: Mutex::default(),
+                local_checkpoints: Mutex::default(),
+                shared_checkpoints: self.checkpoints,
+                pull_progress: self.pull_progress,
+            }),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MockEngine {
+    state: Arc<MockEngineState>,
+}
+
+impl Default for MockEngine {
+    fn default() -> Self {
+        MockEngine::builder().build()
+    }
+}
+
+impl MockEngine {
+    #[must_use]
+    pub fn builder() -> MockEngineBuilder {
+        MockEngineBuilder::default()
+    }
+
+    fn fails(&self, failure: MockFailure) -> bool {
+        self.state.failures.contains(&failure)
+    }
+
+    /// Change the status of a backend, as if it had changed outside of Plane,
+    /// and interrupt the executor so that it notices.
+    pub fn set_status(&self, backend: &BackendId, status: EngineBackendStatus) {
+        Self::set_status_inner(&self.state, backend, status);
+    }
+
+    fn set_status_inner(state: &MockEngineState, backend: &BackendId, status: EngineBackendStatus) {
+        if let Some(mock_backend) = state.backends.lock().unwrap().get_mut(backend) {
+            mock_backend.status = status;
+            if !matches!(status, EngineBackendStatus::Running { .. }) {
+                mock_backend._server = None;
+            }
+        }
+        let _ = state.interrupt_sender.send(backend.clone());
+    }
+
+    /// Current status of a backend, as the executor would see it.
+    #[must_use]
+    pub fn status(&self, backend: &BackendId) -> EngineBackendStatus {
+        self.state
+            .backends
+            .lock()
+            .unwrap()
+            .get(backend)
+            .map_or(EngineBackendStatus::Unknown, |b| b.status)
+    }
+
+    /// Backends which have been loaded, in order.
+    #[must_use]
+    pub fn loaded(&self) -> Vec<BackendId> {
+        self.state.loaded.lock().unwrap().clone()
+    }
+
+    /// Backends which have been stopped, in order.
+    #[must_use]
+    pub fn stopped(&self) -> Vec<BackendId> {
+        self.state.stopped.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Engine for MockEngine {
+    fn interrupt_stream(&self) -> Pin<Box<dyn Stream<Item = BackendId> + Send>> {
+        match self.state.interrupt_receiver.lock().unwrap().take() {
+            Some(receiver) => Box::pin(UnboundedReceiverStream::new(receiver)),
+            None => {
+                tracing::warn!("MockEngine interrupt stream was already taken.");
+                Box::pin(tokio_stream::empty())
+            }
+        }
+    }
+
+    async fn load(&self, spawn_request: &SpawnRequest) -> Result<()> {
+        let backend_id = spawn_request.backend_id.clone();
+        self.state.loaded.lock().unwrap().push(backend_id.clone());
+
+        if self.fails(MockFailure::Load) {
+            return Err(anyhow!("Injected load failure."));
+        }
+        if self.fails(MockFailure::HangLoad) {
+            return std::future::pending().await;
+        }
+
+        let mock_backend = if self.fails(MockFailure::Start) {
+            MockBackend {
+                status: EngineBackendStatus::Failed { exit_code: 1 },
+                _server: None,
+            }
+        } else {
+            let server = Server::new(|_| async { "Hello from MockEngine.".to_string() }).await?;
+            MockBackend {
+                status: EngineBackendStatus::Running {
+                    addr: server.address,
+                },
+                _server: Some(server),
+            }
+        };
+        self.state
+            .backends
+            .lock()
+            .unwrap()
+            .insert(backend_id.clone(), mock_backend);
+
+        for failure in &self.state.failures {
+            if let MockFailure::AfterLoad { delay, status } = *failure {
+                let state = self.state.clone();
+                let backend_id = backend_id.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    Self::set_status_inner(&state, &backend_id, status);
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn image_pull_progress(&self, backend: &BackendId) -> Option<ImagePullProgress> {
+        if !self.state.loaded.lock().unwrap().contains(backend) {
+            return None;
+        }
+        self.state.pull_progress
+    }
+
+    /// Seals the backend's ID as its memory, and removes the backend.
+    async fn checkpoint(&self, backend: &BackendId, recipient: &str) -> Result<String> {
+        if !matches!(self.status(backend), EngineBackendStatus::Running { .. }) {
+            return Err(anyhow!("Backend is not running."));
+        }
+
+        let checkpoint = format!("{}-checkpoint", backend);
+        let sealed = SealedData::seal(recipient, backend.id().as_bytes())?;
+        self.state
+            .shared_checkpoints
+            .lock()
+            .unwrap()
+            .insert(checkpoint.clone(), sealed);
+        self.state
+            .local_checkpoints
+            .lock()
+            .unwrap()
+            .insert(checkpoint.clone(), backend.clone());
+        self.state.backends.lock().unwrap().remove(backend);
+
+        Ok(checkpoint)
+    }
+
+    /// Loads the backend once its checkpoint is opened and found to hold its
+    /// ID.
+    async fn restore(
+        &self,
+        spawn_request: &SpawnRequest,
+        checkpoint: &str,
+        key: Option<Arc<SealingKey>>,
+    ) -> Result<()> {
+        let local = self
+            .state
+            .local_checkpoints
+            .lock()
+            .unwrap()
+            .remove(checkpoint);
+        let backend = match local {
+            Some(backend) => backend,
+            None => {
+                let sealed = self
+                    .state
+                    .shared_checkpoints
+                    .lock()
+                    .unwrap()
+                    .remove(checkpoint)
+                    .ok_or_else(|| anyhow!("No checkpoint {}.", checkpoint))?;
+                let key = key.ok_or_else(|| anyhow!("No key to open checkpoint with."))?;
+                BackendId::new(String::from_utf8(key.open(&sealed)?)?)
+            }
+        };
+        if backend != spawn_request.backend_id {
+            return Err(anyhow!("Checkpoint {} is of another backend.", checkpoint));
+        }
+
+        self.load(spawn_request).await
+    }
+
+    async fn discard_checkpoint(&self, checkpoint: &str) -> Result<()> {
+        self.state
+            .local_checkpoints
+            .lock()
+            .unwrap()
+            .remove(checkpoint);
+        Ok(())
+    }
+
+    async fn backend_status(&self, backend: &BackendId) -> Result<EngineBackendStatus> {
+        if self.fails(MockFailure::Status) {
+            return Err(anyhow!("Injected status failure."));
+        }
+
+        Ok(self.status(backend))
+    }
+
+    async fn stop(&self, backend: &BackendId) -> Result<()> {
+        self.state.stopped.lock().unwrap().push(backend.clone());
+
+        if self.fails(MockFailure::Stop) {
+            return Err(anyhow!("Injected stop failure."));
+        }
+
+        self.state.backends.lock().unwrap().remove(backend);
+        Ok(())
+    }
+
+    async fn list_backends(&self) -> Result<Vec<BackendId>> {
+        Ok(self
+            .state
+            .backends
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect())
+    }
+
+    async fn seed_image(&self, _image: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn image_names(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    async fn data_dir(&self) -> Result<String> {
+        Ok(std::env::temp_dir().to_string_lossy().to_string())
+    }
+
+    fn log_stream(
+        &self,
+        _backend: &BackendId,
+    ) -> Pin<Box<dyn Stream<Item = DroneLogMessage> + Send>> {
+        Box::pin(tokio_stream::empty())
+    }
+
+    fn stats_stream(
+        &self,
+        _backend: &BackendId,
+    ) -> Pin<Box<dyn Stream<Item = BackendStatsMessage> + Send>> {
+        Box::pin(tokio_stream::empty())
+    }
+
+    /// Echoes the command to stdout, then exits successfully. The command
+    /// `sleep` instead never writes output or exits.
+    async fn exec(
+        &self,
+        backend: &BackendId,
+        command: &[String],
+    ) -> Result<Pin<Box<dyn Stream<Item = ExecOutputChunk> + Send>>> {
+        if !matches!(self.status(backend), EngineBackendStatus::Running { .. }) {
+            return Err(anyhow!("Backend is not running."));
+        }
+        if command.first().map(String::as_str) == Some("sleep") {
+            return Ok(Box::pin(futures::stream::pending()));
+        }
+
+        Ok(Box::pin(tokio_stream::iter(vec![
+            ExecOutputChunk::Stdout(format!("{}\n", command.join(" "))),
+            ExecOutputChunk::Exit { code: Some(0) },
+        ])))
+    }
+
+    /// Echoes input back as output until the requester detaches, then exits
+    /// successfully.
+    async fn attach(
+        &self,
+        backend: &BackendId,
+        _command: &[String],
+        _size: TerminalSize,
+        input: Pin<Box<dyn Stream<Item = AttachInput> + Send>>,
+    ) -> Result<Pin<Box<dyn Stream<Item = AttachOutputChunk> + Send>>> {
+        if !matches!(self.status(backend), EngineBackendStatus::Running { .. }) {
+            return Err(anyhow!("Backend is not running."));
+        }
+
+        let output = input
+            .take_while(|input| ready(*input != AttachInput::Detach))
+            .filter_map(|input| {
+                ready(match input {
+                    AttachInput::Data(data) => Some(AttachOutputChunk::Data(data)),
+                    _ => None,
+                })
+            })
+            .chain(futures::stream::once(ready(AttachOutputChunk::Exit {
+                code: Some(0),
+            })));
+        Ok(Box::pin(output))
+    }
+}
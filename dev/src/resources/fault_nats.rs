@@ -0,0 +1,269 @@
+use super::nats::Nats;
+use crate::util::random_loopback_ip;
+use anyhow::{anyhow, Result};
+use plane_core::{
+    nats::{SubscribeSubject, TypedMessage, TypedNats},
+    nats_connection::NatsConnectionSpec,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    select,
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    task::JoinHandle,
+    time::sleep,
+};
+
+const NATS_PORT: u16 = 4222;
+
+/// A fault applied to messages delivered on matching subjects.
+#[derive(Clone, Copy, Debug)]
+pub enum Fault {
+    /// Drop messages, as if they were lost.
+    Drop,
+
+    /// Deliver each message after a delay. Messages on other subjects are not
+    /// held up, so they may overtake delayed messages.
+    Delay(Duration),
+
+    /// Deliver each message twice.
+    Duplicate,
+
+    /// Hold each message back until the next matching message arrives, then
+    /// deliver the two in swapped order.
+    Reorder,
+}
+
+struct FaultRule {
+    pattern: String,
+    fault: Fault,
+}
+
+/// A proxy in front of a NATS server which injects faults into the messages
+/// it delivers to clients connected through it, for testing how components
+/// cope with an unreliable connection.
+///
+/// Faults are matched against the subject a message is delivered on, so they
+/// apply to core NATS subscriptions but not to JetStream consumers, which
+/// deliver on inbox subjects. Messages published by clients are passed
+/// through unchanged.
+pub struct FaultInjectingNats {
+    connection_spec: NatsConnectionSpec,
+    rules: Arc<Mutex<Vec<FaultRule>>>,
+    handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl FaultInjectingNats {
+    pub async fn new(nats: &Nats) -> Result<Self> {
+        let upstream = nats.connection_spec();
+        let upstream_host = upstream
+            .hosts
+            .first()
+            .cloned()
+            .ok_or_else(|| anyhow!("NATS connection spec has no hosts."))?;
+        let address = SocketAddr::new(random_loopback_ip().into(), NATS_PORT);
+        let listener = TcpListener::bind(address).await?;
+
+        let rules: Arc<Mutex<Vec<FaultRule>>> = Arc::default();
+        let handles: Arc<Mutex<Vec<JoinHandle<()>>>> = Arc::default();
+
+        let accept_handle = {
+            let rules = rules.clone();
+            let handles = handles.clone();
+            tokio::spawn(async move {
+                while let Ok((client, _)) = listener.accept().await {
+                    let handle = tokio::spawn(proxy_connection(
+                        client,
+                        upstream_host.clone(),
+                        rules.clone(),
+                    ));
+                    handles.lock().unwrap().push(handle);
+                }
+            })
+        };
+        handles.lock().unwrap().push(accept_handle);
+
+        Ok(FaultInjectingNats {
+            connection_spec: NatsConnectionSpec {
+                hosts: vec![address.to_string()],
+                ..upstream
+            },
+            rules,
+            handles,
+        })
+    }
+
+    pub fn connection_spec(&self) -> &NatsConnectionSpec {
+        &self.connection_spec
+    }
+
+    /// Connect through the proxy, so that faults apply to the messages this
+    /// connection receives.
+    pub async fn connection(&self) -> Result<TypedNats> {
+        self.connection_spec.connect().await
+    }
+
+    /// Apply a fault to messages of a given type. If several faults match a
+    /// message, the first one injected applies.
+    pub fn inject<T: TypedMessage>(&self, subject: SubscribeSubject<T>, fault: Fault) {
+        tracing::info!(subject = subject.subject(), ?fault, "Injecting fault.");
+        self.rules.lock().unwrap().push(FaultRule {
+            pattern: subject.subject().to_string(),
+            fault,
+        });
+    }
+
+    /// Stop injecting faults. Messages held back by [Fault::Reorder] stay
+    /// held until another message arrives on their subject.
+    pub fn clear(&self) {
+        self.rules.lock().unwrap().clear();
+    }
+}
+
+impl Drop for FaultInjectingNats {
+    fn drop(&mut self) {
+        for handle in self.handles.lock().unwrap().iter() {
+            handle.abort();
+        }
+    }
+}
+
+async fn proxy_connection(
+    client: TcpStream,
+    upstream_host: String,
+    rules: Arc<Mutex<Vec<FaultRule>>>,
+) {
+    let result = async {
+        let server = TcpStream::connect((upstream_host.as_str(), NATS_PORT)).await?;
+        let (mut client_read, mut client_write) = client.into_split();
+        let (server_read, mut server_write) = server.into_split();
+        let (send, mut recv) = unbounded_channel::<Vec<u8>>();
+
+        let write_client = async move {
+            while let Some(frame) = recv.recv().await {
+                client_write.write_all(&frame).await?;
+            }
+            Ok::<(), anyhow::Error>(())
+        };
+
+        select! {
+            result = tokio::io::copy(&mut client_read, &mut server_write) => result.map(|_| ())?,
+            result = deliver_frames(server_read, send, &rules) => result?,
+            result = write_client => result?,
+        }
+
+        Ok::<(), anyhow::Error>(())
+    }
+    .await;
+
+    if let Err(error) = result {
+        tracing::info!(?error, "Fault-injecting NATS connection closed.");
+    }
+}
+
+/// Read frames sent by the server and pass them on to the client, applying
+/// faults to messages.
+async fn deliver_frames(
+    server: OwnedReadHalf,
+    send: UnboundedSender<Vec<u8>>,
+    rules: &Mutex<Vec<FaultRule>>,
+) -> Result<()> {
+    let mut server = BufReader::new(server);
+    // Messages held back by Fault::Reorder, by the pattern of the rule which
+    // held them.
+    let mut held: HashMap<String, Vec<u8>> = HashMap::new();
+
+    loop {
+        let mut frame = Vec::new();
+        if server.read_until(b'\n', &mut frame).await? == 0 {
+            return Ok(());
+        }
+
+        let subject = match parse_message_line(&frame) {
+            Some((subject, length)) => {
+                // The payload is followed by a CRLF.
+                let start = frame.len();
+                frame.resize(start + length + 2, 0);
+                server.read_exact(&mut frame[start..]).await?;
+                subject
+            }
+            None => {
+                send.send(frame)?;
+                continue;
+            }
+        };
+
+        let rule = rules
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|rule| subject_matches(&rule.pattern, &subject))
+            .map(|rule| (rule.pattern.clone(), rule.fault));
+
+        match rule {
+            None => send.send(frame)?,
+            Some((_, Fault::Drop)) => tracing::info!(%subject, "Dropping message."),
+            Some((_, Fault::Delay(delay))) => {
+                tracing::info!(%subject, ?delay, "Delaying message.");
+                let send = send.clone();
+                tokio::spawn(async move {
+                    sleep(delay).await;
+                    let _ = send.send(frame);
+                });
+            }
+            Some((_, Fault::Duplicate)) => {
+                tracing::info!(%subject, "Duplicating message.");
+                send.send(frame.clone())?;
+                send.send(frame)?;
+            }
+            Some((pattern, Fault::Reorder)) => match held.remove(&pattern) {
+                Some(previous) => {
+                    tracing::info!(%subject, "Delivering messages out of order.");
+                    send.send(frame)?;
+                    send.send(previous)?;
+                }
+                None => {
+                    held.insert(pattern, frame);
+                }
+            },
+        }
+    }
+}
+
+/// If a protocol line introduces a message (`MSG` or `HMSG`), return the
+/// message's subject and the length of the payload which follows it.
+fn parse_message_line(line: &[u8]) -> Option<(String, usize)> {
+    let line = std::str::from_utf8(line).ok()?;
+    let mut tokens = line.split_whitespace();
+    match tokens.next()? {
+        "MSG" | "HMSG" => (),
+        _ => return None,
+    }
+    let subject = tokens.next()?.to_string();
+    // The total payload length is the last token of both MSG and HMSG.
+    let length = tokens.last()?.parse().ok()?;
+
+    Some((subject, length))
+}
+
+/// Whether a subject matches a pattern which may contain NATS wildcards.
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut pattern = pattern.split('.');
+    let mut subject = subject.split('.');
+
+    loop {
+        match (pattern.next(), subject.next()) {
+            (Some(">"), Some(_)) => return true,
+            (Some("*"), Some(_)) => (),
+            (Some(p), Some(s)) if p == s => (),
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
@@ -1,4 +1,5 @@
 pub mod certs;
+pub mod fault_nats;
 pub mod nats;
 pub mod pebble;
 pub mod server;
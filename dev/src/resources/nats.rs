@@ -26,6 +26,8 @@ impl Nats {
                 token: NATS_TOKEN.into(),
             }),
             hosts: vec![self.container.ip.to_string()],
+            tls: None,
+            compact_encoding: false,
         }
     }
 
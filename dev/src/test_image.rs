@@ -0,0 +1,69 @@
+//! Failures which the test image (`dev/test-server`) can be told to inject,
+//! for exercising the paths of the drone's state machine which need a real
+//! container to misbehave.
+//!
+//! The behavior is passed to the image in the `TEST_BEHAVIOR` environment
+//! variable, as `crash_after:<secs>`, `exit_after:<secs>`,
+//! `oom_after:<secs>`, `hang_on_sigterm`, or `exit_on_sigterm`. Without one,
+//! the image behaves as it always has, serving requests until it is killed.
+//!
+//! The published test image may predate these behaviors, so tests which use
+//! them are ignored unless run with `--ignored` against an image built from
+//! `dev/test-server`.
+
+use plane_core::messages::agent::SpawnRequest;
+use std::time::Duration;
+
+/// Environment variable the test image reads its behavior from.
+pub const TEST_BEHAVIOR_ENV: &str = "TEST_BEHAVIOR";
+
+/// Memory limit given to backends which run out of memory on purpose, so
+/// that they are killed quickly without pressuring the host.
+const OOM_MEMORY_LIMIT_BYTES: i64 = 64 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TestBehavior {
+    /// Exit with status 1 after the given time.
+    CrashAfter(Duration),
+
+    /// Exit with status 0 after the given time.
+    ExitAfter(Duration),
+
+    /// Allocate memory without bound after the given time, until killed for
+    /// exceeding the backend's memory limit.
+    OomAfter(Duration),
+
+    /// Ignore SIGTERM, so that stopping the backend must fall back to
+    /// killing it.
+    HangOnSigterm,
+
+    /// Exit with status 0 on SIGTERM, so that stopping the backend does not
+    /// wait to kill it.
+    ExitOnSigterm,
+}
+
+impl TestBehavior {
+    /// The value of [TEST_BEHAVIOR_ENV] for this behavior.
+    #[must_use]
+    pub fn env_value(&self) -> String {
+        match self {
+            TestBehavior::CrashAfter(delay) => format!("crash_after:{}", delay.as_secs_f64()),
+            TestBehavior::ExitAfter(delay) => format!("exit_after:{}", delay.as_secs_f64()),
+            TestBehavior::OomAfter(delay) => format!("oom_after:{}", delay.as_secs_f64()),
+            TestBehavior::HangOnSigterm => "hang_on_sigterm".into(),
+            TestBehavior::ExitOnSigterm => "exit_on_sigterm".into(),
+        }
+    }
+
+    /// Make a spawn request for the test image behave this way.
+    pub fn apply(&self, request: &mut SpawnRequest) {
+        request
+            .executable
+            .env
+            .insert(TEST_BEHAVIOR_ENV.into(), self.env_value());
+
+        if let TestBehavior::OomAfter(_) = self {
+            request.executable.resource_limits.memory_limit_bytes = Some(OOM_MEMORY_LIMIT_BYTES);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Simulated client traffic against a backend behind the drone proxy, and
+//! assertions on when the backend is swept for being idle.
+//!
+//! The proxy records activity in whole seconds and flushes it to the database
+//! once a second, so a sweep is expected within a small window around
+//! `max_idle_secs` after the last activity, rather than at an exact instant.
+
+use crate::timeout::timeout;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use futures::{SinkExt, StreamExt};
+use plane_core::{
+    messages::agent::{BackendState, BackendStateMessage},
+    nats::TypedSubscription,
+};
+use reqwest::ClientBuilder;
+use std::{net::SocketAddr, time::Duration};
+use tokio::{net::TcpStream, time::Instant};
+use tokio_tungstenite::{
+    tungstenite::{handshake::client::generate_key, protocol::Message},
+    WebSocketStream,
+};
+
+/// How long before `max_idle_secs` has elapsed a sweep may happen, because
+/// activity times are truncated to the second.
+pub const SWEEP_EARLY_TOLERANCE: Duration = Duration::from_secs(1);
+
+/// How long after `max_idle_secs` has elapsed a sweep may happen, to allow for
+/// the proxy's flush interval and the state message reaching NATS.
+pub const SWEEP_LATE_TOLERANCE: Duration = Duration::from_secs(3);
+
+/// A pattern of client activity.
+#[derive(Clone, Copy, Debug)]
+pub enum TrafficPattern {
+    /// An HTTP request every `interval`, until `duration` has elapsed.
+    Http {
+        interval: Duration,
+        duration: Duration,
+    },
+
+    /// A WebSocket connection held open for `duration` without sending
+    /// anything on it.
+    IdleWebSocket { duration: Duration },
+
+    /// A WebSocket connection held open for `duration`, exchanging a message
+    /// with the backend every `interval`.
+    ActiveWebSocket {
+        interval: Duration,
+        duration: Duration,
+    },
+}
+
+/// Sends traffic to one backend through a proxy serving plain HTTP.
+pub struct TrafficClient {
+    proxy_address: SocketAddr,
+    hostname: String,
+}
+
+impl TrafficClient {
+    pub fn new(proxy_address: SocketAddr, hostname: &str) -> Self {
+        TrafficClient {
+            proxy_address,
+            hostname: hostname.to_string(),
+        }
+    }
+
+    pub async fn http_get(&self, path: &str) -> Result<String> {
+        let client = ClientBuilder::new()
+            .resolve(&self.hostname, self.proxy_address)
+            .build()?;
+        let url = format!(
+            "http://{}:{}{}",
+            self.hostname,
+            self.proxy_address.port(),
+            path
+        );
+
+        Ok(client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?)
+    }
+
+    pub async fn websocket(&self, path: &str) -> Result<WebSocketStream<TcpStream>> {
+        let tcp_stream = TcpStream::connect(self.proxy_address).await?;
+        let req = hyper::Request::get(format!("ws://{}{}", self.hostname, path))
+            .header("Sec-WebSocket-Key", generate_key())
+            .header("Connection", "Upgrade")
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Host", &self.hostname)
+            .body(())?;
+
+        let (ws_stream, _) = tokio_tungstenite::client_async(req, tcp_stream).await?;
+        Ok(ws_stream)
+    }
+
+    /// Generate traffic following `pattern`, returning the time of the last
+    /// activity.
+    pub async fn run(&self, pattern: TrafficPattern) -> Result<DateTime<Utc>> {
+        match pattern {
+            TrafficPattern::Http { interval, duration } => {
+                let deadline = Instant::now() + duration;
+                loop {
+                    self.http_get("/").await?;
+                    if Instant::now() + interval > deadline {
+                        break;
+                    }
+                    tokio::time::sleep(interval).await;
+                }
+            }
+            TrafficPattern::IdleWebSocket { duration } => {
+                let mut ws_stream = self.websocket("/ws").await?;
+                tokio::time::sleep(duration).await;
+                ws_stream.close(None).await?;
+            }
+            TrafficPattern::ActiveWebSocket { interval, duration } => {
+                let mut ws_stream = self.websocket("/ws").await?;
+                let deadline = Instant::now() + duration;
+                while Instant::now() + interval <= deadline {
+                    tokio::time::sleep(interval).await;
+                    ws_stream.send(Message::Text("ping".into())).await?;
+                    timeout(5_000, "Echo from backend.", ws_stream.next())
+                        .await?
+                        .ok_or_else(|| anyhow!("WebSocket closed by backend."))??;
+                }
+                ws_stream.close(None).await?;
+            }
+        }
+
+        Ok(Utc::now())
+    }
+}
+
+/// Wait for the backend to be swept, and check that the drone swept it
+/// `max_idle` after `last_activity` (within tolerance), and not before.
+pub async fn expect_swept_after(
+    sub: &mut TypedSubscription<BackendStateMessage>,
+    last_activity: DateTime<Utc>,
+    max_idle: Duration,
+) -> Result<()> {
+    let max_idle = chrono::Duration::from_std(max_idle)?;
+    let early_tolerance = chrono::Duration::from_std(SWEEP_EARLY_TOLERANCE)?;
+    let late_tolerance = chrono::Duration::from_std(SWEEP_LATE_TOLERANCE)?;
+    let earliest = last_activity + max_idle - early_tolerance;
+    let latest = last_activity + max_idle + late_tolerance;
+
+    // Leave time for the state message to arrive after the latest sweep time.
+    let deadline =
+        Instant::now() + (latest - Utc::now()).to_std().unwrap_or_default() + SWEEP_LATE_TOLERANCE;
+
+    let swept = loop {
+        let message = tokio::time::timeout_at(deadline, sub.next())
+            .await
+            .map_err(|_| anyhow!("Backend was not swept by {}.", latest))?
+            .ok_or_else(|| anyhow!("Backend state subscription closed."))?;
+
+        match message.value.state {
+            BackendState::Swept => break message.value.time,
+            state if state.terminal() => {
+                return Err(anyhow!("Backend reached {:?} instead of Swept.", state))
+            }
+            _ => (),
+        }
+    };
+
+    if swept < earliest || swept > latest {
+        return Err(anyhow!(
+            "Backend was swept {}ms after its last activity, expected between {}ms and {}ms.",
+            (swept - last_activity).num_milliseconds(),
+            (max_idle - early_tolerance).num_milliseconds(),
+            (max_idle + late_tolerance).num_milliseconds(),
+        ));
+    }
+
+    Ok(())
+}
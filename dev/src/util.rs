@@ -1,5 +1,7 @@
 use anyhow::{anyhow, Result};
-use plane_core::messages::agent::{DockerExecutableConfig, SpawnRequest};
+use plane_core::messages::agent::{
+    DockerExecutableConfig, IdlePolicy, ObservabilityOptions, SpawnRequest,
+};
 use plane_core::messages::scheduler::ScheduleRequest;
 use plane_core::types::BackendId;
 use plane_core::types::ClusterName;
@@ -109,13 +111,23 @@ pub fn base_spawn_request() -> SpawnRequest {
         drone_id: DroneId::new_random(),
         metadata: vec![("foo".into(), "bar".into())].into_iter().collect(),
         max_idle_secs: Duration::from_secs(10),
+        max_lifetime_secs: None,
+        port_ready_timeout_secs: None,
         executable: DockerExecutableConfig {
             image: TEST_IMAGE.into(),
             env: vec![("PORT".into(), "8080".into())].into_iter().collect(),
             credentials: None,
             resource_limits: Default::default(),
+            egress_policy: Default::default(),
         },
         bearer_token: None,
+        requested_at: None,
+        restore_checkpoint: None,
+        idle_policy: IdlePolicy::default(),
+        progress_id: None,
+        observability: ObservabilityOptions::default(),
+        path_routing: false,
+        backend_url: None,
     }
 }
 
@@ -124,13 +136,24 @@ pub fn base_scheduler_request() -> ScheduleRequest {
         cluster: ClusterName::new("plane.test"),
         metadata: vec![("foo".into(), "bar".into())].into_iter().collect(),
         backend_id: None,
-        max_idle_secs: Duration::from_secs(10),
+        max_idle_secs: Some(Duration::from_secs(10)),
+        max_lifetime_secs: None,
+        port_ready_timeout_secs: None,
         executable: DockerExecutableConfig {
             env: vec![("PORT".into(), "8080".into())].into_iter().collect(),
             image: TEST_IMAGE.into(),
             credentials: None,
             resource_limits: Default::default(),
+            egress_policy: Default::default(),
         },
         require_bearer_token: false,
+        group: None,
+        dry_run: false,
+        schedule_deadline_ms: None,
+        idle_policy: IdlePolicy::default(),
+        arch: None,
+        progress_id: None,
+        affinity_group: None,
+        observability: ObservabilityOptions::default(),
     }
 }
@@ -1,9 +1,50 @@
+use futures_util::{future, StreamExt, TryStreamExt};
+use hyper::header::{CONNECTION, UPGRADE};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server};
+use hyper::{Body, Request, Response, Server, StatusCode};
 use std::env;
 use std::process::exit;
 use std::time::Duration;
 use std::{convert::Infallible, net::SocketAddr};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_tungstenite::tungstenite::{handshake::derive_accept_key, protocol::Role};
+use tokio_tungstenite::WebSocketStream;
+
+/// Accept a WebSocket connection, and echo back every message sent on it.
+fn handle_websocket(mut req: Request<Body>) -> Response<Body> {
+    let accept_key = match req.headers().get("sec-websocket-key") {
+        Some(key) => derive_accept_key(key.as_bytes()),
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .unwrap()
+        }
+    };
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(&mut req).await {
+            Ok(upgraded) => {
+                let ws_stream =
+                    WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                let (write, read) = ws_stream.split();
+                let _ = read
+                    .try_filter(|msg| future::ready(msg.is_text() || msg.is_binary()))
+                    .forward(write)
+                    .await;
+            }
+            Err(e) => eprintln!("upgrade error: {}", e),
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(CONNECTION, "upgrade")
+        .header(UPGRADE, "websocket")
+        .header("sec-websocket-accept", accept_key)
+        .body(Body::empty())
+        .unwrap()
+}
 
 async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
     println!("path: {}", req.uri().path());
@@ -13,9 +54,96 @@ async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
         exit(exit_code);
     }
 
+    if req.uri().path() == "/ws" {
+        return Ok(handle_websocket(req));
+    }
+
     Ok(Response::new("Hello World!".into()))
 }
 
+/// Failure to inject, from the TEST_BEHAVIOR environment variable. See
+/// `plane_dev::test_image::TestBehavior`, which produces it.
+enum TestBehavior {
+    /// Exit with status 1 after the given number of seconds.
+    CrashAfter(Duration),
+
+    /// Exit with status 0 after the given number of seconds.
+    ExitAfter(Duration),
+
+    /// Allocate memory without bound after the given number of seconds,
+    /// until the kernel kills the process.
+    OomAfter(Duration),
+
+    /// Ignore SIGTERM, so that the process must be killed.
+    HangOnSigterm,
+
+    /// Exit with status 0 on SIGTERM, which the process would otherwise
+    /// ignore as PID 1 in its container.
+    ExitOnSigterm,
+}
+
+impl TestBehavior {
+    fn from_env() -> Option<Self> {
+        let behavior = env::var("TEST_BEHAVIOR").ok()?;
+        let (name, secs) = match behavior.split_once(':') {
+            Some((name, secs)) => {
+                let secs: f64 = secs.parse().expect("Couldn't parse TEST_BEHAVIOR seconds.");
+                (name, Some(Duration::from_secs_f64(secs)))
+            }
+            None => (behavior.as_str(), None),
+        };
+
+        Some(match (name, secs) {
+            ("crash_after", Some(secs)) => TestBehavior::CrashAfter(secs),
+            ("exit_after", Some(secs)) => TestBehavior::ExitAfter(secs),
+            ("oom_after", Some(secs)) => TestBehavior::OomAfter(secs),
+            ("hang_on_sigterm", None) => TestBehavior::HangOnSigterm,
+            ("exit_on_sigterm", None) => TestBehavior::ExitOnSigterm,
+            _ => panic!("Unknown TEST_BEHAVIOR {}.", behavior),
+        })
+    }
+
+    async fn run(self) {
+        match self {
+            TestBehavior::CrashAfter(delay) => {
+                tokio::time::sleep(delay).await;
+                println!("Crashing.");
+                exit(1)
+            }
+            TestBehavior::ExitAfter(delay) => {
+                tokio::time::sleep(delay).await;
+                println!("Exiting.");
+                exit(0)
+            }
+            TestBehavior::OomAfter(delay) => {
+                tokio::time::sleep(delay).await;
+                println!("Allocating memory until killed.");
+                let mut allocations: Vec<Vec<u8>> = Vec::new();
+                loop {
+                    // Touch every page, so that the memory is really used.
+                    allocations.push(vec![1; 16 << 20]);
+                    tokio::task::yield_now().await;
+                }
+            }
+            TestBehavior::HangOnSigterm => {
+                let mut sigterm =
+                    signal(SignalKind::terminate()).expect("Couldn't listen for SIGTERM.");
+                loop {
+                    sigterm.recv().await;
+                    println!("Ignoring SIGTERM.");
+                }
+            }
+            TestBehavior::ExitOnSigterm => {
+                let mut sigterm =
+                    signal(SignalKind::terminate()).expect("Couldn't listen for SIGTERM.");
+                sigterm.recv().await;
+                println!("Exiting on SIGTERM.");
+                exit(0)
+            }
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     if let Some(exit_code) = env::var("EXIT_CODE")
@@ -35,6 +163,10 @@ async fn main() {
         .expect("Couldn't parse $PORT as u16.");
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
 
+    if let Some(behavior) = TestBehavior::from_env() {
+        tokio::spawn(behavior.run());
+    }
+
     let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
 
     let server = Server::bind(&addr).serve(make_svc);
@@ -1,13 +1,15 @@
 use anyhow::{anyhow, Result};
+use chrono::Utc;
 use integration_test::integration_test;
 use plane_core::{
+    error::PlaneError,
     messages::{
         agent::{
             BackendState, BackendStateMessage, BackendStatsMessage, DroneConnectRequest,
-            DroneStatusMessage, SpawnRequest, TerminationRequest,
+            DroneStatusMessage, SpawnRequest, StatsRequest, TerminationRequest,
         },
         dns::{DnsRecordType, SetDnsRecord},
-        scheduler::DrainDrone,
+        scheduler::{DrainDrone, DroneMaintenanceEvent, MaintenancePhase, MaintenanceWindow},
     },
     nats::{TypedNats, TypedSubscription},
     types::{BackendId, ClusterName, DroneId},
@@ -17,28 +19,46 @@ use plane_dev::{
     resources::nats::Nats,
     resources::server::Server,
     scratch_dir,
+    test_image::TestBehavior,
     timeout::{expect_to_stay_alive, timeout, LivenessGuard},
     util::{base_spawn_request, random_loopback_ip},
 };
 use plane_drone::config::DockerConfig;
-use plane_drone::{agent::AgentOptions, database::DroneDatabase, ip::IpSource};
+use plane_drone::{
+    agent::{executor::DEFAULT_LOAD_TIMEOUT, AgentOptions},
+    database::DroneDatabase,
+    ip::IpSource,
+};
 use std::net::{IpAddr, Ipv4Addr};
 use std::time::Duration;
 use tokio::time::{sleep, Instant};
 
 const CLUSTER_DOMAIN: &str = "plane.test";
+const ADMIN_TOKEN: &str = "admin-token";
 
 struct Agent {
     #[allow(unused)]
     agent_guard: LivenessGuard<NeverResult>,
     pub ip: Ipv4Addr,
     pub db: DroneDatabase,
+    pub admin_port: u16,
 }
 
 impl Agent {
     pub async fn new(nats: &Nats, drone_id: &DroneId) -> Result<Agent> {
+        Self::with_maintenance_windows(nats, drone_id, Vec::new()).await
+    }
+
+    pub async fn with_maintenance_windows(
+        nats: &Nats,
+        drone_id: &DroneId,
+        maintenance_windows: Vec<MaintenanceWindow>,
+    ) -> Result<Agent> {
         let ip = random_loopback_ip();
         let db = DroneDatabase::new(&scratch_dir("agent").join("drone.db")).await?;
+        let admin_port = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?
+            .port();
 
         let agent_opts = AgentOptions {
             db: db.clone(),
@@ -46,7 +66,30 @@ impl Agent {
             nats: nats.connection().await?,
             cluster_domain: ClusterName::new(CLUSTER_DOMAIN),
             ip: IpSource::Literal(IpAddr::V4(ip)),
+            ipv6: None,
             docker_options: DockerConfig::default(),
+            containerd_options: None,
+            maintenance_windows,
+            heartbeat_interval: Duration::from_secs(4),
+            load_timeout: DEFAULT_LOAD_TIMEOUT,
+            max_backends: None,
+            spawn_limit: None,
+            proxy_self_test: None,
+            proxy_metrics: None,
+            admin_port: Some(admin_port),
+            admin_token: Some(ADMIN_TOKEN.into()),
+            metadata_port: None,
+            max_lifetime_extension: Duration::from_secs(86400),
+            min_disk_free_bytes: None,
+            cert_paths: None,
+            log_archive: None,
+            hooks: None,
+            exec_access: None,
+            migrate_public_key: None,
+            terminate_all_public_key: None,
+            update: None,
+            registration_key: None,
+            certificate_public_key: None,
         };
 
         let agent_guard = expect_to_stay_alive(plane_drone::agent::run_agent(agent_opts));
@@ -55,6 +98,7 @@ impl Agent {
             agent_guard,
             ip,
             db,
+            admin_port,
         })
     }
 }
@@ -145,19 +189,18 @@ impl MockController {
     }
 
     pub async fn spawn_backend(&self, request: &SpawnRequest) -> Result<()> {
-        let result = timeout(
+        timeout(
             10_000,
             "Spawn request acknowledged by agent.",
             self.nats.request(request),
         )
-        .await??;
+        .await???;
 
-        assert!(result, "Spawn request should result in response of _true_.");
         Ok(())
     }
 
     pub async fn terminate_backend(&self, request: &TerminationRequest) -> Result<()> {
-        timeout(10_000, "Termination!", self.nats.request(request)).await??;
+        timeout(10_000, "Termination!", self.nats.request(request)).await???;
 
         Ok(())
     }
@@ -272,18 +315,103 @@ async fn drone_sends_draining_status() {
         .await
         .unwrap();
 
+    drain_drone(&nats_connection, &drone_id, true).await;
+
+    controller_mock
+        .expect_status_message(&drone_id, &ClusterName::new("plane.test"), false, 0)
+        .await
+        .unwrap();
+}
+
+/// Send a DrainDrone request to the drone and wait for it to be acknowledged.
+async fn drain_drone(nats: &TypedNats, drone_id: &DroneId, drain: bool) {
     timeout(
         1_000,
         "Did not receive DrainDrone response",
-        nats_connection.request(&DrainDrone {
+        nats.request(&DrainDrone {
             cluster: ClusterName::new(CLUSTER_DOMAIN),
             drone: drone_id.clone(),
-            drain: true,
+            drain,
         }),
     )
     .await
     .unwrap()
     .unwrap();
+}
+
+#[integration_test]
+async fn maintenance_window_does_not_end_operator_drain() {
+    let nats = Nats::new().await.unwrap();
+    let nats_connection = nats.connection().await.unwrap();
+    let mut controller_mock = MockController::new(nats_connection.clone()).await.unwrap();
+    let drone_id = DroneId::new_random();
+    let mut maintenance_sub = nats_connection
+        .subscribe(DroneMaintenanceEvent::wildcard_subject())
+        .await
+        .unwrap();
+    let window = MaintenanceWindow {
+        start: Utc::now(),
+        duration: Duration::from_secs(4),
+        drain: false,
+    };
+    let agent = Agent::with_maintenance_windows(&nats, &drone_id, vec![window])
+        .await
+        .unwrap();
+
+    controller_mock
+        .expect_handshake(&drone_id, agent.ip)
+        .await
+        .unwrap();
+
+    // The operator drains the drone while the window is in effect.
+    drain_drone(&nats_connection, &drone_id, true).await;
+
+    loop {
+        let event = timeout(
+            10_000,
+            "Maintenance window should complete.",
+            maintenance_sub.next(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        if event.value.phase == MaintenancePhase::Completed {
+            break;
+        }
+    }
+
+    controller_mock
+        .expect_status_message(&drone_id, &ClusterName::new("plane.test"), false, 0)
+        .await
+        .unwrap();
+}
+
+#[integration_test]
+async fn cancelled_drain_does_not_end_maintenance_window() {
+    let nats = Nats::new().await.unwrap();
+    let nats_connection = nats.connection().await.unwrap();
+    let mut controller_mock = MockController::new(nats_connection.clone()).await.unwrap();
+    let drone_id = DroneId::new_random();
+    let window = MaintenanceWindow {
+        start: Utc::now(),
+        duration: Duration::from_secs(600),
+        drain: false,
+    };
+    let agent = Agent::with_maintenance_windows(&nats, &drone_id, vec![window])
+        .await
+        .unwrap();
+
+    controller_mock
+        .expect_handshake(&drone_id, agent.ip)
+        .await
+        .unwrap();
+    controller_mock
+        .expect_status_message(&drone_id, &ClusterName::new("plane.test"), false, 0)
+        .await
+        .unwrap();
+
+    drain_drone(&nats_connection, &drone_id, true).await;
+    drain_drone(&nats_connection, &drone_id, false).await;
 
     controller_mock
         .expect_status_message(&drone_id, &ClusterName::new("plane.test"), false, 0)
@@ -425,10 +553,25 @@ async fn stats_are_acquired() {
     assert!(stat.value.cpu_use_percent >= 0.);
     assert!(stat.value.mem_use_percent >= 0.);
 
+    // The drone keeps the latest stats, so they can also be requested.
+    let stats_request = StatsRequest {
+        backend_id: request.backend_id.clone(),
+    };
+    let response = connection.request(&stats_request).await.unwrap().unwrap();
+    assert!(response.stats.is_some());
+
     state_subscription
         .wait_for_state(BackendState::Swept, 60_000)
         .await
         .unwrap();
+
+    // Once the backend has stopped, its drone says so.
+    let response = connection.request(&stats_request).await.unwrap();
+    assert!(
+        matches!(response, Err(PlaneError::NotFound { .. })),
+        "Expected a not found error, got {:?}.",
+        response
+    );
 }
 
 #[integration_test]
@@ -670,6 +813,8 @@ async fn handle_termination_request() {
     let termination_request = TerminationRequest {
         backend_id: request.backend_id.clone(),
         cluster_id: ClusterName::new(CLUSTER_DOMAIN),
+        drain: false,
+        grace_period_secs: Duration::ZERO,
     };
     controller_mock
         .terminate_backend(&termination_request)
@@ -681,3 +826,268 @@ async fn handle_termination_request() {
         .await
         .unwrap();
 }
+
+#[integration_test]
+async fn backend_swept_after_max_lifetime() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let mut controller_mock = MockController::new(connection.clone()).await.unwrap();
+    let drone_id = DroneId::new_random();
+    let agent = Agent::new(&nats, &drone_id).await.unwrap();
+
+    let mut request = base_spawn_request();
+    request.drone_id = drone_id.clone();
+    // The backend would not be swept for being idle during the test.
+    request.max_idle_secs = Duration::from_secs(10_000);
+    request.max_lifetime_secs = Some(Duration::from_secs(5));
+
+    controller_mock
+        .expect_handshake(&drone_id, agent.ip)
+        .await
+        .unwrap();
+    controller_mock
+        .expect_status_message(&request.drone_id, &ClusterName::new("plane.test"), true, 0)
+        .await
+        .unwrap();
+
+    let mut state_subscription = BackendStateSubscription::new(&connection, &request.backend_id)
+        .await
+        .unwrap();
+
+    controller_mock.spawn_backend(&request).await.unwrap();
+    state_subscription
+        .wait_for_state(BackendState::Ready, 20_000)
+        .await
+        .unwrap();
+
+    state_subscription
+        .wait_for_state(BackendState::Swept, 10_000)
+        .await
+        .unwrap();
+}
+
+/// Start an agent, and spawn a backend of the test image with the given
+/// behavior on it.
+async fn spawn_with_behavior(
+    nats: &Nats,
+    behavior: TestBehavior,
+    customize: impl FnOnce(&mut SpawnRequest),
+) -> (
+    Agent,
+    MockController,
+    SpawnRequest,
+    BackendStateSubscription,
+) {
+    let connection = nats.connection().await.unwrap();
+    let mut controller_mock = MockController::new(connection.clone()).await.unwrap();
+    let drone_id = DroneId::new_random();
+    let agent = Agent::new(nats, &drone_id).await.unwrap();
+    controller_mock
+        .expect_handshake(&drone_id, agent.ip)
+        .await
+        .unwrap();
+    controller_mock
+        .expect_status_message(&drone_id, &ClusterName::new(CLUSTER_DOMAIN), true, 0)
+        .await
+        .unwrap();
+
+    let mut request = base_spawn_request();
+    request.drone_id = drone_id;
+    behavior.apply(&mut request);
+    customize(&mut request);
+
+    let state_subscription = BackendStateSubscription::new(&connection, &request.backend_id)
+        .await
+        .unwrap();
+    controller_mock.spawn_backend(&request).await.unwrap();
+
+    (agent, controller_mock, request, state_subscription)
+}
+
+#[integration_test]
+#[ignore = "Needs a test image with TEST_BEHAVIOR support."]
+async fn test_image_crash_before_ready() {
+    let nats = Nats::new().await.unwrap();
+    let (_agent, _controller_mock, _request, mut state_subscription) =
+        spawn_with_behavior(&nats, TestBehavior::CrashAfter(Duration::ZERO), |_| {}).await;
+
+    state_subscription
+        .wait_for_state(BackendState::ErrorStarting, 60_000)
+        .await
+        .unwrap();
+}
+
+#[integration_test]
+#[ignore = "Needs a test image with TEST_BEHAVIOR support."]
+async fn test_image_crash_after_ready() {
+    let nats = Nats::new().await.unwrap();
+    let (_agent, _controller_mock, _request, mut state_subscription) = spawn_with_behavior(
+        &nats,
+        TestBehavior::CrashAfter(Duration::from_secs(5)),
+        |_| {},
+    )
+    .await;
+
+    state_subscription
+        .wait_for_state(BackendState::Ready, 60_000)
+        .await
+        .unwrap();
+    state_subscription
+        .expect_backend_status_message(BackendState::Failed, 10_000)
+        .await
+        .unwrap();
+}
+
+#[integration_test]
+#[ignore = "Needs a test image with TEST_BEHAVIOR support."]
+async fn test_image_exit_after_ready() {
+    let nats = Nats::new().await.unwrap();
+    let (_agent, _controller_mock, _request, mut state_subscription) = spawn_with_behavior(
+        &nats,
+        TestBehavior::ExitAfter(Duration::from_secs(5)),
+        |_| {},
+    )
+    .await;
+
+    state_subscription
+        .wait_for_state(BackendState::Ready, 60_000)
+        .await
+        .unwrap();
+    state_subscription
+        .expect_backend_status_message(BackendState::Exited, 10_000)
+        .await
+        .unwrap();
+}
+
+#[integration_test]
+#[ignore = "Needs a test image with TEST_BEHAVIOR support."]
+async fn test_image_out_of_memory() {
+    let nats = Nats::new().await.unwrap();
+    let (_agent, _controller_mock, _request, mut state_subscription) = spawn_with_behavior(
+        &nats,
+        TestBehavior::OomAfter(Duration::from_secs(5)),
+        |_| {},
+    )
+    .await;
+
+    state_subscription
+        .wait_for_state(BackendState::Ready, 60_000)
+        .await
+        .unwrap();
+    state_subscription
+        .expect_backend_status_message(BackendState::OutOfMemory, 20_000)
+        .await
+        .unwrap();
+}
+
+#[integration_test]
+#[ignore = "Needs a test image with TEST_BEHAVIOR support."]
+async fn test_image_hang_on_sigterm_terminated() {
+    let nats = Nats::new().await.unwrap();
+    let (_agent, controller_mock, request, mut state_subscription) =
+        spawn_with_behavior(&nats, TestBehavior::HangOnSigterm, |request| {
+            request.max_idle_secs = Duration::from_secs(1000);
+        })
+        .await;
+
+    state_subscription
+        .wait_for_state(BackendState::Ready, 60_000)
+        .await
+        .unwrap();
+
+    // The backend ignores SIGTERM, so it is killed once the stop times out.
+    controller_mock
+        .terminate_backend(&TerminationRequest {
+            backend_id: request.backend_id.clone(),
+            cluster_id: ClusterName::new(CLUSTER_DOMAIN),
+            drain: false,
+            grace_period_secs: Duration::ZERO,
+        })
+        .await
+        .unwrap();
+    state_subscription
+        .wait_for_state(BackendState::Terminated, 30_000)
+        .await
+        .unwrap();
+}
+
+#[integration_test]
+#[ignore = "Needs a test image with TEST_BEHAVIOR support."]
+async fn test_image_hang_on_sigterm_swept() {
+    let nats = Nats::new().await.unwrap();
+    let (_agent, _controller_mock, _request, mut state_subscription) =
+        spawn_with_behavior(&nats, TestBehavior::HangOnSigterm, |request| {
+            request.max_idle_secs = Duration::from_secs(1);
+        })
+        .await;
+
+    state_subscription
+        .wait_for_state(BackendState::Ready, 60_000)
+        .await
+        .unwrap();
+    state_subscription
+        .wait_for_state(BackendState::Swept, 30_000)
+        .await
+        .unwrap();
+}
+
+#[integration_test]
+async fn admin_api() {
+    let nats = Nats::new().await.unwrap();
+    let mut controller_mock = MockController::new(nats.connection().await.unwrap())
+        .await
+        .unwrap();
+    let drone_id = DroneId::new_random();
+    let agent = Agent::new(&nats, &drone_id).await.unwrap();
+
+    controller_mock
+        .expect_handshake(&drone_id, agent.ip)
+        .await
+        .unwrap();
+
+    let client = reqwest::Client::new();
+    let base_url = format!("http://127.0.0.1:{}", agent.admin_port);
+
+    // Health checks do not need the token.
+    let response = client
+        .get(format!("{}/healthz", base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(reqwest::StatusCode::OK, response.status());
+    let health: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert!(health["schema_version"].is_i64());
+
+    let response = client
+        .get(format!("{}/backends", base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(reqwest::StatusCode::UNAUTHORIZED, response.status());
+    let response = client
+        .get(format!("{}/backends", base_url))
+        .bearer_auth("wrong-token")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(reqwest::StatusCode::UNAUTHORIZED, response.status());
+
+    let response = client
+        .get(format!("{}/backends", base_url))
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(reqwest::StatusCode::OK, response.status());
+    let backends: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(serde_json::json!([]), backends);
+
+    let response = client
+        .post(format!("{}/backends/unknown-backend/terminate", base_url))
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(reqwest::StatusCode::NOT_FOUND, response.status());
+}
@@ -0,0 +1,213 @@
+use integration_test::integration_test;
+use plane_core::{
+    messages::agent::{
+        AttachInput, AttachInputMessage, AttachOutputChunk, AttachOutputMessage, AttachRequest,
+        ExecResponse, TerminalSize,
+    },
+    nats::{TypedNats, TypedSubscription},
+    signing::{SigningKey, VerifyingKey},
+    types::{BackendId, ClusterName},
+    NeverResult,
+};
+use plane_dev::{
+    mock_engine::MockEngine,
+    resources::nats::Nats,
+    timeout::{expect_to_stay_alive, timeout, LivenessGuard},
+    util::base_spawn_request,
+};
+use plane_drone::agent::{
+    engine::Engine,
+    exec::{listen_for_attach_requests, ExecAccess},
+};
+use std::time::Duration;
+use tokio::time::sleep;
+
+const CLUSTER_DOMAIN: &str = "plane.test";
+
+struct AttachTest {
+    nats: TypedNats,
+
+    /// Signs requests with the key the drone accepts.
+    signed_nats: TypedNats,
+
+    backend_id: BackendId,
+    _engine: MockEngine,
+    _listener_guard: LivenessGuard<NeverResult>,
+}
+
+impl AttachTest {
+    async fn new(nats: &Nats, idle_timeout: Duration) -> AttachTest {
+        let connection = nats.connection().await.unwrap();
+        let seed = SigningKey::generate().unwrap();
+        let signing_key = SigningKey::from_seed(&seed).unwrap();
+        let public_key = VerifyingKey::from_base64(&signing_key.public_key()).unwrap();
+
+        let engine = MockEngine::default();
+        let spawn_request = base_spawn_request();
+        engine.load(&spawn_request).await.unwrap();
+
+        let listener_guard = expect_to_stay_alive(listen_for_attach_requests(
+            engine.clone(),
+            connection.clone(),
+            ClusterName::new(CLUSTER_DOMAIN),
+            Some(ExecAccess {
+                public_key,
+                idle_timeout,
+            }),
+        ));
+        sleep(Duration::from_millis(100)).await;
+
+        AttachTest {
+            signed_nats: connection.clone().with_signing_key(Some(signing_key)),
+            nats: connection,
+            backend_id: spawn_request.backend_id,
+            _engine: engine,
+            _listener_guard: listener_guard,
+        }
+    }
+
+    fn request(&self, attach_id: &str) -> AttachRequest {
+        AttachRequest {
+            cluster_id: ClusterName::new(CLUSTER_DOMAIN),
+            backend_id: self.backend_id.clone(),
+            attach_id: attach_id.to_string(),
+            command: vec!["sh".to_string()],
+            size: TerminalSize { cols: 80, rows: 24 },
+        }
+    }
+
+    fn input(request: &AttachRequest, input: AttachInput) -> AttachInputMessage {
+        AttachInputMessage {
+            cluster_id: request.cluster_id.clone(),
+            backend_id: request.backend_id.clone(),
+            attach_id: request.attach_id.clone(),
+            input,
+        }
+    }
+}
+
+async fn next_chunk(sub: &mut TypedSubscription<AttachOutputMessage>) -> AttachOutputChunk {
+    timeout(1_000, "Should receive attach output.", sub.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .value
+        .chunk
+}
+
+#[integration_test]
+async fn attach_requires_signed_request() {
+    let nats = Nats::new().await.unwrap();
+    let test = AttachTest::new(&nats, Duration::from_secs(60)).await;
+
+    let response = timeout(
+        1_000,
+        "Attach request should be responded.",
+        test.nats.request(&test.request("session1")),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(matches!(response, ExecResponse::Failed { .. }));
+}
+
+#[integration_test]
+async fn attach_rejects_multi_token_id() {
+    let nats = Nats::new().await.unwrap();
+    let test = AttachTest::new(&nats, Duration::from_secs(60)).await;
+
+    for attach_id in ["*", "session1.input", ""] {
+        let response = timeout(
+            1_000,
+            "Attach request should be responded.",
+            test.signed_nats.request(&test.request(attach_id)),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(
+            matches!(response, ExecResponse::Failed { .. }),
+            "Expected {:?} to be rejected.",
+            attach_id
+        );
+    }
+}
+
+#[integration_test]
+async fn attach_only_accepts_signed_input() {
+    let nats = Nats::new().await.unwrap();
+    let test = AttachTest::new(&nats, Duration::from_secs(60)).await;
+    let request = test.request("session1");
+    let mut output = test
+        .nats
+        .subscribe(AttachOutputMessage::subscribe_subject(&request))
+        .await
+        .unwrap();
+
+    let response = timeout(
+        1_000,
+        "Attach request should be responded.",
+        test.signed_nats.request(&request),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(ExecResponse::Started, response);
+
+    // The mock engine echoes input, so only signed input is echoed.
+    test.nats
+        .publish(&AttachTest::input(
+            &request,
+            AttachInput::Data(b"unsigned".to_vec()),
+        ))
+        .await
+        .unwrap();
+    test.signed_nats
+        .publish(&AttachTest::input(
+            &request,
+            AttachInput::Data(b"signed".to_vec()),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(
+        AttachOutputChunk::Data(b"signed".to_vec()),
+        next_chunk(&mut output).await
+    );
+
+    test.signed_nats
+        .publish(&AttachTest::input(&request, AttachInput::Detach))
+        .await
+        .unwrap();
+    assert_eq!(
+        AttachOutputChunk::Exit { code: Some(0) },
+        next_chunk(&mut output).await
+    );
+}
+
+#[integration_test]
+async fn attach_detaches_when_idle() {
+    let nats = Nats::new().await.unwrap();
+    let test = AttachTest::new(&nats, Duration::from_millis(300)).await;
+    let request = test.request("session1");
+    let mut output = test
+        .nats
+        .subscribe(AttachOutputMessage::subscribe_subject(&request))
+        .await
+        .unwrap();
+
+    let response = timeout(
+        1_000,
+        "Attach request should be responded.",
+        test.signed_nats.request(&request),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(ExecResponse::Started, response);
+
+    // Without any input, the terminal is detached after the idle timeout.
+    assert_eq!(
+        AttachOutputChunk::Exit { code: Some(0) },
+        next_chunk(&mut output).await
+    );
+}
@@ -1,6 +1,10 @@
 use anyhow::Result;
 use integration_test::integration_test;
-use plane_controller::{dns::serve_dns, plan::DnsPlan};
+use plane_controller::{
+    config::{CertificatePaths, EncryptedDnsOptions},
+    dns::serve_dns,
+    plan::DnsPlan,
+};
 use plane_core::{
     messages::dns::{DnsRecordType, SetDnsRecord},
     nats::TypedNats,
@@ -8,15 +12,22 @@ use plane_core::{
     Never,
 };
 use plane_dev::{
-    resources::nats::Nats,
-    timeout::{expect_to_stay_alive, LivenessGuard},
+    resources::{certs::SelfSignedCert, nats::Nats},
+    timeout::{expect_to_stay_alive, timeout, LivenessGuard},
     util::random_loopback_ip,
 };
 use std::{
-    net::{Ipv4Addr, SocketAddr},
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
     str::Utf8Error,
+    sync::Arc,
     time::Duration,
 };
+use tokio::{net::TcpStream, time::sleep};
+use tokio_rustls::{
+    rustls::{client::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
 use trust_dns_resolver::{
     config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
     error::{ResolveError, ResolveErrorKind},
@@ -26,6 +37,8 @@ use trust_dns_resolver::{
 use trust_dns_server::client::rr::Name;
 
 const DNS_PORT: u16 = 5353;
+const DOT_PORT: u16 = 8853;
+const NAME_SERVER: &str = "ns1.plane.test";
 
 struct DnsServer {
     _guard: LivenessGuard<Result<Never, anyhow::Error>>,
@@ -56,6 +69,9 @@ impl DnsServer {
             bind_ip: ip.into(),
             port: DNS_PORT,
             soa_email: Some(Name::from_ascii("admin.plane.test.")?),
+            zones: HashMap::new(),
+            encrypted: None,
+            cluster_configs: Arc::default(),
             nc: nc.clone(),
         };
         let guard = expect_to_stay_alive(serve_dns(plan));
@@ -96,6 +112,12 @@ impl DnsServer {
         Ok(result.into_iter().collect())
     }
 
+    async fn aaaa_record(&self, domain: &str) -> std::result::Result<Vec<Ipv6Addr>, ResolveError> {
+        let result = self.resolver.ipv6_lookup(domain).await?;
+
+        Ok(result.into_iter().collect())
+    }
+
     async fn soa_record(&self, domain: &str) -> std::result::Result<Vec<SOA>, ResolveError> {
         let result = self.resolver.soa_lookup(domain).await?;
 
@@ -215,6 +237,34 @@ async fn dns_multi_a_record() {
     assert_eq!(vec![Ipv4Addr::new(14, 14, 14, 14)], result);
 }
 
+#[integration_test]
+async fn dns_aaaa_record() {
+    let dns = DnsServer::new().await.unwrap();
+
+    for (kind, value) in [
+        (DnsRecordType::A, "12.12.12.12"),
+        (DnsRecordType::AAAA, "2001:db8::12"),
+    ] {
+        dns.nc
+            .publish_jetstream(&SetDnsRecord {
+                cluster: ClusterName::new("plane.test"),
+                kind,
+                name: "louie".into(),
+                value: value.into(),
+            })
+            .await
+            .unwrap();
+    }
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let result = dns.aaaa_record("louie.plane.test").await.unwrap();
+    assert_eq!(vec!["2001:db8::12".parse::<Ipv6Addr>().unwrap()], result);
+
+    let result = dns.a_record("louie.plane.test").await.unwrap();
+    assert_eq!(vec![Ipv4Addr::new(12, 12, 12, 12)], result);
+}
+
 #[integration_test]
 async fn dns_soa_record() {
     let dns = DnsServer::new().await.unwrap();
@@ -227,3 +277,65 @@ async fn dns_soa_record() {
     assert_eq!("admin.plane.test.", &result.rname().to_ascii());
     assert_eq!("plane.test.", &result.mname().to_ascii());
 }
+
+/// Whether the DNS-over-TLS listener at `addr` presents `cert`.
+async fn presents_certificate(addr: SocketAddr, cert: &SelfSignedCert) -> bool {
+    let mut root_certs = RootCertStore::empty();
+    root_certs
+        .add_parsable_certificates(&rustls_pemfile::certs(&mut cert.cert_pem.as_bytes()).unwrap());
+    let client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_certs)
+        .with_no_client_auth();
+
+    let tcp_stream = TcpStream::connect(addr).await.unwrap();
+    TlsConnector::from(Arc::new(client_config))
+        .connect(ServerName::try_from(NAME_SERVER).unwrap(), tcp_stream)
+        .await
+        .is_ok()
+}
+
+#[integration_test]
+async fn dns_over_tls_certificate_reloaded() {
+    let ip = random_loopback_ip();
+    let nats = Nats::new().await.unwrap();
+    let original = SelfSignedCert::new("dns-original", vec![NAME_SERVER.into()]).unwrap();
+    let renewed = SelfSignedCert::new("dns-renewed", vec![NAME_SERVER.into()]).unwrap();
+
+    let plan = DnsPlan {
+        bind_ip: ip.into(),
+        port: DNS_PORT,
+        soa_email: Some(Name::from_ascii("admin.plane.test.").unwrap()),
+        zones: HashMap::new(),
+        encrypted: Some(EncryptedDnsOptions {
+            certificate: CertificatePaths {
+                cert_path: original.path_pair.cert_path.clone(),
+                key_path: original.path_pair.key_path.clone(),
+            },
+            dot_port: Some(DOT_PORT),
+            doh_port: None,
+            doh_hostname: None,
+            certificate_check_secs: 1,
+        }),
+        cluster_configs: Arc::default(),
+        nc: nats.connection().await.unwrap(),
+    };
+    let _guard = expect_to_stay_alive(serve_dns(plan));
+    sleep(Duration::from_millis(500)).await;
+
+    let addr = SocketAddr::new(ip.into(), DOT_PORT);
+    assert!(presents_certificate(addr, &original).await);
+    assert!(!presents_certificate(addr, &renewed).await);
+
+    // Renew the certificate in place, as an ACME client would.
+    std::fs::write(&original.path_pair.cert_path, &renewed.cert_pem).unwrap();
+    std::fs::write(&original.path_pair.key_path, &renewed.key_pem).unwrap();
+
+    timeout(10_000, "Renewed certificate should be presented.", async {
+        while !presents_certificate(addr, &renewed).await {
+            sleep(Duration::from_millis(200)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
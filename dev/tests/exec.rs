@@ -0,0 +1,167 @@
+use integration_test::integration_test;
+use plane_core::{
+    messages::agent::{ExecOutputChunk, ExecOutputMessage, ExecRequest, ExecResponse},
+    nats::{TypedNats, TypedSubscription},
+    signing::{SigningKey, VerifyingKey},
+    types::{BackendId, ClusterName},
+    NeverResult,
+};
+use plane_dev::{
+    mock_engine::MockEngine,
+    resources::nats::Nats,
+    timeout::{expect_to_stay_alive, timeout, LivenessGuard},
+    util::base_spawn_request,
+};
+use plane_drone::agent::{
+    engine::Engine,
+    exec::{listen_for_exec_requests, ExecAccess},
+};
+use std::time::Duration;
+use tokio::time::sleep;
+
+const CLUSTER_DOMAIN: &str = "plane.test";
+
+struct ExecTest {
+    nats: TypedNats,
+
+    /// Signs requests with the key the drone accepts.
+    signed_nats: TypedNats,
+
+    backend_id: BackendId,
+    _engine: MockEngine,
+    _listener_guard: LivenessGuard<NeverResult>,
+}
+
+impl ExecTest {
+    async fn new(nats: &Nats, idle_timeout: Duration) -> ExecTest {
+        let connection = nats.connection().await.unwrap();
+        let signing_key = SigningKey::from_seed(&SigningKey::generate().unwrap()).unwrap();
+        let public_key = VerifyingKey::from_base64(&signing_key.public_key()).unwrap();
+
+        let engine = MockEngine::default();
+        let spawn_request = base_spawn_request();
+        engine.load(&spawn_request).await.unwrap();
+
+        let listener_guard = expect_to_stay_alive(listen_for_exec_requests(
+            engine.clone(),
+            connection.clone(),
+            ClusterName::new(CLUSTER_DOMAIN),
+            Some(ExecAccess {
+                public_key,
+                idle_timeout,
+            }),
+        ));
+        sleep(Duration::from_millis(100)).await;
+
+        ExecTest {
+            signed_nats: connection.clone().with_signing_key(Some(signing_key)),
+            nats: connection,
+            backend_id: spawn_request.backend_id,
+            _engine: engine,
+            _listener_guard: listener_guard,
+        }
+    }
+
+    fn request(&self, exec_id: &str, command: &[&str]) -> ExecRequest {
+        ExecRequest {
+            cluster_id: ClusterName::new(CLUSTER_DOMAIN),
+            backend_id: self.backend_id.clone(),
+            exec_id: exec_id.to_string(),
+            command: command.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    async fn send(&self, nats: &TypedNats, request: &ExecRequest) -> ExecResponse {
+        timeout(
+            1_000,
+            "Exec request should be responded.",
+            nats.request(request),
+        )
+        .await
+        .unwrap()
+        .unwrap()
+    }
+}
+
+async fn next_chunk(sub: &mut TypedSubscription<ExecOutputMessage>) -> ExecOutputChunk {
+    timeout(1_000, "Should receive exec output.", sub.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .value
+        .chunk
+}
+
+#[integration_test]
+async fn exec_requires_signed_request() {
+    let nats = Nats::new().await.unwrap();
+    let test = ExecTest::new(&nats, Duration::from_secs(60)).await;
+
+    let response = test
+        .send(&test.nats, &test.request("exec1", &["echo", "hi"]))
+        .await;
+    assert!(matches!(response, ExecResponse::Failed { .. }));
+}
+
+#[integration_test]
+async fn exec_rejects_multi_token_id() {
+    let nats = Nats::new().await.unwrap();
+    let test = ExecTest::new(&nats, Duration::from_secs(60)).await;
+
+    for exec_id in ["*", "exec1.output", ""] {
+        let response = test
+            .send(&test.signed_nats, &test.request(exec_id, &["echo", "hi"]))
+            .await;
+        assert!(
+            matches!(response, ExecResponse::Failed { .. }),
+            "Expected {:?} to be rejected.",
+            exec_id
+        );
+    }
+}
+
+#[integration_test]
+async fn exec_publishes_output() {
+    let nats = Nats::new().await.unwrap();
+    let test = ExecTest::new(&nats, Duration::from_secs(60)).await;
+    let request = test.request("exec1", &["echo", "hi"]);
+    let mut output = test
+        .nats
+        .subscribe(ExecOutputMessage::subscribe_subject(&request))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        ExecResponse::Started,
+        test.send(&test.signed_nats, &request).await
+    );
+    assert_eq!(
+        ExecOutputChunk::Stdout("echo hi\n".into()),
+        next_chunk(&mut output).await
+    );
+    assert_eq!(
+        ExecOutputChunk::Exit { code: Some(0) },
+        next_chunk(&mut output).await
+    );
+}
+
+#[integration_test]
+async fn exec_ends_when_idle() {
+    let nats = Nats::new().await.unwrap();
+    let test = ExecTest::new(&nats, Duration::from_millis(300)).await;
+    let request = test.request("exec1", &["sleep"]);
+    let mut output = test
+        .nats
+        .subscribe(ExecOutputMessage::subscribe_subject(&request))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        ExecResponse::Started,
+        test.send(&test.signed_nats, &request).await
+    );
+    assert_eq!(
+        ExecOutputChunk::Exit { code: None },
+        next_chunk(&mut output).await
+    );
+}
@@ -0,0 +1,802 @@
+use chrono::Utc;
+use integration_test::integration_test;
+use plane_core::{
+    error::PlaneError,
+    messages::agent::{
+        BackendState, BackendStateMessage, DockerCredentials, DroneStatusMessage, MigrateBackend,
+        SpawnProgress, SpawnProgressEvent, SpawnRequest, TerminateAllRequest, TerminatedBy,
+        TerminationReason, TerminationRequest,
+    },
+    nats::{TypedNats, TypedSubscription},
+    sealing::SealingKey,
+    signing::{SigningKey, VerifyingKey},
+    types::{BackendId, ClusterName, DroneId, ProgressId},
+};
+use plane_dev::{
+    mock_engine::{MockCheckpoints, MockEngine, MockFailure},
+    resources::nats::Nats,
+    scratch_dir,
+    timeout::{expect_to_stay_alive, timeout},
+    util::{base_spawn_request, random_loopback_ip},
+};
+use plane_drone::{
+    agent::{
+        engine::{EngineBackendStatus, ImagePullProgress},
+        executor::Executor,
+        listen_for_terminate_all_requests,
+    },
+    database::DroneDatabase,
+};
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+
+const CLUSTER_DOMAIN: &str = "plane.test";
+const PLANE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+async fn executor(nats: &Nats, engine: MockEngine) -> Executor<MockEngine> {
+    let db = DroneDatabase::new(&scratch_dir("executor").join("drone.db"))
+        .await
+        .unwrap();
+
+    executor_with_database(nats, engine, db).await
+}
+
+async fn executor_with_database(
+    nats: &Nats,
+    engine: MockEngine,
+    db: DroneDatabase,
+) -> Executor<MockEngine> {
+    Executor::new(
+        engine,
+        db,
+        nats.connection().await.unwrap(),
+        IpAddr::V4(random_loopback_ip()),
+        ClusterName::new(CLUSTER_DOMAIN),
+        None,
+    )
+}
+
+async fn state_subscription(
+    nats: &TypedNats,
+    backend_id: &BackendId,
+) -> TypedSubscription<BackendStateMessage> {
+    nats.subscribe(BackendStateMessage::subscribe_subject(backend_id))
+        .await
+        .unwrap()
+}
+
+async fn expect_states(sub: &mut TypedSubscription<BackendStateMessage>, states: &[BackendState]) {
+    for expected_state in states {
+        let message = timeout(
+            5_000,
+            &format!("State should become {:?}", expected_state),
+            sub.next(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(*expected_state, message.value.state);
+    }
+}
+
+#[integration_test]
+async fn mock_backend_runs_until_terminated() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let engine = MockEngine::default();
+    let executor = executor(&nats, engine.clone()).await;
+
+    let request = base_spawn_request();
+    let mut sub = state_subscription(&connection, &request.backend_id).await;
+
+    let handle = {
+        let executor = executor.clone();
+        let request = request.clone();
+        tokio::spawn(async move { executor.start_backend(&request).await })
+    };
+
+    expect_states(
+        &mut sub,
+        &[
+            BackendState::Loading,
+            BackendState::Starting,
+            BackendState::Ready,
+        ],
+    )
+    .await;
+    assert_eq!(vec![request.backend_id.clone()], engine.loaded());
+
+    executor
+        .kill_backend(&TerminationRequest {
+            cluster_id: ClusterName::new(CLUSTER_DOMAIN),
+            backend_id: request.backend_id.clone(),
+            drain: false,
+            grace_period_secs: Duration::ZERO,
+        })
+        .await
+        .unwrap();
+
+    let message = timeout(5_000, "State should become Terminated", sub.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(BackendState::Terminated, message.value.state);
+    assert_eq!(
+        Some(TerminatedBy::Request),
+        message.value.termination.map(|t| t.terminated_by)
+    );
+    timeout(5_000, "Backend should finish running.", handle)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(vec![request.backend_id.clone()], engine.stopped());
+    assert_eq!(
+        EngineBackendStatus::Unknown,
+        engine.status(&request.backend_id)
+    );
+}
+
+#[integration_test]
+async fn mock_backend_records_spawn_timeline() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let engine = MockEngine::default();
+    let executor = executor(&nats, engine.clone()).await;
+
+    let mut request = base_spawn_request();
+    request.requested_at = Some(Utc::now());
+    let mut sub = state_subscription(&connection, &request.backend_id).await;
+
+    {
+        let executor = executor.clone();
+        let request = request.clone();
+        tokio::spawn(async move { executor.start_backend(&request).await });
+    }
+
+    expect_states(&mut sub, &[BackendState::Loading, BackendState::Starting]).await;
+    let message = timeout(5_000, "State should become Ready", sub.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(BackendState::Ready, message.value.state);
+
+    let timeline = message
+        .value
+        .timeline
+        .expect("Ready message should carry a timeline.");
+    assert_eq!(request.requested_at, timeline.requested_at);
+    assert!(timeline.loaded_at.is_some());
+    assert!(timeline.ready_at.is_some());
+    assert_eq!(
+        vec!["accepted", "loaded", "ready"],
+        timeline
+            .steps()
+            .into_iter()
+            .map(|(step, _)| step)
+            .collect::<Vec<_>>()
+    );
+
+    executor
+        .kill_backend(&TerminationRequest {
+            cluster_id: ClusterName::new(CLUSTER_DOMAIN),
+            backend_id: request.backend_id.clone(),
+            drain: false,
+            grace_period_secs: Duration::ZERO,
+        })
+        .await
+        .unwrap();
+    expect_states(&mut sub, &[BackendState::Terminated]).await;
+}
+
+#[integration_test]
+async fn mock_backend_fails_to_load() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let engine = MockEngine::builder()
+        .with_failure(MockFailure::Load)
+        .build();
+    let executor = executor(&nats, engine.clone()).await;
+
+    let request = base_spawn_request();
+    let mut sub = state_subscription(&connection, &request.backend_id).await;
+    executor.start_backend(&request).await;
+
+    expect_states(
+        &mut sub,
+        &[BackendState::Loading, BackendState::ErrorLoading],
+    )
+    .await;
+}
+
+#[integration_test]
+async fn mock_backend_load_times_out() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let engine = MockEngine::builder()
+        .with_failure(MockFailure::HangLoad)
+        .build();
+    let executor = executor(&nats, engine.clone())
+        .await
+        .with_load_timeout(Duration::from_millis(100));
+
+    let request = base_spawn_request();
+    let mut sub = state_subscription(&connection, &request.backend_id).await;
+    executor.start_backend(&request).await;
+
+    expect_states(&mut sub, &[BackendState::Loading]).await;
+    let message = timeout(5_000, "State should become ErrorLoading", sub.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(BackendState::ErrorLoading, message.value.state);
+    assert!(message.value.reason.unwrap().contains("Timed out"));
+    assert_eq!(
+        Some(PlaneError::LoadTimeout { timeout_secs: 0 }),
+        message.value.error
+    );
+    assert_eq!(vec![request.backend_id.clone()], engine.stopped());
+}
+
+async fn progress_subscription(
+    nats: &TypedNats,
+    request: &SpawnRequest,
+) -> TypedSubscription<SpawnProgress> {
+    nats.subscribe(SpawnProgress::subscribe_subject(
+        &ClusterName::new(CLUSTER_DOMAIN),
+        request.progress_id.as_ref().unwrap(),
+    ))
+    .await
+    .unwrap()
+}
+
+#[integration_test]
+async fn mock_backend_publishes_spawn_progress() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let engine = MockEngine::default();
+    let executor = executor(&nats, engine.clone()).await;
+
+    let mut request = base_spawn_request();
+    request.progress_id = Some(ProgressId::new_random());
+    let mut sub = progress_subscription(&connection, &request).await;
+
+    {
+        let executor = executor.clone();
+        let request = request.clone();
+        tokio::spawn(async move { executor.start_backend(&request).await });
+    }
+
+    for expected_event in [
+        SpawnProgressEvent::ContainerCreated,
+        SpawnProgressEvent::PortReady,
+    ] {
+        let message = timeout(5_000, "Spawn progress should be published.", sub.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.backend_id, message.value.backend_id);
+        assert_eq!(expected_event, message.value.event);
+    }
+}
+
+#[integration_test]
+async fn mock_backend_publishes_image_pull_progress() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let engine = MockEngine::builder()
+        .with_failure(MockFailure::HangLoad)
+        .with_pull_progress(ImagePullProgress {
+            pulled_bytes: 1024,
+            total_bytes: 4096,
+        })
+        .build();
+    let executor = executor(&nats, engine.clone()).await;
+
+    let mut request = base_spawn_request();
+    request.progress_id = Some(ProgressId::new_random());
+    let mut sub = progress_subscription(&connection, &request).await;
+
+    // A spawn without a progress ID publishes no progress.
+    let mut other_request = base_spawn_request();
+    other_request.progress_id = None;
+
+    for request in [other_request, request] {
+        let executor = executor.clone();
+        tokio::spawn(async move { executor.start_backend(&request).await });
+    }
+
+    let message = timeout(5_000, "Pull progress should be published.", sub.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        SpawnProgressEvent::ImagePullProgress {
+            pulled_bytes: 1024,
+            total_bytes: 4096
+        },
+        message.value.event
+    );
+}
+
+#[integration_test]
+async fn credentials_cleared_after_loading() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let db = DroneDatabase::new(&scratch_dir("executor").join("drone.db"))
+        .await
+        .unwrap();
+    let executor = executor_with_database(&nats, MockEngine::default(), db.clone()).await;
+
+    let mut request = base_spawn_request();
+    request.executable.credentials = Some(DockerCredentials::UsernamePassword {
+        username: "jane".into(),
+        password: "hunter2".into(),
+    });
+    let mut sub = state_subscription(&connection, &request.backend_id).await;
+
+    {
+        let executor = executor.clone();
+        let request = request.clone();
+        tokio::spawn(async move { executor.start_backend(&request).await });
+    }
+
+    expect_states(&mut sub, &[BackendState::Loading, BackendState::Starting]).await;
+    let backends = db.get_backends().await.unwrap();
+    assert_eq!(1, backends.len());
+    assert_eq!(None, backends[0].spec.executable.credentials);
+}
+
+#[integration_test]
+async fn mock_backend_fails_to_start() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let engine = MockEngine::builder()
+        .with_failure(MockFailure::Start)
+        .build();
+    let executor = executor(&nats, engine.clone()).await;
+
+    let request = base_spawn_request();
+    let mut sub = state_subscription(&connection, &request.backend_id).await;
+    executor.start_backend(&request).await;
+
+    expect_states(
+        &mut sub,
+        &[
+            BackendState::Loading,
+            BackendState::Starting,
+            BackendState::ErrorStarting,
+        ],
+    )
+    .await;
+    assert_eq!(vec![request.backend_id.clone()], engine.stopped());
+}
+
+#[integration_test]
+async fn mock_backend_fails_after_ready() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let engine = MockEngine::builder()
+        .with_failure(MockFailure::AfterLoad {
+            delay: Duration::from_secs(1),
+            status: EngineBackendStatus::Failed { exit_code: 3 },
+        })
+        .build();
+    let executor = executor(&nats, engine.clone()).await;
+
+    let mut request = base_spawn_request();
+    request.max_idle_secs = Duration::from_secs(1000);
+    let mut sub = state_subscription(&connection, &request.backend_id).await;
+    executor.start_backend(&request).await;
+
+    expect_states(
+        &mut sub,
+        &[
+            BackendState::Loading,
+            BackendState::Starting,
+            BackendState::Ready,
+        ],
+    )
+    .await;
+    let message = timeout(5_000, "State should become Failed", sub.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(BackendState::Failed, message.value.state);
+    assert_eq!(
+        Some(TerminationReason::new(TerminatedBy::Backend).with_exit_code(3)),
+        message.value.termination
+    );
+}
+
+#[integration_test]
+async fn mock_backend_lifetime_extended() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let db = DroneDatabase::new(&scratch_dir("executor").join("drone.db"))
+        .await
+        .unwrap();
+    let executor = executor_with_database(&nats, MockEngine::default(), db.clone()).await;
+
+    let mut request = base_spawn_request();
+    request.max_idle_secs = Duration::from_secs(1000);
+    request.max_lifetime_secs = Some(Duration::from_secs(2));
+    let mut sub = state_subscription(&connection, &request.backend_id).await;
+
+    {
+        let executor = executor.clone();
+        let request = request.clone();
+        tokio::spawn(async move { executor.start_backend(&request).await });
+    }
+    expect_states(
+        &mut sub,
+        &[
+            BackendState::Loading,
+            BackendState::Starting,
+            BackendState::Ready,
+        ],
+    )
+    .await;
+
+    // As the metadata API does for a backend asking for more time; only as
+    // much as the cap allows is granted.
+    db.extend_backend_lifetime(&request.backend_id, 600, 3)
+        .await
+        .unwrap();
+    assert_eq!(
+        3,
+        db.get_backend_lifetime_extension(&request.backend_id)
+            .await
+            .unwrap()
+    );
+
+    assert!(
+        tokio::time::timeout(Duration::from_millis(2_500), sub.next())
+            .await
+            .is_err(),
+        "Backend should outlive its original lifetime."
+    );
+    let message = timeout(5_000, "State should become Swept", sub.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(BackendState::Swept, message.value.state);
+    assert_eq!(
+        Some(TerminatedBy::MaxLifetime),
+        message.value.termination.map(|t| t.terminated_by)
+    );
+}
+
+#[integration_test]
+async fn mock_backend_exits_at_own_request() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let engine = MockEngine::default();
+    let executor = executor(&nats, engine.clone()).await;
+
+    let request = base_spawn_request();
+    let mut sub = state_subscription(&connection, &request.backend_id).await;
+
+    let handle = {
+        let executor = executor.clone();
+        let request = request.clone();
+        tokio::spawn(async move { executor.start_backend(&request).await })
+    };
+
+    expect_states(
+        &mut sub,
+        &[
+            BackendState::Loading,
+            BackendState::Starting,
+            BackendState::Ready,
+        ],
+    )
+    .await;
+
+    executor.exit_backend(&request.backend_id).await.unwrap();
+
+    let message = timeout(5_000, "State should become Exited", sub.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(BackendState::Exited, message.value.state);
+    assert_eq!(
+        Some(TerminatedBy::Backend),
+        message.value.termination.map(|t| t.terminated_by)
+    );
+    timeout(5_000, "Backend should finish running.", handle)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(executor.exit_backend(&request.backend_id).await.is_err());
+}
+
+#[integration_test]
+async fn mock_backend_exits_externally() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let engine = MockEngine::default();
+    let executor = executor(&nats, engine.clone()).await;
+
+    let mut request = base_spawn_request();
+    request.max_idle_secs = Duration::from_secs(1000);
+    let mut sub = state_subscription(&connection, &request.backend_id).await;
+
+    let handle = {
+        let executor = executor.clone();
+        let request = request.clone();
+        tokio::spawn(async move { executor.start_backend(&request).await })
+    };
+
+    expect_states(
+        &mut sub,
+        &[
+            BackendState::Loading,
+            BackendState::Starting,
+            BackendState::Ready,
+        ],
+    )
+    .await;
+
+    engine.set_status(&request.backend_id, EngineBackendStatus::Exited);
+
+    expect_states(&mut sub, &[BackendState::Exited]).await;
+    timeout(5_000, "Backend should finish running.", handle)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+/// Publish status messages for a drone, as its agent would, until aborted.
+fn publish_drone_status(
+    nats: TypedNats,
+    drone_id: DroneId,
+    sealing_key: Option<String>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            nats.publish(&DroneStatusMessage {
+                drone_id: drone_id.clone(),
+                cluster: ClusterName::new(CLUSTER_DOMAIN),
+                drone_version: PLANE_VERSION.to_string(),
+                ready: true,
+                running_backends: None,
+                remaining_capacity: None,
+                host_metrics: None,
+                sealing_key: sealing_key.clone(),
+                arch: None,
+                proxy_metrics: None,
+                draining: false,
+            })
+            .await
+            .unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+}
+
+/// Accept spawn requests sent to a drone, and start them on its executor.
+async fn accept_spawn_requests(
+    nats: &TypedNats,
+    drone_id: &DroneId,
+    executor: Executor<MockEngine>,
+) -> JoinHandle<()> {
+    let mut sub = nats
+        .subscribe(SpawnRequest::subscribe_subject(drone_id))
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        while let Some(req) = sub.next().await {
+            req.respond(&Ok(())).await.unwrap();
+            executor.start_backend(&req.value).await;
+        }
+    })
+}
+
+#[integration_test]
+async fn backend_migrates_to_target_drone() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let checkpoints = MockCheckpoints::default();
+    let source_engine = MockEngine::builder()
+        .with_checkpoints(checkpoints.clone())
+        .build();
+    let target_engine = MockEngine::builder()
+        .with_checkpoints(checkpoints.clone())
+        .build();
+    let source = executor(&nats, source_engine.clone()).await;
+    let target_db = DroneDatabase::new(&scratch_dir("target").join("drone.db"))
+        .await
+        .unwrap();
+    let sealing_key = SealingKey::generate().unwrap();
+    let target_drone = DroneId::new_random();
+    let status_handle = publish_drone_status(
+        connection.clone(),
+        target_drone.clone(),
+        Some(sealing_key.public_key().unwrap()),
+    );
+    let target = executor_with_database(&nats, target_engine.clone(), target_db)
+        .await
+        .with_sealing_key(Arc::new(sealing_key));
+    let spawn_handle = accept_spawn_requests(&connection, &target_drone, target).await;
+
+    let request = base_spawn_request();
+    let mut sub = state_subscription(&connection, &request.backend_id).await;
+    {
+        let source = source.clone();
+        let request = request.clone();
+        tokio::spawn(async move { source.start_backend(&request).await });
+    }
+    expect_states(
+        &mut sub,
+        &[
+            BackendState::Loading,
+            BackendState::Starting,
+            BackendState::Ready,
+        ],
+    )
+    .await;
+
+    timeout(
+        5_000,
+        "Backend should be migrated.",
+        source.migrate_backend(&MigrateBackend {
+            cluster_id: ClusterName::new(CLUSTER_DOMAIN),
+            backend_id: request.backend_id.clone(),
+            target_drone: target_drone.clone(),
+        }),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    expect_states(
+        &mut sub,
+        &[
+            BackendState::Checkpointed,
+            BackendState::Loading,
+            BackendState::Starting,
+            BackendState::Ready,
+        ],
+    )
+    .await;
+    assert_eq!(
+        EngineBackendStatus::Unknown,
+        source_engine.status(&request.backend_id)
+    );
+    assert!(matches!(
+        target_engine.status(&request.backend_id),
+        EngineBackendStatus::Running { .. }
+    ));
+    // The target drone removed the sealed checkpoint once it restored it.
+    assert!(checkpoints.lock().unwrap().is_empty());
+
+    status_handle.abort();
+    spawn_handle.abort();
+}
+
+#[integration_test]
+async fn migrate_fails_without_target_sealing_key() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let engine = MockEngine::default();
+    let source = executor(&nats, engine.clone()).await;
+    let target_drone = DroneId::new_random();
+    let status_handle = publish_drone_status(connection.clone(), target_drone.clone(), None);
+
+    let request = base_spawn_request();
+    let mut sub = state_subscription(&connection, &request.backend_id).await;
+    {
+        let source = source.clone();
+        let request = request.clone();
+        tokio::spawn(async move { source.start_backend(&request).await });
+    }
+    expect_states(
+        &mut sub,
+        &[
+            BackendState::Loading,
+            BackendState::Starting,
+            BackendState::Ready,
+        ],
+    )
+    .await;
+
+    let result = timeout(
+        5_000,
+        "Migration should finish.",
+        source.migrate_backend(&MigrateBackend {
+            cluster_id: ClusterName::new(CLUSTER_DOMAIN),
+            backend_id: request.backend_id.clone(),
+            target_drone,
+        }),
+    )
+    .await
+    .unwrap();
+    assert!(result.is_err());
+
+    // The backend was never checkpointed, so it keeps running here.
+    assert!(matches!(
+        engine.status(&request.backend_id),
+        EngineBackendStatus::Running { .. }
+    ));
+
+    status_handle.abort();
+}
+
+#[integration_test]
+async fn terminate_all_requires_signature() {
+    let nats = Nats::new().await.unwrap();
+    let connection = nats.connection().await.unwrap();
+    let executor = executor(&nats, MockEngine::default()).await;
+    let drone_id = DroneId::new_random();
+    let signing_key = SigningKey::from_seed(&SigningKey::generate().unwrap()).unwrap();
+    let public_key = VerifyingKey::from_base64(&signing_key.public_key()).unwrap();
+    let _listener_guard = expect_to_stay_alive(listen_for_terminate_all_requests(
+        executor.clone(),
+        connection.clone(),
+        drone_id.clone(),
+        ClusterName::new(CLUSTER_DOMAIN),
+        Some(public_key),
+    ));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut subs = Vec::new();
+    let mut backends = Vec::new();
+    for _ in 0..2 {
+        let request = base_spawn_request();
+        let mut sub = state_subscription(&connection, &request.backend_id).await;
+        {
+            let executor = executor.clone();
+            let request = request.clone();
+            tokio::spawn(async move { executor.start_backend(&request).await });
+        }
+        expect_states(
+            &mut sub,
+            &[
+                BackendState::Loading,
+                BackendState::Starting,
+                BackendState::Ready,
+            ],
+        )
+        .await;
+        subs.push(sub);
+        backends.push(request.backend_id);
+    }
+
+    let request = |state| TerminateAllRequest {
+        cluster_id: ClusterName::new(CLUSTER_DOMAIN),
+        drone_id: drone_id.clone(),
+        state,
+    };
+
+    // Unsigned requests are refused.
+    let result = connection.request(&request(None)).await.unwrap();
+    assert!(matches!(result, Err(PlaneError::Unauthenticated { .. })));
+
+    let signed = connection.clone().with_signing_key(Some(signing_key));
+    let result = signed
+        .request(&request(Some(BackendState::Starting)))
+        .await
+        .unwrap();
+    assert_eq!(Ok(vec![]), result);
+
+    let mut terminated = signed
+        .request(&request(Some(BackendState::Ready)))
+        .await
+        .unwrap()
+        .unwrap();
+    terminated.sort_by_key(|backend| backend.to_string());
+    backends.sort_by_key(|backend| backend.to_string());
+    assert_eq!(backends, terminated);
+
+    for sub in &mut subs {
+        let message = timeout(5_000, "State should become Terminated", sub.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(BackendState::Terminated, message.value.state);
+        assert_eq!(
+            Some(TerminatedBy::Request),
+            message.value.termination.map(|t| t.terminated_by)
+        );
+    }
+}
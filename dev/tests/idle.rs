@@ -0,0 +1,243 @@
+use anyhow::Result;
+use chrono::Utc;
+use integration_test::integration_test;
+use plane_core::{
+    messages::agent::{BackendState, BackendStateMessage, SpawnRequest},
+    nats::{TypedNats, TypedSubscription},
+    types::{ClusterName, DroneId},
+    NeverResult,
+};
+use plane_dev::{
+    resources::nats::Nats,
+    scratch_dir,
+    timeout::{expect_to_stay_alive, timeout, LivenessGuard},
+    traffic::{expect_swept_after, TrafficClient, TrafficPattern},
+    util::{base_spawn_request, random_loopback_ip},
+};
+use plane_drone::{
+    agent::{executor::DEFAULT_LOAD_TIMEOUT, AgentOptions},
+    config::DockerConfig,
+    database::DroneDatabase,
+    ip::IpSource,
+    proxy::{ProxyOptions, SessionOptions},
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+const CLUSTER_DOMAIN: &str = "plane.test";
+const PROXY_PORT: u16 = 4040;
+
+/// A drone running both the agent and the proxy, as in production, so that
+/// activity seen by the proxy determines when the agent sweeps a backend.
+struct Drone {
+    #[allow(unused)]
+    agent_guard: LivenessGuard<NeverResult>,
+    #[allow(unused)]
+    proxy_guard: LivenessGuard<NeverResult>,
+    drone_id: DroneId,
+    proxy_address: SocketAddr,
+    nats: TypedNats,
+}
+
+impl Drone {
+    pub async fn new(nats: &Nats) -> Result<Drone> {
+        let ip = IpAddr::V4(random_loopback_ip());
+        let db = DroneDatabase::new(&scratch_dir("drone").join("drone.db")).await?;
+        let drone_id = DroneId::new_random();
+        let connection = nats.connection().await?;
+
+        let proxy_guard = expect_to_stay_alive(plane_drone::proxy::serve(ProxyOptions {
+            db: db.clone(),
+            bind_ip: ip,
+            bind_ipv6: None,
+            bind_port: PROXY_PORT,
+            key_pair: None,
+            cluster_domain: CLUSTER_DOMAIN.into(),
+            access_log: None,
+            session: SessionOptions {
+                ttl: Duration::from_secs(60),
+                allowed_origins: Vec::new(),
+            },
+            reconnect: None,
+            cache: None,
+            metrics: None,
+        }));
+
+        let agent_guard = expect_to_stay_alive(plane_drone::agent::run_agent(AgentOptions {
+            db,
+            drone_id: drone_id.clone(),
+            nats: connection.clone(),
+            cluster_domain: ClusterName::new(CLUSTER_DOMAIN),
+            ip: IpSource::Literal(ip),
+            ipv6: None,
+            docker_options: DockerConfig::default(),
+            containerd_options: None,
+            maintenance_windows: Vec::new(),
+            heartbeat_interval: Duration::from_secs(4),
+            load_timeout: DEFAULT_LOAD_TIMEOUT,
+            max_backends: None,
+            spawn_limit: None,
+            proxy_self_test: None,
+            proxy_metrics: None,
+            admin_port: None,
+            admin_token: None,
+            metadata_port: None,
+            max_lifetime_extension: Duration::from_secs(86400),
+            min_disk_free_bytes: None,
+            cert_paths: None,
+            log_archive: None,
+            hooks: None,
+            exec_access: None,
+            migrate_public_key: None,
+            terminate_all_public_key: None,
+            update: None,
+            registration_key: None,
+            certificate_public_key: None,
+        }));
+
+        Ok(Drone {
+            agent_guard,
+            proxy_guard,
+            drone_id,
+            proxy_address: SocketAddr::new(ip, PROXY_PORT),
+            nats: connection,
+        })
+    }
+
+    /// Spawn a backend with the given idle timeout and wait for it to become
+    /// ready, returning a client for it and its state subscription.
+    pub async fn spawn_ready(
+        &self,
+        max_idle: Duration,
+    ) -> Result<(TrafficClient, TypedSubscription<BackendStateMessage>)> {
+        let request = SpawnRequest {
+            drone_id: self.drone_id.clone(),
+            max_idle_secs: max_idle,
+            ..base_spawn_request()
+        };
+
+        let mut sub = self
+            .nats
+            .subscribe(BackendStateMessage::subscribe_subject(&request.backend_id))
+            .await?;
+        // The agent may take a moment to subscribe after starting.
+        timeout(10_000, "Spawn request acknowledged by agent.", async {
+            loop {
+                if let Ok(Ok(())) = self.nats.request(&request).await {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        })
+        .await?;
+
+        timeout(60_000, "Backend should become ready.", async {
+            while let Some(message) = sub.next().await {
+                if message.value.state == BackendState::Ready {
+                    return;
+                }
+            }
+        })
+        .await?;
+
+        let hostname = format!("{}.{}", request.backend_id, CLUSTER_DOMAIN);
+        Ok((TrafficClient::new(self.proxy_address, &hostname), sub))
+    }
+}
+
+const MAX_IDLE: Duration = Duration::from_secs(5);
+
+#[integration_test]
+async fn backend_without_traffic_is_swept() {
+    let nats = Nats::new().await.unwrap();
+    let drone = Drone::new(&nats).await.unwrap();
+    let (_, mut sub) = drone.spawn_ready(MAX_IDLE).await.unwrap();
+
+    // The route's activity time starts when it is created, just before the
+    // backend is reported ready.
+    expect_swept_after(&mut sub, Utc::now(), MAX_IDLE)
+        .await
+        .unwrap();
+}
+
+#[integration_test]
+async fn http_requests_delay_sweep() {
+    let nats = Nats::new().await.unwrap();
+    let drone = Drone::new(&nats).await.unwrap();
+    let (client, mut sub) = drone.spawn_ready(MAX_IDLE).await.unwrap();
+
+    let last_activity = client
+        .run(TrafficPattern::Http {
+            interval: Duration::from_secs(2),
+            duration: Duration::from_secs(12),
+        })
+        .await
+        .unwrap();
+
+    expect_swept_after(&mut sub, last_activity, MAX_IDLE)
+        .await
+        .unwrap();
+}
+
+#[integration_test]
+#[ignore = "Needs a test image with the /ws echo handler."]
+async fn idle_websocket_delays_sweep() {
+    let nats = Nats::new().await.unwrap();
+    let drone = Drone::new(&nats).await.unwrap();
+    let (client, mut sub) = drone.spawn_ready(MAX_IDLE).await.unwrap();
+
+    // An open connection counts as activity even if nothing is sent on it.
+    let last_activity = client
+        .run(TrafficPattern::IdleWebSocket {
+            duration: Duration::from_secs(12),
+        })
+        .await
+        .unwrap();
+
+    expect_swept_after(&mut sub, last_activity, MAX_IDLE)
+        .await
+        .unwrap();
+}
+
+#[integration_test]
+#[ignore = "Needs a test image with the /ws echo handler."]
+async fn active_websocket_delays_sweep() {
+    let nats = Nats::new().await.unwrap();
+    let drone = Drone::new(&nats).await.unwrap();
+    let (client, mut sub) = drone.spawn_ready(MAX_IDLE).await.unwrap();
+
+    let last_activity = client
+        .run(TrafficPattern::ActiveWebSocket {
+            interval: Duration::from_secs(1),
+            duration: Duration::from_secs(12),
+        })
+        .await
+        .unwrap();
+
+    expect_swept_after(&mut sub, last_activity, MAX_IDLE)
+        .await
+        .unwrap();
+}
+
+#[integration_test]
+async fn swept_backend_is_not_routed() {
+    let nats = Nats::new().await.unwrap();
+    let drone = Drone::new(&nats).await.unwrap();
+    let (client, mut sub) = drone.spawn_ready(MAX_IDLE).await.unwrap();
+
+    let last_activity = client
+        .run(TrafficPattern::Http {
+            interval: Duration::from_secs(1),
+            duration: Duration::from_secs(3),
+        })
+        .await
+        .unwrap();
+    expect_swept_after(&mut sub, last_activity, MAX_IDLE)
+        .await
+        .unwrap();
+
+    // Once swept, the backend is no longer routed.
+    assert!(client.http_get("/").await.is_err());
+}
@@ -0,0 +1,162 @@
+use integration_test::integration_test;
+use plane_core::{
+    messages::agent::{BackendState, BackendStateMessage, SpawnRequest, TerminatedBy},
+    nats::TypedSubscription,
+    types::ClusterName,
+    NeverResult,
+};
+use plane_dev::{
+    mock_engine::MockEngine,
+    resources::nats::Nats,
+    scratch_dir,
+    timeout::{expect_to_stay_alive, timeout, LivenessGuard},
+    util::{base_spawn_request, random_loopback_ip},
+};
+use plane_drone::{
+    agent::{engine::EngineBackendStatus, executor::Executor, metadata::serve_metadata},
+    database::DroneDatabase,
+};
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+const CLUSTER_DOMAIN: &str = "plane.test";
+
+/// Total lifetime extension the metadata API grants in these tests.
+const MAX_LIFETIME_EXTENSION_SECS: u64 = 900;
+
+/// A ready backend, and the metadata API it calls.
+struct MetadataTest {
+    port: u16,
+    backend_ip: IpAddr,
+    states: TypedSubscription<BackendStateMessage>,
+    _metadata_guard: LivenessGuard<NeverResult>,
+}
+
+impl MetadataTest {
+    async fn new(nats: &Nats, request: &SpawnRequest) -> MetadataTest {
+        let connection = nats.connection().await.unwrap();
+        let db = DroneDatabase::new(&scratch_dir("metadata").join("drone.db"))
+            .await
+            .unwrap();
+        let engine = MockEngine::default();
+        let executor = Executor::new(
+            engine.clone(),
+            db.clone(),
+            connection.clone(),
+            IpAddr::V4(random_loopback_ip()),
+            ClusterName::new(CLUSTER_DOMAIN),
+            None,
+        );
+
+        let port = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let metadata_guard = expect_to_stay_alive(serve_metadata(
+            port,
+            executor.clone(),
+            db,
+            Duration::from_secs(MAX_LIFETIME_EXTENSION_SECS),
+        ));
+        sleep(Duration::from_millis(100)).await;
+
+        let mut states = connection
+            .subscribe(BackendStateMessage::subscribe_subject(&request.backend_id))
+            .await
+            .unwrap();
+        {
+            let executor = executor.clone();
+            let request = request.clone();
+            tokio::spawn(async move { executor.start_backend(&request).await });
+        }
+        loop {
+            let message = timeout(5_000, "Backend should become ready.", states.next())
+                .await
+                .unwrap()
+                .unwrap();
+            if message.value.state == BackendState::Ready {
+                break;
+            }
+        }
+        let backend_ip = match engine.status(&request.backend_id) {
+            EngineBackendStatus::Running { addr } => addr.ip(),
+            status => panic!("Expected backend to be running, got {:?}.", status),
+        };
+
+        MetadataTest {
+            port,
+            backend_ip,
+            states,
+            _metadata_guard: metadata_guard,
+        }
+    }
+
+    /// POST to the metadata API as if from the given address.
+    async fn post(&self, from: IpAddr, path: &str) -> (StatusCode, Value) {
+        let response = reqwest::Client::builder()
+            .local_address(from)
+            .build()
+            .unwrap()
+            .post(format!("http://127.0.0.1:{}{}", self.port, path))
+            .send()
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        (status, body)
+    }
+}
+
+#[integration_test]
+async fn backend_terminates_itself() {
+    let nats = Nats::new().await.unwrap();
+    let mut test = MetadataTest::new(&nats, &base_spawn_request()).await;
+
+    // Only the backend itself is identified by its address.
+    let (status, _) = test
+        .post(IpAddr::V4(random_loopback_ip()), "/terminate")
+        .await;
+    assert_eq!(StatusCode::FORBIDDEN, status);
+    let (status, _) = test.post(test.backend_ip, "/terminate").await;
+    assert_eq!(StatusCode::ACCEPTED, status);
+
+    let message = timeout(5_000, "State should become Exited.", test.states.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(BackendState::Exited, message.value.state);
+    assert_eq!(
+        Some(TerminatedBy::Backend),
+        message.value.termination.map(|t| t.terminated_by)
+    );
+
+    // Once stopped, the backend is no longer identified.
+    let (status, _) = test.post(test.backend_ip, "/terminate").await;
+    assert_eq!(StatusCode::FORBIDDEN, status);
+}
+
+#[integration_test]
+async fn lifetime_extension_is_capped() {
+    let nats = Nats::new().await.unwrap();
+    let mut request = base_spawn_request();
+    request.max_lifetime_secs = Some(Duration::from_secs(600));
+    let test = MetadataTest::new(&nats, &request).await;
+
+    let (status, body) = test
+        .post(test.backend_ip, "/keepalive?extend_lifetime_secs=600")
+        .await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(600, body["lifetime_extension_secs"]);
+
+    let (status, body) = test
+        .post(test.backend_ip, "/keepalive?extend_lifetime_secs=600")
+        .await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(MAX_LIFETIME_EXTENSION_SECS, body["lifetime_extension_secs"]);
+}
@@ -4,7 +4,10 @@ use chrono::Utc;
 use futures::{SinkExt, StreamExt};
 use http::StatusCode;
 use integration_test::integration_test;
-use plane_core::messages::agent::BackendState;
+use plane_core::grant::{
+    AuthGrant, GRANT_PATH, GRANT_QUERY_PARAM, RECONNECT_PATH, RECONNECT_QUERY_PARAM, SESSION_COOKIE,
+};
+use plane_core::messages::agent::{BackendState, SpawnRequest};
 use plane_core::NeverResult;
 use plane_dev::{
     resources::certs::SelfSignedCert,
@@ -15,7 +18,7 @@ use plane_dev::{
     util::random_loopback_ip,
 };
 use plane_drone::database::DroneDatabase;
-use plane_drone::proxy::ProxyOptions;
+use plane_drone::proxy::{ProxyOptions, ReconnectOptions, SessionOptions};
 use reqwest::Response;
 use reqwest::{Certificate, ClientBuilder};
 use std::net::SocketAddrV4;
@@ -30,6 +33,9 @@ use tokio_tungstenite::{
 
 const CLUSTER: &str = "plane.test";
 
+/// Origin of pages which may exchange grants with the proxy.
+const APP_ORIGIN: &str = "https://app.example";
+
 struct Proxy {
     #[allow(unused)]
     guard: LivenessGuard<NeverResult>,
@@ -70,9 +76,22 @@ impl Proxy {
         let options = ProxyOptions {
             db: db.clone(),
             bind_ip: std::net::IpAddr::V4(bind_ip),
+            bind_ipv6: None,
             bind_port: 4040,
             key_pair: Some(certs.path_pair.clone()),
             cluster_domain: CLUSTER.into(),
+            access_log: None,
+            session: SessionOptions {
+                ttl: Duration::from_secs(60),
+                allowed_origins: vec![APP_ORIGIN.into()],
+            },
+            reconnect: Some(ReconnectOptions {
+                secret: "reconnect-secret".into(),
+                token_ttl: Duration::from_secs(60),
+                allowed_origins: vec![],
+            }),
+            cache: None,
+            metrics: None,
         };
         let guard = expect_to_stay_alive(plane_drone::proxy::serve(options));
 
@@ -97,12 +116,39 @@ impl Proxy {
         &self,
         subdomain: &str,
         path: &str,
+    ) -> std::result::Result<Response, reqwest::Error> {
+        self.http_get_host(&format!("{}.{}", subdomain, CLUSTER), path)
+            .await
+    }
+
+    pub async fn http_get_host(
+        &self,
+        hostname: &str,
+        path: &str,
+    ) -> std::result::Result<Response, reqwest::Error> {
+        self.http_get_host_with_headers(hostname, path, &[]).await
+    }
+
+    pub async fn http_get_with_headers(
+        &self,
+        subdomain: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> std::result::Result<Response, reqwest::Error> {
+        self.http_get_host_with_headers(&format!("{}.{}", subdomain, CLUSTER), path, headers)
+            .await
+    }
+
+    pub async fn http_get_host_with_headers(
+        &self,
+        hostname: &str,
+        path: &str,
+        headers: &[(&str, &str)],
     ) -> std::result::Result<Response, reqwest::Error> {
         let cert = Certificate::from_pem(self.certs.cert_pem.as_bytes()).unwrap();
-        let hostname = format!("{}.{}", subdomain, CLUSTER);
         let client = ClientBuilder::new()
             .add_root_certificate(cert)
-            .resolve(&hostname, self.bind_address)
+            .resolve(hostname, self.bind_address)
             .build()?;
 
         let path = if let Some(path) = path.strip_prefix('/') {
@@ -112,7 +158,11 @@ impl Proxy {
         };
 
         let url = format!("https://{}:{}/{}", hostname, self.bind_address.port(), path);
-        client.get(url).send().await
+        let mut request = client.get(url);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.send().await
     }
 
     pub async fn https_websocket(
@@ -363,6 +413,235 @@ async fn host_header_is_set() {
     assert_eq!("foobar.plane.test:4040", result.text().await.unwrap());
 }
 
+#[integration_test]
+async fn reconnect_token_routes_root_to_backend() {
+    let proxy = Proxy::new().await.unwrap();
+    // The backend sees the request's URI, which should not carry the token.
+    let server = Server::new(|req| async move { req.uri().to_string() })
+        .await
+        .unwrap();
+
+    let sr = base_spawn_request();
+    proxy.db.insert_backend(&sr).await.unwrap();
+    proxy
+        .db
+        .update_backend_state(&sr.backend_id, BackendState::Ready)
+        .await
+        .unwrap();
+
+    proxy
+        .db
+        .insert_proxy_route(
+            &sr.backend_id,
+            sr.backend_id.id(),
+            &server.address.to_string(),
+        )
+        .await
+        .unwrap();
+
+    let token = proxy
+        .http_get(sr.backend_id.id(), RECONNECT_PATH)
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    let result = proxy
+        .http_get_host(
+            CLUSTER,
+            &format!("/?{}={}&room=1", RECONNECT_QUERY_PARAM, token),
+        )
+        .await
+        .unwrap();
+    assert_eq!("/?room=1", result.text().await.unwrap());
+
+    let result = proxy.http_get_host(CLUSTER, "/").await.unwrap();
+    assert_eq!(StatusCode::NOT_FOUND, result.status());
+
+    let result = proxy
+        .http_get_host(CLUSTER, &format!("/?{}={}x", RECONNECT_QUERY_PARAM, token))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::NOT_FOUND, result.status());
+}
+
+#[integration_test]
+async fn grant_exchanged_for_session_cookie() {
+    let proxy = Proxy::new().await.unwrap();
+    // The backend sees the request's credentials, which should not include
+    // the ones the proxy authorized it with.
+    let server = Server::new(|req| async move {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .map(|value| value.to_str().unwrap().to_string())
+                .unwrap_or_default()
+        };
+        format!(
+            "{}|{}",
+            header(http::header::AUTHORIZATION),
+            header(http::header::COOKIE)
+        )
+    })
+    .await
+    .unwrap();
+
+    let sr = SpawnRequest {
+        bearer_token: Some("bearer-token".into()),
+        ..base_spawn_request()
+    };
+    proxy.db.insert_backend(&sr).await.unwrap();
+    proxy
+        .db
+        .update_backend_state(&sr.backend_id, BackendState::Ready)
+        .await
+        .unwrap();
+    proxy
+        .db
+        .insert_proxy_route(&sr.backend_id, "foobar", &server.address.to_string())
+        .await
+        .unwrap();
+
+    let result = proxy.http_get("foobar", "/").await.unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, result.status());
+
+    let grant = AuthGrant::new(sr.backend_id.clone(), Duration::from_secs(30)).sign("bearer-token");
+    let grant_path = format!("{}?{}={}", GRANT_PATH, GRANT_QUERY_PARAM, grant);
+
+    // Only allowed origins are given CORS headers.
+    let result = proxy
+        .http_get_with_headers("foobar", &grant_path, &[("origin", "https://evil.example")])
+        .await
+        .unwrap();
+    assert!(result
+        .headers()
+        .get(http::header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        .is_none());
+
+    let result = proxy
+        .http_get_with_headers("foobar", &grant_path, &[("origin", APP_ORIGIN)])
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::NO_CONTENT, result.status());
+    assert_eq!(
+        APP_ORIGIN,
+        result
+            .headers()
+            .get(http::header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .unwrap()
+    );
+    let set_cookie = result
+        .headers()
+        .get(http::header::SET_COOKIE)
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(set_cookie.contains("Max-Age=60"));
+    let session = set_cookie.split(';').next().unwrap().to_string();
+    assert!(session.starts_with(&format!("{}=", SESSION_COOKIE)));
+
+    let cookies = format!("theme=dark; {}", session);
+    let result = proxy
+        .http_get_with_headers("foobar", "/", &[("cookie", &cookies)])
+        .await
+        .unwrap();
+    assert_eq!("|theme=dark", result.text().await.unwrap());
+
+    let result = proxy
+        .http_get_with_headers("foobar", "/", &[("authorization", "Bearer bearer-token")])
+        .await
+        .unwrap();
+    assert_eq!("|", result.text().await.unwrap());
+}
+
+#[integration_test]
+async fn path_prefix_routes_root_to_backend() {
+    let proxy = Proxy::new().await.unwrap();
+    let server = Server::new(|req| async move {
+        let prefix = req
+            .headers()
+            .get("x-forwarded-prefix")
+            .map(|prefix| prefix.to_str().unwrap().to_owned())
+            .unwrap_or_default();
+        format!("{} {}", prefix, req.uri())
+    })
+    .await
+    .unwrap();
+
+    let path_routed = SpawnRequest {
+        path_routing: true,
+        ..base_spawn_request()
+    };
+    let host_routed = base_spawn_request();
+    for sr in [&path_routed, &host_routed] {
+        proxy.db.insert_backend(sr).await.unwrap();
+        proxy
+            .db
+            .update_backend_state(&sr.backend_id, BackendState::Ready)
+            .await
+            .unwrap();
+
+        proxy
+            .db
+            .insert_proxy_route(
+                &sr.backend_id,
+                sr.backend_id.id(),
+                &server.address.to_string(),
+            )
+            .await
+            .unwrap();
+    }
+
+    let result = proxy
+        .http_get_host(
+            CLUSTER,
+            &format!("/backends/{}/page?x=1", path_routed.backend_id.id()),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        format!("/backends/{} /page?x=1", path_routed.backend_id.id()),
+        result.text().await.unwrap()
+    );
+
+    // Backends of clusters which do not address them by path are only
+    // reached by hostname.
+    let result = proxy
+        .http_get_host(
+            CLUSTER,
+            &format!("/backends/{}/page", host_routed.backend_id.id()),
+        )
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::NOT_FOUND, result.status());
+
+    let result = proxy
+        .http_get_host(CLUSTER, "/backends/no-such-backend/")
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::NOT_FOUND, result.status());
+
+    // A prefix sent by the client is not passed on.
+    let cert = Certificate::from_pem(proxy.certs.cert_pem.as_bytes()).unwrap();
+    let hostname = format!("{}.{}", host_routed.backend_id.id(), CLUSTER);
+    let result = ClientBuilder::new()
+        .add_root_certificate(cert)
+        .resolve(&hostname, proxy.bind_address)
+        .build()
+        .unwrap()
+        .get(format!(
+            "https://{}:{}/page",
+            hostname,
+            proxy.bind_address.port()
+        ))
+        .header("x-forwarded-prefix", "/spoofed")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(" /page", result.text().await.unwrap());
+}
+
 #[integration_test]
 async fn update_certificates() {
     let mut proxy = Proxy::new().await.unwrap();
@@ -1,21 +1,45 @@
 use anyhow::Result;
 use integration_test::integration_test;
-use plane_controller::run_scheduler;
+use plane_controller::{
+    admission::{AdmissionOptions, AdmissionWebhook},
+    auth::StaticTokens,
+    leader::LeaderElectionOptions,
+    plan::{ClusterPlan, SchedulerPlan},
+    rate_limit::RateLimitOptions,
+    run_scheduler,
+};
 use plane_core::{
+    error::PlaneError,
     messages::{
-        agent::{DroneStatusMessage, SpawnRequest},
-        scheduler::ScheduleResponse,
+        agent::{
+            BackendState, BackendStateMessage, DroneRegistrationResponse, DroneStatusMessage,
+            HostFacts, RegisterDrone, SpawnRequest, TerminationRequest,
+        },
+        dns::{DnsRecordType, SetDnsRecord},
+        scheduler::{
+            ApplyClusterConfig, ApproveDrone, ClusterConfig, ClusterListRequest, ScheduleRequest,
+            ScheduleResponse, SchedulerLeader, TerminateBackendRequest,
+        },
     },
     nats::TypedNats,
-    types::{ClusterName, DroneId},
+    signing::{SigningKey, VerifyingKey},
+    types::{BackendId, ClusterName, DroneId},
 };
 use plane_dev::{
-    resources::nats::Nats,
+    resources::{
+        fault_nats::{Fault, FaultInjectingNats},
+        nats::Nats,
+        server::Server,
+    },
     timeout::{expect_to_stay_alive, timeout},
     util::base_scheduler_request,
 };
-use std::time::Duration;
-use tokio::time::sleep;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::time::{sleep, Instant};
 
 const PLANE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -29,6 +53,16 @@ impl MockAgent {
     }
 
     pub async fn schedule_drone(&self, drone_id: &DroneId) -> Result<ScheduleResponse> {
+        self.schedule_drone_responding(drone_id, Ok(())).await
+    }
+
+    /// Schedule a backend, responding to the resulting spawn request with
+    /// `response` as the agent.
+    pub async fn schedule_drone_responding(
+        &self,
+        drone_id: &DroneId,
+        response: Result<(), PlaneError>,
+    ) -> Result<ScheduleResponse> {
         // Subscribe to spawn requests for this drone, to ensure that the
         // scheduler sends them.
         let mut sub = self
@@ -54,8 +88,8 @@ impl MockAgent {
             "Scheduled drone did not match expectation."
         );
 
-        // Acting as the agent, respond true to indicate successful spawn.
-        result.respond(&true).await?;
+        // Acting as the agent, respond to accept or refuse the spawn.
+        result.respond(&response).await?;
 
         // Expect the scheduler to respond.
         let result = timeout(
@@ -73,7 +107,8 @@ impl MockAgent {
 async fn no_drone_available() {
     let nats = Nats::new().await.unwrap();
     let nats_conn = nats.connection().await.unwrap();
-    let _scheduler_guard = expect_to_stay_alive(run_scheduler(nats_conn.clone()));
+    let _scheduler_guard =
+        expect_to_stay_alive(run_scheduler(nats_conn.clone(), SchedulerPlan::default()));
     sleep(Duration::from_millis(100)).await;
 
     let request = base_scheduler_request();
@@ -87,7 +122,177 @@ async fn no_drone_available() {
     .unwrap()
     .unwrap();
 
-    assert_eq!(ScheduleResponse::NoDroneAvailable, result);
+    assert_eq!(
+        ScheduleResponse::Error(PlaneError::NoDroneAvailable),
+        result
+    );
+}
+
+#[integration_test]
+async fn schedule_requests_are_throttled() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let plan = SchedulerPlan {
+        rate_limit: Some(RateLimitOptions {
+            requests_per_second: 1.0,
+            burst: Some(2),
+        }),
+        ..SchedulerPlan::default()
+    };
+    let _scheduler_guard = expect_to_stay_alive(run_scheduler(nats_conn.clone(), plan));
+    sleep(Duration::from_millis(100)).await;
+
+    let request = base_scheduler_request();
+    for _ in 0..2 {
+        let result = timeout(
+            1_000,
+            "Schedule request should be responded.",
+            nats_conn.request(&request),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            ScheduleResponse::Error(PlaneError::NoDroneAvailable),
+            result
+        );
+    }
+
+    let result = timeout(
+        1_000,
+        "Throttled request should be responded.",
+        nats_conn.request(&request),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    match result {
+        ScheduleResponse::Throttled { retry_after_ms } => {
+            assert!(retry_after_ms > 0 && retry_after_ms <= 1_000)
+        }
+        result => panic!("Expected a throttled response, got {:?}.", result),
+    }
+}
+
+#[integration_test]
+async fn terminate_unknown_or_terminated_backend() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let _scheduler_guard =
+        expect_to_stay_alive(run_scheduler(nats_conn.clone(), SchedulerPlan::default()));
+    sleep(Duration::from_millis(100)).await;
+
+    let terminated_backend = BackendId::new("terminated-backend".into());
+    nats_conn
+        .publish_jetstream(&BackendStateMessage::new(
+            BackendState::Swept,
+            terminated_backend.clone(),
+        ))
+        .await
+        .unwrap();
+
+    for backend in [BackendId::new("unknown-backend".into()), terminated_backend] {
+        let result = timeout(
+            1_000,
+            "Termination request should be responded.",
+            nats_conn.request(&TerminateBackendRequest {
+                backend,
+                drain: false,
+                grace_period_secs: Duration::ZERO,
+            }),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(
+            matches!(result, Err(PlaneError::NotFound { .. })),
+            "Expected a not found error, got {:?}.",
+            result
+        );
+    }
+}
+
+#[integration_test]
+async fn terminate_backend_forwarded_to_drone() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let drone_id = DroneId::new_random();
+    let _scheduler_guard =
+        expect_to_stay_alive(run_scheduler(nats_conn.clone(), SchedulerPlan::default()));
+    sleep(Duration::from_millis(100)).await;
+
+    nats_conn
+        .publish(&drone_status(&drone_id, true))
+        .await
+        .unwrap();
+    let (slow_backend, backend) = (BackendId::new_random(), BackendId::new_random());
+    for backend in [&slow_backend, &backend] {
+        nats_conn
+            .publish_jetstream(
+                &BackendStateMessage::new(BackendState::Ready, backend.clone())
+                    .with_drone(drone_id.clone()),
+            )
+            .await
+            .unwrap();
+    }
+    let mut sub = nats_conn
+        .subscribe(TerminationRequest::subscribe_subject(&ClusterName::new(
+            "plane.test",
+        )))
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let request = |backend: &BackendId| TerminateBackendRequest {
+        backend: backend.clone(),
+        drain: false,
+        grace_period_secs: Duration::ZERO,
+    };
+    let mut slow_response = nats_conn
+        .split_request(&request(&slow_backend))
+        .await
+        .unwrap();
+    let slow_termination = timeout(1_000, "Drone should receive termination.", sub.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(slow_backend, slow_termination.value.backend_id);
+
+    // The drone has not responded for the first backend, which does not
+    // hold up the second.
+    let mut response = nats_conn.split_request(&request(&backend)).await.unwrap();
+    let termination = timeout(1_000, "Drone should receive termination.", sub.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(backend, termination.value.backend_id);
+    assert_eq!(ClusterName::new("plane.test"), termination.value.cluster_id);
+    termination.respond(&Ok(())).await.unwrap();
+    let result = timeout(
+        1_000,
+        "Termination should be responded.",
+        response.response(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(Ok(()), result);
+
+    slow_termination
+        .respond(&Err(PlaneError::Internal {
+            reason: "Container would not stop.".into(),
+        }))
+        .await
+        .unwrap();
+    let result = timeout(
+        1_000,
+        "Termination should be responded.",
+        slow_response.response(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(matches!(result, Err(PlaneError::Internal { .. })));
 }
 
 #[integration_test]
@@ -96,7 +301,8 @@ async fn one_drone_available() {
     let nats_conn = nats.connection().await.unwrap();
     let drone_id = DroneId::new_random();
     let mock_agent = MockAgent::new(nats_conn.clone());
-    let _scheduler_guard = expect_to_stay_alive(run_scheduler(nats_conn.clone()));
+    let _scheduler_guard =
+        expect_to_stay_alive(run_scheduler(nats_conn.clone(), SchedulerPlan::default()));
     sleep(Duration::from_millis(100)).await;
 
     nats_conn
@@ -106,6 +312,12 @@ async fn one_drone_available() {
             drone_version: PLANE_VERSION.to_string(),
             ready: true,
             running_backends: None,
+            remaining_capacity: None,
+            host_metrics: None,
+            sealing_key: None,
+            arch: None,
+            proxy_metrics: None,
+            draining: false,
         })
         .await
         .unwrap();
@@ -114,12 +326,141 @@ async fn one_drone_available() {
     assert!(matches!(result, ScheduleResponse::Scheduled { drone, .. } if drone == drone_id));
 }
 
+#[integration_test]
+async fn drone_refuses_backend() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let drone_id = DroneId::new_random();
+    let mock_agent = MockAgent::new(nats_conn.clone());
+    let _scheduler_guard =
+        expect_to_stay_alive(run_scheduler(nats_conn.clone(), SchedulerPlan::default()));
+    sleep(Duration::from_millis(100)).await;
+
+    nats_conn
+        .publish(&drone_status(&drone_id, true))
+        .await
+        .unwrap();
+
+    let error = PlaneError::InsufficientDisk {
+        free_bytes: 500,
+        min_free_bytes: 1000,
+    };
+    let result = mock_agent
+        .schedule_drone_responding(&drone_id, Err(error.clone()))
+        .await
+        .unwrap();
+    assert_eq!(ScheduleResponse::Error(error), result);
+}
+
+#[integration_test]
+async fn dry_run_does_not_spawn() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let drone_id = DroneId::new_random();
+    let _scheduler_guard =
+        expect_to_stay_alive(run_scheduler(nats_conn.clone(), SchedulerPlan::default()));
+    sleep(Duration::from_millis(100)).await;
+
+    nats_conn
+        .publish(&drone_status(&drone_id, true))
+        .await
+        .unwrap();
+
+    // Nothing listens for spawn requests, so a real spawn would fail.
+    let mut request = base_scheduler_request();
+    request.dry_run = true;
+    let result = timeout(
+        1_000,
+        "Schedule request should be responded.",
+        nats_conn.request(&request),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    match result {
+        ScheduleResponse::DryRun {
+            drone,
+            spawn_request,
+        } => {
+            assert_eq!(drone_id, drone);
+            assert_eq!(drone_id, spawn_request.drone_id);
+            assert_eq!(request.executable.image, spawn_request.executable.image);
+        }
+        result => panic!("Expected a dry run, got {:?}", result),
+    }
+}
+
+#[integration_test]
+async fn schedule_deadline_exceeded() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let drone_id = DroneId::new_random();
+    let _scheduler_guard =
+        expect_to_stay_alive(run_scheduler(nats_conn.clone(), SchedulerPlan::default()));
+    sleep(Duration::from_millis(100)).await;
+
+    nats_conn
+        .publish(&drone_status(&drone_id, true))
+        .await
+        .unwrap();
+
+    // The drone receives the spawn request, but only accepts it after the
+    // deadline.
+    let mut spawn_sub = nats_conn
+        .subscribe(SpawnRequest::subscribe_subject(&drone_id))
+        .await
+        .unwrap();
+    let mut request = base_scheduler_request();
+    let mut terminate_sub = nats_conn
+        .subscribe(TerminationRequest::subscribe_subject(&request.cluster))
+        .await
+        .unwrap();
+
+    request.schedule_deadline_ms = Some(Duration::from_millis(200));
+    let result = timeout(
+        1_000,
+        "Schedule request should be responded before the drone times out.",
+        nats_conn.request(&request),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    assert_eq!(
+        ScheduleResponse::Error(PlaneError::ScheduleDeadlineExceeded { deadline_ms: 200 }),
+        result
+    );
+
+    // The backend the drone started late is terminated, since the client
+    // does not know of it.
+    let spawn_request = timeout(
+        1_000,
+        "Drone should receive spawn request.",
+        spawn_sub.next(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    spawn_request.respond(&Ok(())).await.unwrap();
+    let termination = timeout(
+        1_000,
+        "Late backend should be terminated.",
+        terminate_sub.next(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(spawn_request.value.backend_id, termination.value.backend_id);
+}
+
 #[integration_test]
 async fn drone_not_ready() {
     let nats = Nats::new().await.unwrap();
     let nats_conn = nats.connection().await.unwrap();
     let drone_id = DroneId::new_random();
-    let _scheduler_guard = expect_to_stay_alive(run_scheduler(nats_conn.clone()));
+    let _scheduler_guard =
+        expect_to_stay_alive(run_scheduler(nats_conn.clone(), SchedulerPlan::default()));
     sleep(Duration::from_millis(100)).await;
 
     nats_conn
@@ -129,6 +470,12 @@ async fn drone_not_ready() {
             drone_version: PLANE_VERSION.to_string(),
             ready: false,
             running_backends: None,
+            remaining_capacity: None,
+            host_metrics: None,
+            sealing_key: None,
+            arch: None,
+            proxy_metrics: None,
+            draining: false,
         })
         .await
         .unwrap();
@@ -144,7 +491,10 @@ async fn drone_not_ready() {
     .unwrap()
     .unwrap();
 
-    assert_eq!(ScheduleResponse::NoDroneAvailable, result);
+    assert_eq!(
+        ScheduleResponse::Error(PlaneError::NoDroneAvailable),
+        result
+    );
 }
 
 #[integration_test]
@@ -152,7 +502,8 @@ async fn drone_becomes_not_ready() {
     let nats = Nats::new().await.unwrap();
     let nats_conn = nats.connection().await.unwrap();
     let drone_id = DroneId::new_random();
-    let _scheduler_guard = expect_to_stay_alive(run_scheduler(nats_conn.clone()));
+    let _scheduler_guard =
+        expect_to_stay_alive(run_scheduler(nats_conn.clone(), SchedulerPlan::default()));
     sleep(Duration::from_millis(100)).await;
 
     nats_conn
@@ -162,6 +513,12 @@ async fn drone_becomes_not_ready() {
             drone_version: PLANE_VERSION.to_string(),
             ready: true,
             running_backends: None,
+            remaining_capacity: None,
+            host_metrics: None,
+            sealing_key: None,
+            arch: None,
+            proxy_metrics: None,
+            draining: false,
         })
         .await
         .unwrap();
@@ -173,6 +530,12 @@ async fn drone_becomes_not_ready() {
             drone_version: PLANE_VERSION.to_string(),
             ready: false,
             running_backends: None,
+            remaining_capacity: None,
+            host_metrics: None,
+            sealing_key: None,
+            arch: None,
+            proxy_metrics: None,
+            draining: false,
         })
         .await
         .unwrap();
@@ -189,5 +552,902 @@ async fn drone_becomes_not_ready() {
     .await
     .unwrap()
     .unwrap();
-    assert_eq!(ScheduleResponse::NoDroneAvailable, result);
+    assert_eq!(
+        ScheduleResponse::Error(PlaneError::NoDroneAvailable),
+        result
+    );
+}
+
+#[integration_test]
+async fn list_clusters() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let _scheduler_guard =
+        expect_to_stay_alive(run_scheduler(nats_conn.clone(), SchedulerPlan::default()));
+    sleep(Duration::from_millis(100)).await;
+
+    nats_conn
+        .publish(&DroneStatusMessage {
+            cluster: ClusterName::new("plane.test"),
+            drone_id: DroneId::new_random(),
+            drone_version: PLANE_VERSION.to_string(),
+            ready: true,
+            running_backends: Some(2),
+            remaining_capacity: None,
+            host_metrics: None,
+            sealing_key: None,
+            arch: None,
+            proxy_metrics: None,
+            draining: false,
+        })
+        .await
+        .unwrap();
+
+    nats_conn
+        .publish(&SetDnsRecord {
+            cluster: ClusterName::new("other.test"),
+            kind: DnsRecordType::TXT,
+            name: "_acme-challenge".into(),
+            value: "challenge".into(),
+        })
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let clusters = timeout(
+        1_000,
+        "Cluster list request should be responded.",
+        nats_conn.request(&ClusterListRequest::default()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    assert_eq!(2, clusters.len());
+
+    assert_eq!(ClusterName::new("other.test"), clusters[0].cluster);
+    assert_eq!(0, clusters[0].ready_drones);
+    assert!(clusters[0].last_dns_record.is_some());
+
+    assert_eq!(ClusterName::new("plane.test"), clusters[1].cluster);
+    assert_eq!(1, clusters[1].ready_drones);
+    assert_eq!(2, clusters[1].running_backends);
+    assert!(clusters[1].last_dns_record.is_none());
+}
+
+#[integration_test]
+async fn signed_schedule_request() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let drone_id = DroneId::new_random();
+
+    let signing_key = SigningKey::from_seed(&SigningKey::generate().unwrap()).unwrap();
+    let plan = SchedulerPlan {
+        clusters: vec![(
+            ClusterName::new("plane.test"),
+            ClusterPlan {
+                signing_key: Some(VerifyingKey::from_base64(&signing_key.public_key()).unwrap()),
+                ..ClusterPlan::default()
+            },
+        )]
+        .into_iter()
+        .collect(),
+        ..SchedulerPlan::default()
+    };
+    let _scheduler_guard = expect_to_stay_alive(run_scheduler(nats_conn.clone(), plan));
+    sleep(Duration::from_millis(100)).await;
+
+    nats_conn
+        .publish(&DroneStatusMessage {
+            cluster: ClusterName::new("plane.test"),
+            drone_id: drone_id.clone(),
+            drone_version: PLANE_VERSION.to_string(),
+            ready: true,
+            running_backends: None,
+            remaining_capacity: None,
+            host_metrics: None,
+            sealing_key: None,
+            arch: None,
+            proxy_metrics: None,
+            draining: false,
+        })
+        .await
+        .unwrap();
+
+    let result = timeout(
+        1_000,
+        "Schedule request should be responded.",
+        nats_conn.request(&base_scheduler_request()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(matches!(
+        result,
+        ScheduleResponse::Error(PlaneError::Unauthenticated { .. })
+    ));
+
+    let mock_agent = MockAgent::new(nats_conn.with_signing_key(Some(signing_key)));
+    let result = mock_agent.schedule_drone(&drone_id).await.unwrap();
+    assert!(matches!(result, ScheduleResponse::Scheduled { drone, .. } if drone == drone_id));
+}
+
+#[integration_test]
+async fn auth_token_only_sent_to_controller() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let client = nats_conn
+        .clone()
+        .with_auth_token(Some("client-token".to_string()));
+    let cluster = ClusterName::new("plane.test");
+
+    let mut schedule_sub = nats_conn
+        .subscribe(ScheduleRequest::subscribe_subject())
+        .await
+        .unwrap();
+    let mut terminate_sub = nats_conn
+        .subscribe(TerminationRequest::subscribe_subject(&cluster))
+        .await
+        .unwrap();
+
+    client
+        .split_request(&base_scheduler_request())
+        .await
+        .unwrap();
+    let request = timeout(
+        1_000,
+        "Should receive schedule request.",
+        schedule_sub.next(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(Some("client-token"), request.bearer_token());
+
+    // Requests handled by drones do not carry the token.
+    client
+        .split_request(&TerminationRequest {
+            cluster_id: cluster.clone(),
+            backend_id: BackendId::new_random(),
+            drain: false,
+            grace_period_secs: Duration::ZERO,
+        })
+        .await
+        .unwrap();
+    let request = timeout(
+        1_000,
+        "Should receive termination request.",
+        terminate_sub.next(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(None, request.bearer_token());
+}
+
+#[integration_test]
+async fn drone_approval() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let drone_id = DroneId::new_random();
+    let cluster = ClusterName::new("plane.test");
+
+    let plan = SchedulerPlan {
+        clusters: vec![(
+            cluster.clone(),
+            ClusterPlan {
+                require_drone_approval: true,
+                ..ClusterPlan::default()
+            },
+        )]
+        .into_iter()
+        .collect(),
+        ..SchedulerPlan::default()
+    };
+    let _scheduler_guard = expect_to_stay_alive(run_scheduler(nats_conn.clone(), plan));
+    sleep(Duration::from_millis(100)).await;
+
+    let status = DroneStatusMessage {
+        cluster: cluster.clone(),
+        drone_id: drone_id.clone(),
+        drone_version: PLANE_VERSION.to_string(),
+        ready: true,
+        running_backends: None,
+        remaining_capacity: None,
+        host_metrics: None,
+        sealing_key: None,
+        arch: None,
+        proxy_metrics: None,
+        draining: false,
+    };
+    nats_conn.publish(&status).await.unwrap();
+
+    // The drone has not registered, so it is not scheduled to.
+    let result = timeout(
+        1_000,
+        "Schedule request should be responded.",
+        nats_conn.request(&base_scheduler_request()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        ScheduleResponse::Error(PlaneError::NoDroneAvailable),
+        result
+    );
+
+    let registration_key = SigningKey::from_seed(&SigningKey::generate().unwrap()).unwrap();
+    let registration = RegisterDrone {
+        drone_id: drone_id.clone(),
+        cluster: cluster.clone(),
+        public_key: registration_key.public_key(),
+        host: HostFacts {
+            hostname: None,
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            drone_version: PLANE_VERSION.to_string(),
+            os: "linux".into(),
+            arch: "x86_64".into(),
+        },
+    };
+
+    // Registrations which are not signed with their key are rejected.
+    let result = nats_conn.request(&registration).await.unwrap();
+    assert!(matches!(result, DroneRegistrationResponse::Rejected { .. }));
+
+    let key_fingerprint = VerifyingKey::from_base64(&registration.public_key)
+        .unwrap()
+        .fingerprint();
+    let drone_nats = nats_conn.clone().with_signing_key(Some(registration_key));
+    let result = drone_nats.request(&registration).await.unwrap();
+    assert_eq!(DroneRegistrationResponse::Pending, result);
+
+    // Approvals name the key they approve.
+    let other_key = SigningKey::from_seed(&SigningKey::generate().unwrap()).unwrap();
+    let result = nats_conn
+        .request(&ApproveDrone {
+            drone: drone_id.clone(),
+            cluster: cluster.clone(),
+            key_fingerprint: VerifyingKey::from_base64(&other_key.public_key())
+                .unwrap()
+                .fingerprint(),
+        })
+        .await
+        .unwrap();
+    assert!(matches!(result, Err(PlaneError::InvalidRequest { .. })));
+
+    nats_conn
+        .request(&ApproveDrone {
+            drone: drone_id.clone(),
+            cluster: cluster.clone(),
+            key_fingerprint,
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    let result = drone_nats.request(&registration).await.unwrap();
+    assert_eq!(DroneRegistrationResponse::Approved, result);
+
+    // Status messages under the drone's ID are ignored unless they are
+    // signed with its key.
+    nats_conn.publish(&status).await.unwrap();
+    let result = timeout(
+        1_000,
+        "Schedule request should be responded.",
+        nats_conn.request(&base_scheduler_request()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        ScheduleResponse::Error(PlaneError::NoDroneAvailable),
+        result
+    );
+
+    drone_nats.publish(&status).await.unwrap();
+    let mock_agent = MockAgent::new(nats_conn.clone());
+    let result = mock_agent.schedule_drone(&drone_id).await.unwrap();
+    assert!(matches!(result, ScheduleResponse::Scheduled { drone, .. } if drone == drone_id));
+}
+
+#[integration_test]
+async fn custom_hostname() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let _scheduler_guard =
+        expect_to_stay_alive(run_scheduler(nats_conn.clone(), SchedulerPlan::default()));
+    sleep(Duration::from_millis(100)).await;
+
+    nats_conn
+        .publish(&SetDnsRecord {
+            cluster: ClusterName::new("plane.test"),
+            kind: DnsRecordType::A,
+            name: "game-lobby-42".into(),
+            value: "127.0.0.1".into(),
+        })
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    for name in ["game-lobby-42", "Game_Lobby"] {
+        let mut request = base_scheduler_request();
+        request.backend_id = Some(BackendId::new(name.into()));
+
+        let result = timeout(
+            1_000,
+            "Schedule request should be responded.",
+            nats_conn.request(&request),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(
+            matches!(
+                result,
+                ScheduleResponse::Error(PlaneError::InvalidRequest { .. })
+            ),
+            "Expected {} to be rejected.",
+            name
+        );
+    }
+}
+
+#[integration_test]
+async fn applied_cluster_config() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let drone_id = DroneId::new_random();
+
+    // Configs applied before the controller starts are loaded from JetStream.
+    let mut config = ClusterConfig {
+        cluster: ClusterName::new("plane.test"),
+        max_backends: None,
+        default_resource_limits: None,
+        max_memory_bytes: None,
+        max_cpu_period_percent: None,
+        dns_ttl_secs: None,
+        allowed_images: Some(vec!["registry.example.com/".into()]),
+        backend_url: None,
+    };
+    nats_conn.publish_jetstream(&config).await.unwrap();
+
+    let plan = SchedulerPlan {
+        auth: Some(Arc::new(StaticTokens {
+            tokens: [("operator".to_string(), "operator-token".to_string())]
+                .into_iter()
+                .collect(),
+        })),
+        ..SchedulerPlan::default()
+    };
+    let _scheduler_guard = expect_to_stay_alive(run_scheduler(nats_conn.clone(), plan));
+    let operator = nats_conn
+        .clone()
+        .with_auth_token(Some("operator-token".to_string()));
+    sleep(Duration::from_millis(100)).await;
+    nats_conn
+        .publish(&drone_status(&drone_id, true))
+        .await
+        .unwrap();
+
+    let result = timeout(
+        1_000,
+        "Schedule request should be responded.",
+        operator.request(&base_scheduler_request()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(matches!(
+        result,
+        ScheduleResponse::Error(PlaneError::InvalidRequest { .. })
+    ));
+
+    // Applying a config replaces the previous one, but only an
+    // authenticated request may apply one.
+    config.allowed_images = None;
+    config.max_backends = Some(0);
+    let apply = ApplyClusterConfig {
+        config: config.clone(),
+    };
+    let result = timeout(
+        1_000,
+        "Apply request should be responded.",
+        nats_conn.request(&apply),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(matches!(result, Err(PlaneError::Unauthenticated { .. })));
+
+    let result = timeout(
+        1_000,
+        "Apply request should be responded.",
+        operator.request(&apply),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(Ok(()), result);
+    sleep(Duration::from_millis(100)).await;
+
+    let result = timeout(
+        1_000,
+        "Schedule request should be responded.",
+        operator.request(&base_scheduler_request()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(matches!(
+        result,
+        ScheduleResponse::Error(PlaneError::QuotaExceeded { .. })
+    ));
+}
+
+fn leader_plan(controller: &str) -> SchedulerPlan {
+    SchedulerPlan {
+        leader_election: Some(LeaderElectionOptions {
+            controller_id: Some(controller.into()),
+            lease_secs: 3,
+        }),
+        ..SchedulerPlan::default()
+    }
+}
+
+#[integration_test]
+async fn leader_failover() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let mut leader_sub = nats_conn
+        .subscribe(SchedulerLeader::subscribe_subject())
+        .await
+        .unwrap();
+
+    let first_guard = expect_to_stay_alive(run_scheduler(nats_conn.clone(), leader_plan("first")));
+    let leader = timeout(
+        5_000,
+        "First controller should become leader.",
+        leader_sub.next(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!("first", leader.value.controller);
+
+    let _second_guard =
+        expect_to_stay_alive(run_scheduler(nats_conn.clone(), leader_plan("second")));
+    sleep(Duration::from_millis(100)).await;
+    drop(first_guard);
+
+    // Once the first controller's lease lapses, the second takes over.
+    timeout(10_000, "Second controller should become leader.", async {
+        while let Some(leader) = leader_sub.next().await {
+            if leader.value.controller == "second" {
+                return;
+            }
+        }
+    })
+    .await
+    .unwrap();
+
+    let result = timeout(
+        1_000,
+        "Schedule request should be responded by the new leader.",
+        nats_conn.request(&base_scheduler_request()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        ScheduleResponse::Error(PlaneError::NoDroneAvailable),
+        result
+    );
+}
+
+fn drone_status(drone_id: &DroneId, ready: bool) -> DroneStatusMessage {
+    DroneStatusMessage {
+        cluster: ClusterName::new("plane.test"),
+        drone_id: drone_id.clone(),
+        drone_version: PLANE_VERSION.to_string(),
+        ready,
+        running_backends: None,
+        remaining_capacity: None,
+        host_metrics: None,
+        sealing_key: None,
+        arch: None,
+        proxy_metrics: None,
+        draining: false,
+    }
+}
+
+#[integration_test]
+async fn drone_status_lost() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let fault_nats = FaultInjectingNats::new(&nats).await.unwrap();
+    fault_nats.inject(DroneStatusMessage::subscribe_subject(), Fault::Drop);
+    let drone_id = DroneId::new_random();
+    let mock_agent = MockAgent::new(nats_conn.clone());
+    let _scheduler_guard = expect_to_stay_alive(run_scheduler(
+        fault_nats.connection().await.unwrap(),
+        SchedulerPlan::default(),
+    ));
+    sleep(Duration::from_millis(100)).await;
+
+    // The scheduler never hears of the drone.
+    nats_conn
+        .publish(&drone_status(&drone_id, true))
+        .await
+        .unwrap();
+    let result = timeout(
+        1_000,
+        "Schedule request should be responded.",
+        nats_conn.request(&base_scheduler_request()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        ScheduleResponse::Error(PlaneError::NoDroneAvailable),
+        result
+    );
+
+    // The drone's next status gets through.
+    fault_nats.clear();
+    nats_conn
+        .publish(&drone_status(&drone_id, true))
+        .await
+        .unwrap();
+
+    let result = mock_agent.schedule_drone(&drone_id).await.unwrap();
+    assert!(matches!(result, ScheduleResponse::Scheduled { drone, .. } if drone == drone_id));
+}
+
+#[integration_test]
+async fn drone_status_duplicated_and_delayed() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let fault_nats = FaultInjectingNats::new(&nats).await.unwrap();
+    fault_nats.inject(DroneStatusMessage::subscribe_subject(), Fault::Duplicate);
+    fault_nats.inject(
+        ScheduleRequest::subscribe_subject(),
+        Fault::Delay(Duration::from_millis(500)),
+    );
+    let drone_id = DroneId::new_random();
+    let mock_agent = MockAgent::new(nats_conn.clone());
+    let _scheduler_guard = expect_to_stay_alive(run_scheduler(
+        fault_nats.connection().await.unwrap(),
+        SchedulerPlan::default(),
+    ));
+    sleep(Duration::from_millis(100)).await;
+
+    nats_conn
+        .publish(&drone_status(&drone_id, true))
+        .await
+        .unwrap();
+
+    let result = mock_agent.schedule_drone(&drone_id).await.unwrap();
+    assert!(matches!(result, ScheduleResponse::Scheduled { drone, .. } if drone == drone_id));
+}
+
+#[integration_test]
+async fn fault_nats_reorders_messages() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let fault_nats = FaultInjectingNats::new(&nats).await.unwrap();
+    fault_nats.inject(DroneStatusMessage::subscribe_subject(), Fault::Reorder);
+    let mut status_sub = fault_nats
+        .connection()
+        .await
+        .unwrap()
+        .subscribe(DroneStatusMessage::subscribe_subject())
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let (first, second) = (DroneId::new_random(), DroneId::new_random());
+    nats_conn
+        .publish(&drone_status(&first, true))
+        .await
+        .unwrap();
+    nats_conn
+        .publish(&drone_status(&second, true))
+        .await
+        .unwrap();
+
+    for expected in [&second, &first] {
+        let message = timeout(1_000, "Should receive status message.", status_sub.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(expected, &message.value.drone_id);
+    }
+}
+
+#[integration_test]
+async fn fault_nats_delays_messages() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let fault_nats = FaultInjectingNats::new(&nats).await.unwrap();
+    let delay = Duration::from_millis(500);
+    fault_nats.inject(DroneStatusMessage::subscribe_subject(), Fault::Delay(delay));
+    let fault_conn = fault_nats.connection().await.unwrap();
+    let mut status_sub = fault_conn
+        .subscribe(DroneStatusMessage::subscribe_subject())
+        .await
+        .unwrap();
+    let mut dns_sub = fault_conn
+        .subscribe(SetDnsRecord::subscribe_subject())
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let drone_id = DroneId::new_random();
+    let start = Instant::now();
+    nats_conn
+        .publish(&drone_status(&drone_id, true))
+        .await
+        .unwrap();
+    nats_conn
+        .publish(&SetDnsRecord {
+            cluster: ClusterName::new("plane.test"),
+            kind: DnsRecordType::A,
+            name: "foo".into(),
+            value: "12.12.12.12".into(),
+        })
+        .await
+        .unwrap();
+
+    // A message on a subject without a fault is not held up behind the
+    // delayed one.
+    timeout(1_000, "Should receive DNS message.", dns_sub.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(start.elapsed() < delay);
+
+    let message = timeout(
+        2_000,
+        "Should receive delayed status message.",
+        status_sub.next(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(drone_id, message.value.drone_id);
+    assert!(start.elapsed() >= delay);
+}
+
+fn admission_plan(url: String) -> SchedulerPlan {
+    SchedulerPlan {
+        admission: Some(Arc::new(
+            AdmissionWebhook::new(AdmissionOptions {
+                url,
+                timeout_secs: 1,
+                fail_open: false,
+            })
+            .unwrap(),
+        )),
+        ..SchedulerPlan::default()
+    }
+}
+
+#[integration_test]
+async fn admission_webhook_rejects() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let webhook =
+        Server::new(|_| async { r#"{"decision": "reject", "reason": "Over quota."}"#.to_string() })
+            .await
+            .unwrap();
+    let _scheduler_guard = expect_to_stay_alive(run_scheduler(
+        nats_conn.clone(),
+        admission_plan(format!("http://{}/", webhook.address)),
+    ));
+    sleep(Duration::from_millis(100)).await;
+
+    let result = timeout(
+        1_000,
+        "Schedule request should be responded.",
+        nats_conn.request(&base_scheduler_request()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        ScheduleResponse::Error(PlaneError::AdmissionDenied {
+            reason: "Over quota.".into()
+        }),
+        result
+    );
+}
+
+#[integration_test]
+async fn admission_webhook_cannot_undo_dry_run() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let drone_id = DroneId::new_random();
+    let webhook = Server::new(|request| async {
+        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        let mut request: ScheduleRequest = serde_json::from_slice(&body).unwrap();
+        request.dry_run = false;
+        request
+            .executable
+            .env
+            .insert("SECRET".into(), "from-webhook".into());
+        serde_json::json!({"decision": "mutate", "request": request}).to_string()
+    })
+    .await
+    .unwrap();
+    let _scheduler_guard = expect_to_stay_alive(run_scheduler(
+        nats_conn.clone(),
+        admission_plan(format!("http://{}/", webhook.address)),
+    ));
+    sleep(Duration::from_millis(100)).await;
+
+    nats_conn
+        .publish(&drone_status(&drone_id, true))
+        .await
+        .unwrap();
+
+    // Nothing listens for spawn requests, so a real spawn would fail.
+    let mut request = base_scheduler_request();
+    request.dry_run = true;
+    request
+        .executable
+        .env
+        .insert("FROM_CLIENT".into(), "visible".into());
+    let result = timeout(
+        1_000,
+        "Schedule request should be responded.",
+        nats_conn.request(&request),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    match result {
+        ScheduleResponse::DryRun { spawn_request, .. } => {
+            let env = &spawn_request.executable.env;
+            assert_eq!(Some(&"visible".to_string()), env.get("FROM_CLIENT"));
+            assert_eq!(Some(&"<redacted>".to_string()), env.get("SECRET"));
+        }
+        result => panic!("Expected a dry run, got {:?}", result),
+    }
+}
+
+#[integration_test]
+async fn admission_webhook_mutates() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let drone_id = DroneId::new_random();
+    let webhook = Server::new(|request| async {
+        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        let mut request: ScheduleRequest = serde_json::from_slice(&body).unwrap();
+        request
+            .executable
+            .env
+            .insert("INJECTED".into(), "by-webhook".into());
+        serde_json::json!({"decision": "mutate", "request": request}).to_string()
+    })
+    .await
+    .unwrap();
+    let _scheduler_guard = expect_to_stay_alive(run_scheduler(
+        nats_conn.clone(),
+        admission_plan(format!("http://{}/", webhook.address)),
+    ));
+    sleep(Duration::from_millis(100)).await;
+
+    nats_conn
+        .publish(&drone_status(&drone_id, true))
+        .await
+        .unwrap();
+    let mut sub = nats_conn
+        .subscribe(SpawnRequest::subscribe_subject(&drone_id))
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let mut response_handle = nats_conn
+        .split_request(&base_scheduler_request())
+        .await
+        .unwrap();
+    let spawn_request = timeout(1_000, "Agent should receive spawn request.", sub.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        Some(&"by-webhook".to_string()),
+        spawn_request.value.executable.env.get("INJECTED")
+    );
+    spawn_request.respond(&Ok(())).await.unwrap();
+
+    let result = timeout(
+        1_000,
+        "Schedule request should be responded.",
+        response_handle.response(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(matches!(result, ScheduleResponse::Scheduled { drone, .. } if drone == drone_id));
+}
+
+#[integration_test]
+async fn admission_webhook_slow() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    // Requests marked slow outlast the webhook's timeout.
+    let webhook = Server::new(|request| async {
+        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        let request: ScheduleRequest = serde_json::from_slice(&body).unwrap();
+        if request.metadata.contains_key("slow") {
+            sleep(Duration::from_secs(5)).await;
+        }
+        r#"{"decision": "reject", "reason": "Over quota."}"#.to_string()
+    })
+    .await
+    .unwrap();
+    let _scheduler_guard = expect_to_stay_alive(run_scheduler(
+        nats_conn.clone(),
+        admission_plan(format!("http://{}/", webhook.address)),
+    ));
+    sleep(Duration::from_millis(100)).await;
+
+    let mut slow_request = base_scheduler_request();
+    slow_request.metadata.insert("slow".into(), "true".into());
+    let mut slow_response = nats_conn.split_request(&slow_request).await.unwrap();
+
+    // Other requests are not held up by the slow one.
+    let result = timeout(
+        500,
+        "Schedule request should be responded.",
+        nats_conn.request(&base_scheduler_request()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        ScheduleResponse::Error(PlaneError::AdmissionDenied {
+            reason: "Over quota.".into()
+        }),
+        result
+    );
+
+    let result = timeout(
+        2_000,
+        "Slow schedule request should time out.",
+        slow_response.response(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(matches!(
+        result,
+        ScheduleResponse::Error(PlaneError::Internal { .. })
+    ));
+}
+
+#[integration_test]
+async fn admission_webhook_unreachable() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let _scheduler_guard = expect_to_stay_alive(run_scheduler(
+        nats_conn.clone(),
+        // Nothing listens on port 9 (discard) of the loopback address.
+        admission_plan("http://127.0.0.1:9/".into()),
+    ));
+    sleep(Duration::from_millis(100)).await;
+
+    let result = timeout(
+        2_000,
+        "Schedule request should be responded.",
+        nats_conn.request(&base_scheduler_request()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(matches!(
+        result,
+        ScheduleResponse::Error(PlaneError::Internal { .. })
+    ));
 }
@@ -0,0 +1,109 @@
+use async_nats::jetstream::consumer::DeliverPolicy;
+use integration_test::integration_test;
+use plane_core::{
+    messages::agent::{BackendState, BackendStateMessage},
+    nats::TypedMessage,
+    types::BackendId,
+    watch::WatchBackends,
+};
+use plane_dev::{resources::nats::Nats, timeout::timeout};
+
+#[integration_test]
+async fn watch_resumes_after_token() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let backend = BackendId::new("backend1".into());
+    let other_backend = BackendId::new("backend2".into());
+
+    for (backend, state) in [
+        (&backend, BackendState::Loading),
+        (&other_backend, BackendState::Loading),
+        (&backend, BackendState::Starting),
+        (&backend, BackendState::Ready),
+    ] {
+        nats_conn
+            .publish_jetstream(&BackendStateMessage::new(state, backend.clone()))
+            .await
+            .unwrap();
+    }
+
+    let mut watch = WatchBackends::new()
+        .with_backend(backend.clone())
+        .watch(&nats_conn)
+        .await
+        .unwrap();
+    let loading = timeout(1_000, "First state change.", watch.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(BackendState::Loading, loading.message.state);
+    let starting = timeout(1_000, "Second state change.", watch.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(BackendState::Starting, starting.message.state);
+    assert!(starting.resume_token > loading.resume_token);
+    drop(watch);
+
+    // A watch resumed from a token starts with the change after it.
+    let mut watch = WatchBackends::new()
+        .with_backend(backend.clone())
+        .with_resume_token(Some(starting.resume_token))
+        .watch(&nats_conn)
+        .await
+        .unwrap();
+    let ready = timeout(1_000, "Resumed state change.", watch.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(BackendState::Ready, ready.message.state);
+    assert_eq!(backend, ready.message.backend);
+}
+
+#[integration_test]
+async fn purged_backend_states_are_gone() {
+    let nats = Nats::new().await.unwrap();
+    let nats_conn = nats.connection().await.unwrap();
+    let backend = BackendId::new("backend1".into());
+    let other_backend = BackendId::new("backend2".into());
+
+    for (backend, state) in [
+        (&backend, BackendState::Loading),
+        (&backend, BackendState::Swept),
+        (&other_backend, BackendState::Ready),
+    ] {
+        nats_conn
+            .publish_jetstream(&BackendStateMessage::new(state, backend.clone()))
+            .await
+            .unwrap();
+    }
+
+    let last_states = nats_conn
+        .get_all_with_info(
+            &BackendStateMessage::wildcard_subject(),
+            DeliverPolicy::LastPerSubject,
+        )
+        .await
+        .unwrap();
+    let (swept, _) = last_states
+        .into_iter()
+        .find(|(message, _)| message.backend == backend)
+        .unwrap();
+    assert_eq!(BackendState::Swept, swept.state);
+
+    let purged = nats_conn
+        .purge_jetstream_subject::<BackendStateMessage>(&swept.subject())
+        .await
+        .unwrap();
+    assert_eq!(2, purged);
+
+    let remaining = nats_conn
+        .get_all(
+            &BackendStateMessage::wildcard_subject(),
+            DeliverPolicy::LastPerSubject,
+        )
+        .await
+        .unwrap();
+    assert_eq!(1, remaining.len());
+    assert_eq!(other_backend, remaining[0].backend);
+}
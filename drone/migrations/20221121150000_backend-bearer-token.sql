@@ -0,0 +1,2 @@
+-- Bearer token required to access a backend through the proxy, if any.
+alter table "backend" add column "bearer_token" text;
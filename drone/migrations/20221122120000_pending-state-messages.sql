@@ -0,0 +1,8 @@
+-- Backend state messages which could not be published to JetStream, in the
+-- order they were generated. They are replayed once JetStream is reachable.
+create table "pending_state_message" (
+    "id" integer primary key autoincrement,
+
+    -- The BackendStateMessage, as JSON.
+    "message" text not null
+);
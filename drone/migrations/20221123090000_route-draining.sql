@@ -0,0 +1,3 @@
+-- Whether the proxy has stopped routing new requests to this route, because
+-- its backend is draining before termination.
+alter table "route" add column "draining" boolean not null default false;
@@ -0,0 +1,3 @@
+-- Unix time at which the backend was spawned, used to enforce its maximum
+-- lifetime. Null for backends spawned before this column was added.
+alter table "backend" add column "created_at" integer;
@@ -0,0 +1,14 @@
+-- DNS records which could not be published to JetStream. Only the latest
+-- value of each record is kept. They are replayed once JetStream is reachable.
+create table "pending_dns_record" (
+    -- Subject of the SetDnsRecord message, which identifies its cluster and type.
+    "subject" text not null,
+
+    -- Name of the record.
+    "name" text not null,
+
+    -- The SetDnsRecord, as JSON.
+    "message" text not null,
+
+    primary key ("subject", "name")
+);
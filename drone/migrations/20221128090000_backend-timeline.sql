@@ -0,0 +1,2 @@
+-- The backend's SpawnTimeline, as JSON. Null until the drone records one.
+alter table "backend" add column "timeline" text;
@@ -0,0 +1,3 @@
+-- Seconds by which the backend has extended its maximum lifetime through the
+-- drone's metadata endpoint.
+alter table "backend" add column "lifetime_extension_secs" integer not null default 0;
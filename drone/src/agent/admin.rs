@@ -0,0 +1,219 @@
+//! A localhost-only HTTP API for inspecting the agent, intended for host-level
+//! tooling and health checks which do not have access to NATS. If a token is
+//! configured, every request but health checks must carry it as
+//! `Authorization: Bearer <token>`.
+//!
+//! - `GET /healthz`: 200 if the agent can reach its database, with the
+//!   version of the database schema.
+//! - `GET /backends`: backends known to this drone.
+//! - `GET /backends/{id}`: a single backend.
+//! - `POST /backends/{id}/terminate`: terminate a running backend.
+
+use super::{engine::Engine, executor::Executor};
+use crate::database::{Backend, DroneDatabase};
+use anyhow::anyhow;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use plane_core::{
+    messages::agent::TerminationRequest,
+    types::{BackendId, ClusterName},
+    NeverResult,
+};
+use serde_json::{json, Value};
+use std::{
+    convert::Infallible,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+struct AdminState<E: Engine> {
+    executor: Executor<E>,
+    db: DroneDatabase,
+    cluster: ClusterName,
+    token: Option<String>,
+}
+
+/// Summary of a backend. The environment and registry credentials of the
+/// backend are deliberately left out.
+fn backend_json(backend: &Backend) -> Value {
+    json!({
+        "backend_id": backend.backend_id,
+        "state": backend.state,
+        "image": backend.spec.executable.image,
+        "metadata": backend.spec.metadata,
+        "resource_limits": backend.spec.executable.resource_limits,
+        "max_idle_secs": backend.spec.max_idle_secs.as_secs(),
+        "max_lifetime_secs": backend.spec.max_lifetime_secs.map(|d| d.as_secs()),
+    })
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("Response with valid status and header should never fail to build.")
+}
+
+fn error_response(status: StatusCode, error: &str) -> Response<Body> {
+    json_response(status, &json!({ "error": error }))
+}
+
+async fn find_backend(db: &DroneDatabase, backend_id: &str) -> anyhow::Result<Option<Backend>> {
+    Ok(db
+        .get_backends()
+        .await?
+        .into_iter()
+        .find(|backend| backend.backend_id.id() == backend_id))
+}
+
+async fn health(db: &DroneDatabase) -> anyhow::Result<Value> {
+    Ok(json!({
+        "status": "ok",
+        "running_backends": db.running_backends().await?,
+        "schema_version": db.schema_version().await?,
+    }))
+}
+
+/// Returns true if the request may be served: it is a health check, no token
+/// is configured, or it carries the token.
+fn is_authorized(request: &Request<Body>, token: Option<&str>) -> bool {
+    let token = match token {
+        Some(token) => token,
+        None => return true,
+    };
+    if request.method() == Method::GET && request.uri().path().trim_matches('/') == "healthz" {
+        return true;
+    }
+
+    request
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+        == Some(token)
+}
+
+async fn handle<E: Engine>(
+    state: &AdminState<E>,
+    method: &Method,
+    path: &str,
+) -> anyhow::Result<Response<Body>> {
+    let segments: Vec<&str> = path
+        .trim_matches('/')
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    match (method, segments.as_slice()) {
+        (&Method::GET, ["healthz"]) => match health(&state.db).await {
+            Ok(health) => Ok(json_response(StatusCode::OK, &health)),
+            Err(error) => {
+                tracing::warn!(?error, "Admin health check failed.");
+                Ok(error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Database is unavailable.",
+                ))
+            }
+        },
+        (&Method::GET, ["backends"]) => {
+            let backends: Vec<Value> = state
+                .db
+                .get_backends()
+                .await?
+                .iter()
+                .map(backend_json)
+                .collect();
+            Ok(json_response(StatusCode::OK, &Value::Array(backends)))
+        }
+        (&Method::GET, ["backends", backend_id]) => {
+            match find_backend(&state.db, backend_id).await? {
+                Some(backend) => Ok(json_response(StatusCode::OK, &backend_json(&backend))),
+                None => Ok(error_response(StatusCode::NOT_FOUND, "Unknown backend.")),
+            }
+        }
+        (&Method::POST, ["backends", backend_id, "terminate"]) => {
+            if find_backend(&state.db, backend_id).await?.is_none() {
+                return Ok(error_response(StatusCode::NOT_FOUND, "Unknown backend."));
+            }
+
+            let backend_id = BackendId::new((*backend_id).to_string());
+            tracing::info!(%backend_id, "Terminating backend through admin API.");
+            match state
+                .executor
+                .kill_backend(&TerminationRequest {
+                    cluster_id: state.cluster.clone(),
+                    backend_id,
+                    drain: false,
+                    grace_period_secs: Duration::ZERO,
+                })
+                .await
+            {
+                Ok(()) => Ok(json_response(
+                    StatusCode::OK,
+                    &json!({ "terminated": true }),
+                )),
+                // The backend is known but no longer running.
+                Err(error) => Ok(error_response(StatusCode::CONFLICT, &error.to_string())),
+            }
+        }
+        (_, ["healthz"] | ["backends", ..]) => Ok(error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed.",
+        )),
+        _ => Ok(error_response(StatusCode::NOT_FOUND, "Not found.")),
+    }
+}
+
+/// Serve the admin API on the given port of the loopback interface.
+pub async fn serve_admin<E: Engine>(
+    port: u16,
+    token: Option<String>,
+    executor: Executor<E>,
+    db: DroneDatabase,
+    cluster: ClusterName,
+) -> NeverResult {
+    let state = Arc::new(AdminState {
+        executor,
+        db,
+        cluster,
+        token,
+    });
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let state = state.clone();
+                let authorized = is_authorized(&req, state.token.as_deref());
+                let method = req.method().clone();
+                let path = req.uri().path().to_string();
+                async move {
+                    if !authorized {
+                        return Ok::<_, Infallible>(error_response(
+                            StatusCode::UNAUTHORIZED,
+                            "Missing or invalid admin token.",
+                        ));
+                    }
+                    let response = match handle(&state, &method, &path).await {
+                        Ok(response) => response,
+                        Err(error) => {
+                            tracing::error!(?error, %path, "Error in admin API.");
+                            error_response(StatusCode::INTERNAL_SERVER_ERROR, &error.to_string())
+                        }
+                    };
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+
+    tracing::info!(%addr, "Serving admin API.");
+    Server::try_bind(&addr)?.serve(make_service).await?;
+
+    Err(anyhow!("Admin server exited."))
+}
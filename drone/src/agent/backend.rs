@@ -1,12 +1,22 @@
-use crate::agent::engine::Engine;
+use crate::{
+    agent::{engine::Engine, log_archive::LogArchiver},
+    database::DroneDatabase,
+};
 use anyhow::Result;
 use plane_core::{
     logging::LogError,
-    messages::dns::{DnsRecordType, SetDnsRecord},
+    messages::{
+        agent::BackendStatsMessage,
+        dns::{DnsRecordType, SetDnsRecord},
+    },
     nats::TypedNats,
     types::{BackendId, ClusterName},
 };
-use std::{net::IpAddr, time::Duration};
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::{task::JoinHandle, time::sleep};
 use tokio_stream::StreamExt;
 
@@ -23,47 +33,79 @@ pub struct BackendMonitor {
     _log_loop: AbortOnDrop<()>,
     _stats_loop: AbortOnDrop<()>,
     _dns_loop: AbortOnDrop<Result<(), anyhow::Error>>,
+
+    /// The stats most recently published for the backend.
+    latest_stats: Arc<Mutex<Option<BackendStatsMessage>>>,
 }
 
 impl BackendMonitor {
     pub fn new<E: Engine>(
         backend_id: &BackendId,
         cluster: &ClusterName,
-        ip: IpAddr,
+        ips: Vec<IpAddr>,
         engine: &E,
         nc: &TypedNats,
+        db: &DroneDatabase,
+        log_archiver: Option<&LogArchiver>,
     ) -> Self {
-        let log_loop = Self::log_loop(backend_id, engine, nc);
-        let stats_loop = Self::stats_loop(backend_id, engine, nc);
-        let dns_loop = Self::dns_loop(backend_id, ip, nc, cluster);
+        let log_loop = Self::log_loop(backend_id, engine, nc, log_archiver);
+        let latest_stats = Arc::default();
+        let stats_loop = Self::stats_loop(backend_id, engine, nc, &latest_stats);
+        let dns_loop = Self::dns_loop(backend_id, ips, nc, cluster, db);
 
         BackendMonitor {
             _log_loop: AbortOnDrop(log_loop),
             _stats_loop: AbortOnDrop(stats_loop),
             _dns_loop: AbortOnDrop(dns_loop),
+            latest_stats,
         }
     }
 
+    pub fn latest_stats(&self) -> Option<BackendStatsMessage> {
+        self.latest_stats
+            .lock()
+            .expect("Stats lock was poisoned.")
+            .clone()
+    }
+
     fn dns_loop(
         backend_id: &BackendId,
-        ip: IpAddr,
+        ips: Vec<IpAddr>,
         nc: &TypedNats,
         cluster: &ClusterName,
+        db: &DroneDatabase,
     ) -> JoinHandle<Result<(), anyhow::Error>> {
         let backend_id = backend_id.clone();
         let nc = nc.clone();
         let cluster = cluster.clone();
+        let db = db.clone();
 
         tokio::spawn(async move {
             loop {
-                nc.publish_jetstream(&SetDnsRecord {
-                    cluster: cluster.clone(),
-                    kind: DnsRecordType::A,
-                    name: backend_id.to_string(),
-                    value: ip.to_string(),
-                })
-                .await
-                .log_error("Error publishing DNS record.");
+                for ip in &ips {
+                    let record = SetDnsRecord {
+                        cluster: cluster.clone(),
+                        kind: match ip {
+                            IpAddr::V4(_) => DnsRecordType::A,
+                            IpAddr::V6(_) => DnsRecordType::AAAA,
+                        },
+                        name: backend_id.to_string(),
+                        value: ip.to_string(),
+                    };
+
+                    // If JetStream is unavailable, the executor replays the record
+                    // once it returns, rather than waiting for the next period.
+                    if let Err(error) = nc.publish_jetstream(&record).await {
+                        tracing::warn!(
+                            ?error,
+                            %backend_id,
+                            "Could not publish DNS record, buffering it."
+                        );
+                        db.insert_pending_dns_record(&record)
+                            .await
+                            .log_error("Error buffering DNS record.");
+                    }
+                }
 
                 sleep(Duration::from_secs(SetDnsRecord::send_period())).await;
             }
@@ -74,8 +116,12 @@ impl BackendMonitor {
         backend_id: &BackendId,
         engine: &E,
         nc: &TypedNats,
+        log_archiver: Option<&LogArchiver>,
     ) -> JoinHandle<()> {
         let mut stream = engine.log_stream(backend_id);
+        // Dropped along with the loop, which lets the archiver upload the
+        // remaining logs.
+        let archive = log_archiver.map(|archiver| archiver.archive(backend_id));
         let nc = nc.clone();
         let backend_id = backend_id.clone();
 
@@ -84,6 +130,12 @@ impl BackendMonitor {
 
             while let Some(v) = stream.next().await {
                 nc.publish(&v).await.log_error("Error publishing log message.");
+
+                if let Some(archive) = &archive {
+                    // Only fails if the archiver has stopped, which it does
+                    // not do while this sender exists.
+                    let _ = archive.send(v);
+                }
             }
 
             tracing::info!(%backend_id, "Log loop terminated.");
@@ -94,16 +146,19 @@ impl BackendMonitor {
         backend_id: &BackendId,
         engine: &E,
         nc: &TypedNats,
+        latest_stats: &Arc<Mutex<Option<BackendStatsMessage>>>,
     ) -> JoinHandle<()> {
         let mut stream = Box::pin(engine.stats_stream(backend_id));
         let nc = nc.clone();
         let backend_id = backend_id.clone();
+        let latest_stats = latest_stats.clone();
 
         tokio::spawn(async move {
             tracing::info!(%backend_id, "Stats recording loop started.");
 
             while let Some(stats) = stream.next().await {
                 nc.publish(&stats).await.log_error("Error publishing stats message.");
+                *latest_stats.lock().expect("Stats lock was poisoned.") = Some(stats);
             }
 
             tracing::info!(%backend_id, "Stats loop terminated.");
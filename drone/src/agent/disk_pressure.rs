@@ -0,0 +1,90 @@
+//! Protection against the disk holding the engine's data directory filling
+//! up, which would otherwise fail image pulls and writes of every backend on
+//! the host. While free space is below the configured minimum, the drone
+//! reports itself as not ready and refuses new backends.
+
+use plane_core::error::PlaneError;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+pub struct DiskPressure {
+    min_free_bytes: u64,
+
+    /// Free space most recently measured.
+    free_bytes: AtomicU64,
+
+    under_pressure: AtomicBool,
+}
+
+impl DiskPressure {
+    pub fn new(min_free_bytes: u64) -> Self {
+        DiskPressure {
+            min_free_bytes,
+            free_bytes: AtomicU64::new(u64::MAX),
+            under_pressure: AtomicBool::new(false),
+        }
+    }
+
+    /// Record a measurement of free space. Returns true if the drone has
+    /// just come under pressure, i.e. is under pressure now but was not
+    /// before.
+    pub fn update(&self, free_bytes: u64) -> bool {
+        self.free_bytes.store(free_bytes, Ordering::Relaxed);
+        let under_pressure = free_bytes < self.min_free_bytes;
+        let was_under_pressure = self.under_pressure.swap(under_pressure, Ordering::Relaxed);
+
+        if under_pressure && !was_under_pressure {
+            tracing::warn!(
+                free_bytes,
+                min_free_bytes = self.min_free_bytes,
+                "Disk space is low; refusing new backends."
+            );
+        } else if was_under_pressure && !under_pressure {
+            tracing::info!(free_bytes, "Disk space has recovered; accepting backends.");
+        }
+
+        under_pressure && !was_under_pressure
+    }
+
+    pub fn is_under_pressure(&self) -> bool {
+        self.under_pressure.load(Ordering::Relaxed)
+    }
+
+    /// Fail with [PlaneError::InsufficientDisk] if the drone is under
+    /// pressure.
+    pub fn check(&self) -> Result<(), PlaneError> {
+        if self.is_under_pressure() {
+            Err(PlaneError::InsufficientDisk {
+                free_bytes: self.free_bytes.load(Ordering::Relaxed),
+                min_free_bytes: self.min_free_bytes,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_pressure() {
+        let disk_pressure = DiskPressure::new(1000);
+        assert!(disk_pressure.check().is_ok());
+
+        assert!(!disk_pressure.update(5000));
+        assert!(disk_pressure.update(500));
+        assert!(!disk_pressure.update(400));
+        assert_eq!(
+            Err(PlaneError::InsufficientDisk {
+                free_bytes: 400,
+                min_free_bytes: 1000,
+            }),
+            disk_pressure.check()
+        );
+
+        assert!(!disk_pressure.update(1000));
+        assert!(!disk_pressure.is_under_pressure());
+        assert!(disk_pressure.check().is_ok());
+    }
+}
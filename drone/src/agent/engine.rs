@@ -1,11 +1,15 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use futures::Stream;
 use plane_core::{
-    messages::agent::{BackendStatsMessage, DroneLogMessage, SpawnRequest},
+    messages::agent::{
+        AttachInput, AttachOutputChunk, BackendStatsMessage, DroneLogMessage, ExecOutputChunk,
+        SpawnRequest, TerminalSize,
+    },
+    sealing::SealingKey,
     types::BackendId,
 };
-use std::{net::SocketAddr, pin::Pin};
+use std::{net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum EngineBackendStatus {
@@ -19,12 +23,60 @@ pub enum EngineBackendStatus {
     Exited,
 
     /// The backend exited on its own with a failure state.
-    Failed,
+    Failed { exit_code: i64 },
+
+    /// The backend was killed for exceeding its memory limit.
+    OutOfMemory,
 
     /// The backend was terminated by external forces.
     Terminated,
 }
 
+/// A snapshot of the resources a backend is using. Each value is omitted if
+/// the engine is unable to measure it.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct EngineResourceUsage {
+    /// Memory in use, not counting the page cache, in bytes.
+    pub memory_bytes: Option<u64>,
+
+    /// CPU time used since the backend started.
+    pub cpu_time: Option<Duration>,
+
+    /// Disk space used by the backend's writable filesystem, in bytes.
+    pub disk_bytes: Option<u64>,
+}
+
+impl EngineResourceUsage {
+    /// The combined usage of several backends. Each value is the sum of the
+    /// backends which reported it, or omitted if none did.
+    pub fn total(usages: impl IntoIterator<Item = EngineResourceUsage>) -> Self {
+        fn add<T: std::ops::Add<Output = T>>(total: Option<T>, value: Option<T>) -> Option<T> {
+            match (total, value) {
+                (Some(total), Some(value)) => Some(total + value),
+                (total, value) => total.or(value),
+            }
+        }
+
+        usages
+            .into_iter()
+            .fold(EngineResourceUsage::default(), |total, usage| {
+                EngineResourceUsage {
+                    memory_bytes: add(total.memory_bytes, usage.memory_bytes),
+                    cpu_time: add(total.cpu_time, usage.cpu_time),
+                    disk_bytes: add(total.disk_bytes, usage.disk_bytes),
+                }
+            })
+    }
+}
+
+/// Progress of pulling an image, summed over the layers the engine has
+/// started pulling.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct ImagePullProgress {
+    pub pulled_bytes: u64,
+    pub total_bytes: u64,
+}
+
 #[async_trait]
 pub trait Engine: Send + Sync + 'static {
     /// Returns an async stream which yields a backend ID when the status of that
@@ -35,14 +87,89 @@ pub trait Engine: Send + Sync + 'static {
     /// Load resources for a backend.
     async fn load(&self, spawn_request: &SpawnRequest) -> Result<()>;
 
+    /// Progress of a pull of the given backend's image which is under way,
+    /// if the engine reports it.
+    fn image_pull_progress(&self, _backend: &BackendId) -> Option<ImagePullProgress> {
+        None
+    }
+
     /// Return true if the backend is running according to the execution engine.
     /// This is considered a necessary but not sufficient condition for the
     /// backend to be considered "ready" by the agent.
     async fn backend_status(&self, backend: &BackendId) -> Result<EngineBackendStatus>;
 
+    /// Checkpoint the memory and process state of a running backend,
+    /// stopping it, and return the name of the checkpoint. The checkpoint
+    /// leaves this drone sealed to `recipient`, the public key of the drone
+    /// which will restore it (see [plane_core::sealing]). Experimental.
+    async fn checkpoint(&self, backend: &BackendId, _recipient: &str) -> Result<String> {
+        Err(anyhow!(
+            "Checkpointing backend {} is not supported by this engine.",
+            backend
+        ))
+    }
+
+    /// Load resources for a backend and restore it from a checkpoint made by
+    /// [Engine::checkpoint], possibly on another drone, in which case it is
+    /// opened with `key`. Experimental.
+    async fn restore(
+        &self,
+        spawn_request: &SpawnRequest,
+        checkpoint: &str,
+        _key: Option<Arc<SealingKey>>,
+    ) -> Result<()> {
+        Err(anyhow!(
+            "Restoring backend {} from checkpoint {} is not supported by this engine.",
+            spawn_request.backend_id,
+            checkpoint
+        ))
+    }
+
+    /// Remove this drone's copy of a checkpoint, once another drone has taken
+    /// over restoring it.
+    async fn discard_checkpoint(&self, _checkpoint: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// The resources a backend is currently using.
+    async fn usage(&self, _backend: &BackendId) -> Result<EngineResourceUsage> {
+        Ok(EngineResourceUsage::default())
+    }
+
     /// Terminate a backend.
     async fn stop(&self, backend: &BackendId) -> Result<()>;
 
+    /// Return every backend which has resources (e.g. a container) in the
+    /// engine, whether or not it is running.
+    async fn list_backends(&self) -> Result<Vec<BackendId>>;
+
+    /// Fetch an image ahead of any backend requesting it.
+    async fn seed_image(&self, image: &str) -> Result<()>;
+
+    /// Names of the images available locally.
+    async fn image_names(&self) -> Result<Vec<String>>;
+
+    /// Digests of the images available locally, as `repository@sha256:...`
+    /// references, if the engine tracks them.
+    async fn image_digests(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// The directory in which the engine stores images and backend filesystems.
+    async fn data_dir(&self) -> Result<String>;
+
+    /// Disk space reclaimed by removing unused images since the engine was
+    /// created, if the engine garbage-collects images.
+    fn reclaimed_image_bytes(&self) -> Option<u64> {
+        None
+    }
+
+    /// Remove images which no backend has used recently, regardless of how
+    /// full the disk is, if the engine garbage-collects images.
+    async fn collect_unused_images(&self) -> Result<()> {
+        Ok(())
+    }
+
     fn log_stream(
         &self,
         backend: &BackendId,
@@ -52,4 +179,58 @@ pub trait Engine: Send + Sync + 'static {
         &self,
         backend: &BackendId,
     ) -> Pin<Box<dyn Stream<Item = BackendStatsMessage> + Send>>;
+
+    /// Run a command inside a running backend, returning a stream of its
+    /// output which ends with an [ExecOutputChunk::Exit].
+    async fn exec(
+        &self,
+        backend: &BackendId,
+        command: &[String],
+    ) -> Result<Pin<Box<dyn Stream<Item = ExecOutputChunk> + Send>>>;
+
+    /// Run a command inside a running backend with a TTY of the given size,
+    /// feeding it `input` until the input ends or asks to detach. Returns a
+    /// stream of the terminal's output which ends with an
+    /// [AttachOutputChunk::Exit].
+    async fn attach(
+        &self,
+        backend: &BackendId,
+        command: &[String],
+        size: TerminalSize,
+        input: Pin<Box<dyn Stream<Item = AttachInput> + Send>>,
+    ) -> Result<Pin<Box<dyn Stream<Item = AttachOutputChunk> + Send>>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_total_usage() {
+        assert_eq!(
+            EngineResourceUsage::default(),
+            EngineResourceUsage::total(Vec::new())
+        );
+
+        let total = EngineResourceUsage::total(vec![
+            EngineResourceUsage {
+                memory_bytes: Some(100),
+                cpu_time: Some(Duration::from_secs(2)),
+                disk_bytes: None,
+            },
+            EngineResourceUsage {
+                memory_bytes: Some(50),
+                cpu_time: None,
+                disk_bytes: None,
+            },
+        ]);
+        assert_eq!(
+            EngineResourceUsage {
+                memory_bytes: Some(150),
+                cpu_time: Some(Duration::from_secs(2)),
+                disk_bytes: None,
+            },
+            total
+        );
+    }
 }
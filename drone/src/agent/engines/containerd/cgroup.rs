@@ -0,0 +1,161 @@
+//! Reading backend resource usage from cgroup v2 interface files.
+
+use std::{fs::read_to_string, path::PathBuf, time::Instant};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Find the value of a key in a flat-keyed cgroup file such as `cpu.stat`.
+fn parse_keyed(contents: &str, key: &str) -> Option<u64> {
+    contents.lines().find_map(|line| {
+        let (line_key, value) = line.split_once(' ')?;
+        if line_key == key {
+            value.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Parse `memory.max`, which is `max` if the cgroup has no memory limit.
+fn parse_memory_max(contents: &str) -> Option<u64> {
+    match contents.trim() {
+        "max" => None,
+        value => value.parse().ok(),
+    }
+}
+
+/// Sum a field of `io.stat` over devices. Each line describes one device,
+/// e.g. `8:0 rbytes=4096 wbytes=8192 rios=1 wios=2 dbytes=0 dios=0`.
+fn parse_io_stat(contents: &str, field: &str) -> u64 {
+    contents
+        .lines()
+        .flat_map(|line| line.split_whitespace().skip(1))
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            if key == field {
+                value.parse::<u64>().ok()
+            } else {
+                None
+            }
+        })
+        .sum()
+}
+
+/// Total memory of the host, used as the limit of unlimited cgroups.
+fn host_memory_bytes() -> Option<u64> {
+    let meminfo = read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// A point-in-time reading of a cgroup's resource usage.
+pub struct CgroupSample {
+    time: Instant,
+    cpu_usage_usec: u64,
+    memory_used_bytes: u64,
+    memory_limit_bytes: u64,
+
+    /// Bytes read from and written to block devices, if the cgroup's io
+    /// controller is enabled.
+    io_read_bytes: Option<u64>,
+    io_write_bytes: Option<u64>,
+}
+
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// The cgroup with the given path, relative to the cgroup v2 mount.
+    pub fn new(cgroups_path: &str) -> Self {
+        Cgroup {
+            path: PathBuf::from(CGROUP_ROOT).join(cgroups_path.trim_start_matches('/')),
+        }
+    }
+
+    fn read(&self, file: &str) -> Option<String> {
+        read_to_string(self.path.join(file)).ok()
+    }
+
+    /// Whether any process in the cgroup has been killed for exceeding its
+    /// memory limit. Returns `None` if the cgroup no longer exists.
+    pub fn oom_killed(&self) -> Option<bool> {
+        let events = self.read("memory.events")?;
+        Some(parse_keyed(&events, "oom_kill").unwrap_or_default() > 0)
+    }
+
+    /// Read the cgroup's current usage. Returns `None` if the cgroup no longer
+    /// exists.
+    pub fn sample(&self) -> Option<CgroupSample> {
+        let cpu_usage_usec = parse_keyed(&self.read("cpu.stat")?, "usage_usec")?;
+        let memory_current: u64 = self.read("memory.current")?.trim().parse().ok()?;
+        // Page cache can be reclaimed, so it is not counted as used.
+        let inactive_file =
+            parse_keyed(&self.read("memory.stat")?, "inactive_file").unwrap_or_default();
+        let memory_limit_bytes = parse_memory_max(&self.read("memory.max")?)
+            .or_else(host_memory_bytes)
+            .unwrap_or(u64::MAX);
+
+        let io_stat = self.read("io.stat");
+
+        Some(CgroupSample {
+            time: Instant::now(),
+            cpu_usage_usec,
+            memory_used_bytes: memory_current.saturating_sub(inactive_file),
+            memory_limit_bytes,
+            io_read_bytes: io_stat.as_deref().map(|io| parse_io_stat(io, "rbytes")),
+            io_write_bytes: io_stat.as_deref().map(|io| parse_io_stat(io, "wbytes")),
+        })
+    }
+}
+
+impl CgroupSample {
+    /// CPU used since `previous`, as a percentage of all of the host's CPUs,
+    /// and memory used, as a percentage of the cgroup's limit.
+    pub fn usage_since(&self, previous: &CgroupSample) -> (f64, f64) {
+        let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+        let elapsed_usec = self.time.duration_since(previous.time).as_micros() as f64;
+        let cpu_delta_usec = self.cpu_usage_usec.saturating_sub(previous.cpu_usage_usec) as f64;
+        let cpu_use_percent = cpu_delta_usec / (elapsed_usec * cpus as f64) * 100.0;
+
+        let mem_use_percent =
+            (self.memory_used_bytes as f64) / (self.memory_limit_bytes as f64) * 100.0;
+
+        (cpu_use_percent, mem_use_percent)
+    }
+
+    /// Bytes read from and written to block devices since `previous`.
+    pub fn io_since(&self, previous: &CgroupSample) -> (Option<u64>, Option<u64>) {
+        let delta =
+            |current: Option<u64>, previous: Option<u64>| Some(current?.saturating_sub(previous?));
+        (
+            delta(self.io_read_bytes, previous.io_read_bytes),
+            delta(self.io_write_bytes, previous.io_write_bytes),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cgroup_files() {
+        let cpu_stat = "usage_usec 1520000\nuser_usec 1000000\nsystem_usec 520000\n";
+        assert_eq!(Some(1_520_000), parse_keyed(cpu_stat, "usage_usec"));
+        assert_eq!(None, parse_keyed(cpu_stat, "usage"));
+
+        let memory_events = "low 0\nhigh 0\nmax 3\noom 1\noom_kill 1\n";
+        assert_eq!(Some(1), parse_keyed(memory_events, "oom_kill"));
+
+        assert_eq!(None, parse_memory_max("max\n"));
+        assert_eq!(Some(268_435_456), parse_memory_max("268435456\n"));
+
+        let io_stat = "8:0 rbytes=4096 wbytes=8192 rios=1 wios=2 dbytes=0 dios=0\n\
+                       8:16 rbytes=1024 wbytes=0 rios=1 wios=0 dbytes=0 dios=0\n";
+        assert_eq!(5120, parse_io_stat(io_stat, "rbytes"));
+        assert_eq!(8192, parse_io_stat(io_stat, "wbytes"));
+        assert_eq!(0, parse_io_stat("", "rbytes"));
+    }
+}
@@ -0,0 +1,156 @@
+//! Attaching the network namespace of each backend to a network with a CNI
+//! plugin, e.g. the reference `bridge` plugin, so that backends are isolated
+//! from the host's network and from each other's ports.
+//! See https://github.com/containernetworking/cni/blob/main/SPEC.md
+
+use crate::config::CniConfig;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{net::IpAddr, path::PathBuf, process::Stdio};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// Name of the interface created in each backend's network namespace.
+const INTERFACE: &str = "eth0";
+
+#[derive(Deserialize)]
+struct CniIp {
+    /// Address in CIDR notation, e.g. `10.89.0.5/16`.
+    address: String,
+}
+
+#[derive(Deserialize)]
+struct CniResult {
+    #[serde(default)]
+    ips: Vec<CniIp>,
+}
+
+/// The address a backend was given, from the result of an `ADD` command.
+fn parse_result_ip(result: &[u8]) -> Result<IpAddr> {
+    let result: CniResult =
+        serde_json::from_slice(result).context("Error parsing CNI plugin result.")?;
+    let address = result
+        .ips
+        .first()
+        .ok_or_else(|| anyhow!("CNI plugin did not assign an address."))?;
+    let ip = address
+        .address
+        .split_once('/')
+        .map_or(address.address.as_str(), |(ip, _)| ip);
+
+    ip.parse().with_context(|| {
+        format!(
+            "CNI plugin assigned an invalid address {}.",
+            address.address
+        )
+    })
+}
+
+#[derive(Clone)]
+pub struct Cni {
+    bin_dir: PathBuf,
+
+    /// Network configuration passed to the plugin on each command.
+    config: Vec<u8>,
+
+    /// Name of the plugin's binary in `bin_dir`.
+    plugin: String,
+}
+
+impl Cni {
+    pub fn load(config: &CniConfig) -> Result<Self> {
+        let contents = std::fs::read(&config.conf_path)
+            .with_context(|| format!("Error reading {}.", config.conf_path.display()))?;
+        let network: Value = serde_json::from_slice(&contents)
+            .with_context(|| format!("Error parsing {}.", config.conf_path.display()))?;
+        let plugin = network
+            .get("type")
+            .and_then(Value::as_str)
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} does not name a plugin in its type field.",
+                    config.conf_path.display()
+                )
+            })?
+            .to_string();
+
+        Ok(Cni {
+            bin_dir: config.bin_dir.clone(),
+            config: contents,
+            plugin,
+        })
+    }
+
+    async fn invoke(&self, command: &str, container_id: &str, netns: &str) -> Result<Vec<u8>> {
+        let mut child = Command::new(self.bin_dir.join(&self.plugin))
+            .env_clear()
+            .env("CNI_COMMAND", command)
+            .env("CNI_CONTAINERID", container_id)
+            .env("CNI_NETNS", netns)
+            .env("CNI_IFNAME", INTERFACE)
+            .env("CNI_PATH", &self.bin_dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Error running CNI plugin {}.", self.plugin))?;
+
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("CNI plugin has no stdin."))?;
+        stdin.write_all(&self.config).await?;
+        drop(stdin);
+
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            // Plugins report errors as JSON on stdout.
+            return Err(anyhow!(
+                "CNI {} failed ({}): {}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stdout).trim()
+            ));
+        }
+
+        Ok(output.stdout)
+    }
+
+    /// Attach a network namespace to the network, returning the address it
+    /// was given.
+    pub async fn add(&self, container_id: &str, netns: &str) -> Result<IpAddr> {
+        let result = self.invoke("ADD", container_id, netns).await?;
+        parse_result_ip(&result)
+    }
+
+    /// Release a container's address and other resources on the network.
+    /// The namespace is gone by the time this is called, which plugins
+    /// accept on `DEL`.
+    pub async fn del(&self, container_id: &str) -> Result<()> {
+        self.invoke("DEL", container_id, "").await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse_result_ip() {
+        let result = br#"{
+            "cniVersion": "1.0.0",
+            "interfaces": [{"name": "eth0", "sandbox": "/proc/42/ns/net"}],
+            "ips": [{"address": "10.89.0.5/16", "gateway": "10.89.0.1", "interface": 0}]
+        }"#;
+        assert_eq!(
+            IpAddr::V4(Ipv4Addr::new(10, 89, 0, 5)),
+            parse_result_ip(result).unwrap()
+        );
+
+        assert!(parse_result_ip(br#"{"cniVersion": "1.0.0", "ips": []}"#).is_err());
+        assert!(parse_result_ip(b"not json").is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//! Resolution of image references and image configuration from containerd's
+//! content store.
+
+use anyhow::{anyhow, Result};
+use containerd_client::{
+    services::v1::{content_client::ContentClient, ReadContentRequest},
+    types::Descriptor,
+    with_namespace,
+};
+use openssl::sha::sha256;
+use serde::Deserialize;
+use std::fmt::Write;
+use tonic::{transport::Channel, Request};
+
+const DEFAULT_REGISTRY: &str = "docker.io";
+const DEFAULT_TAG: &str = "latest";
+
+const INDEX_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+/// Expand an image reference the way Docker does, e.g. `ubuntu` becomes
+/// `docker.io/library/ubuntu:latest`. containerd only accepts fully
+/// qualified references.
+pub fn normalize_reference(image: &str) -> String {
+    let (domain, remainder) = match image.split_once('/') {
+        Some((domain, remainder))
+            if domain.contains('.') || domain.contains(':') || domain == "localhost" =>
+        {
+            (domain, remainder.to_string())
+        }
+        Some(_) => (DEFAULT_REGISTRY, image.to_string()),
+        None => (DEFAULT_REGISTRY, format!("library/{}", image)),
+    };
+
+    let last_component = remainder.rsplit('/').next().unwrap_or_default();
+    if remainder.contains('@') || last_component.contains(':') {
+        format!("{}/{}", domain, remainder)
+    } else {
+        format!("{}/{}:{}", domain, remainder, DEFAULT_TAG)
+    }
+}
+
+/// The platform architecture name of the drone, as used in image indexes.
+pub fn platform_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        arch => arch,
+    }
+}
+
+/// Compute the chain ID of a list of layer diff IDs, which is the key under
+/// which containerd stores the unpacked snapshot of those layers.
+/// See https://github.com/opencontainers/image-spec/blob/main/config.md#layer-chainid
+pub fn chain_id(diff_ids: &[String]) -> Option<String> {
+    let (first, rest) = diff_ids.split_first()?;
+
+    Some(rest.iter().fold(first.clone(), |parent, diff_id| {
+        let digest = sha256(format!("{} {}", parent, diff_id).as_bytes());
+        let mut chain_id = "sha256:".to_string();
+        for byte in digest {
+            write!(chain_id, "{:02x}", byte).expect("Writing to a string should never fail.");
+        }
+        chain_id
+    }))
+}
+
+#[derive(Deserialize)]
+struct Index {
+    manifests: Vec<IndexEntry>,
+}
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    digest: String,
+    platform: Option<Platform>,
+}
+
+#[derive(Deserialize)]
+struct Platform {
+    os: String,
+    architecture: String,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    config: ManifestConfig,
+}
+
+#[derive(Deserialize)]
+struct ManifestConfig {
+    digest: String,
+}
+
+/// The parts of an image's configuration needed to run it.
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerConfig {
+    #[serde(default)]
+    pub env: Option<Vec<String>>,
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(default)]
+    pub cmd: Option<Vec<String>>,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RootFs {
+    diff_ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ImageConfig {
+    #[serde(default)]
+    config: ContainerConfig,
+    rootfs: RootFs,
+}
+
+/// An image which has been pulled and unpacked, ready to run.
+pub struct ResolvedImage {
+    pub config: ContainerConfig,
+
+    /// Key of the unpacked snapshot of the image's layers.
+    pub chain_id: String,
+}
+
+async fn read_content(
+    content: &mut ContentClient<Channel>,
+    namespace: &str,
+    digest: &str,
+) -> Result<Vec<u8>> {
+    let request = ReadContentRequest {
+        digest: digest.to_string(),
+        offset: 0,
+        size: 0,
+    };
+    let mut stream = content
+        .read(with_namespace!(request, namespace))
+        .await?
+        .into_inner();
+
+    let mut data = Vec::new();
+    while let Some(chunk) = stream.message().await? {
+        data.extend(chunk.data);
+    }
+    Ok(data)
+}
+
+/// Read the configuration of an image from the content store, given the
+/// descriptor its name points to.
+pub async fn resolve_image(
+    content: &mut ContentClient<Channel>,
+    namespace: &str,
+    target: &Descriptor,
+) -> Result<ResolvedImage> {
+    let manifest_digest = if INDEX_MEDIA_TYPES.contains(&target.media_type.as_str()) {
+        let index: Index =
+            serde_json::from_slice(&read_content(content, namespace, &target.digest).await?)?;
+        index
+            .manifests
+            .into_iter()
+            .find(|entry| {
+                !INDEX_MEDIA_TYPES.contains(&entry.media_type.as_str())
+                    && entry.platform.as_ref().map_or(false, |platform| {
+                        platform.os == "linux" && platform.architecture == platform_architecture()
+                    })
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "Image has no manifest for linux/{}.",
+                    platform_architecture()
+                )
+            })?
+            .digest
+    } else {
+        target.digest.clone()
+    };
+
+    let manifest: Manifest =
+        serde_json::from_slice(&read_content(content, namespace, &manifest_digest).await?)?;
+    let image_config: ImageConfig =
+        serde_json::from_slice(&read_content(content, namespace, &manifest.config.digest).await?)?;
+
+    let chain_id =
+        chain_id(&image_config.rootfs.diff_ids).ok_or_else(|| anyhow!("Image has no layers."))?;
+
+    Ok(ResolvedImage {
+        config: image_config.config,
+        chain_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_reference() {
+        assert_eq!(
+            "docker.io/library/ubuntu:latest",
+            normalize_reference("ubuntu")
+        );
+        assert_eq!(
+            "docker.io/library/ubuntu:22.04",
+            normalize_reference("ubuntu:22.04")
+        );
+        assert_eq!(
+            "docker.io/drifting/spawner-test:latest",
+            normalize_reference("drifting/spawner-test")
+        );
+        assert_eq!(
+            "ghcr.io/drifting-in-space/demo-image:v1",
+            normalize_reference("ghcr.io/drifting-in-space/demo-image:v1")
+        );
+        assert_eq!(
+            "localhost:5000/image:latest",
+            normalize_reference("localhost:5000/image")
+        );
+        assert_eq!(
+            "docker.io/library/ubuntu@sha256:abcd",
+            normalize_reference("ubuntu@sha256:abcd")
+        );
+    }
+
+    #[test]
+    fn test_chain_id() {
+        let diff_ids: Vec<String> = ["a", "b", "c"]
+            .iter()
+            .map(|c| format!("sha256:{}", c.repeat(64)))
+            .collect();
+
+        assert_eq!(None, chain_id(&[]));
+        assert_eq!(Some(diff_ids[0].clone()), chain_id(&diff_ids[..1]));
+        assert_eq!(
+            Some("sha256:ccd722928bd92476ba1745586fed6e45a102504185ad88cd89e01ff116fd146c".into()),
+            chain_id(&diff_ids[..2])
+        );
+        assert_eq!(
+            Some("sha256:c1377126441fb2f5ec2c21ae2a60255331d639e830f0ee1b40a36e52d4c40588".into()),
+            chain_id(&diff_ids)
+        );
+    }
+}
@@ -0,0 +1,72 @@
+//! Following the stdout and stderr files which containerd writes backend
+//! output to.
+
+use plane_core::{
+    messages::agent::{DroneLogMessage, DroneLogMessageKind},
+    types::BackendId,
+};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    time::Duration,
+};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+
+/// How often to check a log file for new output once its end is reached.
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Number of lines buffered before reading stops to wait for the consumer.
+const LOG_BUFFER_LINES: usize = 64;
+
+/// Yield each line of a log file from its start, following it until the file
+/// is removed or the stream is dropped.
+pub fn follow_log(
+    backend_id: BackendId,
+    path: PathBuf,
+    kind: DroneLogMessageKind,
+) -> impl Stream<Item = DroneLogMessage> {
+    let (send, recv) = tokio::sync::mpsc::channel(LOG_BUFFER_LINES);
+
+    tokio::task::spawn_blocking(move || {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(error) => {
+                tracing::warn!(?error, ?path, "Could not open backend log.");
+                return;
+            }
+        };
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+
+        loop {
+            match reader.read_line(&mut line) {
+                Ok(0) => {
+                    if send.is_closed() || !path.exists() {
+                        return;
+                    }
+                    std::thread::sleep(LOG_POLL_INTERVAL);
+                }
+                // A line without a newline is still being written; the rest
+                // is appended to it on the next read.
+                Ok(_) if !line.ends_with('\n') => (),
+                Ok(_) => {
+                    let message = DroneLogMessage {
+                        backend_id: backend_id.clone(),
+                        kind,
+                        text: std::mem::take(&mut line),
+                    };
+                    if send.blocking_send(message).is_err() {
+                        return;
+                    }
+                }
+                Err(error) => {
+                    tracing::warn!(?error, ?path, "Error reading backend log.");
+                    return;
+                }
+            }
+        }
+    });
+
+    ReceiverStream::new(recv)
+}
@@ -0,0 +1,669 @@
+//! An engine which runs backends with containerd directly, without going
+//! through the Docker daemon.
+//!
+//! Images are pulled with containerd's transfer service, so containerd 1.7
+//! or later is required. Each backend has its own network namespace, which a
+//! CNI plugin attaches to a network, and listens on the port given in its
+//! `PORT` environment variable at the address the plugin assigned. Resource
+//! usage is read from cgroup v2.
+
+mod cgroup;
+mod cni;
+mod image;
+mod logs;
+mod spec;
+use self::{
+    cgroup::{Cgroup, CgroupSample},
+    cni::Cni,
+    image::{normalize_reference, platform_architecture, resolve_image, ResolvedImage},
+    logs::follow_log,
+    spec::runtime_spec,
+};
+use crate::{
+    agent::engine::{Engine, EngineBackendStatus},
+    config::ContainerdConfig,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use containerd_client::{
+    events::{TaskExit, TaskOom},
+    services::v1::{
+        container::Runtime,
+        containers_client::ContainersClient,
+        content_client::ContentClient,
+        events_client::EventsClient,
+        images_client::ImagesClient,
+        snapshots::{
+            snapshots_client::SnapshotsClient, PrepareSnapshotRequest, RemoveSnapshotRequest,
+        },
+        tasks_client::TasksClient,
+        transfer_client::TransferClient,
+        Container, CreateContainerRequest, CreateTaskRequest, DeleteContainerRequest,
+        DeleteTaskRequest, GetContainerRequest, GetImageRequest, GetRequest, KillRequest,
+        ListContainersRequest, ListImagesRequest, StartRequest, SubscribeRequest, TransferOptions,
+        TransferRequest, UpdateContainerRequest, WaitRequest,
+    },
+    types::{
+        transfer::{ImageStore, OciRegistry, UnpackConfiguration},
+        v1::Status,
+        Platform,
+    },
+    with_namespace,
+};
+use futures::{future, stream, Stream, StreamExt};
+use plane_core::{
+    error::PlaneError,
+    messages::agent::{
+        AttachInput, AttachOutputChunk, BackendStatsMessage, DroneLogMessage, DroneLogMessageKind,
+        EgressPolicy, ExecOutputChunk, ResourceLimits, SpawnRequest, TerminalSize,
+    },
+    timing::Timer,
+    types::BackendId,
+};
+use prost::Message;
+use prost_types::{Any, FieldMask};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    time::Duration,
+};
+use tonic::{transport::Channel, Code, Request};
+
+/// Interval between reporting stats of a running backend.
+const STATS_INTERVAL_SECONDS: u64 = 10;
+/// Time a backend is given to exit after being asked to stop, before it is killed.
+const STOP_TIMEOUT_SECONDS: u64 = 10;
+/// Time to wait before subscribing to containerd's events again after the
+/// subscription ends.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Port backends listen on, in their own network namespace.
+const CONTAINER_PORT: u16 = 8080;
+
+const SIGKILL: u32 = 9;
+const SIGTERM: u32 = 15;
+
+const LABEL_MANAGED: &str = "dev.plane.managed";
+const LABEL_BACKEND: &str = "dev.plane.backend";
+/// Label recording the address the CNI plugin assigned to the backend.
+const LABEL_ADDRESS: &str = "dev.plane.address";
+
+const SPEC_TYPE_URL: &str = "types.containerd.io/opencontainers/runtime-spec/1/Spec";
+
+/// Helper trait for swallowing containerd not found errors.
+trait AllowNotFound<T> {
+    /// Return `None` if the result is a NotFound error, and propagate other errors.
+    fn allow_not_found(self) -> Result<Option<T>, tonic::Status>;
+}
+
+impl<T> AllowNotFound<T> for Result<T, tonic::Status> {
+    fn allow_not_found(self) -> Result<Option<T>, tonic::Status> {
+        match self {
+            Ok(value) => Ok(Some(value)),
+            Err(status) if status.code() == Code::NotFound => Ok(None),
+            Err(status) => Err(status),
+        }
+    }
+}
+
+/// Wrap a protobuf message for a containerd API field which accepts several
+/// message types.
+fn to_any<M: Message>(type_name: &str, message: &M) -> Any {
+    Any {
+        type_url: format!("types.containerd.io/{}", type_name),
+        value: message.encode_to_vec(),
+    }
+}
+
+/// The backend whose task an event is about, if it is an exit or OOM event.
+fn event_backend(topic: &str, event: &Any) -> Option<BackendId> {
+    let container_id = match topic {
+        "/tasks/exit" => TaskExit::decode(event.value.as_slice()).ok()?.container_id,
+        "/tasks/oom" => {
+            let container_id = TaskOom::decode(event.value.as_slice()).ok()?.container_id;
+            tracing::warn!(name=%container_id, "Container ran out of memory.");
+            container_id
+        }
+        _ => return None,
+    };
+
+    BackendId::from_resource_name(&container_id)
+}
+
+#[derive(Clone)]
+pub struct ContainerdInterface {
+    channel: Channel,
+    namespace: String,
+    snapshotter: String,
+    runtime: String,
+    root: PathBuf,
+    log_dir: PathBuf,
+    cgroup_parent: String,
+    cni: Cni,
+}
+
+impl ContainerdInterface {
+    pub async fn try_new(config: &ContainerdConfig) -> Result<Self> {
+        let channel = containerd_client::connect(&config.socket).await?;
+        std::fs::create_dir_all(&config.log_dir)?;
+
+        Ok(ContainerdInterface {
+            channel,
+            namespace: config.namespace.clone(),
+            snapshotter: config.snapshotter.clone(),
+            runtime: config.runtime.clone(),
+            root: config.root.clone(),
+            log_dir: config.log_dir.clone(),
+            cgroup_parent: config.cgroup_parent.clone(),
+            cni: Cni::load(&config.cni)?,
+        })
+    }
+
+    /// Wrap a message in a request scoped to the configured namespace.
+    fn request<T>(&self, message: T) -> Request<T> {
+        with_namespace!(message, self.namespace.as_str())
+    }
+
+    fn log_path(&self, name: &str, kind: DroneLogMessageKind) -> PathBuf {
+        let suffix = match kind {
+            DroneLogMessageKind::Stdout => "stdout",
+            DroneLogMessageKind::Stderr => "stderr",
+        };
+        self.log_dir.join(format!("{}.{}.log", name, suffix))
+    }
+
+    fn cgroups_path(&self, name: &str) -> String {
+        format!("{}/{}", self.cgroup_parent.trim_end_matches('/'), name)
+    }
+
+    /// Pull an image and unpack it for the configured snapshotter.
+    async fn pull_image(&self, reference: &str) -> Result<()> {
+        let timer = Timer::new();
+        let platform = Platform {
+            os: "linux".into(),
+            architecture: platform_architecture().into(),
+            ..Platform::default()
+        };
+        let source = OciRegistry {
+            reference: reference.to_string(),
+            ..OciRegistry::default()
+        };
+        let destination = ImageStore {
+            name: reference.to_string(),
+            platforms: vec![platform.clone()],
+            unpacks: vec![UnpackConfiguration {
+                platform: Some(platform),
+                snapshotter: self.snapshotter.clone(),
+            }],
+            ..ImageStore::default()
+        };
+
+        TransferClient::new(self.channel.clone())
+            .transfer(self.request(TransferRequest {
+                source: Some(to_any("containerd.types.transfer.OCIRegistry", &source)),
+                destination: Some(to_any("containerd.types.transfer.ImageStore", &destination)),
+                options: Some(TransferOptions::default()),
+            }))
+            .await?;
+
+        tracing::info!(duration=?timer.duration(), %reference, "Pulled image.");
+        Ok(())
+    }
+
+    async fn resolve_image(&self, reference: &str) -> Result<ResolvedImage> {
+        let target = ImagesClient::new(self.channel.clone())
+            .get(self.request(GetImageRequest {
+                name: reference.to_string(),
+            }))
+            .await?
+            .into_inner()
+            .image
+            .and_then(|image| image.target)
+            .ok_or_else(|| anyhow!("Image {} has no target.", reference))?;
+
+        resolve_image(
+            &mut ContentClient::new(self.channel.clone()),
+            &self.namespace,
+            &target,
+        )
+        .await
+    }
+
+    /// Create and start a container for the given image.
+    async fn run_container(
+        &self,
+        name: &str,
+        reference: &str,
+        image: &ResolvedImage,
+        env: &HashMap<String, String>,
+        resource_limits: &ResourceLimits,
+    ) -> Result<()> {
+        resource_limits.validate()?;
+        let mut env = env.clone();
+        env.insert("PORT".to_string(), CONTAINER_PORT.to_string());
+        let spec = runtime_spec(
+            name,
+            &image.config,
+            &env,
+            resource_limits,
+            &self.cgroups_path(name),
+        )?;
+
+        // Build the container.
+        let rootfs = {
+            let timer = Timer::new();
+            let mounts = SnapshotsClient::new(self.channel.clone())
+                .prepare(self.request(PrepareSnapshotRequest {
+                    snapshotter: self.snapshotter.clone(),
+                    key: name.to_string(),
+                    parent: image.chain_id.clone(),
+                    labels: HashMap::new(),
+                }))
+                .await?
+                .into_inner()
+                .mounts;
+
+            let container = Container {
+                id: name.to_string(),
+                image: reference.to_string(),
+                labels: vec![
+                    (LABEL_MANAGED.to_string(), "true".to_string()),
+                    (LABEL_BACKEND.to_string(), name.to_string()),
+                ]
+                .into_iter()
+                .collect(),
+                runtime: Some(Runtime {
+                    name: self.runtime.clone(),
+                    options: None,
+                }),
+                spec: Some(Any {
+                    type_url: SPEC_TYPE_URL.to_string(),
+                    value: serde_json::to_vec(&spec)?,
+                }),
+                snapshotter: self.snapshotter.clone(),
+                snapshot_key: name.to_string(),
+                ..Container::default()
+            };
+            ContainersClient::new(self.channel.clone())
+                .create(self.request(CreateContainerRequest {
+                    container: Some(container),
+                }))
+                .await?;
+            tracing::info!(duration=?timer.duration(), %reference, "Created container.");
+
+            mounts
+        };
+
+        // Start the container.
+        {
+            let timer = Timer::new();
+            let mut tasks = TasksClient::new(self.channel.clone());
+            let stdout = self.log_path(name, DroneLogMessageKind::Stdout);
+            let stderr = self.log_path(name, DroneLogMessageKind::Stderr);
+
+            let pid = tasks
+                .create(self.request(CreateTaskRequest {
+                    container_id: name.to_string(),
+                    rootfs,
+                    stdout: format!("file://{}", stdout.display()),
+                    stderr: format!("file://{}", stderr.display()),
+                    ..CreateTaskRequest::default()
+                }))
+                .await?
+                .into_inner()
+                .pid;
+
+            // The task's network namespace exists once it is created, and is
+            // attached to the network before the backend starts.
+            let ip = self.cni.add(name, &format!("/proc/{}/ns/net", pid)).await?;
+            ContainersClient::new(self.channel.clone())
+                .update(
+                    self.request(UpdateContainerRequest {
+                        container: Some(Container {
+                            id: name.to_string(),
+                            labels: vec![(LABEL_ADDRESS.to_string(), ip.to_string())]
+                                .into_iter()
+                                .collect(),
+                            ..Container::default()
+                        }),
+                        update_mask: Some(FieldMask {
+                            paths: vec![format!("labels.{}", LABEL_ADDRESS)],
+                        }),
+                    }),
+                )
+                .await?;
+
+            tasks
+                .start(self.request(StartRequest {
+                    container_id: name.to_string(),
+                    exec_id: String::new(),
+                }))
+                .await?;
+            tracing::info!(duration=?timer.duration(), %name, %ip, "Started container.");
+        }
+
+        Ok(())
+    }
+
+    /// Send a signal to every process of a task, returning false if there
+    /// is no running task.
+    async fn kill_task(&self, name: &str, signal: u32) -> Result<bool> {
+        Ok(TasksClient::new(self.channel.clone())
+            .kill(self.request(KillRequest {
+                container_id: name.to_string(),
+                exec_id: String::new(),
+                signal,
+                all: true,
+            }))
+            .await
+            .allow_not_found()?
+            .is_some())
+    }
+}
+
+#[async_trait]
+impl Engine for ContainerdInterface {
+    fn interrupt_stream(&self) -> Pin<Box<dyn Stream<Item = BackendId> + Send>> {
+        let channel = self.channel.clone();
+        let namespace = self.namespace.clone();
+        let request = SubscribeRequest {
+            filters: vec![
+                "topic==\"/tasks/exit\"".to_string(),
+                "topic==\"/tasks/oom\"".to_string(),
+            ],
+        };
+
+        // containerd ends the subscription when it restarts, so the stream
+        // subscribes again whenever a subscription ends or fails.
+        let subscriptions = stream::unfold(true, move |first| {
+            let mut events = EventsClient::new(channel.clone());
+            let request = request.clone();
+            async move {
+                if !first {
+                    tracing::warn!("containerd event subscription ended; resubscribing.");
+                    tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+                }
+                let events = match events.subscribe(request).await {
+                    Ok(response) => response.into_inner().boxed(),
+                    Err(error) => {
+                        tracing::error!(?error, "Error subscribing to containerd events.");
+                        stream::empty().boxed()
+                    }
+                };
+                Some((events, false))
+            }
+        });
+
+        let stream = subscriptions.flatten().filter_map(move |envelope| {
+            let backend = match envelope {
+                Ok(envelope) if envelope.namespace == namespace => envelope
+                    .event
+                    .as_ref()
+                    .and_then(|event| event_backend(&envelope.topic, event)),
+                Ok(_) => None,
+                Err(error) => {
+                    tracing::error!(?error, "Error tracking container terminations.");
+                    None
+                }
+            };
+            future::ready(backend)
+        });
+
+        Box::pin(stream)
+    }
+
+    async fn load(&self, spawn_request: &SpawnRequest) -> Result<()> {
+        let executable = &spawn_request.executable;
+        if executable.credentials.is_some() {
+            return Err(anyhow!(
+                "Registry credentials are not supported by the containerd engine."
+            ));
+        }
+        if executable.egress_policy != EgressPolicy::AllowAll {
+            return Err(anyhow!(
+                "Egress policies are not supported by the containerd engine."
+            ));
+        }
+
+        let reference = normalize_reference(&executable.image);
+        self.pull_image(&reference)
+            .await
+            .map_err(|error| PlaneError::ImagePullFailed {
+                image: executable.image.clone(),
+                reason: error.to_string(),
+            })?;
+        let image = self.resolve_image(&reference).await?;
+
+        let backend_id = spawn_request.backend_id.to_resource_name();
+        self.run_container(
+            &backend_id,
+            &reference,
+            &image,
+            &executable.env,
+            &executable.resource_limits,
+        )
+        .await?;
+        tracing::info!(%backend_id, "Container is running.");
+
+        Ok(())
+    }
+
+    async fn backend_status(&self, backend: &BackendId) -> Result<EngineBackendStatus> {
+        let name = backend.to_resource_name();
+        let container = match ContainersClient::new(self.channel.clone())
+            .get(self.request(GetContainerRequest { id: name.clone() }))
+            .await
+            .allow_not_found()?
+        {
+            Some(response) => response
+                .into_inner()
+                .container
+                .ok_or_else(|| anyhow!("No container returned."))?,
+            None => return Ok(EngineBackendStatus::Unknown),
+        };
+
+        let process = match TasksClient::new(self.channel.clone())
+            .get(self.request(GetRequest {
+                container_id: name.clone(),
+                exec_id: String::new(),
+            }))
+            .await
+            .allow_not_found()?
+        {
+            Some(response) => response
+                .into_inner()
+                .process
+                .ok_or_else(|| anyhow!("No process returned for task."))?,
+            None => return Ok(EngineBackendStatus::Terminated),
+        };
+
+        if process.status == Status::Running as i32 {
+            let ip: IpAddr = container
+                .labels
+                .get(LABEL_ADDRESS)
+                .ok_or_else(|| anyhow!("Container has no address label."))?
+                .parse()?;
+            let addr = SocketAddr::new(ip, CONTAINER_PORT);
+
+            Ok(EngineBackendStatus::Running { addr })
+        } else if process.status != Status::Stopped as i32 {
+            // The task has been created but not started.
+            Ok(EngineBackendStatus::Unknown)
+        } else if Cgroup::new(&self.cgroups_path(&name)).oom_killed() == Some(true) {
+            Ok(EngineBackendStatus::OutOfMemory)
+        } else if process.exit_status == 0 {
+            Ok(EngineBackendStatus::Exited)
+        } else {
+            Ok(EngineBackendStatus::Failed {
+                exit_code: process.exit_status.into(),
+            })
+        }
+    }
+
+    fn log_stream(
+        &self,
+        backend: &BackendId,
+    ) -> Pin<Box<dyn Stream<Item = DroneLogMessage> + Send>> {
+        let name = backend.to_resource_name();
+        let stdout = follow_log(
+            backend.clone(),
+            self.log_path(&name, DroneLogMessageKind::Stdout),
+            DroneLogMessageKind::Stdout,
+        );
+        let stderr = follow_log(
+            backend.clone(),
+            self.log_path(&name, DroneLogMessageKind::Stderr),
+            DroneLogMessageKind::Stderr,
+        );
+
+        Box::pin(stream::select(stdout, stderr))
+    }
+
+    fn stats_stream(
+        &self,
+        backend: &BackendId,
+    ) -> Pin<Box<dyn Stream<Item = BackendStatsMessage> + Send>> {
+        let cgroup = Cgroup::new(&self.cgroups_path(&backend.to_resource_name()));
+        let ticker = {
+            let mut ticker = tokio::time::interval(Duration::from_secs(STATS_INTERVAL_SECONDS));
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            ticker
+        };
+        let backend = backend.clone();
+
+        // Usage is reported as the change between consecutive samples, so the
+        // first sample produces no message. The stream ends with the cgroup.
+        let stream = stream::unfold(
+            (cgroup, ticker, None::<CgroupSample>),
+            move |(cgroup, mut ticker, previous)| {
+                let backend = backend.clone();
+                async move {
+                    ticker.tick().await;
+                    let sample = cgroup.sample()?;
+                    let message = previous.map(|previous| {
+                        let (cpu_use_percent, mem_use_percent) = sample.usage_since(&previous);
+                        let (read_bytes, write_bytes) = sample.io_since(&previous);
+                        BackendStatsMessage::new(&backend, cpu_use_percent, mem_use_percent)
+                            .with_block_io_deltas(read_bytes, write_bytes)
+                    });
+                    Some((message, (cgroup, ticker, Some(sample))))
+                }
+            },
+        )
+        .filter_map(future::ready);
+
+        Box::pin(stream)
+    }
+
+    async fn exec(
+        &self,
+        _backend: &BackendId,
+        _command: &[String],
+    ) -> Result<Pin<Box<dyn Stream<Item = ExecOutputChunk> + Send>>> {
+        Err(anyhow!(
+            "Running commands in backends is not supported with containerd."
+        ))
+    }
+
+    async fn attach(
+        &self,
+        _backend: &BackendId,
+        _command: &[String],
+        _size: TerminalSize,
+        _input: Pin<Box<dyn Stream<Item = AttachInput> + Send>>,
+    ) -> Result<Pin<Box<dyn Stream<Item = AttachOutputChunk> + Send>>> {
+        Err(anyhow!(
+            "Attaching to backends is not supported with containerd."
+        ))
+    }
+
+    async fn stop(&self, backend: &BackendId) -> Result<()> {
+        let name = backend.to_resource_name();
+        let mut tasks = TasksClient::new(self.channel.clone());
+
+        if self.kill_task(&name, SIGTERM).await? {
+            let wait = tasks.wait(self.request(WaitRequest {
+                container_id: name.clone(),
+                exec_id: String::new(),
+            }));
+            if tokio::time::timeout(Duration::from_secs(STOP_TIMEOUT_SECONDS), wait)
+                .await
+                .is_err()
+            {
+                self.kill_task(&name, SIGKILL).await?;
+            }
+        }
+
+        tasks
+            .delete(self.request(DeleteTaskRequest {
+                container_id: name.clone(),
+            }))
+            .await
+            .allow_not_found()?;
+        if let Err(error) = self.cni.del(&name).await {
+            tracing::warn!(?error, %name, "Error releasing container network.");
+        }
+        ContainersClient::new(self.channel.clone())
+            .delete(self.request(DeleteContainerRequest { id: name.clone() }))
+            .await
+            .allow_not_found()?;
+        SnapshotsClient::new(self.channel.clone())
+            .remove(self.request(RemoveSnapshotRequest {
+                snapshotter: self.snapshotter.clone(),
+                key: name.clone(),
+            }))
+            .await
+            .allow_not_found()?;
+
+        // Removing the log files also ends any log streams following them.
+        for kind in [DroneLogMessageKind::Stdout, DroneLogMessageKind::Stderr] {
+            match std::fs::remove_file(self.log_path(&name, kind)) {
+                Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                    tracing::warn!(?error, %name, "Error removing container log.")
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn list_backends(&self) -> Result<Vec<BackendId>> {
+        let containers = ContainersClient::new(self.channel.clone())
+            .list(self.request(ListContainersRequest {
+                filters: vec![format!("labels.\"{}\"==true", LABEL_MANAGED)],
+            }))
+            .await?
+            .into_inner()
+            .containers;
+
+        Ok(containers
+            .iter()
+            .filter_map(|container| BackendId::from_resource_name(&container.id))
+            .collect())
+    }
+
+    async fn seed_image(&self, image: &str) -> Result<()> {
+        self.pull_image(&normalize_reference(image)).await
+    }
+
+    async fn image_names(&self) -> Result<Vec<String>> {
+        let images = ImagesClient::new(self.channel.clone())
+            .list(self.request(ListImagesRequest {
+                filters: Vec::new(),
+            }))
+            .await?
+            .into_inner()
+            .images;
+
+        let mut names: Vec<String> = images.into_iter().map(|image| image.name).collect();
+        names.sort();
+        Ok(names)
+    }
+
+    async fn data_dir(&self) -> Result<String> {
+        Ok(self.root.to_string_lossy().into_owned())
+    }
+}
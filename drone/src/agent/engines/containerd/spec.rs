@@ -0,0 +1,335 @@
+//! Construction of the OCI runtime spec of a backend container.
+//! See https://github.com/opencontainers/runtime-spec/blob/main/config.md
+
+use super::image::ContainerConfig;
+use anyhow::{anyhow, Result};
+use plane_core::messages::agent::ResourceLimits;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+const DEFAULT_PATH: &str = "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Capabilities granted to containers, matching Docker's defaults.
+const DEFAULT_CAPABILITIES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_FSETID",
+    "CAP_FOWNER",
+    "CAP_MKNOD",
+    "CAP_NET_RAW",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETFCAP",
+    "CAP_SETPCAP",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_SYS_CHROOT",
+    "CAP_KILL",
+    "CAP_AUDIT_WRITE",
+];
+
+/// System calls which fail with `EPERM`. These are the calls Docker's default
+/// seccomp profile denies to containers which have not been granted extra
+/// capabilities, so that backends are confined as they are with Docker.
+const DENIED_SYSCALLS: &[&str] = &[
+    "acct",
+    "add_key",
+    "bpf",
+    "clock_adjtime",
+    "clock_settime",
+    "create_module",
+    "delete_module",
+    "finit_module",
+    "fsconfig",
+    "fsmount",
+    "fsopen",
+    "fspick",
+    "get_kernel_syms",
+    "init_module",
+    "ioperm",
+    "iopl",
+    "kcmp",
+    "kexec_file_load",
+    "kexec_load",
+    "keyctl",
+    "lookup_dcookie",
+    "mount",
+    "move_mount",
+    "move_pages",
+    "name_to_handle_at",
+    "nfsservctl",
+    "open_by_handle_at",
+    "open_tree",
+    "perf_event_open",
+    "pidfd_getfd",
+    "pivot_root",
+    "query_module",
+    "quotactl",
+    "reboot",
+    "request_key",
+    "setns",
+    "settimeofday",
+    "stime",
+    "swapoff",
+    "swapon",
+    "syslog",
+    "sysfs",
+    "_sysctl",
+    "umount",
+    "umount2",
+    "unshare",
+    "uselib",
+    "userfaultfd",
+    "ustat",
+    "vm86",
+    "vm86old",
+];
+
+/// `ENOSYS`, returned for `clone3` so that C libraries fall back to `clone`,
+/// as under Docker's default profile.
+const ENOSYS: u32 = 38;
+
+/// The seccomp profile of backend containers.
+fn seccomp_profile() -> Value {
+    json!({
+        "defaultAction": "SCMP_ACT_ALLOW",
+        "syscalls": [
+            { "names": DENIED_SYSCALLS, "action": "SCMP_ACT_ERRNO", "errnoRet": 1 },
+            { "names": ["clone3"], "action": "SCMP_ACT_ERRNO", "errnoRet": ENOSYS },
+        ],
+    })
+}
+
+/// Parse the image's user, which must be numeric since the container's
+/// `/etc/passwd` is not consulted.
+fn parse_user(user: Option<&str>) -> Result<(u32, u32)> {
+    let user = match user {
+        Some(user) if !user.is_empty() => user,
+        _ => return Ok((0, 0)),
+    };
+
+    let (uid, gid) = user.split_once(':').unwrap_or((user, "0"));
+    match (uid.parse(), gid.parse()) {
+        (Ok(uid), Ok(gid)) => Ok((uid, gid)),
+        _ => Err(anyhow!(
+            "Image user {:?} is not numeric, which containerd backends require.",
+            user
+        )),
+    }
+}
+
+/// Linux resource limits in the form of the runtime spec.
+fn linux_resources(resource_limits: &ResourceLimits) -> Value {
+    let mut resources = json!({});
+
+    if let Some(memory) = resource_limits.memory_limit_bytes {
+        resources["memory"] = json!({ "limit": memory });
+    }
+
+    // runc translates shares and weights to their cgroup v2 equivalents
+    // (cpu.weight and io.weight) on hosts which use the unified hierarchy.
+    let cpu_period = resource_limits
+        .cpu_period
+        .unwrap_or(ResourceLimits::DEFAULT_CPU_PERIOD);
+    if let Some(quota) = resource_limits.effective_cpu_quota() {
+        resources["cpu"] = json!({
+            "period": cpu_period.as_micros() as u64,
+            "quota": quota.as_micros() as u64,
+        });
+    }
+    if let Some(cpu_shares) = resource_limits.cpu_shares {
+        resources["cpu"]["shares"] = json!(cpu_shares);
+    }
+
+    if let Some(io_weight) = resource_limits.io_weight {
+        resources["blockIO"] = json!({ "weight": io_weight });
+    }
+
+    if let Some(pids_limit) = resource_limits.pids_limit {
+        resources["pids"] = json!({ "limit": pids_limit });
+    }
+
+    resources
+}
+
+/// Build the runtime spec for a backend. The container has its own network
+/// namespace, which is attached to a network once its task is created, and
+/// is confined by [seccomp_profile].
+pub fn runtime_spec(
+    hostname: &str,
+    image_config: &ContainerConfig,
+    env: &HashMap<String, String>,
+    resource_limits: &ResourceLimits,
+    cgroups_path: &str,
+) -> Result<Value> {
+    let args: Vec<String> = image_config
+        .entrypoint
+        .iter()
+        .chain(image_config.cmd.iter())
+        .flatten()
+        .cloned()
+        .collect();
+    if args.is_empty() {
+        return Err(anyhow!("Image has neither an entrypoint nor a command."));
+    }
+
+    let mut process_env: Vec<String> = image_config.env.clone().unwrap_or_default();
+    if !process_env.iter().any(|var| var.starts_with("PATH=")) {
+        process_env.push(DEFAULT_PATH.to_string());
+    }
+    process_env.extend(env.iter().map(|(k, v)| format!("{}={}", k, v)));
+
+    let (uid, gid) = parse_user(image_config.user.as_deref())?;
+
+    let rlimits = match resource_limits.cpu_time_limit {
+        Some(cpu_time_limit) => json!([{
+            "type": "RLIMIT_CPU",
+            "hard": cpu_time_limit.as_secs(),
+            "soft": cpu_time_limit.as_secs(),
+        }]),
+        None => json!([]),
+    };
+
+    Ok(json!({
+        "ociVersion": "1.0.2",
+        "process": {
+            "terminal": false,
+            "user": { "uid": uid, "gid": gid },
+            "args": args,
+            "env": process_env,
+            "cwd": image_config.working_dir.as_deref().filter(|dir| !dir.is_empty()).unwrap_or("/"),
+            "capabilities": {
+                "bounding": DEFAULT_CAPABILITIES,
+                "effective": DEFAULT_CAPABILITIES,
+                "permitted": DEFAULT_CAPABILITIES,
+            },
+            "rlimits": rlimits,
+            "noNewPrivileges": true,
+        },
+        "root": { "path": "rootfs" },
+        "hostname": hostname,
+        "mounts": [
+            { "destination": "/proc", "type": "proc", "source": "proc", "options": ["nosuid", "noexec", "nodev"] },
+            { "destination": "/dev", "type": "tmpfs", "source": "tmpfs", "options": ["nosuid", "strictatime", "mode=755", "size=65536k"] },
+            { "destination": "/dev/pts", "type": "devpts", "source": "devpts", "options": ["nosuid", "noexec", "newinstance", "ptmxmode=0666", "mode=0620", "gid=5"] },
+            { "destination": "/dev/shm", "type": "tmpfs", "source": "shm", "options": ["nosuid", "noexec", "nodev", "mode=1777", "size=65536k"] },
+            { "destination": "/dev/mqueue", "type": "mqueue", "source": "mqueue", "options": ["nosuid", "noexec", "nodev"] },
+            { "destination": "/sys", "type": "sysfs", "source": "sysfs", "options": ["nosuid", "noexec", "nodev", "ro"] },
+            { "destination": "/sys/fs/cgroup", "type": "cgroup", "source": "cgroup", "options": ["nosuid", "noexec", "nodev", "relatime", "ro"] },
+            { "destination": "/etc/resolv.conf", "type": "bind", "source": "/etc/resolv.conf", "options": ["rbind", "ro"] },
+            { "destination": "/etc/hosts", "type": "bind", "source": "/etc/hosts", "options": ["rbind", "ro"] },
+        ],
+        "linux": {
+            "resources": linux_resources(resource_limits),
+            "cgroupsPath": cgroups_path,
+            "namespaces": [
+                { "type": "pid" },
+                { "type": "ipc" },
+                { "type": "uts" },
+                { "type": "mount" },
+                { "type": "network" },
+            ],
+            "seccomp": seccomp_profile(),
+            "maskedPaths": [
+                "/proc/acpi",
+                "/proc/asound",
+                "/proc/kcore",
+                "/proc/keys",
+                "/proc/latency_stats",
+                "/proc/timer_list",
+                "/proc/timer_stats",
+                "/proc/sched_debug",
+                "/proc/scsi",
+                "/sys/firmware",
+            ],
+            "readonlyPaths": [
+                "/proc/bus",
+                "/proc/fs",
+                "/proc/irq",
+                "/proc/sys",
+                "/proc/sysrq-trigger",
+            ],
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_user() {
+        assert_eq!((0, 0), parse_user(None).unwrap());
+        assert_eq!((0, 0), parse_user(Some("")).unwrap());
+        assert_eq!((1000, 0), parse_user(Some("1000")).unwrap());
+        assert_eq!((1000, 100), parse_user(Some("1000:100")).unwrap());
+        assert!(parse_user(Some("nobody")).is_err());
+    }
+
+    #[test]
+    fn test_runtime_spec() {
+        let image_config = ContainerConfig {
+            env: Some(vec!["PATH=/app/bin".into(), "FOO=image".into()]),
+            entrypoint: Some(vec!["/app/server".into()]),
+            cmd: Some(vec!["--verbose".into()]),
+            ..ContainerConfig::default()
+        };
+        let env = vec![("PORT".to_string(), "4000".to_string())]
+            .into_iter()
+            .collect();
+        let resource_limits = ResourceLimits {
+            cpu_period: Some(Duration::from_millis(200)),
+            cpu_period_percent: Some(50),
+            cpu_time_limit: Some(Duration::from_secs(120)),
+            memory_limit_bytes: Some(1 << 28),
+            pids_limit: Some(64),
+            ..ResourceLimits::default()
+        };
+
+        let spec = runtime_spec(
+            "backend",
+            &image_config,
+            &env,
+            &resource_limits,
+            "/plane/backend",
+        )
+        .unwrap();
+
+        assert_eq!(json!(["/app/server", "--verbose"]), spec["process"]["args"]);
+        assert_eq!(
+            json!(["PATH=/app/bin", "FOO=image", "PORT=4000"]),
+            spec["process"]["env"]
+        );
+        assert_eq!(json!("/"), spec["process"]["cwd"]);
+        assert_eq!(json!(120), spec["process"]["rlimits"][0]["hard"]);
+        assert_eq!(
+            json!({
+                "memory": { "limit": 1 << 28 },
+                "cpu": { "period": 200_000, "quota": 100_000 },
+                "pids": { "limit": 64 },
+            }),
+            spec["linux"]["resources"]
+        );
+        assert!(spec["linux"]["namespaces"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "type": "network" })));
+        assert_eq!(
+            json!("SCMP_ACT_ALLOW"),
+            spec["linux"]["seccomp"]["defaultAction"]
+        );
+        assert!(spec["linux"]["seccomp"]["syscalls"][0]["names"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("mount")));
+
+        assert!(runtime_spec(
+            "backend",
+            &ContainerConfig::default(),
+            &env,
+            &ResourceLimits::default(),
+            "/plane/backend",
+        )
+        .is_err());
+    }
+}
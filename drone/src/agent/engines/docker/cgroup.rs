@@ -0,0 +1,118 @@
+use bollard::models::SystemInfo;
+use plane_core::{error::PlaneError, messages::agent::ResourceLimits};
+
+/// Which cgroup controllers the Docker host supports, as reported by
+/// `docker info`. Docker silently drops limits which the host cannot
+/// enforce, so requests for them are rejected instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CgroupSupport {
+    pub cpu_quota: bool,
+    pub cpu_shares: bool,
+    pub io_weight: bool,
+    pub pids_limit: bool,
+}
+
+impl CgroupSupport {
+    pub fn from_info(info: &SystemInfo) -> Self {
+        // Docker has no field for IO weight support, but warns when it is
+        // missing ("No blkio weight support" on cgroup v1, "No io.weight
+        // support" on cgroup v2).
+        let io_weight = !info.warnings.iter().flatten().any(|warning| {
+            warning.contains("No blkio weight support") || warning.contains("No io.weight support")
+        });
+
+        CgroupSupport {
+            cpu_quota: info.cpu_cfs_quota.unwrap_or(false) && info.cpu_cfs_period.unwrap_or(false),
+            cpu_shares: info.cpu_shares.unwrap_or(false),
+            io_weight,
+            pids_limit: info.pids_limit.unwrap_or(false),
+        }
+    }
+
+    /// Whether `docker info` is needed to check the given limits.
+    pub fn needs_check(resource_limits: &ResourceLimits) -> bool {
+        resource_limits.effective_cpu_quota().is_some()
+            || resource_limits.cpu_shares.is_some()
+            || resource_limits.io_weight.is_some()
+            || resource_limits.pids_limit.is_some()
+    }
+
+    /// Check that the host can enforce every requested limit.
+    pub fn check(&self, resource_limits: &ResourceLimits) -> Result<(), PlaneError> {
+        let unsupported = [
+            (
+                resource_limits.effective_cpu_quota().is_some() && !self.cpu_quota,
+                "a CPU quota (cpu.max)",
+            ),
+            (
+                resource_limits.cpu_shares.is_some() && !self.cpu_shares,
+                "CPU shares (cpu.weight)",
+            ),
+            (
+                resource_limits.io_weight.is_some() && !self.io_weight,
+                "an IO weight (io.weight)",
+            ),
+            (
+                resource_limits.pids_limit.is_some() && !self.pids_limit,
+                "a PIDs limit (pids.max)",
+            ),
+        ];
+
+        match unsupported.iter().find(|(unsupported, _)| *unsupported) {
+            Some((_, limit)) => Err(PlaneError::InvalidRequest {
+                reason: format!(
+                    "The drone's cgroup configuration does not support {}.",
+                    limit
+                ),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_cgroup_support() {
+        let info = SystemInfo {
+            cpu_cfs_period: Some(true),
+            cpu_cfs_quota: Some(true),
+            cpu_shares: Some(true),
+            pids_limit: Some(false),
+            warnings: Some(vec!["WARNING: No io.weight support".into()]),
+            ..SystemInfo::default()
+        };
+        let support = CgroupSupport::from_info(&info);
+        assert_eq!(
+            CgroupSupport {
+                cpu_quota: true,
+                cpu_shares: true,
+                io_weight: false,
+                pids_limit: false,
+            },
+            support
+        );
+
+        let limits = ResourceLimits {
+            cpu_quota: Some(Duration::from_millis(50)),
+            cpu_shares: Some(512),
+            ..ResourceLimits::default()
+        };
+        assert!(CgroupSupport::needs_check(&limits));
+        assert!(support.check(&limits).is_ok());
+
+        let limits = ResourceLimits {
+            io_weight: Some(100),
+            ..ResourceLimits::default()
+        };
+        assert!(support.check(&limits).is_err());
+
+        assert!(!CgroupSupport::needs_check(&ResourceLimits {
+            memory_limit_bytes: Some(1 << 28),
+            ..ResourceLimits::default()
+        }));
+    }
+}
@@ -0,0 +1,267 @@
+//! Checkpointing and restoring containers with CRIU, through Docker's
+//! experimental checkpoint support. The version of bollard the drone uses
+//! does not expose the checkpoint endpoints of the Engine API, so they are
+//! requested directly over the same connection as bollard's.
+//!
+//! Docker writes checkpoints in the clear, so they are only ever written to a
+//! directory local to the drone. A checkpoint is copied to the directory
+//! shared between drones sealed to the drone which will restore it (see
+//! [plane_core::sealing]), since it holds the backend's memory, and is opened
+//! into that drone's local directory before the container is restored.
+
+use crate::config::{CheckpointConfig, DockerConnection};
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use hyper::{client::conn::handshake, Body, Method, Request, StatusCode};
+use plane_core::{
+    sealing::{SealedData, SealingKey},
+    types::BackendId,
+};
+use serde_json::json;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UnixStream},
+};
+
+/// Suffix of sealed checkpoint files in the shared directory.
+const SEALED_SUFFIX: &str = ".sealed";
+
+/// A name for a new checkpoint of a backend, unique across its checkpoints.
+pub fn checkpoint_name(backend: &BackendId) -> String {
+    format!("{}-{}", backend.to_resource_name(), Utc::now().timestamp())
+}
+
+/// Seal every file of the checkpoint in `from` into `to`, which is created.
+fn seal_dir(from: &Path, to: &Path, recipient: &str) -> Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            return Err(anyhow!(
+                "Unexpected non-file {:?} in checkpoint.",
+                entry.path()
+            ));
+        }
+        let sealed = SealedData::seal(recipient, &std::fs::read(entry.path())?)?;
+        let mut name = entry.file_name();
+        name.push(SEALED_SUFFIX);
+        std::fs::write(to.join(name), serde_json::to_vec(&sealed)?)?;
+    }
+    Ok(())
+}
+
+/// Open every sealed file of the checkpoint in `from` into `to`, which is
+/// created.
+fn open_dir(from: &Path, to: &Path, key: &SealingKey) -> Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let name = name
+            .strip_suffix(SEALED_SUFFIX)
+            .ok_or_else(|| anyhow!("Unexpected unsealed file {:?} in checkpoint.", name))?;
+        let sealed: SealedData = serde_json::from_slice(&std::fs::read(entry.path())?)?;
+        std::fs::write(to.join(name), key.open(&sealed)?)?;
+    }
+    Ok(())
+}
+
+/// Send a request to the Docker daemon over an established connection.
+async fn send<S>(stream: S, request: Request<Body>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = handshake(stream).await?;
+    tokio::spawn(connection);
+
+    let response = sender.send_request(request).await?;
+    let status = response.status();
+    // Starting a container which is already running is not an error.
+    if status.is_success() || status == StatusCode::NOT_MODIFIED {
+        return Ok(());
+    }
+
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Err(anyhow!(
+        "Docker responded with {}: {}",
+        status,
+        String::from_utf8_lossy(&body)
+    ))
+}
+
+/// Make a POST request to an Engine API endpoint, e.g. `/containers/{id}/start`.
+async fn engine_post(
+    connection: &DockerConnection,
+    path: &str,
+    query: &[(&str, &str)],
+    body: Option<serde_json::Value>,
+) -> Result<()> {
+    let mut url = reqwest::Url::parse("http://docker")?;
+    url.set_path(path);
+    if !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query);
+    }
+    let path_and_query = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+
+    let host = match connection {
+        DockerConnection::Socket { .. } => "docker",
+        DockerConnection::Http { http } => http.trim_start_matches("http://").trim_end_matches('/'),
+    };
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(path_and_query)
+        .header(hyper::header::HOST, host)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(match body {
+            Some(body) => Body::from(serde_json::to_vec(&body)?),
+            None => Body::empty(),
+        })?;
+
+    let result = match connection {
+        DockerConnection::Socket { socket } => {
+            send(UnixStream::connect(socket).await?, request).await
+        }
+        DockerConnection::Http { .. } => send(TcpStream::connect(host).await?, request).await,
+    };
+    result.with_context(|| format!("Error requesting {} from Docker.", path))
+}
+
+#[derive(Clone)]
+pub struct DockerCheckpoints {
+    connection: DockerConnection,
+
+    /// Directory local to this drone which Docker reads and writes
+    /// checkpoints in.
+    local_dir: PathBuf,
+
+    /// Directory shared between drones which sealed checkpoints are copied to.
+    shared_dir: PathBuf,
+}
+
+impl DockerCheckpoints {
+    pub fn new(connection: DockerConnection, config: &CheckpointConfig) -> Self {
+        DockerCheckpoints {
+            connection,
+            local_dir: config.local_dir.clone().into(),
+            shared_dir: config.shared_dir.clone().into(),
+        }
+    }
+
+    fn local_dir_string(&self) -> String {
+        self.local_dir.to_string_lossy().to_string()
+    }
+
+    /// Checkpoint a running container, which stops it, and copy the
+    /// checkpoint to the shared directory sealed to `recipient`. The
+    /// checkpoint is kept in the local directory too, so that the container
+    /// can be restored on this drone if it is not restored elsewhere.
+    pub async fn create(&self, container: &str, checkpoint: &str, recipient: &str) -> Result<()> {
+        engine_post(
+            &self.connection,
+            &format!("/containers/{}/checkpoints", container),
+            &[],
+            Some(json!({
+                "CheckpointID": checkpoint,
+                "CheckpointDir": self.local_dir_string(),
+                "Exit": true,
+            })),
+        )
+        .await?;
+
+        let from = self.local_dir.join(checkpoint);
+        let to = self.shared_dir.join(checkpoint);
+        let recipient = recipient.to_string();
+        tokio::task::spawn_blocking(move || seal_dir(&from, &to, &recipient))
+            .await?
+            .context("Error sealing checkpoint.")
+    }
+
+    /// Start a created container from a checkpoint. Unless this drone made
+    /// the checkpoint, it is first opened from the shared directory with
+    /// `key`.
+    pub async fn start(
+        &self,
+        container: &str,
+        checkpoint: &str,
+        key: Option<Arc<SealingKey>>,
+    ) -> Result<()> {
+        let local = self.local_dir.join(checkpoint);
+        if !local.exists() {
+            let key = key.ok_or_else(|| {
+                anyhow!("Restoring checkpoints made elsewhere requires a sealing key.")
+            })?;
+            let from = self.shared_dir.join(checkpoint);
+            let to = local.clone();
+            tokio::task::spawn_blocking(move || open_dir(&from, &to, &key))
+                .await?
+                .context("Error opening checkpoint.")?;
+        }
+
+        let result = engine_post(
+            &self.connection,
+            &format!("/containers/{}/start", container),
+            &[
+                ("checkpoint", checkpoint),
+                ("checkpoint-dir", &self.local_dir_string()),
+            ],
+            None,
+        )
+        .await;
+        remove_dir(&local).await?;
+        remove_dir(&self.shared_dir.join(checkpoint)).await?;
+
+        result
+    }
+
+    /// Remove this drone's copy of a checkpoint, once another drone has
+    /// taken over restoring it.
+    pub async fn discard(&self, checkpoint: &str) -> Result<()> {
+        remove_dir(&self.local_dir.join(checkpoint)).await
+    }
+}
+
+async fn remove_dir(dir: &Path) -> Result<()> {
+    match tokio::fs::remove_dir_all(dir).await {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open_dir() {
+        let dir = std::env::temp_dir().join(format!("plane-checkpoint-{}", std::process::id()));
+        let (local, shared, restored) =
+            (dir.join("local"), dir.join("shared"), dir.join("restored"));
+        std::fs::create_dir_all(&local).unwrap();
+        std::fs::write(local.join("pages-1.img"), b"memory").unwrap();
+
+        let key = SealingKey::generate().unwrap();
+        seal_dir(&local, &shared, &key.public_key().unwrap()).unwrap();
+        assert!(!std::fs::read(shared.join("pages-1.img.sealed"))
+            .unwrap()
+            .windows(6)
+            .any(|window| window == b"memory"));
+
+        open_dir(&shared, &restored, &key).unwrap();
+        assert_eq!(
+            b"memory",
+            &std::fs::read(restored.join("pages-1.img")).unwrap()[..]
+        );
+
+        let other_key = SealingKey::generate().unwrap();
+        assert!(open_dir(&shared, &dir.join("other"), &other_key).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+use dashmap::DashMap;
+use plane_core::types::BackendId;
+use std::time::{Duration, Instant};
+
+/// The sizes of backends' writable filesystems, as last measured. Docker
+/// measures them by walking the filesystem, which is slow for large
+/// backends, so they are measured less often than other usage.
+pub struct DiskSizes {
+    sizes: DashMap<BackendId, (Instant, Option<u64>)>,
+    max_age: Duration,
+}
+
+impl DiskSizes {
+    pub fn new(max_age: Duration) -> Self {
+        DiskSizes {
+            sizes: DashMap::new(),
+            max_age,
+        }
+    }
+
+    /// The size last measured for a backend, unless it is due to be measured
+    /// again.
+    pub fn get(&self, backend: &BackendId, now: Instant) -> Option<Option<u64>> {
+        let entry = self.sizes.get(backend)?;
+        let (measured, size) = *entry;
+        if now.duration_since(measured) < self.max_age {
+            Some(size)
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(&self, backend: &BackendId, size: Option<u64>, now: Instant) {
+        self.sizes.insert(backend.clone(), (now, size));
+    }
+
+    pub fn remove(&self, backend: &BackendId) {
+        self.sizes.remove(backend);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_sizes_expire() {
+        let sizes = DiskSizes::new(Duration::from_secs(60));
+        let backend = BackendId::new("backend1".into());
+        let now = Instant::now();
+        assert_eq!(None, sizes.get(&backend, now));
+
+        sizes.insert(&backend, Some(1024), now);
+        assert_eq!(Some(Some(1024)), sizes.get(&backend, now));
+        assert_eq!(
+            Some(Some(1024)),
+            sizes.get(&backend, now + Duration::from_secs(59))
+        );
+        assert_eq!(None, sizes.get(&backend, now + Duration::from_secs(60)));
+
+        sizes.insert(&backend, Some(1024), now);
+        sizes.remove(&backend);
+        assert_eq!(None, sizes.get(&backend, now));
+    }
+}
@@ -0,0 +1,147 @@
+//! Enforcement of backend egress policies, using iptables rules.
+//!
+//! A backend with an egress policy does not get a network namespace of its
+//! own. Instead, the drone first runs a container from the configured egress
+//! image (which must provide `iptables`, `ip6tables` and `sleep`) to hold a
+//! network namespace, installs the policy's rules in it through Docker, and
+//! only then starts the backend in that namespace. The backend never runs
+//! with more network access than its policy allows, and the drone needs
+//! neither the iptables tools nor permission to enter other namespaces.
+
+use anyhow::{Context, Result};
+use plane_core::messages::agent::EgressPolicy;
+
+/// Destinations a backend may connect to, split into IPv4 and IPv6.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct AllowedDestinations {
+    v4: Vec<String>,
+    v6: Vec<String>,
+}
+
+impl AllowedDestinations {
+    fn add(&mut self, destination: String) {
+        if destination.contains(':') {
+            self.v6.push(destination);
+        } else {
+            self.v4.push(destination);
+        }
+    }
+}
+
+/// Resolve the destinations allowed by a policy. Hostnames are resolved once,
+/// when the backend starts.
+async fn allowed_destinations(policy: &EgressPolicy) -> Result<AllowedDestinations> {
+    let mut allowed = AllowedDestinations::default();
+
+    if let EgressPolicy::Allowlist { cidrs, hostnames } = policy {
+        for cidr in cidrs {
+            allowed.add(cidr.clone());
+        }
+
+        for hostname in hostnames {
+            let addrs = tokio::net::lookup_host((hostname.as_str(), 0))
+                .await
+                .with_context(|| format!("Error resolving allowed hostname {}.", hostname))?;
+            for addr in addrs {
+                allowed.add(addr.ip().to_string());
+            }
+        }
+    }
+
+    Ok(allowed)
+}
+
+fn rule(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// Arguments to iptables which restrict outbound traffic to the given
+/// destinations, while still allowing replies to inbound connections.
+fn iptables_rules(allowed: &[String]) -> Vec<Vec<String>> {
+    let mut rules = vec![
+        rule(&["-A", "OUTPUT", "-o", "lo", "-j", "ACCEPT"]),
+        rule(&[
+            "-A",
+            "OUTPUT",
+            "-m",
+            "conntrack",
+            "--ctstate",
+            "ESTABLISHED,RELATED",
+            "-j",
+            "ACCEPT",
+        ]),
+    ];
+
+    for destination in allowed {
+        rules.push(rule(&["-A", "OUTPUT", "-d", destination, "-j", "ACCEPT"]));
+    }
+
+    rules.push(rule(&["-P", "OUTPUT", "DROP"]));
+    rules
+}
+
+/// Commands which install the rules for `policy` in a network namespace.
+pub async fn egress_commands(policy: &EgressPolicy) -> Result<Vec<Vec<String>>> {
+    if *policy == EgressPolicy::AllowAll {
+        return Ok(Vec::new());
+    }
+
+    let allowed = allowed_destinations(policy).await?;
+    let mut commands = Vec::new();
+    for (program, allowed) in [("iptables", &allowed.v4), ("ip6tables", &allowed.v6)] {
+        for mut rule in iptables_rules(allowed) {
+            rule.insert(0, program.to_string());
+            commands.push(rule);
+        }
+    }
+
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_destinations() {
+        let mut allowed = AllowedDestinations::default();
+        allowed.add("10.0.0.0/8".into());
+        allowed.add("fd00::/8".into());
+        allowed.add("192.168.1.1".into());
+
+        assert_eq!(
+            AllowedDestinations {
+                v4: vec!["10.0.0.0/8".into(), "192.168.1.1".into()],
+                v6: vec!["fd00::/8".into()],
+            },
+            allowed
+        );
+    }
+
+    #[test]
+    fn test_iptables_rules() {
+        let rules = iptables_rules(&["10.0.0.0/8".to_string()]);
+
+        assert_eq!(4, rules.len());
+        assert_eq!(
+            vec!["-A", "OUTPUT", "-d", "10.0.0.0/8", "-j", "ACCEPT"],
+            rules[2]
+        );
+        assert_eq!(vec!["-P", "OUTPUT", "DROP"], rules[3]);
+    }
+
+    #[test]
+    fn test_egress_commands() {
+        futures::executor::block_on(async {
+            assert!(egress_commands(&EgressPolicy::AllowAll)
+                .await
+                .unwrap()
+                .is_empty());
+
+            let commands = egress_commands(&EgressPolicy::DenyAll).await.unwrap();
+            assert_eq!(6, commands.len());
+            assert_eq!(vec!["iptables", "-P", "OUTPUT", "DROP"], commands[2]);
+            assert_eq!(vec!["ip6tables", "-P", "OUTPUT", "DROP"], commands[5]);
+        });
+    }
+}
@@ -0,0 +1,285 @@
+//! Removal of images which no backend has used recently, once the disk
+//! holding Docker's data directory fills up. Only images which Plane pulled
+//! for a backend are ever removed.
+
+use crate::config::ImageGcConfig;
+use anyhow::{anyhow, Result};
+use bollard::{
+    container::ListContainersOptions,
+    image::{ListImagesOptions, RemoveImageOptions},
+    Docker,
+};
+use dashmap::DashMap;
+use plane_core::logging::LogError;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Percentage of a disk in use, given its total and available space.
+fn disk_usage_percent(total: u64, available: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+
+    100.0 * total.saturating_sub(available) as f64 / total as f64
+}
+
+pub struct ImageGc {
+    config: ImageGcConfig,
+
+    /// Last time each image Plane pulled, by ID, was used by a backend.
+    /// Images which are not in this map, such as those which were already
+    /// present when the drone started, are never removed.
+    last_used: DashMap<String, Instant>,
+
+    /// Number of spawns under way for each image reference. These images
+    /// are not removed, since their containers do not exist yet.
+    pending: Arc<DashMap<String, usize>>,
+
+    /// Total size of the images removed so far.
+    reclaimed_bytes: AtomicU64,
+}
+
+impl ImageGc {
+    pub fn new(config: ImageGcConfig) -> Self {
+        ImageGc {
+            config,
+            last_used: DashMap::default(),
+            pending: Arc::default(),
+            reclaimed_bytes: AtomicU64::default(),
+        }
+    }
+
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.reclaimed_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Record that a backend used an image which Plane pulled, by ID.
+    pub fn record_use(&self, image_id: &str, now: Instant) {
+        self.last_used.insert(image_id.to_string(), now);
+    }
+
+    /// Keep the image with the given reference from being removed until the
+    /// returned guard is dropped, once the spawn which needs it has created
+    /// its container.
+    pub fn pending_spawn(&self, image: &str) -> PendingSpawnGuard {
+        *self.pending.entry(image.to_string()).or_default() += 1;
+        PendingSpawnGuard {
+            pending: self.pending.clone(),
+            image: image.to_string(),
+        }
+    }
+
+    /// IDs of the images which pending spawns need, of those present.
+    async fn pending_image_ids(&self, docker: &Docker) -> HashSet<String> {
+        let images: Vec<String> = self
+            .pending
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        let mut ids = HashSet::new();
+        for image in images {
+            // Images which are not present yet are still being pulled.
+            if let Ok(Some(id)) = docker.inspect_image(&image).await.map(|image| image.id) {
+                ids.insert(id);
+            }
+        }
+
+        ids
+    }
+
+    /// Of the images present which Plane pulled, those which no backend has
+    /// used for long enough to be removed, given the images used by existing
+    /// containers.
+    fn unused_images(
+        &self,
+        images: &[String],
+        in_use: &HashSet<String>,
+        now: Instant,
+    ) -> Vec<String> {
+        // Forget images which have been removed by other means.
+        self.last_used.retain(|id, _| images.contains(id));
+
+        let unused_for = Duration::from_secs(self.config.unused_hours * 3600);
+        let mut unused = Vec::new();
+        for id in images {
+            let last_used = match self.last_used.get(id) {
+                Some(last_used) => *last_used,
+                None => continue,
+            };
+            if in_use.contains(id) {
+                self.record_use(id, now);
+                continue;
+            }
+
+            if now.saturating_duration_since(last_used) >= unused_for {
+                unused.push(id.clone());
+            }
+        }
+
+        unused
+    }
+
+    /// Remove unused images if the disk holding Docker's data directory is
+    /// fuller than allowed, or regardless if `force` is set.
+    pub async fn collect(&self, docker: &Docker, force: bool) -> Result<()> {
+        let containers = docker
+            .list_containers(Some(ListContainersOptions::<String> {
+                all: true,
+                ..ListContainersOptions::default()
+            }))
+            .await?;
+        let in_use: HashSet<String> = containers
+            .into_iter()
+            .filter_map(|container| container.image_id)
+            .collect();
+
+        let images = docker
+            .list_images(Some(ListImagesOptions::<String>::default()))
+            .await?;
+        let sizes: HashMap<String, u64> = images
+            .into_iter()
+            .map(|image| (image.id, image.size.max(0) as u64))
+            .collect();
+        let ids: Vec<String> = sizes.keys().cloned().collect();
+        let unused = self.unused_images(&ids, &in_use, Instant::now());
+
+        let data_dir = docker
+            .info()
+            .await?
+            .docker_root_dir
+            .ok_or_else(|| anyhow!("Docker did not report its data directory."))?;
+        let usage_percent = disk_usage_percent(
+            fs2::total_space(&data_dir)?,
+            fs2::available_space(&data_dir)?,
+        );
+        if usage_percent < self.config.disk_usage_percent as f64 && !force {
+            return Ok(());
+        }
+
+        tracing::info!(
+            %usage_percent,
+            num_images = unused.len(),
+            "Disk usage is over threshold; removing unused images."
+        );
+        for id in unused {
+            // Check just before removing each image, so that spawns which
+            // started since the images were listed keep theirs.
+            if self.pending_image_ids(docker).await.contains(&id) {
+                tracing::info!(%id, "Not removing image needed by a pending spawn.");
+                continue;
+            }
+
+            let options = RemoveImageOptions {
+                force: false,
+                noprune: false,
+            };
+            match docker.remove_image(&id, Some(options), None).await {
+                Ok(_) => {
+                    let size = sizes.get(&id).copied().unwrap_or_default();
+                    self.reclaimed_bytes.fetch_add(size, Ordering::Relaxed);
+                    self.last_used.remove(&id);
+                    tracing::info!(%id, size, "Removed unused image.");
+                }
+                Err(error) => tracing::warn!(?error, %id, "Error removing unused image."),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Periodically remove unused images.
+    pub async fn gc_loop(self: Arc<Self>, docker: Docker) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval_secs));
+
+        loop {
+            interval.tick().await;
+            self.collect(&docker, false)
+                .await
+                .log_error("Error removing unused images.");
+        }
+    }
+}
+
+/// Marks an image as needed by a pending spawn, until dropped.
+pub struct PendingSpawnGuard {
+    pending: Arc<DashMap<String, usize>>,
+    image: String,
+}
+
+impl Drop for PendingSpawnGuard {
+    fn drop(&mut self) {
+        self.pending.remove_if_mut(&self.image, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image_gc() -> ImageGc {
+        ImageGc::new(ImageGcConfig {
+            disk_usage_percent: 80,
+            unused_hours: 1,
+            interval_secs: 600,
+        })
+    }
+
+    #[test]
+    fn test_disk_usage_percent() {
+        assert_eq!(75.0, disk_usage_percent(400, 100));
+        assert_eq!(0.0, disk_usage_percent(400, 400));
+        assert_eq!(0.0, disk_usage_percent(0, 0));
+    }
+
+    #[test]
+    fn test_unused_images() {
+        let image_gc = image_gc();
+        let images = vec!["sha256:a".to_string(), "sha256:b".to_string()];
+        let in_use: HashSet<String> = vec!["sha256:a".to_string()].into_iter().collect();
+        let start = Instant::now();
+
+        // Images which Plane did not pull are never removed.
+        assert!(image_gc
+            .unused_images(&images, &in_use, start + Duration::from_secs(7200))
+            .is_empty());
+
+        image_gc.record_use("sha256:a", start);
+        image_gc.record_use("sha256:b", start);
+        assert!(image_gc.unused_images(&images, &in_use, start).is_empty());
+
+        let later = start + Duration::from_secs(3600);
+        assert_eq!(
+            vec!["sha256:b".to_string()],
+            image_gc.unused_images(&images, &in_use, later)
+        );
+
+        // Images used recently by a backend are kept.
+        image_gc.record_use("sha256:b", later);
+        let even_later = later + Duration::from_secs(60);
+        assert!(image_gc
+            .unused_images(&images, &HashSet::new(), even_later)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_pending_spawns() {
+        let image_gc = image_gc();
+        let guard1 = image_gc.pending_spawn("ubuntu:22.04");
+        let guard2 = image_gc.pending_spawn("ubuntu:22.04");
+
+        // The image stays pending until every spawn needing it is done.
+        drop(guard1);
+        assert!(image_gc.pending.contains_key("ubuntu:22.04"));
+        drop(guard2);
+        assert!(!image_gc.pending.contains_key("ubuntu:22.04"));
+    }
+}
@@ -1,35 +1,66 @@
+mod cgroup;
+mod checkpoint;
+mod disk_sizes;
+mod egress;
+mod image_gc;
+mod pull_progress;
+mod registry;
 mod util;
+use self::cgroup::CgroupSupport;
+use self::checkpoint::{checkpoint_name, DockerCheckpoints};
+use self::disk_sizes::DiskSizes;
+use self::egress::egress_commands;
+use self::image_gc::ImageGc;
+use self::pull_progress::PullProgress;
+use self::registry::cached_image_name;
 use self::util::{
-    get_ip_of_container, AllowNotFound, ContainerEvent, ContainerEventType, StatsStream,
+    get_ip_of_container, memory_bytes, AllowNotFound, ContainerEvent, ContainerEventType,
+    StatsStream,
 };
 use crate::{
     agent::{
-        engine::{Engine, EngineBackendStatus},
+        engine::{Engine, EngineBackendStatus, EngineResourceUsage, ImagePullProgress},
         engines::docker::util::{make_exposed_ports, MinuteExt},
+        metadata::{metadata_url, METADATA_HOST, METADATA_URL_ENV},
     },
-    config::{DockerConfig, DockerConnection},
+    config::{DockerConfig, DockerConnection, PullThroughCacheConfig},
 };
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use bollard::{
     auth::DockerCredentials,
     container::{
-        Config, CreateContainerOptions, LogOutput, LogsOptions, StartContainerOptions, Stats,
-        StatsOptions, StopContainerOptions,
+        Config, CreateContainerOptions, InspectContainerOptions, ListContainersOptions, LogOutput,
+        LogsOptions, StartContainerOptions, Stats, StatsOptions, StopContainerOptions,
+    },
+    exec::{CreateExecOptions, ResizeExecOptions, StartExecResults},
+    image::{CreateImageOptions, ListImagesOptions},
+    models::{
+        ContainerInspectResponse, ContainerSummary, HostConfig, PortBinding, ProgressDetail,
+        ResourcesUlimits,
     },
-    image::CreateImageOptions,
-    models::{HostConfig, PortBinding, ResourcesUlimits},
     system::EventsOptions,
     Docker, API_DEFAULT_VERSION,
 };
 use plane_core::{
-    messages::agent::ResourceLimits,
-    messages::agent::{BackendStatsMessage, DroneLogMessage, SpawnRequest},
+    error::PlaneError,
+    logging::LogError,
+    messages::agent::{AttachInput, AttachOutputChunk, EgressPolicy, ResourceLimits, TerminalSize},
+    messages::agent::{BackendStatsMessage, DroneLogMessage, ExecOutputChunk, SpawnRequest},
+    sealing::SealingKey,
     timing::Timer,
     types::BackendId,
 };
-use std::{collections::HashMap, time::Duration};
-use std::{net::SocketAddr, pin::Pin};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+};
+use tokio::io::AsyncWriteExt;
 use tokio_stream::{wrappers::IntervalStream, Stream, StreamExt};
 
 /// The port in the container which is exposed.
@@ -38,12 +69,57 @@ const DEFAULT_DOCKER_TIMEOUT_SECONDS: u64 = 30;
 /// Interval between reporting stats of a running backend.
 /// NOTE: the minimum possible interval is 1 second.
 const DEFAULT_DOCKER_STATS_INTERVAL_SECONDS: u64 = 10;
+/// Interval between measuring the size of a backend's writable filesystem,
+/// which is slower to measure than its other usage.
+const DISK_SIZE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Label of a network holder container naming the container whose network
+/// namespace it holds.
+const NETWORK_OF_LABEL: &str = "dev.plane.network_of";
+
+/// Name of the container holding the network namespace of the container
+/// `name`, if it has an egress policy.
+fn network_holder_name(name: &str) -> String {
+    format!("{}-net", name)
+}
+
+/// The backend a container managed by Plane belongs to. A network holder
+/// belongs to the backend whose network namespace it holds, so that it is
+/// stopped along with that backend, e.g. if the drone crashed between
+/// creating the two.
+fn backend_of_container(container: &ContainerSummary) -> Option<BackendId> {
+    let name = match container
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(NETWORK_OF_LABEL))
+    {
+        Some(name) => name.as_str(),
+        // Docker prefixes container names with a slash.
+        None => container.names.as_ref()?.first()?.trim_start_matches('/'),
+    };
+    BackendId::from_resource_name(name)
+}
 
 #[derive(Clone)]
 pub struct DockerInterface {
     docker: Docker,
     runtime: Option<String>,
     network: Option<String>,
+    pull_through_cache: Option<PullThroughCacheConfig>,
+    image_gc: Option<Arc<ImageGc>>,
+    checkpoints: Option<DockerCheckpoints>,
+
+    /// If provided, the port backends reach the agent's metadata API on.
+    metadata_port: Option<u16>,
+
+    /// Image run to hold the network namespace of backends with an egress
+    /// policy, in which the policy's rules are installed.
+    egress_image: Option<String>,
+
+    /// Progress of image pulls under way, by the backend pulling.
+    pull_progress: PullProgress,
+
+    disk_sizes: Arc<DiskSizes>,
 }
 
 impl DockerInterface {
@@ -61,13 +137,37 @@ impl DockerInterface {
             )?,
         };
 
+        let image_gc = config.image_gc.clone().map(|image_gc_config| {
+            let image_gc = Arc::new(ImageGc::new(image_gc_config));
+            tokio::spawn(image_gc.clone().gc_loop(docker.clone()));
+            image_gc
+        });
+
+        let checkpoints = config.checkpoint.as_ref().map(|checkpoint_config| {
+            DockerCheckpoints::new(config.connection.clone(), checkpoint_config)
+        });
+
         Ok(DockerInterface {
             docker,
             runtime: config.runtime.clone(),
             network: config.network.clone(),
+            pull_through_cache: config.pull_through_cache.clone(),
+            image_gc,
+            checkpoints,
+            metadata_port: None,
+            egress_image: config.egress_image.clone(),
+            pull_progress: PullProgress::default(),
+            disk_sizes: Arc::new(DiskSizes::new(DISK_SIZE_INTERVAL)),
         })
     }
 
+    /// Make the agent's metadata API, served on the given port of the host,
+    /// reachable from backends at `plane.internal`.
+    pub fn with_metadata_port(mut self, port: u16) -> Self {
+        self.metadata_port = Some(port);
+        self
+    }
+
     fn get_logs(
         &self,
         container_name: &str,
@@ -119,16 +219,55 @@ impl DockerInterface {
         })
     }
 
-    async fn pull_image(&self, image: &str, credentials: &Option<DockerCredentials>) -> Result<()> {
+    /// Pull an image, recording its progress while the pull is under way if
+    /// it is for a backend.
+    async fn pull_image(
+        &self,
+        image: &str,
+        credentials: &Option<DockerCredentials>,
+        backend: Option<&BackendId>,
+    ) -> Result<()> {
         let timer = Timer::new();
         let options = Some(CreateImageOptions {
             from_image: image,
             ..Default::default()
         });
 
+        let progress = backend.map(|backend| self.pull_progress.track(backend.clone()));
+        // Bytes downloaded and total bytes of each layer.
+        let mut layers: HashMap<String, (u64, u64)> = HashMap::new();
         let mut result = self.docker.create_image(options, None, credentials.clone());
         while let Some(next) = result.next().await {
-            next?;
+            let info = next?;
+            let layer = match info.id {
+                Some(layer) => layer,
+                None => continue,
+            };
+
+            match info.status.as_deref() {
+                Some("Downloading") => {
+                    if let Some(ProgressDetail {
+                        current: Some(current),
+                        total: Some(total),
+                    }) = info.progress_detail
+                    {
+                        layers.insert(layer, (current.max(0) as u64, total.max(0) as u64));
+                    }
+                }
+                Some("Download complete") => {
+                    if let Some((current, total)) = layers.get_mut(&layer) {
+                        *current = *total;
+                    }
+                }
+                _ => continue,
+            }
+
+            if let Some(progress) = &progress {
+                progress.update(ImagePullProgress {
+                    pulled_bytes: layers.values().map(|(current, _)| current).sum(),
+                    total_bytes: layers.values().map(|(_, total)| total).sum(),
+                });
+            }
         }
 
         tracing::info!(duration=?timer.duration(), ?image, "Pulled image.");
@@ -136,30 +275,301 @@ impl DockerInterface {
         Ok(())
     }
 
+    /// Pull an image, through the pull-through cache if one is configured for the
+    /// image's registry. Returns the reference of the image that was pulled.
+    async fn pull_image_through_cache(
+        &self,
+        image: &str,
+        credentials: &Option<DockerCredentials>,
+        backend: Option<&BackendId>,
+    ) -> Result<String> {
+        // Images which need credentials are always pulled directly, so that a
+        // shared cache never serves a private image to another tenant.
+        if credentials.is_none() {
+            if let Some(cache) = &self.pull_through_cache {
+                if let Some(cached_image) = cached_image_name(&cache.registries, image) {
+                    let cache_credentials: Option<DockerCredentials> =
+                        cache.credentials.as_ref().map(|d| d.into());
+                    match self
+                        .pull_image(&cached_image, &cache_credentials, backend)
+                        .await
+                    {
+                        Ok(()) => return Ok(cached_image),
+                        Err(error) => tracing::warn!(
+                            ?error,
+                            %cached_image,
+                            "Error pulling image through cache, pulling directly instead."
+                        ),
+                    }
+                }
+            }
+        }
+
+        self.pull_image(image, credentials, backend).await?;
+        Ok(image.to_string())
+    }
+
+    /// Record that a backend used an image which Plane pulled, so that it is
+    /// only garbage-collected once no backend has used it for a while.
+    async fn record_image_use(&self, image_gc: &ImageGc, image: &str) -> Result<()> {
+        let id = self
+            .docker
+            .inspect_image(image)
+            .await?
+            .id
+            .ok_or_else(|| anyhow!("Docker did not report the ID of image {}.", image))?;
+        image_gc.record_use(&id, Instant::now());
+
+        Ok(())
+    }
+
+    /// Stop and remove a container, along with the container holding its
+    /// network namespace, if it has one.
     pub async fn stop_container(&self, name: &str) -> Result<()> {
-        let options = StopContainerOptions { t: 10 };
+        for name in [name.to_string(), network_holder_name(name)] {
+            self.docker
+                .stop_container(&name, Some(StopContainerOptions { t: 10 }))
+                .await
+                .allow_not_found()?;
+            self.docker
+                .remove_container(&name, None)
+                .await
+                .allow_not_found()?;
+        }
 
-        self.docker
-            .stop_container(name, Some(options))
-            .await
-            .allow_not_found()?;
-        self.docker
-            .remove_container(name, None)
+        Ok(())
+    }
+
+    /// Run a command in a container to completion, failing unless it exits
+    /// successfully.
+    async fn exec_checked(&self, container: &str, command: Vec<String>) -> Result<()> {
+        let exec = self
+            .docker
+            .create_exec(
+                container,
+                CreateExecOptions {
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    cmd: Some(command.clone()),
+                    ..CreateExecOptions::default()
+                },
+            )
+            .await?;
+
+        let mut output = String::new();
+        if let StartExecResults::Attached {
+            output: mut stream, ..
+        } = self.docker.start_exec(&exec.id, None).await?
+        {
+            while let Some(chunk) = stream.next().await {
+                output.push_str(&chunk?.to_string());
+            }
+        }
+
+        match self.docker.inspect_exec(&exec.id).await?.exit_code {
+            Some(0) => Ok(()),
+            code => Err(anyhow!(
+                "{:?} exited with {:?}: {}",
+                command,
+                code,
+                output.trim()
+            )),
+        }
+    }
+
+    /// Run a container to hold the network namespace of the backend `name`,
+    /// with the rules of its egress policy installed, and return its ID. The
+    /// backend's port is published from this container.
+    async fn run_network_holder(&self, name: &str, egress_policy: &EgressPolicy) -> Result<String> {
+        let image = self.egress_image.as_deref().ok_or_else(|| {
+            anyhow!("Egress policies require egress_image to be configured in [agent.docker].")
+        })?;
+        let commands = egress_commands(egress_policy).await?;
+        if self.docker.inspect_image(image).await.is_err() {
+            self.pull_image_through_cache(image, &None, None).await?;
+        }
+
+        let holder_name = network_holder_name(name);
+        let config: Config<String> = Config {
+            image: Some(image.to_string()),
+            entrypoint: Some(vec!["sleep".to_string(), "infinity".to_string()]),
+            exposed_ports: make_exposed_ports(CONTAINER_PORT),
+            labels: Some(
+                vec![
+                    ("dev.plane.managed".to_string(), "true".to_string()),
+                    ("dev.plane.backend".to_string(), name.to_string()),
+                    (NETWORK_OF_LABEL.to_string(), name.to_string()),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+            host_config: Some(HostConfig {
+                port_bindings: Some(self.port_bindings()),
+                network_mode: self.network.clone(),
+                extra_hosts: self.extra_hosts(),
+                cap_add: Some(vec!["NET_ADMIN".to_string()]),
+                ..HostConfig::default()
+            }),
+            ..Config::default()
+        };
+        let id = self
+            .docker
+            .create_container(
+                Some(CreateContainerOptions {
+                    name: holder_name.clone(),
+                    platform: None,
+                }),
+                config,
+            )
+            .await?
+            .id;
+
+        let result: Result<()> = async {
+            self.docker
+                .start_container(&id, None::<StartContainerOptions<&str>>)
+                .await?;
+            for command in commands {
+                self.exec_checked(&id, command).await?;
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(error) = result {
+            self.stop_container(name).await?;
+            return Err(error);
+        }
+
+        Ok(id)
+    }
+
+    /// The address of a running container, which is that of the container
+    /// whose network namespace it joined, if any.
+    async fn container_ip(&self, container: &ContainerInspectResponse) -> Result<IpAddr> {
+        let network_mode = container
+            .host_config
+            .as_ref()
+            .and_then(|host_config| host_config.network_mode.as_deref());
+        match network_mode.and_then(|mode| mode.strip_prefix("container:")) {
+            Some(holder) => {
+                get_ip_of_container(&self.docker.inspect_container(holder, None).await?)
+            }
+            None => get_ip_of_container(container),
+        }
+    }
+
+    fn port_bindings(&self) -> HashMap<String, Option<Vec<PortBinding>>> {
+        vec![(
+            format!("{}/tcp", CONTAINER_PORT),
+            Some(vec![PortBinding {
+                host_ip: None,
+                host_port: Some("0".to_string()),
+            }]),
+        )]
+        .into_iter()
+        .collect()
+    }
+
+    fn extra_hosts(&self) -> Option<Vec<String>> {
+        self.metadata_port
+            .map(|_| vec![format!("{}:host-gateway", METADATA_HOST)])
+    }
+
+    fn checkpoints(&self) -> Result<&DockerCheckpoints> {
+        self.checkpoints.as_ref().ok_or_else(|| {
+            anyhow!("Checkpointing requires [agent.docker.checkpoint] to be configured.")
+        })
+    }
+
+    /// Pull a backend's image and run it, restoring it from a checkpoint if
+    /// one is given.
+    async fn create_backend(
+        &self,
+        spawn_request: &SpawnRequest,
+        checkpoint: Option<(&str, Option<Arc<SealingKey>>)>,
+    ) -> Result<()> {
+        // Keep the image from being garbage-collected until the container
+        // which uses it exists.
+        let _pending = self
+            .image_gc
+            .as_ref()
+            .map(|image_gc| image_gc.pending_spawn(&spawn_request.executable.image));
+        let image = self
+            .pull_image_through_cache(
+                &spawn_request.executable.image,
+                &spawn_request
+                    .executable
+                    .credentials
+                    .as_ref()
+                    .map(|d| d.into()),
+                Some(&spawn_request.backend_id),
+            )
             .await
-            .allow_not_found()?;
+            .map_err(|error| PlaneError::ImagePullFailed {
+                image: spawn_request.executable.image.clone(),
+                reason: error.to_string(),
+            })?;
+
+        let _pending_cached = self
+            .image_gc
+            .as_ref()
+            .filter(|_| image != spawn_request.executable.image)
+            .map(|image_gc| image_gc.pending_spawn(&image));
+        if let Some(image_gc) = &self.image_gc {
+            self.record_image_use(image_gc, &image)
+                .await
+                .log_error("Error recording image use.");
+        }
+
+        let backend_id = spawn_request.backend_id.to_resource_name();
+        self.run_container(
+            &backend_id,
+            &image,
+            &spawn_request.executable.env,
+            &spawn_request.executable.resource_limits,
+            &spawn_request.executable.egress_policy,
+            checkpoint,
+        )
+        .await?;
+        tracing::info!(%backend_id, "Container is running.");
 
         Ok(())
     }
 
     /// Run the specified image and return the name of the created container.
+    /// If a checkpoint is given, the container is restored from it.
+    #[allow(clippy::too_many_arguments)]
     async fn run_container(
         &self,
         name: &str,
         image: &str,
         env: &HashMap<String, String>,
         resource_limits: &ResourceLimits,
+        egress_policy: &EgressPolicy,
+        checkpoint: Option<(&str, Option<Arc<SealingKey>>)>,
     ) -> Result<()> {
-        let env: Vec<String> = env.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        resource_limits.validate()?;
+        if CgroupSupport::needs_check(resource_limits) {
+            let info = self.docker.info().await?;
+            CgroupSupport::from_info(&info).check(resource_limits)?;
+        }
+
+        let mut env: Vec<String> = env.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        if let Some(port) = self.metadata_port {
+            env.push(format!("{}={}", METADATA_URL_ENV, metadata_url(port)));
+        }
+
+        // Restrict outbound traffic before the backend starts, by running it
+        // in a network namespace which already has the policy's rules.
+        let network_holder = if *egress_policy == EgressPolicy::AllowAll {
+            None
+        } else {
+            let holder = self
+                .run_network_holder(name, egress_policy)
+                .await
+                .context("Error applying egress policy.")?;
+            tracing::info!(%name, ?egress_policy, "Applied egress policy.");
+            Some(holder)
+        };
 
         // Build the container.
         let container_id = {
@@ -172,7 +582,10 @@ impl DockerInterface {
             let config: Config<String> = Config {
                 image: Some(image.to_string()),
                 env: Some(env),
-                exposed_ports: make_exposed_ports(CONTAINER_PORT),
+                exposed_ports: match network_holder {
+                    Some(_) => None,
+                    None => make_exposed_ports(CONTAINER_PORT),
+                },
                 labels: Some(
                     vec![
                         ("dev.plane.managed".to_string(), "true".to_string()),
@@ -182,33 +595,31 @@ impl DockerInterface {
                     .collect(),
                 ),
                 host_config: Some(HostConfig {
-                    port_bindings: Some(
-                        vec![(
-                            format!("{}/tcp", CONTAINER_PORT),
-                            Some(vec![PortBinding {
-                                host_ip: None,
-                                host_port: Some("0".to_string()),
-                            }]),
-                        )]
-                        .into_iter()
-                        .collect(),
-                    ),
-                    network_mode: self.network.clone(),
+                    // A container which joins another's network namespace
+                    // shares its ports and hosts.
+                    port_bindings: match network_holder {
+                        Some(_) => None,
+                        None => Some(self.port_bindings()),
+                    },
+                    network_mode: match &network_holder {
+                        Some(holder) => Some(format!("container:{}", holder)),
+                        None => self.network.clone(),
+                    },
+                    extra_hosts: match network_holder {
+                        Some(_) => None,
+                        None => self.extra_hosts(),
+                    },
                     runtime: self.runtime.clone(),
                     cpu_period: resource_limits
                         .cpu_period
                         .map(|cpu_period| cpu_period.as_micros() as i64),
                     cpu_quota: resource_limits
-                        .cpu_period_percent
-                        .and_then(|cpu_period_percent| {
-                            let cpu_period = resource_limits
-                                .cpu_period
-                                .unwrap_or(std::time::Duration::from_millis(100));
-                            cpu_period
-                                .saturating_mul(cpu_period_percent as u32)
-                                .checked_div(100)
-                                .map(|cpu_period_time| cpu_period_time.as_micros() as i64)
-                        }),
+                        .effective_cpu_quota()
+                        .map(|cpu_quota| cpu_quota.as_micros() as i64),
+                    cpu_shares: resource_limits.cpu_shares.map(i64::from),
+                    blkio_weight: resource_limits.io_weight,
+                    pids_limit: resource_limits.pids_limit,
+                    memory: resource_limits.memory_limit_bytes,
                     ulimits: resource_limits.cpu_time_limit.map(|cpu_time_limit| {
                         vec![ResourcesUlimits {
                             name: Some("cpu".to_string()),
@@ -221,7 +632,13 @@ impl DockerInterface {
                 ..Config::default()
             };
 
-            let result = self.docker.create_container(options, config).await?;
+            let result = match self.docker.create_container(options, config).await {
+                Ok(result) => result,
+                Err(error) => {
+                    self.stop_container(name).await?;
+                    return Err(error.into());
+                }
+            };
             tracing::info!(duration=?timer.duration(), %image, "Created container.");
             result.id
         };
@@ -229,9 +646,23 @@ impl DockerInterface {
         // Start the container.
         {
             let timer = Timer::new();
-            let options: Option<StartContainerOptions<&str>> = None;
 
-            self.docker.start_container(&container_id, options).await?;
+            match checkpoint {
+                Some((checkpoint, key)) => {
+                    let result = self
+                        .checkpoints()?
+                        .start(&container_id, checkpoint, key)
+                        .await;
+                    if let Err(error) = result {
+                        self.stop_container(name).await?;
+                        return Err(error.context("Error restoring container from checkpoint."));
+                    }
+                }
+                None => {
+                    let options: Option<StartContainerOptions<&str>> = None;
+                    self.docker.start_container(&container_id, options).await?;
+                }
+            }
             tracing::info!(duation=?timer.duration(), %container_id, "Started container.");
         };
 
@@ -253,10 +684,13 @@ impl Engine for DockerInterface {
             .filter_map(|event| match event {
                 Ok(event) => {
                     let event = ContainerEvent::from_event_message(&event)?;
-                    if event.event == ContainerEventType::Die {
-                        BackendId::from_resource_name(&event.name)
-                    } else {
-                        None
+                    match event.event {
+                        ContainerEventType::Die => BackendId::from_resource_name(&event.name),
+                        ContainerEventType::Oom => {
+                            tracing::warn!(name=%event.name, "Container ran out of memory.");
+                            BackendId::from_resource_name(&event.name)
+                        }
+                        _ => None,
                     }
                 }
                 Err(error) => {
@@ -269,27 +703,35 @@ impl Engine for DockerInterface {
     }
 
     async fn load(&self, spawn_request: &SpawnRequest) -> Result<()> {
-        self.pull_image(
-            &spawn_request.executable.image,
-            &spawn_request
-                .executable
-                .credentials
-                .as_ref()
-                .map(|d| d.into()),
-        )
-        .await?;
+        self.create_backend(spawn_request, None).await
+    }
 
-        let backend_id = spawn_request.backend_id.to_resource_name();
-        self.run_container(
-            &backend_id,
-            &spawn_request.executable.image,
-            &spawn_request.executable.env,
-            &spawn_request.executable.resource_limits,
-        )
-        .await?;
-        tracing::info!(%backend_id, "Container is running.");
+    fn image_pull_progress(&self, backend: &BackendId) -> Option<ImagePullProgress> {
+        self.pull_progress.get(backend)
+    }
 
-        Ok(())
+    async fn checkpoint(&self, backend: &BackendId, recipient: &str) -> Result<String> {
+        let checkpoint = checkpoint_name(backend);
+        self.checkpoints()?
+            .create(&backend.to_resource_name(), &checkpoint, recipient)
+            .await?;
+        tracing::info!(%backend, %checkpoint, "Checkpointed container.");
+
+        Ok(checkpoint)
+    }
+
+    async fn restore(
+        &self,
+        spawn_request: &SpawnRequest,
+        checkpoint: &str,
+        key: Option<Arc<SealingKey>>,
+    ) -> Result<()> {
+        self.create_backend(spawn_request, Some((checkpoint, key)))
+            .await
+    }
+
+    async fn discard_checkpoint(&self, checkpoint: &str) -> Result<()> {
+        self.checkpoints()?.discard(checkpoint).await
     }
 
     async fn backend_status(&self, backend: &BackendId) -> Result<EngineBackendStatus> {
@@ -311,19 +753,66 @@ impl Engine for DockerInterface {
             .ok_or_else(|| anyhow!("State found but no running field for container."))?;
 
         if running {
-            let ip = get_ip_of_container(&container)?;
+            let ip = self.container_ip(&container).await?;
             let addr = SocketAddr::new(ip, CONTAINER_PORT);
 
             Ok(EngineBackendStatus::Running { addr })
+        } else if state.oom_killed == Some(true) {
+            Ok(EngineBackendStatus::OutOfMemory)
         } else {
             match state.exit_code {
                 None => Ok(EngineBackendStatus::Terminated),
                 Some(0) => Ok(EngineBackendStatus::Exited),
-                Some(_) => Ok(EngineBackendStatus::Failed),
+                Some(exit_code) => Ok(EngineBackendStatus::Failed { exit_code }),
             }
         }
     }
 
+    async fn usage(&self, backend: &BackendId) -> Result<EngineResourceUsage> {
+        let container_name = backend.to_resource_name();
+
+        // A one-shot sample returns immediately, but without the previous
+        // sample needed to compute a CPU percentage, so CPU time is reported.
+        let stats = self
+            .docker
+            .stats(
+                &container_name,
+                Some(StatsOptions {
+                    stream: false,
+                    one_shot: true,
+                }),
+            )
+            .next()
+            .await
+            .transpose()?;
+
+        let now = Instant::now();
+        let disk_bytes = match self.disk_sizes.get(backend, now) {
+            Some(disk_bytes) => disk_bytes,
+            None => {
+                let disk_bytes = self
+                    .docker
+                    .inspect_container(
+                        &container_name,
+                        Some(InspectContainerOptions { size: true }),
+                    )
+                    .await?
+                    .size_rw
+                    .and_then(|size_rw| u64::try_from(size_rw).ok());
+                self.disk_sizes.insert(backend, disk_bytes, now);
+                disk_bytes
+            }
+        };
+
+        Ok(EngineResourceUsage {
+            memory_bytes: stats.as_ref().and_then(memory_bytes),
+            cpu_time: stats
+                .as_ref()
+                .map(|stats| Duration::from_nanos(stats.cpu_stats.cpu_usage.total_usage)),
+            disk_bytes,
+        })
+    }
+
     fn log_stream(
         &self,
         backend: &BackendId,
@@ -348,7 +837,246 @@ impl Engine for DockerInterface {
         Box::pin(StatsStream::new(backend, stream))
     }
 
+    async fn exec(
+        &self,
+        backend: &BackendId,
+        command: &[String],
+    ) -> Result<Pin<Box<dyn Stream<Item = ExecOutputChunk> + Send>>> {
+        let exec = self
+            .docker
+            .create_exec(
+                &backend.to_resource_name(),
+                CreateExecOptions {
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    cmd: Some(command.to_vec()),
+                    ..CreateExecOptions::default()
+                },
+            )
+            .await?;
+
+        let output = match self.docker.start_exec(&exec.id, None).await? {
+            StartExecResults::Attached { output, .. } => output,
+            StartExecResults::Detached => {
+                return Err(anyhow!("Docker did not attach to the exec output."))
+            }
+        };
+
+        // Once the output ends, the exit code is available from Docker.
+        let docker = self.docker.clone();
+        let exit = futures::stream::once(async move {
+            let code = match docker.inspect_exec(&exec.id).await {
+                Ok(inspect) => inspect.exit_code,
+                Err(error) => {
+                    tracing::warn!(?error, "Error inspecting exec.");
+                    None
+                }
+            };
+            ExecOutputChunk::Exit { code }
+        });
+
+        let output =
+            output.filter_map(|v| v.ok().as_ref().and_then(ExecOutputChunk::from_log_message));
+        Ok(Box::pin(output.chain(exit)))
+    }
+
+    async fn attach(
+        &self,
+        backend: &BackendId,
+        command: &[String],
+        size: TerminalSize,
+        mut input: Pin<Box<dyn Stream<Item = AttachInput> + Send>>,
+    ) -> Result<Pin<Box<dyn Stream<Item = AttachOutputChunk> + Send>>> {
+        let exec = self
+            .docker
+            .create_exec(
+                &backend.to_resource_name(),
+                CreateExecOptions {
+                    attach_stdin: Some(true),
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    tty: Some(true),
+                    cmd: Some(command.to_vec()),
+                    ..CreateExecOptions::default()
+                },
+            )
+            .await?;
+
+        let (output, mut stdin) = match self.docker.start_exec(&exec.id, None).await? {
+            StartExecResults::Attached { output, input } => (output, input),
+            StartExecResults::Detached => {
+                return Err(anyhow!("Docker did not attach to the exec output."))
+            }
+        };
+
+        // The TTY can only be resized once the exec has started.
+        let resize = |size: TerminalSize| ResizeExecOptions {
+            width: size.cols,
+            height: size.rows,
+        };
+        self.docker.resize_exec(&exec.id, resize(size)).await?;
+
+        let docker = self.docker.clone();
+        let exec_id = exec.id.clone();
+        let input_task = tokio::spawn(async move {
+            while let Some(message) = input.next().await {
+                match message {
+                    AttachInput::Data(data) => {
+                        if let Err(error) = stdin.write_all(&data).await {
+                            tracing::warn!(?error, "Error writing to attached TTY.");
+                            break;
+                        }
+                    }
+                    AttachInput::Resize(size) => docker
+                        .resize_exec(&exec_id, resize(size))
+                        .await
+                        .log_error("Error resizing attached TTY."),
+                    AttachInput::Detach => break,
+                }
+            }
+
+            // Close stdin, so that a shell reading it exits.
+            stdin
+                .shutdown()
+                .await
+                .log_error("Error closing attached TTY.");
+        });
+
+        // Once the output ends, the exit code is available from Docker.
+        let docker = self.docker.clone();
+        let exit = futures::stream::once(async move {
+            input_task.abort();
+            let code = match docker.inspect_exec(&exec.id).await {
+                Ok(inspect) => inspect.exit_code,
+                Err(error) => {
+                    tracing::warn!(?error, "Error inspecting exec.");
+                    None
+                }
+            };
+            AttachOutputChunk::Exit { code }
+        });
+
+        let output = output.filter_map(|v| match v.ok()? {
+            LogOutput::StdOut { message }
+            | LogOutput::StdErr { message }
+            | LogOutput::Console { message } => Some(AttachOutputChunk::Data(message.to_vec())),
+            LogOutput::StdIn { .. } => None,
+        });
+        Ok(Box::pin(output.chain(exit)))
+    }
+
     async fn stop(&self, backend: &BackendId) -> Result<()> {
+        self.disk_sizes.remove(backend);
         self.stop_container(&backend.to_resource_name()).await
     }
+
+    async fn list_backends(&self) -> Result<Vec<BackendId>> {
+        let options = ListContainersOptions {
+            all: true,
+            filters: vec![("label", vec!["dev.plane.managed=true"])]
+                .into_iter()
+                .collect(),
+            ..ListContainersOptions::default()
+        };
+
+        let containers = self.docker.list_containers(Some(options)).await?;
+
+        // A backend with an egress policy has two containers.
+        let backends: HashSet<BackendId> =
+            containers.iter().filter_map(backend_of_container).collect();
+        Ok(backends.into_iter().collect())
+    }
+
+    async fn seed_image(&self, image: &str) -> Result<()> {
+        self.pull_image_through_cache(image, &None, None).await?;
+        Ok(())
+    }
+
+    async fn data_dir(&self) -> Result<String> {
+        self.docker
+            .info()
+            .await?
+            .docker_root_dir
+            .ok_or_else(|| anyhow!("Docker did not report its data directory."))
+    }
+
+    fn reclaimed_image_bytes(&self) -> Option<u64> {
+        self.image_gc
+            .as_ref()
+            .map(|image_gc| image_gc.reclaimed_bytes())
+    }
+
+    async fn collect_unused_images(&self) -> Result<()> {
+        match &self.image_gc {
+            Some(image_gc) => image_gc.collect(&self.docker, true).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn image_names(&self) -> Result<Vec<String>> {
+        let images = self
+            .docker
+            .list_images(Some(ListImagesOptions::<String>::default()))
+            .await?;
+
+        let mut names: Vec<String> = images
+            .into_iter()
+            .flat_map(|image| image.repo_tags)
+            .filter(|tag| tag != "<none>:<none>")
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    async fn image_digests(&self) -> Result<Vec<String>> {
+        let images = self
+            .docker
+            .list_images(Some(ListImagesOptions::<String>::default()))
+            .await?;
+
+        let mut digests: Vec<String> = images
+            .into_iter()
+            .flat_map(|image| image.repo_digests)
+            .filter(|digest| digest != "<none>@<none>")
+            .collect();
+        digests.sort();
+        Ok(digests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(name: &str, labels: &[(&str, &str)]) -> ContainerSummary {
+        ContainerSummary {
+            names: Some(vec![format!("/{}", name)]),
+            labels: Some(
+                labels
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+            ),
+            ..ContainerSummary::default()
+        }
+    }
+
+    #[test]
+    fn test_backend_of_container() {
+        let backend = BackendId::new("backend1".into());
+        let name = backend.to_resource_name();
+
+        assert_eq!(
+            Some(backend.clone()),
+            backend_of_container(&container(&name, &[("dev.plane.managed", "true")]))
+        );
+        assert_eq!(
+            Some(backend),
+            backend_of_container(&container(
+                &network_holder_name(&name),
+                &[("dev.plane.managed", "true"), (NETWORK_OF_LABEL, &name)]
+            ))
+        );
+        assert_eq!(None, backend_of_container(&container("postgres", &[])));
+    }
 }
@@ -0,0 +1,82 @@
+use crate::agent::engine::ImagePullProgress;
+use dashmap::DashMap;
+use plane_core::types::BackendId;
+use std::sync::Arc;
+
+/// Progress of the image pulls under way, by the backend each is for.
+/// Backends which pull the same image at once each pull it themselves, so
+/// each records its own progress.
+#[derive(Clone, Default)]
+pub struct PullProgress {
+    pulls: Arc<DashMap<BackendId, ImagePullProgress>>,
+}
+
+impl PullProgress {
+    /// Start recording the progress of a pull for `backend`, until the
+    /// returned guard is dropped.
+    pub fn track(&self, backend: BackendId) -> PullProgressGuard {
+        PullProgressGuard {
+            pulls: self.pulls.clone(),
+            backend,
+        }
+    }
+
+    pub fn get(&self, backend: &BackendId) -> Option<ImagePullProgress> {
+        self.pulls.get(backend).map(|progress| *progress)
+    }
+}
+
+/// Records the progress of an image pull, and removes it when the pull
+/// finishes or is cancelled.
+pub struct PullProgressGuard {
+    pulls: Arc<DashMap<BackendId, ImagePullProgress>>,
+    backend: BackendId,
+}
+
+impl PullProgressGuard {
+    pub fn update(&self, progress: ImagePullProgress) {
+        self.pulls.insert(self.backend.clone(), progress);
+    }
+}
+
+impl Drop for PullProgressGuard {
+    fn drop(&mut self) {
+        self.pulls.remove(&self.backend);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(pulled_bytes: u64) -> ImagePullProgress {
+        ImagePullProgress {
+            pulled_bytes,
+            total_bytes: 100,
+        }
+    }
+
+    #[test]
+    fn test_concurrent_pulls() {
+        let pull_progress = PullProgress::default();
+        let backend1 = BackendId::new("backend1".into());
+        let backend2 = BackendId::new("backend2".into());
+
+        let guard1 = pull_progress.track(backend1.clone());
+        let guard2 = pull_progress.track(backend2.clone());
+        assert_eq!(None, pull_progress.get(&backend1));
+
+        guard1.update(progress(10));
+        guard2.update(progress(20));
+        assert_eq!(Some(progress(10)), pull_progress.get(&backend1));
+        assert_eq!(Some(progress(20)), pull_progress.get(&backend2));
+
+        // One pull finishing does not end the other's progress.
+        drop(guard1);
+        assert_eq!(None, pull_progress.get(&backend1));
+        assert_eq!(Some(progress(20)), pull_progress.get(&backend2));
+
+        drop(guard2);
+        assert_eq!(None, pull_progress.get(&backend2));
+    }
+}
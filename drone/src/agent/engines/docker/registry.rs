@@ -0,0 +1,76 @@
+//! Rewriting of image references so that they are pulled through a cache registry.
+
+use std::collections::HashMap;
+
+/// Registry used by Docker when an image reference does not name one.
+const DEFAULT_REGISTRY: &str = "docker.io";
+
+/// Split an image reference into its registry host and the remainder of the
+/// reference, normalized the way Docker resolves it.
+fn split_registry(image: &str) -> (&str, String) {
+    match image.split_once('/') {
+        Some(("index.docker.io", rest)) => (DEFAULT_REGISTRY, rest.to_string()),
+        Some((first, rest))
+            if first.contains('.') || first.contains(':') || first == "localhost" =>
+        {
+            (first, rest.to_string())
+        }
+        Some(_) => (DEFAULT_REGISTRY, image.to_string()),
+        None => (DEFAULT_REGISTRY, format!("library/{}", image)),
+    }
+}
+
+/// Returns the reference under which an image is available from the cache
+/// registry, if the cache mirrors the image's upstream registry.
+pub fn cached_image_name(registries: &HashMap<String, String>, image: &str) -> Option<String> {
+    let (registry, path) = split_registry(image);
+    let mirror = registries.get(registry)?;
+
+    Some(format!("{}/{}", mirror.trim_end_matches('/'), path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registries() -> HashMap<String, String> {
+        vec![
+            ("docker.io".into(), "cache.internal:5000/dockerhub".into()),
+            ("ghcr.io".into(), "cache.internal:5000/ghcr/".into()),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn test_docker_hub_images() {
+        assert_eq!(
+            Some("cache.internal:5000/dockerhub/library/nginx:latest".to_string()),
+            cached_image_name(&registries(), "nginx:latest")
+        );
+        assert_eq!(
+            Some("cache.internal:5000/dockerhub/jane/app".to_string()),
+            cached_image_name(&registries(), "jane/app")
+        );
+        assert_eq!(
+            Some("cache.internal:5000/dockerhub/jane/app".to_string()),
+            cached_image_name(&registries(), "index.docker.io/jane/app")
+        );
+    }
+
+    #[test]
+    fn test_other_registries() {
+        assert_eq!(
+            Some("cache.internal:5000/ghcr/drifting-in-space/test-image:latest".to_string()),
+            cached_image_name(&registries(), "ghcr.io/drifting-in-space/test-image:latest")
+        );
+        assert_eq!(
+            None,
+            cached_image_name(&registries(), "localhost:5000/my-image")
+        );
+        assert_eq!(
+            None,
+            cached_image_name(&registries(), "registry.example.com/my-image")
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::{anyhow, Result};
-use bollard::container::Stats;
+use bollard::container::{MemoryStatsStats, Stats};
 use bollard::service::{ContainerInspectResponse, EventMessage};
 use futures::Stream;
 use plane_core::{messages::agent::BackendStatsMessage, types::BackendId};
@@ -20,6 +20,17 @@ pub fn make_exposed_ports(port: u16) -> Option<HashMap<String, HashMap<(), ()>>>
     Some(vec![(format!("{}/tcp", port), dummy)].into_iter().collect())
 }
 
+/// Memory used by a container, not counting the page cache, in bytes.
+pub fn memory_bytes(stats: &Stats) -> Option<u64> {
+    let usage = stats.memory_stats.usage?;
+    let cache = match stats.memory_stats.stats? {
+        MemoryStatsStats::V1(stats) => stats.cache,
+        MemoryStatsStats::V2(stats) => stats.inactive_file,
+    };
+
+    Some(usage.saturating_sub(cache))
+}
+
 /// Helper trait for swallowing Docker not found errors.
 pub trait AllowNotFound {
     /// Swallow a result if it is a success result or a NotFound; propagate it otherwise.
@@ -125,16 +136,30 @@ impl ContainerEvent {
     }
 }
 
+/// Parse the first non-empty address among `candidates`.
+fn first_ip(candidates: &[&Option<String>]) -> Result<Option<IpAddr>> {
+    for ip in candidates.iter().copied().flatten() {
+        if !ip.is_empty() {
+            return Ok(Some(ip.parse()?));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Get the address of a container. IPv4 addresses are preferred, falling back
+/// to the global IPv6 address for containers on IPv6-only networks.
 pub fn get_ip_of_container(inspect_response: &ContainerInspectResponse) -> Result<IpAddr> {
     let network_settings = inspect_response
         .network_settings
         .as_ref()
         .ok_or_else(|| anyhow!("Inspect did not return network settings."))?;
 
-    if let Some(ip_addr) = network_settings.ip_address.as_ref() {
-        if !ip_addr.is_empty() {
-            return Ok(ip_addr.parse()?);
-        }
+    if let Some(ip) = first_ip(&[
+        &network_settings.ip_address,
+        &network_settings.global_ipv6_address,
+    ])? {
+        return Ok(ip);
     }
 
     let networks = network_settings
@@ -154,12 +179,8 @@ pub fn get_ip_of_container(inspect_response: &ContainerInspectResponse) -> Resul
         .next()
         .expect("next() should never fail after length check.");
 
-    let ip = network
-        .ip_address
-        .as_ref()
-        .ok_or_else(|| anyhow!("One network found, but did not have IP address."))?;
-
-    Ok(ip.parse()?)
+    first_ip(&[&network.ip_address, &network.global_ipv6_address])?
+        .ok_or_else(|| anyhow!("One network found, but did not have IP address."))
 }
 
 pub struct StatsStream<T: Stream<Item = Stats> + Unpin> {
@@ -1 +1,3 @@
+#[cfg(feature = "containerd")]
+pub mod containerd;
 pub mod docker;
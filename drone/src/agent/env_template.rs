@@ -0,0 +1,158 @@
+//! Expansion of template variables in backend environment variables.
+//!
+//! Values may refer to the spawn context as `{{backend_id}}`, `{{drone_id}}`,
+//! `{{cluster}}`, `{{backend_hostname}}` or `{{backend_url}}`, so that a
+//! backend can learn its own public URL. Whitespace inside the braces is
+//! ignored. Anything else in braces, including an unterminated `{{`, is left
+//! as it is, since values may use the same syntax for templates of their own.
+
+use plane_core::{
+    messages::agent::SpawnRequest,
+    types::{BackendId, ClusterName, DroneId},
+};
+use std::collections::HashMap;
+
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+
+pub struct SpawnContext<'a> {
+    pub backend_id: &'a BackendId,
+    pub drone_id: &'a DroneId,
+    pub cluster: &'a ClusterName,
+
+    /// The backend's URL as configured for its cluster, if the controller
+    /// provided it.
+    pub backend_url: Option<&'a str>,
+}
+
+impl<'a> SpawnContext<'a> {
+    pub fn new(spawn_request: &'a SpawnRequest, cluster: &'a ClusterName) -> Self {
+        SpawnContext {
+            backend_id: &spawn_request.backend_id,
+            drone_id: &spawn_request.drone_id,
+            cluster,
+            backend_url: spawn_request.backend_url.as_deref(),
+        }
+    }
+
+    fn hostname(&self) -> String {
+        format!("{}.{}", self.backend_id.id(), self.cluster.hostname())
+    }
+
+    fn variable(&self, name: &str) -> Option<String> {
+        match name {
+            "backend_id" => Some(self.backend_id.id().to_string()),
+            "drone_id" => Some(self.drone_id.id().to_string()),
+            "cluster" => Some(self.cluster.hostname().to_string()),
+            "backend_hostname" => Some(self.hostname()),
+            // Without a configured URL, proxies are expected to serve HTTPS
+            // on the default port.
+            "backend_url" => Some(match self.backend_url {
+                Some(url) => url.to_string(),
+                None => format!("https://{}/", self.hostname()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Expand the template variables in each value of `env`.
+pub fn expand_env(
+    env: &HashMap<String, String>,
+    context: &SpawnContext,
+) -> HashMap<String, String> {
+    env.iter()
+        .map(|(key, value)| (key.clone(), expand(value, context)))
+        .collect()
+}
+
+fn expand(template: &str, context: &SpawnContext) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find(OPEN) {
+        let after_open = &rest[start + OPEN.len()..];
+        let end = match after_open.find(CLOSE) {
+            Some(end) => end,
+            None => break,
+        };
+        result.push_str(&rest[..start]);
+        match context.variable(after_open[..end].trim()) {
+            Some(value) => result.push_str(&value),
+            None => result.push_str(&rest[start..start + OPEN.len() + end + CLOSE.len()]),
+        }
+        rest = &after_open[end + CLOSE.len()..];
+    }
+    result.push_str(rest);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_with_url(template: &str, backend_url: Option<&str>) -> String {
+        let backend_id = BackendId::new("abc123".into());
+        let drone_id = DroneId::new("drone1".into());
+        let cluster = ClusterName::new("plane.test");
+        let context = SpawnContext {
+            backend_id: &backend_id,
+            drone_id: &drone_id,
+            cluster: &cluster,
+            backend_url,
+        };
+
+        expand(template, &context)
+    }
+
+    fn expand_with_context(template: &str) -> String {
+        expand_with_url(template, None)
+    }
+
+    #[test]
+    fn test_expand_variables() {
+        assert_eq!(
+            "https://abc123.plane.test/",
+            expand_with_context("{{backend_url}}")
+        );
+        assert_eq!(
+            "abc123 on drone1 in plane.test",
+            expand_with_context("{{ backend_id }} on {{drone_id}} in {{cluster}}")
+        );
+        assert_eq!(
+            "host=abc123.plane.test;",
+            expand_with_context("host={{backend_hostname}};")
+        );
+    }
+
+    #[test]
+    fn test_configured_backend_url() {
+        assert_eq!(
+            "http://plane.test:8080/backends/abc123/app/",
+            expand_with_url(
+                "{{backend_url}}",
+                Some("http://plane.test:8080/backends/abc123/app/")
+            )
+        );
+    }
+
+    #[test]
+    fn test_plain_values_unchanged() {
+        assert_eq!("plain value", expand_with_context("plain value"));
+        assert_eq!("{not a template}", expand_with_context("{not a template}"));
+    }
+
+    #[test]
+    fn test_unknown_templates_unchanged() {
+        assert_eq!("{{ nope }}", expand_with_context("{{ nope }}"));
+        assert_eq!(
+            "{{.Values.x}} abc123",
+            expand_with_context("{{.Values.x}} {{backend_id}}")
+        );
+        assert_eq!(
+            "abc123 {{backend_id",
+            expand_with_context("{{backend_id}} {{backend_id")
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! Running commands inside backends, for debugging: one-off commands with
+//! `plane-cli exec`, and interactive terminals with `plane-cli attach`.
+
+use super::engine::Engine;
+use anyhow::anyhow;
+use plane_core::{
+    logging::LogError,
+    messages::agent::{
+        AttachInput, AttachInputMessage, AttachOutputMessage, AttachRequest, ExecOutputChunk,
+        ExecOutputMessage, ExecRequest, ExecResponse,
+    },
+    nats::{MessageWithResponseHandle, TypedMessage, TypedNats},
+    signing::{SeenNonces, VerifyingKey},
+    subjects,
+    types::ClusterName,
+    NeverResult,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::time::timeout;
+use tokio_stream::StreamExt;
+
+/// Who may run commands inside backends, and for how long.
+#[derive(Clone, Debug)]
+pub struct ExecAccess {
+    /// Requests must be signed with the private key matching this one.
+    pub public_key: VerifyingKey,
+
+    /// How long an attached terminal may go without input before it is
+    /// detached, and how long a command may go without output before its
+    /// output is no longer published.
+    pub idle_timeout: Duration,
+}
+
+/// Listen for requests to run commands in backends running on this drone.
+pub async fn listen_for_exec_requests<E: Engine + Clone>(
+    engine: E,
+    nats: TypedNats,
+    cluster: ClusterName,
+    access: Option<ExecAccess>,
+) -> NeverResult {
+    let mut sub = nats
+        .subscribe(ExecRequest::subscribe_subject(&cluster))
+        .await?;
+    tracing::info!("Listening for exec requests.");
+
+    let seen_nonces = SeenNonces::default();
+    while let Some(req) = sub.next().await {
+        let backend = req.value.backend_id.clone();
+        match engine.list_backends().await {
+            Ok(backends) if backends.contains(&backend) => (),
+            // The backend belongs to another drone, which responds instead.
+            Ok(_) => continue,
+            Err(error) => {
+                tracing::error!(?error, %backend, "Error listing backends.");
+                continue;
+            }
+        }
+
+        let idle_timeout =
+            match check_exec_access(access.as_ref(), &req, &req.value.exec_id, &seen_nonces) {
+                Ok(access) => access.idle_timeout,
+                Err(reason) => {
+                    tracing::warn!(%backend, %reason, "Refused exec request.");
+                    req.respond(&ExecResponse::Failed { reason }).await?;
+                    continue;
+                }
+            };
+
+        tracing::info!(%backend, command=?req.value.command, "Running command in backend.");
+        let mut output = match engine.exec(&backend, &req.value.command).await {
+            Ok(output) => output,
+            Err(error) => {
+                tracing::warn!(?error, %backend, "Error running command in backend.");
+                req.respond(&ExecResponse::Failed {
+                    reason: error.to_string(),
+                })
+                .await?;
+                continue;
+            }
+        };
+        req.respond(&ExecResponse::Started).await?;
+
+        let nats = nats.clone();
+        tokio::spawn(async move {
+            loop {
+                let chunk = match timeout(idle_timeout, output.next()).await {
+                    Ok(Some(chunk)) => chunk,
+                    Ok(None) => break,
+                    // The command may still be running, but nobody is told
+                    // how it ends.
+                    Err(_) => {
+                        tracing::info!(backend=%req.value.backend_id, "Command went idle.");
+                        ExecOutputChunk::Exit { code: None }
+                    }
+                };
+                let last = matches!(chunk, ExecOutputChunk::Exit { .. });
+                nats.publish(&ExecOutputMessage {
+                    cluster_id: req.value.cluster_id.clone(),
+                    backend_id: req.value.backend_id.clone(),
+                    exec_id: req.value.exec_id.clone(),
+                    chunk,
+                })
+                .await
+                .log_error("Error publishing exec output.");
+                if last {
+                    break;
+                }
+            }
+        });
+    }
+
+    Err(anyhow!("Exec request subscription closed."))
+}
+
+/// Why a request to run a command in a backend may not be served, if it
+/// may not. `id` is the requester's ID for the session's subjects.
+fn check_exec_access<'a, T: TypedMessage>(
+    access: Option<&'a ExecAccess>,
+    req: &MessageWithResponseHandle<T>,
+    id: &str,
+    seen_nonces: &SeenNonces,
+) -> Result<&'a ExecAccess, String> {
+    let access =
+        access.ok_or_else(|| "Running commands is not enabled on this drone.".to_string())?;
+    if !subjects::is_single_token(id) {
+        return Err(format!("Invalid session ID {:?}.", id));
+    }
+    req.verify_signature(&access.public_key, seen_nonces)
+        .map_err(|error| format!("Request was not authenticated: {}", error))?;
+    Ok(access)
+}
+
+/// Listen for requests to attach a terminal to backends running on this drone.
+pub async fn listen_for_attach_requests<E: Engine + Clone>(
+    engine: E,
+    nats: TypedNats,
+    cluster: ClusterName,
+    access: Option<ExecAccess>,
+) -> NeverResult {
+    let mut sub = nats
+        .subscribe(AttachRequest::subscribe_subject(&cluster))
+        .await?;
+    tracing::info!("Listening for attach requests.");
+
+    // Input messages are signed too, and share the request's nonces, so that
+    // none can be replayed into a session.
+    let seen_nonces = Arc::new(SeenNonces::default());
+    while let Some(req) = sub.next().await {
+        let backend = req.value.backend_id.clone();
+        match engine.list_backends().await {
+            Ok(backends) if backends.contains(&backend) => (),
+            // The backend belongs to another drone, which responds instead.
+            Ok(_) => continue,
+            Err(error) => {
+                tracing::error!(?error, %backend, "Error listing backends.");
+                continue;
+            }
+        }
+
+        let access =
+            match check_exec_access(access.as_ref(), &req, &req.value.attach_id, &seen_nonces) {
+                Ok(access) => access.clone(),
+                Err(reason) => {
+                    tracing::warn!(%backend, %reason, "Refused attach request.");
+                    req.respond(&ExecResponse::Failed { reason }).await?;
+                    continue;
+                }
+            };
+
+        // Subscribe to input before responding, so that none is missed.
+        let input = match nats
+            .subscribe(AttachInputMessage::subscribe_subject(&req.value))
+            .await
+        {
+            Ok(input) => input,
+            Err(error) => {
+                tracing::warn!(?error, %backend, "Error subscribing to attach input.");
+                req.respond(&ExecResponse::Failed {
+                    reason: error.to_string(),
+                })
+                .await?;
+                continue;
+            }
+        };
+        // Unsigned input is dropped, and a terminal left without input for
+        // the idle timeout (e.g. because the requester went away without
+        // detaching) is detached.
+        let seen = seen_nonces.clone();
+        let input = Box::pin(futures::stream::unfold(Some(input), move |input| {
+            let access = access.clone();
+            let seen = seen.clone();
+            async move {
+                let mut input = input?;
+                loop {
+                    match timeout(access.idle_timeout, input.next()).await {
+                        Ok(Some(message)) => {
+                            if let Err(error) = message.verify_signature(&access.public_key, &seen)
+                            {
+                                tracing::warn!(?error, "Dropping unauthenticated attach input.");
+                                continue;
+                            }
+                            return Some((message.value.input, Some(input)));
+                        }
+                        Ok(None) => return None,
+                        Err(_) => {
+                            tracing::info!("Detaching idle terminal.");
+                            return Some((AttachInput::Detach, None));
+                        }
+                    }
+                }
+            }
+        }));
+
+        tracing::info!(%backend, command=?req.value.command, "Attaching to backend.");
+        let mut output = match engine
+            .attach(&backend, &req.value.command, req.value.size, input)
+            .await
+        {
+            Ok(output) => output,
+            Err(error) => {
+                tracing::warn!(?error, %backend, "Error attaching to backend.");
+                req.respond(&ExecResponse::Failed {
+                    reason: error.to_string(),
+                })
+                .await?;
+                continue;
+            }
+        };
+        req.respond(&ExecResponse::Started).await?;
+
+        let nats = nats.clone();
+        tokio::spawn(async move {
+            while let Some(chunk) = output.next().await {
+                nats.publish(&AttachOutputMessage {
+                    cluster_id: req.value.cluster_id.clone(),
+                    backend_id: req.value.backend_id.clone(),
+                    attach_id: req.value.attach_id.clone(),
+                    chunk,
+                })
+                .await
+                .log_error("Error publishing attach output.");
+            }
+            tracing::info!(backend=%req.value.backend_id, "Detached from backend.");
+        });
+    }
+
+    Err(anyhow!("Attach request subscription closed."))
+}
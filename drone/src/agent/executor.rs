@@ -1,24 +1,50 @@
 use super::{
     backend::BackendMonitor,
+    disk_pressure::DiskPressure,
     engine::{Engine, EngineBackendStatus},
+    env_template::{expand_env, SpawnContext},
+    hooks::{Hooks, LifecycleHook},
+    log_archive::LogArchiver,
+    spawn_limit::SpawnLimiter,
 };
 use crate::{
-    agent::wait_port_ready,
+    agent::{
+        port_ready::{wait_port_ready, DEFAULT_PORT_READY_TIMEOUT},
+        wait_proxy_ready, ProxySelfTest,
+    },
+    config::{HooksConfig, LogArchiveConfig, SpawnLimitConfig},
     database::{Backend, DroneDatabase},
 };
 use anyhow::{anyhow, Result};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
 use plane_core::{
-    messages::agent::{BackendState, BackendStateMessage, SpawnRequest, TerminationRequest},
-    nats::TypedNats,
-    types::{BackendId, ClusterName},
+    error::PlaneError,
+    messages::agent::{
+        BackendState, BackendStateMessage, BackendStatsMessage, DroneStatusMessage, MigrateBackend,
+        SpawnProgress, SpawnProgressEvent, SpawnRequest, SpawnTimeline, TerminatedBy,
+        TerminationReason, TerminationRequest,
+    },
+    nats::{SubscribeSubject, TypedNats},
+    sealing::SealingKey,
+    subjects,
+    types::{BackendId, ClusterName, DroneId},
 };
 use serde_json::json;
-use std::{fmt::Debug, net::IpAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    net::{IpAddr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
-    sync::mpsc::{channel, Sender},
+    sync::{
+        mpsc::{channel, Sender},
+        oneshot, Mutex,
+    },
     task::JoinHandle,
+    time::timeout,
 };
 use tokio_stream::StreamExt;
 
@@ -37,7 +63,86 @@ impl<T, E: Debug> LogError for Result<T, E> {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// A draining backend is considered idle once the proxy has not seen activity
+/// for this long. The proxy records activity about once a second.
+const DRAIN_IDLE_SECONDS: i64 = 3;
+
+/// How long to wait for a status message from a drone a backend is being
+/// migrated to, which carries the key to seal the checkpoint to. Drones send
+/// one at least every few seconds.
+const MIGRATE_STATUS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check whether a draining backend has become idle.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often to retry publishing state messages and DNS records which were
+/// buffered because JetStream was unavailable.
+const STATE_MESSAGE_REPLAY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often to report the progress of pulling a backend's image, to callers
+/// which asked for progress.
+const PULL_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default for how long a backend may spend loading before it is considered
+/// to have failed.
+pub const DEFAULT_LOAD_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// The state a backend is actually in after the drone restarts, given the
+/// state last recorded for it and its status according to the engine, and
+/// why it stopped if it did. None if the recorded state can be resumed as is.
+fn reconcile_state(
+    recorded: BackendState,
+    status: EngineBackendStatus,
+) -> Option<(BackendState, Option<TerminationReason>)> {
+    match (recorded, status) {
+        // The drone stopped between creating a container and recording that
+        // the backend was starting, so the container is adopted instead of
+        // loading again.
+        (BackendState::Loading, EngineBackendStatus::Unknown) => None,
+        (BackendState::Loading, _) => Some((BackendState::Starting, None)),
+        (BackendState::Ready, EngineBackendStatus::Running { .. }) => None,
+        (BackendState::Ready, EngineBackendStatus::Unknown) => Some((
+            BackendState::Swept,
+            Some(
+                TerminationReason::new(TerminatedBy::External)
+                    .with_error("Container was gone when the drone restarted.".into()),
+            ),
+        )),
+        (BackendState::Ready, status) => {
+            let state = match status {
+                EngineBackendStatus::Failed { .. } => BackendState::Failed,
+                EngineBackendStatus::OutOfMemory => BackendState::OutOfMemory,
+                EngineBackendStatus::Exited => BackendState::Exited,
+                _ => BackendState::Swept,
+            };
+            Some((state, exit_termination(status)))
+        }
+        // Starting backends are checked by the state machine as soon as it
+        // resumes, and stopped backends only need cleaning up.
+        _ => None,
+    }
+}
+
+/// Why a backend which is no longer running stopped, according to the
+/// engine, or None if it is still running or its status is unknown.
+fn exit_termination(status: EngineBackendStatus) -> Option<TerminationReason> {
+    match status {
+        EngineBackendStatus::Exited => {
+            Some(TerminationReason::new(TerminatedBy::Backend).with_exit_code(0))
+        }
+        EngineBackendStatus::Failed { exit_code } => {
+            Some(TerminationReason::new(TerminatedBy::Backend).with_exit_code(exit_code))
+        }
+        EngineBackendStatus::OutOfMemory => Some(TerminationReason {
+            oom_killed: true,
+            ..TerminationReason::new(TerminatedBy::Backend)
+        }),
+        EngineBackendStatus::Terminated => Some(TerminationReason::new(TerminatedBy::External)),
+        EngineBackendStatus::Unknown | EngineBackendStatus::Running { .. } => None,
+    }
+}
+
+#[derive(Debug)]
 enum Signal {
     /// Tells the executor to interrupt current step to recapture an external status
     /// change. This signal is sent after an engine detects an external status change,
@@ -45,7 +150,16 @@ enum Signal {
     Interrupt,
 
     /// Tells the executor to terminate the current step.
-    Terminate,
+    Terminate(TerminatedBy),
+
+    /// Tells the executor to stop a backend at its own request, which then
+    /// counts as having exited.
+    Exit,
+
+    /// Tells the executor to checkpoint a ready backend, sealed to the given
+    /// public key, and stop it. Once it has stopped, the request to restore
+    /// it is sent back.
+    Checkpoint(String, oneshot::Sender<Result<SpawnRequest>>),
 }
 
 pub struct Executor<E: Engine> {
@@ -53,6 +167,11 @@ pub struct Executor<E: Engine> {
     database: DroneDatabase,
     nc: TypedNats,
     _container_events_handle: Arc<JoinHandle<()>>,
+    _state_message_replay_handle: Arc<JoinHandle<()>>,
+
+    /// Held while publishing backend state messages, so that they are
+    /// published in order even when some had to be buffered.
+    state_message_lock: Arc<Mutex<()>>,
 
     /// Associates a backend with a monitor, which owns a number of
     /// event loops related to a backend.
@@ -64,11 +183,43 @@ pub struct Executor<E: Engine> {
     /// for terminating backends.
     backend_to_listener: Arc<DashMap<BackendId, Sender<Signal>>>,
 
+    /// Why a backend is stopping, recorded when the state machine decides to
+    /// stop it and published with the state it stops in.
+    backend_to_termination: Arc<DashMap<BackendId, TerminationReason>>,
+
     /// The IP address associated with this executor.
     ip: IpAddr,
 
+    /// An IPv6 address associated with this executor, in addition to `ip`.
+    ipv6: Option<Ipv6Addr>,
+
     /// The cluster name associated with this executor.
     cluster: ClusterName,
+
+    /// If set, backends are only marked ready once they can be reached
+    /// through the local proxy.
+    proxy_self_test: Option<ProxySelfTest>,
+
+    /// How long a backend may spend loading (e.g. pulling its image) before
+    /// it is considered to have failed.
+    load_timeout: Duration,
+
+    /// If set, backend logs are archived to object storage.
+    log_archiver: Option<LogArchiver>,
+
+    /// If set, limits how many backends load at once and how quickly they
+    /// start loading.
+    spawn_limiter: Option<Arc<SpawnLimiter>>,
+
+    /// If set, new backends fail while the drone is low on disk space.
+    disk_pressure: Option<Arc<DiskPressure>>,
+
+    /// If set, commands run as backends move through their lifecycle.
+    hooks: Option<Arc<Hooks>>,
+
+    /// If set, backends can be restored from checkpoints sealed to this
+    /// drone by others.
+    sealing_key: Option<Arc<SealingKey>>,
 }
 
 impl<E: Engine> Clone for Executor<E> {
@@ -78,10 +229,21 @@ impl<E: Engine> Clone for Executor<E> {
             database: self.database.clone(),
             nc: self.nc.clone(),
             _container_events_handle: self._container_events_handle.clone(),
+            _state_message_replay_handle: self._state_message_replay_handle.clone(),
+            state_message_lock: self.state_message_lock.clone(),
             backend_to_monitor: self.backend_to_monitor.clone(),
             backend_to_listener: self.backend_to_listener.clone(),
+            backend_to_termination: self.backend_to_termination.clone(),
             ip: self.ip,
+            ipv6: self.ipv6,
             cluster: self.cluster.clone(),
+            proxy_self_test: self.proxy_self_test.clone(),
+            load_timeout: self.load_timeout,
+            log_archiver: self.log_archiver.clone(),
+            spawn_limiter: self.spawn_limiter.clone(),
+            disk_pressure: self.disk_pressure.clone(),
+            hooks: self.hooks.clone(),
+            sealing_key: self.sealing_key.clone(),
         }
     }
 }
@@ -93,24 +255,118 @@ impl<E: Engine> Executor<E> {
         nc: TypedNats,
         ip: IpAddr,
         cluster: ClusterName,
+        proxy_self_test: Option<ProxySelfTest>,
     ) -> Self {
         let backend_to_listener: Arc<DashMap<BackendId, Sender<Signal>>> = Arc::default();
         let engine = Arc::new(engine);
 
+        let state_message_lock: Arc<Mutex<()>> = Arc::default();
+
         let container_events_handle = tokio::spawn(Self::listen_for_container_events(
             engine.clone(),
             backend_to_listener.clone(),
         ));
 
+        let state_message_replay_handle = tokio::spawn(Self::replay_state_messages_loop(
+            database.clone(),
+            nc.clone(),
+            state_message_lock.clone(),
+        ));
+
         Executor {
             engine,
             database,
             nc,
             _container_events_handle: Arc::new(container_events_handle),
+            _state_message_replay_handle: Arc::new(state_message_replay_handle),
+            state_message_lock,
             backend_to_monitor: Arc::default(),
             backend_to_listener,
+            backend_to_termination: Arc::default(),
             ip,
+            ipv6: None,
             cluster,
+            proxy_self_test,
+            load_timeout: DEFAULT_LOAD_TIMEOUT,
+            log_archiver: None,
+            spawn_limiter: None,
+            disk_pressure: None,
+            hooks: None,
+            sealing_key: None,
+        }
+    }
+
+    /// Set how long a backend may spend loading before it is considered to
+    /// have failed.
+    #[must_use]
+    pub fn with_load_timeout(mut self, load_timeout: Duration) -> Self {
+        self.load_timeout = load_timeout;
+        self
+    }
+
+    /// Also advertise backends at an IPv6 address.
+    #[must_use]
+    pub fn with_ipv6(mut self, ipv6: Ipv6Addr) -> Self {
+        self.ipv6 = Some(ipv6);
+        self
+    }
+
+    /// Addresses which backends are advertised at in DNS.
+    fn backend_ips(&self) -> Vec<IpAddr> {
+        std::iter::once(self.ip)
+            .chain(self.ipv6.map(IpAddr::V6))
+            .collect()
+    }
+
+    /// Archive backend logs to S3-compatible object storage.
+    #[must_use]
+    pub fn with_log_archive(mut self, config: LogArchiveConfig) -> Self {
+        self.log_archiver = Some(LogArchiver::new(config));
+        self
+    }
+
+    /// Limit how many backends load at once and how quickly they start
+    /// loading.
+    #[must_use]
+    pub fn with_spawn_limit(mut self, config: SpawnLimitConfig) -> Self {
+        self.spawn_limiter = Some(Arc::new(SpawnLimiter::new(config)));
+        self
+    }
+
+    /// Fail new backends while the drone is low on disk space.
+    #[must_use]
+    pub fn with_disk_pressure(mut self, disk_pressure: Arc<DiskPressure>) -> Self {
+        self.disk_pressure = Some(disk_pressure);
+        self
+    }
+
+    /// Open checkpoints sealed to this drone with the given key, so that
+    /// backends can be migrated to it.
+    #[must_use]
+    pub fn with_sealing_key(mut self, sealing_key: Arc<SealingKey>) -> Self {
+        self.sealing_key = Some(sealing_key);
+        self
+    }
+
+    /// Run commands as backends move through their lifecycle.
+    #[must_use]
+    pub fn with_hooks(mut self, config: HooksConfig) -> Self {
+        self.hooks = Some(Arc::new(Hooks::new(config)));
+        self
+    }
+
+    /// Run a lifecycle hook, if one is configured, logging rather than
+    /// returning its failure.
+    async fn run_hook(
+        &self,
+        hook: LifecycleHook,
+        spawn_request: &SpawnRequest,
+        state: BackendState,
+    ) {
+        if let Some(hooks) = &self.hooks {
+            if let Err(error) = hooks.run(hook, spawn_request, &self.cluster, state).await {
+                tracing::warn!(?error, %hook, backend_id=%spawn_request.backend_id, "Hook failed.");
+            }
         }
     }
 
@@ -126,32 +382,147 @@ impl<E: Engine> Executor<E> {
         }
     }
 
+    /// Publish buffered state messages in order, stopping at the first failure.
+    async fn replay_state_messages(database: &DroneDatabase, nc: &TypedNats) -> Result<()> {
+        for (id, message) in database.get_pending_state_messages().await? {
+            nc.publish_jetstream(&message).await?;
+            database.delete_pending_state_message(id).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn replay_state_messages_loop(
+        database: DroneDatabase,
+        nc: TypedNats,
+        state_message_lock: Arc<Mutex<()>>,
+    ) {
+        let mut interval = tokio::time::interval(STATE_MESSAGE_REPLAY_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let _lock = state_message_lock.lock().await;
+            if let Err(error) = Self::replay_state_messages(&database, &nc).await {
+                tracing::debug!(?error, "Could not replay buffered state messages yet.");
+            }
+            if let Err(error) = Self::replay_dns_records(&database, &nc).await {
+                tracing::debug!(?error, "Could not replay buffered DNS records yet.");
+            }
+        }
+    }
+
+    /// Publish buffered DNS records, stopping at the first failure.
+    async fn replay_dns_records(database: &DroneDatabase, nc: &TypedNats) -> Result<()> {
+        for record in database.get_pending_dns_records().await? {
+            nc.publish_jetstream(&record).await?;
+            database.delete_pending_dns_record(&record).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Publish a backend state message over JetStream. If JetStream is unavailable,
+    /// the message is buffered in sqlite and replayed (in order) once it returns.
+    async fn publish_state_message(&self, message: BackendStateMessage) {
+        let _lock = self.state_message_lock.lock().await;
+
+        // Anything buffered earlier must be published first to preserve ordering.
+        let result = match Self::replay_state_messages(&self.database, &self.nc).await {
+            Ok(()) => self.nc.publish_jetstream(&message).await,
+            Err(error) => Err(error),
+        };
+
+        if let Err(error) = result {
+            tracing::warn!(
+                ?error,
+                ?message,
+                "Could not publish state message, buffering it."
+            );
+            self.database
+                .insert_pending_state_message(&message)
+                .await
+                .log_error();
+        }
+    }
+
     pub async fn start_backend(&self, spawn_request: &SpawnRequest) {
         self.database
             .insert_backend(spawn_request)
             .await
             .log_error();
 
-        self.nc
-            .publish_jetstream(&BackendStateMessage::new(
-                BackendState::Loading,
-                spawn_request.backend_id.clone(),
-            ))
-            .await
-            .log_error();
+        let timeline = self
+            .update_timeline(spawn_request, BackendState::Loading)
+            .await;
+        self.publish_state_message(
+            BackendStateMessage::new(BackendState::Loading, spawn_request.backend_id.clone())
+                .with_drone(spawn_request.drone_id.clone())
+                .with_timeline(timeline),
+        )
+        .await;
 
         self.run_backend(spawn_request, BackendState::Loading).await
     }
 
+    /// Stop routing new requests to a backend, then wait until it has had no
+    /// activity for a few seconds or the grace period elapses.
+    async fn drain_backend(&self, backend_id: &BackendId, grace_period: Duration) -> Result<()> {
+        self.database.set_backend_draining(backend_id).await?;
+        tracing::info!(%backend_id, ?grace_period, "Draining backend.");
+
+        let deadline = Utc::now() + chrono::Duration::from_std(grace_period)?;
+        while Utc::now() < deadline {
+            let last_active = self.database.get_backend_last_active(backend_id).await?;
+            if Utc::now().signed_duration_since(last_active)
+                > chrono::Duration::seconds(DRAIN_IDLE_SECONDS)
+            {
+                tracing::info!(%backend_id, "Backend drained.");
+                return Ok(());
+            }
+
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+
+        tracing::info!(%backend_id, "Grace period elapsed before backend drained.");
+        Ok(())
+    }
+
+    /// Whether this executor is managing the given backend.
+    pub fn has_backend(&self, backend_id: &BackendId) -> bool {
+        self.backend_to_listener.contains_key(backend_id)
+    }
+
+    /// The stats most recently sampled for a backend on this drone, if any.
+    pub fn backend_stats(&self, backend_id: &BackendId) -> Option<BackendStatsMessage> {
+        self.backend_to_monitor
+            .get(backend_id)
+            .and_then(|monitor| monitor.latest_stats())
+    }
+
     pub async fn kill_backend(
         &self,
         termination_request: &TerminationRequest,
     ) -> Result<(), anyhow::Error> {
+        if termination_request.drain
+            && self
+                .backend_to_listener
+                .contains_key(&termination_request.backend_id)
+        {
+            self.drain_backend(
+                &termination_request.backend_id,
+                termination_request.grace_period_secs,
+            )
+            .await
+            .log_error();
+        }
+
         if let Some(sender) = self
             .backend_to_listener
             .get(&termination_request.backend_id)
         {
-            Ok(sender.send(Signal::Terminate).await?)
+            Ok(sender
+                .send(Signal::Terminate(TerminatedBy::Request))
+                .await?)
         } else {
             Err(anyhow!(
                 "Unknown backend {}",
@@ -160,16 +531,210 @@ impl<E: Engine> Executor<E> {
         }
     }
 
+    /// Gracefully stop a backend which asked to be stopped, e.g. through the
+    /// metadata API because its session ended.
+    pub async fn exit_backend(&self, backend_id: &BackendId) -> Result<()> {
+        if let Some(sender) = self.backend_to_listener.get(backend_id) {
+            Ok(sender.send(Signal::Exit).await?)
+        } else {
+            Err(anyhow!("Unknown backend {}", backend_id))
+        }
+    }
+
+    /// Checkpoint a backend and hand it to another drone, which restores it
+    /// from the checkpoint. If the other drone does not accept the backend,
+    /// it is restored on this drone instead.
+    pub async fn migrate_backend(&self, request: &MigrateBackend) -> Result<()> {
+        let backend_id = &request.backend_id;
+        let sender = self
+            .backend_to_listener
+            .get(backend_id)
+            .map(|sender| sender.clone())
+            .ok_or_else(|| anyhow!("Unknown backend {}", backend_id))?;
+
+        let recipient = self.sealing_public_key(&request.target_drone).await?;
+        let (send, recv) = oneshot::channel();
+        sender.send(Signal::Checkpoint(recipient, send)).await?;
+        let restore_request = recv
+            .await
+            .map_err(|_| anyhow!("Backend {} stopped before it was checkpointed.", backend_id))??;
+
+        let target_request = SpawnRequest {
+            drone_id: request.target_drone.clone(),
+            ..restore_request.clone()
+        };
+        match self.nc.request(&target_request).await {
+            Ok(Ok(())) => {
+                tracing::info!(
+                    %backend_id,
+                    target_drone=%request.target_drone,
+                    "Migrated backend."
+                );
+                if let Some(checkpoint) = &restore_request.restore_checkpoint {
+                    self.engine.discard_checkpoint(checkpoint).await.log_error();
+                }
+                Ok(())
+            }
+            result => {
+                tracing::warn!(?result, %backend_id, "Target drone did not accept backend.");
+                let executor = self.clone();
+                tokio::spawn(async move { executor.restore_backend(&restore_request).await });
+
+                Err(anyhow!(
+                    "Drone {} did not accept backend {}, so it was restored on this drone.",
+                    request.target_drone,
+                    backend_id
+                ))
+            }
+        }
+    }
+
+    /// The public key a drone advertises in its status messages for data
+    /// sealed to it, such as checkpoints.
+    async fn sealing_public_key(&self, drone: &DroneId) -> Result<String> {
+        let mut sub = self
+            .nc
+            .subscribe(SubscribeSubject::<DroneStatusMessage>::new(
+                subjects::drone_status(drone),
+            ))
+            .await?;
+        let status = timeout(MIGRATE_STATUS_TIMEOUT, sub.next())
+            .await
+            .map_err(|_| anyhow!("Drone {} did not send a status message.", drone))?
+            .ok_or_else(|| anyhow!("Drone status subscription closed."))?;
+
+        status
+            .value
+            .sealing_key
+            .ok_or_else(|| anyhow!("Drone {} cannot restore checkpoints.", drone))
+    }
+
+    /// When a backend reaches its maximum lifetime, including any extension
+    /// it was granted through the metadata API.
+    async fn lifetime_deadline(
+        &self,
+        backend_id: &BackendId,
+        max_lifetime: Duration,
+    ) -> Result<DateTime<Utc>> {
+        // Backends spawned before creation times were recorded are given
+        // their full lifetime from now.
+        let created_at = self
+            .database
+            .get_backend_created_at(backend_id)
+            .await?
+            .unwrap_or_else(Utc::now);
+        let extension = self
+            .database
+            .get_backend_lifetime_extension(backend_id)
+            .await?;
+
+        Ok(created_at
+            + chrono::Duration::from_std(max_lifetime)?
+            + chrono::Duration::seconds(extension))
+    }
+
+    /// Restore a backend which this drone checkpointed.
+    async fn restore_backend(&self, spawn_request: &SpawnRequest) {
+        tracing::info!(backend_id=%spawn_request.backend_id, "Restoring backend.");
+        self.update_backend_state(spawn_request, BackendState::Loading, None, None)
+            .await;
+        self.run_backend(spawn_request, BackendState::Loading).await
+    }
+
+    /// Send a termination signal to every backend this executor is managing.
+    pub async fn terminate_all_backends(&self) {
+        // Collect senders first to avoid holding a DashMap reference across an await.
+        let senders: Vec<(BackendId, Sender<Signal>)> = self
+            .backend_to_listener
+            .iter()
+            .map(|d| (d.key().clone(), d.value().clone()))
+            .collect();
+
+        for (backend_id, sender) in senders {
+            tracing::info!(%backend_id, "Terminating backend.");
+            sender
+                .send(Signal::Terminate(TerminatedBy::Drone))
+                .await
+                .log_error();
+        }
+    }
+
+    /// Send a termination signal to every backend this executor is managing
+    /// which is in the given state (or to every backend, if none is given),
+    /// on behalf of a [plane_core::messages::agent::TerminateAllRequest].
+    /// Returns the backends signalled.
+    pub async fn terminate_backends(&self, state: Option<BackendState>) -> Result<Vec<BackendId>> {
+        let states: HashMap<BackendId, BackendState> = self
+            .database
+            .get_backends()
+            .await?
+            .into_iter()
+            .map(|backend| (backend.backend_id, backend.state))
+            .collect();
+
+        // Collect senders first to avoid holding a DashMap reference across an await.
+        let senders: Vec<(BackendId, Sender<Signal>)> = self
+            .backend_to_listener
+            .iter()
+            .filter(|d| state.is_none() || states.get(d.key()) == state.as_ref())
+            .map(|d| (d.key().clone(), d.value().clone()))
+            .collect();
+
+        let mut terminated = Vec::with_capacity(senders.len());
+        for (backend_id, sender) in senders {
+            tracing::info!(%backend_id, "Terminating backend.");
+            if sender
+                .send(Signal::Terminate(TerminatedBy::Request))
+                .await
+                .is_ok()
+            {
+                terminated.push(backend_id);
+            }
+        }
+
+        Ok(terminated)
+    }
+
     pub async fn resume_backends(&self) -> Result<()> {
         let backends = self.database.get_backends().await?;
+        let engine_backends: HashSet<BackendId> =
+            self.engine.list_backends().await?.into_iter().collect();
+        let mut known_backends: HashSet<BackendId> = HashSet::new();
 
         for backend in backends {
             let executor = self.clone();
             let Backend {
                 backend_id,
-                state,
+                mut state,
                 spec,
             } = backend;
+            known_backends.insert(backend_id.clone());
+
+            // The recorded state may be stale if the drone stopped in the middle
+            // of a transition, so it is checked against the engine and corrected
+            // before the state machine resumes.
+            if !state.terminal() && state != BackendState::Checkpointed {
+                match self.engine.backend_status(&backend_id).await {
+                    Ok(status) => {
+                        if let Some((actual, termination)) = reconcile_state(state, status) {
+                            tracing::info!(
+                                %backend_id,
+                                recorded=?state,
+                                ?actual,
+                                ?status,
+                                "Correcting state of backend."
+                            );
+                            state = actual;
+                            self.update_backend_state(&spec, state, None, termination)
+                                .await;
+                        }
+                    }
+                    Err(error) => {
+                        tracing::warn!(?error, %backend_id, "Could not get status of backend.")
+                    }
+                }
+            }
+
             tracing::info!(%backend_id, ?state, "Resuming backend");
 
             if state.running() {
@@ -178,15 +743,24 @@ impl<E: Engine> Executor<E> {
                     BackendMonitor::new(
                         &backend_id,
                         &self.cluster,
-                        self.ip,
+                        self.backend_ips(),
                         self.engine.as_ref(),
                         &self.nc,
+                        &self.database,
+                        self.log_archiver.as_ref(),
                     ),
                 );
             }
             tokio::spawn(async move { executor.run_backend(&spec, state).await });
         }
 
+        // Containers with no database row (e.g. because the database was lost)
+        // would otherwise never be cleaned up.
+        for backend_id in engine_backends.difference(&known_backends) {
+            tracing::warn!(%backend_id, "Stopping orphaned container.");
+            self.engine.stop(backend_id).await.log_error();
+        }
+
         Ok(())
     }
 
@@ -195,11 +769,8 @@ impl<E: Engine> Executor<E> {
         self.backend_to_listener
             .insert(spawn_request.backend_id.clone(), send);
 
-        if spawn_request.bearer_token.is_some() {
-            tracing::warn!(
-                "Spawn request included bearer token, which is not currently supported."
-            );
-        }
+        // Sent once the backend has been checkpointed and stopped.
+        let mut restore_reply = None;
 
         loop {
             tracing::info!(
@@ -223,9 +794,43 @@ impl<E: Engine> Executor<E> {
                                 tracing::info!("State may have updated externally.");
                                 continue;
                             },
-                            Some(Signal::Terminate) => {
+                            Some(Signal::Terminate(terminated_by)) => {
+                                self.record_termination(
+                                    &spawn_request.backend_id,
+                                    TerminationReason::new(terminated_by),
+                                );
                                 break Ok(Some(BackendState::Terminated))
                             },
+                            Some(Signal::Exit) => {
+                                self.record_termination(
+                                    &spawn_request.backend_id,
+                                    TerminationReason::new(TerminatedBy::Backend),
+                                );
+                                break Ok(Some(BackendState::Exited))
+                            },
+                            Some(Signal::Checkpoint(recipient, reply)) => {
+                                if state != BackendState::Ready {
+                                    let _ = reply.send(Err(anyhow!(
+                                        "Only ready backends can be checkpointed, not {:?} ones.",
+                                        state
+                                    )));
+                                    continue;
+                                }
+
+                                match self.engine.checkpoint(&spawn_request.backend_id, &recipient).await {
+                                    Ok(checkpoint) => {
+                                        restore_reply = Some((reply, SpawnRequest {
+                                            restore_checkpoint: Some(checkpoint),
+                                            ..spawn_request.clone()
+                                        }));
+                                        break Ok(Some(BackendState::Checkpointed))
+                                    },
+                                    Err(error) => {
+                                        let _ = reply.send(Err(error));
+                                        continue;
+                                    }
+                                }
+                            },
                             None => {
                                 tracing::error!("Signal sender lost!");
                                 return
@@ -245,14 +850,33 @@ impl<E: Engine> Executor<E> {
                             BackendMonitor::new(
                                 &spawn_request.backend_id,
                                 &self.cluster,
-                                self.ip,
+                                self.backend_ips(),
                                 self.engine.as_ref(),
                                 &self.nc,
+                                &self.database,
+                                self.log_archiver.as_ref(),
                             ),
                         );
                     }
 
-                    self.update_backend_state(spawn_request, state).await;
+                    let termination = self
+                        .backend_to_termination
+                        .remove(&spawn_request.backend_id)
+                        .map(|(_, termination)| termination);
+                    self.update_backend_state(spawn_request, state, None, termination)
+                        .await;
+
+                    // Run in the background, so that the backend can be
+                    // stopped while its hook runs.
+                    if state == BackendState::Ready && self.hooks.is_some() {
+                        let executor = self.clone();
+                        let spawn_request = spawn_request.clone();
+                        tokio::spawn(async move {
+                            executor
+                                .run_hook(LifecycleHook::PostReady, &spawn_request, state)
+                                .await
+                        });
+                    }
                 }
                 Ok(None) => {
                     // Successful termination.
@@ -264,7 +888,13 @@ impl<E: Engine> Executor<E> {
                     match state {
                         BackendState::Loading => {
                             state = BackendState::ErrorLoading;
-                            self.update_backend_state(spawn_request, state).await;
+                            self.update_backend_state(
+                                spawn_request,
+                                state,
+                                Some(PlaneError::from_anyhow(&error)),
+                                None,
+                            )
+                            .await;
                         }
                         _ => tracing::error!(
                             ?error,
@@ -279,24 +909,109 @@ impl<E: Engine> Executor<E> {
 
         self.backend_to_monitor.remove(&spawn_request.backend_id);
         self.backend_to_listener.remove(&spawn_request.backend_id);
+        self.backend_to_termination
+            .remove(&spawn_request.backend_id);
+
+        // Buffered DNS records would point at an address the backend no
+        // longer has. The lock keeps a replay under way from publishing them.
+        {
+            let _lock = self.state_message_lock.lock().await;
+            if let Err(error) = self
+                .database
+                .delete_pending_dns_records(&spawn_request.backend_id)
+                .await
+            {
+                tracing::warn!(
+                    ?error,
+                    backend_id = spawn_request.backend_id.id(),
+                    "Error removing buffered DNS records."
+                );
+            }
+        }
+
+        if let Some((reply, restore_request)) = restore_reply {
+            let _ = reply.send(Ok(restore_request));
+        }
+    }
+
+    /// Tell the caller which spawned a backend how spawning it is going, if
+    /// it asked to be told.
+    async fn publish_progress(&self, spawn_request: &SpawnRequest, event: SpawnProgressEvent) {
+        if let Some(progress_id) = &spawn_request.progress_id {
+            self.nc
+                .publish(&SpawnProgress {
+                    cluster_id: self.cluster.clone(),
+                    backend_id: spawn_request.backend_id.clone(),
+                    progress_id: progress_id.clone(),
+                    event,
+                })
+                .await
+                .log_error();
+        }
+    }
+
+    /// Record why a backend is stopping, to be published with its next state.
+    fn record_termination(&self, backend_id: &BackendId, termination: TerminationReason) {
+        self.backend_to_termination
+            .insert(backend_id.clone(), termination);
     }
 
     /// Update the rest of the system on the state of a backend, by writing it to the local
     /// sqlite database (where the proxy can see it), and by broadcasting it to interested
     /// remote listeners over NATS.
-    async fn update_backend_state(&self, spawn_request: &SpawnRequest, state: BackendState) {
+    async fn update_backend_state(
+        &self,
+        spawn_request: &SpawnRequest,
+        state: BackendState,
+        error: Option<PlaneError>,
+        termination: Option<TerminationReason>,
+    ) {
         self.database
             .update_backend_state(&spawn_request.backend_id, state)
             .await
             .log_error();
 
-        self.nc
-            .publish_jetstream(&BackendStateMessage::new(
-                state,
-                spawn_request.backend_id.clone(),
-            ))
-            .await
-            .log_error();
+        let timeline = self.update_timeline(spawn_request, state).await;
+        self.publish_state_message(
+            BackendStateMessage::new(state, spawn_request.backend_id.clone())
+                .with_drone(spawn_request.drone_id.clone())
+                .with_error(error)
+                .with_termination(termination)
+                .with_timeline(timeline),
+        )
+        .await;
+    }
+
+    /// Record the time a backend reached a state in its persisted spawn
+    /// timeline, returning the updated timeline.
+    async fn update_timeline(
+        &self,
+        spawn_request: &SpawnRequest,
+        state: BackendState,
+    ) -> Option<SpawnTimeline> {
+        let backend_id = &spawn_request.backend_id;
+        let result = async {
+            let now = Utc::now();
+            let mut timeline = self
+                .database
+                .get_backend_timeline(backend_id)
+                .await?
+                .unwrap_or_else(|| SpawnTimeline::new(spawn_request.requested_at, now));
+            timeline.record(state, now);
+            self.database
+                .set_backend_timeline(backend_id, &timeline)
+                .await?;
+            Ok::<_, anyhow::Error>(timeline)
+        }
+        .await;
+
+        match result {
+            Ok(timeline) => Some(timeline),
+            Err(error) => {
+                tracing::warn!(?error, %backend_id, "Could not record spawn timeline.");
+                None
+            }
+        }
     }
 
     pub async fn step(
@@ -306,8 +1021,113 @@ impl<E: Engine> Executor<E> {
     ) -> Result<Option<BackendState>> {
         match state {
             BackendState::Loading => {
-                self.engine.load(spawn_request).await?;
+                if let Some(hooks) = &self.hooks {
+                    hooks.loading(&spawn_request.backend_id);
+                }
+                if let Some(disk_pressure) = &self.disk_pressure {
+                    disk_pressure.check()?;
+                }
+                if let Some(hooks) = &self.hooks {
+                    hooks
+                        .run(LifecycleHook::PreStart, spawn_request, &self.cluster, state)
+                        .await?;
+                }
+
+                let mut env = expand_env(
+                    &spawn_request.executable.env,
+                    &SpawnContext::new(spawn_request, &self.cluster),
+                );
+
+                // Variables set explicitly in the request take precedence.
+                let observability_env = spawn_request
+                    .observability
+                    .env(&spawn_request.backend_id, &self.cluster)
+                    .map_err(|error| PlaneError::InvalidRequest {
+                        reason: error.to_string(),
+                    })?;
+                for (key, value) in observability_env {
+                    env.entry(key.to_string()).or_insert(value);
+                }
+                let mut spawn_request = spawn_request.clone();
+                spawn_request.executable.env = env;
+
+                // Held until loading finishes, so that the next queued backend
+                // can start loading.
+                let _permit = match &self.spawn_limiter {
+                    Some(spawn_limiter) => Some(spawn_limiter.acquire().await?),
+                    None => None,
+                };
+
+                let load = async {
+                    match &spawn_request.restore_checkpoint {
+                        Some(checkpoint) => {
+                            self.engine
+                                .restore(&spawn_request, checkpoint, self.sealing_key.clone())
+                                .await
+                        }
+                        None => self.engine.load(&spawn_request).await,
+                    }
+                };
+
+                // Report the progress of the image pull until loading finishes.
+                let report_pull_progress = async {
+                    let mut interval = tokio::time::interval(PULL_PROGRESS_INTERVAL);
+                    let mut last_progress = None;
+                    loop {
+                        interval.tick().await;
+                        let progress = self.engine.image_pull_progress(&spawn_request.backend_id);
+                        if let Some(progress) = progress {
+                            if last_progress != Some(progress) {
+                                self.publish_progress(
+                                    &spawn_request,
+                                    SpawnProgressEvent::ImagePullProgress {
+                                        pulled_bytes: progress.pulled_bytes,
+                                        total_bytes: progress.total_bytes,
+                                    },
+                                )
+                                .await;
+                                last_progress = Some(progress);
+                            }
+                        }
+                    }
+                };
+                let load = async {
+                    if spawn_request.progress_id.is_none() {
+                        return load.await;
+                    }
+
+                    tokio::select! {
+                        result = load => result,
+                        _ = report_pull_progress => unreachable!("Reporting progress never ends."),
+                    }
+                };
+
+                // Dropping the load future cancels it, e.g. aborting a hung image pull.
+                match tokio::time::timeout(self.load_timeout, load).await {
+                    Ok(result) => result?,
+                    Err(_) => {
+                        // Clean up anything the load created before it was cancelled.
+                        self.engine
+                            .stop(&spawn_request.backend_id)
+                            .await
+                            .log_error();
+                        return Err(PlaneError::LoadTimeout {
+                            timeout_secs: self.load_timeout.as_secs(),
+                        }
+                        .into());
+                    }
+                }
 
+                // The image is pulled, so the credentials need not outlive the load.
+                if spawn_request.executable.credentials.is_some() {
+                    self.database
+                        .clear_backend_credentials(&spawn_request.backend_id)
+                        .await
+                        .log_error();
+                }
+
+                self.publish_progress(&spawn_request, SpawnProgressEvent::ContainerCreated)
+                    .await;
                 Ok(Some(BackendState::Starting))
             }
             BackendState::Starting => {
@@ -318,11 +1138,39 @@ impl<E: Engine> Executor<E> {
 
                 let backend_addr = match status {
                     EngineBackendStatus::Running { addr } => addr,
-                    _ => return Ok(Some(BackendState::ErrorStarting)),
+                    status => {
+                        self.record_termination(
+                            &spawn_request.backend_id,
+                            exit_termination(status).unwrap_or_else(|| {
+                                TerminationReason::new(TerminatedBy::Drone)
+                                    .with_error("Backend was not running after loading.".into())
+                            }),
+                        );
+                        return Ok(Some(BackendState::ErrorStarting));
+                    }
                 };
 
                 tracing::info!(%backend_addr, "Got address from container.");
-                wait_port_ready(&backend_addr).await?;
+                let timeout = spawn_request
+                    .port_ready_timeout_secs
+                    .unwrap_or(DEFAULT_PORT_READY_TIMEOUT);
+                wait_port_ready(&backend_addr, timeout, |waited, error| {
+                    self.publish_state_message(
+                        BackendStateMessage::new(
+                            BackendState::Starting,
+                            spawn_request.backend_id.clone(),
+                        )
+                        .with_drone(spawn_request.drone_id.clone())
+                        .with_reason(Some(format!(
+                            "Waiting for port ({} after {} seconds).",
+                            error,
+                            waited.as_secs()
+                        ))),
+                    )
+                })
+                .await?;
+                self.publish_progress(spawn_request, SpawnProgressEvent::PortReady)
+                    .await;
 
                 self.database
                     .insert_proxy_route(
@@ -332,33 +1180,107 @@ impl<E: Engine> Executor<E> {
                     )
                     .await?;
 
+                if let Some(self_test) = &self.proxy_self_test {
+                    if let Err(error) =
+                        wait_proxy_ready(self_test, &spawn_request.backend_id, &self.cluster).await
+                    {
+                        tracing::error!(?error, "Backend was not reachable through the proxy.");
+                        self.record_termination(
+                            &spawn_request.backend_id,
+                            TerminationReason::new(TerminatedBy::Drone).with_error(format!(
+                                "Backend was not reachable through the proxy: {}",
+                                error
+                            )),
+                        );
+                        return Ok(Some(BackendState::ErrorStarting));
+                    }
+                }
+
                 Ok(Some(BackendState::Ready))
             }
             BackendState::Ready => {
-                match self
+                let status = self
                     .engine
                     .backend_status(&spawn_request.backend_id)
-                    .await?
-                {
-                    EngineBackendStatus::Failed => return Ok(Some(BackendState::Failed)),
-                    EngineBackendStatus::Exited => return Ok(Some(BackendState::Exited)),
-                    EngineBackendStatus::Terminated => return Ok(Some(BackendState::Swept)),
-                    _ => (),
+                    .await?;
+                let next_state = match status {
+                    EngineBackendStatus::Failed { .. } => Some(BackendState::Failed),
+                    EngineBackendStatus::OutOfMemory => Some(BackendState::OutOfMemory),
+                    EngineBackendStatus::Exited => Some(BackendState::Exited),
+                    EngineBackendStatus::Terminated => Some(BackendState::Swept),
+                    _ => None,
+                };
+                if let Some(next_state) = next_state {
+                    if let Some(termination) = exit_termination(status) {
+                        self.record_termination(&spawn_request.backend_id, termination);
+                    }
+                    return Ok(Some(next_state));
                 }
 
-                // wait for idle
+                let mut lifetime_deadline = match spawn_request.max_lifetime_secs {
+                    Some(max_lifetime) => Some(
+                        self.lifetime_deadline(&spawn_request.backend_id, max_lifetime)
+                            .await?,
+                    ),
+                    None => None,
+                };
+
+                // wait for idle, or for the end of the backend's lifetime
                 loop {
-                    let last_active = self
-                        .database
-                        .get_backend_last_active(&spawn_request.backend_id)
-                        .await?;
-                    let next_check = last_active
-                        .checked_add_signed(chrono::Duration::from_std(
-                            spawn_request.max_idle_secs,
-                        )?)
-                        .ok_or_else(|| anyhow!("Checked add error."))?;
+                    let idle_deadline = if spawn_request.max_idle_secs.is_zero() {
+                        None
+                    } else {
+                        let last_active = self
+                            .database
+                            .get_backend_last_active(&spawn_request.backend_id)
+                            .await?;
+                        Some(
+                            last_active
+                                .checked_add_signed(chrono::Duration::from_std(
+                                    spawn_request.max_idle_secs,
+                                )?)
+                                .ok_or_else(|| anyhow!("Checked add error."))?,
+                        )
+                    };
+
+                    let next_check = match (idle_deadline, lifetime_deadline) {
+                        (Some(idle_deadline), Some(lifetime_deadline)) => {
+                            idle_deadline.min(lifetime_deadline)
+                        }
+                        (Some(deadline), None) | (None, Some(deadline)) => deadline,
+                        // Idle sweeping is disabled and there is no lifetime;
+                        // run until the backend exits or is terminated, either
+                        // of which interrupts this step.
+                        (None, None) => return std::future::pending().await,
+                    };
 
                     if next_check < Utc::now() {
+                        let terminated_by = if Some(next_check) == lifetime_deadline {
+                            // The backend may have extended its lifetime
+                            // through the metadata API since the deadline was
+                            // last read.
+                            if let Some(max_lifetime) = spawn_request.max_lifetime_secs {
+                                let deadline = self
+                                    .lifetime_deadline(&spawn_request.backend_id, max_lifetime)
+                                    .await?;
+                                if deadline > next_check {
+                                    lifetime_deadline = Some(deadline);
+                                    continue;
+                                }
+                            }
+
+                            tracing::info!(
+                                backend_id=%spawn_request.backend_id,
+                                "Backend reached its maximum lifetime."
+                            );
+                            TerminatedBy::MaxLifetime
+                        } else {
+                            TerminatedBy::IdleTimeout
+                        };
+                        self.record_termination(
+                            &spawn_request.backend_id,
+                            TerminationReason::new(terminated_by),
+                        );
                         break;
                     } else {
                         tokio::time::sleep(next_check.signed_duration_since(Utc::now()).to_std()?)
@@ -372,16 +1294,62 @@ impl<E: Engine> Executor<E> {
             | BackendState::ErrorStarting
             | BackendState::TimedOutBeforeReady
             | BackendState::Failed
+            | BackendState::OutOfMemory
             | BackendState::Exited
             | BackendState::Swept
-            | BackendState::Terminated => {
+            | BackendState::Terminated
+            | BackendState::Checkpointed => {
+                self.run_hook(LifecycleHook::PreStop, spawn_request, state)
+                    .await;
                 self.engine
                     .stop(&spawn_request.backend_id)
                     .await
                     .map_err(|e| anyhow!("Error stopping container: {:?}", e))?;
+                self.run_hook(LifecycleHook::PostTerminate, spawn_request, state)
+                    .await;
 
                 Ok(None)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconcile_state() {
+        let running = EngineBackendStatus::Running {
+            addr: "127.0.0.1:8080".parse().unwrap(),
+        };
+
+        assert_eq!(
+            Some((BackendState::Starting, None)),
+            reconcile_state(BackendState::Loading, running)
+        );
+        assert_eq!(
+            None,
+            reconcile_state(BackendState::Loading, EngineBackendStatus::Unknown)
+        );
+        assert_eq!(None, reconcile_state(BackendState::Ready, running));
+
+        let (state, termination) = reconcile_state(
+            BackendState::Ready,
+            EngineBackendStatus::Failed { exit_code: 2 },
+        )
+        .unwrap();
+        assert_eq!(BackendState::Failed, state);
+        assert_eq!(Some(2), termination.unwrap().exit_code);
+
+        let (state, termination) =
+            reconcile_state(BackendState::Ready, EngineBackendStatus::Unknown).unwrap();
+        assert_eq!(BackendState::Swept, state);
+        assert_eq!(TerminatedBy::External, termination.unwrap().terminated_by);
+
+        assert_eq!(
+            None,
+            reconcile_state(BackendState::Swept, EngineBackendStatus::Unknown)
+        );
+    }
+}
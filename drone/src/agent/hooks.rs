@@ -0,0 +1,317 @@
+//! Site-specific commands run on the drone when backends move through their
+//! lifecycle, e.g. to set up networking or check out a license before a
+//! backend starts, and to clean up after it terminates.
+//!
+//! Each hook is an argument vector which is executed directly, not through a
+//! shell, since the drone's image does not include one. Hooks which need a
+//! shell can be given as `["sh", "-c", "..."]` on drones run from an image
+//! which has one. The backend is described in the hook's environment:
+//!
+//! - `PLANE_HOOK`: `pre_start`, `post_ready`, `pre_stop`, or `post_terminate`.
+//! - `PLANE_BACKEND_ID`, `PLANE_DRONE_ID`, `PLANE_CLUSTER`, `PLANE_IMAGE`.
+//! - `PLANE_BACKEND_STATE`: the state the backend is entering.
+//! - `PLANE_BACKEND_METADATA`: the backend's metadata, as a JSON object.
+//!
+//! A failing `pre_start` hook fails the backend before it is loaded, and the
+//! backend's `pre_stop` and `post_terminate` hooks are then not run, since
+//! there is nothing to clean up. Other hooks are only logged if they fail.
+//! A hook's output is discarded, except for the start of its standard error,
+//! which is included in the error if it fails.
+
+use crate::config::HooksConfig;
+use anyhow::{anyhow, Context, Result};
+use dashmap::DashSet;
+use plane_core::{
+    messages::agent::{BackendState, SpawnRequest},
+    types::{BackendId, ClusterName},
+};
+use serde_json::json;
+use std::{fmt::Display, process::Stdio, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Command,
+};
+
+/// How much of a hook's standard error is kept for its error message.
+const MAX_STDERR_BYTES: usize = 4096;
+
+/// Read a stream to its end, keeping at most the first `limit` bytes, so
+/// that a hook which writes a lot of output neither blocks on a full pipe
+/// nor uses unbounded memory.
+async fn read_bounded(mut stream: impl AsyncRead + Unpin, limit: usize) -> Vec<u8> {
+    let mut kept = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return kept,
+            Ok(len) => {
+                let keep = len.min(limit - kept.len());
+                kept.extend_from_slice(&chunk[..keep]);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LifecycleHook {
+    /// Before the backend's image is loaded and its container started.
+    PreStart,
+
+    /// Once the backend is ready.
+    PostReady,
+
+    /// Before the backend's container is stopped.
+    PreStop,
+
+    /// After the backend's container is stopped.
+    PostTerminate,
+}
+
+impl Display for LifecycleHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            LifecycleHook::PreStart => "pre_start",
+            LifecycleHook::PostReady => "post_ready",
+            LifecycleHook::PreStop => "pre_stop",
+            LifecycleHook::PostTerminate => "post_terminate",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub struct Hooks {
+    config: HooksConfig,
+
+    /// Backends which are loading, but whose `pre_start` hook has not
+    /// succeeded.
+    not_started: DashSet<BackendId>,
+}
+
+impl Hooks {
+    #[must_use]
+    pub fn new(config: HooksConfig) -> Self {
+        Hooks {
+            config,
+            not_started: DashSet::default(),
+        }
+    }
+
+    fn command(&self, hook: LifecycleHook) -> Option<&[String]> {
+        match hook {
+            LifecycleHook::PreStart => self.config.pre_start.as_deref(),
+            LifecycleHook::PostReady => self.config.post_ready.as_deref(),
+            LifecycleHook::PreStop => self.config.pre_stop.as_deref(),
+            LifecycleHook::PostTerminate => self.config.post_terminate.as_deref(),
+        }
+    }
+
+    /// Record that a backend has started loading, so that its `pre_stop` and
+    /// `post_terminate` hooks are skipped unless its `pre_start` hook
+    /// succeeds.
+    pub fn loading(&self, backend_id: &BackendId) {
+        self.not_started.insert(backend_id.clone());
+    }
+
+    /// Run a hook for a backend, if one is configured, and wait for it to
+    /// exit. Fails if it exits unsuccessfully or outlives its timeout.
+    pub async fn run(
+        &self,
+        hook: LifecycleHook,
+        spawn_request: &SpawnRequest,
+        cluster: &ClusterName,
+        state: BackendState,
+    ) -> Result<()> {
+        let backend_id = &spawn_request.backend_id;
+        match hook {
+            LifecycleHook::PreStop if self.not_started.contains(backend_id) => return Ok(()),
+            LifecycleHook::PostTerminate if self.not_started.remove(backend_id).is_some() => {
+                return Ok(())
+            }
+            _ => {}
+        }
+
+        let result = self.run_command(hook, spawn_request, cluster, state).await;
+        if hook == LifecycleHook::PreStart && result.is_ok() {
+            self.not_started.remove(backend_id);
+        }
+
+        result
+    }
+
+    async fn run_command(
+        &self,
+        hook: LifecycleHook,
+        spawn_request: &SpawnRequest,
+        cluster: &ClusterName,
+        state: BackendState,
+    ) -> Result<()> {
+        let (program, args) = match self.command(hook) {
+            Some(command) => command
+                .split_first()
+                .ok_or_else(|| anyhow!("{} hook has no command.", hook))?,
+            None => return Ok(()),
+        };
+
+        tracing::info!(%hook, backend_id=%spawn_request.backend_id, "Running lifecycle hook.");
+        let mut child = Command::new(program)
+            .args(args)
+            .envs(hook_env(hook, spawn_request, cluster, state))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Error running {} hook.", hook))?;
+        let stderr = child
+            .stderr
+            .take()
+            .ok_or_else(|| anyhow!("{} hook has no stderr.", hook))?;
+
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let (status, stderr) = tokio::time::timeout(timeout, async {
+            tokio::join!(child.wait(), read_bounded(stderr, MAX_STDERR_BYTES))
+        })
+        .await
+        .map_err(|_| anyhow!("{} hook did not finish within {:?}.", hook, timeout))?;
+        let status = status?;
+
+        if !status.success() {
+            return Err(anyhow!(
+                "{} hook failed ({}): {}",
+                hook,
+                status,
+                String::from_utf8_lossy(&stderr).trim()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// The environment a hook is run with.
+fn hook_env(
+    hook: LifecycleHook,
+    spawn_request: &SpawnRequest,
+    cluster: &ClusterName,
+    state: BackendState,
+) -> Vec<(&'static str, String)> {
+    vec![
+        ("PLANE_HOOK", hook.to_string()),
+        ("PLANE_BACKEND_ID", spawn_request.backend_id.to_string()),
+        ("PLANE_DRONE_ID", spawn_request.drone_id.to_string()),
+        ("PLANE_CLUSTER", cluster.hostname().to_string()),
+        ("PLANE_IMAGE", spawn_request.executable.image.clone()),
+        ("PLANE_BACKEND_STATE", state.to_string()),
+        (
+            "PLANE_BACKEND_METADATA",
+            json!(spawn_request.metadata).to_string(),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn spawn_request() -> SpawnRequest {
+        serde_json::from_value(json!({
+            "drone_id": "drone1",
+            "max_idle_secs": 30,
+            "backend_id": "backend1",
+            "metadata": {"game": "chess"},
+            "executable": {
+                "image": "ghcr.io/example/game:latest",
+                "env": {},
+                "credentials": null
+            }
+        }))
+        .unwrap()
+    }
+
+    fn argv(args: &[&str]) -> Option<Vec<String>> {
+        Some(args.iter().map(ToString::to_string).collect())
+    }
+
+    fn configured_hooks(pre_start: &[&str], post_terminate: &[&str]) -> Hooks {
+        Hooks::new(HooksConfig {
+            pre_start: argv(pre_start),
+            post_ready: None,
+            pre_stop: None,
+            post_terminate: argv(post_terminate),
+            timeout_secs: 1,
+        })
+    }
+
+    async fn run(hooks: &Hooks, hook: LifecycleHook) -> Result<()> {
+        hooks
+            .run(
+                hook,
+                &spawn_request(),
+                &ClusterName::new("plane.test"),
+                BackendState::Loading,
+            )
+            .await
+    }
+
+    #[test]
+    fn test_hook_env() {
+        let spawn_request = spawn_request();
+        let env: HashMap<_, _> = hook_env(
+            LifecycleHook::PostReady,
+            &spawn_request,
+            &ClusterName::new("plane.test"),
+            BackendState::Ready,
+        )
+        .into_iter()
+        .collect();
+
+        assert_eq!("post_ready", env["PLANE_HOOK"]);
+        assert_eq!("backend1", env["PLANE_BACKEND_ID"]);
+        assert_eq!("drone1", env["PLANE_DRONE_ID"]);
+        assert_eq!("plane.test", env["PLANE_CLUSTER"]);
+        assert_eq!("ghcr.io/example/game:latest", env["PLANE_IMAGE"]);
+        assert_eq!("Ready", env["PLANE_BACKEND_STATE"]);
+        assert_eq!(r#"{"game":"chess"}"#, env["PLANE_BACKEND_METADATA"]);
+    }
+
+    #[tokio::test]
+    async fn test_run_success() {
+        let hooks = configured_hooks(&["true"], &["true"]);
+        run(&hooks, LifecycleHook::PreStart).await.unwrap();
+        // Hooks which are not configured succeed.
+        run(&hooks, LifecycleHook::PostReady).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_failure() {
+        let hooks = configured_hooks(&["ls", "/nonexistent-plane-hook-path"], &["true"]);
+        let error = run(&hooks, LifecycleHook::PreStart).await.unwrap_err();
+        assert!(error.to_string().starts_with("pre_start hook failed"));
+
+        let hooks = configured_hooks(&["/nonexistent-plane-hook"], &["true"]);
+        assert!(run(&hooks, LifecycleHook::PreStart).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_timeout() {
+        let hooks = configured_hooks(&["sleep", "5"], &["true"]);
+        let error = run(&hooks, LifecycleHook::PreStart).await.unwrap_err();
+        assert!(error.to_string().contains("did not finish"));
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_skipped_after_failed_pre_start() {
+        // post_terminate would fail if it were run.
+        let hooks = configured_hooks(&["false"], &["false"]);
+        hooks.loading(&spawn_request().backend_id);
+        assert!(run(&hooks, LifecycleHook::PreStart).await.is_err());
+        run(&hooks, LifecycleHook::PreStop).await.unwrap();
+        run(&hooks, LifecycleHook::PostTerminate).await.unwrap();
+
+        let hooks = configured_hooks(&["true"], &["false"]);
+        hooks.loading(&spawn_request().backend_id);
+        run(&hooks, LifecycleHook::PreStart).await.unwrap();
+        assert!(run(&hooks, LifecycleHook::PostTerminate).await.is_err());
+    }
+}
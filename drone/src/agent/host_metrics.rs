@@ -0,0 +1,108 @@
+//! Collection of the host resource information included in drone status messages.
+
+use super::engine::{Engine, EngineResourceUsage};
+use futures::future::join_all;
+use plane_core::{logging::LogError, messages::agent::HostMetrics};
+use std::{fs::read_to_string, time::Duration};
+
+/// How long to wait for the engine to measure the usage of backends, so that
+/// a slow engine does not delay the drone's status message.
+const BACKEND_USAGE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Parse the memory available for new processes, in bytes, from the contents
+/// of `/proc/meminfo`.
+fn parse_available_memory(meminfo: &str) -> Option<u64> {
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+
+    Some(kilobytes * 1024)
+}
+
+/// Parse the one-minute load average from the contents of `/proc/loadavg`.
+fn parse_load_average(loadavg: &str) -> Option<f64> {
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+async fn engine_disk_free_bytes<E: Engine>(engine: &E) -> Option<u64> {
+    let data_dir = engine.data_dir().await;
+    data_dir.log_error("Error getting engine data directory.");
+    let data_dir = data_dir.ok()?;
+
+    // The data directory may not be visible if the drone itself runs in a container.
+    fs2::available_space(&data_dir).ok()
+}
+
+/// The combined resource usage of every backend the engine has, and the
+/// number of backends whose usage could not be measured.
+async fn backend_usage<E: Engine>(engine: &E) -> Option<(EngineResourceUsage, u32)> {
+    let backends = engine.list_backends().await;
+    backends.log_error("Error listing backends.");
+    let backends = backends.ok()?;
+
+    let usages = tokio::time::timeout(
+        BACKEND_USAGE_TIMEOUT,
+        join_all(backends.iter().map(|backend| engine.usage(backend))),
+    )
+    .await;
+    usages.log_error("Timed out measuring backend usage.");
+    let usages = match usages {
+        Ok(usages) => usages,
+        Err(_) => return Some((EngineResourceUsage::default(), backends.len() as u32)),
+    };
+
+    let (usages, failures): (Vec<_>, Vec<_>) = usages.into_iter().partition(Result::is_ok);
+    if let Some(Err(error)) = failures.first() {
+        tracing::warn!(
+            ?error,
+            failures = failures.len(),
+            "Error measuring backend usage."
+        );
+    }
+
+    Some((
+        EngineResourceUsage::total(usages.into_iter().filter_map(Result::ok)),
+        failures.len() as u32,
+    ))
+}
+
+pub async fn host_metrics<E: Engine>(engine: &E) -> HostMetrics {
+    let meminfo = read_to_string("/proc/meminfo").ok();
+    let loadavg = read_to_string("/proc/loadavg").ok();
+
+    let cached_images = engine.image_names().await;
+    cached_images.log_error("Error listing images.");
+    let image_digests = engine.image_digests().await;
+    image_digests.log_error("Error listing image digests.");
+    let (backend_usage, backend_usage_failures) = backend_usage(engine).await.unwrap_or_default();
+
+    HostMetrics {
+        free_memory_bytes: meminfo.as_deref().and_then(parse_available_memory),
+        load_average: loadavg.as_deref().and_then(parse_load_average),
+        docker_disk_free_bytes: engine_disk_free_bytes(engine).await,
+        cached_images: cached_images.unwrap_or_default(),
+        image_digests: image_digests.unwrap_or_default(),
+        reclaimed_image_bytes: engine.reclaimed_image_bytes(),
+        backend_memory_bytes: backend_usage.memory_bytes,
+        backend_disk_bytes: backend_usage.disk_bytes,
+        backend_usage_failures,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_files() {
+        let meminfo = "MemTotal:       16314376 kB\nMemFree:         1234567 kB\nMemAvailable:    8000000 kB\n";
+        assert_eq!(Some(8_192_000_000), parse_available_memory(meminfo));
+        assert_eq!(None, parse_available_memory("MemTotal: 100 kB\n"));
+
+        assert_eq!(
+            Some(0.52),
+            parse_load_average("0.52 0.58 0.59 1/1234 56789\n")
+        );
+    }
+}
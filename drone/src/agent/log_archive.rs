@@ -0,0 +1,389 @@
+//! Archival of backend logs to S3-compatible object storage.
+//!
+//! Log messages of each backend are buffered as newline-delimited JSON and
+//! uploaded in parts under `{prefix}{backend_id}/`. The final part is
+//! uploaded once the backend's log stream ends, so that logs outlive the
+//! container. Requests are signed with AWS Signature Version 4.
+
+use crate::config::LogArchiveConfig;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use openssl::{hash::MessageDigest, pkey::PKey, sha::sha256, sign::Signer};
+use plane_core::{messages::agent::DroneLogMessage, retry::do_with_retry, types::BackendId};
+use reqwest::{Client, Url};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+const UPLOAD_RETRIES: u16 = 5;
+const UPLOAD_RETRY_DELAY: Duration = Duration::from_secs(2);
+const S3_SERVICE: &str = "s3";
+
+#[derive(Clone)]
+pub struct LogArchiver {
+    config: Arc<LogArchiveConfig>,
+    client: Client,
+}
+
+impl LogArchiver {
+    pub fn new(config: LogArchiveConfig) -> Self {
+        LogArchiver {
+            config: Arc::new(config),
+            client: Client::new(),
+        }
+    }
+
+    /// Start archiving the logs of a backend. Messages sent to the returned
+    /// channel are uploaded in parts; the remainder is uploaded when the
+    /// sender is dropped.
+    ///
+    /// The archiving task is detached, so that it finishes uploading even
+    /// after the backend's monitor is dropped.
+    pub fn archive(&self, backend_id: &BackendId) -> UnboundedSender<DroneLogMessage> {
+        let (send, recv) = unbounded_channel();
+        let archiver = self.clone();
+        let backend_id = backend_id.clone();
+
+        tokio::spawn(async move { archiver.archive_loop(backend_id, recv).await });
+
+        send
+    }
+
+    async fn archive_loop(
+        &self,
+        backend_id: BackendId,
+        mut recv: UnboundedReceiver<DroneLogMessage>,
+    ) {
+        // Backends resumed after a drone restart start a new series of
+        // parts, rather than overwriting the previous one.
+        let started = Utc::now().timestamp();
+        let mut part: u32 = 0;
+        let mut buffer: Vec<u8> = Vec::new();
+
+        while let Some(message) = recv.recv().await {
+            if let Err(error) = serde_json::to_writer(&mut buffer, &message) {
+                tracing::warn!(?error, %backend_id, "Could not serialize log message.");
+                continue;
+            }
+            buffer.push(b'\n');
+
+            if buffer.len() >= self.config.part_bytes {
+                self.upload_part(&backend_id, started, part, std::mem::take(&mut buffer))
+                    .await;
+                part += 1;
+            }
+        }
+
+        if !buffer.is_empty() {
+            self.upload_part(&backend_id, started, part, buffer).await;
+        }
+
+        tracing::info!(%backend_id, "Log archive complete.");
+    }
+
+    async fn upload_part(&self, backend_id: &BackendId, started: i64, part: u32, body: Vec<u8>) {
+        let key = object_key(&self.config.prefix, backend_id, started, part);
+
+        let result = do_with_retry(
+            || self.put_object(&key, body.clone()),
+            UPLOAD_RETRIES,
+            UPLOAD_RETRY_DELAY,
+        )
+        .await;
+
+        match result {
+            Ok(()) => tracing::info!(%backend_id, %key, "Uploaded log archive part."),
+            Err(error) => {
+                tracing::error!(?error, %backend_id, %key, "Could not upload log archive part.")
+            }
+        }
+    }
+
+    async fn put_object(&self, key: &str, body: Vec<u8>) -> Result<()> {
+        let request = sign_put(&self.config, key, &body, Utc::now())?;
+
+        let mut builder = self.client.put(request.url);
+        for (name, value) in request.headers {
+            builder = builder.header(name, value);
+        }
+        let response = builder.body(body).send().await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            return Err(anyhow!("Upload failed with status {}: {}", status, text));
+        }
+
+        Ok(())
+    }
+}
+
+fn object_key(prefix: &str, backend_id: &BackendId, started: i64, part: u32) -> String {
+    format!("{}{}/{}-{:04}.ndjson", prefix, backend_id, started, part)
+}
+
+struct SignedRequest {
+    url: Url,
+    headers: Vec<(&'static str, String)>,
+}
+
+/// Build the URL and headers of a signed PUT of `body` to `key`.
+fn sign_put(
+    config: &LogArchiveConfig,
+    key: &str,
+    body: &[u8],
+    now: DateTime<Utc>,
+) -> Result<SignedRequest> {
+    let endpoint = Url::parse(&config.endpoint)?;
+    let host = match (endpoint.host_str(), endpoint.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return Err(anyhow!("Log archive endpoint has no host.")),
+    };
+    let path = format!(
+        "{}/{}/{}",
+        endpoint.path().trim_end_matches('/'),
+        uri_encode(&config.bucket),
+        uri_encode(key)
+    );
+    let url = Url::parse(&format!("{}://{}{}", endpoint.scheme(), host, path))?;
+
+    let date = format!("{:04}{:02}{:02}", now.year(), now.month(), now.day());
+    let timestamp = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        now.hour(),
+        now.minute(),
+        now.second()
+    );
+    let payload_hash = hex(&sha256(body));
+
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+    let canonical_request = format!(
+        "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+        path, host, payload_hash, timestamp, signed_headers, payload_hash
+    );
+
+    let signature = signature(
+        &config.secret_access_key,
+        &config.region,
+        S3_SERVICE,
+        &timestamp,
+        &canonical_request,
+    )?;
+
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        config.access_key_id,
+        credential_scope(&date, &config.region, S3_SERVICE),
+        signed_headers,
+        signature
+    );
+
+    Ok(SignedRequest {
+        url,
+        headers: vec![
+            ("x-amz-content-sha256", payload_hash),
+            ("x-amz-date", timestamp),
+            ("authorization", authorization),
+        ],
+    })
+}
+
+fn credential_scope(date: &str, region: &str, service: &str) -> String {
+    format!("{}/{}/{}/aws4_request", date, region, service)
+}
+
+/// Derive the key which signs requests to `service` on `date` (`YYYYMMDD`).
+fn signing_key(
+    secret_access_key: &str,
+    date: &str,
+    region: &str,
+    service: &str,
+) -> Result<Vec<u8>> {
+    let key = hmac(
+        format!("AWS4{}", secret_access_key).as_bytes(),
+        date.as_bytes(),
+    )?;
+    let key = hmac(&key, region.as_bytes())?;
+    let key = hmac(&key, service.as_bytes())?;
+    hmac(&key, b"aws4_request")
+}
+
+/// Sign a canonical request made at `timestamp` (`YYYYMMDDTHHMMSSZ`),
+/// returning the hex-encoded signature.
+fn signature(
+    secret_access_key: &str,
+    region: &str,
+    service: &str,
+    timestamp: &str,
+    canonical_request: &str,
+) -> Result<String> {
+    let date = timestamp
+        .get(..8)
+        .ok_or_else(|| anyhow!("Invalid signing timestamp {:?}.", timestamp))?;
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp,
+        credential_scope(date, region, service),
+        hex(&sha256(canonical_request.as_bytes()))
+    );
+
+    let key = signing_key(secret_access_key, date, region, service)?;
+    Ok(hex(&hmac(&key, string_to_sign.as_bytes())?))
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encode a path for signing, leaving unreserved characters and
+/// the slashes between path segments as they are.
+fn uri_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn config() -> LogArchiveConfig {
+        LogArchiveConfig {
+            endpoint: "http://localhost:9000/".into(),
+            bucket: "plane-logs".into(),
+            region: "us-east-1".into(),
+            access_key_id: "AKIDEXAMPLE".into(),
+            secret_access_key: "secret".into(),
+            prefix: "drone-logs/".into(),
+            part_bytes: 1024,
+        }
+    }
+
+    #[test]
+    fn test_object_key() {
+        let backend_id = BackendId::new("abc123".into());
+        assert_eq!(
+            "drone-logs/abc123/1669000000-0002.ndjson",
+            object_key("drone-logs/", &backend_id, 1669000000, 2)
+        );
+    }
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!("a/b-c_d.e~f", uri_encode("a/b-c_d.e~f"));
+        assert_eq!("a%20b/c%2Bd", uri_encode("a b/c+d"));
+    }
+
+    // Known answers from the examples in AWS's Signature Version 4
+    // documentation and test suite.
+
+    #[test]
+    fn test_signing_key() {
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        )
+        .unwrap();
+        assert_eq!(
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d",
+            hex(&key)
+        );
+    }
+
+    #[test]
+    fn test_signature_get_vanilla() {
+        let canonical_request = "GET\n/\n\n\
+            host:example.amazonaws.com\n\
+            x-amz-date:20150830T123600Z\n\n\
+            host;x-amz-date\n\
+            e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(
+            "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+            signature(
+                "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+                "us-east-1",
+                "service",
+                "20150830T123600Z",
+                canonical_request,
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_signature_s3_put_object() {
+        let payload_hash = hex(&sha256(b"Welcome to Amazon S3."));
+        assert_eq!(
+            "44ce7dd67c959e0d3524ffac1771dfbba87d2b6b4b4e99e42034a8b803f8b072",
+            payload_hash
+        );
+
+        let canonical_request = format!(
+            "PUT\n/{}\n\n\
+             date:Fri, 24 May 2013 00:00:00 GMT\n\
+             host:examplebucket.s3.amazonaws.com\n\
+             x-amz-content-sha256:{}\n\
+             x-amz-date:20130524T000000Z\n\
+             x-amz-storage-class:REDUCED_REDUNDANCY\n\n\
+             date;host;x-amz-content-sha256;x-amz-date;x-amz-storage-class\n\
+             {}",
+            uri_encode("test$file.text"),
+            payload_hash,
+            payload_hash
+        );
+        assert_eq!(
+            "98ad721746da40c64f1a55b78f14c238d841ea1380cd77a1b5971af0ece108bd",
+            signature(
+                "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+                "us-east-1",
+                S3_SERVICE,
+                "20130524T000000Z",
+                &canonical_request,
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sign_put() {
+        let now = Utc.ymd(2022, 11, 28).and_hms(9, 30, 5);
+        let request = sign_put(&config(), "drone-logs/abc/1-0000.ndjson", b"{}\n", now).unwrap();
+
+        assert_eq!(
+            "http://localhost:9000/plane-logs/drone-logs/abc/1-0000.ndjson",
+            request.url.as_str()
+        );
+
+        let header = |name: &str| {
+            request
+                .headers
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.clone())
+                .unwrap()
+        };
+        assert_eq!("20221128T093005Z", header("x-amz-date"));
+        assert!(header("authorization").starts_with(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20221128/us-east-1/s3/aws4_request, \
+             SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature="
+        ));
+    }
+}
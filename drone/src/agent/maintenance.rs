@@ -0,0 +1,176 @@
+use super::{engine::Engine, executor::Executor};
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use plane_core::{
+    logging::LogError,
+    messages::scheduler::{
+        DroneMaintenanceEvent, MaintenancePhase, MaintenanceWindow, ScheduleMaintenance,
+    },
+    nats::TypedNats,
+    types::{ClusterName, DroneId},
+    NeverResult,
+};
+use std::time::Duration;
+use tokio::sync::watch::{Receiver, Sender};
+
+/// Maximum time to sleep before re-checking maintenance windows, so that
+/// clock adjustments are picked up in a reasonable time.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+struct MaintenanceSchedule {
+    /// Windows which have not started yet, ordered by start time.
+    pending: Vec<MaintenanceWindow>,
+
+    /// The window currently in effect, if any.
+    active: Option<MaintenanceWindow>,
+}
+
+impl MaintenanceSchedule {
+    fn new(windows: Vec<MaintenanceWindow>, now: DateTime<Utc>) -> Self {
+        let mut schedule = MaintenanceSchedule {
+            pending: Vec::new(),
+            active: None,
+        };
+        for window in windows {
+            schedule.add(window, now);
+        }
+        schedule
+    }
+
+    /// Add a window to the schedule. Returns false if the window has already ended.
+    fn add(&mut self, window: MaintenanceWindow, now: DateTime<Utc>) -> bool {
+        if window.end() <= now {
+            tracing::warn!(
+                ?window,
+                "Ignoring maintenance window which has already ended."
+            );
+            return false;
+        }
+
+        self.pending.push(window);
+        self.pending.sort_by_key(|d| d.start);
+        true
+    }
+
+    /// The next time at which the drone needs to change phase.
+    fn next_deadline(&self) -> Option<DateTime<Utc>> {
+        if let Some(active) = &self.active {
+            Some(active.end())
+        } else {
+            self.pending.first().map(|d| d.start)
+        }
+    }
+}
+
+async fn publish_phase(
+    nc: &TypedNats,
+    drone_id: &DroneId,
+    cluster: &ClusterName,
+    window: &MaintenanceWindow,
+    phase: MaintenancePhase,
+) {
+    tracing::info!(?phase, ?window, "Maintenance window changed phase.");
+    nc.publish(&DroneMaintenanceEvent {
+        drone: drone_id.clone(),
+        cluster: cluster.clone(),
+        phase,
+        window: window.clone(),
+        time: Utc::now(),
+    })
+    .await
+    .log_error("Error publishing maintenance event.");
+}
+
+/// Cordon (and optionally drain) the drone during scheduled maintenance windows,
+/// returning it to service when each window ends unless an operator has
+/// drained it in the meantime.
+#[allow(clippy::too_many_arguments)]
+pub async fn maintenance_loop<E: Engine>(
+    nc: TypedNats,
+    drone_id: DroneId,
+    cluster: ClusterName,
+    windows: Vec<MaintenanceWindow>,
+    send_ready: &Sender<bool>,
+    send_in_maintenance: &Sender<bool>,
+    recv_draining: Receiver<bool>,
+    executor: Executor<E>,
+) -> NeverResult {
+    let mut sub = nc
+        .subscribe(ScheduleMaintenance::subscribe_subject(
+            drone_id.clone(),
+            cluster.clone(),
+        ))
+        .await?;
+    let mut schedule = MaintenanceSchedule::new(windows, Utc::now());
+
+    for window in &schedule.pending {
+        publish_phase(
+            &nc,
+            &drone_id,
+            &cluster,
+            window,
+            MaintenancePhase::Scheduled,
+        )
+        .await;
+    }
+
+    loop {
+        let sleep_duration = schedule
+            .next_deadline()
+            .map(|deadline| {
+                deadline
+                    .signed_duration_since(Utc::now())
+                    .to_std()
+                    .unwrap_or_default()
+            })
+            .unwrap_or(MAX_SLEEP)
+            .min(MAX_SLEEP);
+
+        tokio::select! {
+            _ = tokio::time::sleep(sleep_duration) => {
+                let now = Utc::now();
+
+                if let Some(active) = &schedule.active {
+                    if active.end() <= now {
+                        send_in_maintenance
+                            .send(false)
+                            .log_error("Error sending maintenance state.");
+                        send_ready
+                            .send(!*recv_draining.borrow())
+                            .log_error("Error sending ready instruction.");
+                        publish_phase(&nc, &drone_id, &cluster, active, MaintenancePhase::Completed).await;
+                        schedule.active = None;
+                    }
+                } else if schedule.pending.first().map(|d| d.start <= now).unwrap_or_default() {
+                    let window = schedule.pending.remove(0);
+
+                    send_in_maintenance
+                        .send(true)
+                        .log_error("Error sending maintenance state.");
+                    send_ready
+                        .send(false)
+                        .log_error("Error sending drain instruction.");
+                    publish_phase(&nc, &drone_id, &cluster, &window, MaintenancePhase::Cordoned).await;
+
+                    if window.drain {
+                        executor.terminate_all_backends().await;
+                        publish_phase(&nc, &drone_id, &cluster, &window, MaintenancePhase::Drained).await;
+                    }
+
+                    schedule.active = Some(window);
+                }
+            }
+
+            req = sub.next() => {
+                let req = req.ok_or_else(|| anyhow!("Reached the end of ScheduleMaintenance subscription."))?;
+                tracing::info!(window=?req.value.window, "Received maintenance window.");
+                req.respond(&()).await?;
+
+                let window = req.value.window;
+                if schedule.add(window.clone(), Utc::now()) {
+                    publish_phase(&nc, &drone_id, &cluster, &window, MaintenancePhase::Scheduled).await;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! An HTTP API which backends call from inside their container, reachable at
+//! `http://plane.internal` when the metadata port is 80. Callers are
+//! identified by the IP address of their container, so no credentials are
+//! needed.
+//!
+//! - `POST /keepalive`: reset the backend's idle timer, e.g. while it is busy
+//!   computing without any open connections. With
+//!   `?extend_lifetime_secs=<n>`, the backend's maximum lifetime (if it has
+//!   one) is also extended by `n` seconds, up to the drone's configured
+//!   maximum extension in total.
+//! - `POST /terminate`: gracefully stop the backend, e.g. because the app
+//!   knows its session has ended. The backend then counts as having exited,
+//!   rather than waiting to be swept as idle.
+//!
+//! Backends which use the host's network share its address, so they cannot
+//! be told apart, and their calls are refused.
+
+use super::{engine::Engine, executor::Executor};
+use crate::database::DroneDatabase;
+use anyhow::anyhow;
+use hyper::{
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use plane_core::{types::BackendId, NeverResult};
+use serde_json::{json, Value};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+/// Hostname under which backends reach the metadata API.
+pub const METADATA_HOST: &str = "plane.internal";
+
+/// Environment variable which tells backends where the metadata API is.
+pub const METADATA_URL_ENV: &str = "PLANE_METADATA_URL";
+
+/// The URL backends reach the metadata API at.
+pub fn metadata_url(port: u16) -> String {
+    if port == 80 {
+        format!("http://{}", METADATA_HOST)
+    } else {
+        format!("http://{}:{}", METADATA_HOST, port)
+    }
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("Response with valid status and header should never fail to build.")
+}
+
+fn error_response(status: StatusCode, error: &str) -> Response<Body> {
+    json_response(status, &json!({ "error": error }))
+}
+
+/// Returns the value of the given query parameter, if it is present.
+fn get_query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+async fn keepalive(
+    db: &DroneDatabase,
+    remote_ip: IpAddr,
+    query: Option<&str>,
+    max_lifetime_extension: Duration,
+) -> anyhow::Result<Response<Body>> {
+    let extend_lifetime_secs = match get_query_param(query, "extend_lifetime_secs") {
+        Some(secs) => match secs.parse::<u32>() {
+            Ok(secs) => Some(secs),
+            Err(_) => {
+                return Ok(error_response(
+                    StatusCode::BAD_REQUEST,
+                    "extend_lifetime_secs must be a non-negative integer.",
+                ))
+            }
+        },
+        None => None,
+    };
+
+    let (backend_id, subdomain) = match caller(db, remote_ip).await? {
+        Ok(backend) => backend,
+        Err(response) => return Ok(response),
+    };
+
+    db.reset_last_active_times(&[subdomain]).await?;
+    if let Some(secs) = extend_lifetime_secs {
+        tracing::info!(%backend_id, secs, "Backend extended its lifetime.");
+        db.extend_backend_lifetime(
+            &backend_id,
+            secs.into(),
+            max_lifetime_extension.as_secs().try_into()?,
+        )
+        .await?;
+    }
+
+    Ok(json_response(
+        StatusCode::OK,
+        &json!({
+            "backend_id": backend_id,
+            "lifetime_extension_secs": db.get_backend_lifetime_extension(&backend_id).await?,
+        }),
+    ))
+}
+
+async fn terminate<E: Engine>(
+    executor: &Executor<E>,
+    db: &DroneDatabase,
+    remote_ip: IpAddr,
+) -> anyhow::Result<Response<Body>> {
+    let (backend_id, _) = match caller(db, remote_ip).await? {
+        Ok(backend) => backend,
+        Err(response) => return Ok(response),
+    };
+
+    tracing::info!(%backend_id, "Backend asked to be terminated.");
+    if let Err(error) = executor.exit_backend(&backend_id).await {
+        // The backend was stopped between being looked up and signalled.
+        tracing::info!(?error, %backend_id, "Backend stopped before it could be terminated.");
+        return Ok(error_response(
+            StatusCode::CONFLICT,
+            "Backend is no longer running.",
+        ));
+    }
+
+    Ok(json_response(
+        StatusCode::ACCEPTED,
+        &json!({ "backend_id": backend_id }),
+    ))
+}
+
+/// The backend calling from the given address and its subdomain, or the
+/// response to send if the caller cannot be identified as a single running
+/// backend.
+async fn caller(
+    db: &DroneDatabase,
+    remote_ip: IpAddr,
+) -> anyhow::Result<Result<(BackendId, String), Response<Body>>> {
+    let mut backends = db.get_backends_by_ip(remote_ip).await?;
+    Ok(match backends.len() {
+        0 => Err(error_response(
+            StatusCode::FORBIDDEN,
+            "Caller is not a running backend.",
+        )),
+        1 => Ok(backends.remove(0)),
+        _ => Err(error_response(
+            StatusCode::CONFLICT,
+            "Several backends share the caller's address (e.g. because they use the host's network), so it cannot be identified.",
+        )),
+    })
+}
+
+async fn handle<E: Engine>(
+    executor: &Executor<E>,
+    db: &DroneDatabase,
+    remote_ip: IpAddr,
+    method: &Method,
+    path: &str,
+    query: Option<&str>,
+    max_lifetime_extension: Duration,
+) -> anyhow::Result<Response<Body>> {
+    let path = path.trim_end_matches('/');
+    if path != "/keepalive" && path != "/terminate" {
+        return Ok(error_response(StatusCode::NOT_FOUND, "Not found."));
+    }
+    if *method != Method::POST {
+        return Ok(error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed.",
+        ));
+    }
+
+    if path == "/terminate" {
+        terminate(executor, db, remote_ip).await
+    } else {
+        keepalive(db, remote_ip, query, max_lifetime_extension).await
+    }
+}
+
+/// Serve the metadata API on the given port of every interface, so that it
+/// is reachable from container networks. Requests from anything but a
+/// running backend are refused.
+pub async fn serve_metadata<E: Engine>(
+    port: u16,
+    executor: Executor<E>,
+    db: DroneDatabase,
+    max_lifetime_extension: Duration,
+) -> NeverResult {
+    let addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port);
+
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let executor = executor.clone();
+        let db = db.clone();
+        let remote_ip = conn.remote_addr().ip();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let executor = executor.clone();
+                let db = db.clone();
+                async move {
+                    let path = req.uri().path();
+                    let query = req.uri().query();
+                    let response = match handle(
+                        &executor,
+                        &db,
+                        remote_ip,
+                        req.method(),
+                        path,
+                        query,
+                        max_lifetime_extension,
+                    )
+                    .await
+                    {
+                        Ok(response) => response,
+                        Err(error) => {
+                            tracing::error!(?error, %path, "Error in metadata API.");
+                            error_response(StatusCode::INTERNAL_SERVER_ERROR, &error.to_string())
+                        }
+                    };
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+
+    tracing::info!(%addr, "Serving metadata API.");
+    Server::try_bind(&addr)?.serve(make_service).await?;
+
+    Err(anyhow!("Metadata server exited."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_url() {
+        assert_eq!("http://plane.internal", metadata_url(80));
+        assert_eq!("http://plane.internal:8000", metadata_url(8000));
+    }
+}
@@ -1,31 +1,76 @@
-use self::executor::Executor;
+use self::{
+    admin::serve_admin,
+    disk_pressure::DiskPressure,
+    engine::Engine,
+    exec::{listen_for_attach_requests, listen_for_exec_requests, ExecAccess},
+    executor::Executor,
+    host_metrics::host_metrics,
+    maintenance::maintenance_loop,
+    metadata::serve_metadata,
+    registration::registration_loop,
+    update::update_loop,
+};
+#[cfg(feature = "containerd")]
+use crate::agent::engines::containerd::ContainerdInterface;
 use crate::{
-    agent::engines::docker::DockerInterface, config::DockerConfig, database::DroneDatabase,
+    agent::engines::docker::DockerInterface,
+    cert::install_certificate_update,
+    config::{
+        ContainerdConfig, DockerConfig, HooksConfig, LogArchiveConfig, SpawnLimitConfig,
+        UpdateConfig,
+    },
+    database::DroneDatabase,
     ip::IpSource,
+    keys::KeyCertPathPair,
+    proxy::{metrics::ProxyMetricsTracker, SELF_TEST_HEADER},
 };
 use anyhow::{anyhow, Result};
-use http::Uri;
-use hyper::Client;
+use chrono::Utc;
 use plane_core::{
+    error::PlaneError,
     logging::LogError,
     messages::{
-        agent::{DroneConnectRequest, DroneStatusMessage, SpawnRequest, TerminationRequest},
-        scheduler::DrainDrone,
+        agent::{
+            normalize_arch, BackendMetadataMessage, DroneConnectRequest, DroneStatusMessage,
+            MigrateBackend, SpawnRequest, StatsRequest, StatsResponse, TerminateAllRequest,
+            TerminationRequest, UpdateBackendMetadata,
+        },
+        cert::CertificateUpdate,
+        scheduler::{DrainDrone, MaintenanceWindow, SeedImage},
     },
     nats::TypedNats,
     retry::do_with_retry,
-    types::{ClusterName, DroneId},
+    sealing::SealingKey,
+    signing::{SeenNonces, SigningKey, VerifyingKey},
+    types::{BackendId, ClusterName, DroneId},
     NeverResult,
 };
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::sync::watch::{self, Receiver, Sender};
 
 const PLANE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+mod admin;
 mod backend;
-mod engine;
+mod disk_pressure;
+pub mod engine;
 mod engines;
-mod executor;
+mod env_template;
+pub mod exec;
+pub mod executor;
+mod hooks;
+mod host_metrics;
+mod log_archive;
+mod maintenance;
+pub mod metadata;
+mod port_ready;
+mod registration;
+mod spawn_limit;
+mod update;
 
 pub struct AgentOptions {
     pub drone_id: DroneId,
@@ -36,24 +81,150 @@ pub struct AgentOptions {
     /// Public IP of the machine the drone is running on.
     pub ip: IpSource,
 
+    /// Public IPv6 address of the machine, if backends should also be
+    /// reachable over IPv6 and `ip` is an IPv4 address.
+    pub ipv6: Option<IpSource>,
+
     pub docker_options: DockerConfig,
-}
 
-pub async fn wait_port_ready(addr: &SocketAddr) -> Result<()> {
-    tracing::info!(%addr, "Waiting for ready port.");
+    /// If provided, backends are run with containerd instead of Docker.
+    pub containerd_options: Option<ContainerdConfig>,
+
+    /// Maintenance windows known at startup. More can be scheduled over NATS.
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+
+    /// How often to publish the drone's status.
+    pub heartbeat_interval: Duration,
+
+    /// How long a backend may spend loading before it is considered to have
+    /// failed.
+    pub load_timeout: Duration,
+
+    /// If provided, the most backends the drone advertises capacity for.
+    pub max_backends: Option<u32>,
+
+    /// If provided, limits on how backends are loaded.
+    pub spawn_limit: Option<SpawnLimitConfig>,
+
+    /// If the proxy runs alongside the agent, how to reach it to check that
+    /// each backend is reachable through it before marking it ready.
+    pub proxy_self_test: Option<ProxySelfTest>,
+
+    /// If the proxy runs alongside the agent, its traffic, which is reported
+    /// in the drone's status.
+    pub proxy_metrics: Option<ProxyMetricsTracker>,
+
+    /// If provided, the agent serves an admin HTTP API on this port of the
+    /// loopback interface.
+    pub admin_port: Option<u16>,
+
+    /// If provided, the token requests to the admin API other than health
+    /// checks must carry.
+    pub admin_token: Option<String>,
+
+    /// If provided, the agent serves the metadata API to backends on this
+    /// port.
+    pub metadata_port: Option<u16>,
+
+    /// The most a backend may extend its maximum lifetime by through the
+    /// metadata API, in total.
+    pub max_lifetime_extension: Duration,
+
+    /// If provided, the drone refuses new backends while the engine's data
+    /// directory has less than this many bytes of disk free.
+    pub min_disk_free_bytes: Option<u64>,
+
+    /// If provided, certificates pushed by the controller are written to
+    /// these paths, where the proxy picks them up.
+    pub cert_paths: Option<KeyCertPathPair>,
+
+    /// If provided, backend logs are archived to S3-compatible object
+    /// storage after the backend terminates.
+    pub log_archive: Option<LogArchiveConfig>,
+
+    /// If provided, commands run as backends move through their lifecycle.
+    pub hooks: Option<HooksConfig>,
+
+    /// If provided, commands can be run inside backends by signed requests.
+    pub exec_access: Option<ExecAccess>,
+
+    /// If provided, backends can be moved to other drones by requests signed
+    /// with the private key matching this one.
+    pub migrate_public_key: Option<VerifyingKey>,
+
+    /// If provided, every backend can be terminated at once by requests
+    /// signed with the private key matching this one.
+    pub terminate_all_public_key: Option<VerifyingKey>,
+
+    /// If provided, the drone updates itself to the version the controller
+    /// publishes for the cluster.
+    pub update: Option<UpdateConfig>,
+
+    /// If provided, base64-encoded Ed25519 seed of the key the drone
+    /// registers with the controller under.
+    pub registration_key: Option<String>,
 
-    let client = Client::new();
-    let uri = Uri::from_maybe_shared(format!("http://{}:{}/", addr.ip(), addr.port()))?;
+    /// If provided, certificates pushed by the controller are installed if
+    /// they are signed with this key.
+    pub certificate_public_key: Option<VerifyingKey>,
+}
 
-    do_with_retry(|| client.get(uri.clone()), 3000, Duration::from_millis(10)).await?;
+#[derive(Clone, Debug)]
+pub struct ProxySelfTest {
+    /// Address of the local proxy.
+    pub addr: SocketAddr,
 
-    Ok(())
+    /// Whether the proxy terminates TLS.
+    pub https: bool,
 }
 
-async fn listen_for_spawn_requests(
+/// Send a request to the backend through the local proxy, retrying until the
+/// proxy reports that it reached the backend.
+pub async fn wait_proxy_ready(
+    self_test: &ProxySelfTest,
+    backend: &BackendId,
+    cluster: &ClusterName,
+) -> Result<()> {
+    let host = format!("{}.{}", backend.id(), cluster.hostname());
+    tracing::info!(%host, addr=%self_test.addr, "Checking backend through proxy.");
+
+    // The proxy's certificate is issued for the public cluster name, which
+    // we are bypassing DNS for, so it is not verified here.
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .resolve(&host, self_test.addr)
+        .build()?;
+    let scheme = if self_test.https { "https" } else { "http" };
+    let url = format!("{}://{}:{}/", scheme, host, self_test.addr.port());
+
+    do_with_retry(
+        || async {
+            let response = client
+                .get(&url)
+                .header(SELF_TEST_HEADER, "1")
+                .send()
+                .await?;
+
+            if response.headers().contains_key(SELF_TEST_HEADER) {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "Proxy did not reach backend (status {}).",
+                    response.status()
+                ))
+            }
+        },
+        20,
+        Duration::from_millis(250),
+    )
+    .await
+}
+
+async fn listen_for_spawn_requests<E: Engine>(
     drone_id: &DroneId,
-    executor: Executor<DockerInterface>,
+    executor: Executor<E>,
     nats: TypedNats,
+    disk_pressure: Option<Arc<DiskPressure>>,
 ) -> NeverResult {
     let mut sub = nats
         .subscribe(SpawnRequest::subscribe_subject(drone_id))
@@ -66,9 +237,17 @@ async fn listen_for_spawn_requests(
 
         match req {
             Some(req) => {
+                // Refused up front, so that the controller can tell the
+                // backend was not placed rather than seeing it fail later.
+                if let Some(Err(error)) = disk_pressure.as_ref().map(|d| d.check()) {
+                    tracing::warn!(backend_id=%req.value.backend_id, %error, "Refused backend.");
+                    req.respond(&Err(error)).await?;
+                    continue;
+                }
+
                 let executor = executor.clone();
 
-                req.respond(&true).await?;
+                req.respond(&Ok(())).await?;
                 tokio::spawn(async move {
                     executor.start_backend(&req.value).await;
                 });
@@ -78,8 +257,8 @@ async fn listen_for_spawn_requests(
     }
 }
 
-async fn listen_for_termination_requests(
-    executor: Executor<DockerInterface>,
+async fn listen_for_termination_requests<E: Engine>(
+    executor: Executor<E>,
     nats: TypedNats,
     cluster: ClusterName,
 ) -> NeverResult {
@@ -91,37 +270,327 @@ async fn listen_for_termination_requests(
         let req = sub.next().await;
         match req {
             Some(req) => {
-                let executor = executor.clone();
+                // The backend belongs to another drone, which responds instead.
+                if !executor.has_backend(&req.value.backend_id) {
+                    continue;
+                }
 
-                req.respond(&()).await?;
-                tokio::spawn(async move { executor.kill_backend(&req.value).await });
+                let executor = executor.clone();
+                tokio::spawn(async move {
+                    if req.value.drain {
+                        // Draining can take up to the grace period, so respond first.
+                        req.respond(&Ok(()))
+                            .await
+                            .log_error("Error responding to termination request.");
+                        executor
+                            .kill_backend(&req.value)
+                            .await
+                            .log_error("Error terminating backend.");
+                    } else {
+                        let result = executor
+                            .kill_backend(&req.value)
+                            .await
+                            .map_err(|error| PlaneError::from_anyhow(&error));
+                        req.respond(&result)
+                            .await
+                            .log_error("Error responding to termination request.");
+                    }
+                });
             }
             None => return Err(anyhow!("Termination request subscription closed.")),
         }
     }
 }
 
+/// Listen for requests to terminate every backend running on this drone,
+/// which must be signed with the private key matching `public_key`.
+pub async fn listen_for_terminate_all_requests<E: Engine>(
+    executor: Executor<E>,
+    nats: TypedNats,
+    drone_id: DroneId,
+    cluster: ClusterName,
+    public_key: Option<VerifyingKey>,
+) -> NeverResult {
+    let mut sub = nats
+        .subscribe(TerminateAllRequest::subscribe_subject(&cluster, &drone_id))
+        .await?;
+    tracing::info!("Listening for terminate-all requests.");
+
+    let seen_nonces = SeenNonces::default();
+    while let Some(req) = sub.next().await {
+        let verified = match &public_key {
+            Some(public_key) => req
+                .verify_signature(public_key, &seen_nonces)
+                .map_err(|error| format!("Request was not authenticated: {}", error)),
+            None => Err("Terminating all backends is not enabled on this drone.".to_string()),
+        };
+        if let Err(reason) = verified {
+            tracing::warn!(%reason, "Refused terminate-all request.");
+            req.respond(&Err(PlaneError::Unauthenticated { reason }))
+                .await?;
+            continue;
+        }
+
+        tracing::info!(state=?req.value.state, "Terminating all backends.");
+        let result = executor
+            .terminate_backends(req.value.state)
+            .await
+            .map_err(|error| PlaneError::from_anyhow(&error));
+        req.respond(&result)
+            .await
+            .log_error("Error responding to terminate-all request.");
+    }
+
+    Err(anyhow!("Terminate-all request subscription closed."))
+}
+
+/// Listen for requests to move backends running on this drone to other drones.
+async fn listen_for_migrate_requests<E: Engine>(
+    executor: Executor<E>,
+    nats: TypedNats,
+    drone_id: DroneId,
+    cluster: ClusterName,
+    public_key: Option<VerifyingKey>,
+) -> NeverResult {
+    let mut sub = nats
+        .subscribe(MigrateBackend::subscribe_subject(&cluster))
+        .await?;
+    tracing::info!("Listening for migrate requests.");
+
+    let seen_nonces = SeenNonces::default();
+    while let Some(req) = sub.next().await {
+        // The backend belongs to another drone, which responds instead.
+        if !executor.has_backend(&req.value.backend_id) {
+            continue;
+        }
+
+        let verified = match &public_key {
+            Some(public_key) => req
+                .verify_signature(public_key, &seen_nonces)
+                .map_err(|error| format!("Request was not authenticated: {}", error)),
+            None => Err("Migrating backends is not enabled on this drone.".to_string()),
+        };
+        if let Err(reason) = verified {
+            tracing::warn!(backend=%req.value.backend_id, %reason, "Refused migrate request.");
+            req.respond(&Err(PlaneError::Unauthenticated { reason }))
+                .await?;
+            continue;
+        }
+
+        if req.value.target_drone == drone_id {
+            req.respond(&Err(PlaneError::InvalidRequest {
+                reason: "Backend is already running on the target drone.".into(),
+            }))
+            .await?;
+            continue;
+        }
+
+        let executor = executor.clone();
+        tokio::spawn(async move {
+            tracing::info!(
+                backend=%req.value.backend_id,
+                target_drone=%req.value.target_drone,
+                "Migrating backend."
+            );
+            let result = executor
+                .migrate_backend(&req.value)
+                .await
+                .map_err(|error| PlaneError::from_anyhow(&error));
+            req.respond(&result)
+                .await
+                .log_error("Error responding to migrate request.");
+        });
+    }
+
+    Err(anyhow!("Migrate request subscription closed."))
+}
+
+/// Listen for requests for the stats of backends running on this drone, or
+/// which ran on it and have stopped.
+async fn listen_for_stats_requests<E: Engine>(
+    executor: Executor<E>,
+    db: DroneDatabase,
+    nats: TypedNats,
+) -> NeverResult {
+    let mut sub = nats.subscribe(StatsRequest::subscribe_subject()).await?;
+    tracing::info!("Listening for stats requests.");
+
+    while let Some(req) = sub.next().await {
+        let backend_id = &req.value.backend_id;
+        let response = if executor.has_backend(backend_id) {
+            Ok(StatsResponse {
+                stats: executor.backend_stats(backend_id),
+            })
+        } else {
+            match db.get_backends().await {
+                Ok(backends)
+                    if backends
+                        .iter()
+                        .any(|backend| &backend.backend_id == backend_id) =>
+                {
+                    Err(PlaneError::NotFound {
+                        reason: format!("Backend {} is no longer running.", backend_id),
+                    })
+                }
+                // The backend belongs to another drone, which responds instead.
+                Ok(_) => continue,
+                // Another drone may run the backend, so this one does not
+                // respond with its own failure.
+                Err(error) => {
+                    tracing::error!(?error, %backend_id, "Error looking up backend for stats.");
+                    continue;
+                }
+            }
+        };
+
+        req.respond(&response)
+            .await
+            .log_error("Error responding to stats request.");
+    }
+
+    Err(anyhow!("Stats request subscription closed."))
+}
+
+/// Listen for requests to change the metadata of backends running on this drone.
+async fn listen_for_metadata_updates(
+    db: DroneDatabase,
+    nats: TypedNats,
+    cluster: ClusterName,
+) -> NeverResult {
+    let mut sub = nats
+        .subscribe(UpdateBackendMetadata::subscribe_subject(&cluster))
+        .await?;
+    tracing::info!("Listening for metadata update requests.");
+
+    while let Some(req) = sub.next().await {
+        if !req.subject_matches() {
+            tracing::warn!(
+                subject=%req.message().subject,
+                backend=%req.value.backend_id,
+                "Ignored metadata update for a backend other than its subject's."
+            );
+            continue;
+        }
+        let metadata = match db.update_backend_metadata(&req.value).await {
+            Ok(Some(metadata)) => metadata,
+            // The backend belongs to another drone, which responds instead.
+            Ok(None) => continue,
+            Err(error) => {
+                tracing::error!(?error, backend=%req.value.backend_id, "Error updating metadata.");
+                continue;
+            }
+        };
+
+        tracing::info!(backend=%req.value.backend_id, ?metadata, "Updated backend metadata.");
+        req.respond(&metadata).await?;
+        nats.publish(&BackendMetadataMessage {
+            backend: req.value.backend_id.clone(),
+            metadata,
+            time: Utc::now(),
+        })
+        .await
+        .log_error("Error publishing backend metadata.");
+    }
+
+    Err(anyhow!("Metadata update subscription closed."))
+}
+
+/// Listen for requests to pull images ahead of time.
+async fn listen_for_seed_requests<E: Engine + Clone>(
+    engine: E,
+    nats: TypedNats,
+    drone_id: DroneId,
+    cluster: ClusterName,
+) -> NeverResult {
+    let mut sub = nats
+        .subscribe(SeedImage::subscribe_subject(&drone_id, &cluster))
+        .await?;
+    tracing::info!("Listening for seed image requests.");
+
+    while let Some(req) = sub.next().await {
+        req.respond(&()).await?;
+
+        let engine = engine.clone();
+        tokio::spawn(async move {
+            engine
+                .seed_image(&req.value.image)
+                .await
+                .log_error("Error seeding image.");
+        });
+    }
+
+    Err(anyhow!("Seed image subscription closed."))
+}
+
+/// Number of further backends a drone accepts, given its configured maximum.
+fn remaining_capacity(max_backends: Option<u32>, running_backends: u32) -> Option<u32> {
+    max_backends.map(|max_backends| max_backends.saturating_sub(running_backends))
+}
+
 /// Repeatedly publish a status message advertising this drone as available.
-async fn ready_loop(
+/// A drone which is low on disk space advertises itself as not ready, and
+/// removes unused images when it first runs low.
+#[allow(clippy::too_many_arguments)]
+async fn ready_loop<E: Engine + Clone>(
     nc: TypedNats,
     drone_id: &DroneId,
     cluster: ClusterName,
     recv_ready: Receiver<bool>,
+    recv_draining: Receiver<bool>,
     db: DroneDatabase,
+    engine: E,
+    heartbeat_interval: Duration,
+    sealing_key: Option<String>,
+    max_backends: Option<u32>,
+    disk_pressure: Option<Arc<DiskPressure>>,
+    proxy_metrics: Option<ProxyMetricsTracker>,
 ) -> NeverResult {
-    let mut interval = tokio::time::interval(Duration::from_secs(4));
+    let mut interval = tokio::time::interval(heartbeat_interval);
 
     loop {
-        let ready = *recv_ready.borrow();
+        let host_metrics = host_metrics(&engine).await;
 
-        let running_backends = db.running_backends().await?;
+        if let (Some(disk_pressure), Some(free_bytes)) =
+            (&disk_pressure, host_metrics.docker_disk_free_bytes)
+        {
+            if disk_pressure.update(free_bytes) {
+                let engine = engine.clone();
+                tokio::spawn(async move {
+                    engine
+                        .collect_unused_images()
+                        .await
+                        .log_error("Error removing unused images.");
+                });
+            }
+        }
+
+        // A drone which refuses spawn requests for lack of disk space is not
+        // ready, even when the latest measurement failed.
+        let ready = *recv_ready.borrow()
+            && !disk_pressure
+                .as_ref()
+                .map_or(false, |disk_pressure| disk_pressure.is_under_pressure());
+
+        let draining = *recv_draining.borrow();
+
+        let running_backends = db.running_backends().await? as u32;
+        let remaining_capacity = remaining_capacity(max_backends, running_backends);
+        if remaining_capacity == Some(0) {
+            tracing::info!(%running_backends, "Drone is at capacity.");
+        }
 
         nc.publish_jetstream(&DroneStatusMessage {
             drone_id: drone_id.clone(),
             cluster: cluster.clone(),
             drone_version: PLANE_VERSION.to_string(),
             ready,
-            running_backends: Some(running_backends as u32),
+            running_backends: Some(running_backends),
+            remaining_capacity,
+            host_metrics: Some(host_metrics),
+            sealing_key: sealing_key.clone(),
+            arch: Some(normalize_arch(std::env::consts::ARCH)),
+            proxy_metrics: proxy_metrics.as_ref().map(ProxyMetricsTracker::snapshot),
+            draining,
         })
         .await
         .log_error("Error in ready loop.");
@@ -130,12 +599,50 @@ async fn ready_loop(
     }
 }
 
-/// Listen for drain instruction.
+/// Listen for TLS certificates pushed by the controller, and install those
+/// signed with the controller's certificate signing key.
+async fn listen_for_certificate_updates(
+    nats: TypedNats,
+    drone_id: DroneId,
+    cluster: ClusterName,
+    sealing_key: &SealingKey,
+    cert_paths: &KeyCertPathPair,
+    public_key: &VerifyingKey,
+) -> NeverResult {
+    let mut sub = nats
+        .subscribe(CertificateUpdate::subscribe_subject(&cluster))
+        .await?;
+    tracing::info!("Listening for certificate updates.");
+
+    let seen_nonces = SeenNonces::default();
+    while let Some(req) = sub.next().await {
+        if let Err(error) = req.verify_signature(public_key, &seen_nonces) {
+            tracing::warn!(
+                ?error,
+                "Ignoring certificate update with invalid signature."
+            );
+            continue;
+        }
+
+        match install_certificate_update(cert_paths, &req.value, &drone_id, sealing_key) {
+            Ok(true) => tracing::info!("Installed certificate pushed by controller."),
+            Ok(false) => (),
+            Err(error) => tracing::warn!(?error, "Error installing pushed certificate."),
+        }
+    }
+
+    Err(anyhow!("Certificate update subscription closed."))
+}
+
+/// Listen for drain instruction. Cancelling a drain does not return the
+/// drone to service while a maintenance window is in effect.
 async fn listen_for_drain(
     nc: TypedNats,
     drone_id: DroneId,
     cluster: ClusterName,
-    send_ready: Sender<bool>,
+    send_ready: &Sender<bool>,
+    send_draining: &Sender<bool>,
+    recv_in_maintenance: Receiver<bool>,
 ) -> NeverResult {
     let mut sub = nc
         .subscribe(DrainDrone::subscribe_subject(drone_id, cluster))
@@ -146,7 +653,10 @@ async fn listen_for_drain(
         req.respond(&()).await?;
 
         send_ready
-            .send(!req.value.drain)
+            .send(!req.value.drain && !*recv_in_maintenance.borrow())
+            .log_error("Error sending drain instruction.");
+        send_draining
+            .send(req.value.drain)
             .log_error("Error sending drain instruction.");
     }
 
@@ -154,10 +664,35 @@ async fn listen_for_drain(
 }
 
 pub async fn run_agent(agent_opts: AgentOptions) -> NeverResult {
-    let nats = &agent_opts.nats;
+    #[cfg(feature = "containerd")]
+    if let Some(containerd_options) = &agent_opts.containerd_options {
+        tracing::info!("Connecting to containerd.");
+        let engine = ContainerdInterface::try_new(containerd_options).await?;
+        return run_agent_with_engine(engine, agent_opts).await;
+    }
+
+    #[cfg(not(feature = "containerd"))]
+    if agent_opts.containerd_options.is_some() {
+        return Err(anyhow!(
+            "containerd was configured, but the drone was built without the containerd feature."
+        ));
+    }
 
     tracing::info!("Connecting to Docker.");
     let docker = DockerInterface::try_new(&agent_opts.docker_options).await?;
+    let docker = match agent_opts.metadata_port {
+        Some(port) => docker.with_metadata_port(port),
+        None => docker,
+    };
+    run_agent_with_engine(docker, agent_opts).await
+}
+
+async fn run_agent_with_engine<E: Engine + Clone>(
+    engine: E,
+    agent_opts: AgentOptions,
+) -> NeverResult {
+    let nats = &agent_opts.nats;
+
     tracing::info!("Connecting to sqlite.");
     let db = agent_opts.db;
     let cluster = agent_opts.cluster_domain.clone();
@@ -171,23 +706,102 @@ pub async fn run_agent(agent_opts: AgentOptions) -> NeverResult {
 
     nats.publish(&request).await?;
 
-    let executor = Executor::new(docker, db.clone(), nats.clone(), ip, cluster.clone());
+    let registration_key = agent_opts
+        .registration_key
+        .as_deref()
+        .map(SigningKey::from_seed)
+        .transpose()?;
+
+    // Status messages are signed with the registration key too, so that in
+    // clusters which require approval the controller can tell them apart
+    // from status messages published by anyone else under this drone's ID.
+    let status_nats = nats.clone().with_signing_key(
+        agent_opts
+            .registration_key
+            .as_deref()
+            .map(SigningKey::from_seed)
+            .transpose()?,
+    );
+
+    let executor = Executor::new(
+        engine.clone(),
+        db.clone(),
+        nats.clone(),
+        ip,
+        cluster.clone(),
+        agent_opts.proxy_self_test.clone(),
+    )
+    .with_load_timeout(agent_opts.load_timeout);
+    let executor = match &agent_opts.ipv6 {
+        Some(ipv6) => match do_with_retry(|| ipv6.get_ip(), 10, Duration::from_secs(10)).await? {
+            IpAddr::V6(ipv6) => executor.with_ipv6(ipv6),
+            ip => return Err(anyhow!("Expected an IPv6 address for ipv6, got {}.", ip)),
+        },
+        None => executor,
+    };
+    let executor = match agent_opts.log_archive.clone() {
+        Some(log_archive) => executor.with_log_archive(log_archive),
+        None => executor,
+    };
+    let executor = match agent_opts.spawn_limit.clone() {
+        Some(spawn_limit) => executor.with_spawn_limit(spawn_limit),
+        None => executor,
+    };
+    let executor = match agent_opts.hooks.clone() {
+        Some(hooks) => executor.with_hooks(hooks),
+        None => executor,
+    };
+    let disk_pressure = agent_opts
+        .min_disk_free_bytes
+        .map(|min_free_bytes| Arc::new(DiskPressure::new(min_free_bytes)));
+    let executor = match disk_pressure.clone() {
+        Some(disk_pressure) => executor.with_disk_pressure(disk_pressure),
+        None => executor,
+    };
 
     let (send_ready, recv_ready) = watch::channel(true);
+    let (send_draining, recv_draining) = watch::channel(false);
+    let (send_in_maintenance, recv_in_maintenance) = watch::channel(false);
+
+    // Only drones which can install pushed certificates, or restore
+    // checkpoints made by other drones, advertise a key to seal them to.
+    let installs_certificates =
+        agent_opts.cert_paths.is_some() && agent_opts.certificate_public_key.is_some();
+    let sealing_key = if installs_certificates || agent_opts.docker_options.checkpoint.is_some() {
+        Some(Arc::new(SealingKey::generate()?))
+    } else {
+        None
+    };
+    let sealing_public_key = sealing_key
+        .as_ref()
+        .map(|sealing_key| sealing_key.public_key())
+        .transpose()?;
+    let executor = match sealing_key.clone() {
+        Some(sealing_key) => executor.with_sealing_key(sealing_key),
+        None => executor,
+    };
 
     tokio::select!(
         result = ready_loop(
-            nats.clone(),
+            status_nats,
             &agent_opts.drone_id,
             cluster.clone(),
             recv_ready.clone(),
-            db,
+            recv_draining.clone(),
+            db.clone(),
+            engine.clone(),
+            agent_opts.heartbeat_interval,
+            sealing_public_key,
+            agent_opts.max_backends,
+            disk_pressure.clone(),
+            agent_opts.proxy_metrics.clone(),
         ) => result,
 
         result = listen_for_spawn_requests(
             &agent_opts.drone_id,
             executor.clone(),
-            nats.clone()
+            nats.clone(),
+            disk_pressure,
         ) => result,
 
         result = listen_for_termination_requests(
@@ -196,11 +810,143 @@ pub async fn run_agent(agent_opts: AgentOptions) -> NeverResult {
             cluster.clone(),
         ) => result,
 
+        result = listen_for_terminate_all_requests(
+            executor.clone(),
+            nats.clone(),
+            agent_opts.drone_id.clone(),
+            cluster.clone(),
+            agent_opts.terminate_all_public_key.clone(),
+        ) => result,
+
+        result = listen_for_migrate_requests(
+            executor.clone(),
+            nats.clone(),
+            agent_opts.drone_id.clone(),
+            cluster.clone(),
+            agent_opts.migrate_public_key.clone(),
+        ) => result,
+
+        result = listen_for_stats_requests(
+            executor.clone(),
+            db.clone(),
+            nats.clone(),
+        ) => result,
+
+        result = listen_for_metadata_updates(
+            db.clone(),
+            nats.clone(),
+            cluster.clone(),
+        ) => result,
+
+        result = listen_for_exec_requests(
+            engine.clone(),
+            nats.clone(),
+            cluster.clone(),
+            agent_opts.exec_access.clone(),
+        ) => result,
+
+        result = listen_for_attach_requests(
+            engine.clone(),
+            nats.clone(),
+            cluster.clone(),
+            agent_opts.exec_access.clone(),
+        ) => result,
+
+        result = listen_for_seed_requests(
+            engine,
+            nats.clone(),
+            agent_opts.drone_id.clone(),
+            cluster.clone(),
+        ) => result,
+
         result = listen_for_drain(
             nats.clone(),
             agent_opts.drone_id.clone(),
             cluster.clone(),
-            send_ready,
+            &send_ready,
+            &send_draining,
+            recv_in_maintenance,
         ) => result,
+
+        result = maintenance_loop(
+            nats.clone(),
+            agent_opts.drone_id.clone(),
+            cluster.clone(),
+            agent_opts.maintenance_windows,
+            &send_ready,
+            &send_in_maintenance,
+            recv_draining,
+            executor.clone(),
+        ) => result,
+
+        result = async {
+            match (&sealing_key, &agent_opts.cert_paths, &agent_opts.certificate_public_key) {
+                (Some(sealing_key), Some(cert_paths), Some(public_key)) => {
+                    listen_for_certificate_updates(
+                        nats.clone(),
+                        agent_opts.drone_id.clone(),
+                        cluster.clone(),
+                        sealing_key,
+                        cert_paths,
+                        public_key,
+                    ).await
+                }
+                _ => std::future::pending().await,
+            }
+        } => result,
+
+        result = async {
+            match agent_opts.update {
+                Some(config) => update_loop(
+                    nats.clone(),
+                    cluster.clone(),
+                    config,
+                    &send_ready,
+                ).await,
+                None => std::future::pending().await,
+            }
+        } => result,
+
+        result = async {
+            match registration_key {
+                Some(key) => registration_loop(
+                    nats.clone(),
+                    agent_opts.drone_id.clone(),
+                    cluster.clone(),
+                    ip,
+                    PLANE_VERSION,
+                    key,
+                ).await,
+                None => std::future::pending().await,
+            }
+        } => result,
+
+        result = async {
+            match agent_opts.admin_port {
+                Some(port) => serve_admin(
+                    port,
+                    agent_opts.admin_token.clone(),
+                    executor.clone(),
+                    db.clone(),
+                    cluster.clone(),
+                ).await,
+                None => std::future::pending().await,
+            }
+        } => result,
+
+        result = async {
+            match agent_opts.metadata_port {
+                Some(port) => {
+                    serve_metadata(
+                        port,
+                        executor.clone(),
+                        db.clone(),
+                        agent_opts.max_lifetime_extension,
+                    )
+                    .await
+                }
+                None => std::future::pending().await,
+            }
+        } => result,
     )
 }
@@ -0,0 +1,169 @@
+//! Waiting for a backend to accept HTTP requests on its port once it has
+//! started.
+
+use anyhow::{anyhow, Result};
+use http::Uri;
+use hyper::Client;
+use rand::Rng;
+use std::{fmt::Display, future::Future, io, net::SocketAddr, time::Duration};
+use tokio::time::Instant;
+
+/// How long to wait for a backend's port if its spawn request does not say.
+pub const DEFAULT_PORT_READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delay after the first failed attempt. It doubles after each attempt.
+const INITIAL_DELAY: Duration = Duration::from_millis(10);
+
+/// Upper bound of the delay between attempts.
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// How long a single attempt may take. Connecting to an address which drops
+/// packets would otherwise hang until the OS gives up.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often progress is reported while waiting.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Why an attempt to reach a backend failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortError {
+    /// Nothing is listening on the port yet.
+    Refused,
+
+    /// The backend's address could not be reached, or did not answer in time.
+    Unreachable,
+
+    /// A connection was made, but the request failed.
+    RequestFailed,
+}
+
+impl PortError {
+    fn classify(error: &hyper::Error) -> Self {
+        if !error.is_connect() {
+            return PortError::RequestFailed;
+        }
+
+        let mut source = std::error::Error::source(error);
+        while let Some(error) = source {
+            if let Some(error) = error.downcast_ref::<io::Error>() {
+                return Self::classify_io(error);
+            }
+            source = error.source();
+        }
+
+        PortError::Unreachable
+    }
+
+    fn classify_io(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => PortError::Refused,
+            _ => PortError::Unreachable,
+        }
+    }
+}
+
+impl Display for PortError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PortError::Refused => write!(f, "connection refused"),
+            PortError::Unreachable => write!(f, "address unreachable"),
+            PortError::RequestFailed => write!(f, "request failed"),
+        }
+    }
+}
+
+/// Delay before retrying after the given failed attempt, counting from zero.
+/// The delay doubles with each attempt up to [MAX_DELAY]; `jitter`, between
+/// zero and one, picks a point in its upper half, so that backends started
+/// together are not probed in lockstep.
+fn backoff_delay(attempt: u32, jitter: f64) -> Duration {
+    let delay = INITIAL_DELAY
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_DELAY);
+
+    delay.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// Wait for the backend at `addr` to respond to an HTTP request, retrying
+/// with exponential backoff for up to `timeout`. While waiting,
+/// `on_progress` is called every [PROGRESS_INTERVAL] with how long it has
+/// waited so far and why the last attempt failed.
+pub async fn wait_port_ready<F, Fut>(
+    addr: &SocketAddr,
+    timeout: Duration,
+    mut on_progress: F,
+) -> Result<()>
+where
+    F: FnMut(Duration, PortError) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    tracing::info!(%addr, ?timeout, "Waiting for ready port.");
+
+    let client = Client::new();
+    let uri = Uri::from_maybe_shared(format!("http://{}/", addr))?;
+    let start = Instant::now();
+    let deadline = start + timeout;
+    let mut next_progress = start + PROGRESS_INTERVAL;
+    let mut attempt = 0;
+
+    loop {
+        let attempt_deadline = deadline.min(Instant::now() + ATTEMPT_TIMEOUT);
+        let error = match tokio::time::timeout_at(attempt_deadline, client.get(uri.clone())).await {
+            Ok(Ok(_)) => {
+                tracing::info!(%addr, attempts = attempt + 1, "Port is ready.");
+                return Ok(());
+            }
+            Ok(Err(error)) => {
+                let kind = PortError::classify(&error);
+                tracing::debug!(?error, %kind, %attempt, "Port is not ready.");
+                kind
+            }
+            Err(_) => PortError::Unreachable,
+        };
+
+        let now = Instant::now();
+        let delay = backoff_delay(attempt, rand::thread_rng().gen());
+        if now + delay >= deadline {
+            return Err(anyhow!(
+                "Backend did not accept requests on {} within {} seconds ({}).",
+                addr,
+                timeout.as_secs(),
+                error
+            ));
+        }
+
+        if now >= next_progress {
+            on_progress(now - start, error).await;
+            next_progress = now + PROGRESS_INTERVAL;
+        }
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_delay() {
+        assert_eq!(Duration::from_millis(5), backoff_delay(0, 0.0));
+        assert_eq!(Duration::from_millis(10), backoff_delay(0, 1.0));
+        assert_eq!(Duration::from_millis(80), backoff_delay(3, 1.0));
+        assert_eq!(Duration::from_millis(500), backoff_delay(10, 0.0));
+        assert_eq!(MAX_DELAY, backoff_delay(u32::MAX, 1.0));
+    }
+
+    #[test]
+    fn test_classify_io() {
+        assert_eq!(
+            PortError::Refused,
+            PortError::classify_io(&io::Error::from(io::ErrorKind::ConnectionRefused))
+        );
+        assert_eq!(
+            PortError::Unreachable,
+            PortError::classify_io(&io::Error::from(io::ErrorKind::TimedOut))
+        );
+    }
+}
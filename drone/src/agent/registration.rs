@@ -0,0 +1,78 @@
+use plane_core::{
+    messages::agent::{DroneRegistrationResponse, HostFacts, RegisterDrone},
+    nats::TypedNats,
+    signing::{SigningKey, VerifyingKey},
+    types::{ClusterName, DroneId},
+    NeverResult,
+};
+use std::{net::IpAddr, time::Duration};
+
+/// How often the drone registers. Registrations are kept in the controller's
+/// memory, so the drone keeps registering after it is approved, in case the
+/// controller restarts.
+const REGISTRATION_INTERVAL: Duration = Duration::from_secs(10);
+
+fn host_facts(ip: IpAddr, drone_version: &str) -> HostFacts {
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty());
+
+    HostFacts {
+        hostname,
+        ip,
+        drone_version: drone_version.to_string(),
+        os: std::env::consts::OS.to_string(),
+        arch: std::env::consts::ARCH.to_string(),
+    }
+}
+
+/// Register with the controller periodically, signing each registration with
+/// the drone's registration key.
+pub async fn registration_loop(
+    nats: TypedNats,
+    drone_id: DroneId,
+    cluster: ClusterName,
+    ip: IpAddr,
+    drone_version: &str,
+    key: SigningKey,
+) -> NeverResult {
+    let registration = RegisterDrone {
+        drone_id,
+        cluster,
+        public_key: key.public_key(),
+        host: host_facts(ip, drone_version),
+    };
+    let key_fingerprint = VerifyingKey::from_base64(&registration.public_key)?.fingerprint();
+    let nats = nats.with_signing_key(Some(key));
+    let mut last_response = None;
+    let mut interval = tokio::time::interval(REGISTRATION_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let response = match nats.request(&registration).await {
+            Ok(response) => response,
+            Err(error) => {
+                tracing::warn!(?error, "Error registering drone.");
+                continue;
+            }
+        };
+
+        if last_response.as_ref() != Some(&response) {
+            match &response {
+                DroneRegistrationResponse::Approved => tracing::info!("Drone is approved."),
+                DroneRegistrationResponse::Pending => tracing::warn!(
+                    drone_id=%registration.drone_id,
+                    public_key=%registration.public_key,
+                    %key_fingerprint,
+                    "Drone is waiting for approval, e.g. with `plane-cli approve-drone`."
+                ),
+                DroneRegistrationResponse::Rejected { reason } => {
+                    tracing::error!(%reason, "Drone registration was rejected.")
+                }
+            }
+            last_response = Some(response);
+        }
+    }
+}
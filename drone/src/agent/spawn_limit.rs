@@ -0,0 +1,144 @@
+//! Limits on how many backends the drone loads at once and how quickly it
+//! starts loading them, so that a burst of spawns does not saturate the
+//! host's disk and network with image pulls.
+
+use crate::config::SpawnLimitConfig;
+use anyhow::Result;
+use plane_core::error::PlaneError;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+/// Token bucket which admits a number of spawns per minute on average, in
+/// bursts of up to the same number.
+struct TokenBucket {
+    capacity: f64,
+
+    /// Tokens added per second.
+    rate: f64,
+
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute: u32, now: Instant) -> Self {
+        let capacity = per_minute.max(1) as f64;
+
+        TokenBucket {
+            capacity,
+            rate: capacity / 60.0,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    /// Take a token if one is available, or return how long until one is.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+/// Held while a backend loads. Dropping it lets the next waiting backend
+/// start loading.
+pub struct LoadPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+pub struct SpawnLimiter {
+    loads: Option<Arc<Semaphore>>,
+    spawns: Option<Mutex<TokenBucket>>,
+    queue_timeout: Duration,
+}
+
+impl SpawnLimiter {
+    pub fn new(config: SpawnLimitConfig) -> Self {
+        SpawnLimiter {
+            loads: config
+                .max_concurrent_loads
+                .map(|max| Arc::new(Semaphore::new(max.max(1)))),
+            spawns: config
+                .max_spawns_per_minute
+                .map(|per_minute| Mutex::new(TokenBucket::new(per_minute, Instant::now()))),
+            queue_timeout: Duration::from_secs(config.queue_timeout_secs),
+        }
+    }
+
+    async fn wait(&self) -> LoadPermit {
+        // A load slot is taken first, so that the rate limit only counts
+        // backends which are about to load.
+        let permit = match &self.loads {
+            Some(loads) => Some(
+                loads
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("Load semaphore is never closed."),
+            ),
+            None => None,
+        };
+
+        if let Some(spawns) = &self.spawns {
+            loop {
+                let result = spawns.lock().await.take(Instant::now());
+                match result {
+                    Ok(()) => break,
+                    Err(wait) => tokio::time::sleep(wait).await,
+                }
+            }
+        }
+
+        LoadPermit { _permit: permit }
+    }
+
+    /// Wait until a backend may start loading, failing if that takes longer
+    /// than the queue timeout.
+    pub async fn acquire(&self) -> Result<LoadPermit> {
+        match tokio::time::timeout(self.queue_timeout, self.wait()).await {
+            Ok(permit) => Ok(permit),
+            Err(_) => Err(PlaneError::SpawnQueueTimeout {
+                timeout_secs: self.queue_timeout.as_secs(),
+            }
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, start);
+
+        // A burst of up to the limit is admitted at once.
+        assert_eq!(Ok(()), bucket.take(start));
+        assert_eq!(Ok(()), bucket.take(start));
+        let wait = bucket.take(start).unwrap_err();
+        assert!((wait.as_secs_f64() - 30.0).abs() < 0.001);
+
+        // Tokens are refilled at the limit spread over a minute.
+        let later = start + Duration::from_secs(31);
+        assert_eq!(Ok(()), bucket.take(later));
+        assert!(bucket.take(later).is_err());
+
+        // The bucket never holds more than the limit.
+        let much_later = later + Duration::from_secs(3600);
+        assert_eq!(Ok(()), bucket.take(much_later));
+        assert_eq!(Ok(()), bucket.take(much_later));
+        assert!(bucket.take(much_later).is_err());
+    }
+}
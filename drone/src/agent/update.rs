@@ -0,0 +1,233 @@
+//! Updating the drone binary to the version the controller publishes for the
+//! cluster.
+//!
+//! When an update names a newer version than the one running, the drone
+//! downloads the binary and checks that it was signed as a release of that
+//! version, then stops accepting backends for a short while so that schedule
+//! requests in flight can complete. It then replaces its own binary and
+//! re-execs into it with the same arguments. Backends keep running
+//! throughout, and are resumed by the new binary from the drone's database.
+//!
+//! Each update is attempted at most once. The drone records the update in
+//! its state file before applying it, so that an update which fails, or
+//! which installs a binary reporting another version than published, is not
+//! retried after the drone restarts.
+
+use crate::config::UpdateConfig;
+use anyhow::{anyhow, Context, Result};
+use plane_core::{
+    logging::LogError, messages::scheduler::DroneUpdate, nats::TypedNats, signing::VerifyingKey,
+    types::ClusterName, NeverResult,
+};
+use std::{
+    os::unix::{fs::PermissionsExt, process::CommandExt},
+    path::Path,
+    time::Duration,
+};
+use tokio::sync::watch::Sender;
+
+const PLANE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// How long downloading a binary may take before the update fails.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// The numeric components of a version such as `0.3.4`, ignoring any
+/// pre-release or build suffix.
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let version = version.split(|c| c == '-' || c == '+').next()?;
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// Whether `version` is newer than `running_version`. Versions which can
+/// not be compared are not considered newer.
+fn is_newer(version: &str, running_version: &str) -> bool {
+    match (parse_version(version), parse_version(running_version)) {
+        (Some(version), Some(running_version)) => version > running_version,
+        _ => false,
+    }
+}
+
+/// Whether an update should be applied. Only newer versions are installed,
+/// so that an old, signed release can not be used to downgrade drones, and
+/// an update which was already attempted is not retried until the
+/// controller publishes a different one.
+fn needs_update(
+    update: &DroneUpdate,
+    running_version: &str,
+    last_attempted: Option<&DroneUpdate>,
+) -> bool {
+    is_newer(&update.version, running_version) && last_attempted != Some(update)
+}
+
+/// Read the last update attempted, as recorded by [record_attempt].
+fn last_attempted(state_path: &Path) -> Option<DroneUpdate> {
+    let state = std::fs::read(state_path).ok()?;
+    serde_json::from_slice(&state)
+        .map_err(|error| tracing::warn!(?error, "Ignoring unreadable drone update state."))
+        .ok()
+}
+
+/// Record that an update is being attempted, before it is applied.
+fn record_attempt(state_path: &Path, update: &DroneUpdate) -> Result<()> {
+    std::fs::write(state_path, serde_json::to_vec(update)?)
+        .with_context(|| format!("Error writing {}.", state_path.display()))
+}
+
+/// Download the binary of an update, and check that it is signed as a
+/// release of the update's version with `key`.
+async fn download(update: &DroneUpdate, key: &VerifyingKey) -> Result<Vec<u8>> {
+    let client = reqwest::Client::builder()
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()?;
+    let binary = client
+        .get(&update.artifact_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    key.verify_release(&update.version, &binary, &update.signature)?;
+    Ok(binary.to_vec())
+}
+
+/// Replace the binary at `path`. The new binary is written next to it and
+/// renamed over it, so that a failed write leaves the old binary in place.
+fn install(binary: &[u8], path: &Path) -> Result<()> {
+    let staged = path.with_extension("update");
+    std::fs::write(&staged, binary)
+        .with_context(|| format!("Error writing {}.", staged.display()))?;
+    std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755))?;
+    std::fs::rename(&staged, path)
+        .with_context(|| format!("Error replacing {}.", path.display()))?;
+    Ok(())
+}
+
+/// Apply an update and re-exec into the new binary. Only returns if the
+/// update failed.
+async fn apply(
+    update: &DroneUpdate,
+    key: &VerifyingKey,
+    config: &UpdateConfig,
+    send_ready: &Sender<bool>,
+) -> NeverResult {
+    let path = std::env::current_exe()?;
+    let binary = download(update, key).await?;
+
+    tracing::info!(drain_secs=%config.drain_secs, "Draining drone before update.");
+    send_ready.send(false)?;
+    tokio::time::sleep(Duration::from_secs(config.drain_secs)).await;
+
+    install(&binary, &path)?;
+    tracing::info!(version=%update.version, "Re-executing into updated drone.");
+    let error = std::process::Command::new(&path)
+        .args(std::env::args_os().skip(1))
+        .exec();
+
+    Err(anyhow!("Error re-executing {}: {}", path.display(), error))
+}
+
+pub async fn update_loop(
+    nats: TypedNats,
+    cluster: ClusterName,
+    config: UpdateConfig,
+    send_ready: &Sender<bool>,
+) -> NeverResult {
+    let key = VerifyingKey::from_base64(&config.public_key)
+        .context("Invalid public_key for drone updates.")?;
+    let mut sub = nats
+        .subscribe(DroneUpdate::subscribe_subject(&cluster))
+        .await?;
+    tracing::info!("Listening for drone updates.");
+
+    let mut attempted = last_attempted(&config.state_path);
+    if let Some(update) = &attempted {
+        if update.version != PLANE_VERSION {
+            tracing::warn!(
+                version=%update.version,
+                "Drone is not running the version of the last update, which will not be retried."
+            );
+        }
+    }
+
+    while let Some(message) = sub.next().await {
+        let update = message.value;
+        if !needs_update(&update, PLANE_VERSION, attempted.as_ref()) {
+            continue;
+        }
+
+        tracing::info!(
+            version=%update.version,
+            artifact_url=%update.artifact_url,
+            "Updating drone."
+        );
+        // If the attempt can not be recorded, the update could be retried
+        // on every restart, so it is not applied.
+        let result = match record_attempt(&config.state_path, &update) {
+            Ok(()) => {
+                let was_ready = *send_ready.borrow();
+                let result = apply(&update, &key, &config, send_ready).await;
+                send_ready
+                    .send(was_ready)
+                    .log_error("Error restoring readiness after failed update.");
+                result
+            }
+            Err(error) => Err(error),
+        };
+        result.log_error(&format!("Error updating drone to {}.", update.version));
+        attempted = Some(update);
+    }
+
+    Err(anyhow!("Drone update subscription closed."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(version: &str) -> DroneUpdate {
+        DroneUpdate {
+            cluster: ClusterName::new("plane.test"),
+            version: version.into(),
+            artifact_url: "https://example.com/plane-drone".into(),
+            signature: "signature".into(),
+        }
+    }
+
+    #[test]
+    fn test_needs_update() {
+        let update = update("0.4.0");
+
+        assert!(needs_update(&update, "0.3.4", None));
+        assert!(!needs_update(&update, "0.4.0", None));
+        assert!(!needs_update(&update, "0.3.4", Some(&update)));
+
+        let republished = DroneUpdate {
+            signature: "other-signature".into(),
+            ..update.clone()
+        };
+        assert!(needs_update(&republished, "0.3.4", Some(&update)));
+    }
+
+    #[test]
+    fn test_refuses_downgrade() {
+        assert!(!needs_update(&update("0.3.3"), "0.3.4", None));
+        assert!(!needs_update(&update("0.2.10"), "0.3.4", None));
+        assert!(needs_update(&update("0.3.10"), "0.3.4", None));
+        assert!(needs_update(&update("1.0.0-rc.1"), "0.3.4", None));
+        assert!(!needs_update(&update("latest"), "0.3.4", None));
+    }
+
+    #[test]
+    fn test_last_attempted_persists() {
+        let state_path =
+            std::env::temp_dir().join(format!("plane-drone-update-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&state_path);
+        assert_eq!(None, last_attempted(&state_path));
+
+        let update = update("0.4.0");
+        record_attempt(&state_path, &update).unwrap();
+        assert_eq!(Some(update), last_attempted(&state_path));
+
+        std::fs::remove_file(&state_path).unwrap();
+    }
+}
@@ -11,15 +11,18 @@ use openssl::{
     x509::X509,
 };
 use plane_core::{
-    messages::cert::SetAcmeDnsRecord,
+    messages::cert::{CertificateUpdate, SetAcmeDnsRecord},
     messages::dns::{DnsRecordType, SetDnsRecord},
     nats::TypedNats,
-    types::ClusterName,
+    sealing::SealingKey,
+    types::{ClusterName, DroneId},
     NeverResult,
 };
 use reqwest::Client;
-use std::io::Write;
-use std::{fs::File, path::Path, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use crate::keys::KeyCertPathPair;
 
@@ -160,25 +163,75 @@ pub async fn refresh_certificate(cert_options: &CertOptions, client: &Client) ->
     )
     .await?;
 
-    {
-        let mut fh = File::options()
-            .create(true)
-            .write(true)
-            .open(&cert_options.key_paths.cert_path)?;
-
-        for cert in certs {
-            fh.write_all(&cert.to_pem()?)?;
-        }
+    let mut cert_pem = Vec::new();
+    for cert in certs {
+        cert_pem.extend(cert.to_pem()?);
     }
 
-    std::fs::write(
-        &cert_options.key_paths.key_path,
-        pkey.private_key_to_pem_pkcs8()?,
+    write_key_cert_pair(
+        &cert_options.key_paths,
+        &cert_pem,
+        &pkey.private_key_to_pem_pkcs8()?,
     )?;
 
+    match std::fs::remove_file(pushed_marker(&cert_options.key_paths)) {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+        _ => Ok(()),
+    }
+}
+
+/// File whose presence marks the installed certificate as pushed by the
+/// controller, so that ACME does not replace it.
+fn pushed_marker(key_paths: &KeyCertPathPair) -> PathBuf {
+    key_paths.cert_path.with_extension("pushed")
+}
+
+/// Write a PEM-encoded certificate chain and PKCS #8 private key to the
+/// configured paths. The proxy picks up the new pair once both files have
+/// been written.
+fn write_key_cert_pair(key_paths: &KeyCertPathPair, cert_pem: &[u8], key_pem: &[u8]) -> Result<()> {
+    std::fs::write(&key_paths.cert_path, cert_pem)?;
+    std::fs::write(&key_paths.key_path, key_pem)?;
+
     Ok(())
 }
 
+/// Install the certificate from a [CertificateUpdate] pushed by the
+/// controller, if it carries a key sealed to this drone and differs from the
+/// certificate already installed. Returns whether a certificate was written.
+pub fn install_certificate_update(
+    key_paths: &KeyCertPathPair,
+    update: &CertificateUpdate,
+    drone_id: &DroneId,
+    sealing_key: &SealingKey,
+) -> Result<bool> {
+    let sealed_key = match update.keys.get(drone_id) {
+        Some(sealed_key) => sealed_key,
+        None => return Ok(false),
+    };
+
+    if std::fs::read(&key_paths.cert_path).ok().as_deref() == Some(update.cert_pem.as_bytes()) {
+        return Ok(false);
+    }
+
+    let pkey = PKey::private_key_from_pem(&sealing_key.open(sealed_key)?)?;
+    let cert = X509::from_pem(update.cert_pem.as_bytes())?;
+    if !cert.public_key()?.public_eq(&pkey) {
+        return Err(anyhow!("Pushed private key does not match certificate."));
+    }
+
+    // The proxy only reads PKCS #8 keys, so the key is re-encoded in case it
+    // was pushed in another format.
+    write_key_cert_pair(
+        key_paths,
+        update.cert_pem.as_bytes(),
+        &pkey.private_key_to_pem_pkcs8()?,
+    )?;
+    std::fs::write(pushed_marker(key_paths), b"")?;
+
+    Ok(true)
+}
+
 pub fn cert_validity(certificate_path: &Path) -> Option<DateTime<Utc>> {
     let cert_pem = std::fs::read(certificate_path).ok()?;
     let cert = X509::from_pem(&cert_pem).ok()?;
@@ -192,7 +245,18 @@ pub fn cert_validity(certificate_path: &Path) -> Option<DateTime<Utc>> {
 }
 
 pub async fn refresh_if_not_valid(cert_options: &CertOptions) -> Result<Option<Duration>> {
-    if let Some(valid_until) = cert_validity(&cert_options.key_paths.cert_path) {
+    let valid_until = cert_validity(&cert_options.key_paths.cert_path);
+
+    // The controller renews certificates it pushes, so ACME only takes over
+    // again if a pushed certificate is allowed to expire.
+    if pushed_marker(&cert_options.key_paths).exists()
+        && valid_until.map_or(false, |valid_until| valid_until > Utc::now())
+    {
+        tracing::debug!("Certificate was pushed by the controller, not refreshing with ACME.");
+        return Ok(Some(MAX_SLEEP));
+    }
+
+    if let Some(valid_until) = valid_until {
         let refresh_at = valid_until
             .checked_sub_signed(chrono::Duration::from_std(REFRESH_MARGIN)?)
             .ok_or_else(|| {
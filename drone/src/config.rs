@@ -1,8 +1,16 @@
-use crate::{cert::acme::AcmeConfiguration, ip::IpSource, keys::KeyCertPathPair};
-use plane_core::{nats_connection::NatsConnectionSpec, types::DroneId};
+use crate::{
+    agent::executor::DEFAULT_LOAD_TIMEOUT, cert::acme::AcmeConfiguration, ip::IpSource,
+    keys::KeyCertPathPair,
+};
+use plane_core::{
+    messages::{agent::DockerCredentials, scheduler::MaintenanceWindow},
+    nats_connection::NatsConnectionSpec,
+    types::DroneId,
+};
 use serde::{Deserialize, Serialize};
 use std::{
-    net::{IpAddr, Ipv4Addr},
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     path::PathBuf,
 };
 
@@ -14,7 +22,7 @@ enum NatsAuthentication {
     // JWT
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum DockerConnection {
     Socket { socket: String },
@@ -29,6 +37,18 @@ impl Default for DockerConnection {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PullThroughCacheConfig {
+    /// Map of upstream registry hosts (e.g. "docker.io" or "ghcr.io") to the
+    /// location on the cache registry which mirrors them (e.g.
+    /// "cache.internal:5000/ghcr"). Images from other registries are pulled
+    /// directly.
+    pub registries: HashMap<String, String>,
+
+    /// Credentials for the cache registry, if it requires them.
+    pub credentials: Option<DockerCredentials>,
+}
+
 #[derive(Serialize, Deserialize, Default)]
 pub struct DockerConfig {
     pub runtime: Option<String>,
@@ -36,6 +56,308 @@ pub struct DockerConfig {
     pub connection: DockerConnection,
 
     pub network: Option<String>,
+
+    /// If provided, public images are pulled through a shared cache registry
+    /// instead of directly from their upstream registry.
+    pub pull_through_cache: Option<PullThroughCacheConfig>,
+
+    /// If provided, images which no backend has used recently are removed
+    /// when the disk holding Docker's data directory fills up.
+    pub image_gc: Option<ImageGcConfig>,
+
+    /// If provided, backends can be checkpointed and restored (see
+    /// [plane_core::messages::agent::MigrateBackend]).
+    pub checkpoint: Option<CheckpointConfig>,
+
+    /// Image providing `iptables`, `ip6tables` and `sleep`, run alongside
+    /// each backend with an egress policy to install the policy's rules.
+    /// Backends with an egress policy are refused unless this is set.
+    pub egress_image: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointConfig {
+    /// Directory local to the drone which Docker writes checkpoints to, and
+    /// restores them from. Checkpoints hold backends' memory in the clear,
+    /// so this should only be readable by root.
+    pub local_dir: String,
+
+    /// Directory shared by every drone a backend may be moved between,
+    /// through which checkpoints are passed sealed to the drone restoring
+    /// them.
+    pub shared_dir: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ImageGcConfig {
+    /// Percentage of the disk holding Docker's data directory which must be
+    /// in use before images are removed.
+    #[serde(default = "default_image_gc_disk_usage_percent")]
+    pub disk_usage_percent: u8,
+
+    /// Images are only removed if no backend has used them for this many hours.
+    #[serde(default = "default_image_gc_unused_hours")]
+    pub unused_hours: u64,
+
+    /// How often to check disk usage.
+    #[serde(default = "default_image_gc_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_image_gc_disk_usage_percent() -> u8 {
+    80
+}
+
+fn default_image_gc_unused_hours() -> u64 {
+    24
+}
+
+fn default_image_gc_interval_secs() -> u64 {
+    600
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ContainerdConfig {
+    /// Path to the containerd socket.
+    #[serde(default = "default_containerd_socket")]
+    pub socket: String,
+
+    /// containerd namespace in which images and containers are created.
+    #[serde(default = "default_containerd_namespace")]
+    pub namespace: String,
+
+    /// Snapshotter used to unpack images and create container filesystems.
+    #[serde(default = "default_containerd_snapshotter")]
+    pub snapshotter: String,
+
+    /// Runtime used to run containers.
+    #[serde(default = "default_containerd_runtime")]
+    pub runtime: String,
+
+    /// containerd's root directory, used to report free disk space.
+    #[serde(default = "default_containerd_root")]
+    pub root: PathBuf,
+
+    /// Directory to which backend stdout and stderr are written.
+    #[serde(default = "default_containerd_log_dir")]
+    pub log_dir: PathBuf,
+
+    /// Parent cgroup of backend cgroups, relative to the cgroup v2 mount.
+    #[serde(default = "default_containerd_cgroup_parent")]
+    pub cgroup_parent: String,
+
+    /// CNI plugin which attaches each backend's network namespace to a
+    /// network.
+    #[serde(default = "default_cni_config")]
+    pub cni: CniConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CniConfig {
+    /// Directory containing CNI plugin binaries.
+    #[serde(default = "default_cni_bin_dir")]
+    pub bin_dir: PathBuf,
+
+    /// Network configuration, whose `type` names the plugin to run, e.g. a
+    /// `bridge` network with `host-local` address management.
+    #[serde(default = "default_cni_conf_path")]
+    pub conf_path: PathBuf,
+}
+
+fn default_cni_config() -> CniConfig {
+    CniConfig {
+        bin_dir: default_cni_bin_dir(),
+        conf_path: default_cni_conf_path(),
+    }
+}
+
+fn default_cni_bin_dir() -> PathBuf {
+    "/opt/cni/bin".into()
+}
+
+fn default_cni_conf_path() -> PathBuf {
+    "/etc/cni/net.d/plane.conf".into()
+}
+
+fn default_containerd_socket() -> String {
+    "/run/containerd/containerd.sock".into()
+}
+
+fn default_containerd_namespace() -> String {
+    "plane".into()
+}
+
+fn default_containerd_snapshotter() -> String {
+    "overlayfs".into()
+}
+
+fn default_containerd_runtime() -> String {
+    "io.containerd.runc.v2".into()
+}
+
+fn default_containerd_root() -> PathBuf {
+    "/var/lib/containerd".into()
+}
+
+fn default_containerd_log_dir() -> PathBuf {
+    "/var/log/plane".into()
+}
+
+fn default_containerd_cgroup_parent() -> String {
+    "/plane".into()
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LogArchiveConfig {
+    /// Base URL of the S3-compatible endpoint, e.g.
+    /// "https://s3.us-east-1.amazonaws.com". Objects are addressed
+    /// path-style, as `{endpoint}/{bucket}/{key}`.
+    pub endpoint: String,
+
+    pub bucket: String,
+
+    /// Region used to sign requests.
+    #[serde(default = "default_log_archive_region")]
+    pub region: String,
+
+    pub access_key_id: String,
+
+    pub secret_access_key: String,
+
+    /// Prepended to object keys. Each backend's logs are stored under
+    /// `{prefix}{backend_id}/`.
+    #[serde(default)]
+    pub prefix: String,
+
+    /// Logs are uploaded in parts of roughly this many bytes. The last
+    /// part is uploaded once the backend terminates.
+    #[serde(default = "default_log_archive_part_bytes")]
+    pub part_bytes: usize,
+}
+
+fn default_log_archive_region() -> String {
+    "us-east-1".into()
+}
+
+fn default_log_archive_part_bytes() -> usize {
+    8 * 1024 * 1024
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SpawnLimitConfig {
+    /// If provided, the most backends loaded (e.g. having their image
+    /// pulled) at once. Further backends wait for one to finish loading.
+    pub max_concurrent_loads: Option<usize>,
+
+    /// If provided, the most backends which start loading per minute, on
+    /// average. Bursts of up to this many are allowed.
+    pub max_spawns_per_minute: Option<u32>,
+
+    /// How long a backend may wait to start loading, in seconds, before it
+    /// fails.
+    #[serde(default = "default_spawn_queue_timeout_secs")]
+    pub queue_timeout_secs: u64,
+}
+
+fn default_spawn_queue_timeout_secs() -> u64 {
+    60
+}
+
+/// Commands run on the drone as backends move through their lifecycle.
+/// Each is an argument vector, e.g. `["/usr/local/bin/checkout", "--quiet"]`,
+/// which is executed directly rather than through a shell, with the
+/// backend's ID, drone, cluster, image, state, and metadata in `PLANE_*`
+/// environment variables.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct HooksConfig {
+    /// Run before a backend is loaded. If it fails, so does the backend,
+    /// and its `pre_stop` and `post_terminate` hooks are not run.
+    pub pre_start: Option<Vec<String>>,
+
+    /// Run once a backend is ready.
+    pub post_ready: Option<Vec<String>>,
+
+    /// Run before a backend's container is stopped.
+    pub pre_stop: Option<Vec<String>>,
+
+    /// Run after a backend's container is stopped.
+    pub post_terminate: Option<Vec<String>>,
+
+    /// How long each hook may run, in seconds, before it is killed and
+    /// considered to have failed.
+    #[serde(default = "default_hook_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_hook_timeout_secs() -> u64 {
+    30
+}
+
+/// Access to run commands inside backends, with `plane-cli exec` and
+/// `plane-cli attach`. Without it, the drone refuses such requests.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ExecConfig {
+    /// Base64-encoded Ed25519 public key which requests, and the input sent
+    /// to attached terminals, must be signed with (see
+    /// `plane-cli generate-signing-key` and `plane-cli --signing-key`).
+    pub public_key: String,
+
+    /// How long an attached terminal may go without input, or a command
+    /// without output, in seconds, before it is detached.
+    #[serde(default = "default_exec_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+}
+
+fn default_exec_idle_timeout_secs() -> u64 {
+    900
+}
+
+/// Access to move backends between drones (see
+/// [plane_core::messages::agent::MigrateBackend]). Without it, the drone
+/// refuses such requests.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MigrateConfig {
+    /// Base64-encoded Ed25519 public key which requests must be signed with
+    /// (see `plane-cli generate-signing-key` and `plane-cli --signing-key`).
+    pub public_key: String,
+}
+
+/// Access to terminate every backend on the drone at once (see
+/// [plane_core::messages::agent::TerminateAllRequest]). Without it, the drone
+/// refuses such requests.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TerminateAllConfig {
+    /// Base64-encoded Ed25519 public key which requests must be signed with
+    /// (see `plane-cli generate-signing-key` and `plane-cli --signing-key`).
+    pub public_key: String,
+}
+
+/// Updating the drone binary to the version the controller publishes for
+/// the cluster.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UpdateConfig {
+    /// Base64-encoded Ed25519 public key which drone releases must be
+    /// signed with (see `plane-cli sign-artifact`).
+    pub public_key: String,
+
+    /// How long the drone stops accepting backends before it re-execs into
+    /// the new binary, so that schedule requests in flight can complete.
+    #[serde(default = "default_update_drain_secs")]
+    pub drain_secs: u64,
+
+    /// File in which the drone records the last update it attempted, so that
+    /// an update which failed, or which installed a binary reporting another
+    /// version, is not retried after a restart.
+    #[serde(default = "default_update_state_path")]
+    pub state_path: PathBuf,
+}
+
+fn default_update_drain_secs() -> u64 {
+    30
+}
+
+fn default_update_state_path() -> PathBuf {
+    PathBuf::from("drone-update.json")
 }
 
 #[derive(Serialize, Deserialize)]
@@ -47,8 +369,121 @@ struct CertRefreshOptions {
 pub struct ProxyOptions {
     #[serde(default = "default_bind_address")]
     pub bind_ip: IpAddr,
+
+    /// If provided, the proxy also accepts IPv6 connections on this address,
+    /// e.g. "::" for every interface.
+    pub bind_ipv6: Option<Ipv6Addr>,
     #[serde(default = "default_https_port")]
     pub https_port: u16,
+
+    /// If provided, the proxy publishes a sample of the requests it serves
+    /// to NATS. Requires `nats`.
+    pub access_log: Option<AccessLogConfig>,
+
+    /// Session cookies which the proxy issues in exchange for grants.
+    #[serde(default)]
+    pub session: SessionConfig,
+
+    /// If provided, the proxy issues reconnect tokens which route requests
+    /// to the cluster's root hostname to a backend.
+    pub reconnect: Option<ReconnectConfig>,
+
+    /// If provided, static responses which backends mark as cacheable are
+    /// cached by the proxy, separately for each backend.
+    pub cache: Option<ResponseCacheConfig>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResponseCacheConfig {
+    /// Patterns of paths whose `GET` responses may be cached, e.g.
+    /// "/static/*" or "*.js". `*` matches any sequence of characters.
+    pub path_patterns: Vec<String>,
+
+    /// Maximum number of responses cached for each backend.
+    #[serde(default = "default_cache_max_entries_per_backend")]
+    pub max_entries_per_backend: usize,
+
+    /// Responses with larger bodies, or without a `Content-Length`, are not
+    /// cached.
+    #[serde(default = "default_cache_max_body_bytes")]
+    pub max_body_bytes: usize,
+
+    /// Maximum bytes cached across every backend on the drone. The least
+    /// recently used responses of any backend are evicted beyond it.
+    #[serde(default = "default_cache_max_total_bytes")]
+    pub max_total_bytes: usize,
+}
+
+fn default_cache_max_entries_per_backend() -> usize {
+    256
+}
+
+fn default_cache_max_body_bytes() -> usize {
+    1 << 20
+}
+
+fn default_cache_max_total_bytes() -> usize {
+    256 << 20
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SessionConfig {
+    /// How long a session cookie is valid for, after which the browser must
+    /// exchange a new grant.
+    #[serde(default = "default_session_ttl_secs")]
+    pub ttl_secs: u64,
+
+    /// Origins, e.g. `https://app.example.com`, of pages which may exchange
+    /// a grant at a backend's hostname with credentials. Requests from other
+    /// origins are not given CORS headers.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            ttl_secs: default_session_ttl_secs(),
+            allowed_origins: Vec::new(),
+        }
+    }
+}
+
+fn default_session_ttl_secs() -> u64 {
+    12 * 3600
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReconnectConfig {
+    /// Secret used to sign tokens. Drones in a cluster should share it, so
+    /// that any of them accepts tokens issued by the others.
+    pub secret: String,
+
+    /// How long issued tokens are valid for.
+    #[serde(default = "default_reconnect_token_ttl_secs")]
+    pub token_ttl_secs: u64,
+
+    /// Origins, e.g. `https://app.example.com`, of pages which may fetch a
+    /// token from a backend's hostname with credentials. Requests from other
+    /// origins are not given CORS headers, so their pages can not read the
+    /// token.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+fn default_reconnect_token_ttl_secs() -> u64 {
+    3600
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AccessLogConfig {
+    /// Fraction of requests to publish, between 0 and 1.
+    #[serde(default = "default_access_log_sample_rate")]
+    pub sample_rate: f64,
+}
+
+fn default_access_log_sample_rate() -> f64 {
+    1.0
 }
 
 fn default_bind_address() -> IpAddr {
@@ -64,9 +499,113 @@ pub struct AgentOptions {
     #[serde(default)]
     pub docker: DockerConfig,
 
+    /// If provided, backends are run directly with containerd instead of
+    /// Docker, and `docker` is ignored. Requires the `containerd` feature.
+    pub containerd: Option<ContainerdConfig>,
+
     pub ip: IpSource,
 
+    /// If provided, backends are also advertised at this IPv6 address with
+    /// AAAA records, in addition to `ip`.
+    pub ipv6: Option<IpSource>,
+
     pub drone_id: Option<DroneId>,
+
+    /// Windows during which the drone takes itself out of service.
+    #[serde(default)]
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+
+    /// How often to publish the drone's status, in seconds. The controller
+    /// considers a drone gone after five seconds without a status, so this
+    /// may be at most four.
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
+
+    /// How long a backend may spend loading (e.g. pulling its image), in
+    /// seconds, before it is considered to have failed.
+    #[serde(default = "default_load_timeout_secs")]
+    pub load_timeout_secs: u64,
+
+    /// If provided, the most backends this drone runs at once. Once it runs
+    /// this many, the scheduler stops placing backends on it.
+    pub max_backends: Option<u32>,
+
+    /// If provided, limits how many backends load at once and how quickly
+    /// they start loading, so that a burst of spawns does not saturate the
+    /// host's disk and network.
+    pub spawn_limit: Option<SpawnLimitConfig>,
+
+    /// If provided, the agent serves an admin HTTP API on this port of
+    /// 127.0.0.1, for host-level tooling and health checks.
+    pub admin_port: Option<u16>,
+
+    /// If provided, requests to the admin API other than health checks must
+    /// carry this token as `Authorization: Bearer <token>`, so that other
+    /// processes on the host cannot inspect or terminate backends.
+    pub admin_token: Option<String>,
+
+    /// If provided, the agent serves an HTTP API to backends on this port,
+    /// which they reach at `plane.internal` (e.g. to keep themselves alive
+    /// without connections). The port should not be reachable from outside
+    /// the host. Only supported with Docker.
+    pub metadata_port: Option<u16>,
+
+    /// The most, in seconds, a backend may extend its maximum lifetime by
+    /// through the metadata API, in total.
+    #[serde(default = "default_max_lifetime_extension_secs")]
+    pub max_lifetime_extension_secs: u64,
+
+    /// If provided, the drone reports itself as not ready, removes unused
+    /// images, and refuses new backends while the disk holding the engine's
+    /// data directory has less than this many bytes free.
+    pub min_disk_free_bytes: Option<u64>,
+
+    /// If provided, backend logs are archived to S3-compatible object
+    /// storage, so that they remain available after the backend is gone.
+    pub log_archive: Option<LogArchiveConfig>,
+
+    /// If provided, commands run as backends start, become ready, and stop,
+    /// for site-specific integrations.
+    pub hooks: Option<HooksConfig>,
+
+    /// If provided, commands can be run inside backends by requests signed
+    /// with the configured key.
+    pub exec: Option<ExecConfig>,
+
+    /// If provided, backends on this drone can be moved to other drones by
+    /// requests signed with the configured key.
+    pub migrate: Option<MigrateConfig>,
+
+    /// If provided, every backend on this drone can be terminated at once by
+    /// requests signed with the configured key.
+    pub terminate_all: Option<TerminateAllConfig>,
+
+    /// If provided, the drone replaces its binary and re-execs itself when
+    /// the controller publishes another drone version for the cluster.
+    pub update: Option<UpdateConfig>,
+
+    /// Base64-encoded Ed25519 public key of the controller's certificate
+    /// signing key. If provided (along with `cert`), the drone installs TLS
+    /// certificates pushed by the controller which are signed with the key.
+    pub certificate_public_key: Option<String>,
+
+    /// Base64-encoded private key (as generated by
+    /// `plane-cli generate-signing-key`) which the drone registers with the
+    /// controller under. Required to join clusters which require drones to
+    /// be approved.
+    pub registration_key: Option<String>,
+}
+
+fn default_heartbeat_interval_secs() -> u64 {
+    4
+}
+
+fn default_load_timeout_secs() -> u64 {
+    DEFAULT_LOAD_TIMEOUT.as_secs()
+}
+
+fn default_max_lifetime_extension_secs() -> u64 {
+    86400
 }
 
 #[derive(Serialize, Deserialize)]
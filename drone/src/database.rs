@@ -8,14 +8,26 @@
 //! based on type information stored in `sqlx-data.json`. If
 //! you change a query in this file, you will likely need to
 //! run `generate-sqlx-data.mjs` to get Rust to accept it.
+//!
+//! The schema is defined by the migrations in `drone/migrations`, which are
+//! embedded in the binary and applied when the database is opened. Since a
+//! fleet of drones is upgraded (and sometimes rolled back) gradually, each
+//! migration should be additive: new tables, and new columns which are
+//! nullable or have a default, so that older drones can keep using a
+//! database migrated by a newer one.
 use chrono::{DateTime, TimeZone, Utc};
 use plane_core::{
-    messages::agent::{BackendState, SpawnRequest},
+    messages::agent::{
+        BackendState, BackendStateMessage, IdlePolicy, SpawnRequest, SpawnTimeline,
+        UpdateBackendMetadata,
+    },
+    messages::dns::SetDnsRecord,
+    nats::TypedMessage,
     types::BackendId,
 };
 use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
 use sqlx::{migrate, Result, SqlitePool};
-use std::{path::Path, str::FromStr};
+use std::{collections::HashMap, net::IpAddr, path::Path, str::FromStr};
 
 #[allow(unused)]
 #[derive(Clone, Debug)]
@@ -36,26 +48,85 @@ impl DroneDatabase {
             .filename(db_path)
             .create_if_missing(true);
         let pool = SqlitePoolOptions::new().connect_with(co).await?;
-        migrate!("./migrations").run(&pool).await?;
-
         let connection = DroneDatabase { pool };
+        connection.migrate().await?;
+
         Ok(connection)
     }
 
+    /// Apply the migrations which have not yet been applied to the database.
+    /// Migrations applied by a newer drone, which this binary does not know
+    /// about, are ignored so that drones can be rolled back.
+    async fn migrate(&self) -> Result<()> {
+        let mut migrator = migrate!("./migrations");
+        migrator.set_ignore_missing(true);
+
+        let applied = self.applied_migrations().await?;
+        for migration in migrator.iter() {
+            if !applied.contains(&migration.version) {
+                tracing::info!(
+                    version = migration.version,
+                    description = %migration.description,
+                    "Applying database migration."
+                );
+            }
+        }
+
+        let unknown: Vec<i64> = applied
+            .iter()
+            .filter(|version| !migrator.iter().any(|m| m.version == **version))
+            .copied()
+            .collect();
+        if !unknown.is_empty() {
+            tracing::warn!(
+                ?unknown,
+                "Database has migrations applied by a newer drone; ignoring them."
+            );
+        }
+
+        migrator.run(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Versions of the migrations which have been applied to the database.
+    async fn applied_migrations(&self) -> Result<Vec<i64>> {
+        let tables: i64 = sqlx::query_scalar(
+            "select count(*) from sqlite_master where type = 'table' and name = '_sqlx_migrations'",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if tables == 0 {
+            return Ok(Vec::new());
+        }
+
+        sqlx::query_scalar("select version from _sqlx_migrations where success = 1")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// Version of the most recent migration applied to the database.
+    pub async fn schema_version(&self) -> Result<Option<i64>> {
+        Ok(self.applied_migrations().await?.into_iter().max())
+    }
+
     pub async fn insert_backend(&self, spec: &SpawnRequest) -> Result<()> {
         let backend_id = spec.backend_id.id().to_string();
+        let bearer_token = spec.bearer_token.clone();
         let spec =
             serde_json::to_string(&spec).expect("SpawnRequest serialization should never fail.");
+        let created_at = Utc::now().timestamp();
 
         sqlx::query!(
             r"
             insert into backend
-            (name, spec, state)
+            (name, spec, state, bearer_token, created_at)
             values
-            (?, ?, 'Loading')
+            (?, ?, 'Loading', ?, ?)
             ",
             backend_id,
             spec,
+            bearer_token,
+            created_at,
         )
         .execute(&self.pool)
         .await?;
@@ -118,6 +189,272 @@ impl DroneDatabase {
         Ok(())
     }
 
+    pub async fn get_backend_timeline(
+        &self,
+        backend: &BackendId,
+    ) -> anyhow::Result<Option<SpawnTimeline>> {
+        let backend_id = backend.id().to_string();
+
+        let row = sqlx::query!(
+            r"
+            select timeline
+            from backend
+            where name = ?
+            ",
+            backend_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row.and_then(|row| row.timeline) {
+            Some(timeline) => Ok(Some(serde_json::from_str(&timeline)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set_backend_timeline(
+        &self,
+        backend: &BackendId,
+        timeline: &SpawnTimeline,
+    ) -> Result<()> {
+        let backend_id = backend.id().to_string();
+        let timeline = serde_json::to_string(timeline)
+            .expect("SpawnTimeline serialization should never fail.");
+
+        sqlx::query!(
+            r"
+            update backend
+            set timeline = ?
+            where name = ?
+            ",
+            timeline,
+            backend_id,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Apply a metadata update to the stored spec of a backend, returning its
+    /// new metadata, or None if the backend is not known to this drone.
+    pub async fn update_backend_metadata(
+        &self,
+        update: &UpdateBackendMetadata,
+    ) -> anyhow::Result<Option<HashMap<String, String>>> {
+        let backend_id = update.backend_id.id().to_string();
+
+        let mut transaction = self.pool.begin().await?;
+        let row = sqlx::query!(
+            r"
+            select spec
+            from backend
+            where name = ?
+            ",
+            backend_id
+        )
+        .fetch_optional(&mut transaction)
+        .await?;
+
+        let mut spec: SpawnRequest = match row {
+            Some(row) => serde_json::from_str(&row.spec)?,
+            None => return Ok(None),
+        };
+        update.apply(&mut spec.metadata);
+        let spec_json =
+            serde_json::to_string(&spec).expect("SpawnRequest serialization should never fail.");
+
+        sqlx::query!(
+            r"
+            update backend
+            set spec = ?
+            where name = ?
+            ",
+            spec_json,
+            backend_id,
+        )
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(Some(spec.metadata))
+    }
+
+    /// Remove the registry credentials from the stored spec of a backend, once
+    /// its image has been pulled and they are no longer needed.
+    pub async fn clear_backend_credentials(&self, backend_id: &BackendId) -> anyhow::Result<()> {
+        let backend_id = backend_id.id().to_string();
+
+        let mut transaction = self.pool.begin().await?;
+        let row = sqlx::query!(
+            r"
+            select spec
+            from backend
+            where name = ?
+            ",
+            backend_id
+        )
+        .fetch_optional(&mut transaction)
+        .await?;
+
+        let mut spec: SpawnRequest = match row {
+            Some(row) => serde_json::from_str(&row.spec)?,
+            None => return Ok(()),
+        };
+        if spec.executable.credentials.take().is_none() {
+            return Ok(());
+        }
+        let spec_json =
+            serde_json::to_string(&spec).expect("SpawnRequest serialization should never fail.");
+
+        sqlx::query!(
+            r"
+            update backend
+            set spec = ?
+            where name = ?
+            ",
+            spec_json,
+            backend_id,
+        )
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Buffer a state message which could not be published, to be replayed later.
+    pub async fn insert_pending_state_message(&self, message: &BackendStateMessage) -> Result<()> {
+        let message = serde_json::to_string(message)
+            .expect("BackendStateMessage serialization should never fail.");
+
+        sqlx::query!(
+            r"
+            insert into pending_state_message
+            (message)
+            values
+            (?)
+            ",
+            message
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Buffered state messages, in the order they were buffered, along with their ids.
+    pub async fn get_pending_state_messages(
+        &self,
+    ) -> anyhow::Result<Vec<(i64, BackendStateMessage)>> {
+        sqlx::query!(
+            r"
+            select id, message
+            from pending_state_message
+            order by id
+            "
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|d| Ok((d.id, serde_json::from_str(&d.message)?)))
+        .collect()
+    }
+
+    pub async fn delete_pending_state_message(&self, id: i64) -> Result<()> {
+        sqlx::query!(
+            r"
+            delete from pending_state_message
+            where id = ?
+            ",
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Buffer a DNS record which could not be published, to be replayed later.
+    /// Replaces any buffered value of the same record.
+    pub async fn insert_pending_dns_record(&self, record: &SetDnsRecord) -> Result<()> {
+        let subject = record.subject();
+        let message =
+            serde_json::to_string(record).expect("SetDnsRecord serialization should never fail.");
+
+        sqlx::query!(
+            r"
+            insert into pending_dns_record
+            (subject, name, message)
+            values
+            (?, ?, ?)
+            on conflict (subject, name) do update
+            set message = excluded.message
+            ",
+            subject,
+            record.name,
+            message
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Buffered DNS records.
+    pub async fn get_pending_dns_records(&self) -> anyhow::Result<Vec<SetDnsRecord>> {
+        sqlx::query!(
+            r"
+            select message
+            from pending_dns_record
+            "
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|d| Ok(serde_json::from_str(&d.message)?))
+        .collect()
+    }
+
+    /// Remove a buffered DNS record, unless it has since been replaced by a
+    /// newer value.
+    pub async fn delete_pending_dns_record(&self, record: &SetDnsRecord) -> Result<()> {
+        let subject = record.subject();
+        let message =
+            serde_json::to_string(record).expect("SetDnsRecord serialization should never fail.");
+
+        sqlx::query!(
+            r"
+            delete from pending_dns_record
+            where subject = ? and name = ? and message = ?
+            ",
+            subject,
+            record.name,
+            message
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Remove the buffered DNS records of a backend, once it has stopped.
+    pub async fn delete_pending_dns_records(&self, backend: &BackendId) -> Result<()> {
+        let name = backend.to_string();
+
+        sqlx::query!(
+            r"
+            delete from pending_dns_record
+            where name = ?
+            ",
+            name
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
     /// Get the downstream source to direct a request on an incoming subdomain to.
     pub async fn get_proxy_route(&self, subdomain: &str) -> Result<Option<String>> {
         Ok(sqlx::query!(
@@ -128,6 +465,83 @@ impl DroneDatabase {
             on route.backend = backend.name
             where subdomain = ?
             and state = 'Ready'
+            and not draining
+            ",
+            subdomain
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|d| d.address))
+    }
+
+    /// Get the spawn request of the backend behind a subdomain, if there is
+    /// one.
+    async fn get_proxy_route_spec(&self, subdomain: &str) -> anyhow::Result<Option<SpawnRequest>> {
+        let row = sqlx::query!(
+            r"
+            select backend.spec as spec
+            from route
+            left join backend
+            on route.backend = backend.name
+            where subdomain = ?
+            ",
+            subdomain
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row.and_then(|row| row.spec) {
+            Some(spec) => Ok(Some(serde_json::from_str(&spec)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the idle policy of the backend behind a subdomain, if there is one.
+    pub async fn get_proxy_route_idle_policy(
+        &self,
+        subdomain: &str,
+    ) -> anyhow::Result<Option<IdlePolicy>> {
+        Ok(self
+            .get_proxy_route_spec(subdomain)
+            .await?
+            .map(|spec| spec.idle_policy))
+    }
+
+    /// Whether the backend behind a subdomain is also routed to by path.
+    pub async fn get_proxy_route_path_routing(&self, subdomain: &str) -> anyhow::Result<bool> {
+        Ok(self
+            .get_proxy_route_spec(subdomain)
+            .await?
+            .map_or(false, |spec| spec.path_routing))
+    }
+
+    /// Subdomains whose backend is ready, so that the proxy can forget what it
+    /// has cached about backends which have since terminated.
+    pub async fn get_ready_route_subdomains(&self) -> Result<Vec<String>> {
+        Ok(sqlx::query!(
+            r"
+            select subdomain
+            from route
+            left join backend
+            on route.backend = backend.name
+            where state = 'Ready'
+            "
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|row| row.subdomain)
+        .collect())
+    }
+
+    /// Get the address behind a subdomain regardless of the backend's state,
+    /// for the proxy self-test run before a backend is advertised as ready.
+    pub async fn get_self_test_route(&self, subdomain: &str) -> Result<Option<String>> {
+        Ok(sqlx::query!(
+            r"
+            select address
+            from route
+            where subdomain = ?
             ",
             subdomain
         )
@@ -136,6 +550,27 @@ impl DroneDatabase {
         .map(|d| d.address))
     }
 
+    /// Get the backend behind a subdomain and the bearer token required to
+    /// access it, if the backend requires one.
+    pub async fn get_proxy_route_bearer_token(
+        &self,
+        subdomain: &str,
+    ) -> Result<Option<(BackendId, String)>> {
+        Ok(sqlx::query!(
+            r"
+            select route.backend as backend, backend.bearer_token as bearer_token
+            from route
+            left join backend
+            on route.backend = backend.name
+            where subdomain = ?
+            ",
+            subdomain
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .and_then(|d| Some((BackendId::new(d.backend?), d.bearer_token?))))
+    }
+
     pub async fn insert_proxy_route(
         &self,
         backend: &BackendId,
@@ -160,6 +595,23 @@ impl DroneDatabase {
         Ok(())
     }
 
+    /// Stop routing new requests to a backend.
+    pub async fn set_backend_draining(&self, backend: &BackendId) -> Result<()> {
+        let backend_id = backend.id().to_string();
+        sqlx::query!(
+            r"
+            update route
+            set draining = true
+            where backend = ?
+            ",
+            backend_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
     pub async fn reset_last_active_times(&self, subdomains: &[String]) -> Result<()> {
         for subdomain in subdomains {
             sqlx::query!(
@@ -194,4 +646,95 @@ impl DroneDatabase {
 
         Ok(Utc.timestamp(time, 0))
     }
+
+    /// The ready backends behind routes whose address is on the given IP, and
+    /// the routes' subdomains, used to identify backends calling the drone.
+    /// Backends which use the host's network all share its IP.
+    pub async fn get_backends_by_ip(&self, ip: IpAddr) -> Result<Vec<(BackendId, String)>> {
+        let address_pattern = match ip {
+            IpAddr::V4(ip) => format!("{}:%", ip),
+            IpAddr::V6(ip) => format!("[{}]:%", ip),
+        };
+
+        Ok(sqlx::query!(
+            r"
+            select route.backend as backend, route.subdomain as subdomain
+            from route
+            left join backend
+            on route.backend = backend.name
+            where address like ?
+            and state = 'Ready'
+            ",
+            address_pattern
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .filter_map(|d| Some((BackendId::new(d.backend?), d.subdomain)))
+        .collect())
+    }
+
+    /// Extend a backend's maximum lifetime by the given number of seconds, up
+    /// to a total extension of `max_secs`.
+    pub async fn extend_backend_lifetime(
+        &self,
+        backend: &BackendId,
+        secs: i64,
+        max_secs: i64,
+    ) -> Result<()> {
+        let backend_id = backend.id();
+        sqlx::query!(
+            r"
+            update backend
+            set lifetime_extension_secs = min(lifetime_extension_secs + ?, ?)
+            where name = ?
+            ",
+            secs,
+            max_secs,
+            backend_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// How many seconds a backend has extended its maximum lifetime by.
+    pub async fn get_backend_lifetime_extension(&self, backend: &BackendId) -> Result<i64> {
+        let backend_id = backend.id();
+
+        Ok(sqlx::query!(
+            r#"
+            select lifetime_extension_secs
+            from backend
+            where name = ?
+            "#,
+            backend_id
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .lifetime_extension_secs)
+    }
+
+    /// The time a backend was spawned, if known.
+    pub async fn get_backend_created_at(
+        &self,
+        backend: &BackendId,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let backend_id = backend.id();
+
+        let time = sqlx::query!(
+            r#"
+            select created_at
+            from backend
+            where name = ?
+            "#,
+            backend_id
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .created_at;
+
+        Ok(time.map(|time| Utc.timestamp(time, 0)))
+    }
 }
@@ -1,11 +1,23 @@
-use super::{agent::AgentOptions, cert::CertOptions, proxy::ProxyOptions};
+use super::{
+    agent::{exec::ExecAccess, AgentOptions, ProxySelfTest},
+    cert::CertOptions,
+    proxy::{
+        metrics::ProxyMetricsTracker, AccessLogOptions, ProxyOptions, ReconnectOptions,
+        ResponseCacheOptions, SessionOptions,
+    },
+};
 use crate::config::DroneConfig;
 use crate::database::DroneDatabase;
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use plane_core::{
     nats::TypedNats,
+    signing::VerifyingKey,
     types::{ClusterName, DroneId},
 };
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
 
 pub struct DronePlan {
     pub proxy_options: Option<ProxyOptions>,
@@ -44,28 +56,184 @@ impl DronePlan {
             None
         };
 
+        let proxy_self_test = config.proxy.as_ref().map(|proxy_config| {
+            // A proxy bound to every interface is reached over loopback.
+            let ip = match proxy_config.bind_ip {
+                IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                ip => ip,
+            };
+
+            ProxySelfTest {
+                addr: SocketAddr::new(ip, proxy_config.https_port),
+                https: config.cert.is_some(),
+            }
+        });
+
+        // Traffic through the proxy is reported in the agent's status.
+        let proxy_metrics = config
+            .proxy
+            .as_ref()
+            .map(|_| ProxyMetricsTracker::default());
+
         let proxy_options = if let Some(proxy_config) = config.proxy {
+            let access_log = if let Some(access_log_config) = proxy_config.access_log {
+                if !(0.0..=1.0).contains(&access_log_config.sample_rate) {
+                    return Err(anyhow!(
+                        "access_log.sample_rate must be between 0 and 1, got {}.",
+                        access_log_config.sample_rate
+                    ));
+                }
+
+                Some(AccessLogOptions {
+                    nats: nats
+                        .clone()
+                        .context("proxy.access_log requires nats to be configured.")?,
+                    sample_rate: access_log_config.sample_rate,
+                })
+            } else {
+                None
+            };
+
             Some(ProxyOptions {
                 cluster_domain: config.cluster_domain.clone(),
                 db: db.clone(),
                 bind_ip: proxy_config.bind_ip,
+                bind_ipv6: proxy_config.bind_ipv6,
                 bind_port: proxy_config.https_port,
                 key_pair: config.cert.clone(),
+                access_log,
+                session: SessionOptions {
+                    ttl: Duration::from_secs(proxy_config.session.ttl_secs),
+                    allowed_origins: proxy_config.session.allowed_origins,
+                },
+                reconnect: proxy_config.reconnect.map(|reconnect| ReconnectOptions {
+                    secret: reconnect.secret,
+                    token_ttl: Duration::from_secs(reconnect.token_ttl_secs),
+                    allowed_origins: reconnect.allowed_origins,
+                }),
+                cache: proxy_config.cache.map(|cache| ResponseCacheOptions {
+                    path_patterns: cache.path_patterns,
+                    max_entries_per_backend: cache.max_entries_per_backend,
+                    max_body_bytes: cache.max_body_bytes,
+                    max_total_bytes: cache.max_total_bytes,
+                }),
+                metrics: proxy_metrics.clone(),
             })
         } else {
             None
         };
 
         let agent_options = if let Some(agent_config) = config.agent {
+            if !(1..=4).contains(&agent_config.heartbeat_interval_secs) {
+                return Err(anyhow!(
+                    "heartbeat_interval_secs must be between 1 and 4, got {}.",
+                    agent_config.heartbeat_interval_secs
+                ));
+            }
+
+            if let Some(hooks) = &agent_config.hooks {
+                if hooks.timeout_secs == 0 {
+                    return Err(anyhow!("hooks.timeout_secs must be greater than 0."));
+                }
+                for (name, command) in [
+                    ("pre_start", &hooks.pre_start),
+                    ("post_ready", &hooks.post_ready),
+                    ("pre_stop", &hooks.pre_stop),
+                    ("post_terminate", &hooks.post_terminate),
+                ] {
+                    if matches!(command, Some(command) if command.is_empty()) {
+                        return Err(anyhow!("hooks.{} must name a command to run.", name));
+                    }
+                }
+            }
+
+            if let Some(image_gc) = &agent_config.docker.image_gc {
+                if image_gc.interval_secs == 0 {
+                    return Err(anyhow!("image_gc.interval_secs must be greater than 0."));
+                }
+                if image_gc.disk_usage_percent > 100 {
+                    return Err(anyhow!(
+                        "image_gc.disk_usage_percent must be at most 100, got {}.",
+                        image_gc.disk_usage_percent
+                    ));
+                }
+            }
+
+            if agent_config.load_timeout_secs == 0 {
+                return Err(anyhow!("load_timeout_secs must be greater than 0."));
+            }
+            if let Some(spawn_limit) = &agent_config.spawn_limit {
+                if spawn_limit.queue_timeout_secs == 0 {
+                    return Err(anyhow!(
+                        "spawn_limit.queue_timeout_secs must be greater than 0."
+                    ));
+                }
+            }
+
+            let exec_access = match &agent_config.exec {
+                Some(exec) => {
+                    if exec.idle_timeout_secs == 0 {
+                        return Err(anyhow!("exec.idle_timeout_secs must be greater than 0."));
+                    }
+                    Some(ExecAccess {
+                        public_key: VerifyingKey::from_base64(&exec.public_key)
+                            .context("Invalid exec.public_key.")?,
+                        idle_timeout: Duration::from_secs(exec.idle_timeout_secs),
+                    })
+                }
+                None => None,
+            };
+
             Some(AgentOptions {
                 cluster_domain: ClusterName::new(&config.cluster_domain),
                 drone_id: drone_id.clone(),
                 db,
                 docker_options: agent_config.docker,
+                containerd_options: agent_config.containerd,
                 nats: nats
                     .clone()
                     .expect("Expected --nats-url for running agent."),
                 ip: agent_config.ip,
+                ipv6: agent_config.ipv6,
+                maintenance_windows: agent_config.maintenance_windows,
+                heartbeat_interval: Duration::from_secs(agent_config.heartbeat_interval_secs),
+                load_timeout: Duration::from_secs(agent_config.load_timeout_secs),
+                max_backends: agent_config.max_backends,
+                spawn_limit: agent_config.spawn_limit,
+                proxy_self_test,
+                proxy_metrics,
+                admin_port: agent_config.admin_port,
+                admin_token: agent_config.admin_token,
+                metadata_port: agent_config.metadata_port,
+                max_lifetime_extension: Duration::from_secs(
+                    agent_config.max_lifetime_extension_secs,
+                ),
+                min_disk_free_bytes: agent_config.min_disk_free_bytes,
+                log_archive: agent_config.log_archive,
+                hooks: agent_config.hooks,
+                exec_access,
+                migrate_public_key: agent_config
+                    .migrate
+                    .as_ref()
+                    .map(|migrate| VerifyingKey::from_base64(&migrate.public_key))
+                    .transpose()
+                    .context("Invalid migrate.public_key.")?,
+                terminate_all_public_key: agent_config
+                    .terminate_all
+                    .as_ref()
+                    .map(|terminate_all| VerifyingKey::from_base64(&terminate_all.public_key))
+                    .transpose()
+                    .context("Invalid terminate_all.public_key.")?,
+                update: agent_config.update,
+                registration_key: agent_config.registration_key,
+                certificate_public_key: agent_config
+                    .certificate_public_key
+                    .as_deref()
+                    .map(VerifyingKey::from_base64)
+                    .transpose()
+                    .context("Invalid certificate_public_key.")?,
+                cert_paths: config.cert.clone(),
             })
         } else {
             None
@@ -0,0 +1,94 @@
+//! Publishing of sampled proxy requests as [AccessLogMessage]s.
+
+use chrono::Utc;
+use hyper::{Body, Response};
+use plane_core::{
+    logging::LogError, messages::agent::AccessLogMessage, nats::TypedNats, types::BackendId,
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Publishes a fraction of proxied requests to NATS.
+#[derive(Clone)]
+pub struct AccessLogger {
+    nats: TypedNats,
+    sample_rate: f64,
+    requests: Arc<AtomicU64>,
+}
+
+/// Whether the request numbered `n` (counting from zero) is logged. Sampling
+/// is deterministic, so that requests are logged evenly instead of in bursts.
+fn is_sampled(n: u64, sample_rate: f64) -> bool {
+    ((n + 1) as f64 * sample_rate).floor() > (n as f64 * sample_rate).floor()
+}
+
+impl AccessLogger {
+    /// `sample_rate` is the fraction of requests to log, between 0 and 1.
+    pub fn new(nats: TypedNats, sample_rate: f64) -> Self {
+        AccessLogger {
+            nats,
+            sample_rate,
+            requests: Arc::default(),
+        }
+    }
+
+    /// Record a request which received a response, publishing it in the
+    /// background if it is sampled.
+    pub fn log(
+        &self,
+        backend: &BackendId,
+        method: &str,
+        path: &str,
+        response: &Response<Body>,
+        latency: Duration,
+    ) {
+        let n = self.requests.fetch_add(1, Ordering::Relaxed);
+        if !is_sampled(n, self.sample_rate) {
+            return;
+        }
+
+        let message = AccessLogMessage {
+            backend: backend.clone(),
+            method: method.to_string(),
+            path: path.to_string(),
+            status: response.status().as_u16(),
+            latency_ms: latency.as_millis() as u64,
+            bytes: response
+                .headers()
+                .get(http::header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok()),
+            time: Utc::now(),
+        };
+
+        let nats = self.nats.clone();
+        tokio::spawn(async move {
+            nats.publish(&message)
+                .await
+                .log_error("Error publishing access log.");
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampled(sample_rate: f64) -> usize {
+        (0..100).filter(|n| is_sampled(*n, sample_rate)).count()
+    }
+
+    #[test]
+    fn test_sampling() {
+        assert_eq!(100, sampled(1.0));
+        assert_eq!(25, sampled(0.25));
+        assert_eq!(0, sampled(0.0));
+        assert!(is_sampled(3, 0.25));
+        assert!(!is_sampled(4, 0.25));
+    }
+}
@@ -0,0 +1,666 @@
+//! Caching of static responses from backends.
+//!
+//! Backends of the same image often serve identical static bundles to each
+//! of their clients. When enabled, the proxy keeps a small LRU cache for each
+//! backend of `GET` responses for paths matching configured patterns, which
+//! the backend marks as cacheable with a `Cache-Control` header. Entries are
+//! only served to requests to the same backend, after the request has been
+//! authorized like any other. The caches of all backends share a limit on
+//! the bytes they hold, beyond which the least recently used entries of any
+//! backend are evicted.
+
+use super::ResponseCacheOptions;
+use anyhow::{Context, Result};
+use http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use hyper::{body::Bytes, Body, Request, Response};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Header added to responses served from the cache.
+const CACHE_HEADER: &str = "x-plane-cache";
+
+/// Whether `path` matches `pattern`, in which `*` matches any sequence of
+/// characters, including `/`.
+fn matches_pattern(pattern: &str, path: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match path.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // The pattern has no wildcard, so the path must equal it.
+        None => return rest.is_empty(),
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// How long a response may be cached for, according to its `Cache-Control`
+/// header. Responses which are private, set cookies, or do not give a
+/// lifetime are not cached. As a shared cache, responses to requests with an
+/// `Authorization` header are only cached if the response explicitly allows
+/// it with `public`, `s-maxage` or `must-revalidate` (RFC 9111, section 3.5).
+fn response_ttl(headers: &HeaderMap, has_authorization: bool) -> Option<Duration> {
+    if headers.contains_key(header::SET_COOKIE) {
+        return None;
+    }
+
+    // Only variants by encoding are cached, since the encoding is part of the key.
+    for vary in headers.get_all(header::VARY) {
+        let vary = vary.to_str().ok()?;
+        if vary
+            .split(',')
+            .any(|field| !field.trim().eq_ignore_ascii_case("accept-encoding"))
+        {
+            return None;
+        }
+    }
+
+    let mut max_age = None;
+    let mut s_maxage = None;
+    let mut shareable = false;
+    for directive in headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+    {
+        let directive = directive.trim().to_ascii_lowercase();
+        match directive.split_once('=') {
+            Some(("max-age", secs)) => max_age = secs.trim_matches('"').parse().ok(),
+            Some(("s-maxage", secs)) => {
+                s_maxage = secs.trim_matches('"').parse().ok();
+                shareable = true;
+            }
+            None if ["no-store", "no-cache", "private"].contains(&directive.as_str()) => {
+                return None
+            }
+            None if ["public", "must-revalidate"].contains(&directive.as_str()) => shareable = true,
+            _ => {}
+        }
+    }
+
+    if has_authorization && !shareable {
+        return None;
+    }
+
+    match s_maxage.or(max_age) {
+        Some(0) | None => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+    }
+}
+
+/// Key of a cached response within a backend's cache.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    path_and_query: String,
+    accept_encoding: Option<String>,
+}
+
+/// A request whose response may be served from or stored in the cache.
+#[derive(Clone, Debug)]
+pub struct CacheableRequest {
+    key: CacheKey,
+    has_authorization: bool,
+}
+
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    expires: Instant,
+    last_used: u64,
+}
+
+impl CachedResponse {
+    /// Bytes of memory the entry is counted as holding.
+    fn size(&self) -> usize {
+        self.body.len()
+            + self
+                .headers
+                .iter()
+                .map(|(key, value)| key.as_str().len() + value.len())
+                .sum::<usize>()
+    }
+
+    fn to_response(&self, now: Instant) -> Result<Response<Body>> {
+        let mut builder = Response::builder().status(self.status);
+        for (key, value) in &self.headers {
+            builder = builder.header(key, value);
+        }
+
+        Ok(builder
+            .header(
+                header::AGE,
+                now.duration_since(self.stored).as_secs().to_string(),
+            )
+            .header(CACHE_HEADER, HeaderValue::from_static("hit"))
+            .body(Body::from(self.body.clone()))?)
+    }
+}
+
+/// Responses cached for a single backend.
+#[derive(Default)]
+struct BackendCache {
+    entries: HashMap<CacheKey, CachedResponse>,
+}
+
+/// The caches of every backend, and the bytes they hold between them.
+#[derive(Default)]
+struct Caches {
+    backends: HashMap<String, BackendCache>,
+    total_bytes: usize,
+
+    /// Incremented on each use of an entry, to order entries by recency.
+    uses: u64,
+}
+
+impl Caches {
+    fn get(&mut self, backend: &str, key: &CacheKey, now: Instant) -> Option<&CachedResponse> {
+        let cache = self.backends.get_mut(backend)?;
+        if cache.entries.get(key)?.expires <= now {
+            if let Some(entry) = cache.entries.remove(key) {
+                self.total_bytes -= entry.size();
+            }
+            return None;
+        }
+
+        self.uses += 1;
+        let entry = cache.entries.get_mut(key)?;
+        entry.last_used = self.uses;
+        Some(entry)
+    }
+
+    fn remove(&mut self, backend: &str, key: &CacheKey) {
+        if let Some(cache) = self.backends.get_mut(backend) {
+            if let Some(entry) = cache.entries.remove(key) {
+                self.total_bytes -= entry.size();
+            }
+            if cache.entries.is_empty() {
+                self.backends.remove(backend);
+            }
+        }
+    }
+
+    /// The least recently used entry of a backend's cache, or of every
+    /// backend's if none is given.
+    fn least_recent(&self, backend: Option<&str>) -> Option<(String, CacheKey)> {
+        self.backends
+            .iter()
+            .filter(|(name, _)| backend.map_or(true, |backend| backend == name.as_str()))
+            .flat_map(|(name, cache)| {
+                cache
+                    .entries
+                    .iter()
+                    .map(move |(key, entry)| (entry.last_used, name, key))
+            })
+            .min_by_key(|(last_used, _, _)| *last_used)
+            .map(|(_, name, key)| (name.clone(), key.clone()))
+    }
+
+    fn insert(
+        &mut self,
+        backend: &str,
+        key: CacheKey,
+        mut entry: CachedResponse,
+        options: &ResponseCacheOptions,
+    ) {
+        self.remove(backend, &key);
+
+        let entries = self
+            .backends
+            .get(backend)
+            .map_or(0, |cache| cache.entries.len());
+        if entries >= options.max_entries_per_backend {
+            if let Some((backend, key)) = self.least_recent(Some(backend)) {
+                self.remove(&backend, &key);
+            }
+        }
+        while self.total_bytes + entry.size() > options.max_total_bytes {
+            match self.least_recent(None) {
+                Some((backend, key)) => self.remove(&backend, &key),
+                None => break,
+            }
+        }
+
+        self.uses += 1;
+        entry.last_used = self.uses;
+        self.total_bytes += entry.size();
+        self.backends
+            .entry(backend.to_string())
+            .or_default()
+            .entries
+            .insert(key, entry);
+    }
+}
+
+#[derive(Clone)]
+pub struct ResponseCache {
+    options: Arc<ResponseCacheOptions>,
+    caches: Arc<Mutex<Caches>>,
+}
+
+impl ResponseCache {
+    pub fn new(options: ResponseCacheOptions) -> Self {
+        ResponseCache {
+            options: Arc::new(options),
+            caches: Arc::default(),
+        }
+    }
+
+    fn caches(&self) -> std::sync::MutexGuard<'_, Caches> {
+        self.caches
+            .lock()
+            .expect("Response cache lock was poisoned.")
+    }
+
+    /// What a request's response would be cached under, if it may be served
+    /// from or stored in the cache.
+    pub fn cacheable_request(&self, req: &Request<Body>) -> Option<CacheableRequest> {
+        if req.method() != Method::GET {
+            return None;
+        }
+
+        let path = req.uri().path();
+        if !self
+            .options
+            .path_patterns
+            .iter()
+            .any(|pattern| matches_pattern(pattern, path))
+        {
+            return None;
+        }
+
+        // Honor clients which ask to bypass caches.
+        let bypass = req
+            .headers()
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.contains("no-cache") || value.contains("no-store"));
+        if bypass {
+            return None;
+        }
+
+        Some(CacheableRequest {
+            key: CacheKey {
+                path_and_query: req
+                    .uri()
+                    .path_and_query()
+                    .map(|path_and_query| path_and_query.to_string())
+                    .unwrap_or_else(|| path.to_string()),
+                accept_encoding: req
+                    .headers()
+                    .get(header::ACCEPT_ENCODING)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| value.to_string()),
+            },
+            has_authorization: req.headers().contains_key(header::AUTHORIZATION),
+        })
+    }
+
+    /// A fresh cached response for the request, if there is one.
+    pub fn get(&self, backend: &str, request: &CacheableRequest) -> Option<Response<Body>> {
+        let mut caches = self.caches();
+        let now = Instant::now();
+        let entry = caches.get(backend, &request.key, now)?;
+
+        match entry.to_response(now) {
+            Ok(response) => Some(response),
+            Err(error) => {
+                tracing::warn!(?error, "Error building cached response.");
+                None
+            }
+        }
+    }
+
+    /// Store a response from a backend if it is cacheable, and return it to
+    /// be sent to the client. Cacheable responses are read into memory
+    /// first, so only those which declare a length within the limit are
+    /// stored.
+    pub async fn insert(
+        &self,
+        backend: &str,
+        request: CacheableRequest,
+        response: Response<Body>,
+    ) -> Result<Response<Body>> {
+        if response.status() != StatusCode::OK {
+            return Ok(response);
+        }
+        let ttl = match response_ttl(response.headers(), request.has_authorization) {
+            Some(ttl) => ttl,
+            None => return Ok(response),
+        };
+        let content_length = response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+        if !matches!(content_length, Some(length) if length <= self.options.max_body_bytes) {
+            return Ok(response);
+        }
+
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body)
+            .await
+            .context("Error reading cacheable response.")?;
+
+        let now = Instant::now();
+        let entry = CachedResponse {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+            stored: now,
+            expires: now + ttl,
+            last_used: 0,
+        };
+        self.caches()
+            .insert(backend, request.key, entry, &self.options);
+
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+
+    /// Drop the responses cached for a backend which is gone.
+    pub fn remove_backend(&self, backend: &str) {
+        let mut caches = self.caches();
+        if let Some(cache) = caches.backends.remove(backend) {
+            caches.total_bytes -= cache
+                .entries
+                .values()
+                .map(|entry| entry.size())
+                .sum::<usize>();
+        }
+    }
+
+    /// Drop the responses cached for every backend but the given ones.
+    pub fn retain_backends(&self, backends: &HashSet<String>) {
+        let gone: Vec<String> = self
+            .caches()
+            .backends
+            .keys()
+            .filter(|backend| !backends.contains(*backend))
+            .cloned()
+            .collect();
+        for backend in gone {
+            self.remove_backend(&backend);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn cache_with_total(max_entries_per_backend: usize, max_total_bytes: usize) -> ResponseCache {
+        ResponseCache::new(ResponseCacheOptions {
+            path_patterns: vec!["/static/*".into(), "*.js".into()],
+            max_entries_per_backend,
+            max_body_bytes: 1024,
+            max_total_bytes,
+        })
+    }
+
+    fn cache(max_entries_per_backend: usize) -> ResponseCache {
+        cache_with_total(max_entries_per_backend, 1 << 20)
+    }
+
+    fn get(path: &str) -> Request<Body> {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    fn cacheable(body: &'static str) -> Response<Body> {
+        Response::builder()
+            .header(header::CACHE_CONTROL, "public, max-age=60")
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("/static/*", "/static/app.css"));
+        assert!(matches_pattern("/static/*", "/static/"));
+        assert!(!matches_pattern("/static/*", "/api/static/app.css"));
+        assert!(matches_pattern("*.js", "/bundle/app.js"));
+        assert!(!matches_pattern("*.js", "/bundle/app.json"));
+        assert!(matches_pattern("/assets/*/*.wasm", "/assets/v2/game.wasm"));
+        assert!(matches_pattern("/index.html", "/index.html"));
+        assert!(!matches_pattern("/index.html", "/index.html/x"));
+    }
+
+    #[test]
+    fn test_response_ttl() {
+        let ttl = |values: &[(header::HeaderName, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (key, value) in values {
+                headers.append(key, HeaderValue::from_static(value));
+            }
+            response_ttl(&headers, false)
+        };
+
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            ttl(&[(header::CACHE_CONTROL, "public, max-age=60")])
+        );
+        assert_eq!(
+            Some(Duration::from_secs(10)),
+            ttl(&[(header::CACHE_CONTROL, "max-age=60, s-maxage=10")])
+        );
+        assert_eq!(None, ttl(&[]));
+        assert_eq!(None, ttl(&[(header::CACHE_CONTROL, "max-age=0")]));
+        assert_eq!(None, ttl(&[(header::CACHE_CONTROL, "private, max-age=60")]));
+        assert_eq!(None, ttl(&[(header::CACHE_CONTROL, "no-store")]));
+        assert_eq!(
+            None,
+            ttl(&[
+                (header::CACHE_CONTROL, "max-age=60"),
+                (header::SET_COOKIE, "a=b")
+            ])
+        );
+        assert_eq!(
+            None,
+            ttl(&[
+                (header::CACHE_CONTROL, "max-age=60"),
+                (header::VARY, "Cookie")
+            ])
+        );
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            ttl(&[
+                (header::CACHE_CONTROL, "max-age=60"),
+                (header::VARY, "Accept-Encoding")
+            ])
+        );
+    }
+
+    #[test]
+    fn test_response_ttl_with_authorization() {
+        let ttl = |cache_control: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::CACHE_CONTROL,
+                HeaderValue::from_static(cache_control),
+            );
+            response_ttl(&headers, true)
+        };
+
+        assert_eq!(None, ttl("max-age=60"));
+        assert_eq!(Some(Duration::from_secs(60)), ttl("public, max-age=60"));
+        assert_eq!(Some(Duration::from_secs(10)), ttl("s-maxage=10"));
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            ttl("max-age=60, must-revalidate")
+        );
+    }
+
+    #[test]
+    fn test_request_key() {
+        let cache = cache(2);
+        assert!(cache.cacheable_request(&get("/static/app.css")).is_some());
+        assert!(cache.cacheable_request(&get("/api/state")).is_none());
+
+        let post = Request::post("/static/app.css")
+            .body(Body::empty())
+            .unwrap();
+        assert!(cache.cacheable_request(&post).is_none());
+
+        let no_cache = Request::get("/static/app.css")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(Body::empty())
+            .unwrap();
+        assert!(cache.cacheable_request(&no_cache).is_none());
+    }
+
+    #[test]
+    fn test_cache_per_backend_lru() {
+        block_on(async {
+            let cache = cache(2);
+            let key = |path| cache.cacheable_request(&get(path)).unwrap();
+
+            let response = cache
+                .insert("backend1", key("/static/a.css"), cacheable("a"))
+                .await
+                .unwrap();
+            assert_eq!(
+                Bytes::from("a"),
+                hyper::body::to_bytes(response.into_body()).await.unwrap()
+            );
+
+            let hit = cache.get("backend1", &key("/static/a.css")).unwrap();
+            assert_eq!("hit", hit.headers().get(CACHE_HEADER).unwrap());
+            assert_eq!(
+                Bytes::from("a"),
+                hyper::body::to_bytes(hit.into_body()).await.unwrap()
+            );
+
+            // Entries are not shared between backends.
+            assert!(cache.get("backend2", &key("/static/a.css")).is_none());
+
+            // Filling the cache evicts the least recently used entry.
+            cache
+                .insert("backend1", key("/static/b.css"), cacheable("b"))
+                .await
+                .unwrap();
+            assert!(cache.get("backend1", &key("/static/a.css")).is_some());
+            cache
+                .insert("backend1", key("/app.js"), cacheable("c"))
+                .await
+                .unwrap();
+            assert!(cache.get("backend1", &key("/static/a.css")).is_some());
+            assert!(cache.get("backend1", &key("/static/b.css")).is_none());
+            assert!(cache.get("backend1", &key("/app.js")).is_some());
+
+            cache.remove_backend("backend1");
+            assert!(cache.get("backend1", &key("/app.js")).is_none());
+        });
+    }
+
+    #[test]
+    fn test_uncacheable_responses_pass_through() {
+        block_on(async {
+            let cache = cache(2);
+            let key = cache.cacheable_request(&get("/static/a.css")).unwrap();
+
+            let private = Response::builder()
+                .header(header::CACHE_CONTROL, "private, max-age=60")
+                .header(header::CONTENT_LENGTH, 1)
+                .body(Body::from("a"))
+                .unwrap();
+            cache
+                .insert("backend1", key.clone(), private)
+                .await
+                .unwrap();
+            assert!(cache.get("backend1", &key).is_none());
+
+            let too_large = "a".repeat(2048);
+            let response = Response::builder()
+                .header(header::CACHE_CONTROL, "max-age=60")
+                .header(header::CONTENT_LENGTH, too_large.len())
+                .body(Body::from(too_large))
+                .unwrap();
+            cache
+                .insert("backend1", key.clone(), response)
+                .await
+                .unwrap();
+            assert!(cache.get("backend1", &key).is_none());
+        });
+    }
+    #[test]
+    fn test_cache_total_bytes() {
+        block_on(async {
+            // Each entry below counts as 47 bytes, so two fit.
+            let cache = cache_with_total(8, 100);
+            let key = |path| cache.cacheable_request(&get(path)).unwrap();
+
+            cache
+                .insert("backend1", key("/static/a.css"), cacheable("a"))
+                .await
+                .unwrap();
+            cache
+                .insert("backend2", key("/static/b.css"), cacheable("b"))
+                .await
+                .unwrap();
+            assert!(cache.get("backend1", &key("/static/a.css")).is_some());
+
+            // The least recently used entry of any backend is evicted.
+            cache
+                .insert("backend3", key("/static/c.css"), cacheable("c"))
+                .await
+                .unwrap();
+            assert!(cache.get("backend1", &key("/static/a.css")).is_some());
+            assert!(cache.get("backend2", &key("/static/b.css")).is_none());
+            assert!(cache.get("backend3", &key("/static/c.css")).is_some());
+
+            // Entries of backends which are gone are dropped.
+            cache.retain_backends(&HashSet::from(["backend3".to_string()]));
+            assert!(cache.get("backend1", &key("/static/a.css")).is_none());
+            assert!(cache.get("backend3", &key("/static/c.css")).is_some());
+            assert_eq!(47, cache.caches().total_bytes);
+        });
+    }
+
+    #[test]
+    fn test_authorized_requests() {
+        block_on(async {
+            let cache = cache(2);
+            let request = Request::get("/static/a.css")
+                .header(header::AUTHORIZATION, "Bearer token")
+                .body(Body::empty())
+                .unwrap();
+            let request = cache.cacheable_request(&request).unwrap();
+
+            let response = Response::builder()
+                .header(header::CACHE_CONTROL, "max-age=60")
+                .header(header::CONTENT_LENGTH, 1)
+                .body(Body::from("a"))
+                .unwrap();
+            cache
+                .insert("backend1", request.clone(), response)
+                .await
+                .unwrap();
+            assert!(cache.get("backend1", &request).is_none());
+
+            cache
+                .insert("backend1", request.clone(), cacheable("a"))
+                .await
+                .unwrap();
+            assert!(cache.get("backend1", &request).is_some());
+        });
+    }
+}
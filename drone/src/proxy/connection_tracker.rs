@@ -33,12 +33,14 @@ impl ConnectionTracker {
         self.request_events.insert(backend.to_string());
     }
 
-    pub fn increment_connections(&self, backend: &str) {
+    /// Count a connection to a backend as long as the returned guard is
+    /// held, even if the future holding it is dropped.
+    pub fn track_connection(&self, backend: &str) -> ConnectionGuard {
         self.long_lived_connections.add(backend);
-    }
-
-    pub fn decrement_connections(&self, backend: &str) {
-        self.long_lived_connections.remove(backend);
+        ConnectionGuard {
+            connections: self.long_lived_connections.clone(),
+            backend: backend.to_string(),
+        }
     }
 
     pub fn get_and_clear_active_backends(&self) -> Vec<String> {
@@ -54,3 +56,41 @@ impl ConnectionTracker {
         result
     }
 }
+
+/// A connection counted by [ConnectionTracker::track_connection], which is
+/// no longer counted once dropped.
+pub struct ConnectionGuard {
+    connections: Arc<DashMultiset>,
+    backend: String,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.remove(&self.backend);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_guard() {
+        let tracker = ConnectionTracker::default();
+        let first = tracker.track_connection("backend1");
+        let second = tracker.track_connection("backend1");
+        assert_eq!(
+            vec!["backend1".to_string()],
+            tracker.get_and_clear_active_backends()
+        );
+
+        drop(first);
+        assert_eq!(
+            vec!["backend1".to_string()],
+            tracker.get_and_clear_active_backends()
+        );
+
+        drop(second);
+        assert!(tracker.get_and_clear_active_backends().is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+//! Aggregate traffic through the proxy, which the agent reports in the
+//! drone's status so that the scheduler can weigh it.
+
+use plane_core::messages::agent::ProxyMetrics;
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+#[derive(Default)]
+struct Counters {
+    open_connections: AtomicU32,
+    websocket_connections: AtomicU32,
+    requests: AtomicU64,
+}
+
+/// Counts the proxy's connections and requests. Clones share their counts.
+#[derive(Clone)]
+pub struct ProxyMetricsTracker {
+    counters: Arc<Counters>,
+
+    /// Number of requests and time of the previous snapshot, from which the
+    /// request rate is measured.
+    last_snapshot: Arc<Mutex<(u64, Instant)>>,
+}
+
+impl Default for ProxyMetricsTracker {
+    fn default() -> Self {
+        ProxyMetricsTracker {
+            counters: Arc::default(),
+            last_snapshot: Arc::new(Mutex::new((0, Instant::now()))),
+        }
+    }
+}
+
+/// Decrements a count when dropped.
+pub struct OpenGuard {
+    counters: Arc<Counters>,
+    websocket: bool,
+}
+
+impl Drop for OpenGuard {
+    fn drop(&mut self) {
+        if self.websocket {
+            self.counters
+                .websocket_connections
+                .fetch_sub(1, Ordering::Relaxed);
+        } else {
+            self.counters
+                .open_connections
+                .fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl ProxyMetricsTracker {
+    /// Count a client connection as open until the returned guard is dropped.
+    #[must_use]
+    pub fn open_connection(&self) -> OpenGuard {
+        self.counters
+            .open_connections
+            .fetch_add(1, Ordering::Relaxed);
+        OpenGuard {
+            counters: self.counters.clone(),
+            websocket: false,
+        }
+    }
+
+    /// Count an upgraded connection as open until the returned guard is
+    /// dropped.
+    #[must_use]
+    pub fn open_websocket(&self) -> OpenGuard {
+        self.counters
+            .websocket_connections
+            .fetch_add(1, Ordering::Relaxed);
+        OpenGuard {
+            counters: self.counters.clone(),
+            websocket: true,
+        }
+    }
+
+    pub fn record_request(&self) {
+        self.counters.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current metrics, with the request rate measured since the previous
+    /// snapshot.
+    pub fn snapshot(&self) -> ProxyMetrics {
+        let requests = self.counters.requests.load(Ordering::Relaxed);
+        let now = Instant::now();
+        let mut last_snapshot = self
+            .last_snapshot
+            .lock()
+            .expect("Proxy metrics lock was poisoned.");
+        let (last_requests, last_time) = *last_snapshot;
+        *last_snapshot = (requests, now);
+
+        let elapsed = now.duration_since(last_time).as_secs_f64();
+        let requests_per_second = if elapsed > 0.0 {
+            requests.saturating_sub(last_requests) as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        ProxyMetrics {
+            open_connections: self.counters.open_connections.load(Ordering::Relaxed),
+            requests_per_second,
+            websocket_connections: self.counters.websocket_connections.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_metrics() {
+        let tracker = ProxyMetricsTracker::default();
+        let connection = tracker.open_connection();
+        let websocket = tracker.clone().open_websocket();
+        tracker.record_request();
+        tracker.record_request();
+
+        let metrics = tracker.snapshot();
+        assert_eq!(1, metrics.open_connections);
+        assert_eq!(1, metrics.websocket_connections);
+        assert!(metrics.requests_per_second > 0.0);
+
+        drop(connection);
+        drop(websocket);
+        let metrics = tracker.snapshot();
+        assert_eq!(0, metrics.open_connections);
+        assert_eq!(0, metrics.websocket_connections);
+        assert_eq!(0.0, metrics.requests_per_second);
+    }
+}
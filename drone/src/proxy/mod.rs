@@ -1,26 +1,121 @@
 use self::{
-    certs::CertRefresher, connection_tracker::ConnectionTracker, service::MakeProxyService,
+    access_log::AccessLogger, cache::ResponseCache, certs::CertRefresher,
+    connection_tracker::ConnectionTracker, metrics::ProxyMetricsTracker, service::MakeProxyService,
     tls::TlsAcceptor,
 };
 use crate::{database::DroneDatabase, keys::KeyCertPathPair};
 use anyhow::{anyhow, Context};
+use futures::future::try_join_all;
 use hyper::{server::conn::AddrIncoming, Server};
-use plane_core::NeverResult;
+use plane_core::{nats::TypedNats, NeverResult};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::net::SocketAddr;
-use std::{net::IpAddr, sync::Arc, time::Duration};
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::select;
 
+mod access_log;
+mod cache;
 mod certs;
 mod connection_tracker;
+pub mod metrics;
 mod service;
 mod tls;
 
+/// Header marking a request sent by the agent through the local proxy to check
+/// that a backend is reachable before it is advertised as ready.
+pub const SELF_TEST_HEADER: &str = "x-plane-self-test";
+
+/// How often state kept for terminated backends is dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+
 pub struct ProxyOptions {
     pub db: DroneDatabase,
     pub bind_ip: IpAddr,
+
+    /// If provided, the proxy also listens on this IPv6 address.
+    pub bind_ipv6: Option<Ipv6Addr>,
+
     pub bind_port: u16,
     pub key_pair: Option<KeyCertPathPair>,
     pub cluster_domain: String,
+
+    /// If provided, a sample of proxied requests is published to NATS.
+    pub access_log: Option<AccessLogOptions>,
+
+    /// Session cookies issued in exchange for grants.
+    pub session: SessionOptions,
+
+    /// If provided, the proxy issues reconnect tokens and routes requests to
+    /// the cluster's root hostname which carry one.
+    pub reconnect: Option<ReconnectOptions>,
+
+    /// If provided, cacheable static responses are cached for each backend.
+    pub cache: Option<ResponseCacheOptions>,
+
+    /// If provided, the proxy counts its connections and requests here.
+    pub metrics: Option<ProxyMetricsTracker>,
+}
+
+#[derive(Clone)]
+pub struct SessionOptions {
+    /// How long session cookies are valid for.
+    pub ttl: Duration,
+
+    /// Origins of pages which may exchange a grant from another origin than
+    /// the backend's own.
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct ReconnectOptions {
+    /// Secret used to sign reconnect tokens. Proxies which share a secret
+    /// accept each other's tokens.
+    pub secret: String,
+
+    /// How long issued tokens are valid for.
+    pub token_ttl: Duration,
+
+    /// Origins of pages which may fetch a token from another origin than the
+    /// backend's own.
+    pub allowed_origins: Vec<String>,
+}
+
+pub struct ResponseCacheOptions {
+    /// Patterns of paths whose `GET` responses may be cached, in which `*`
+    /// matches any sequence of characters.
+    pub path_patterns: Vec<String>,
+
+    /// Maximum number of responses cached for each backend.
+    pub max_entries_per_backend: usize,
+
+    /// Responses with larger bodies are not cached.
+    pub max_body_bytes: usize,
+
+    /// Maximum bytes cached across every backend.
+    pub max_total_bytes: usize,
+}
+
+pub struct AccessLogOptions {
+    pub nats: TypedNats,
+
+    /// Fraction of requests to publish, between 0 and 1.
+    pub sample_rate: f64,
+}
+
+/// Periodically drop what the proxy holds about backends which have
+/// terminated.
+async fn prune_backends(make_proxy: MakeProxyService) -> NeverResult {
+    loop {
+        if let Err(error) = make_proxy.prune_backends().await {
+            tracing::error!(?error, "Encountered database error.");
+        }
+
+        tokio::time::sleep(PRUNE_INTERVAL).await;
+    }
 }
 
 async fn record_connections(
@@ -37,35 +132,91 @@ async fn record_connections(
     }
 }
 
+/// Bind a listener for IPv6 connections only, so that it does not conflict
+/// with an IPv4 listener on the same port even if it is bound to `::`.
+fn bind_ipv6_only(address: SocketAddr) -> anyhow::Result<AddrIncoming> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+
+    let listener = tokio::net::TcpListener::from_std(socket.into())?;
+    Ok(AddrIncoming::from_listener(listener)?)
+}
+
+async fn serve_incoming(
+    incoming: AddrIncoming,
+    make_proxy: MakeProxyService,
+    tls_cfg: Option<Arc<rustls::ServerConfig>>,
+) -> anyhow::Result<()> {
+    if let Some(tls_cfg) = tls_cfg {
+        let server = Server::builder(TlsAcceptor::new(tls_cfg, incoming)).serve(make_proxy);
+        server.await.context("Error from TLS proxy.")?;
+    } else {
+        let server = Server::builder(incoming).serve(make_proxy);
+        server.await.context("Error from non-TLS proxy.")?;
+    }
+
+    Ok(())
+}
+
 async fn run_server(options: ProxyOptions, connection_tracker: ConnectionTracker) -> NeverResult {
     let make_proxy = MakeProxyService::new(
         options.db,
         options.cluster_domain,
         connection_tracker.clone(),
+        options.bind_ip,
+        options
+            .access_log
+            .map(|access_log| AccessLogger::new(access_log.nats, access_log.sample_rate)),
+        options.session,
+        options.reconnect,
+        options.cache.map(ResponseCache::new),
+        options.metrics,
     );
-    let bind_address = SocketAddr::new(options.bind_ip, options.bind_port);
 
-    if let Some(key_pair) = options.key_pair {
-        let cert_refresher =
-            CertRefresher::new(key_pair.clone()).context("Error building cert refresher.")?;
+    let make_proxy_for_pruning = make_proxy.clone();
 
-        let tls_cfg = {
-            let cfg = rustls::ServerConfig::builder()
-                .with_safe_defaults()
-                .with_no_client_auth()
-                .with_cert_resolver(Arc::new(cert_refresher.resolver()));
+    let cert_refresher = options
+        .key_pair
+        .map(|key_pair| CertRefresher::new(key_pair).context("Error building cert refresher."))
+        .transpose()?;
+    let tls_cfg = cert_refresher.as_ref().map(|cert_refresher| {
+        let cfg = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(cert_refresher.resolver()));
 
-            Arc::new(cfg)
-        };
+        Arc::new(cfg)
+    });
 
-        let incoming =
-            AddrIncoming::bind(&bind_address).context("Error binding port for HTTPS.")?;
-        let server = Server::builder(TlsAcceptor::new(tls_cfg, incoming)).serve(make_proxy);
-        server.await.context("Error from TLS proxy.")?;
-    } else {
-        let server = Server::bind(&bind_address).serve(make_proxy);
-        server.await.context("Error from non-TLS proxy.")?;
-    };
+    let bind_address = SocketAddr::new(options.bind_ip, options.bind_port);
+    let mut servers = vec![serve_incoming(
+        AddrIncoming::bind(&bind_address).context("Error binding proxy port.")?,
+        make_proxy.clone(),
+        tls_cfg.clone(),
+    )];
+
+    if let Some(bind_ipv6) = options.bind_ipv6 {
+        let bind_address = SocketAddr::new(IpAddr::V6(bind_ipv6), options.bind_port);
+        servers.push(serve_incoming(
+            bind_ipv6_only(bind_address).context("Error binding IPv6 proxy port.")?,
+            make_proxy,
+            tls_cfg,
+        ));
+    }
+
+    select! {
+        result = try_join_all(servers) => {
+            result?;
+        }
+        result = prune_backends(make_proxy_for_pruning) => {
+            tracing::info!("prune_backends returned early.");
+            return result;
+        }
+    }
 
     Err(anyhow!("Server should not have terminated, but did."))
 }
@@ -1,17 +1,31 @@
+use super::access_log::AccessLogger;
+use super::cache::ResponseCache;
 use super::connection_tracker::ConnectionTracker;
+use super::metrics::{OpenGuard, ProxyMetricsTracker};
 use super::tls::TlsStream;
+use super::{ReconnectOptions, SessionOptions, SELF_TEST_HEADER};
 use crate::database::DroneDatabase;
 use anyhow::{anyhow, Context, Result};
-use http::uri::{Authority, Scheme};
-use http::Uri;
+use chrono::Utc;
+use dashmap::DashMap;
+use http::uri::{Authority, PathAndQuery, Scheme};
+use http::{HeaderValue, Uri};
 use hyper::client::HttpConnector;
 use hyper::server::conn::AddrStream;
 use hyper::Client;
 use hyper::{service::Service, Body, Request, Response, StatusCode};
+use plane_core::grant::{
+    session_token, verify_session_token, AuthGrant, ReconnectToken, GRANT_PATH, GRANT_QUERY_PARAM,
+    RECONNECT_HEADER, RECONNECT_PATH, RECONNECT_QUERY_PARAM, SESSION_COOKIE,
+};
+use plane_core::messages::agent::IdlePolicy;
+use plane_core::types::BackendId;
+use std::collections::HashSet;
 use std::io::ErrorKind;
 use std::net::IpAddr;
 use std::str::FromStr;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 use std::{
     convert::Infallible,
     future::{ready, Future, Ready},
@@ -21,6 +35,144 @@ use std::{
 
 const UPGRADE: &str = "upgrade";
 
+/// Prefix of paths on the cluster's root hostname which are routed to a
+/// backend, when routing by path is enabled.
+const BACKEND_PATH_PREFIX: &str = "/backends/";
+
+/// Header telling a backend reached by path the prefix that was stripped from
+/// the request path, so that it can generate links.
+const FORWARDED_PREFIX_HEADER: &str = "x-forwarded-prefix";
+
+/// Split a path of the form `/backends/<backend>/<rest>` into the backend and
+/// `/<rest>`.
+fn split_backend_path(path: &str) -> Option<(&str, &str)> {
+    let rest = path.strip_prefix(BACKEND_PATH_PREFIX)?;
+    let (backend, rest) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+
+    if backend.is_empty() {
+        None
+    } else {
+        Some((backend, rest))
+    }
+}
+
+/// Returns the value of the given cookie, if it is present on the request.
+fn get_cookie<'a>(request: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Returns the value of the given query parameter, if it is present on the request.
+fn get_query_param<'a>(request: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Returns true if the request carries the bearer token for the given backend,
+/// either directly or as a session cookie.
+fn is_authorized(request: &Request<Body>, backend: &BackendId, bearer_token: &str) -> bool {
+    if let Some(auth) = request.headers().get(http::header::AUTHORIZATION) {
+        if auth.to_str().unwrap_or_default().strip_prefix("Bearer ") == Some(bearer_token) {
+            return true;
+        }
+    }
+
+    get_cookie(request, SESSION_COOKIE)
+        .map(|token| verify_session_token(backend, bearer_token, token, Utc::now()))
+        .unwrap_or_default()
+}
+
+/// Remove the credentials the proxy authorized a request with, so that they
+/// are not forwarded to the backend. An `Authorization` header which does not
+/// carry the bearer token is left for the backend, and so are other cookies.
+fn strip_credentials(request: &mut Request<Body>, bearer_token: &str) {
+    let headers = request.headers_mut();
+    let carries_bearer_token = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+        == Some(bearer_token);
+    if carries_bearer_token {
+        headers.remove(http::header::AUTHORIZATION);
+    }
+
+    let cookies: Vec<String> = headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .map(str::trim)
+        .filter(|cookie| {
+            !cookie.is_empty() && cookie.split_once('=').map(|(key, _)| key) != Some(SESSION_COOKIE)
+        })
+        .map(str::to_string)
+        .collect();
+    headers.remove(http::header::COOKIE);
+    if !cookies.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&cookies.join("; ")) {
+            headers.insert(http::header::COOKIE, value);
+        }
+    }
+}
+
+/// Remove a query parameter from a URI, keeping the others in order.
+fn remove_query_param(uri: &Uri, name: &str) -> anyhow::Result<Uri> {
+    let query = match uri.query() {
+        Some(query) => query,
+        None => return Ok(uri.clone()),
+    };
+    let query: Vec<&str> = query
+        .split('&')
+        .filter(|param| param.split_once('=').map_or(*param, |(key, _)| key) != name)
+        .collect();
+    let path_and_query = if query.is_empty() {
+        uri.path().to_string()
+    } else {
+        format!("{}?{}", uri.path(), query.join("&"))
+    };
+
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::from_str(&path_and_query)?);
+    Uri::from_parts(parts).context("Error rewriting proxy query.")
+}
+
+/// Start a response which allows credentialed requests from the origin of the
+/// request, for endpoints typically called by a `fetch` from the application's
+/// own origin, if that origin is in `allowed_origins`.
+fn credentialed_cors_response(
+    req: &Request<Body>,
+    allowed_origins: &[String],
+) -> http::response::Builder {
+    let mut builder = Response::builder();
+    if let Some(origin) = req.headers().get(http::header::ORIGIN) {
+        let allowed = allowed_origins
+            .iter()
+            .any(|allowed| origin.as_bytes() == allowed.as_bytes());
+        if allowed {
+            builder = builder
+                .header(hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+                .header(hyper::header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")
+                .header(hyper::header::VARY, "Origin");
+        }
+    }
+    builder
+}
+
 /// Clone a request (method and headers, not body).
 fn clone_request(request: &Request<Body>) -> Result<Request<Body>, hyper::http::Error> {
     let mut builder = Request::builder();
@@ -42,21 +194,69 @@ fn clone_response(response: &Response<Body>) -> Result<Response<Body>, hyper::ht
     builder.body(Body::empty())
 }
 
+#[derive(Clone)]
 pub struct MakeProxyService {
     db: DroneDatabase,
     client: Client<HttpConnector, Body>,
     cluster: String,
     connection_tracker: ConnectionTracker,
+    bind_ip: IpAddr,
+    access_log: Option<AccessLogger>,
+    session: SessionOptions,
+    reconnect: Option<ReconnectOptions>,
+    cache: Option<ResponseCache>,
+    metrics: Option<ProxyMetricsTracker>,
+
+    /// Idle policy of each backend proxied to, which does not change during
+    /// the backend's life.
+    idle_policies: Arc<DashMap<String, IdlePolicy>>,
 }
 
 impl MakeProxyService {
-    pub fn new(db: DroneDatabase, cluster: String, connection_tracker: ConnectionTracker) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        db: DroneDatabase,
+        cluster: String,
+        connection_tracker: ConnectionTracker,
+        bind_ip: IpAddr,
+        access_log: Option<AccessLogger>,
+        session: SessionOptions,
+        reconnect: Option<ReconnectOptions>,
+        cache: Option<ResponseCache>,
+        metrics: Option<ProxyMetricsTracker>,
+    ) -> Self {
         MakeProxyService {
             db,
             client: Client::new(),
             cluster,
             connection_tracker,
+            bind_ip,
+            access_log,
+            session,
+            reconnect,
+            cache,
+            metrics,
+            idle_policies: Arc::default(),
+        }
+    }
+
+    /// Forget the idle policies and cached responses of backends which are
+    /// no longer ready, so that they do not accumulate over the life of the
+    /// drone.
+    pub async fn prune_backends(&self) -> Result<()> {
+        let ready: HashSet<String> = self
+            .db
+            .get_ready_route_subdomains()
+            .await?
+            .into_iter()
+            .collect();
+        self.idle_policies
+            .retain(|subdomain, _| ready.contains(subdomain));
+        if let Some(cache) = &self.cache {
+            cache.retain_backends(&ready);
         }
+
+        Ok(())
     }
 }
 
@@ -80,6 +280,17 @@ impl<'a> Service<&'a AddrStream> for MakeProxyService {
             cluster: self.cluster.clone(),
             connection_tracker: self.connection_tracker.clone(),
             remote_ip,
+            bind_ip: self.bind_ip,
+            access_log: self.access_log.clone(),
+            session: self.session.clone(),
+            reconnect: self.reconnect.clone(),
+            cache: self.cache.clone(),
+            metrics: self.metrics.clone(),
+            _open_connection: self
+                .metrics
+                .as_ref()
+                .map(|metrics| Arc::new(metrics.open_connection())),
+            idle_policies: self.idle_policies.clone(),
         }))
     }
 }
@@ -104,6 +315,17 @@ impl<'a> Service<&'a TlsStream> for MakeProxyService {
             cluster: self.cluster.clone(),
             connection_tracker: self.connection_tracker.clone(),
             remote_ip,
+            bind_ip: self.bind_ip,
+            access_log: self.access_log.clone(),
+            session: self.session.clone(),
+            reconnect: self.reconnect.clone(),
+            cache: self.cache.clone(),
+            metrics: self.metrics.clone(),
+            _open_connection: self
+                .metrics
+                .as_ref()
+                .map(|metrics| Arc::new(metrics.open_connection())),
+            idle_policies: self.idle_policies.clone(),
         }))
     }
 }
@@ -115,6 +337,20 @@ pub struct ProxyService {
     cluster: String,
     connection_tracker: ConnectionTracker,
     remote_ip: IpAddr,
+    bind_ip: IpAddr,
+    access_log: Option<AccessLogger>,
+    session: SessionOptions,
+    reconnect: Option<ReconnectOptions>,
+    cache: Option<ResponseCache>,
+    metrics: Option<ProxyMetricsTracker>,
+
+    /// Keeps the client's connection counted as open until every clone of
+    /// the service handling it is dropped.
+    _open_connection: Option<Arc<OpenGuard>>,
+
+    /// Idle policy of each backend proxied to, which does not change during
+    /// the backend's life.
+    idle_policies: Arc<DashMap<String, IdlePolicy>>,
 }
 
 #[allow(unused)]
@@ -128,10 +364,172 @@ impl ProxyService {
         Ok(uri)
     }
 
+    /// Self-test requests are only honored when they come from the drone itself.
+    fn is_self_test(&self, req: &Request<Body>) -> bool {
+        req.headers().contains_key(SELF_TEST_HEADER)
+            && (self.remote_ip.is_loopback() || self.remote_ip == self.bind_ip)
+    }
+
+    /// Forward a self-test request to a backend which may not be ready yet, and
+    /// mark the response so the agent can tell it came from the backend.
+    async fn handle_self_test(
+        self,
+        mut req: Request<Body>,
+        subdomain: &str,
+    ) -> anyhow::Result<Response<Body>> {
+        let addr = if let Some(addr) = self.db.get_self_test_route(subdomain).await? {
+            addr
+        } else {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())?);
+        };
+
+        *req.uri_mut() = Self::rewrite_uri(&addr, req.uri())?;
+        let mut response = self
+            .client
+            .request(req)
+            .await
+            .context("Error handling self-test request.")?;
+        response
+            .headers_mut()
+            .insert(SELF_TEST_HEADER, http::HeaderValue::from_static("ok"));
+
+        Ok(response)
+    }
+
+    /// Exchange a signed grant for a session cookie scoped to the backend's
+    /// hostname, or to its path prefix if it is routed by path. Pages on
+    /// other origins may only exchange a grant if they are in the configured
+    /// allow-list.
+    fn handle_grant(
+        req: &Request<Body>,
+        backend: &BackendId,
+        bearer_token: &str,
+        cookie_path: &str,
+        session: &SessionOptions,
+    ) -> anyhow::Result<Response<Body>> {
+        let builder = credentialed_cors_response(req, &session.allowed_origins);
+
+        let grant = get_query_param(req, GRANT_QUERY_PARAM).unwrap_or_default();
+        if let Err(error) = AuthGrant::verify(grant, backend, bearer_token, Utc::now()) {
+            tracing::warn!(%error, %backend, "Rejected grant.");
+            return Ok(builder.status(StatusCode::FORBIDDEN).body(Body::empty())?);
+        }
+
+        let expires = Utc::now()
+            + chrono::Duration::from_std(session.ttl).unwrap_or_else(|_| chrono::Duration::zero());
+        let cookie = format!(
+            "{}={}; Path={}; Max-Age={}; HttpOnly; Secure; SameSite=None",
+            SESSION_COOKIE,
+            session_token(backend, bearer_token, expires),
+            cookie_path,
+            session.ttl.as_secs()
+        );
+        Ok(builder
+            .status(StatusCode::NO_CONTENT)
+            .header(hyper::header::SET_COOKIE, cookie)
+            .body(Body::empty())?)
+    }
+
+    /// Issue a reconnect token for a backend the request is authorized to reach.
+    /// Pages on other origins may only fetch a token if they are in the
+    /// configured allow-list, so that an arbitrary site can not obtain a
+    /// token for a backend its visitor is authorized to reach.
+    fn handle_reconnect_token(
+        req: &Request<Body>,
+        backend: &BackendId,
+        reconnect: &ReconnectOptions,
+    ) -> anyhow::Result<Response<Body>> {
+        let token =
+            ReconnectToken::new(backend.clone(), reconnect.token_ttl).sign(&reconnect.secret);
+        Ok(credentialed_cors_response(req, &reconnect.allowed_origins)
+            .status(StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, "text/plain")
+            .header(hyper::header::CACHE_CONTROL, "no-store")
+            .body(Body::from(token))?)
+    }
+
+    /// The backend a request to the cluster's root hostname is routed to, if it
+    /// carries a valid reconnect token. The token is removed from the request,
+    /// so that it is not forwarded to the backend.
+    fn reconnect_backend(&self, req: &mut Request<Body>) -> anyhow::Result<Option<BackendId>> {
+        let reconnect = match self.reconnect.as_ref() {
+            Some(reconnect) => reconnect,
+            None => return Ok(None),
+        };
+        let token = match req
+            .headers()
+            .get(RECONNECT_HEADER)
+            .and_then(|token| token.to_str().ok())
+            .or_else(|| get_query_param(req, RECONNECT_QUERY_PARAM))
+        {
+            Some(token) => token.to_string(),
+            None => return Ok(None),
+        };
+
+        req.headers_mut().remove(RECONNECT_HEADER);
+        *req.uri_mut() = remove_query_param(req.uri(), RECONNECT_QUERY_PARAM)?;
+
+        match ReconnectToken::verify(&token, &reconnect.secret, Utc::now()) {
+            Ok(token) => Ok(Some(token.backend_id)),
+            Err(error) => {
+                tracing::warn!(%error, "Rejected reconnect token.");
+                Ok(None)
+            }
+        }
+    }
+
+    /// If the request is for a path under [BACKEND_PATH_PREFIX], and the
+    /// backend's cluster addresses it by path, strip the backend's prefix
+    /// from the request's path and return the backend's route and the prefix.
+    async fn strip_backend_path(
+        &self,
+        req: &mut Request<Body>,
+    ) -> anyhow::Result<Option<(String, String)>> {
+        let (subdomain, path) = match split_backend_path(req.uri().path()) {
+            Some((subdomain, path)) => (subdomain.to_string(), path.to_string()),
+            None => return Ok(None),
+        };
+        if !self.db.get_proxy_route_path_routing(&subdomain).await? {
+            return Ok(None);
+        }
+
+        let prefix = format!("{}{}", BACKEND_PATH_PREFIX, subdomain);
+        let path_and_query = match req.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = Some(PathAndQuery::from_str(&path_and_query)?);
+        *req.uri_mut() = Uri::from_parts(parts).context("Error rewriting proxy path.")?;
+        req.headers_mut()
+            .insert(FORWARDED_PREFIX_HEADER, HeaderValue::from_str(&prefix)?);
+
+        Ok(Some((subdomain, prefix)))
+    }
+
+    /// The idle policy of the backend behind a subdomain.
+    async fn idle_policy(&self, subdomain: &str) -> anyhow::Result<IdlePolicy> {
+        if let Some(policy) = self.idle_policies.get(subdomain) {
+            return Ok(policy.clone());
+        }
+
+        let policy = self
+            .db
+            .get_proxy_route_idle_policy(subdomain)
+            .await?
+            .unwrap_or_default();
+        self.idle_policies
+            .insert(subdomain.to_string(), policy.clone());
+        Ok(policy)
+    }
+
     async fn handle_upgrade(
         self,
         mut req: Request<Body>,
         backend: &str,
+        idle_policy: IdlePolicy,
     ) -> anyhow::Result<Response<Body>> {
         let response = self
             .client
@@ -151,19 +549,33 @@ impl ProxyService {
             };
 
             let connection_tracker = self.connection_tracker.clone();
+            let websocket = self
+                .metrics
+                .as_ref()
+                .map(ProxyMetricsTracker::open_websocket);
             let backend = backend.to_string();
+            let counted = !idle_policy.ignores_path(req.uri().path());
+            let min_connection = idle_policy.min_connection_secs.unwrap_or_default();
             tokio::task::spawn(async move {
                 match hyper::upgrade::on(&mut req).await {
                     Ok(mut upgraded_request) => {
                         let started = SystemTime::now();
 
-                        connection_tracker.increment_connections(&backend);
-                        let result = tokio::io::copy_bidirectional(
+                        let copy = tokio::io::copy_bidirectional(
                             &mut upgraded_response,
                             &mut upgraded_request,
-                        )
-                        .await;
-                        connection_tracker.decrement_connections(&backend);
+                        );
+                        tokio::pin!(copy);
+
+                        // The connection only keeps the backend from being idle once it
+                        // has been open for as long as the backend's idle policy requires.
+                        let result = tokio::select! {
+                            result = &mut copy => result,
+                            _ = tokio::time::sleep(min_connection), if counted => {
+                                let _connection = connection_tracker.track_connection(&backend);
+                                copy.await
+                            }
+                        };
                         let duration = SystemTime::now()
                             .duration_since(started)
                             .unwrap_or_default()
@@ -190,6 +602,7 @@ impl ProxyService {
                     }
                     Err(e) => tracing::error!(?e, "Error upgrading request."),
                 }
+                drop(websocket);
             });
 
             Ok(response_clone)
@@ -199,37 +612,156 @@ impl ProxyService {
     }
 
     async fn handle(self, mut req: Request<Body>) -> anyhow::Result<Response<Body>> {
-        if let Some(host) = req.headers().get(http::header::HOST) {
+        // Only the proxy tells a backend which prefix it is reached by.
+        req.headers_mut().remove(FORWARDED_PREFIX_HEADER);
+
+        // Cloned, since routing may rewrite the request.
+        if let Some(host) = req.headers().get(http::header::HOST).cloned() {
             let host = std::str::from_utf8(host.as_bytes())?;
             // If the host includes a port, strip it.
             let host = host.split_once(':').map(|(host, _)| host).unwrap_or(host);
 
-            tracing::info!(ip=%self.remote_ip, url=%req.uri(), "Proxy Request");
+            // The query is not logged, as it may carry a grant or reconnect token.
+            tracing::info!(ip=%self.remote_ip, path=%req.uri().path(), "Proxy Request");
 
             // TODO: we shouldn't need to allocate a string just to strip a prefix.
-            if let Some(subdomain) = host.strip_suffix(&format!(".{}", self.cluster)) {
-                let subdomain = subdomain.to_string();
+            let route = if let Some(subdomain) = host.strip_suffix(&format!(".{}", self.cluster)) {
+                Some((subdomain.to_string(), false, None))
+            } else if host == self.cluster {
+                match self.strip_backend_path(&mut req).await? {
+                    Some((subdomain, prefix)) => Some((subdomain, false, Some(prefix))),
+                    // A valid reconnect token stands in for the backend's own
+                    // hostname and its authorization.
+                    None => self
+                        .reconnect_backend(&mut req)?
+                        .map(|backend| (backend.id().to_string(), true, None)),
+                }
+            } else {
+                None
+            };
+
+            if let Some((subdomain, reconnected, prefix)) = route {
+                if !reconnected {
+                    if self.is_self_test(&req) {
+                        return self.handle_self_test(req, &subdomain).await;
+                    }
+
+                    if let Some((backend, bearer_token)) =
+                        self.db.get_proxy_route_bearer_token(&subdomain).await?
+                    {
+                        if req.uri().path() == GRANT_PATH {
+                            let cookie_path = prefix.as_deref().unwrap_or("/");
+                            return Self::handle_grant(
+                                &req,
+                                &backend,
+                                &bearer_token,
+                                cookie_path,
+                                &self.session,
+                            );
+                        }
+
+                        if !is_authorized(&req, &backend, &bearer_token) {
+                            return Ok(Response::builder()
+                                .status(StatusCode::UNAUTHORIZED)
+                                .header(hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                                .body(Body::empty())?);
+                        }
+                        strip_credentials(&mut req, &bearer_token);
+                    }
+                }
+
                 if let Some(addr) = self.db.get_proxy_route(&subdomain).await? {
-                    self.connection_tracker.track_request(&subdomain);
+                    if let (RECONNECT_PATH, Some(reconnect)) = (req.uri().path(), &self.reconnect) {
+                        // Routes are keyed by the ID of their backend.
+                        return Self::handle_reconnect_token(
+                            &req,
+                            &BackendId::new(subdomain),
+                            reconnect,
+                        );
+                    }
+
+                    let idle_policy = self.idle_policy(&subdomain).await?;
                     *req.uri_mut() = Self::rewrite_uri(&addr, req.uri())?;
 
-                    if let Some(connection) = req.headers().get(hyper::http::header::CONNECTION) {
-                        if connection
-                            .to_str()
-                            .unwrap_or_default()
-                            .to_lowercase()
-                            .contains(UPGRADE)
-                        {
-                            return self.handle_upgrade(req, &subdomain).await;
+                    let started = Instant::now();
+                    let method = req.method().to_string();
+                    let path = req.uri().path().to_string();
+                    let access_log = self.access_log.clone();
+
+                    let is_upgrade = req
+                        .headers()
+                        .get(hyper::http::header::CONNECTION)
+                        .map(|connection| {
+                            connection
+                                .to_str()
+                                .unwrap_or_default()
+                                .to_lowercase()
+                                .contains(UPGRADE)
+                        })
+                        .unwrap_or_default();
+
+                    let counted = idle_policy.counts_request(&path, is_upgrade);
+                    if counted {
+                        self.connection_tracker.track_request(&subdomain);
+                    }
+
+                    // Static responses may be served from the backend's cache, after the
+                    // request has been authorized and counted as activity.
+                    let cache = if is_upgrade { None } else { self.cache.clone() };
+                    let mut cacheable = cache
+                        .as_ref()
+                        .and_then(|cache| cache.cacheable_request(&req));
+                    let cached = match (&cache, &cacheable) {
+                        (Some(cache), Some(request)) => cache.get(&subdomain, request),
+                        _ => None,
+                    };
+
+                    let result = if let Some(response) = cached {
+                        cacheable = None;
+                        Ok(response)
+                    } else if is_upgrade {
+                        self.handle_upgrade(req, &subdomain, idle_policy).await
+                    } else if counted {
+                        // Count the request as a connection until the response arrives
+                        // or the client goes away, so that a draining backend waits for it.
+                        let _connection = self.connection_tracker.track_connection(&subdomain);
+                        self.client
+                            .request(req)
+                            .await
+                            .context("Error handling client request.")
+                    } else {
+                        self.client
+                            .request(req)
+                            .await
+                            .context("Error handling client request.")
+                    };
+
+                    let result = match (cache, cacheable, result) {
+                        (Some(cache), Some(request), Ok(response)) => {
+                            cache.insert(&subdomain, request, response).await
                         }
+                        (_, _, result) => result,
+                    };
+
+                    if let (Some(access_log), Ok(response)) = (access_log, &result) {
+                        // Routes are keyed by the ID of their backend.
+                        access_log.log(
+                            &BackendId::new(subdomain),
+                            &method,
+                            &path,
+                            response,
+                            started.elapsed(),
+                        );
                     }
 
-                    let result = self
-                        .client
-                        .request(req)
-                        .await
-                        .context("Error handling client request.")?;
-                    return Ok(result);
+                    return result;
+                }
+
+                // The backend is gone, so its policy and responses will not be
+                // needed again.
+                self.idle_policies.remove(&subdomain);
+                if let Some(cache) = &self.cache {
+                    cache.remove_backend(&subdomain);
                 }
             }
 
@@ -245,6 +777,9 @@ impl ProxyService {
     }
 
     async fn warn_handle(self, mut req: Request<Body>) -> anyhow::Result<Response<Body>> {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_request();
+        }
         let result = self.handle(req).await;
 
         if let Err(error) = &result {
@@ -274,3 +809,109 @@ impl Service<Request<Body>> for ProxyService {
         Box::pin(self.clone().warn_handle(req))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_backend_path() {
+        assert_eq!(
+            Some(("abc123", "/index.html")),
+            split_backend_path("/backends/abc123/index.html")
+        );
+        assert_eq!(
+            Some(("abc123", "/")),
+            split_backend_path("/backends/abc123/")
+        );
+        assert_eq!(
+            Some(("abc123", "/")),
+            split_backend_path("/backends/abc123")
+        );
+        assert_eq!(None, split_backend_path("/backends/"));
+        assert_eq!(None, split_backend_path("/other/abc123"));
+    }
+
+    #[test]
+    fn test_remove_query_param() {
+        let uri = Uri::from_static("/ws?plane_reconnect=token&room=1");
+        assert_eq!(
+            "/ws?room=1",
+            remove_query_param(&uri, "plane_reconnect").unwrap()
+        );
+
+        let uri = Uri::from_static("/ws?plane_reconnect=token");
+        assert_eq!("/ws", remove_query_param(&uri, "plane_reconnect").unwrap());
+
+        let uri = Uri::from_static("/ws?plane_reconnect_other=1");
+        assert_eq!(
+            "/ws?plane_reconnect_other=1",
+            remove_query_param(&uri, "plane_reconnect").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_strip_credentials() {
+        let mut request = Request::builder()
+            .header(http::header::AUTHORIZATION, "Bearer my-token")
+            .header(http::header::COOKIE, "theme=dark; plane_session=abc")
+            .header(http::header::COOKIE, "plane_session=abc")
+            .body(Body::empty())
+            .unwrap();
+        strip_credentials(&mut request, "my-token");
+        assert!(request.headers().get(http::header::AUTHORIZATION).is_none());
+        assert_eq!(
+            vec!["theme=dark"],
+            request
+                .headers()
+                .get_all(http::header::COOKIE)
+                .iter()
+                .collect::<Vec<_>>()
+        );
+
+        // Credentials of the backend's own are forwarded.
+        let mut request = Request::builder()
+            .header(http::header::AUTHORIZATION, "Bearer app-token")
+            .header(http::header::COOKIE, "plane_session=abc")
+            .body(Body::empty())
+            .unwrap();
+        strip_credentials(&mut request, "my-token");
+        assert_eq!(
+            "Bearer app-token",
+            request.headers().get(http::header::AUTHORIZATION).unwrap()
+        );
+        assert!(request.headers().get(http::header::COOKIE).is_none());
+    }
+
+    #[test]
+    fn test_cors_allow_list() {
+        let request = Request::builder()
+            .header(http::header::ORIGIN, "https://evil.example")
+            .body(Body::empty())
+            .unwrap();
+        let allowed = vec!["https://app.example".to_string()];
+
+        let response = credentialed_cors_response(&request, &allowed)
+            .body(())
+            .unwrap();
+        assert!(response
+            .headers()
+            .get(hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        let request = Request::builder()
+            .header(http::header::ORIGIN, "https://app.example")
+            .body(Body::empty())
+            .unwrap();
+        let response = credentialed_cors_response(&request, &allowed)
+            .body(())
+            .unwrap();
+        assert_eq!(
+            "https://app.example",
+            response
+                .headers()
+                .get(hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap()
+        );
+    }
+}